@@ -0,0 +1,344 @@
+use aes_gcm::aead::AeadInPlace;
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce, Tag};
+
+use crate::afalg::KernelGcm;
+use crate::header::{NONCE_LEN, TAG_LEN};
+use crate::progress;
+
+/// An AES-256-GCM implementation for the payload chunks.
+pub trait ChunkAead: Send + Sync {
+    /// Encrypt `buf` in place and return the detached tag.
+    fn seal(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> Result<[u8; TAG_LEN], String>;
+
+    /// Decrypt `buf` in place. Returns `false` when authentication fails,
+    /// which may leave `buf` scrambled; an error means the implementation
+    /// itself failed.
+    fn open(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+        tag: &[u8],
+    ) -> Result<bool, String>;
+}
+
+impl ChunkAead for Aes256Gcm {
+    fn seal(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> Result<[u8; TAG_LEN], String> {
+        self.encrypt_in_place_detached(Nonce::from_slice(nonce), aad, buf)
+            .map(Into::into)
+            .map_err(|e| e.to_string())
+    }
+
+    fn open(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+        tag: &[u8],
+    ) -> Result<bool, String> {
+        Ok(self
+            .decrypt_in_place_detached(Nonce::from_slice(nonce), aad, buf, Tag::from_slice(tag))
+            .is_ok())
+    }
+}
+
+impl ChunkAead for KernelGcm {
+    fn seal(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> Result<[u8; TAG_LEN], String> {
+        self.encrypt(nonce, aad, buf).map_err(|e| e.to_string())
+    }
+
+    fn open(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+        tag: &[u8],
+    ) -> Result<bool, String> {
+        self.decrypt(nonce, aad, buf, tag).map_err(|e| e.to_string())
+    }
+}
+
+/// AES-256-GCM from ring.
+#[cfg(feature = "ring")]
+struct RingGcm(ring::aead::LessSafeKey);
+
+#[cfg(feature = "ring")]
+impl RingGcm {
+    fn new(key: &[u8; 32]) -> Result<Self, String> {
+        let key = ring::aead::UnboundKey::new(&ring::aead::AES_256_GCM, key)
+            .map_err(|_| "ring rejected the key".to_string())?;
+        Ok(RingGcm(ring::aead::LessSafeKey::new(key)))
+    }
+}
+
+#[cfg(feature = "ring")]
+impl ChunkAead for RingGcm {
+    fn seal(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> Result<[u8; TAG_LEN], String> {
+        let nonce = ring::aead::Nonce::assume_unique_for_key(*nonce);
+        let tag = self
+            .0
+            .seal_in_place_separate_tag(nonce, ring::aead::Aad::from(aad), buf)
+            .map_err(|_| "ring failed to encrypt".to_string())?;
+        Ok(tag.as_ref().try_into().unwrap())
+    }
+
+    fn open(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+        tag: &[u8],
+    ) -> Result<bool, String> {
+        let nonce = ring::aead::Nonce::assume_unique_for_key(*nonce);
+        let tag = ring::aead::Tag::try_from(tag).map_err(|_| "Bad tag length".to_string())?;
+        Ok(self
+            .0
+            .open_in_place_separate_tag(nonce, ring::aead::Aad::from(aad), tag, buf, 0..)
+            .is_ok())
+    }
+}
+
+/// AES-256-GCM from the system OpenSSL.
+#[cfg(feature = "openssl")]
+struct OpensslGcm([u8; 32]);
+
+#[cfg(feature = "openssl")]
+impl OpensslGcm {
+    /// Run `buf` through a GCM crypter in `mode`. Decrypting checks `tag`;
+    /// encrypting returns the computed tag.
+    fn crypt(
+        &self,
+        mode: openssl::symm::Mode,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+        tag: Option<&[u8]>,
+    ) -> Result<Option<[u8; TAG_LEN]>, openssl::error::ErrorStack> {
+        let cipher = openssl::symm::Cipher::aes_256_gcm();
+        let mut crypter = openssl::symm::Crypter::new(cipher, mode, &self.0, Some(nonce))?;
+        crypter.aad_update(aad)?;
+        // OpenSSL may hold back up to a block, and cannot work in place
+        let mut out = vec![0u8; buf.len() + cipher.block_size()];
+        let mut n = crypter.update(buf, &mut out)?;
+        if let Some(tag) = tag {
+            crypter.set_tag(tag)?;
+        }
+        n += crypter.finalize(&mut out[n..])?;
+        buf.copy_from_slice(&out[..n]);
+        if tag.is_some() {
+            return Ok(None);
+        }
+        let mut computed = [0u8; TAG_LEN];
+        crypter.get_tag(&mut computed)?;
+        Ok(Some(computed))
+    }
+}
+
+#[cfg(feature = "openssl")]
+impl ChunkAead for OpensslGcm {
+    fn seal(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> Result<[u8; TAG_LEN], String> {
+        match self.crypt(openssl::symm::Mode::Encrypt, nonce, aad, buf, None) {
+            Ok(tag) => Ok(tag.unwrap()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn open(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+        tag: &[u8],
+    ) -> Result<bool, String> {
+        // A failed tag check is the only error finalize reports
+        Ok(self.crypt(openssl::symm::Mode::Decrypt, nonce, aad, buf, Some(tag)).is_ok())
+    }
+}
+
+/// Where the payload chunks are encrypted and decrypted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// The RustCrypto implementation, using AES instructions when present.
+    #[default]
+    Software,
+    /// The Linux kernel crypto API, for accelerators only it can drive.
+    Kernel,
+    /// ring, with the `ring` cargo feature.
+    Ring,
+    /// The system OpenSSL, with the `openssl` cargo feature.
+    Openssl,
+}
+
+/// Every backend, in the order they are benchmarked.
+pub const BACKENDS: &[Backend] =
+    &[Backend::Software, Backend::Kernel, Backend::Ring, Backend::Openssl];
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Software => "software",
+            Backend::Kernel => "kernel",
+            Backend::Ring => "ring",
+            Backend::Openssl => "openssl",
+        }
+    }
+
+    /// Whether this build includes the backend. The kernel backend may
+    /// still be missing from the running kernel.
+    pub fn is_built(self) -> bool {
+        match self {
+            Backend::Software | Backend::Kernel => true,
+            Backend::Ring => cfg!(feature = "ring"),
+            Backend::Openssl => cfg!(feature = "openssl"),
+        }
+    }
+}
+
+/// Parse a `--crypto-backend` value.
+pub fn parse_backend(arg: &str) -> Result<Backend, String> {
+    let Some(&backend) = BACKENDS.iter().find(|backend| backend.name() == arg) else {
+        let names: Vec<_> = BACKENDS.iter().map(|backend| backend.name()).collect();
+        return Err(format!("expected one of {}, got '{}'", names.join(", "), arg));
+    };
+    if !backend.is_built() {
+        return Err(format!(
+            "this build has no {0} backend; rebuild with the `{0}` feature",
+            arg
+        ));
+    }
+    Ok(backend)
+}
+
+/// AES-256-GCM for the payload chunks, on the chosen backend.
+pub struct PayloadCipher(Box<dyn ChunkAead>);
+
+impl PayloadCipher {
+    /// Set up the cipher under `key`. A backend that cannot be set up falls
+    /// back to software, with a warning.
+    pub fn new(key: &[u8; 32], backend: Backend) -> Self {
+        Self::with_backend(key, backend).unwrap_or_else(|msg| {
+            progress::emit_warning(
+                "crypto_backend_unavailable",
+                &format!(
+                    "The {} crypto backend is not available ({}); using software AES-GCM",
+                    backend.name(),
+                    msg
+                ),
+            );
+            PayloadCipher(Box::new(Aes256Gcm::new(key.into())))
+        })
+    }
+
+    /// Set up the cipher under `key` on exactly `backend`.
+    pub fn with_backend(key: &[u8; 32], backend: Backend) -> Result<Self, String> {
+        let aead: Box<dyn ChunkAead> = match backend {
+            Backend::Software => Box::new(Aes256Gcm::new(key.into())),
+            Backend::Kernel => Box::new(KernelGcm::new(key).map_err(|e| e.to_string())?),
+            #[cfg(feature = "ring")]
+            Backend::Ring => Box::new(RingGcm::new(key)?),
+            #[cfg(feature = "openssl")]
+            Backend::Openssl => Box::new(OpensslGcm(*key)),
+            #[allow(unreachable_patterns)]
+            _ => return Err("not included in this build".to_string()),
+        };
+        Ok(PayloadCipher(aead))
+    }
+
+    /// Encrypt `buf` in place and return the detached tag.
+    pub fn seal(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> Result<[u8; TAG_LEN], String> {
+        self.0.seal(nonce, aad, buf)
+    }
+
+    /// Decrypt `buf` in place, as `ChunkAead::open`.
+    pub fn open(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+        tag: &[u8],
+    ) -> Result<bool, String> {
+        self.0.open(nonce, aad, buf, tag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backends_agree_with_software() {
+        let key = [9u8; 32];
+        let software = PayloadCipher::new(&key, Backend::Software);
+        let nonce = [1u8; NONCE_LEN];
+        let plaintext: Vec<u8> = (0..70_001u32).map(|i| (i % 253) as u8).collect();
+        let mut expected = plaintext.clone();
+        let expected_tag = software.seal(&nonce, b"aad", &mut expected).unwrap();
+
+        for &backend in BACKENDS {
+            // Backends missing here are covered by the software fallback
+            let Ok(cipher) = PayloadCipher::with_backend(&key, backend) else {
+                continue;
+            };
+            let mut sealed = plaintext.clone();
+            let tag = cipher.seal(&nonce, b"aad", &mut sealed).unwrap();
+            assert_eq!(tag, expected_tag, "{}", backend.name());
+            assert_eq!(sealed, expected, "{}", backend.name());
+
+            let mut tampered = sealed.clone();
+            assert!(!cipher.open(&nonce, b"other", &mut tampered, &tag).unwrap());
+            assert!(cipher.open(&nonce, b"aad", &mut sealed, &tag).unwrap());
+            assert_eq!(sealed, plaintext, "{}", backend.name());
+        }
+    }
+
+    #[test]
+    fn test_unavailable_backend_falls_back() {
+        let key = [9u8; 32];
+        let nonce = [1u8; NONCE_LEN];
+        let mut sealed = b"chunk data".to_vec();
+        let tag = PayloadCipher::new(&key, Backend::Kernel)
+            .seal(&nonce, b"aad", &mut sealed)
+            .unwrap();
+        let software = PayloadCipher::new(&key, Backend::Software);
+        assert!(software.open(&nonce, b"aad", &mut sealed, &tag).unwrap());
+        assert_eq!(sealed, b"chunk data");
+    }
+
+    #[test]
+    fn test_parse_backend() {
+        assert_eq!(parse_backend("software").unwrap(), Backend::Software);
+        assert_eq!(parse_backend("kernel").unwrap(), Backend::Kernel);
+        assert_eq!(parse_backend("ring").is_ok(), cfg!(feature = "ring"));
+        assert!(parse_backend("gpu").is_err());
+    }
+}
@@ -0,0 +1,321 @@
+use std::io;
+
+use crate::header::{NONCE_LEN, TAG_LEN};
+
+/// AES-256-GCM performed by the kernel crypto API through AF_ALG sockets,
+/// which reaches crypto accelerators that only have a kernel driver.
+///
+/// The key is set once on a transform socket; each operation runs on a
+/// request socket accepted from it, kept in a pool so that several threads
+/// can work at once.
+#[cfg(target_os = "linux")]
+pub struct KernelGcm {
+    tfm: std::os::fd::OwnedFd,
+    requests: std::sync::Mutex<Vec<std::os::fd::OwnedFd>>,
+}
+
+#[cfg(target_os = "linux")]
+impl KernelGcm {
+    /// Set up `gcm(aes)` with `key`. Fails where the kernel has no AF_ALG
+    /// support or no GCM implementation.
+    pub fn new(key: &[u8; 32]) -> io::Result<Self> {
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+        // SAFETY: socket has no preconditions
+        let fd =
+            unsafe { libc::socket(libc::AF_ALG, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a fresh descriptor that nothing else owns
+        let tfm = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: sockaddr_alg is plain old data, for which all zeros is valid
+        let mut addr: libc::sockaddr_alg = unsafe { std::mem::zeroed() };
+        addr.salg_family = libc::AF_ALG as libc::sa_family_t;
+        addr.salg_type[..4].copy_from_slice(b"aead");
+        addr.salg_name[..8].copy_from_slice(b"gcm(aes)");
+        // SAFETY: `addr` is a valid sockaddr_alg of the given length
+        let bound = unsafe {
+            libc::bind(
+                tfm.as_raw_fd(),
+                (&addr as *const libc::sockaddr_alg).cast(),
+                std::mem::size_of::<libc::sockaddr_alg>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the key buffer is valid for its length
+        if unsafe {
+            libc::setsockopt(
+                tfm.as_raw_fd(),
+                libc::SOL_ALG,
+                libc::ALG_SET_KEY,
+                key.as_ptr().cast(),
+                key.len() as libc::socklen_t,
+            )
+        } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the tag size is passed as the option length, with no value
+        if unsafe {
+            libc::setsockopt(
+                tfm.as_raw_fd(),
+                libc::SOL_ALG,
+                libc::ALG_SET_AEAD_AUTHSIZE,
+                std::ptr::null(),
+                TAG_LEN as libc::socklen_t,
+            )
+        } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        let gcm = KernelGcm {
+            tfm,
+            requests: std::sync::Mutex::new(Vec::new()),
+        };
+        // Accepting a request socket is the first point at which a missing
+        // implementation shows
+        let request = gcm.take_request()?;
+        gcm.put_request(request);
+        Ok(gcm)
+    }
+
+    /// Encrypt `buf` in place and return the tag.
+    pub fn encrypt(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> io::Result<[u8; TAG_LEN]> {
+        // In: aad, plaintext. Out: aad, ciphertext, tag.
+        let input = [aad, buf].concat();
+        let mut output = vec![0u8; aad.len() + buf.len() + TAG_LEN];
+        self.run(libc::ALG_OP_ENCRYPT, nonce, aad.len(), &input, &mut output)?;
+        let (ciphertext, tag) = output[aad.len()..].split_at(buf.len());
+        buf.copy_from_slice(ciphertext);
+        Ok(tag.try_into().unwrap())
+    }
+
+    /// Decrypt `buf` in place. Returns `false`, leaving `buf` untouched,
+    /// when authentication fails.
+    pub fn decrypt(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+        tag: &[u8],
+    ) -> io::Result<bool> {
+        // In: aad, ciphertext, tag. Out: aad, plaintext.
+        let input = [aad, buf, tag].concat();
+        let mut output = vec![0u8; aad.len() + buf.len()];
+        match self.run(libc::ALG_OP_DECRYPT, nonce, aad.len(), &input, &mut output) {
+            Ok(()) => {
+                buf.copy_from_slice(&output[aad.len()..]);
+                Ok(true)
+            }
+            Err(e) if e.raw_os_error() == Some(libc::EBADMSG) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Run one operation on a request socket: send the input with the
+    /// operation, nonce and AAD length as control messages, then read the
+    /// whole output back.
+    fn run(
+        &self,
+        op: libc::c_int,
+        nonce: &[u8; NONCE_LEN],
+        aad_len: usize,
+        input: &[u8],
+        output: &mut [u8],
+    ) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let request = self.take_request()?;
+        let fd = request.as_raw_fd();
+
+        let op = op as u32;
+        let mut iv = (NONCE_LEN as u32).to_ne_bytes().to_vec();
+        iv.extend_from_slice(nonce);
+        let assoc_len = aad_len as u32;
+        let messages: [(libc::c_int, &[u8]); 3] = [
+            (libc::ALG_SET_OP, &op.to_ne_bytes()),
+            (libc::ALG_SET_IV, &iv),
+            (libc::ALG_SET_AEAD_ASSOCLEN, &assoc_len.to_ne_bytes()),
+        ];
+        // SAFETY: CMSG_SPACE only computes a size
+        let space: usize = messages
+            .iter()
+            .map(|(_, data)| unsafe { libc::CMSG_SPACE(data.len() as u32) } as usize)
+            .sum();
+        // u64 words keep the control buffer aligned for cmsghdr
+        let mut control = vec![0u64; space.div_ceil(8)];
+
+        let mut iov = libc::iovec {
+            iov_base: input.as_ptr() as *mut libc::c_void,
+            iov_len: input.len(),
+        };
+        // SAFETY: msghdr is plain old data, for which all zeros is valid
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = space as _;
+
+        // SAFETY: the control buffer holds `space` bytes, room for every
+        // message header and its data as laid out by the CMSG macros
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            for (kind, data) in messages {
+                (*cmsg).cmsg_level = libc::SOL_ALG;
+                (*cmsg).cmsg_type = kind;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(data.len() as u32) as _;
+                std::ptr::copy_nonoverlapping(data.as_ptr(), libc::CMSG_DATA(cmsg), data.len());
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+
+        // SAFETY: `msg` points at live buffers for the duration of the call
+        let sent = unsafe { libc::sendmsg(fd, &msg, 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if sent as usize != input.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "The kernel took only part of the input",
+            ));
+        }
+
+        let mut filled = 0;
+        while filled < output.len() {
+            let rest = &mut output[filled..];
+            // SAFETY: reads into the unfilled part of `output`
+            let n = unsafe { libc::read(fd, rest.as_mut_ptr().cast(), rest.len()) };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            filled += n as usize;
+        }
+
+        // A request socket that failed part way is dropped, not reused
+        self.put_request(request);
+        Ok(())
+    }
+
+    fn take_request(&self) -> io::Result<std::os::fd::OwnedFd> {
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+        if let Some(request) = self.requests.lock().unwrap().pop() {
+            return Ok(request);
+        }
+        // SAFETY: accept on a bound AF_ALG socket, without a peer address
+        let fd = unsafe {
+            libc::accept4(
+                self.tfm.as_raw_fd(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                libc::SOCK_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a fresh descriptor that nothing else owns
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    fn put_request(&self, request: std::os::fd::OwnedFd) {
+        self.requests.lock().unwrap().push(request);
+    }
+}
+
+/// Stands in for the kernel backend where there is no AF_ALG; it cannot be
+/// created.
+#[cfg(not(target_os = "linux"))]
+pub struct KernelGcm {
+    never: std::convert::Infallible,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl KernelGcm {
+    pub fn new(_key: &[u8; 32]) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "kernel crypto is only available on Linux",
+        ))
+    }
+
+    pub fn encrypt(
+        &self,
+        _nonce: &[u8; NONCE_LEN],
+        _aad: &[u8],
+        _buf: &mut [u8],
+    ) -> io::Result<[u8; TAG_LEN]> {
+        match self.never {}
+    }
+
+    pub fn decrypt(
+        &self,
+        _nonce: &[u8; NONCE_LEN],
+        _aad: &[u8],
+        _buf: &mut [u8],
+        _tag: &[u8],
+    ) -> io::Result<bool> {
+        match self.never {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_gcm::aead::AeadInPlace;
+    use aes_gcm::{Aes256Gcm, KeyInit, Nonce, Tag};
+
+    #[test]
+    fn test_kernel_gcm_matches_software() {
+        let key = [7u8; 32];
+        // Most build machines and containers have no AF_ALG
+        let Ok(kernel) = KernelGcm::new(&key) else {
+            return;
+        };
+        let software = Aes256Gcm::new(&key.into());
+        let nonce = [3u8; NONCE_LEN];
+        let plaintext: Vec<u8> = (0..70_000u32).map(|i| (i % 251) as u8).collect();
+
+        let mut buf = plaintext.clone();
+        let tag = kernel.encrypt(&nonce, b"header", &mut buf).unwrap();
+        let mut expected = plaintext.clone();
+        let expected_tag = software
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), b"header", &mut expected)
+            .unwrap();
+        assert_eq!(buf, expected);
+        assert_eq!(tag.as_slice(), expected_tag.as_slice());
+
+        assert!(kernel.decrypt(&nonce, b"header", &mut buf, &tag).unwrap());
+        assert_eq!(buf, plaintext);
+
+        let mut tampered = expected.clone();
+        tampered[0] ^= 1;
+        assert!(!kernel.decrypt(&nonce, b"header", &mut tampered, &tag).unwrap());
+        software
+            .decrypt_in_place_detached(
+                Nonce::from_slice(&nonce),
+                b"header",
+                &mut expected,
+                Tag::from_slice(&tag),
+            )
+            .unwrap();
+    }
+}
@@ -0,0 +1,1436 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::Serialize;
+
+use crate::cancel;
+use crate::decrypt::{self, DecryptError, DecryptOptions, PayloadReader, RestoreOptions};
+use crate::header::{self, Extension, EXT_ARCHIVE};
+use crate::kdf::{KdfLimits, KeyCache};
+use crate::metadata::{self, Metadata, Timestamp};
+use crate::progress;
+use crate::temp;
+
+/// Archive format version stored in `EXT_ARCHIVE`.
+pub const ARCHIVE_VERSION: u8 = 1;
+
+/// The archive stream ends with the index length (uint64 BE) and this
+/// magic, so readers can find the index from the end of the plaintext.
+const TRAILER_MAGIC: &[u8; 8] = b"GTKRARC1";
+const TRAILER_LEN: u64 = 16;
+
+/// Index record tags. Each entry is a TLV area in the same encoding as the
+/// header extensions; unknown tags are ignored.
+const ENTRY_PATH: u16 = 0x0001;
+const ENTRY_KIND: u16 = 0x0002;
+const ENTRY_MODE: u16 = 0x0003;
+const ENTRY_OFFSET: u16 = 0x0004;
+const ENTRY_SIZE: u16 = 0x0005;
+const ENTRY_HASH: u16 = 0x0006;
+const ENTRY_LINK_TARGET: u16 = 0x0007;
+const ENTRY_MTIME: u16 = 0x0008;
+const ENTRY_OWNER: u16 = 0x0009;
+const ENTRY_ATTRIBUTES: u16 = 0x000A;
+
+/// Length of the per-entry BLAKE3 content hash.
+const ENTRY_HASH_LEN: usize = 32;
+
+/// Kind of an archive entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    /// Another name for a file stored earlier in the archive.
+    HardLink,
+}
+
+impl EntryKind {
+    fn code(self) -> u8 {
+        match self {
+            EntryKind::File => 0,
+            EntryKind::Directory => 1,
+            EntryKind::Symlink => 2,
+            EntryKind::HardLink => 3,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(EntryKind::File),
+            1 => Some(EntryKind::Directory),
+            2 => Some(EntryKind::Symlink),
+            3 => Some(EntryKind::HardLink),
+            _ => None,
+        }
+    }
+}
+
+/// One entry of the archive index.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ArchiveEntry {
+    /// Path relative to the archived directory, with `/` separators.
+    pub path: String,
+    pub kind: EntryKind,
+    pub mode: Option<u32>,
+    /// Content length; zero for directories.
+    pub size: u64,
+    /// Offset of the content in the archive stream.
+    #[serde(skip)]
+    pub offset: u64,
+    /// BLAKE3 hash of the content.
+    #[serde(skip)]
+    pub hash: [u8; ENTRY_HASH_LEN],
+    /// Target of a symbolic link, exactly as it was read, or the archive
+    /// path of the file a hard link shares its data with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<Timestamp>,
+    /// Owner as (uid, gid).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<(u32, u32)>,
+    /// Windows attributes (see [`metadata::ATTRIBUTE_MASK`]).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<u32>,
+}
+
+impl ArchiveEntry {
+    fn new(path: String, kind: EntryKind) -> Self {
+        ArchiveEntry {
+            path,
+            kind,
+            mode: None,
+            size: 0,
+            offset: 0,
+            hash: [0u8; ENTRY_HASH_LEN],
+            link_target: None,
+            mtime: None,
+            owner: None,
+            attributes: None,
+        }
+    }
+}
+
+/// What to do with symbolic links found while archiving a directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Archive the link itself, restored as a link on extraction.
+    #[default]
+    Store,
+    /// Archive whatever the link points to in its place.
+    Follow,
+    /// Leave the link out and emit a warning.
+    Skip,
+}
+
+/// Parse a `--symlinks` value.
+pub fn parse_symlink_policy(arg: &str) -> Result<SymlinkPolicy, String> {
+    match arg {
+        "store" => Ok(SymlinkPolicy::Store),
+        "follow" => Ok(SymlinkPolicy::Follow),
+        "skip" => Ok(SymlinkPolicy::Skip),
+        _ => Err(format!("expected store, follow or skip, got '{}'", arg)),
+    }
+}
+
+/// Encode the index: an entry count (uint32 BE), then one TLV area per
+/// entry.
+fn encode_index(entries: &[ArchiveEntry]) -> Vec<u8> {
+    let mut index = (entries.len() as u32).to_be_bytes().to_vec();
+    for entry in entries {
+        let mut records = vec![
+            Extension {
+                tag: ENTRY_PATH,
+                value: entry.path.as_bytes().to_vec(),
+            },
+            Extension {
+                tag: ENTRY_KIND,
+                value: vec![entry.kind.code()],
+            },
+        ];
+        if let Some(mode) = entry.mode {
+            records.push(Extension {
+                tag: ENTRY_MODE,
+                value: mode.to_be_bytes().to_vec(),
+            });
+        }
+        if let Some(ref target) = entry.link_target {
+            records.push(Extension {
+                tag: ENTRY_LINK_TARGET,
+                value: target.as_bytes().to_vec(),
+            });
+        }
+        if let Some(mtime) = entry.mtime {
+            records.push(Extension {
+                tag: ENTRY_MTIME,
+                value: mtime.to_bytes().to_vec(),
+            });
+        }
+        if let Some((uid, gid)) = entry.owner {
+            let mut value = uid.to_be_bytes().to_vec();
+            value.extend_from_slice(&gid.to_be_bytes());
+            records.push(Extension {
+                tag: ENTRY_OWNER,
+                value,
+            });
+        }
+        if let Some(attributes) = entry.attributes {
+            records.push(Extension {
+                tag: ENTRY_ATTRIBUTES,
+                value: attributes.to_be_bytes().to_vec(),
+            });
+        }
+        if entry.kind == EntryKind::File {
+            records.push(Extension {
+                tag: ENTRY_OFFSET,
+                value: entry.offset.to_be_bytes().to_vec(),
+            });
+            records.push(Extension {
+                tag: ENTRY_SIZE,
+                value: entry.size.to_be_bytes().to_vec(),
+            });
+            records.push(Extension {
+                tag: ENTRY_HASH,
+                value: entry.hash.to_vec(),
+            });
+        }
+        index.extend_from_slice(&header::encode_extensions(&records));
+    }
+    index
+}
+
+/// Decode and validate the index of an archive whose file data occupies
+/// the first `data_len` bytes of the stream.
+fn decode_index(data: &[u8], data_len: u64) -> Result<Vec<ArchiveEntry>, String> {
+    let malformed = || "Archive index is malformed".to_string();
+    let count = u32::from_be_bytes(data.get(..4).ok_or_else(malformed)?.try_into().unwrap());
+    let mut entries = Vec::new();
+    let mut pos = 4;
+    for _ in 0..count {
+        let len_bytes = data.get(pos..pos + 4).ok_or_else(malformed)?;
+        let len = u32::from_be_bytes(len_bytes.try_into().unwrap()) as usize;
+        pos += 4;
+        let body = data.get(pos..pos + len).ok_or_else(malformed)?;
+        pos += len;
+        let records = header::decode_extensions(body).map_err(|_| malformed())?;
+        entries.push(decode_entry(&records, data_len)?);
+    }
+    if pos != data.len() {
+        return Err(malformed());
+    }
+
+    // A hard link must name a file that comes before it
+    let mut files = HashSet::new();
+    for entry in &entries {
+        match (entry.kind, &entry.link_target) {
+            (EntryKind::File, _) => {
+                files.insert(entry.path.as_str());
+            }
+            (EntryKind::HardLink, Some(target)) if !files.contains(target.as_str()) => {
+                return Err(format!(
+                    "Hard link '{}' does not point to an earlier file",
+                    entry.path
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    // Extraction must never write through a link it has just created
+    let links: HashSet<&str> = entries
+        .iter()
+        .filter(|e| e.kind == EntryKind::Symlink)
+        .map(|e| e.path.as_str())
+        .collect();
+    for entry in &entries {
+        let mut path = entry.path.as_str();
+        while let Some((parent, _)) = path.rsplit_once('/') {
+            if links.contains(parent) {
+                return Err(format!(
+                    "Archive entry '{}' lies beneath a symbolic link",
+                    entry.path
+                ));
+            }
+            path = parent;
+        }
+    }
+    Ok(entries)
+}
+
+fn decode_entry(records: &[Extension], data_len: u64) -> Result<ArchiveEntry, String> {
+    let field = |tag: u16| records.iter().find(|r| r.tag == tag).map(|r| r.value.as_slice());
+    let u64_field = |tag: u16| -> Result<u64, String> {
+        field(tag)
+            .and_then(|v| v.try_into().ok())
+            .map(u64::from_be_bytes)
+            .ok_or_else(|| "Archive entry is missing its location".to_string())
+    };
+
+    let path = field(ENTRY_PATH)
+        .and_then(|v| std::str::from_utf8(v).ok())
+        .ok_or_else(|| "Archive entry has no valid path".to_string())?;
+    if !is_safe_path(path) {
+        return Err(format!("Archive entry has an unsafe path: {:?}", path));
+    }
+    let kind = match field(ENTRY_KIND) {
+        Some([code]) => EntryKind::from_code(*code),
+        _ => None,
+    }
+    .ok_or_else(|| format!("Archive entry '{}' has an unknown kind", path))?;
+    let mode = match field(ENTRY_MODE) {
+        Some(v) => Some(u32::from_be_bytes(
+            v.try_into().map_err(|_| format!("Archive entry '{}' has a bad mode", path))?,
+        )),
+        None => None,
+    };
+
+    let mut entry = ArchiveEntry::new(path.to_string(), kind);
+    entry.mode = mode;
+    if let Some(v) = field(ENTRY_MTIME) {
+        entry.mtime = Some(
+            Timestamp::from_bytes(v)
+                .ok_or_else(|| format!("Archive entry '{}' has a bad timestamp", path))?,
+        );
+    }
+    if let Some(v) = field(ENTRY_OWNER) {
+        let bytes: [u8; 8] = v
+            .try_into()
+            .map_err(|_| format!("Archive entry '{}' has a bad owner", path))?;
+        entry.owner = Some((
+            u32::from_be_bytes(bytes[..4].try_into().unwrap()),
+            u32::from_be_bytes(bytes[4..].try_into().unwrap()),
+        ));
+    }
+    if let Some(v) = field(ENTRY_ATTRIBUTES) {
+        let bytes: [u8; 4] = v
+            .try_into()
+            .map_err(|_| format!("Archive entry '{}' has bad attributes", path))?;
+        entry.attributes = Some(u32::from_be_bytes(bytes) & metadata::ATTRIBUTE_MASK);
+    }
+    if kind == EntryKind::Symlink {
+        let target = field(ENTRY_LINK_TARGET)
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .filter(|t| !t.is_empty() && !t.contains('\0'))
+            .ok_or_else(|| format!("Archive entry '{}' has no valid link target", path))?;
+        entry.link_target = Some(target.to_string());
+    }
+    if kind == EntryKind::HardLink {
+        let target = field(ENTRY_LINK_TARGET)
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .ok_or_else(|| format!("Archive entry '{}' has no valid link target", path))?;
+        entry.link_target = Some(target.to_string());
+    }
+    if kind == EntryKind::File {
+        entry.offset = u64_field(ENTRY_OFFSET)?;
+        entry.size = u64_field(ENTRY_SIZE)?;
+        if entry.offset.checked_add(entry.size).is_none_or(|end| end > data_len) {
+            return Err(format!("Archive entry '{}' lies outside the archive", path));
+        }
+        entry.hash = field(ENTRY_HASH)
+            .and_then(|v| v.try_into().ok())
+            .ok_or_else(|| format!("Archive entry '{}' has no content hash", path))?;
+    }
+    Ok(entry)
+}
+
+/// True for a relative path made only of normal components, so joining it
+/// onto an output directory cannot escape that directory.
+fn is_safe_path(path: &str) -> bool {
+    !path.is_empty()
+        && !path.contains(['\0', '\\'])
+        && path
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..")
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Include and exclude glob patterns applied while walking a directory.
+///
+/// A pattern without a `/` is matched against each entry name at any depth
+/// (`node_modules`, `*.log`); one with a `/` is matched against the path
+/// relative to the archived directory (`build/*.o`, `**/cache`). `*` never
+/// crosses a `/`. Excluded directories are not descended into. With include
+/// patterns, only matching files and the contents of matching directories
+/// are archived, along with the directories leading to them.
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, String> {
+        Ok(PathFilter {
+            include: build_glob_set(include)?,
+            exclude: build_glob_set(exclude)?,
+        })
+    }
+
+    fn excludes(&self, path: &str, name: &str) -> bool {
+        self.exclude
+            .as_ref()
+            .is_some_and(|set| set.is_match(name) || set.is_match(path))
+    }
+
+    fn includes(&self, path: &str, name: &str) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|set| set.is_match(name) || set.is_match(path))
+    }
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<Option<GlobSet>, String> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        // A trailing slash only marks a directory pattern for the reader
+        let trimmed = pattern.trim_end_matches('/');
+        let glob = GlobBuilder::new(trimmed)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| format!("Invalid pattern '{}': {}", pattern, e))?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map(Some)
+        .map_err(|e| format!("Invalid patterns: {}", e))
+}
+
+/// Scan `root` for archive entries, paired with their source paths.
+fn scan(
+    root: &Path,
+    filter: &PathFilter,
+    symlinks: SymlinkPolicy,
+) -> io::Result<Vec<(ArchiveEntry, PathBuf)>> {
+    let mut walker = Walker {
+        filter,
+        symlinks,
+        ancestors: Vec::new(),
+        inodes: HashMap::new(),
+        found: Vec::new(),
+    };
+    walker.walk(root, "", false)?;
+    Ok(walker.found)
+}
+
+struct Walker<'a> {
+    filter: &'a PathFilter,
+    symlinks: SymlinkPolicy,
+    /// Canonical paths of the directories being walked, to catch loops
+    /// when following symlinks.
+    ancestors: Vec<PathBuf>,
+    /// Archive path of the first file seen for each multiply-linked inode.
+    inodes: HashMap<(u64, u64), String>,
+    found: Vec<(ArchiveEntry, PathBuf)>,
+}
+
+impl Walker<'_> {
+    /// Collect the entries below `dir` in a stable, depth-first order, each
+    /// directory before its contents. `included` is set once an enclosing
+    /// directory has matched an include pattern.
+    fn walk(&mut self, dir: &Path, prefix: &str, included: bool) -> io::Result<()> {
+        let following = self.symlinks == SymlinkPolicy::Follow;
+        if following {
+            let canonical = fs::canonicalize(dir)?;
+            if self.ancestors.contains(&canonical) {
+                return Err(io::Error::other(format!(
+                    "Symbolic link loop at {}",
+                    dir.display()
+                )));
+            }
+            self.ancestors.push(canonical);
+        }
+
+        let mut children = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+        children.sort_by_key(|child| child.file_name());
+
+        for child in children {
+            let name = child.file_name().into_string().map_err(|name| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("File name is not valid UTF-8: {:?}", name),
+                )
+            })?;
+            let path = if prefix.is_empty() {
+                name.clone()
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            if self.filter.excludes(&path, &name) {
+                continue;
+            }
+            let matched = included || self.filter.includes(&path, &name);
+
+            // DirEntry::metadata does not follow symlinks
+            let mut metadata = child.metadata()?;
+            let mut link_target = None;
+            if metadata.file_type().is_symlink() {
+                match self.symlinks {
+                    SymlinkPolicy::Store => link_target = Some(read_link_target(&child.path())?),
+                    SymlinkPolicy::Follow => match fs::metadata(child.path()) {
+                        Ok(target) => metadata = target,
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                            progress::emit_warning(
+                                "symlink_skipped",
+                                &format!("Skipped dangling symbolic link {}", path),
+                            );
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    },
+                    SymlinkPolicy::Skip => {
+                        if matched {
+                            progress::emit_warning(
+                                "symlink_skipped",
+                                &format!("Skipped symbolic link {}", path),
+                            );
+                        }
+                        continue;
+                    }
+                }
+            }
+
+            let mut entry = ArchiveEntry::new(path, EntryKind::File);
+            entry.owner = metadata::file_owner(&metadata);
+            entry.attributes = metadata::file_attributes(&metadata);
+            if link_target.is_some() {
+                if matched {
+                    entry.kind = EntryKind::Symlink;
+                    entry.link_target = link_target;
+                    self.found.push((entry, child.path()));
+                }
+            } else if metadata.is_dir() {
+                entry.kind = EntryKind::Directory;
+                entry.mode = file_mode(&metadata);
+                entry.mtime = metadata.modified().ok().map(Timestamp::from_system_time);
+                let prefix = entry.path.clone();
+                let mark = self.found.len();
+                self.found.push((entry, child.path()));
+                self.walk(&child.path(), &prefix, matched)?;
+                // Drop directories that only lead to filtered-out entries
+                if !matched && self.found.len() == mark + 1 {
+                    self.found.pop();
+                }
+            } else if metadata.is_file() && matched {
+                if let Some(id) = hard_link_id(&metadata) {
+                    if let Some(first) = self.inodes.get(&id) {
+                        entry.kind = EntryKind::HardLink;
+                        entry.link_target = Some(first.clone());
+                        self.found.push((entry, child.path()));
+                        continue;
+                    }
+                    self.inodes.insert(id, entry.path.clone());
+                }
+                entry.mode = file_mode(&metadata);
+                entry.mtime = metadata.modified().ok().map(Timestamp::from_system_time);
+                entry.size = metadata.len();
+                self.found.push((entry, child.path()));
+            }
+            // Sockets, FIFOs and devices are not archived
+        }
+
+        if following {
+            self.ancestors.pop();
+        }
+        Ok(())
+    }
+}
+
+/// Device and inode of a file with more than one link.
+#[cfg(unix)]
+fn hard_link_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn hard_link_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+fn read_link_target(path: &Path) -> io::Result<String> {
+    fs::read_link(path)?.into_os_string().into_string().map_err(|target| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Symbolic link target is not valid UTF-8: {:?}", target),
+        )
+    })
+}
+
+/// A directory read as an archive stream: the contents of every file in
+/// index order, then the index and the trailer.
+///
+/// The tree is scanned up front so the stream length is known before
+/// encryption starts. Files are hashed as they stream past and must still
+/// have the size they had when scanned.
+pub struct ArchiveReader {
+    entries: Vec<ArchiveEntry>,
+    sources: Vec<PathBuf>,
+    /// Index of the next entry to consider opening.
+    next: usize,
+    current: Option<OpenEntry>,
+    /// Index and trailer, once every file has been read.
+    tail: Option<Cursor<Vec<u8>>>,
+    len: u64,
+}
+
+struct OpenEntry {
+    index: usize,
+    file: fs::File,
+    remaining: u64,
+    hasher: blake3::Hasher,
+}
+
+impl ArchiveReader {
+    pub fn new(root: &Path, filter: &PathFilter, symlinks: SymlinkPolicy) -> io::Result<Self> {
+        let found = scan(root, filter, symlinks)?;
+        let (mut entries, sources): (Vec<_>, Vec<_>) = found.into_iter().unzip();
+
+        let mut offset = 0u64;
+        for entry in entries.iter_mut().filter(|e| e.kind == EntryKind::File) {
+            entry.offset = offset;
+            offset += entry.size;
+        }
+        // Hashes have a fixed size, so the placeholders give the final length
+        let len = offset + encode_index(&entries).len() as u64 + TRAILER_LEN;
+
+        Ok(ArchiveReader {
+            entries,
+            sources,
+            next: 0,
+            current: None,
+            tail: None,
+            len,
+        })
+    }
+
+    /// Total length of the archive stream.
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    fn changed(&self, index: usize) -> io::Error {
+        io::Error::other(format!(
+            "{} changed while it was being archived",
+            self.sources[index].display()
+        ))
+    }
+}
+
+impl Read for ArchiveReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(ref mut tail) = self.tail {
+                return tail.read(buf);
+            }
+
+            if let Some(ref mut open) = self.current {
+                let index = open.index;
+                if open.remaining > 0 {
+                    let want = std::cmp::min(buf.len() as u64, open.remaining) as usize;
+                    let n = open.file.read(&mut buf[..want])?;
+                    if n == 0 {
+                        return Err(self.changed(index));
+                    }
+                    open.hasher.update(&buf[..n]);
+                    open.remaining -= n as u64;
+                    return Ok(n);
+                }
+                // The file must end where the scan said it would
+                if open.file.read(&mut [0u8; 1])? != 0 {
+                    return Err(self.changed(index));
+                }
+                self.entries[index].hash = *open.hasher.finalize().as_bytes();
+                self.current = None;
+                continue;
+            }
+
+            let next_file = (self.next..self.entries.len())
+                .find(|&i| self.entries[i].kind == EntryKind::File);
+            match next_file {
+                Some(index) => {
+                    self.current = Some(OpenEntry {
+                        index,
+                        file: fs::File::open(&self.sources[index])?,
+                        remaining: self.entries[index].size,
+                        hasher: blake3::Hasher::new(),
+                    });
+                    self.next = index + 1;
+                }
+                None => {
+                    let mut tail = encode_index(&self.entries);
+                    tail.extend_from_slice(&(tail.len() as u64).to_be_bytes());
+                    tail.extend_from_slice(TRAILER_MAGIC);
+                    self.tail = Some(Cursor::new(tail));
+                }
+            }
+        }
+    }
+}
+
+/// Open an archive container and read its index from the end of the
+/// payload, decrypting only the chunks that hold it.
+pub fn open_archive(
+    input_path: &str,
+    passphrase: &[u8],
+    keys: &KeyCache,
+    limits: &KdfLimits,
+) -> Result<(Metadata, PayloadReader, Vec<ArchiveEntry>), DecryptError> {
+    let (header_obj, file_metadata, mut payload) =
+        decrypt::open_payload_cached(input_path, passphrase, keys, limits)?;
+    match header_obj.extension(EXT_ARCHIVE) {
+        Some([ARCHIVE_VERSION]) => {}
+        Some(_) => {
+            return Err(DecryptError::Internal(
+                "Unsupported archive format version".to_string(),
+            ))
+        }
+        None => {
+            return Err(DecryptError::Internal(
+                "Container does not hold a directory archive".to_string(),
+            ))
+        }
+    }
+
+    let stream_len = file_metadata.original_file_size;
+    if stream_len < TRAILER_LEN {
+        return Err(DecryptError::CorruptFile("Archive is too short".to_string()));
+    }
+    let mut trailer = [0u8; TRAILER_LEN as usize];
+    read_at(&mut payload, stream_len - TRAILER_LEN, &mut trailer)?;
+    if &trailer[8..] != TRAILER_MAGIC {
+        return Err(DecryptError::CorruptFile("Archive trailer is damaged".to_string()));
+    }
+    let index_len = u64::from_be_bytes(trailer[..8].try_into().unwrap());
+    let data_len = (stream_len - TRAILER_LEN)
+        .checked_sub(index_len)
+        .ok_or_else(|| DecryptError::CorruptFile("Archive index is too long".to_string()))?;
+
+    let mut index = vec![0u8; index_len as usize];
+    read_at(&mut payload, data_len, &mut index)?;
+    let entries = decode_index(&index, data_len).map_err(DecryptError::CorruptFile)?;
+    Ok((file_metadata, payload, entries))
+}
+
+fn read_at(payload: &mut PayloadReader, offset: u64, buf: &mut [u8]) -> Result<(), DecryptError> {
+    payload.seek_plaintext(offset)?;
+    payload.read_exact(buf).map_err(|e| payload_error(payload, e))
+}
+
+fn payload_error(payload: &mut PayloadReader, e: io::Error) -> DecryptError {
+    payload.take_error().unwrap_or_else(|| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            DecryptError::CorruptFile("Archive ends early".to_string())
+        } else {
+            DecryptError::Internal(format!("Failed to read archive: {}", e))
+        }
+    })
+}
+
+fn write_error(path: &Path) -> impl Fn(io::Error) -> DecryptError + '_ {
+    move |e| {
+        if e.kind() == io::ErrorKind::PermissionDenied {
+            DecryptError::Permission(format!("Cannot write {}: {}", path.display(), e))
+        } else {
+            DecryptError::Internal(format!("Failed to write {}: {}", path.display(), e))
+        }
+    }
+}
+
+/// Write archive entries below the existing directory `output_dir`.
+///
+/// `select` limits extraction to the named paths and everything below
+/// them; an empty list extracts everything. Each file is checked against
+/// its index hash. A hard link becomes a link to its file when that file
+/// is extracted too, and a copy of it otherwise. Symbolic links are
+/// created once every file is written, and directory modes are applied
+/// last so read-only directories can still be filled.
+pub fn extract(
+    payload: &mut PayloadReader,
+    entries: &[ArchiveEntry],
+    output_dir: &Path,
+    select: &[String],
+    restore: RestoreOptions,
+) -> Result<(), DecryptError> {
+    Extractor::new(payload, restore).extract(entries, output_dir, select)
+}
+
+/// State shared by the entries of one extraction.
+struct Extractor<'a> {
+    payload: &'a mut PayloadReader,
+    restore: RestoreOptions,
+    buf: Vec<u8>,
+    done: u64,
+    total: u64,
+    /// Set once an owner could not be restored, so the warning is given
+    /// only once.
+    owner_denied: bool,
+}
+
+impl<'a> Extractor<'a> {
+    fn new(payload: &'a mut PayloadReader, restore: RestoreOptions) -> Self {
+        Extractor {
+            payload,
+            restore,
+            buf: vec![0u8; header::CHUNK_SIZE],
+            done: 0,
+            total: 0,
+            owner_denied: false,
+        }
+    }
+
+    fn extract(
+        &mut self,
+        entries: &[ArchiveEntry],
+        output_dir: &Path,
+        select: &[String],
+    ) -> Result<(), DecryptError> {
+        let mut selected: Vec<&ArchiveEntry> = Vec::new();
+        for entry in entries {
+            let wanted = select.is_empty()
+                || select.iter().any(|name| {
+                    let name = name.trim_end_matches('/');
+                    entry.path == name || entry.path.starts_with(&format!("{}/", name))
+                });
+            if wanted {
+                selected.push(entry);
+            }
+        }
+        for name in select {
+            let name = name.trim_end_matches('/');
+            if !entries.iter().any(|e| e.path == name) {
+                return Err(DecryptError::Internal(format!(
+                    "No entry named '{}' in the archive",
+                    name
+                )));
+            }
+        }
+
+        // decode_index guarantees every hard link names an earlier file
+        let linked_file = |entry: &ArchiveEntry| {
+            entries
+                .iter()
+                .find(|e| Some(&e.path) == entry.link_target.as_ref() && e.kind == EntryKind::File)
+        };
+        let selected_paths: HashSet<&str> = selected.iter().map(|e| e.path.as_str()).collect();
+        self.total = selected
+            .iter()
+            .map(|e| match (e.kind, linked_file(e)) {
+                (EntryKind::HardLink, Some(file))
+                    if !selected_paths.contains(file.path.as_str()) =>
+                {
+                    file.size
+                }
+                _ => e.size,
+            })
+            .sum();
+        progress::emit_progress("extract", 0, self.total);
+
+        let mut directories = Vec::new();
+        let mut links = Vec::new();
+        for entry in selected {
+            let target = output_dir.join(&entry.path);
+            if entry.kind == EntryKind::Directory {
+                fs::create_dir_all(&target).map_err(write_error(&target))?;
+                directories.push((target, entry));
+                continue;
+            }
+
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).map_err(write_error(parent))?;
+            }
+            match (entry.kind, &entry.link_target) {
+                (EntryKind::Symlink, Some(_)) => links.push((target, entry)),
+                (EntryKind::HardLink, _) => {
+                    let file = linked_file(entry).ok_or_else(|| {
+                        DecryptError::CorruptFile(format!(
+                            "Hard link '{}' has no file to point to",
+                            entry.path
+                        ))
+                    })?;
+                    if selected_paths.contains(file.path.as_str()) {
+                        let original = output_dir.join(&file.path);
+                        fs::hard_link(&original, &target).map_err(write_error(&target))?;
+                    } else {
+                        self.file(file, &target)?;
+                    }
+                }
+                _ => self.file(entry, &target)?,
+            }
+        }
+
+        for (target, entry) in links {
+            create_symlink(entry.link_target.as_deref().unwrap_or_default(), &target)?;
+            self.owner(&target, entry.owner)?;
+        }
+        // Creating entries touches each directory, so its time goes on last
+        for (target, entry) in directories.iter().rev() {
+            if self.restore.times {
+                set_dir_mtime(target, entry.mtime)?;
+            }
+            self.owner(target, entry.owner)?;
+            set_mode(target, self.restore.mode(entry.mode, true))?;
+            set_attributes(target, self.restore.attributes(entry.attributes))?;
+        }
+        progress::emit_progress("extract", self.total, self.total);
+        Ok(())
+    }
+
+    /// Write the content of a file entry to `target` and check it against
+    /// the index hash.
+    fn file(&mut self, entry: &ArchiveEntry, target: &Path) -> Result<(), DecryptError> {
+        let mut file = fs::File::create(target).map_err(write_error(target))?;
+        self.payload.seek_plaintext(entry.offset)?;
+        let mut hasher = blake3::Hasher::new();
+        let mut remaining = entry.size;
+        while remaining > 0 {
+            let want = std::cmp::min(remaining, self.buf.len() as u64) as usize;
+            let buf = &mut self.buf[..want];
+            if let Err(e) = self.payload.read_exact(buf) {
+                return Err(payload_error(self.payload, e));
+            }
+            hasher.update(buf);
+            file.write_all(buf).map_err(write_error(target))?;
+            remaining -= want as u64;
+            self.done += want as u64;
+            progress::emit_progress("extract", self.done, self.total);
+        }
+        if self.restore.times {
+            metadata::set_file_times(&file, entry.mtime, None).map_err(write_error(target))?;
+        }
+        drop(file);
+
+        if hasher.finalize() != entry.hash {
+            let _ = fs::remove_file(target);
+            return Err(DecryptError::CorruptFile(format!(
+                "Archive entry '{}' does not match its recorded hash",
+                entry.path
+            )));
+        }
+        // Before the mode, since changing owner clears setuid and setgid bits
+        self.owner(target, entry.owner)?;
+        set_mode(target, self.restore.mode(entry.mode, false))?;
+        set_attributes(target, self.restore.attributes(entry.attributes))
+    }
+
+    fn owner(&mut self, path: &Path, owner: Option<(u32, u32)>) -> Result<(), DecryptError> {
+        let Some(owner) = owner.filter(|_| self.restore.owner) else {
+            return Ok(());
+        };
+        let restored = metadata::restore_owner(path, owner).map_err(write_error(path))?;
+        if !restored && !self.owner_denied {
+            self.owner_denied = true;
+            decrypt::warn_owner_not_restored();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: Option<u32>) -> Result<(), DecryptError> {
+    use std::os::unix::fs::PermissionsExt;
+    match mode {
+        Some(mode) => fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))
+            .map_err(write_error(path)),
+        None => Ok(()),
+    }
+}
+
+/// Set the modification time of a directory whose contents are complete.
+#[cfg(unix)]
+fn set_dir_mtime(path: &Path, mtime: Option<Timestamp>) -> Result<(), DecryptError> {
+    if mtime.is_none() {
+        return Ok(());
+    }
+    let dir = fs::File::open(path).map_err(write_error(path))?;
+    metadata::set_file_times(&dir, mtime, None).map_err(write_error(path))
+}
+
+#[cfg(not(unix))]
+fn set_dir_mtime(_path: &Path, _mtime: Option<Timestamp>) -> Result<(), DecryptError> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(link_target: &str, path: &Path) -> Result<(), DecryptError> {
+    std::os::unix::fs::symlink(link_target, path).map_err(write_error(path))
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_link_target: &str, path: &Path) -> Result<(), DecryptError> {
+    progress::emit_warning(
+        "symlink_not_restored",
+        &format!("Symbolic link {} cannot be created here", path.display()),
+    );
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: Option<u32>) -> Result<(), DecryptError> {
+    Ok(())
+}
+
+fn set_attributes(path: &Path, attributes: Option<u32>) -> Result<(), DecryptError> {
+    match attributes {
+        Some(attributes) => {
+            metadata::set_file_attributes(path, attributes).map_err(write_error(path))
+        }
+        None => Ok(()),
+    }
+}
+
+/// List the entries of an archive container.
+pub fn list(input_path: &str, passphrase: &[u8]) -> Result<Vec<ArchiveEntry>, DecryptError> {
+    open_archive(input_path, passphrase, &KeyCache::default(), &KdfLimits::default())
+        .map(|(_, _, entries)| entries)
+}
+
+/// Render a listing as one line per entry: mode, size and path, with a
+/// trailing `/` on directories and the target of links.
+pub fn listing_text(entries: &[ArchiveEntry]) -> String {
+    entries
+        .iter()
+        .map(|entry| {
+            let mode = entry
+                .mode
+                .map_or_else(|| "----".to_string(), |mode| format!("{:04o}", mode));
+            let suffix = match (entry.kind, &entry.link_target) {
+                (EntryKind::Directory, _) => "/".to_string(),
+                (EntryKind::HardLink, Some(target)) => format!(" link to {}", target),
+                (_, Some(target)) => format!(" -> {}", target),
+                _ => String::new(),
+            };
+            format!("{} {:>12} {}{}", mode, entry.size, entry.path, suffix)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Extract entries of an archive container below `output_dir`, creating
+/// it if needed. Only the chunks holding the index and the selected
+/// entries are decrypted.
+pub fn extract_entries(
+    input_path: &str,
+    passphrase: &[u8],
+    output_dir: &str,
+    select: &[String],
+    restore: RestoreOptions,
+) -> Result<(), DecryptError> {
+    let (_, mut payload, entries) =
+        open_archive(input_path, passphrase, &KeyCache::default(), &KdfLimits::default())?;
+    let output_dir = Path::new(output_dir);
+    fs::create_dir_all(output_dir).map_err(write_error(output_dir))?;
+    extract(&mut payload, &entries, output_dir, select, restore)
+}
+
+/// Decrypt an archive container into a new directory at `output_path`.
+///
+/// Everything is extracted into a temporary directory next to the output,
+/// which only takes its final name once every entry has been verified.
+/// The phases are timed on `timer` for the summary line.
+pub fn decrypt_archive(
+    opts: &DecryptOptions,
+    mut timer: progress::PhaseTimer,
+) -> Result<(), DecryptError> {
+    let (file_metadata, mut payload, entries) = timer.time("kdf", || {
+        open_archive(&opts.input_path, &opts.passphrase, &opts.keys, &opts.kdf_limits)
+    })?;
+    let output_path = opts.resolve_output_path(&file_metadata)?;
+    let _lock = decrypt::lock_output(&output_path)?;
+    let restore = opts.restore;
+
+    let output_dir = output_path.parent().unwrap_or(Path::new("."));
+    // The handle keeps the directory marked as in use until it is renamed
+    let (temp_dir, _handle) = temp::temp_dir_in(output_dir).map_err(|e| {
+        if e.kind() == io::ErrorKind::PermissionDenied {
+            DecryptError::Permission(format!("Cannot write to output directory: {}", e))
+        } else {
+            DecryptError::Internal(format!("Failed to create temp directory: {}", e))
+        }
+    })?;
+
+    let mut extractor = Extractor::new(&mut payload, restore);
+    timer.time("extract", || extractor.extract(&entries, temp_dir.path(), &[]))?;
+    if restore.times {
+        set_dir_mtime(temp_dir.path(), file_metadata.mtime)?;
+    }
+    extractor.owner(temp_dir.path(), file_metadata.owner)?;
+    set_mode(temp_dir.path(), restore.mode(file_metadata.mode, true))?;
+    set_attributes(temp_dir.path(), restore.attributes(file_metadata.attributes))?;
+
+    let temp_path = temp_dir.keep();
+    fs::rename(&temp_path, &output_path).map_err(|e| {
+        let _ = fs::remove_dir_all(&temp_path);
+        if e.kind() == io::ErrorKind::PermissionDenied {
+            DecryptError::Permission(format!("Cannot write to output path: {}", e))
+        } else {
+            DecryptError::Internal(format!("Failed to move archive into place: {}", e))
+        }
+    })?;
+    cancel::untrack(&temp_path);
+
+    let extracted = entries
+        .iter()
+        .filter(|entry| entry.kind == EntryKind::File)
+        .map(|entry| entry.size)
+        .sum();
+    timer.emit(&output_path, payload.container_len(), extracted, payload.chunk_count());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt::DecryptOptions;
+    use crate::encrypt::{self, EncryptOptions};
+
+    const PASSPHRASE: &[u8] = b"archive_pass";
+
+    fn build_tree(root: &Path) {
+        fs::create_dir_all(root.join("docs/empty")).unwrap();
+        fs::write(root.join("readme.txt"), b"top level").unwrap();
+        fs::write(root.join("docs/big.bin"), vec![0x5Au8; 150_000]).unwrap();
+        fs::write(root.join("docs/zero.txt"), b"").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(root.join("readme.txt"), fs::Permissions::from_mode(0o640))
+                .unwrap();
+        }
+    }
+
+    fn encrypt_tree(dir: &Path) -> String {
+        let root = dir.join("project");
+        build_tree(&root);
+        let output_path = dir.join("project.gtkrypt");
+        encrypt::encrypt(&EncryptOptions {
+            input_path: root.to_str().unwrap().to_string(),
+            output_path: output_path.to_str().unwrap().to_string(),
+            passphrase: PASSPHRASE.to_vec(),
+            time_cost: 1,
+            memory_cost_kib: 1024,
+            parallelism: 1,
+            store_filename: true,
+            ..Default::default()
+        })
+        .unwrap();
+        output_path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_index_roundtrip_and_listing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = encrypt_tree(dir.path());
+
+        let (metadata, _, entries) =
+            open_archive(&path, PASSPHRASE, &KeyCache::default(), &KdfLimits::default()).unwrap();
+        assert_eq!(metadata.filename.as_deref(), Some("project"));
+        let paths: Vec<&str> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec!["docs", "docs/big.bin", "docs/empty", "docs/zero.txt", "readme.txt"]
+        );
+        assert_eq!(entries[1].size, 150_000);
+        assert_eq!(entries[1].hash, *blake3::hash(&[0x5Au8; 150_000]).as_bytes());
+        assert_eq!(entries[2].kind, EntryKind::Directory);
+    }
+
+    #[test]
+    fn test_decrypt_restores_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = encrypt_tree(dir.path());
+        let restored = dir.path().join("restored");
+
+        decrypt::decrypt(&DecryptOptions {
+            input_path: path,
+            output_path: restored.to_str().unwrap().to_string(),
+            passphrase: PASSPHRASE.to_vec(),
+            restore: RestoreOptions {
+                times: true,
+                owner: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(fs::read(restored.join("readme.txt")).unwrap(), b"top level");
+        let source = dir.path().join("project");
+        for path in ["", "docs", "docs/big.bin"] {
+            let mtime = |root: &Path| fs::metadata(root.join(path)).unwrap().modified().unwrap();
+            assert_eq!(mtime(&restored), mtime(&source), "{:?}", path);
+        }
+        assert_eq!(fs::read(restored.join("docs/big.bin")).unwrap().len(), 150_000);
+        assert_eq!(fs::read(restored.join("docs/zero.txt")).unwrap(), b"");
+        assert!(restored.join("docs/empty").is_dir());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            use std::os::unix::fs::MetadataExt;
+            let restored_file = fs::metadata(restored.join("readme.txt")).unwrap();
+            assert_eq!(restored_file.permissions().mode() & 0o777, 0o640);
+            // Restoring our own uid and gid always succeeds
+            let source_file = fs::metadata(source.join("readme.txt")).unwrap();
+            assert_eq!(
+                (restored_file.uid(), restored_file.gid()),
+                (source_file.uid(), source_file.gid())
+            );
+        }
+    }
+
+    #[test]
+    fn test_extract_selected_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = encrypt_tree(dir.path());
+        let out = dir.path().join("out");
+        fs::create_dir(&out).unwrap();
+
+        let (_, mut payload, entries) =
+            open_archive(&path, PASSPHRASE, &KeyCache::default(), &KdfLimits::default()).unwrap();
+        let select = ["docs/zero.txt".to_string()];
+        extract(&mut payload, &entries, &out, &select, Default::default()).unwrap();
+        assert!(out.join("docs/zero.txt").is_file());
+        assert!(!out.join("readme.txt").exists());
+
+        let select = ["nope".to_string()];
+        let missing = extract(&mut payload, &entries, &out, &select, Default::default());
+        assert!(matches!(missing, Err(DecryptError::Internal(_))));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let fixed = dir.path().join("fixed");
+            let restore = RestoreOptions {
+                permissions: decrypt::PermissionPolicy::Mode(0o600),
+                ..Default::default()
+            };
+            extract(&mut payload, &entries, &fixed, &select[..0], restore).unwrap();
+            let mode = |path: &str| {
+                fs::metadata(fixed.join(path)).unwrap().permissions().mode() & 0o7777
+            };
+            assert_eq!(mode("readme.txt"), 0o600);
+            assert_eq!(mode("docs"), 0o700);
+        }
+    }
+
+    fn walked_paths(root: &Path, include: &[&str], exclude: &[&str]) -> Vec<String> {
+        let to_vec = |p: &[&str]| p.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let filter = PathFilter::new(&to_vec(include), &to_vec(exclude)).unwrap();
+        let found = scan(root, &filter, SymlinkPolicy::Store).unwrap();
+        found.into_iter().map(|(entry, _)| entry.path).collect()
+    }
+
+    #[test]
+    fn test_walk_applies_include_and_exclude_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for path in ["src/main.rs", "src/cache/x.bin", "node_modules/pkg/index.js", "build/a.o"] {
+            fs::create_dir_all(root.join(path).parent().unwrap()).unwrap();
+            fs::write(root.join(path), b"x").unwrap();
+        }
+        fs::write(root.join("debug.log"), b"x").unwrap();
+
+        assert_eq!(
+            walked_paths(root, &[], &["node_modules/", "*.log", "src/cache"]),
+            vec!["build", "build/a.o", "src", "src/main.rs"]
+        );
+        // "*" does not cross directories
+        assert_eq!(
+            walked_paths(root, &["src/*"], &[]),
+            vec!["src", "src/cache", "src/cache/x.bin", "src/main.rs"]
+        );
+        assert_eq!(walked_paths(root, &["*.rs"], &["src"]), Vec::<String>::new());
+        assert_eq!(walked_paths(root, &["*.o"], &[]), vec!["build", "build/a.o"]);
+        assert!(PathFilter::new(&["[".to_string()], &[]).is_err());
+    }
+
+    #[test]
+    fn test_decode_index_rejects_unsafe_paths() {
+        for path in ["../escape", "/etc/passwd", "a//b", "a/./b", "a\\b", ""] {
+            let entry = ArchiveEntry::new(path.to_string(), EntryKind::Directory);
+            assert!(decode_index(&encode_index(&[entry]), 0).is_err(), "{:?}", path);
+        }
+
+        let entry = ArchiveEntry {
+            path: "big.bin".to_string(),
+            kind: EntryKind::File,
+            mode: Some(0o644),
+            size: 10,
+            offset: 5,
+            hash: [7u8; ENTRY_HASH_LEN],
+            link_target: None,
+            mtime: Some(Timestamp {
+                secs: 1_700_000_000,
+                nanos: 42,
+            }),
+            owner: Some((0, 0)),
+            attributes: Some(0x01),
+        };
+        let index = encode_index(std::slice::from_ref(&entry));
+        assert_eq!(decode_index(&index, 15).unwrap(), vec![entry]);
+        assert!(decode_index(&index, 14).is_err());
+    }
+
+    #[test]
+    fn test_decode_index_rejects_entries_beneath_links() {
+        let mut link = ArchiveEntry::new("etc".to_string(), EntryKind::Symlink);
+        link.link_target = Some("/etc".to_string());
+        let beneath = ArchiveEntry::new("etc/cron.d".to_string(), EntryKind::Directory);
+        assert!(decode_index(&encode_index(&[link.clone()]), 0).is_ok());
+        assert!(decode_index(&encode_index(&[link, beneath]), 0).is_err());
+
+        let no_target = ArchiveEntry::new("dangling".to_string(), EntryKind::Symlink);
+        assert!(decode_index(&encode_index(&[no_target]), 0).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_policies() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("tree");
+        fs::create_dir_all(root.join("real")).unwrap();
+        fs::write(root.join("real/data.txt"), b"linked data").unwrap();
+        symlink("real", root.join("alias")).unwrap();
+        symlink("missing", root.join("dangling")).unwrap();
+
+        let filter = PathFilter::new(&[], &[]).unwrap();
+        let kinds = |policy| -> Vec<(String, EntryKind)> {
+            scan(&root, &filter, policy)
+                .unwrap()
+                .into_iter()
+                .map(|(entry, _)| (entry.path, entry.kind))
+                .collect()
+        };
+
+        let stored = scan(&root, &filter, SymlinkPolicy::Store).unwrap();
+        assert_eq!(stored[0].0.path, "alias");
+        assert_eq!(stored[0].0.link_target.as_deref(), Some("real"));
+        assert_eq!(stored[1].0.link_target.as_deref(), Some("missing"));
+
+        assert_eq!(
+            kinds(SymlinkPolicy::Follow),
+            vec![
+                ("alias".to_string(), EntryKind::Directory),
+                ("alias/data.txt".to_string(), EntryKind::File),
+                ("real".to_string(), EntryKind::Directory),
+                ("real/data.txt".to_string(), EntryKind::File),
+            ]
+        );
+        assert_eq!(
+            kinds(SymlinkPolicy::Skip),
+            vec![
+                ("real".to_string(), EntryKind::Directory),
+                ("real/data.txt".to_string(), EntryKind::File),
+            ]
+        );
+
+        // A link back up the tree is an error rather than endless recursion
+        symlink("..", root.join("real/up")).unwrap();
+        assert!(scan(&root, &filter, SymlinkPolicy::Follow).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stored_symlink_is_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        build_tree(&root);
+        std::os::unix::fs::symlink("docs/big.bin", root.join("shortcut")).unwrap();
+        let output_path = dir.path().join("project.gtkrypt");
+        encrypt::encrypt(&EncryptOptions {
+            input_path: root.to_str().unwrap().to_string(),
+            output_path: output_path.to_str().unwrap().to_string(),
+            passphrase: PASSPHRASE.to_vec(),
+            time_cost: 1,
+            memory_cost_kib: 1024,
+            parallelism: 1,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let out = dir.path().join("out");
+        let input = output_path.to_str().unwrap();
+        extract_entries(input, PASSPHRASE, out.to_str().unwrap(), &[], Default::default())
+            .unwrap();
+        let restored = out.join("shortcut");
+        assert_eq!(fs::read_link(&restored).unwrap(), Path::new("docs/big.bin"));
+        assert_eq!(fs::read(&restored).unwrap().len(), 150_000);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hard_links_are_stored_once() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("photos");
+        fs::create_dir_all(root.join("albums")).unwrap();
+        fs::write(root.join("original.jpg"), vec![0xEEu8; 100_000]).unwrap();
+        fs::hard_link(root.join("original.jpg"), root.join("albums/copy.jpg")).unwrap();
+
+        let filter = PathFilter::new(&[], &[]).unwrap();
+        let reader = ArchiveReader::new(&root, &filter, SymlinkPolicy::Store).unwrap();
+        assert!(reader.size() < 200_000);
+        // The walk reaches albums/ first, so the later name is the link
+        let link = &reader.entries[2];
+        assert_eq!(link.kind, EntryKind::HardLink);
+        assert_eq!(link.link_target.as_deref(), Some("albums/copy.jpg"));
+
+        let output_path = dir.path().join("photos.gtkrypt");
+        encrypt::encrypt(&EncryptOptions {
+            input_path: root.to_str().unwrap().to_string(),
+            output_path: output_path.to_str().unwrap().to_string(),
+            passphrase: PASSPHRASE.to_vec(),
+            time_cost: 1,
+            memory_cost_kib: 1024,
+            parallelism: 1,
+            ..Default::default()
+        })
+        .unwrap();
+        let input = output_path.to_str().unwrap();
+
+        let out = dir.path().join("out");
+        extract_entries(input, PASSPHRASE, out.to_str().unwrap(), &[], Default::default()).unwrap();
+        let original = fs::metadata(out.join("original.jpg")).unwrap();
+        let copy = fs::metadata(out.join("albums/copy.jpg")).unwrap();
+        assert_eq!(original.ino(), copy.ino());
+        assert_eq!(original.nlink(), 2);
+
+        // Without its file, the link is extracted as a copy
+        let partial = dir.path().join("partial");
+        let select = ["original.jpg".to_string()];
+        extract_entries(input, PASSPHRASE, partial.to_str().unwrap(), &select, Default::default())
+            .unwrap();
+        assert_eq!(fs::read(partial.join("original.jpg")).unwrap(), vec![0xEEu8; 100_000]);
+        assert!(!partial.join("albums").exists());
+    }
+
+    #[test]
+    fn test_decode_index_rejects_hard_link_without_file() {
+        let mut link = ArchiveEntry::new("b".to_string(), EntryKind::HardLink);
+        link.link_target = Some("a".to_string());
+        let mut file = ArchiveEntry::new("a".to_string(), EntryKind::File);
+        file.size = 1;
+        assert!(decode_index(&encode_index(&[link.clone(), file.clone()]), 1).is_err());
+        assert!(decode_index(&encode_index(&[file, link]), 1).is_ok());
+    }
+}
@@ -0,0 +1,252 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::decrypt::DecryptError;
+use crate::inspect;
+use crate::kdf;
+use crate::progress;
+
+/// `prev` of the first entry, which has no entry before it.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Longest entry looked for when reading the last one back.
+const MAX_ENTRY_LEN: u64 = 64 * 1024;
+
+/// The `--audit-log` every operation is recorded in.
+static AUDIT_LOG: OnceLock<PathBuf> = OnceLock::new();
+
+/// One line of the audit log. `prev` is the SHA-256 of the line before it,
+/// so that changing, removing or reordering entries breaks the chain from
+/// there on.
+#[derive(Debug, Serialize)]
+struct Entry<'a> {
+    /// UTC, as RFC 3339.
+    time: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uid: Option<u32>,
+    pid: u32,
+    operation: &'a str,
+    /// The input as an absolute path.
+    input: String,
+    /// "ok" or "failed".
+    result: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    prev: String,
+}
+
+/// What `verify-audit-log` reports for an intact log.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct AuditReport {
+    pub entries: u64,
+    /// SHA-256 of the last entry. Kept somewhere the log's writers cannot
+    /// reach, it shows later that nothing was cut off or rewritten since.
+    pub head: String,
+}
+
+/// Record every operation in the audit log at `path` from now on. The log
+/// is only ever appended to, and opened now so that a log that cannot be
+/// written fails the run before it does anything.
+pub fn init(path: &Path) -> io::Result<()> {
+    OpenOptions::new().create(true).append(true).open(path)?;
+    let _ = AUDIT_LOG.set(path.to_path_buf());
+    Ok(())
+}
+
+/// Record `operation` on `input` and its outcome in the audit log, if one
+/// was given. The operation is done by now, so an entry that cannot be
+/// written is reported as a warning.
+pub fn record<E: std::fmt::Display>(operation: &str, input: &str, result: &Result<(), E>) {
+    let Some(path) = AUDIT_LOG.get() else {
+        return;
+    };
+    let entry = Entry {
+        time: inspect::format_unix_time(now()),
+        user: ["USER", "LOGNAME", "USERNAME"].iter().find_map(|var| std::env::var(var).ok()),
+        uid: uid(),
+        pid: std::process::id(),
+        operation,
+        input: std::path::absolute(input).map_or_else(
+            |_| input.to_string(),
+            |path| path.to_string_lossy().into_owned(),
+        ),
+        result: if result.is_ok() { "ok" } else { "failed" },
+        error: result.as_ref().err().map(ToString::to_string),
+        prev: String::new(),
+    };
+    if let Err(e) = append(path, entry) {
+        progress::emit_warning(
+            "audit_log_failed",
+            &format!("Cannot record {} of {} in {}: {}", operation, input, path.display(), e),
+        );
+    }
+}
+
+#[cfg(unix)]
+fn uid() -> Option<u32> {
+    // SAFETY: getuid cannot fail
+    Some(unsafe { libc::getuid() })
+}
+
+#[cfg(not(unix))]
+fn uid() -> Option<u32> {
+    None
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+/// Append `entry` to the log at `path`, chained to its last entry. Runs
+/// recording at the same time take turns through a lock on the log.
+fn append(path: &Path, mut entry: Entry) -> io::Result<()> {
+    let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+    file.lock()?;
+    entry.prev = match last_line(&mut file)? {
+        Some(line) => line_hash(&line),
+        None => GENESIS.to_string(),
+    };
+    let mut line = serde_json::to_vec(&entry)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    file.sync_data()
+}
+
+/// The last line of `file`, without its newline.
+fn last_line(file: &mut File) -> io::Result<Option<Vec<u8>>> {
+    let len = file.seek(SeekFrom::End(0))?;
+    if len == 0 {
+        return Ok(None);
+    }
+    let start = len.saturating_sub(MAX_ENTRY_LEN);
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    if tail.pop().is_some_and(|last| last != b'\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the audit log does not end with a whole entry",
+        ));
+    }
+    match tail.iter().rposition(|&byte| byte == b'\n') {
+        Some(newline) => Ok(Some(tail[newline + 1..].to_vec())),
+        None if start == 0 => Ok(Some(tail)),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "the last audit entry is too long")),
+    }
+}
+
+fn line_hash(line: &[u8]) -> String {
+    kdf::to_hex(&Sha256::digest(line))
+}
+
+/// Check that every entry of the audit log at `path` names the hash of the
+/// entry before it.
+pub fn verify(path: &Path) -> Result<AuditReport, DecryptError> {
+    let file = File::open(path).map_err(|e| {
+        DecryptError::Internal(format!("Cannot open audit log {}: {}", path.display(), e))
+    })?;
+    let mut report = AuditReport {
+        entries: 0,
+        head: GENESIS.to_string(),
+    };
+    for line in BufReader::new(file).split(b'\n') {
+        let line = line
+            .map_err(|e| DecryptError::Internal(format!("Cannot read audit log: {}", e)))?;
+        let number = report.entries + 1;
+        let entry: serde_json::Value = serde_json::from_slice(&line).map_err(|_| {
+            DecryptError::CorruptFile(format!("Audit entry {} is not a JSON object", number))
+        })?;
+        if entry.get("prev").and_then(|prev| prev.as_str()) != Some(&report.head) {
+            return Err(DecryptError::CorruptFile(format!(
+                "Audit entry {} does not follow entry {}: the log was changed",
+                number, report.entries
+            )));
+        }
+        report.entries = number;
+        report.head = line_hash(&line);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(operation: &str) -> Entry<'_> {
+        Entry {
+            time: inspect::format_unix_time(0),
+            user: Some("alice".to_string()),
+            uid: Some(1000),
+            pid: 1,
+            operation,
+            input: "/home/alice/a.txt".to_string(),
+            result: "ok",
+            error: None,
+            prev: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_entries_are_chained() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        append(&path, entry("encrypt")).unwrap();
+        append(&path, entry("decrypt")).unwrap();
+        append(&path, entry("reencrypt")).unwrap();
+
+        let log = std::fs::read(&path).unwrap();
+        let lines: Vec<&[u8]> = log.split_inclusive(|&byte| byte == b'\n').collect();
+        let first: serde_json::Value = serde_json::from_slice(lines[0]).unwrap();
+        assert_eq!(first["prev"], GENESIS);
+        assert_eq!(first["operation"], "encrypt");
+        assert_eq!(first["time"], "1970-01-01T00:00:00Z");
+        let report = verify(&path).unwrap();
+        assert_eq!(report.entries, 3);
+        assert_eq!(report.head, line_hash(lines[2].strip_suffix(b"\n").unwrap()));
+    }
+
+    #[test]
+    fn test_changed_or_removed_entries_break_the_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        for operation in ["encrypt", "decrypt", "encrypt"] {
+            append(&path, entry(operation)).unwrap();
+        }
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+
+        let changed = log.replacen("\"decrypt\"", "\"encrypt\"", 1);
+        std::fs::write(&path, changed).unwrap();
+        assert!(matches!(
+            verify(&path),
+            Err(DecryptError::CorruptFile(msg)) if msg.starts_with("Audit entry 3 ")
+        ));
+
+        let removed = format!("{}\n{}\n", lines[0], lines[2]);
+        std::fs::write(&path, removed).unwrap();
+        assert!(matches!(verify(&path), Err(DecryptError::CorruptFile(_))));
+
+        // Cutting entries off the end is only seen against a kept head
+        let truncated = format!("{}\n{}\n", lines[0], lines[1]);
+        std::fs::write(&path, truncated).unwrap();
+        assert_eq!(verify(&path).unwrap().entries, 2);
+    }
+
+    #[test]
+    fn test_torn_last_entry_is_not_chained_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        append(&path, entry("encrypt")).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"time\":").unwrap();
+        assert_eq!(append(&path, entry("decrypt")).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,158 @@
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::decrypt::{self, DecryptError};
+use crate::header;
+use crate::persist;
+use crate::temp;
+
+/// Copy the header of a container to a backup file.
+///
+/// The backup holds the raw header bytes (magic through header MAC), so it
+/// is itself a parseable header. Returns the number of bytes written.
+pub fn backup_header(input_path: &str, output_path: &str) -> Result<usize, DecryptError> {
+    let (_reader, _header_obj, header_size, header_bytes) = decrypt::open_container(input_path)?;
+
+    let output_dir = Path::new(output_path)
+        .parent()
+        .unwrap_or(Path::new("."));
+    let mut temp_file = temp::temp_file_in(output_dir).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            DecryptError::Permission(format!("Cannot write to output directory: {}", e))
+        } else {
+            DecryptError::Internal(format!("Failed to create temp file: {}", e))
+        }
+    })?;
+
+    temp_file
+        .write_all(&header_bytes)
+        .map_err(|e| DecryptError::Internal(format!("Failed to write header backup: {}", e)))?;
+
+    persist::persist(temp_file, Path::new(output_path), true).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            DecryptError::Permission(format!("Cannot write to output path: {}", e))
+        } else {
+            DecryptError::Internal(format!("Failed to rename temp file to output: {}", e))
+        }
+    })?;
+
+    Ok(header_size)
+}
+
+/// Overwrite the header of a container with a previously saved backup.
+///
+/// The target's own header is not parsed, since it may be the part that is
+/// damaged. Instead the backup must describe a container of exactly the
+/// target's size, which guards against applying a backup to the wrong file.
+pub fn restore_header(backup_path: &str, target_path: &str) -> Result<(), DecryptError> {
+    let data = fs::read(backup_path).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            DecryptError::Permission(format!("Cannot read header backup: {}", e))
+        } else {
+            DecryptError::Internal(format!("Failed to read header backup: {}", e))
+        }
+    })?;
+    let (header_obj, header_size) =
+        header::decode_header(&data).map_err(decrypt::map_header_error)?;
+    if header_size != data.len() {
+        return Err(DecryptError::CorruptFile(format!(
+            "Header backup has {} trailing bytes",
+            data.len() - header_size
+        )));
+    }
+
+    let mut target = fs::OpenOptions::new()
+        .write(true)
+        .open(target_path)
+        .map_err(|e| {
+            if e.kind() == std::io::ErrorKind::PermissionDenied {
+                DecryptError::Permission(format!("Cannot open container for writing: {}", e))
+            } else {
+                DecryptError::Internal(format!("Failed to open container: {}", e))
+            }
+        })?;
+    let target_size = target
+        .metadata()
+        .map_err(|e| DecryptError::Internal(format!("Failed to stat container: {}", e)))?
+        .len();
+
+    let expected_size = decrypt::expected_container_size(&header_obj, header_size)?;
+    if target_size != expected_size {
+        return Err(DecryptError::CorruptFile(format!(
+            "Header backup does not match this container: expected {} bytes, file has {}",
+            expected_size, target_size
+        )));
+    }
+
+    target
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| target.write_all(&data))
+        .and_then(|_| target.sync_all())
+        .map_err(|e| DecryptError::Internal(format!("Failed to write header: {}", e)))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt::tests::{encrypt_fixture, Fixture};
+    use crate::decrypt::DecryptOptions;
+
+    fn encrypt_plain(dir: &Path, plaintext: &[u8]) -> String {
+        let fixture = Fixture {
+            filename: Some("plain.txt"),
+            ..Default::default()
+        };
+        encrypt_fixture(dir, plaintext, b"backup_pass", fixture)
+    }
+
+    #[test]
+    fn test_backup_and_restore_damaged_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let container = encrypt_plain(dir.path(), b"header backup test");
+        let backup_path = dir.path().join("plain.gtkrypt.header");
+        let backup = backup_path.to_str().unwrap();
+
+        let header_size = backup_header(&container, backup).unwrap();
+        assert_eq!(fs::metadata(backup).unwrap().len() as usize, header_size);
+
+        // Wipe the salt: the container can no longer be decrypted
+        let mut data = fs::read(&container).unwrap();
+        data[20..36].fill(0);
+        fs::write(&container, &data).unwrap();
+
+        let decrypted_path = dir.path().join("out.txt");
+        let opts = DecryptOptions {
+            input_path: container.clone(),
+            output_path: decrypted_path.to_str().unwrap().to_string(),
+            passphrase: b"backup_pass".to_vec(),
+            ..Default::default()
+        };
+        assert!(decrypt::decrypt(&opts).is_err());
+
+        restore_header(backup, &container).unwrap();
+        decrypt::decrypt(&opts).unwrap();
+        assert_eq!(fs::read(&decrypted_path).unwrap(), b"header backup test");
+    }
+
+    #[test]
+    fn test_restore_rejects_backup_of_other_container() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = encrypt_plain(dir.path(), b"short");
+        let backup_path = dir.path().join("first.header");
+        let backup = backup_path.to_str().unwrap();
+        backup_header(&first, backup).unwrap();
+
+        let other_dir = tempfile::tempdir().unwrap();
+        let second = encrypt_plain(other_dir.path(), b"a rather longer plaintext");
+        let before = fs::read(&second).unwrap();
+
+        assert!(matches!(
+            restore_header(backup, &second),
+            Err(DecryptError::CorruptFile(_))
+        ));
+        assert_eq!(fs::read(&second).unwrap(), before);
+    }
+}
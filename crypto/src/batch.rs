@@ -0,0 +1,284 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use globset::GlobBuilder;
+
+use crate::progress::{self, ErrorEvent};
+
+/// Run `op` on each input. With more than one input this is [`run_all`];
+/// a single input runs as if on its own.
+///
+/// Returns the error to exit with: that of a single input, or for a batch
+/// one naming how many inputs failed, with the code of the first failure.
+pub fn run<F>(inputs: &[String], jobs: usize, op: F) -> Result<(), (ErrorEvent, i32)>
+where
+    F: Fn(&str) -> Result<(), (ErrorEvent, i32)> + Sync,
+{
+    if let [input] = inputs {
+        return op(input);
+    }
+    run_all(inputs, jobs, |_, input| op(input))
+}
+
+/// Run `op` on each input with its index, up to `jobs` at a time and
+/// otherwise in order. The events of each are tagged with its path and
+/// followed by a result line, and a failure does not stop the rest. A
+/// summary line closes the batch.
+pub fn run_all<S, F>(inputs: &[S], jobs: usize, op: F) -> Result<(), (ErrorEvent, i32)>
+where
+    S: AsRef<str> + Sync,
+    F: Fn(usize, &str) -> Result<(), (ErrorEvent, i32)> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let failures = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, inputs.len().max(1)) {
+            // Each worker takes the next input still waiting
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(input) = inputs.get(index).map(AsRef::as_ref) else {
+                    break;
+                };
+                progress::set_current_file(Some(input));
+                let result = op(index, input);
+                progress::set_current_file(None);
+                match result {
+                    Ok(()) => progress::emit_file_result(input, None),
+                    Err((event, code)) => {
+                        let summary = ErrorEvent {
+                            message: format!("{}: {}", input, event.message),
+                            ..ErrorEvent::new(event.code, "")
+                        };
+                        progress::emit_file_result(input, Some(event));
+                        failures
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .push((index, summary, code));
+                    }
+                }
+            });
+        }
+    });
+
+    let mut failures = failures.into_inner().unwrap_or_else(|e| e.into_inner());
+    failures.sort_by_key(|(index, _, _)| *index);
+    let failed = failures.len();
+    progress::emit_batch_summary(
+        inputs.len() - failed,
+        failures.iter().map(|(index, _, _)| inputs[*index].as_ref().to_string()).collect(),
+    );
+    match failures.into_iter().next() {
+        None => Ok(()),
+        Some((_, mut event, code)) => {
+            event.message = format!(
+                "{} of {} files failed; first, {}",
+                failed,
+                inputs.len(),
+                event.message
+            );
+            Err((event, code))
+        }
+    }
+}
+
+/// Every container below `root` in sorted order: files named `*.gtkrypt`,
+/// and the first volume `*.gtkrypt.001` of a split one. Symbolic links are
+/// not followed, and a subdirectory that cannot be read is skipped with a
+/// warning.
+pub fn find_containers(root: &Path) -> io::Result<Vec<String>> {
+    let mut found = walk_files(root)?;
+    found.retain(|name| name.ends_with(".gtkrypt") || name.ends_with(".gtkrypt.001"));
+    found.sort();
+    Ok(found)
+}
+
+/// Expand the inputs that are glob patterns rather than existing paths,
+/// such as `photos/**/*.raw`, into the files they match in sorted order.
+/// `*` stays within a directory and `**` crosses any number of them.
+/// Returns the inputs and whether any pattern was expanded.
+pub fn expand_globs(inputs: Vec<String>) -> Result<(Vec<String>, bool), String> {
+    let mut expanded = Vec::new();
+    let mut any = false;
+    for input in inputs {
+        if !input.contains(['*', '?', '[', '{']) || Path::new(&input).exists() {
+            expanded.push(input);
+            continue;
+        }
+        any = true;
+        let matcher = GlobBuilder::new(&input)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| format!("Invalid input pattern '{}': {}", input, e))?
+            .compile_matcher();
+        // Walk only below the leading components that have no wildcards
+        let base: Vec<&str> = input
+            .split('/')
+            .take_while(|part| !part.contains(['*', '?', '[', '{']))
+            .collect();
+        let base = base.join("/");
+        let root = match base.as_str() {
+            "" if input.starts_with('/') => "/",
+            "" => ".",
+            base => base,
+        };
+        let mut matched: Vec<String> = match walk_files(Path::new(root)) {
+            Ok(files) => files,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("Failed to read '{}': {}", root, e)),
+        }
+        .into_iter()
+        .map(|path| match base.as_str() {
+            "" if root == "." => path.strip_prefix("./").unwrap_or(&path).to_string(),
+            _ => path,
+        })
+        .filter(|path| matcher.is_match(path))
+        .collect();
+        if matched.is_empty() {
+            return Err(format!("No files match '{}'", input));
+        }
+        matched.sort();
+        expanded.extend(matched);
+    }
+    expanded.dedup();
+    Ok((expanded, any))
+}
+
+/// Every file below `root`, without following symbolic links.
+fn walk_files(root: &Path) -> io::Result<Vec<String>> {
+    let mut found = Vec::new();
+    let mut dirs = vec![(root.to_path_buf(), fs::read_dir(root)?)];
+    while let Some((dir, entries)) = dirs.pop() {
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    skip_warning(&dir, &e);
+                    break;
+                }
+            };
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => match fs::read_dir(&path) {
+                    Ok(entries) => dirs.push((path, entries)),
+                    Err(e) => skip_warning(&path, &e),
+                },
+                Ok(file_type) if file_type.is_file() => match path.into_os_string().into_string() {
+                    Ok(name) => found.push(name),
+                    Err(path) => progress::emit_warning(
+                        "path_skipped",
+                        &format!("Skipping non-UTF-8 path {}", Path::new(&path).display()),
+                    ),
+                },
+                _ => {}
+            }
+        }
+    }
+    Ok(found)
+}
+
+fn skip_warning(dir: &Path, e: &io::Error) {
+    progress::emit_warning(
+        "path_skipped",
+        &format!("Skipping unreadable directory {}: {}", dir.display(), e),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::ErrorCode;
+
+    #[test]
+    fn test_batch_carries_on_and_reports_first_failure() {
+        let inputs: Vec<String> = ["a", "b", "c", "d"].iter().map(|s| s.to_string()).collect();
+        let seen = Mutex::new(Vec::new());
+        let result = run(&inputs, 1, |input| {
+            seen.lock().unwrap().push(input.to_string());
+            match input {
+                "b" => Err((ErrorEvent::new(ErrorCode::CorruptFile, "Bad header"), 2)),
+                "d" => Err((ErrorEvent::new(ErrorCode::WrongPassphrase, "Wrong"), 1)),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!(seen.into_inner().unwrap(), inputs);
+        let (event, code) = result.unwrap_err();
+        assert_eq!((event.error, code), ("corrupt_file", 2));
+        assert_eq!(event.message, "2 of 4 files failed; first, b: Bad header");
+    }
+
+    #[test]
+    fn test_single_input_error_is_passed_through() {
+        let inputs = vec!["only".to_string()];
+        let fail = |_: &str| Err((ErrorEvent::new(ErrorCode::WrongPassphrase, "Wrong"), 1));
+        let (event, code) = run(&inputs, 4, fail).unwrap_err();
+        assert_eq!((event.message.as_str(), code), ("Wrong", 1));
+    }
+
+    #[test]
+    fn test_parallel_jobs_overlap_and_keep_input_order_for_errors() {
+        let inputs: Vec<String> = (0..6).map(|n| format!("file{}", n)).collect();
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let result = run_all(&inputs, 3, |index, _| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            // The later failure finishes first
+            let wait = if index == 1 { 100 } else { 20 };
+            thread::sleep(std::time::Duration::from_millis(wait));
+            running.fetch_sub(1, Ordering::SeqCst);
+            match index {
+                1 | 4 => Err((ErrorEvent::new(ErrorCode::CorruptFile, "Bad"), 2)),
+                _ => Ok(()),
+            }
+        });
+        assert!(peak.load(Ordering::SeqCst) > 1);
+        assert!(peak.load(Ordering::SeqCst) <= 3);
+        let (event, _) = result.unwrap_err();
+        assert_eq!(event.message, "2 of 6 files failed; first, file1: Bad");
+    }
+
+    #[test]
+    fn test_find_containers() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("b/deep")).unwrap();
+        for name in ["z.gtkrypt", "notes.txt", "b/a.gtkrypt", "b/deep/big.gtkrypt.001"] {
+            fs::write(root.join(name), b"").unwrap();
+        }
+        fs::write(root.join("b/deep/big.gtkrypt.002"), b"").unwrap();
+
+        let found = find_containers(root).unwrap();
+        let expected: Vec<String> = ["b/a.gtkrypt", "b/deep/big.gtkrypt.001", "z.gtkrypt"]
+            .iter()
+            .map(|name| root.join(name).to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(found, expected);
+        assert!(find_containers(&root.join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_expand_globs() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        fs::create_dir_all(dir.path().join("2024/june")).unwrap();
+        for name in ["a.raw", "b.jpg", "2024/c.raw", "2024/june/d.raw"] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+        let path = |name: &str| format!("{}/{}", root, name);
+
+        let (found, any) = expand_globs(vec![path("**/*.raw")]).unwrap();
+        assert!(any);
+        assert_eq!(found, [path("2024/c.raw"), path("2024/june/d.raw"), path("a.raw")]);
+        let (found, _) = expand_globs(vec![path("*.raw"), path("b.jpg")]).unwrap();
+        assert_eq!(found, [path("a.raw"), path("b.jpg")]);
+
+        // Paths without wildcards are left for the operation to check
+        let (found, any) = expand_globs(vec![path("missing.txt")]).unwrap();
+        assert_eq!((found, any), (vec![path("missing.txt")], false));
+        assert!(expand_globs(vec![path("*.png")]).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use std::time::{Duration, Instant};
+
+use rand::RngCore;
+use serde::Serialize;
+
+use crate::aead::{Backend, PayloadCipher, BACKENDS};
+use crate::hash;
+use crate::header::{CHUNK_SIZE, NONCE_LEN};
+
+/// An AEAD the container payload can be encrypted with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    Aes256Gcm,
+}
+
+/// Every supported cipher, in the order they are benchmarked.
+pub const CIPHERS: &[Cipher] = &[Cipher::Aes256Gcm];
+
+impl Cipher {
+    pub fn name(self) -> &'static str {
+        match self {
+            Cipher::Aes256Gcm => "aes-256-gcm",
+        }
+    }
+}
+
+/// The ciphers picked by `--cipher`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherChoice {
+    All,
+    Only(Cipher),
+}
+
+impl CipherChoice {
+    pub fn ciphers(self) -> Vec<Cipher> {
+        match self {
+            CipherChoice::All => CIPHERS.to_vec(),
+            CipherChoice::Only(cipher) => vec![cipher],
+        }
+    }
+}
+
+/// Parse a `--cipher` value: `all` or a cipher name.
+pub fn parse_cipher_choice(arg: &str) -> Result<CipherChoice, String> {
+    if arg == "all" {
+        return Ok(CipherChoice::All);
+    }
+    CIPHERS
+        .iter()
+        .find(|cipher| cipher.name() == arg)
+        .map(|&cipher| CipherChoice::Only(cipher))
+        .ok_or_else(|| {
+            let names: Vec<_> = CIPHERS.iter().map(|cipher| cipher.name()).collect();
+            format!("expected all or {}, got '{}'", names.join(", "), arg)
+        })
+}
+
+/// Throughput of one cipher on one backend on this machine, on a single
+/// thread.
+#[derive(Debug, Serialize)]
+pub struct CipherResult {
+    pub cipher: &'static str,
+    pub backend: &'static str,
+    /// Bytes per call, the container chunk size.
+    pub chunk_size: usize,
+    pub encrypt_bytes_per_sec: u64,
+    pub decrypt_bytes_per_sec: u64,
+}
+
+/// Throughput of SHA-256, used for parity shards, on one backend.
+#[derive(Debug, Serialize)]
+pub struct HashResult {
+    pub hash: &'static str,
+    pub backend: &'static str,
+    pub bytes_per_sec: u64,
+}
+
+/// Printed as one JSON object on stdout by `bench`.
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub results: Vec<CipherResult>,
+    pub hashes: Vec<HashResult>,
+}
+
+/// Measure the selected ciphers on every backend available here, spending
+/// about `duration` on each direction of each, then SHA-256 likewise.
+pub fn run(choice: CipherChoice, duration: Duration) -> Result<BenchReport, String> {
+    let mut key = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+
+    let mut results = Vec::new();
+    for cipher in choice.ciphers() {
+        for &backend in BACKENDS {
+            match cipher {
+                Cipher::Aes256Gcm => {
+                    // Backends missing from this build or machine are left out
+                    if let Ok(aead) = PayloadCipher::with_backend(&key, backend) {
+                        results.push(bench_aes_256_gcm(&aead, backend, duration)?);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut hashes = Vec::new();
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    rand::thread_rng().fill_bytes(&mut chunk);
+    for &backend in BACKENDS {
+        if let Some(digest) = hash::sha256_on(backend) {
+            // Feeding each digest back in keeps the calls from being elided
+            let bytes_per_sec = measure(duration, || {
+                let sum = digest(&chunk);
+                chunk[..sum.len()].copy_from_slice(&sum);
+                Ok(())
+            })?;
+            hashes.push(HashResult {
+                hash: "sha-256",
+                backend: backend.name(),
+                bytes_per_sec,
+            });
+        }
+    }
+    Ok(BenchReport { results, hashes })
+}
+
+fn bench_aes_256_gcm(
+    cipher: &PayloadCipher,
+    backend: Backend,
+    duration: Duration,
+) -> Result<CipherResult, String> {
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    rand::thread_rng().fill_bytes(&mut chunk);
+
+    // A fresh nonce per call, as for the chunks of a container
+    let mut counter = 0u64;
+    let mut next_nonce = || {
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[NONCE_LEN - 8..].copy_from_slice(&counter.to_be_bytes());
+        counter += 1;
+        nonce
+    };
+
+    let encrypt_bytes_per_sec = measure(duration, || {
+        let nonce = next_nonce();
+        cipher
+            .seal(&nonce, b"", &mut chunk)
+            .map(|_| ())
+            .map_err(|e| format!("Encryption failed: {}", e))
+    })?;
+
+    let decrypt_bytes_per_sec = decrypt_bytes_per_sec(cipher, &next_nonce(), duration)?;
+
+    Ok(CipherResult {
+        cipher: Cipher::Aes256Gcm.name(),
+        backend: backend.name(),
+        chunk_size: CHUNK_SIZE,
+        encrypt_bytes_per_sec,
+        decrypt_bytes_per_sec,
+    })
+}
+
+/// Bytes of chunks `cipher` decrypts per second on one thread, measured
+/// for about `duration` on a chunk encrypted under `nonce`.
+pub fn decrypt_bytes_per_sec(
+    cipher: &PayloadCipher,
+    nonce: &[u8; NONCE_LEN],
+    duration: Duration,
+) -> Result<u64, String> {
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    rand::thread_rng().fill_bytes(&mut chunk);
+    let tag = cipher
+        .seal(nonce, b"", &mut chunk)
+        .map_err(|e| format!("Encryption failed: {}", e))?;
+    let ciphertext = chunk.clone();
+    // Each call decrypts in place, so the ciphertext is put back first; the
+    // copy is small next to the cipher itself
+    measure(duration, || {
+        chunk.copy_from_slice(&ciphertext);
+        match cipher.open(nonce, b"", &mut chunk, &tag) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err("Decryption failed: authentication error".to_string()),
+            Err(e) => Err(format!("Decryption failed: {}", e)),
+        }
+    })
+}
+
+/// Call `op`, which processes one chunk, until `duration` has passed, and
+/// return the bytes processed per second.
+fn measure<F>(duration: Duration, mut op: F) -> Result<u64, String>
+where
+    F: FnMut() -> Result<(), String>,
+{
+    let start = Instant::now();
+    let mut calls = 0u64;
+    loop {
+        op()?;
+        calls += 1;
+        let elapsed = start.elapsed();
+        if elapsed >= duration {
+            return Ok(((calls * CHUNK_SIZE as u64) as f64 / elapsed.as_secs_f64()) as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cipher_choice() {
+        assert_eq!(parse_cipher_choice("all").unwrap(), CipherChoice::All);
+        assert_eq!(
+            parse_cipher_choice("aes-256-gcm").unwrap(),
+            CipherChoice::Only(Cipher::Aes256Gcm)
+        );
+        assert!(parse_cipher_choice("rot13").is_err());
+    }
+
+    #[test]
+    fn test_run_reports_every_cipher() {
+        let report = run(CipherChoice::All, Duration::from_millis(20)).unwrap();
+        for &cipher in CIPHERS {
+            let software = report
+                .results
+                .iter()
+                .find(|result| result.cipher == cipher.name() && result.backend == "software")
+                .unwrap();
+            assert!(software.encrypt_bytes_per_sec > 0);
+            assert!(software.decrypt_bytes_per_sec > 0);
+        }
+        assert!(report.hashes.iter().any(|result| result.backend == "software"));
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::cpu;
+use crate::kdf;
+use crate::progress;
+
+/// File in the state directory that calibrations are kept in.
+const FILE_NAME: &str = "kdf-calibration.json";
+
+/// Age after which a calibration is measured again, in case it was taken
+/// while the machine was busy.
+const MAX_AGE: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// An Argon2id rate measured on one machine.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Calibration {
+    kib_per_second: f64,
+    /// Seconds since the Unix epoch.
+    measured_at: u64,
+}
+
+/// Calibrations by machine fingerprint, so that machines sharing a home
+/// directory keep one each.
+type Calibrations = BTreeMap<String, Calibration>;
+
+/// Set by `enable`; until then calibrations are neither loaded nor kept,
+/// as in unit tests.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Keep calibrations in the state directory from now on.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// The state directory, looked up the first time a calibration is needed,
+/// if `enable` was called.
+fn enabled_state_dir() -> Option<&'static PathBuf> {
+    static STATE_DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    STATE_DIR.get_or_init(state_dir).as_ref()
+}
+
+/// Where calibrations are kept: `$GTKRYPT_STATE_DIR` when set, with an
+/// empty value turning the cache off, else `$XDG_STATE_HOME/gtkrypt`, or
+/// else `~/.local/state/gtkrypt`.
+fn state_dir() -> Option<PathBuf> {
+    let var = |name| std::env::var_os(name).map(PathBuf::from);
+    let override_dir = var("GTKRYPT_STATE_DIR");
+    // Empty turns the cache off on purpose; relative is a mistake
+    let relative = |dir: &&PathBuf| dir.is_relative() && !dir.as_os_str().is_empty();
+    if let Some(dir) = override_dir.as_ref().filter(relative) {
+        let message = format!(
+            "GTKRYPT_STATE_DIR is not an absolute path, so {} is ignored and the KDF \
+             calibration is not kept",
+            dir.display()
+        );
+        progress::emit_warning("state_dir_ignored", &message);
+    }
+    state_dir_from(override_dir, var("XDG_STATE_HOME"), var("HOME"))
+}
+
+fn state_dir_from(
+    override_dir: Option<PathBuf>,
+    state_home: Option<PathBuf>,
+    home: Option<PathBuf>,
+) -> Option<PathBuf> {
+    if let Some(dir) = override_dir {
+        return Some(dir).filter(|dir| dir.is_absolute());
+    }
+    let state_home = state_home
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| Some(home?.join(".local").join("state")))?;
+    Some(state_home.join("gtkrypt"))
+}
+
+/// What the Argon2id rate depends on: the CPU, its cores, the memory, and
+/// this build. Any change to them calls for a new measurement.
+fn fingerprint() -> String {
+    let cpu = cpu::detect();
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+    let mut hash = Sha256::new();
+    hash.update(format!(
+        "{}\n{}\n{}\n{}\n{}\n{}\n",
+        env!("CARGO_PKG_VERSION"),
+        cpu.arch,
+        cpu_model().unwrap_or_default(),
+        cores,
+        total_memory_kib().unwrap_or_default(),
+        cpu.aes_gcm_accelerated(),
+    ));
+    kdf::to_hex(&hash.finalize())
+}
+
+fn cpu_model() -> Option<String> {
+    let cpuinfo = fs::read_to_string("/proc/cpuinfo").ok()?;
+    cpuinfo.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        matches!(key.trim(), "model name" | "Model" | "cpu model").then(|| value.trim().into())
+    })
+}
+
+fn total_memory_kib() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))
+        .and_then(|rest| rest.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+/// The Argon2id rate, in KiB per second and thread, that an earlier run
+/// measured on this machine, unless it is missing or too old.
+pub fn load() -> Option<f64> {
+    load_from(enabled_state_dir()?, &fingerprint(), now())
+}
+
+/// Keep `kib_per_second`, just measured, for later runs. Failing to is no
+/// more than a missed shortcut, so errors are ignored.
+pub fn save(kib_per_second: f64) {
+    if let Some(dir) = enabled_state_dir() {
+        let _ = save_to(dir, &fingerprint(), kib_per_second, now());
+    }
+}
+
+fn read(dir: &Path) -> Calibrations {
+    fs::read(dir.join(FILE_NAME))
+        .ok()
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn load_from(dir: &Path, fingerprint: &str, now: u64) -> Option<f64> {
+    let calibration = read(dir).remove(fingerprint)?;
+    let age = Duration::from_secs(now.saturating_sub(calibration.measured_at));
+    let rate = calibration.kib_per_second;
+    (age < MAX_AGE && rate.is_finite() && rate > 0.0).then_some(rate)
+}
+
+fn save_to(dir: &Path, fingerprint: &str, kib_per_second: f64, now: u64) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut calibrations = read(dir);
+    calibrations.insert(
+        fingerprint.to_string(),
+        Calibration {
+            kib_per_second,
+            measured_at: now,
+        },
+    );
+    // Replaced whole, so that a run reading it never sees half a file
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(&serde_json::to_vec_pretty(&calibrations)?)?;
+    file.persist(dir.join(FILE_NAME))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibration_is_kept_per_machine() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = dir.path().join("gtkrypt");
+        assert_eq!(load_from(&state, "this", 1000), None);
+
+        save_to(&state, "this", 50_000.0, 1000).unwrap();
+        save_to(&state, "other", 90_000.0, 1000).unwrap();
+        assert_eq!(load_from(&state, "this", 1000), Some(50_000.0));
+        assert_eq!(load_from(&state, "other", 2000), Some(90_000.0));
+        assert_eq!(load_from(&state, "third", 1000), None);
+
+        // Measured again once old
+        let later = 1000 + MAX_AGE.as_secs();
+        assert_eq!(load_from(&state, "this", later), None);
+    }
+
+    #[test]
+    fn test_unreadable_calibration_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(FILE_NAME), b"{not json").unwrap();
+        assert_eq!(load_from(dir.path(), "this", 0), None);
+        save_to(dir.path(), "this", 0.0, 0).unwrap();
+        assert_eq!(load_from(dir.path(), "this", 0), None);
+    }
+
+    #[test]
+    fn test_state_dir_lookup() {
+        let dir = |path: &str| Some(PathBuf::from(path));
+        let state_dir = state_dir_from(None, dir("/state"), dir("/home/me"));
+        assert_eq!(state_dir, dir("/state/gtkrypt"));
+        let state_dir = state_dir_from(None, dir("state"), dir("/home/me"));
+        assert_eq!(state_dir, dir("/home/me/.local/state/gtkrypt"));
+        assert_eq!(state_dir_from(None, None, None), None);
+
+        // An override is taken as it is, and only when absolute
+        let state_dir = state_dir_from(dir("/cache"), dir("/state"), dir("/home/me"));
+        assert_eq!(state_dir, dir("/cache"));
+        assert_eq!(state_dir_from(dir(""), dir("/state"), dir("/home/me")), None);
+        assert_eq!(state_dir_from(dir("cache"), dir("/state"), dir("/home/me")), None);
+    }
+
+    #[test]
+    fn test_fingerprint_is_stable() {
+        assert_eq!(fingerprint(), fingerprint());
+        assert_eq!(fingerprint().len(), 64);
+    }
+}
@@ -0,0 +1,186 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use crate::passphrase;
+use crate::progress::{self, ErrorCode, ErrorEvent};
+
+/// Files and directories that only exist while an operation runs, such as
+/// temp outputs and lock files, and that a cancelled run must not leave
+/// behind.
+static TRACKED: Registry = Registry(Mutex::new(Vec::new()));
+
+struct Registry(Mutex<Vec<Tracked>>);
+
+struct Tracked {
+    path: PathBuf,
+    /// Device and inode of what was created, so that whatever has taken
+    /// its name since is left alone.
+    #[cfg(unix)]
+    id: (u64, u64),
+}
+
+#[cfg(unix)]
+fn file_id(path: &Path) -> io::Result<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = fs::symlink_metadata(path)?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+impl Registry {
+    fn lock(&self) -> MutexGuard<'_, Vec<Tracked>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn track<T>(
+        &self,
+        create: impl FnOnce() -> io::Result<T>,
+        path: fn(&T) -> &Path,
+    ) -> io::Result<T> {
+        let mut tracked = self.lock();
+        let created = create()?;
+        let path = path(&created).to_path_buf();
+        tracked.push(Tracked {
+            #[cfg(unix)]
+            id: file_id(&path)?,
+            path,
+        });
+        Ok(created)
+    }
+
+    fn untrack(&self, path: &Path) {
+        self.lock().retain(|entry| entry.path != path);
+    }
+
+    /// Remove everything still tracked. The lock is kept, so that no more
+    /// can be created.
+    fn remove_all(&self) -> MutexGuard<'_, Vec<Tracked>> {
+        let mut tracked = self.lock();
+        for entry in tracked.drain(..) {
+            #[cfg(unix)]
+            if file_id(&entry.path).ok() != Some(entry.id) {
+                continue;
+            }
+            let _ = match fs::symlink_metadata(&entry.path) {
+                Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&entry.path),
+                _ => fs::remove_file(&entry.path),
+            };
+        }
+        tracked
+    }
+}
+
+/// Create something with `create` and remember the path `path` gives for
+/// it, to be removed if the run is cancelled before `untrack`.
+///
+/// Cancellation waits for this to finish, so nothing is created without
+/// being tracked.
+pub fn track<T>(create: impl FnOnce() -> io::Result<T>, path: fn(&T) -> &Path) -> io::Result<T> {
+    TRACKED.track(create, path)
+}
+
+/// Forget `path`, which has been removed or has become a finished output.
+pub fn untrack(path: &Path) {
+    TRACKED.untrack(path)
+}
+
+/// Handle SIGINT and SIGTERM by removing what the run had in progress and
+/// exiting with a `cancelled` error. Must be called before any other
+/// thread is started.
+///
+/// The signals are blocked on every thread and taken by one that waits
+/// for them, so the cleanup runs as ordinary code rather than in a signal
+/// handler.
+#[cfg(unix)]
+pub fn install() {
+    // SAFETY: sigset_t is plain old data, set up by sigemptyset before use
+    let mut set: libc::sigset_t = unsafe { std::mem::zeroed() };
+    // SAFETY: `set` is a valid signal set; the mask is changed before any
+    // other thread exists, so all of them inherit it
+    unsafe {
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+    }
+    std::thread::spawn(move || {
+        let mut signal = 0;
+        // SAFETY: `set` and `signal` are valid for the call
+        if unsafe { libc::sigwait(&set, &mut signal) } == 0 {
+            let name = if signal == libc::SIGINT { "SIGINT" } else { "SIGTERM" };
+            let event = ErrorEvent::with_id(
+                ErrorCode::Cancelled,
+                &format!("Cancelled by {}; nothing was written", name),
+                "cancelled_by_signal",
+                [("signal", name.into())],
+            );
+            abort(event, progress::EXIT_CANCELLED);
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn install() {}
+
+/// Stop the run like a cancellation once `limit` has passed, with a
+/// `timeout` error.
+pub fn set_timeout(limit: Duration) {
+    std::thread::spawn(move || {
+        std::thread::sleep(limit);
+        let event = ErrorEvent::with_id(
+            ErrorCode::Timeout,
+            &format!("Gave up after {} seconds; nothing was written", limit.as_secs()),
+            "timed_out",
+            [("seconds", limit.as_secs().into())],
+        );
+        abort(event, progress::EXIT_TIMEOUT);
+    });
+}
+
+/// Remove what the run had in progress, report `event` and exit. Whatever
+/// the other threads are doing, nothing more gets created.
+fn abort(event: ErrorEvent, exit_code: i32) -> ! {
+    let _tracked = TRACKED.remove_all();
+    passphrase::restore_terminal();
+    progress::emit_error_event_and_exit(event, exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_all_spares_replaced_and_untracked() {
+        let registry = Registry(Mutex::new(Vec::new()));
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("partial.tmp");
+        let sub = dir.path().join("partial.dir");
+        let replaced = dir.path().join("replaced.tmp");
+        let finished = dir.path().join("finished.tmp");
+
+        let create = |path: &PathBuf| fs::write(path, b"partial").map(|_| path.clone());
+        registry.track(|| create(&file), PathBuf::as_path).unwrap();
+        registry.track(|| create(&replaced), PathBuf::as_path).unwrap();
+        registry.track(|| create(&finished), PathBuf::as_path).unwrap();
+        let make_dir = || {
+            fs::create_dir(&sub)?;
+            fs::write(sub.join("entry"), b"partial")?;
+            Ok(sub.clone())
+        };
+        registry.track(make_dir, PathBuf::as_path).unwrap();
+        registry.untrack(&finished);
+        // Something else has taken the name since
+        fs::write(dir.path().join("other"), b"someone else's").unwrap();
+        fs::rename(dir.path().join("other"), &replaced).unwrap();
+
+        drop(registry.remove_all());
+        assert!(!file.exists());
+        assert!(!sub.exists());
+        assert!(finished.exists());
+        #[cfg(unix)]
+        assert_eq!(fs::read(&replaced).unwrap(), b"someone else's");
+    }
+}
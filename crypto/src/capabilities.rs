@@ -0,0 +1,141 @@
+use serde::Serialize;
+
+use crate::aead::BACKENDS;
+use crate::bench::CIPHERS;
+use crate::cpu::{self, CpuFeatures};
+use crate::header;
+use crate::progress;
+
+/// What this backend and the machine it runs on support, printed as JSON
+/// by `capabilities`, so that a frontend can adapt to the binary it finds
+/// installed.
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    /// Version of the events on stdout and the errors on stderr.
+    pub protocol_version: u32,
+    pub container_versions: ContainerVersions,
+    pub ciphers: Vec<&'static str>,
+    pub kdfs: Vec<&'static str>,
+    /// The `--crypto-backend` values this build accepts.
+    pub crypto_backends: Vec<&'static str>,
+    /// The `--event-format` values.
+    pub event_formats: Vec<&'static str>,
+    /// The `--passphrase-format` values.
+    pub passphrase_formats: Vec<&'static str>,
+    /// The `--passphrase-encoding` values.
+    pub passphrase_encodings: Vec<&'static str>,
+    pub subcommands: Vec<String>,
+    /// `keyfile --dpapi` can wrap keyfiles, and wrapped ones can be used.
+    pub dpapi_keyfiles: bool,
+    pub hardware: Hardware,
+}
+
+/// Container format versions that can be read, and the one new containers
+/// are written in.
+#[derive(Debug, Serialize)]
+pub struct ContainerVersions {
+    pub read: Vec<u8>,
+    pub write: u8,
+}
+
+/// Versions of this build, printed as JSON by `--version-json`, so that a
+/// frontend can tell what it can expect of it.
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub container_versions: ContainerVersions,
+    /// Version of the events on stdout and the errors on stderr.
+    pub protocol_version: u32,
+}
+
+/// Crypto acceleration available on this machine.
+#[derive(Debug, Serialize)]
+pub struct Hardware {
+    #[serde(flatten)]
+    pub cpu: CpuFeatures,
+    /// AES-GCM runs on hardware instructions.
+    pub aes_gcm_accelerated: bool,
+}
+
+fn container_versions() -> ContainerVersions {
+    ContainerVersions {
+        read: (1..=header::VERSION).collect(),
+        write: header::VERSION,
+    }
+}
+
+pub fn version_info() -> VersionInfo {
+    VersionInfo {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        container_versions: container_versions(),
+        protocol_version: progress::PROTOCOL_VERSION,
+    }
+}
+
+/// The capabilities of this build, which has `subcommands`.
+pub fn capabilities(subcommands: Vec<String>) -> Capabilities {
+    let cpu = cpu::detect();
+    Capabilities {
+        protocol_version: progress::PROTOCOL_VERSION,
+        container_versions: container_versions(),
+        ciphers: CIPHERS.iter().map(|cipher| cipher.name()).collect(),
+        kdfs: vec!["argon2id"],
+        crypto_backends: BACKENDS
+            .iter()
+            .filter(|backend| backend.is_built())
+            .map(|backend| backend.name())
+            .collect(),
+        event_formats: vec!["json", "msgpack"],
+        passphrase_formats: vec!["line", "length-prefixed"],
+        passphrase_encodings: vec!["bytes", "utf-8", "latin-1"],
+        subcommands,
+        dpapi_keyfiles: cfg!(windows),
+        hardware: Hardware {
+            cpu,
+            aes_gcm_accelerated: cpu.aes_gcm_accelerated(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passphrase;
+
+    #[test]
+    fn test_capabilities_json() {
+        let json = serde_json::to_value(capabilities(vec!["encrypt".to_string()])).unwrap();
+        assert_eq!(json["protocol_version"], progress::PROTOCOL_VERSION);
+        assert_eq!(json["container_versions"]["read"], serde_json::json!([1, 2, 3]));
+        assert_eq!(json["container_versions"]["write"], header::VERSION);
+        assert_eq!(json["ciphers"], serde_json::json!(["aes-256-gcm"]));
+        assert_eq!(json["kdfs"], serde_json::json!(["argon2id"]));
+        assert_eq!(json["crypto_backends"][0], "software");
+        assert_eq!(json["subcommands"], serde_json::json!(["encrypt"]));
+        assert_eq!(json["dpapi_keyfiles"], cfg!(windows));
+        for format in json["event_formats"].as_array().unwrap() {
+            assert!(progress::parse_event_format(format.as_str().unwrap()).is_ok());
+        }
+        for format in json["passphrase_formats"].as_array().unwrap() {
+            assert!(passphrase::parse_passphrase_format(format.as_str().unwrap()).is_ok());
+        }
+        for encoding in json["passphrase_encodings"].as_array().unwrap() {
+            assert!(passphrase::parse_passphrase_encoding(encoding.as_str().unwrap()).is_ok());
+        }
+        let hardware = &json["hardware"];
+        assert_eq!(hardware["arch"], std::env::consts::ARCH);
+        assert!(hardware["aes"].is_boolean());
+        assert!(hardware["aes_gcm_accelerated"].is_boolean());
+    }
+
+    #[test]
+    fn test_version_info_json() {
+        let json = serde_json::to_value(version_info()).unwrap();
+        assert_eq!(json["name"], "gtkrypt-crypto");
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(json["container_versions"]["write"], header::VERSION);
+        assert_eq!(json["protocol_version"], progress::PROTOCOL_VERSION);
+    }
+}
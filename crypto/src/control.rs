@@ -0,0 +1,122 @@
+use std::cell::RefCell;
+use std::io::BufRead;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Once, PoisonError};
+
+use serde::Deserialize;
+
+use crate::progress;
+
+/// Whether the chunk loops are held, and the condition they wait on.
+static PAUSED: (Mutex<bool>, Condvar) = (Mutex::new(false), Condvar::new());
+
+thread_local! {
+    /// Set to stop the operation running on this thread, in `serve`.
+    static CANCEL: RefCell<Option<Arc<AtomicBool>>> = const { RefCell::new(None) };
+}
+
+/// A control message, one JSON object per line on stdin after the
+/// passphrase.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum Command {
+    Pause,
+    Resume,
+}
+
+/// Read control messages from the rest of stdin on a thread of its own,
+/// from the first call on. Each accepted message is confirmed with a
+/// `state` line; others get a warning.
+///
+/// When stdin closes while paused, work resumes, since nobody is left to
+/// resume it.
+pub fn listen() {
+    static LISTENING: Once = Once::new();
+    LISTENING.call_once(|| {
+        std::thread::spawn(|| {
+            for line in std::io::stdin().lock().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str(&line) {
+                    Ok(command) => apply(command),
+                    Err(e) => progress::emit_warning(
+                        "unknown_command",
+                        &format!("Ignored control message {:?}: {}", line, e),
+                    ),
+                }
+            }
+            set_paused(false);
+        });
+    });
+}
+
+fn apply(command: Command) {
+    set_paused(command == Command::Pause);
+    progress::emit_state(if command == Command::Pause { "paused" } else { "running" });
+}
+
+fn set_paused(paused: bool) {
+    let (lock, resumed) = &PAUSED;
+    *lock.lock().unwrap_or_else(PoisonError::into_inner) = paused;
+    if !paused {
+        resumed.notify_all();
+    }
+}
+
+/// Block while paused. Called by the chunk loops between chunks.
+pub fn wait_if_paused() {
+    let (lock, resumed) = &PAUSED;
+    let paused = lock.lock().unwrap_or_else(PoisonError::into_inner);
+    let _paused = resumed
+        .wait_while(paused, |paused| *paused)
+        .unwrap_or_else(PoisonError::into_inner);
+}
+
+/// Stop the operation on this thread at its next chunk once `flag` is set,
+/// until cleared with `None`.
+pub fn set_cancel_flag(flag: Option<Arc<AtomicBool>>) {
+    CANCEL.with(|cancel| *cancel.borrow_mut() = flag);
+}
+
+/// Whether the operation on this thread was cancelled. Checked by the
+/// chunk loops between chunks, which then give up with a `Cancelled` error.
+pub fn is_cancelled() -> bool {
+    CANCEL.with(|cancel| {
+        cancel.borrow().as_ref().is_some_and(|flag| flag.load(Ordering::Relaxed))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(
+            serde_json::from_str::<Command>(r#"{"cmd":"pause"}"#).unwrap(),
+            Command::Pause
+        );
+        assert_eq!(
+            serde_json::from_str::<Command>(r#"{"cmd":"resume"}"#).unwrap(),
+            Command::Resume
+        );
+        assert!(serde_json::from_str::<Command>(r#"{"cmd":"rewind"}"#).is_err());
+        assert!(serde_json::from_str::<Command>("pause").is_err());
+    }
+
+    #[test]
+    fn test_cancel_flag_is_per_thread() {
+        let flag = Arc::new(AtomicBool::new(false));
+        set_cancel_flag(Some(Arc::clone(&flag)));
+        assert!(!is_cancelled());
+        flag.store(true, Ordering::Relaxed);
+        assert!(is_cancelled());
+        assert!(!std::thread::spawn(is_cancelled).join().unwrap());
+        set_cancel_flag(None);
+        assert!(!is_cancelled());
+    }
+}
@@ -0,0 +1,94 @@
+use serde::Serialize;
+
+use crate::progress;
+
+/// Inputs from this size up are slow enough to encrypt without AES
+/// instructions that a warning is worth it.
+pub const SLOW_AES_WARN_SIZE: u64 = 256 << 20;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+cpufeatures::new!(aes_x86, "aes");
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+cpufeatures::new!(clmul_x86, "pclmulqdq");
+
+// On ARM the "aes" feature covers both AES and PMULL
+#[cfg(target_arch = "aarch64")]
+cpufeatures::new!(aes_arm, "aes");
+
+/// Crypto instructions of the CPU this runs on.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CpuFeatures {
+    pub arch: &'static str,
+    /// AES rounds in hardware (AES-NI, ARMv8 AES).
+    pub aes: bool,
+    /// Carry-less multiplication for GHASH (PCLMULQDQ, PMULL).
+    pub clmul: bool,
+}
+
+impl CpuFeatures {
+    /// Whether AES-GCM runs on hardware instructions rather than the
+    /// much slower constant-time software fallback.
+    pub fn aes_gcm_accelerated(&self) -> bool {
+        self.aes && self.clmul
+    }
+}
+
+/// Detect the crypto instructions of the running CPU.
+pub fn detect() -> CpuFeatures {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        CpuFeatures {
+            arch: std::env::consts::ARCH,
+            aes: aes_x86::get(),
+            clmul: clmul_x86::get(),
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        let aes = aes_arm::get();
+        CpuFeatures {
+            arch: std::env::consts::ARCH,
+            aes,
+            clmul: aes,
+        }
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        CpuFeatures {
+            arch: std::env::consts::ARCH,
+            aes: false,
+            clmul: false,
+        }
+    }
+}
+
+/// Warn before encrypting `size` bytes on a CPU without AES acceleration,
+/// where large files take noticeably long.
+pub fn warn_if_slow(size: u64) {
+    if size >= SLOW_AES_WARN_SIZE && !detect().aes_gcm_accelerated() {
+        progress::emit_warning(
+            "no_aes_acceleration",
+            &format!(
+                "This CPU has no AES acceleration; encrypting {} bytes will be slow",
+                size
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_matches_std() {
+        let features = detect();
+        assert_eq!(features.arch, std::env::consts::ARCH);
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            features.aes_gcm_accelerated(),
+            std::arch::is_x86_feature_detected!("aes")
+                && std::arch::is_x86_feature_detected!("pclmulqdq")
+        );
+    }
+}
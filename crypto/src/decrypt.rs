@@ -1,238 +1,1486 @@
 use std::fs;
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::path::Path;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 use aes_gcm::aead::AeadInPlace;
 use aes_gcm::{Aes256Gcm, KeyInit, Nonce, Tag};
 
-use crate::header::{self, AAD_LENGTH, CHUNK_SIZE, TAG_LEN};
-use crate::kdf;
-use crate::progress;
+use crate::aead::{Backend, PayloadCipher};
+use crate::archive;
+use crate::audit;
+use crate::control;
+use crate::direct::{self, BlockWriter};
+use crate::footer::{self, ChunkEntry, FooterError};
+use crate::header::{
+    self, ContainerHeader, HeaderError, CHUNK_SIZE, EXT_ARCHIVE, EXT_KEY_CHECK, NONCE_LEN,
+    PLAINTEXT_HASH_LEN, TAG_LEN,
+};
+use crate::kdf::{self, KdfLimits, KeyCache, SUBKEY_PAYLOAD};
+use crate::lock::{self, OutputLock};
+use crate::log;
+use crate::metadata::{self, Metadata, MetadataError};
+use crate::naming::{Conflict, NamingError, OutputNaming};
+use crate::persist;
+use crate::pipeline;
+use crate::progress::{self, BadChunk};
+use crate::space;
+use crate::temp;
+use crate::volume::VolumeReader;
 
 /// Options for decryption.
+#[derive(Default)]
 pub struct DecryptOptions {
     pub input_path: String,
     pub output_path: String,
+    /// `output_path` names a directory; the output inside it is named after
+    /// the stored filename.
+    pub output_dir: bool,
+    /// Template and collision handling for the output name.
+    pub naming: OutputNaming,
+    /// Replace the input with its plaintext, keeping the mode and owner of
+    /// the container file rather than the stored ones.
+    pub in_place: bool,
     pub passphrase: Vec<u8>,
+    /// Plaintext byte offset to start writing from.
+    pub offset: u64,
+    /// Number of plaintext bytes to write; `None` reads to the end.
+    pub length: Option<u64>,
+    /// Keep authenticating after a corrupted chunk so the error lists
+    /// every bad chunk rather than just the first.
+    pub scan_all: bool,
+    /// Salvage mode: write every chunk that authenticates, leave zero-filled
+    /// holes for the bad ones and keep the output. The bad chunks are still
+    /// reported as an error. Implies `scan_all`.
+    pub keep_partial: bool,
+    /// Stored attributes to apply to the output.
+    pub restore: RestoreOptions,
+    /// Key cache shared by the files of a batch.
+    pub keys: KeyCache,
+    /// Threads that authenticate and decrypt chunks; 0 uses one per
+    /// available core.
+    pub threads: usize,
+    /// Read the container and write the plaintext with direct I/O,
+    /// bypassing the page cache.
+    pub direct_io: bool,
+    /// Drop the pages of the container and of the plaintext from the page
+    /// cache once they are processed.
+    pub drop_cache: bool,
+    /// Size of the read and write buffers in bytes; 0 uses the default.
+    pub io_buffer_size: usize,
+    /// Where the chunks are decrypted.
+    pub backend: Backend,
+    /// Caps on the Argon2id parameters honoured from the header.
+    pub kdf_limits: KdfLimits,
+    /// Stage a file output here rather than beside it. An archive is
+    /// always extracted beside its output, since a directory tree cannot
+    /// be moved across filesystems in one step.
+    pub temp_dir: Option<PathBuf>,
+}
+
+/// Which stored attributes are applied to decrypted output.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RestoreOptions {
+    /// Set the modification and access times recorded at encryption.
+    pub times: bool,
+    /// Give the output its stored owner. Without the privilege to do so a
+    /// warning is emitted and the output keeps the current user.
+    pub owner: bool,
+    /// Where the permission bits of the output come from.
+    pub permissions: PermissionPolicy,
+}
+
+/// Source of the permission bits given to decrypted output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PermissionPolicy {
+    /// The mode recorded at encryption, when there is one.
+    #[default]
+    Stored,
+    /// What a newly created file would get under the current umask.
+    Umask,
+    /// A fixed mode. Directories also get the search bit wherever the mode
+    /// grants read, so they stay traversable.
+    Mode(u32),
+}
+
+impl RestoreOptions {
+    /// The mode to give an output file or directory, if any.
+    pub fn mode(&self, stored: Option<u32>, dir: bool) -> Option<u32> {
+        match self.permissions {
+            PermissionPolicy::Stored => stored,
+            PermissionPolicy::Umask => {
+                Some(if dir { 0o777 } else { 0o666 } & !metadata::umask())
+            }
+            PermissionPolicy::Mode(mode) if dir => Some(mode | (mode & 0o444) >> 2),
+            PermissionPolicy::Mode(mode) => Some(mode),
+        }
+    }
+
+    /// The Windows attributes to give an output, which follow the stored
+    /// mode in being restored only under [`PermissionPolicy::Stored`].
+    pub fn attributes(&self, stored: Option<u32>) -> Option<u32> {
+        stored.filter(|_| self.permissions == PermissionPolicy::Stored)
+    }
+}
+
+/// Parse a `--mode` value given in octal.
+pub fn parse_mode(arg: &str) -> Result<u32, String> {
+    match u32::from_str_radix(arg, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("expected an octal mode such as 640, got '{}'", arg)),
+    }
+}
+
+impl DecryptOptions {
+    /// True when only a slice of the plaintext was requested.
+    fn is_partial(&self) -> bool {
+        self.offset > 0 || self.length.is_some()
+    }
+
+    /// Where the output goes once the metadata is known. In directory mode
+    /// the name is the sanitized stored filename, or failing that the input
+    /// name without its `.gtkrypt` extension, passed through the template.
+    /// A path the caller did not spell out is reported.
+    pub fn resolve_output_path(&self, file_metadata: &Metadata) -> Result<PathBuf, DecryptError> {
+        if self.in_place {
+            return Ok(PathBuf::from(&self.input_path));
+        }
+        if !self.output_dir {
+            let (path, renamed) = self
+                .naming
+                .settle(Path::new(&self.input_path), PathBuf::from(&self.output_path), false)
+                .map_err(naming_error)?;
+            if renamed {
+                progress::emit_output_path(&path);
+            }
+            return Ok(path);
+        }
+        let input = Path::new(&self.input_path);
+        // A split container is named by its first volume
+        let base = Path::new(self.input_path.strip_suffix(".001").unwrap_or(&self.input_path));
+        let name = file_metadata
+            .filename
+            .as_deref()
+            .and_then(metadata::sanitize_filename)
+            .or_else(|| {
+                let stem = base.file_stem()?.to_str()?;
+                let is_container = base.extension().is_some_and(|ext| ext == "gtkrypt");
+                is_container.then(|| metadata::sanitize_filename(stem)).flatten()
+            })
+            .ok_or_else(|| {
+                DecryptError::Internal(
+                    "The container has no stored filename to name the output; give --output"
+                        .to_string(),
+                )
+            })?;
+        let (path, _) = self
+            .naming
+            .resolve(input, Path::new(&self.output_path), &name)
+            .map_err(naming_error)?;
+        progress::emit_output_path(&path);
+        Ok(path)
+    }
+}
+
+/// Lock `output_path` against other runs writing it, until the returned
+/// guard is dropped.
+pub fn lock_output(output_path: &Path) -> Result<OutputLock, DecryptError> {
+    lock::lock_output(output_path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::WouldBlock => DecryptError::Busy(e.to_string()),
+        std::io::ErrorKind::PermissionDenied => {
+            DecryptError::Permission(format!("Cannot lock output: {}", e))
+        }
+        _ => DecryptError::Internal(format!("Failed to lock output: {}", e)),
+    })
+}
+
+/// Log where the chunks that failed authentication lie.
+fn log_bad_chunks(chunks: &[BadChunk]) {
+    for chunk in chunks {
+        log::debug(|| {
+            format!(
+                "chunk {} failed authentication: {} bytes at offset {} of the container",
+                chunk.chunk_index, chunk.file_length, chunk.file_offset
+            )
+        });
+    }
+}
+
+pub fn naming_error(e: NamingError) -> DecryptError {
+    match e {
+        NamingError::Invalid(msg) => DecryptError::Internal(msg),
+        NamingError::Exists(path) => DecryptError::OutputExists(path.display().to_string()),
+        NamingError::SameFile(path) => DecryptError::SameFile(path.display().to_string()),
+    }
 }
 
 /// Perform streaming chunked decryption of a gtkrypt container file and write
 /// plaintext to the output path.
 ///
 /// Reads chunks of (up to 64 KiB ciphertext + 16-byte tag) at a time, keeping
-/// peak memory bounded regardless of input file size.
+/// peak memory bounded regardless of input file size. When `offset` or
+/// `length` is set, seeks straight to the chunks covering that byte range
+/// and writes only the requested slice. With `keep_partial`, corrupted
+/// chunks become holes in an output that is kept, and are reported after
+/// it is in place.
 pub fn decrypt(opts: &DecryptOptions) -> Result<(), DecryptError> {
-    // 1. Open input file with BufReader and read header only
-    let input_file = fs::File::open(&opts.input_path).map_err(|e| {
-        if e.kind() == std::io::ErrorKind::PermissionDenied {
-            DecryptError::Permission(format!("Cannot read input file: {}", e))
-        } else {
-            DecryptError::Internal(format!("Failed to read input file: {}", e))
+    let started = Instant::now();
+    let result = decrypt_file(opts);
+    log::record("decrypt", &opts.input_path, started.elapsed(), &result);
+    audit::record("decrypt", &opts.input_path, &result);
+    result
+}
+
+fn decrypt_file(opts: &DecryptOptions) -> Result<(), DecryptError> {
+    let mut timer = progress::PhaseTimer::start("decrypt");
+    // 1-2. Open input file and parse the header from the stream
+    let io_buffer_size = direct::io_buffer_size(opts.io_buffer_size);
+    let (mut reader, header_obj, header_size, header_bytes) =
+        open_container_buffered(&opts.input_path, io_buffer_size)?;
+
+    // A directory archive is unpacked into a directory at the output path;
+    // a byte range still returns the raw archive stream
+    if header_obj.extension(EXT_ARCHIVE).is_some() && !opts.is_partial() {
+        if opts.in_place {
+            return Err(DecryptError::Internal(
+                "A directory archive cannot be decrypted in place".to_string(),
+            ));
         }
-    })?;
-    let mut reader = BufReader::new(input_file);
+        if opts.scan_all || opts.keep_partial {
+            return Err(DecryptError::Internal(
+                "--scan-all and --keep-partial are not supported for directory archives"
+                    .to_string(),
+            ));
+        }
+        drop(reader);
+        return archive::decrypt_archive(opts, timer);
+    }
 
-    // 2. Parse header from the stream
-    let (header_obj, header_size, header_bytes) =
-        header::read_header_from_reader(&mut reader).map_err(|e| match e {
-            header::HeaderError::InvalidMagic => {
-                DecryptError::CorruptFile(format!("Not a gtkrypt file: {}", e))
-            }
-            header::HeaderError::UnsupportedVersion(_) => {
-                DecryptError::CorruptFile(format!("Unsupported version: {}", e))
-            }
-            header::HeaderError::UnsupportedKdf(_) => {
-                DecryptError::CorruptFile(format!("Unsupported KDF: {}", e))
-            }
-            _ => DecryptError::CorruptFile(format!("Invalid header: {}", e)),
-        })?;
+    // 3. Validate the file has exactly the data for all chunks + tags
+    let container_len = reader.get_ref().size();
+    check_container_size(container_len, &header_obj, header_size)?;
+    let footer_len = header_obj.footer_len().map_err(map_header_error)?;
 
-    // 3. Validate the file has enough data for all chunks + tags
-    let ciphertext_len = header_obj.ciphertext_length as usize;
-    let num_chunks = if ciphertext_len == 0 {
-        0usize
-    } else {
-        (ciphertext_len + CHUNK_SIZE - 1) / CHUNK_SIZE
-    };
+    // 4. Derive key via Argon2id with header params
+    progress::emit_progress("kdf", 0, 0);
 
-    // Guard against nonce reuse: chunk_index is u32, so reject if too many chunks.
-    if num_chunks > u32::MAX as usize {
-        return Err(DecryptError::CorruptFile(format!(
-            "Ciphertext too large: {} chunks exceeds maximum of {}",
-            num_chunks, u32::MAX
-        )));
-    }
+    let key = timer.time("kdf", || {
+        derive_header_key(&opts.passphrase, &header_obj, &opts.keys, &opts.kdf_limits)
+    })?;
 
-    let total_tags_size = num_chunks * TAG_LEN;
+    progress::emit_progress("kdf", 1, 1);
 
-    // Check overall file size
-    let file_size = fs::metadata(&opts.input_path)
-        .map_err(|e| DecryptError::Internal(format!("Failed to stat input file: {}", e)))?
-        .len() as usize;
+    // v3: check the passphrase, then authenticate every header field
+    // before trusting any of them
+    let key_confirmed = verify_key(&header_obj, &header_bytes, &key)?;
+
+    // 5. Resolve file metadata (decrypting the metadata block if present)
+    let file_metadata = metadata::resolve(&header_obj, &header_bytes, &key)
+        .map_err(|e| map_metadata_error(e, key_confirmed))?;
+
+    let output_path = opts.resolve_output_path(&file_metadata)?;
+    let _lock = lock_output(&output_path)?;
+    // In place, the container's own mode and owner pass to the plaintext
+    let in_place_original = opts
+        .in_place
+        .then(|| fs::metadata(&opts.input_path))
+        .transpose()
+        .map_err(|e| {
+            DecryptError::Internal(format!("In-place decryption needs a single file: {}", e))
+        })?;
 
-    let expected_total = header_size + ciphertext_len + total_tags_size;
-    if file_size != expected_total {
+    // Padded payloads carry trailing zero bytes beyond the original size
+    let plaintext_len = file_metadata.original_file_size;
+    if plaintext_len > header_obj.ciphertext_length {
         return Err(DecryptError::CorruptFile(format!(
-            "File size mismatch: expected {} bytes, got {}",
-            expected_total, file_size
+            "Original size {} exceeds ciphertext length {}",
+            plaintext_len, header_obj.ciphertext_length
         )));
     }
 
-    // 4. Extract AAD from raw header bytes
-    let aad = &header_bytes[..AAD_LENGTH];
-
-    // 5. Derive key via Argon2id with header params
-    progress::emit_progress("kdf", 0, 0);
+    // Plaintext byte range to emit
+    if opts.offset > plaintext_len {
+        return Err(DecryptError::Internal(format!(
+            "Offset {} is beyond the end of the {} byte plaintext",
+            opts.offset, plaintext_len
+        )));
+    }
+    let range_start = opts.offset;
+    let range_end = match opts.length {
+        Some(length) => std::cmp::min(range_start.saturating_add(length), plaintext_len),
+        None => plaintext_len,
+    };
 
-    let key = kdf::derive_key(
-        &opts.passphrase,
-        &header_obj.salt,
-        &header_obj.kdf_params,
-    )
-    .map_err(|e| DecryptError::Internal(format!("KDF failed: {}", e)))?;
+    // Chunk layout: from the authenticated index footer when present,
+    // otherwise implied by the fixed chunk size
+    let entries = match footer_len {
+        Some(_) => read_chunk_index(
+            &mut reader,
+            footer_offset(&header_obj, header_size),
+            &header_obj,
+            &header_bytes,
+            &key,
+        )?,
+        None => footer::fixed_layout(header_size, header_obj.ciphertext_length),
+    };
 
-    progress::emit_progress("kdf", 1, 1);
+    // A full decrypt authenticates every chunk, padding included; a range
+    // decrypt only touches the chunks that overlap the range.
+    let mut selected = Vec::new();
+    let mut chunk_start: u64 = 0;
+    for (chunk_index, entry) in entries.iter().enumerate() {
+        let chunk_end = chunk_start + entry.len as u64;
+        let overlaps =
+            range_start < range_end && chunk_start < range_end && chunk_end > range_start;
+        if !opts.is_partial() || overlaps {
+            selected.push((chunk_index as u64, chunk_start, *entry));
+        }
+        chunk_start = chunk_end;
+    }
 
     // 6. Initialize cipher
-    let cipher = Aes256Gcm::new_from_slice(&key)
-        .map_err(|e| DecryptError::Internal(format!("Failed to initialize cipher: {}", e)))?;
-
-    // 7. Open temp output file with BufWriter
-    let output_dir = Path::new(&opts.output_path)
-        .parent()
-        .unwrap_or(Path::new("."));
+    let chunk_cipher = ChunkCipher::new(
+        &header_obj,
+        &header_bytes,
+        &key,
+        entries.len() as u64,
+        key_confirmed,
+        opts.backend,
+    );
+
+    // 7. Open temp output file, buffered by the block writer
+    let staging_dir = match opts.temp_dir {
+        Some(ref dir) => dir.as_path(),
+        None => output_path.parent().unwrap_or(Path::new(".")),
+    };
+    let output_len = range_end - range_start;
+    space::check(staging_dir, output_len)
+        .map_err(|e| output_error("Cannot write output", e))?;
 
-    let temp_file = tempfile::NamedTempFile::new_in(output_dir).map_err(|e| {
+    let temp_file = temp::temp_file_in(staging_dir).map_err(|e| {
         if e.kind() == std::io::ErrorKind::PermissionDenied {
             DecryptError::Permission(format!("Cannot write to output directory: {}", e))
         } else {
             DecryptError::Internal(format!("Failed to create temp file: {}", e))
         }
     })?;
+    space::preallocate(temp_file.as_file(), output_len)
+        .map_err(|e| output_error("Failed to reserve output space", e))?;
+
+    if opts.direct_io {
+        if let Err(e) = reader.get_mut().set_direct_io() {
+            direct::warn_unsupported("input", &e);
+        }
+    }
+    let direct_output = opts.direct_io
+        && direct::set_direct(temp_file.as_file(), true)
+            .map_err(|e| direct::warn_unsupported("output", &e))
+            .is_ok();
+    let mut writer = BlockWriter::new(temp_file.as_file(), direct_output, io_buffer_size);
+    if opts.drop_cache {
+        reader.get_mut().set_drop_cache();
+        writer.set_drop_cache();
+    }
 
-    let mut writer = BufWriter::new(temp_file.as_file());
+    // Only a full decrypt reassembles the whole plaintext to check
+    let mut plaintext_check = if opts.is_partial() {
+        None
+    } else {
+        PlaintextCheck::new(&header_obj, &key)?
+    };
 
     // 8. Stream chunks: read (chunk_ciphertext + 16-byte tag), decrypt, write plaintext
-    progress::emit_progress("decrypt", 0, ciphertext_len as u64);
+    let total_bytes: u64 = selected.iter().map(|(_, _, entry)| entry.len as u64).sum();
+    log::debug(|| {
+        format!(
+            "decrypt: {} chunks, {} to decrypt, {} bytes of output, {} threads, I/O buffer of \
+             {} bytes",
+            entries.len(),
+            selected.len(),
+            output_len,
+            decrypt_threads(opts.threads),
+            io_buffer_size
+        )
+    });
+    progress::emit_progress("decrypt", 0, total_bytes);
 
-    let mut remaining_ciphertext = ciphertext_len;
-    let mut chunk_index: u32 = 0;
     let mut bytes_decrypted: u64 = 0;
-    // Allocate a single buffer large enough for the largest chunk + tag
-    let mut chunk_buf = vec![0u8; CHUNK_SIZE + TAG_LEN];
-
-    while remaining_ciphertext > 0 {
-        let this_chunk_ct_len = std::cmp::min(remaining_ciphertext, CHUNK_SIZE);
-        let read_len = this_chunk_ct_len + TAG_LEN;
+    let mut bad_chunks = Vec::new();
+    let mut position: Option<u64> = None;
+    // Batches of chunks are read, authenticated in parallel and written out
+    // in order, with reading and writing on threads of their own
+    let mut groups = selected.chunks(ChunkBatch::new(opts.threads).capacity());
+
+    timer.time("decrypt", || {
+        pipeline::run(
+            || DecryptBlock {
+                batch: ChunkBatch::new(opts.threads),
+                group: &[],
+                spans: Vec::new(),
+            },
+            |block| {
+                let Some(group) = groups.next() else {
+                    return Ok(false);
+                };
+                let chunks = group.iter().map(|&(chunk_index, _, entry)| (chunk_index, entry));
+                block.batch.read(&mut reader, &mut position, chunks);
+                block.group = group;
+                Ok(true)
+            },
+            |block| {
+                if control::is_cancelled() {
+                    return Err(DecryptError::Cancelled);
+                }
+                block.batch.open(&chunk_cipher);
+                block.spans.clear();
+                for (slot, &(_, chunk_start, entry)) in block.group.iter().enumerate() {
+                    let chunk_len = entry.len as u64;
+                    let keep_from =
+                        std::cmp::min(range_start.saturating_sub(chunk_start), chunk_len) as usize;
+                    let keep_to =
+                        std::cmp::min(range_end.saturating_sub(chunk_start), chunk_len) as usize;
+                    let keep = keep_from..keep_to;
+
+                    match block.batch.take(slot) {
+                        // Write the part of the chunk inside the range, dropping
+                        // any padding
+                        Ok(plaintext) => {
+                            if let Some(ref mut check) = plaintext_check {
+                                check.update(&plaintext[keep.clone()]);
+                            }
+                            block.spans.push(Span::Write(keep));
+                        }
+                        Err(DecryptError::CorruptChunks(_, chunks))
+                            if opts.scan_all || opts.keep_partial =>
+                        {
+                            log_bad_chunks(&chunks);
+                            bad_chunks.extend(chunks);
+                            plaintext_check = None;
+                            // Leave a hole where the chunk would go in a kept output
+                            let hole = if opts.keep_partial { keep.len() } else { 0 };
+                            block.spans.push(Span::Hole(hole));
+                        }
+                        Err(e) => {
+                            if let DecryptError::CorruptChunks(_, ref chunks) = e {
+                                log_bad_chunks(chunks);
+                            }
+                            return Err(e);
+                        }
+                    }
 
-        // Read exactly chunk ciphertext + tag
-        reader
-            .read_exact(&mut chunk_buf[..read_len])
-            .map_err(|e| {
-                if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                    DecryptError::CorruptFile(format!(
-                        "File is truncated at chunk {}",
-                        chunk_index
-                    ))
-                } else {
-                    DecryptError::Internal(format!("Failed to read input: {}", e))
+                    bytes_decrypted += chunk_len;
+                    progress::emit_progress("decrypt", bytes_decrypted, total_bytes);
                 }
-            })?;
+                Ok(())
+            },
+            |block| {
+                for (slot, span) in block.spans.iter().enumerate() {
+                    match span {
+                        Span::Write(range) if !range.is_empty() => {
+                            let plaintext = &block.batch.plaintext(slot)[range.clone()];
+                            writer
+                                .write_all(plaintext)
+                                .map_err(|e| output_error("Failed to write plaintext", e))?;
+                        }
+                        Span::Hole(len) if *len > 0 => {
+                            writer.seek(SeekFrom::Current(*len as i64)).map_err(|e| {
+                                DecryptError::Internal(format!("Failed to seek output: {}", e))
+                            })?;
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(())
+            },
+        )
+    })?;
 
-        // Split into ciphertext and tag
-        let (ct_slice, tag_slice) = chunk_buf[..read_len].split_at_mut(this_chunk_ct_len);
-        let tag = Tag::from_slice(&tag_slice[..TAG_LEN]);
-
-        // Derive per-chunk nonce and AAD
-        let chunk_nonce_bytes = header::derive_chunk_nonce(&header_obj.nonce, chunk_index);
-        let chunk_nonce = Nonce::from_slice(&chunk_nonce_bytes);
-        let chunk_aad = header::build_chunk_aad(aad, chunk_index);
-
-        // Decrypt in place
-        cipher
-            .decrypt_in_place_detached(chunk_nonce, &chunk_aad, ct_slice, tag)
-            .map_err(|_| {
-                DecryptError::WrongPassphrase(
-                    "Decryption failed: incorrect passphrase or corrupted data".to_string(),
-                )
-            })?;
+    let corruption = if bad_chunks.is_empty() {
+        None
+    } else {
+        let mut message = format!(
+            "Authentication failed for {} of {} chunks: data is corrupted",
+            bad_chunks.len(),
+            entries.len()
+        );
+        if !opts.keep_partial {
+            return Err(DecryptError::CorruptChunks(message, bad_chunks));
+        }
+        message.push_str("; the readable data was kept with the bad ranges left as zeros");
+        Some(DecryptError::CorruptChunks(message, bad_chunks))
+    };
 
-        // Write decrypted plaintext
-        writer.write_all(ct_slice).map_err(|e| {
-            DecryptError::Internal(format!("Failed to write plaintext: {}", e))
-        })?;
+    writer
+        .flush()
+        .map_err(|e| output_error("Failed to flush output", e))?;
+    // Drop the writer so only the NamedTempFile owns the file handle
+    drop(writer);
 
-        remaining_ciphertext -= this_chunk_ct_len;
-        bytes_decrypted += this_chunk_ct_len as u64;
-        chunk_index += 1;
+    // Holes at the end only extend the file once its length is set
+    if corruption.is_some() {
+        temp_file
+            .as_file()
+            .set_len(output_len)
+            .map_err(|e| output_error("Failed to extend output", e))?;
+    }
 
-        progress::emit_progress("decrypt", bytes_decrypted, ciphertext_len as u64);
+    if let Some(check) = plaintext_check {
+        check.verify()?;
     }
 
-    writer.flush().map_err(|e| {
-        DecryptError::Internal(format!("Failed to flush output: {}", e))
-    })?;
-    // Drop the BufWriter so only the NamedTempFile owns the file handle
-    drop(writer);
+    // The rename keeps the times, and nothing writes to the file after it
+    if opts.restore.times && !opts.is_partial() {
+        metadata::set_file_times(temp_file.as_file(), file_metadata.mtime, file_metadata.atime)
+            .map_err(|e| DecryptError::Internal(format!("Failed to restore timestamps: {}", e)))?;
+    }
+    if let Some(container) = in_place_original {
+        let kept = metadata::carry_over(&container, temp_file.path())
+            .map_err(|e| DecryptError::Internal(format!("Failed to keep file mode: {}", e)))?;
+        if !kept {
+            warn_owner_not_restored();
+        }
+    }
+    // Before the mode, since changing owner clears setuid and setgid bits
+    let restore_owner = opts.restore.owner && !opts.is_partial() && !opts.in_place;
+    if let Some(owner) = file_metadata.owner.filter(|_| restore_owner) {
+        let restored = metadata::restore_owner(temp_file.path(), owner)
+            .map_err(|e| DecryptError::Internal(format!("Failed to restore owner: {}", e)))?;
+        if !restored {
+            warn_owner_not_restored();
+        }
+    }
 
-    // 9. Atomic rename
-    temp_file
-        .persist(&opts.output_path)
+    // 9. Atomic rename, which only replaces an existing output with --force
+    let replace = opts.in_place || opts.naming.conflict == Conflict::Replace;
+    persist::persist(temp_file, &output_path, replace)
         .map_err(|e| {
-            if e.error.kind() == std::io::ErrorKind::PermissionDenied {
-                DecryptError::Permission(format!("Cannot write to output path: {}", e.error))
+            if e.kind() == std::io::ErrorKind::AlreadyExists {
+                DecryptError::OutputExists(output_path.display().to_string())
+            } else if e.kind() == std::io::ErrorKind::PermissionDenied {
+                DecryptError::Permission(format!("Cannot write to output path: {}", e))
             } else {
-                DecryptError::Internal(format!(
-                    "Failed to rename temp file to output: {}",
-                    e.error
-                ))
+                DecryptError::Internal(format!("Failed to rename temp file to output: {}", e))
             }
         })?;
 
+    // A slice of the file does not inherit the original's permissions
+    let keep_stored = !opts.is_partial() && !opts.in_place;
+    let stored_mode = file_metadata.mode.filter(|&mode| mode != 0 && keep_stored);
     #[cfg(unix)]
+    if let Some(mode) = opts.restore.mode(stored_mode, false) {
+        use std::os::unix::fs::PermissionsExt;
+        let perms = fs::Permissions::from_mode(mode & 0o7777);
+        fs::set_permissions(&output_path, perms).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::PermissionDenied {
+                DecryptError::Permission(format!("Cannot set output permissions: {}", e))
+            } else {
+                DecryptError::Internal(format!("Failed to set output permissions: {}", e))
+            }
+        })?;
+    }
+    let stored_attributes = file_metadata.attributes.filter(|_| keep_stored);
+    if let Some(attributes) = opts.restore.attributes(stored_attributes) {
+        metadata::set_file_attributes(&output_path, attributes).map_err(|e| {
+            DecryptError::Internal(format!("Failed to set output attributes: {}", e))
+        })?;
+    }
+
+    progress::emit_progress("decrypt", total_bytes, total_bytes);
+
+    match corruption {
+        Some(e) => Err(e),
+        None => {
+            timer.emit(&output_path, container_len, output_len, selected.len() as u64);
+            Ok(())
+        }
+    }
+}
+
+/// Per-container state for authenticating and decrypting payload chunks.
+struct ChunkCipher {
+    cipher: PayloadCipher,
+    version: u8,
+    nonce: [u8; NONCE_LEN],
+    aad: Vec<u8>,
+    num_chunks: u64,
+    key_confirmed: bool,
+}
+
+impl ChunkCipher {
+    fn new(
+        header_obj: &ContainerHeader,
+        header_bytes: &[u8],
+        key: &[u8; 32],
+        num_chunks: u64,
+        key_confirmed: bool,
+        backend: Backend,
+    ) -> Self {
+        // v3 encrypts the payload under a dedicated subkey
+        let payload_key = if header_obj.version >= 3 {
+            kdf::derive_subkey(key, SUBKEY_PAYLOAD)
+        } else {
+            *key
+        };
+        ChunkCipher {
+            cipher: PayloadCipher::new(&payload_key, backend),
+            version: header_obj.version,
+            nonce: header_obj.nonce,
+            aad: header::extract_aad(header_bytes).to_vec(),
+            num_chunks,
+            key_confirmed,
+        }
+    }
+
+    /// Decrypt one chunk in place.
+    ///
+    /// For v3, a last chunk that authenticates as non-final means the stream
+    /// was cut at a chunk boundary, which is reported as truncation.
+    fn decrypt(
+        &self,
+        chunk_index: u64,
+        ct_slice: &mut [u8],
+        tag: &Tag,
+    ) -> Result<(), DecryptError> {
+        let last = chunk_index + 1 == self.num_chunks;
+        // A failed attempt may scramble the buffer, so the ciphertext is
+        // kept aside for the second one
+        let retry = (last && self.version >= 3).then(|| ct_slice.to_vec());
+        if self.try_decrypt(chunk_index, last, ct_slice, tag)? {
+            return Ok(());
+        }
+        if let Some(ciphertext) = retry {
+            ct_slice.copy_from_slice(&ciphertext);
+        } else {
+            return Err(auth_failure(self.key_confirmed, &format!("chunk {}", chunk_index)));
+        }
+        if self.try_decrypt(chunk_index, false, ct_slice, tag)? {
+            return Err(DecryptError::CorruptFile(format!(
+                "File is truncated: chunk {} is not marked as the final chunk",
+                chunk_index
+            )));
+        }
+        Err(auth_failure(self.key_confirmed, &format!("chunk {}", chunk_index)))
+    }
+
+    /// Attempt to decrypt a chunk with the given final-chunk flag.
+    fn try_decrypt(
+        &self,
+        chunk_index: u64,
+        last: bool,
+        ct_slice: &mut [u8],
+        tag: &Tag,
+    ) -> Result<bool, DecryptError> {
+        let nonce_bytes = header::chunk_nonce(self.version, &self.nonce, chunk_index, last);
+        let chunk_aad = header::chunk_aad(self.version, &self.aad, chunk_index, last);
+        self.cipher
+            .open(&nonce_bytes, &chunk_aad, ct_slice, tag)
+            .map_err(|e| DecryptError::Internal(format!("Decryption failed: {}", e)))
+    }
+}
+
+/// Bytes set aside per chunk in a `ChunkBatch`: a full chunk and its tag.
+const SLOT_LEN: usize = CHUNK_SIZE + TAG_LEN;
+
+/// Chunks each worker thread authenticates per batch.
+const CHUNKS_PER_THREAD: usize = 8;
+
+/// Number of threads to decrypt with for a `threads` setting, where 0 means
+/// one per available core.
+pub fn decrypt_threads(threads: usize) -> usize {
+    match threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+}
+
+/// A run of chunks read in order, then authenticated and decrypted on
+/// several threads at once and handed back in order.
+struct ChunkBatch {
+    threads: usize,
+    /// One `SLOT_LEN` slot per chunk, holding its plaintext once opened.
+    buf: Vec<u8>,
+    chunks: Vec<(u64, ChunkEntry, Result<(), DecryptError>)>,
+}
+
+impl ChunkBatch {
+    fn new(threads: usize) -> Self {
+        ChunkBatch {
+            threads: decrypt_threads(threads),
+            buf: Vec::new(),
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Most chunks `load` takes at once.
+    fn capacity(&self) -> usize {
+        self.threads * CHUNKS_PER_THREAD
+    }
+
+    /// `read` and `open` in one go.
+    fn load<I>(
+        &mut self,
+        reader: &mut BufReader<VolumeReader>,
+        position: &mut Option<u64>,
+        chunks: I,
+        cipher: &ChunkCipher,
+    ) where
+        I: IntoIterator<Item = (u64, ChunkEntry)>,
     {
-        if let Some(mode) = header_obj.mode {
-            if mode != 0 {
-                use std::os::unix::fs::PermissionsExt;
-                let perms = fs::Permissions::from_mode(mode & 0o7777);
-                fs::set_permissions(&opts.output_path, perms).map_err(|e| {
-                    if e.kind() == std::io::ErrorKind::PermissionDenied {
-                        DecryptError::Permission(format!(
-                            "Cannot set output permissions: {}",
-                            e
-                        ))
-                    } else {
-                        DecryptError::Internal(format!(
-                            "Failed to set output permissions: {}",
-                            e
-                        ))
-                    }
-                })?;
+        self.read(reader, position, chunks);
+        self.open(cipher);
+    }
+
+    /// Read `chunks`, at most `capacity` of them, replacing the batch.
+    /// `position` is the input offset the reader is known to be at, so that
+    /// only gaps between chunks cost a seek. A chunk that cannot be read
+    /// keeps its error for `take`.
+    fn read<I>(
+        &mut self,
+        reader: &mut BufReader<VolumeReader>,
+        position: &mut Option<u64>,
+        chunks: I,
+    ) where
+        I: IntoIterator<Item = (u64, ChunkEntry)>,
+    {
+        self.chunks.clear();
+        for (slot, (chunk_index, entry)) in chunks.into_iter().enumerate() {
+            let end = slot * SLOT_LEN + entry.stored_len() as usize;
+            if self.buf.len() < end {
+                self.buf.resize((slot + 1) * SLOT_LEN, 0);
+            }
+            let buf = &mut self.buf[slot * SLOT_LEN..end];
+            let result = read_chunk(reader, position, chunk_index, &entry, buf)
+                .map_err(|e| locate_chunk_error(e, chunk_index, &entry));
+            self.chunks.push((chunk_index, entry, result));
+        }
+    }
+
+    /// Authenticate and decrypt the chunks read, in place. A chunk that
+    /// fails keeps its error for `take`.
+    fn open(&mut self, cipher: &ChunkCipher) {
+        // Contiguous runs of chunks per thread, so each writes only its slots
+        let per_thread = self.chunks.len().div_ceil(self.threads).max(1);
+        if self.chunks.len() <= per_thread {
+            open_slots(cipher, &mut self.buf, &mut self.chunks);
+            return;
+        }
+        std::thread::scope(|scope| {
+            let slots = self.buf.chunks_mut(per_thread * SLOT_LEN);
+            for (slots, chunks) in slots.zip(self.chunks.chunks_mut(per_thread)) {
+                scope.spawn(move || open_slots(cipher, slots, chunks));
             }
+        });
+    }
+
+    /// The slot of `chunk_index` if it is loaded and authenticated.
+    fn slot_of(&self, chunk_index: u64) -> Option<usize> {
+        let first = self.chunks.first()?.0;
+        let slot = chunk_index.checked_sub(first)? as usize;
+        match self.chunks.get(slot) {
+            Some((index, _, Ok(()))) if *index == chunk_index => Some(slot),
+            _ => None,
         }
     }
 
-    progress::emit_progress("decrypt", ciphertext_len as u64, ciphertext_len as u64);
+    /// The plaintext of the chunk in `slot`, or the error it failed with.
+    /// A failed slot gives its error only once.
+    fn take(&mut self, slot: usize) -> Result<&[u8], DecryptError> {
+        let (chunk_index, _, result) = &mut self.chunks[slot];
+        if result.is_err() {
+            let taken = DecryptError::Internal(format!("Chunk {} failed to decrypt", chunk_index));
+            std::mem::replace(result, Err(taken))?;
+        }
+        Ok(self.plaintext(slot))
+    }
 
+    /// The decrypted bytes in `slot`.
+    fn plaintext(&self, slot: usize) -> &[u8] {
+        &self.buf[slot * SLOT_LEN..][..self.chunks[slot].1.len as usize]
+    }
+}
+
+/// A batch of chunks on its way through the decrypt pipeline.
+struct DecryptBlock<'a> {
+    batch: ChunkBatch,
+    /// The selected chunks in the batch, with their plaintext offsets.
+    group: &'a [(u64, u64, ChunkEntry)],
+    /// What to write for each chunk of the batch.
+    spans: Vec<Span>,
+}
+
+/// Output of one chunk: part of its plaintext, or a hole of zeros in place
+/// of a bad chunk.
+enum Span {
+    Write(std::ops::Range<usize>),
+    Hole(usize),
+}
+
+/// Read one chunk and its tag into `buf`, seeking first unless the input
+/// is already at the chunk. `position` is unknown after a failed read.
+fn read_chunk(
+    reader: &mut BufReader<VolumeReader>,
+    position: &mut Option<u64>,
+    chunk_index: u64,
+    entry: &ChunkEntry,
+    buf: &mut [u8],
+) -> Result<(), DecryptError> {
+    if *position != Some(entry.offset) {
+        reader
+            .seek(SeekFrom::Start(entry.offset))
+            .map_err(|e| DecryptError::Internal(format!("Failed to seek input: {}", e)))?;
+    }
+    *position = None;
+    reader.read_exact(buf).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            DecryptError::CorruptFile(format!("File is truncated at chunk {}", chunk_index))
+        } else {
+            DecryptError::Internal(format!("Failed to read input: {}", e))
+        }
+    })?;
+    *position = Some(entry.offset + entry.stored_len());
     Ok(())
 }
 
+/// Decrypt the chunks whose read succeeded, each in its slot of `slots`.
+fn open_slots(
+    cipher: &ChunkCipher,
+    slots: &mut [u8],
+    chunks: &mut [(u64, ChunkEntry, Result<(), DecryptError>)],
+) {
+    for ((chunk_index, entry, result), slot) in chunks.iter_mut().zip(slots.chunks_mut(SLOT_LEN)) {
+        if result.is_err() {
+            continue;
+        }
+        let stored = &mut slot[..entry.stored_len() as usize];
+        let (ct_slice, tag_slice) = stored.split_at_mut(entry.len as usize);
+        *result = cipher
+            .decrypt(*chunk_index, ct_slice, Tag::from_slice(tag_slice))
+            .map_err(|e| locate_chunk_error(e, *chunk_index, entry));
+    }
+}
+
+/// Open a container and authenticate it up to the payload.
+///
+/// Derives the key, checks the header and metadata, and returns a reader
+/// that yields the plaintext as it decrypts the chunks in order. Used by
+/// operations that stream one container into another.
+pub fn open_payload(
+    input_path: &str,
+    passphrase: &[u8],
+) -> Result<(ContainerHeader, Metadata, PayloadReader), DecryptError> {
+    open_payload_cached(input_path, passphrase, &KeyCache::default(), &KdfLimits::default())
+}
+
+/// `open_payload` taking the key from `keys` when a container with the
+/// same salt was opened before, and honouring the header's Argon2id
+/// parameters only within `limits`.
+pub fn open_payload_cached(
+    input_path: &str,
+    passphrase: &[u8],
+    keys: &KeyCache,
+    limits: &KdfLimits,
+) -> Result<(ContainerHeader, Metadata, PayloadReader), DecryptError> {
+    open_payload_with(input_path, |header_obj| {
+        progress::emit_progress("kdf", 0, 0);
+        let key = derive_header_key(passphrase, header_obj, keys, limits)?;
+        progress::emit_progress("kdf", 1, 1);
+        Ok(key)
+    })
+}
+
+/// Derive the key of a container with the Argon2id parameters of its
+/// header, through `keys`. Fails up front with `KdfParamsExcessive` when
+/// the parameters exceed `limits`, and with `InsufficientMemory` when the
+/// memory they ask for is not available, rather than being killed part
+/// way.
+pub fn derive_header_key(
+    passphrase: &[u8],
+    header_obj: &ContainerHeader,
+    keys: &KeyCache,
+    limits: &KdfLimits,
+) -> Result<[u8; 32], DecryptError> {
+    limits.check(&header_obj.kdf_params).map_err(DecryptError::KdfParamsExcessive)?;
+    kdf::check_memory(&header_obj.kdf_params).map_err(DecryptError::InsufficientMemory)?;
+    keys.derive(passphrase, &header_obj.salt, &header_obj.kdf_params)
+        .map_err(|e| DecryptError::Internal(format!("KDF failed: {}", e)))
+}
+
+/// Authenticate every chunk of a container with an already derived key,
+/// discarding the plaintext. Progress is reported under "verify".
+pub fn verify_container(input_path: &str, key: &[u8; 32]) -> Result<(), DecryptError> {
+    let (_header_obj, file_metadata, mut payload) = open_payload_with(input_path, |_| Ok(*key))?;
+    let total = file_metadata.original_file_size;
+    progress::emit_progress("verify", 0, total);
+
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut verified: u64 = 0;
+    loop {
+        control::wait_if_paused();
+        if control::is_cancelled() {
+            return Err(DecryptError::Cancelled);
+        }
+        match payload.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => verified += n as u64,
+            Err(e) => {
+                return Err(payload.take_error().unwrap_or_else(|| {
+                    DecryptError::Internal(format!("Failed to read container: {}", e))
+                }))
+            }
+        }
+        progress::emit_progress("verify", verified, total);
+    }
+    Ok(())
+}
+
+/// `open_payload` with the key supplied by `derive_key` once the header
+/// has passed the size check.
+fn open_payload_with<F>(
+    input_path: &str,
+    derive_key: F,
+) -> Result<(ContainerHeader, Metadata, PayloadReader), DecryptError>
+where
+    F: FnOnce(&ContainerHeader) -> Result<[u8; 32], DecryptError>,
+{
+    let (mut reader, header_obj, header_size, header_bytes) = open_container(input_path)?;
+    let container_len = reader.get_ref().size();
+    check_container_size(container_len, &header_obj, header_size)?;
+
+    let key = derive_key(&header_obj)?;
+
+    let key_confirmed = verify_key(&header_obj, &header_bytes, &key)?;
+    let file_metadata = metadata::resolve(&header_obj, &header_bytes, &key)
+        .map_err(|e| map_metadata_error(e, key_confirmed))?;
+    if file_metadata.original_file_size > header_obj.ciphertext_length {
+        return Err(DecryptError::CorruptFile(format!(
+            "Original size {} exceeds ciphertext length {}",
+            file_metadata.original_file_size, header_obj.ciphertext_length
+        )));
+    }
+
+    let entries = match header_obj.footer_len().map_err(map_header_error)? {
+        Some(_) => {
+            let footer_offset = footer_offset(&header_obj, header_size);
+            let entries =
+                read_chunk_index(&mut reader, footer_offset, &header_obj, &header_bytes, &key)?;
+            reader
+                .seek(SeekFrom::Start(header_size as u64))
+                .map_err(|e| DecryptError::Internal(format!("Failed to seek input: {}", e)))?;
+            entries
+        }
+        None => footer::fixed_layout(header_size, header_obj.ciphertext_length),
+    };
+
+    let chunk_cipher = ChunkCipher::new(
+        &header_obj,
+        &header_bytes,
+        &key,
+        entries.len() as u64,
+        key_confirmed,
+        Backend::Software,
+    );
+    let plaintext_check = PlaintextCheck::new(&header_obj, &key)?;
+    let payload = PayloadReader {
+        reader,
+        chunk_cipher,
+        plaintext_check,
+        entries,
+        next_chunk: 0,
+        plaintext_len: file_metadata.original_file_size,
+        remaining: file_metadata.original_file_size,
+        batch: ChunkBatch::new(0),
+        position: None,
+        slot: 0,
+        pos: 0,
+        end: 0,
+        error: None,
+    };
+    Ok((header_obj, file_metadata, payload))
+}
+
+/// Running keyed hash of decrypted plaintext, compared with the hash stored
+/// in the header once all of it has been seen.
+struct PlaintextCheck {
+    hasher: blake3::Hasher,
+    expected: [u8; PLAINTEXT_HASH_LEN],
+}
+
+impl PlaintextCheck {
+    /// `None` when the container stores no plaintext hash.
+    fn new(header_obj: &ContainerHeader, key: &[u8; 32]) -> Result<Option<Self>, DecryptError> {
+        Ok(header_obj
+            .plaintext_hash()
+            .map_err(map_header_error)?
+            .map(|expected| PlaintextCheck {
+                hasher: header::plaintext_hasher(key),
+                expected,
+            }))
+    }
+
+    fn update(&mut self, plaintext: &[u8]) {
+        self.hasher.update(plaintext);
+    }
+
+    fn verify(self) -> Result<(), DecryptError> {
+        // blake3::Hash compares in constant time
+        if self.hasher.finalize() == self.expected {
+            Ok(())
+        } else {
+            Err(DecryptError::CorruptFile(
+                "Decrypted data does not match the stored plaintext hash".to_string(),
+            ))
+        }
+    }
+}
+
+/// Plaintext of a container, decrypted chunk by chunk as it is read.
+///
+/// Every chunk is authenticated, including trailing padding, which is
+/// dropped. A decryption failure surfaces as an I/O error; the underlying
+/// `DecryptError` is kept for `take_error`.
+pub struct PayloadReader {
+    reader: BufReader<VolumeReader>,
+    chunk_cipher: ChunkCipher,
+    /// Checked once the last chunk has been read.
+    plaintext_check: Option<PlaintextCheck>,
+    entries: Vec<ChunkEntry>,
+    next_chunk: usize,
+    plaintext_len: u64,
+    /// Plaintext bytes not yet decrypted.
+    remaining: u64,
+    /// Chunks read ahead and decrypted in parallel.
+    batch: ChunkBatch,
+    /// Input offset the reader is at, when known.
+    position: Option<u64>,
+    /// Slot in `batch` of the chunk being read.
+    slot: usize,
+    pos: usize,
+    end: usize,
+    error: Option<DecryptError>,
+}
+
+impl PayloadReader {
+    /// True when the container is a volume set.
+    pub fn is_split(&self) -> bool {
+        self.reader.get_ref().is_split()
+    }
+
+    /// Size of the container, across all of its volumes.
+    pub fn container_len(&self) -> u64 {
+        self.reader.get_ref().size()
+    }
+
+    /// Number of payload chunks, padding included.
+    pub fn chunk_count(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    /// The decryption failure behind the last read error, if any.
+    pub fn take_error(&mut self) -> Option<DecryptError> {
+        self.error.take()
+    }
+
+    /// Continue reading from plaintext offset `offset`.
+    ///
+    /// Only the chunk holding the offset is decrypted. Reading out of order
+    /// gives up the whole-payload hash check, so callers that seek must
+    /// authenticate what they read by other means.
+    pub fn seek_plaintext(&mut self, offset: u64) -> Result<(), DecryptError> {
+        if offset > self.plaintext_len {
+            return Err(DecryptError::Internal(format!(
+                "Offset {} is beyond the end of the {} byte plaintext",
+                offset, self.plaintext_len
+            )));
+        }
+        self.plaintext_check = None;
+
+        let chunk = (offset / CHUNK_SIZE as u64) as usize;
+        let chunk_start = chunk as u64 * CHUNK_SIZE as u64;
+        if chunk >= self.entries.len() {
+            self.next_chunk = self.entries.len();
+            self.remaining = 0;
+            self.pos = 0;
+            self.end = 0;
+            return Ok(());
+        }
+        // The batch may already hold the chunk
+        if self.batch.slot_of(chunk as u64).is_none() {
+            self.load(chunk..chunk + 1);
+        }
+        self.next_chunk = chunk;
+        self.remaining = self.plaintext_len - chunk_start;
+        self.decrypt_next_chunk()?;
+        self.pos = (offset - chunk_start) as usize;
+        Ok(())
+    }
+
+    /// Read and decrypt the chunks in `chunks`.
+    fn load(&mut self, chunks: std::ops::Range<usize>) {
+        let entries = self.entries[chunks.clone()].iter().copied();
+        self.batch.load(
+            &mut self.reader,
+            &mut self.position,
+            chunks.map(|chunk_index| chunk_index as u64).zip(entries),
+            &self.chunk_cipher,
+        );
+    }
+
+    /// Move on to the next chunk, reading ahead a batch when it is not
+    /// loaded yet.
+    fn decrypt_next_chunk(&mut self) -> Result<(), DecryptError> {
+        let chunk_index = self.next_chunk;
+        let slot = match self.batch.slot_of(chunk_index as u64) {
+            Some(slot) => slot,
+            None => {
+                let end = std::cmp::min(chunk_index + self.batch.capacity(), self.entries.len());
+                self.load(chunk_index..end);
+                0
+            }
+        };
+        let plaintext = self.batch.take(slot)?;
+
+        self.next_chunk += 1;
+        self.slot = slot;
+        self.pos = 0;
+        self.end = std::cmp::min(plaintext.len() as u64, self.remaining) as usize;
+        self.remaining -= self.end as u64;
+        if let Some(ref mut check) = self.plaintext_check {
+            check.update(&plaintext[..self.end]);
+        }
+        Ok(())
+    }
+}
+
+impl Read for PayloadReader {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        if self.error.is_some() {
+            return Err(std::io::Error::other("container failed to decrypt"));
+        }
+        // Padding-only chunks yield nothing but must still authenticate
+        while self.pos == self.end {
+            let result = if self.next_chunk < self.entries.len() {
+                self.decrypt_next_chunk()
+            } else {
+                // End of the payload: check the plaintext hash once
+                match self.plaintext_check.take() {
+                    Some(check) => check.verify(),
+                    None => return Ok(0),
+                }
+            };
+            if let Err(e) = result {
+                let io_error = std::io::Error::other(e.to_string());
+                self.error = Some(e);
+                return Err(io_error);
+            }
+        }
+        let n = std::cmp::min(out.len(), self.end - self.pos);
+        let plaintext = self.batch.plaintext(self.slot);
+        out[..n].copy_from_slice(&plaintext[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Verify a passphrase against a container without decrypting it.
+///
+/// For v3 containers only the header is read; the key-check value and
+/// header MAC decide. v1/v2 containers have neither, so the first chunk is
+/// authenticated instead.
+pub fn check_passphrase(input_path: &str, passphrase: &[u8]) -> Result<(), DecryptError> {
+    let (mut reader, header_obj, header_size, header_bytes) = open_container(input_path)?;
+    let container_len = reader.get_ref().size();
+    check_container_size(container_len, &header_obj, header_size)?;
+
+    progress::emit_progress("kdf", 0, 0);
+    let key =
+        derive_header_key(passphrase, &header_obj, &KeyCache::default(), &KdfLimits::default())?;
+    progress::emit_progress("kdf", 1, 1);
+
+    verify_key(&header_obj, &header_bytes, &key)?;
+    if header_obj.version >= 3 || header_obj.ciphertext_length == 0 {
+        return Ok(());
+    }
+
+    let chunk_len = std::cmp::min(header_obj.ciphertext_length, CHUNK_SIZE as u64) as usize;
+    let mut chunk_buf = vec![0u8; chunk_len + TAG_LEN];
+    reader.read_exact(&mut chunk_buf).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            DecryptError::CorruptFile("File is truncated at chunk 0".to_string())
+        } else {
+            DecryptError::Internal(format!("Failed to read input: {}", e))
+        }
+    })?;
+    let (ct_slice, tag_slice) = chunk_buf.split_at_mut(chunk_len);
+
+    let cipher = Aes256Gcm::new_from_slice(&key)
+        .map_err(|e| DecryptError::Internal(format!("Failed to initialize cipher: {}", e)))?;
+    let last = header_obj.ciphertext_length <= CHUNK_SIZE as u64;
+    let nonce_bytes = header::chunk_nonce(header_obj.version, &header_obj.nonce, 0, last);
+    let aad = header::extract_aad(&header_bytes);
+    let chunk_aad = header::chunk_aad(header_obj.version, &aad, 0, last);
+    cipher
+        .decrypt_in_place_detached(
+            Nonce::from_slice(&nonce_bytes),
+            &chunk_aad,
+            ct_slice,
+            Tag::from_slice(tag_slice),
+        )
+        .map_err(|_| auth_failure(false, "chunk 0"))
+}
+
+/// Report that stored owners could not be applied for lack of privilege.
+pub fn warn_owner_not_restored() {
+    progress::emit_warning(
+        "owner_not_restored",
+        "Not permitted to restore the stored owner; the output belongs to the current user",
+    );
+}
+
+/// Offset just past the last chunk, where the chunk index footer starts.
+pub fn footer_offset(header_obj: &ContainerHeader, header_size: usize) -> u64 {
+    let num_chunks = header_obj.ciphertext_length.div_ceil(CHUNK_SIZE as u64);
+    (header_size as u64)
+        .saturating_add(header_obj.ciphertext_length)
+        .saturating_add(num_chunks * TAG_LEN as u64)
+}
+
+/// Total file size implied by a header: the header itself, the ciphertext,
+/// one tag per chunk, the chunk index footer and the parity region if
+/// present.
+pub fn expected_container_size(
+    header_obj: &ContainerHeader,
+    header_size: usize,
+) -> Result<u64, DecryptError> {
+    let footer_len = header_obj.footer_len().map_err(map_header_error)?;
+    let parity = header_obj.parity().map_err(map_header_error)?;
+    Ok(footer_offset(header_obj, header_size)
+        .saturating_add(footer_len.unwrap_or(0))
+        .saturating_add(parity.map_or(0, |(len, _)| len)))
+}
+
+/// Check that a container of `file_size` bytes, all volumes together, holds
+/// exactly what its header accounts for. This runs before the KDF, so a
+/// header claiming a payload the file does not have is turned away cheaply.
+pub fn check_container_size(
+    file_size: u64,
+    header_obj: &ContainerHeader,
+    header_size: usize,
+) -> Result<(), DecryptError> {
+    // Guard against nonce reuse: v1/v2 chunk indices are u32, so reject if
+    // too many chunks. v3 uses a 64-bit counter.
+    let num_chunks = header_obj.ciphertext_length.div_ceil(CHUNK_SIZE as u64);
+    if header_obj.version < 3 && num_chunks > u32::MAX as u64 {
+        return Err(DecryptError::CorruptFile(format!(
+            "Ciphertext too large: {} chunks exceeds maximum of {}",
+            num_chunks,
+            u32::MAX
+        )));
+    }
+
+    let expected_total = expected_container_size(header_obj, header_size)?;
+    if file_size != expected_total {
+        return Err(DecryptError::SizeMismatch(expected_total, file_size));
+    }
+    Ok(())
+}
+
+/// Open a container, either a single file or a volume set, and parse its
+/// header without reading the payload.
+///
+/// Returns the reader positioned at the first chunk, the parsed header,
+/// the header size in bytes, and the raw header bytes.
+pub fn open_container(
+    path: &str,
+) -> Result<(BufReader<VolumeReader>, ContainerHeader, usize, Vec<u8>), DecryptError> {
+    // The default BufReader size, plenty for the header and a few chunks
+    open_container_buffered(path, 8 * 1024)
+}
+
+/// Like `open_container`, with a read buffer of `capacity` bytes for
+/// streaming the payload.
+pub fn open_container_buffered(
+    path: &str,
+    capacity: usize,
+) -> Result<(BufReader<VolumeReader>, ContainerHeader, usize, Vec<u8>), DecryptError> {
+    let input_file = VolumeReader::open(path).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            DecryptError::Permission(format!("Cannot read input file: {}", e))
+        } else {
+            DecryptError::Internal(format!("Failed to read input file: {}", e))
+        }
+    })?;
+    let mut reader = BufReader::with_capacity(capacity, input_file);
+
+    let (header_obj, header_size, header_bytes) =
+        header::read_header_from_reader(&mut reader).map_err(map_header_error)?;
+
+    Ok((reader, header_obj, header_size, header_bytes))
+}
+
+/// Check the derived key against the header.
+///
+/// With a key-check value, a mismatch is reported as a wrong passphrase and
+/// any later authentication failure as corruption. Returns whether the key
+/// was confirmed this way.
+pub fn verify_key(
+    header_obj: &ContainerHeader,
+    header_bytes: &[u8],
+    key: &[u8; 32],
+) -> Result<bool, DecryptError> {
+    let key_confirmed = match header_obj.extension(EXT_KEY_CHECK) {
+        Some(value) => {
+            if !header::verify_key_check(value, key) {
+                return Err(DecryptError::WrongPassphrase("Incorrect passphrase".to_string()));
+            }
+            true
+        }
+        None => false,
+    };
+
+    if !header::verify_header(header_bytes, key) {
+        return Err(auth_failure(key_confirmed, "header"));
+    }
+    Ok(key_confirmed)
+}
+
+/// Error for an authentication failure on `what`. Without a confirmed key
+/// a wrong passphrase and corrupted data are indistinguishable.
+fn auth_failure(key_confirmed: bool, what: &str) -> DecryptError {
+    if key_confirmed {
+        DecryptError::CorruptFile(format!("Authentication failed for {}: data is corrupted", what))
+    } else {
+        DecryptError::WrongPassphrase(
+            "Decryption failed: incorrect passphrase or corrupted data".to_string(),
+        )
+    }
+}
+
+/// Attach the location of a chunk to a corruption error raised while
+/// decrypting it. Other errors pass through unchanged.
+/// Error for a failed write to the output, telling a full disk apart.
+fn output_error(context: &str, e: std::io::Error) -> DecryptError {
+    if space::is_disk_full(&e) {
+        DecryptError::DiskFull(format!("{}: {}", context, e))
+    } else {
+        DecryptError::Internal(format!("{}: {}", context, e))
+    }
+}
+
+fn locate_chunk_error(e: DecryptError, chunk_index: u64, entry: &ChunkEntry) -> DecryptError {
+    match e {
+        DecryptError::CorruptFile(msg) => DecryptError::CorruptChunks(
+            msg,
+            vec![BadChunk {
+                chunk_index,
+                file_offset: entry.offset,
+                file_length: entry.stored_len(),
+                // Every chunk but the last holds exactly CHUNK_SIZE bytes
+                plaintext_offset: chunk_index * CHUNK_SIZE as u64,
+                plaintext_length: entry.len as u64,
+            }],
+        ),
+        e => e,
+    }
+}
+
+/// Read and verify the chunk index footer starting at `footer_offset`.
+pub fn read_chunk_index(
+    reader: &mut BufReader<VolumeReader>,
+    footer_offset: u64,
+    header_obj: &ContainerHeader,
+    header_bytes: &[u8],
+    key: &[u8; 32],
+) -> Result<Vec<ChunkEntry>, DecryptError> {
+    reader
+        .seek(SeekFrom::Start(footer_offset))
+        .map_err(|e| DecryptError::Internal(format!("Failed to seek input: {}", e)))?;
+    // Anything after the index (parity data) is not part of it
+    let footer_len = header_obj.footer_len().map_err(map_header_error)?.unwrap_or(0);
+    let mut data = Vec::new();
+    reader
+        .take(footer_len)
+        .read_to_end(&mut data)
+        .map_err(|e| DecryptError::Internal(format!("Failed to read chunk index: {}", e)))?;
+
+    footer::decode(&data, header_bytes, header_obj.ciphertext_length, key).map_err(|e| match e {
+        FooterError::Authentication => {
+            DecryptError::CorruptFile("Chunk index authentication failed".to_string())
+        }
+        FooterError::Malformed(msg) => DecryptError::CorruptFile(msg),
+    })
+}
+
+/// Classify a header parse failure as a decryption error.
+pub fn map_header_error(e: HeaderError) -> DecryptError {
+    match e {
+        HeaderError::InvalidMagic => DecryptError::CorruptFile(format!("Not a gtkrypt file: {}", e)),
+        HeaderError::UnsupportedVersion(_) => {
+            DecryptError::CorruptFile(format!("Unsupported version: {}", e))
+        }
+        HeaderError::UnsupportedKdf(_) => DecryptError::CorruptFile(format!("Unsupported KDF: {}", e)),
+        _ => DecryptError::CorruptFile(format!("Invalid header: {}", e)),
+    }
+}
+
+/// Map a metadata failure to a decryption error.
+pub fn map_metadata_error(e: MetadataError, key_confirmed: bool) -> DecryptError {
+    match e {
+        MetadataError::Authentication => auth_failure(key_confirmed, "metadata"),
+        MetadataError::Malformed(msg) => DecryptError::CorruptFile(msg),
+    }
+}
+
 /// Errors that can occur during decryption.
 #[derive(Debug)]
 pub enum DecryptError {
     WrongPassphrase(String),
     CorruptFile(String),
+    /// Corruption located in specific chunks of the payload.
+    CorruptChunks(String, Vec<BadChunk>),
+    /// The container is not the size its header implies: the expected and
+    /// the actual size in bytes.
+    SizeMismatch(u64, u64),
     Permission(String),
     Internal(String),
+    /// The output path is taken and the naming policy forbids replacing it.
+    OutputExists(String),
+    /// The output path names the input file itself.
+    SameFile(String),
+    /// The output does not fit on its filesystem.
+    DiskFull(String),
+    /// The Argon2id memory the header asks for is not available.
+    InsufficientMemory(String),
+    /// The header's Argon2id parameters exceed the caller's limits.
+    KdfParamsExcessive(String),
+    /// Another process is writing the same output.
+    Busy(String),
+    /// The operation was cancelled between two chunks.
+    Cancelled,
 }
 
 impl std::fmt::Display for DecryptError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             DecryptError::WrongPassphrase(msg) => write!(f, "Wrong passphrase: {}", msg),
-            DecryptError::CorruptFile(msg) => write!(f, "Corrupt file: {}", msg),
+            DecryptError::CorruptFile(msg) | DecryptError::CorruptChunks(msg, _) => {
+                write!(f, "Corrupt file: {}", msg)
+            }
+            DecryptError::SizeMismatch(expected, actual) => write!(
+                f,
+                "Corrupt file: File size mismatch: expected {} bytes, got {}",
+                expected, actual
+            ),
             DecryptError::Permission(msg) => write!(f, "Permission error: {}", msg),
             DecryptError::Internal(msg) => write!(f, "Internal error: {}", msg),
+            DecryptError::OutputExists(path) => write!(f, "Output already exists: {}", path),
+            DecryptError::SameFile(path) => write!(f, "Output {} is the input file itself", path),
+            DecryptError::DiskFull(msg) => write!(f, "Disk full: {}", msg),
+            DecryptError::InsufficientMemory(msg) => write!(f, "Insufficient memory: {}", msg),
+            DecryptError::KdfParamsExcessive(msg) => {
+                write!(f, "KDF parameters excessive: {}", msg)
+            }
+            DecryptError::Busy(msg) => write!(f, "Resource busy: {}", msg),
+            DecryptError::Cancelled => write!(f, "Cancelled"),
         }
     }
 }
@@ -240,7 +1488,7 @@ impl std::fmt::Display for DecryptError {
 impl std::error::Error for DecryptError {}
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use crate::encrypt::{self, EncryptOptions};
     use std::io::Write;
@@ -255,26 +1503,82 @@ mod tests {
         assert_eq!(max_plaintext, 4294967295u64 * 65536);
     }
 
-    fn encrypt_test_file(plaintext: &[u8], passphrase: &str) -> (String, tempfile::TempDir) {
-        let mut input_file = NamedTempFile::new().unwrap();
-        input_file.write_all(plaintext).unwrap();
-        input_file.flush().unwrap();
+    /// How `encrypt_fixture` encrypts its test file.
+    #[derive(Default)]
+    pub(crate) struct Fixture<'a> {
+        /// Name of the plaintext file, stored in the header when set;
+        /// `test` otherwise.
+        pub filename: Option<&'a str>,
+        pub comment: Option<&'a str>,
+        pub meta: &'a [(&'a str, &'a str)],
+        pub pad: bool,
+        pub encrypt_metadata: bool,
+        /// Encrypt over the plaintext file instead of beside it.
+        pub in_place: bool,
+        /// Mode and times given to the plaintext file before encrypting.
+        pub mode: Option<u32>,
+        pub times: Option<fs::FileTimes>,
+    }
 
-        let output_dir = tempfile::tempdir().unwrap();
-        let output_path = output_dir.path().join("test.gtkrypt");
+    /// Encrypt `plaintext`, written to a file in `dir`, with a fast KDF
+    /// into a container beside it named after the file, as `test.gtkrypt`,
+    /// or over it when in place. Returns the path of the container.
+    pub(crate) fn encrypt_fixture(
+        dir: &Path,
+        plaintext: &[u8],
+        passphrase: &[u8],
+        fixture: Fixture,
+    ) -> String {
+        let input_path = dir.join(fixture.filename.unwrap_or("test"));
+        fs::write(&input_path, plaintext).unwrap();
+        #[cfg(unix)]
+        if let Some(mode) = fixture.mode {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&input_path, fs::Permissions::from_mode(mode)).unwrap();
+        }
+        if let Some(times) = fixture.times {
+            let file = fs::File::options().write(true).open(&input_path).unwrap();
+            file.set_times(times).unwrap();
+        }
+        let output_path = if fixture.in_place {
+            input_path.clone()
+        } else {
+            input_path.with_extension("gtkrypt")
+        };
 
         let opts = EncryptOptions {
-            input_path: input_file.path().to_str().unwrap().to_string(),
+            input_path: input_path.to_str().unwrap().to_string(),
             output_path: output_path.to_str().unwrap().to_string(),
-            passphrase: passphrase.as_bytes().to_vec(),
+            passphrase: passphrase.to_vec(),
             time_cost: 1,
             memory_cost_kib: 1024,
             parallelism: 1,
-            store_filename: false,
+            store_filename: fixture.filename.is_some(),
+            comment: fixture.comment.map(str::to_string),
+            user_metadata: fixture
+                .meta
+                .iter()
+                .map(|&(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            pad: fixture.pad,
+            encrypt_metadata: fixture.encrypt_metadata,
+            in_place: fixture.in_place,
+            ..Default::default()
         };
 
         encrypt::encrypt(&opts).unwrap();
-        (output_path.to_str().unwrap().to_string(), output_dir)
+        output_path.to_str().unwrap().to_string()
+    }
+
+    fn encrypt_test_file(plaintext: &[u8], passphrase: &str) -> (String, tempfile::TempDir) {
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = encrypt_fixture(
+            output_dir.path(),
+            plaintext,
+            passphrase.as_bytes(),
+            Fixture::default(),
+        );
+        (output_path, output_dir)
     }
 
     #[test]
@@ -289,6 +1593,7 @@ mod tests {
             input_path: encrypted_path,
             output_path: decrypted_path.to_str().unwrap().to_string(),
             passphrase: passphrase.as_bytes().to_vec(),
+            ..Default::default()
         };
 
         decrypt(&opts).unwrap();
@@ -307,6 +1612,7 @@ mod tests {
             input_path: encrypted_path,
             output_path: decrypted_path.to_str().unwrap().to_string(),
             passphrase: b"wrong_password".to_vec(),
+            ..Default::default()
         };
 
         let result = decrypt(&opts);
@@ -328,6 +1634,7 @@ mod tests {
             input_path: input_file.path().to_str().unwrap().to_string(),
             output_path: decrypted_path.to_str().unwrap().to_string(),
             passphrase: b"any_password".to_vec(),
+            ..Default::default()
         };
 
         let result = decrypt(&opts);
@@ -354,6 +1661,7 @@ mod tests {
             input_path: truncated_path.to_str().unwrap().to_string(),
             output_path: decrypted_path.to_str().unwrap().to_string(),
             passphrase: b"password".to_vec(),
+            ..Default::default()
         };
 
         let result = decrypt(&opts);
@@ -372,6 +1680,7 @@ mod tests {
             input_path: encrypted_path,
             output_path: decrypted_path.to_str().unwrap().to_string(),
             passphrase: passphrase.as_bytes().to_vec(),
+            ..Default::default()
         };
 
         decrypt(&opts).unwrap();
@@ -393,6 +1702,7 @@ mod tests {
             input_path: encrypted_path,
             output_path: decrypted_path.to_str().unwrap().to_string(),
             passphrase: passphrase.as_bytes().to_vec(),
+            ..Default::default()
         };
 
         decrypt(&opts).unwrap();
@@ -415,6 +1725,7 @@ mod tests {
             input_path: encrypted_path,
             output_path: decrypted_path.to_str().unwrap().to_string(),
             passphrase: passphrase.as_bytes().to_vec(),
+            ..Default::default()
         };
 
         decrypt(&opts).unwrap();
@@ -425,40 +1736,386 @@ mod tests {
 
     #[cfg(unix)]
     #[test]
-    fn test_decrypt_restores_permissions() {
-        use std::os::unix::fs::PermissionsExt;
+    fn test_decrypt_range_across_chunk_boundary() {
+        let plaintext: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let passphrase = "range_password";
+        let (encrypted_path, dir) = encrypt_test_file(&plaintext, passphrase);
+        let decrypted_path = dir.path().join("slice.bin");
 
-        let plaintext = b"perm test";
-        let passphrase = "perm_pass";
+        let opts = DecryptOptions {
+            input_path: encrypted_path.clone(),
+            output_path: decrypted_path.to_str().unwrap().to_string(),
+            passphrase: passphrase.as_bytes().to_vec(),
+            offset: CHUNK_SIZE as u64 - 10,
+            length: Some(CHUNK_SIZE as u64 + 20),
+            ..Default::default()
+        };
+        decrypt(&opts).unwrap();
+        let slice = fs::read(&decrypted_path).unwrap();
+        assert_eq!(slice, &plaintext[CHUNK_SIZE - 10..2 * CHUNK_SIZE + 10]);
 
-        let mut input_file = NamedTempFile::new().unwrap();
-        input_file.write_all(plaintext).unwrap();
-        input_file.flush().unwrap();
+        // Length past the end is clamped
+        let opts = DecryptOptions {
+            offset: 199_990,
+            length: Some(1000),
+            naming: OutputNaming {
+                template: None,
+                conflict: Conflict::Replace,
+            },
+            ..opts
+        };
+        decrypt(&opts).unwrap();
+        assert_eq!(fs::read(&decrypted_path).unwrap(), &plaintext[199_990..]);
 
-        let input_path = input_file.path();
-        let perms = std::fs::Permissions::from_mode(0o640);
-        std::fs::set_permissions(input_path, perms).unwrap();
+        let opts = DecryptOptions {
+            offset: 200_001,
+            length: None,
+            ..opts
+        };
+        assert!(matches!(decrypt(&opts), Err(DecryptError::Internal(_))));
 
-        let output_dir = tempfile::tempdir().unwrap();
-        let encrypted_path = output_dir.path().join("test.gtkrypt");
+        // Without --force an existing output is left alone
+        let opts = DecryptOptions {
+            offset: 0,
+            naming: OutputNaming::default(),
+            ..opts
+        };
+        assert!(matches!(decrypt(&opts), Err(DecryptError::OutputExists(_))));
+        assert_eq!(fs::read(&decrypted_path).unwrap(), &plaintext[199_990..]);
+    }
 
-        let enc_opts = EncryptOptions {
-            input_path: input_path.to_str().unwrap().to_string(),
-            output_path: encrypted_path.to_str().unwrap().to_string(),
+    #[test]
+    fn test_decrypt_detects_chunk_aligned_truncation() {
+        // Simulate a writer holding the key that cuts a two-chunk container
+        // after the first chunk and fixes up the header and index to match.
+        let plaintext = vec![0x11u8; CHUNK_SIZE + 10];
+        let passphrase = "truncate_password";
+        let (encrypted_path, dir) = encrypt_test_file(&plaintext, passphrase);
+
+        let data = fs::read(&encrypted_path).unwrap();
+        let (mut forged, header_size) = header::decode_header(&data).unwrap();
+        let key =
+            kdf::derive_key(passphrase.as_bytes(), &forged.salt, &forged.kdf_params).unwrap();
+
+        forged.original_file_size = CHUNK_SIZE as u64;
+        forged.ciphertext_length = CHUNK_SIZE as u64;
+        for ext in forged.extensions.iter_mut() {
+            if ext.tag == header::EXT_CHUNK_INDEX {
+                ext.value = footer::footer_len(1).to_be_bytes().to_vec();
+            }
+        }
+        let mut header_bytes = header::encode_header(&forged);
+        header::sign_header(&mut header_bytes, &key);
+        assert_eq!(header_bytes.len(), header_size);
+
+        let mut truncated = header_bytes.clone();
+        truncated.extend_from_slice(&data[header_size..header_size + CHUNK_SIZE + TAG_LEN]);
+        let entries = footer::fixed_layout(header_size, CHUNK_SIZE as u64);
+        truncated.extend_from_slice(&footer::encode(&entries, &header_bytes, &key));
+        fs::write(&encrypted_path, &truncated).unwrap();
+
+        let opts = DecryptOptions {
+            input_path: encrypted_path,
+            output_path: dir.path().join("out.bin").to_str().unwrap().to_string(),
+            passphrase: passphrase.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        match decrypt(&opts) {
+            Err(DecryptError::CorruptChunks(msg, chunks)) => {
+                assert!(msg.contains("truncated"), "{}", msg);
+                assert_eq!(chunks[0].chunk_index, 0);
+            }
+            other => panic!("expected truncation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decrypt_detects_plaintext_hash_mismatch() {
+        // A container whose chunks all authenticate but whose plaintext does
+        // not match the hash recorded at encryption time
+        let plaintext = vec![0x22u8; CHUNK_SIZE + 10];
+        let passphrase = "hash_password";
+        let (encrypted_path, dir) = encrypt_test_file(&plaintext, passphrase);
+
+        let mut data = fs::read(&encrypted_path).unwrap();
+        let (mut forged, header_size) = header::decode_header(&data).unwrap();
+        let key =
+            kdf::derive_key(passphrase.as_bytes(), &forged.salt, &forged.kdf_params).unwrap();
+
+        for ext in forged.extensions.iter_mut() {
+            if ext.tag == header::EXT_PLAINTEXT_HASH {
+                ext.value[0] ^= 0x01;
+            }
+        }
+        let mut header_bytes = header::encode_header(&forged);
+        header::sign_header(&mut header_bytes, &key);
+        data[..header_size].copy_from_slice(&header_bytes);
+        let entries = footer::fixed_layout(header_size, forged.ciphertext_length);
+        let index = footer::encode(&entries, &header_bytes, &key);
+        let index_start = data.len() - index.len();
+        data[index_start..].copy_from_slice(&index);
+        fs::write(&encrypted_path, &data).unwrap();
+
+        let output_path = dir.path().join("out.bin");
+        let mut opts = DecryptOptions {
+            input_path: encrypted_path.clone(),
+            output_path: output_path.to_str().unwrap().to_string(),
+            passphrase: passphrase.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        match decrypt(&opts) {
+            Err(DecryptError::CorruptFile(msg)) => assert!(msg.contains("hash"), "{}", msg),
+            other => panic!("expected hash mismatch, got {:?}", other),
+        }
+        assert!(!output_path.exists());
+
+        // A range decrypt never sees the whole plaintext and skips the check
+        opts.length = Some(100);
+        decrypt(&opts).unwrap();
+        assert_eq!(fs::read(&output_path).unwrap(), &plaintext[..100]);
+
+        let (_, _, mut payload) = open_payload(&encrypted_path, passphrase.as_bytes()).unwrap();
+        assert!(std::io::copy(&mut payload, &mut std::io::sink()).is_err());
+        assert!(matches!(payload.take_error(), Some(DecryptError::CorruptFile(_))));
+    }
+
+    #[test]
+    fn test_decrypt_reports_bad_chunks() {
+        let plaintext = vec![0x44u8; 3 * CHUNK_SIZE];
+        let passphrase = "scan_password";
+        let (encrypted_path, dir) = encrypt_test_file(&plaintext, passphrase);
+
+        let mut data = fs::read(&encrypted_path).unwrap();
+        let (_, header_size) = header::decode_header(&data).unwrap();
+        let stored = CHUNK_SIZE + TAG_LEN;
+        data[header_size + 5] ^= 0x01;
+        data[header_size + 2 * stored + 5] ^= 0x01;
+        fs::write(&encrypted_path, &data).unwrap();
+
+        let output_path = dir.path().join("out.bin");
+        let mut opts = DecryptOptions {
+            input_path: encrypted_path,
+            output_path: output_path.to_str().unwrap().to_string(),
+            passphrase: passphrase.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        match decrypt(&opts) {
+            Err(DecryptError::CorruptChunks(_, chunks)) => {
+                assert_eq!(chunks.len(), 1);
+                assert_eq!(chunks[0].file_offset, header_size as u64);
+                assert_eq!(chunks[0].file_length, stored as u64);
+            }
+            other => panic!("expected a located chunk error, got {:?}", other),
+        }
+
+        opts.scan_all = true;
+        match decrypt(&opts) {
+            Err(DecryptError::CorruptChunks(msg, chunks)) => {
+                assert!(msg.contains("2 of 3 chunks"), "{}", msg);
+                let indices: Vec<u64> = chunks.iter().map(|c| c.chunk_index).collect();
+                assert_eq!(indices, vec![0, 2]);
+                assert_eq!(chunks[1].plaintext_offset, 2 * CHUNK_SIZE as u64);
+                assert_eq!(chunks[1].plaintext_length, CHUNK_SIZE as u64);
+            }
+            other => panic!("expected a located chunk error, got {:?}", other),
+        }
+        assert!(!output_path.exists());
+    }
+
+    #[test]
+    fn test_parallel_decrypt_keeps_chunk_order() {
+        let plaintext: Vec<u8> =
+            (0..40 * CHUNK_SIZE as u32 + 123).map(|i| (i % 251) as u8).collect();
+        let passphrase = "parallel_password";
+        let (encrypted_path, dir) = encrypt_test_file(&plaintext, passphrase);
+
+        for threads in [1, 2, 3] {
+            let output_path = dir.path().join(format!("out{}.bin", threads));
+            decrypt(&DecryptOptions {
+                input_path: encrypted_path.clone(),
+                output_path: output_path.to_str().unwrap().to_string(),
+                passphrase: passphrase.as_bytes().to_vec(),
+                threads,
+                ..Default::default()
+            })
+            .unwrap();
+            assert_eq!(fs::read(&output_path).unwrap(), plaintext, "{} threads", threads);
+        }
+
+        // Reads ahead in batches, yet seeks back and forth across them
+        let (_, _, mut payload) = open_payload(&encrypted_path, passphrase.as_bytes()).unwrap();
+        let mut buf = vec![0u8; 100];
+        for offset in [35 * CHUNK_SIZE + 7, 2 * CHUNK_SIZE - 50, 40 * CHUNK_SIZE] {
+            payload.seek_plaintext(offset as u64).unwrap();
+            payload.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, plaintext[offset..offset + 100]);
+        }
+
+        let mut data = fs::read(&encrypted_path).unwrap();
+        let (_, header_size) = header::decode_header(&data).unwrap();
+        let stored = CHUNK_SIZE + TAG_LEN;
+        for chunk in [3, 17, 38] {
+            data[header_size + chunk * stored + 5] ^= 0x01;
+        }
+        fs::write(&encrypted_path, &data).unwrap();
+        for threads in [1, 2, 3] {
+            let opts = DecryptOptions {
+                input_path: encrypted_path.clone(),
+                output_path: dir.path().join("bad.bin").to_str().unwrap().to_string(),
+                passphrase: passphrase.as_bytes().to_vec(),
+                scan_all: true,
+                threads,
+                ..Default::default()
+            };
+            match decrypt(&opts) {
+                Err(DecryptError::CorruptChunks(_, chunks)) => {
+                    let indices: Vec<u64> = chunks.iter().map(|c| c.chunk_index).collect();
+                    assert_eq!(indices, vec![3, 17, 38], "{} threads", threads);
+                }
+                other => panic!("expected a located chunk error, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decrypt_keep_partial_leaves_holes() {
+        let plaintext: Vec<u8> =
+            (0..2 * CHUNK_SIZE as u32 + 1000).map(|i| (i % 199) as u8 + 1).collect();
+        let passphrase = "salvage_password";
+        let (encrypted_path, dir) = encrypt_test_file(&plaintext, passphrase);
+
+        // Damage the middle chunk and the short last one
+        let mut data = fs::read(&encrypted_path).unwrap();
+        let (_, header_size) = header::decode_header(&data).unwrap();
+        let stored = CHUNK_SIZE + TAG_LEN;
+        data[header_size + stored + 9] ^= 0x80;
+        data[header_size + 2 * stored + 9] ^= 0x80;
+        fs::write(&encrypted_path, &data).unwrap();
+
+        let output_path = dir.path().join("salvaged.bin");
+        let opts = DecryptOptions {
+            input_path: encrypted_path,
+            output_path: output_path.to_str().unwrap().to_string(),
             passphrase: passphrase.as_bytes().to_vec(),
+            keep_partial: true,
+            ..Default::default()
+        };
+        match decrypt(&opts) {
+            Err(DecryptError::CorruptChunks(_, chunks)) => assert_eq!(chunks.len(), 2),
+            other => panic!("expected a located chunk error, got {:?}", other),
+        }
+
+        let salvaged = fs::read(&output_path).unwrap();
+        assert_eq!(salvaged.len(), plaintext.len());
+        assert_eq!(&salvaged[..CHUNK_SIZE], &plaintext[..CHUNK_SIZE]);
+        assert!(salvaged[CHUNK_SIZE..].iter().all(|&b| b == 0));
+    }
+
+    /// Build a v2 container by hand: u32 XOR nonces, master key payload, no
+    /// header MAC and no chunk index.
+    pub(crate) fn write_legacy_v2_container(path: &Path, plaintext: &[u8], passphrase: &[u8]) {
+        let kdf_params = kdf::KdfParams {
             time_cost: 1,
             memory_cost_kib: 1024,
             parallelism: 1,
-            store_filename: false,
         };
+        let container_header = ContainerHeader {
+            version: 2,
+            kdf_id: header::KDF_ID_ARGON2ID,
+            kdf_params: kdf_params.clone(),
+            salt: [3u8; header::SALT_LEN],
+            nonce: [4u8; header::NONCE_LEN],
+            filename: None,
+            mode: Some(0o600),
+            original_file_size: plaintext.len() as u64,
+            ciphertext_length: plaintext.len() as u64,
+            extensions: Vec::new(),
+        };
+        let key = kdf::derive_key(passphrase, &container_header.salt, &kdf_params).unwrap();
+        let cipher = Aes256Gcm::new_from_slice(&key).unwrap();
+
+        let mut data = header::encode_header(&container_header);
+        let aad = header::extract_aad(&data);
+        for (index, chunk) in plaintext.chunks(CHUNK_SIZE).enumerate() {
+            let nonce = header::derive_chunk_nonce(&container_header.nonce, index as u32);
+            let mut buf = chunk.to_vec();
+            let tag = cipher
+                .encrypt_in_place_detached(
+                    Nonce::from_slice(&nonce),
+                    &header::build_chunk_aad(&aad, index as u32),
+                    &mut buf,
+                )
+                .unwrap();
+            data.extend_from_slice(&buf);
+            data.extend_from_slice(&tag);
+        }
+        fs::write(path, &data).unwrap();
+    }
+
+    #[test]
+    fn test_decrypt_legacy_v2_container() {
+        let plaintext: Vec<u8> = (0..CHUNK_SIZE as u32 + 100).map(|i| (i % 7) as u8).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let encrypted_path = dir.path().join("legacy.gtkrypt");
+        let decrypted_path = dir.path().join("legacy.txt");
+        write_legacy_v2_container(&encrypted_path, &plaintext, b"legacy_pass");
+
+        let opts = DecryptOptions {
+            input_path: encrypted_path.to_str().unwrap().to_string(),
+            output_path: decrypted_path.to_str().unwrap().to_string(),
+            passphrase: b"legacy_pass".to_vec(),
+            ..Default::default()
+        };
+        decrypt(&opts).unwrap();
+        assert_eq!(fs::read(&decrypted_path).unwrap(), plaintext);
+    }
 
-        encrypt::encrypt(&enc_opts).unwrap();
+    #[test]
+    fn test_check_passphrase() {
+        let (encrypted_path, _dir) = encrypt_test_file(b"check me", "right_password");
+
+        assert!(check_passphrase(&encrypted_path, b"right_password").is_ok());
+        assert!(matches!(
+            check_passphrase(&encrypted_path, b"wrong_password"),
+            Err(DecryptError::WrongPassphrase(_))
+        ));
+
+        // Legacy containers fall back to authenticating the first chunk
+        let dir = tempfile::tempdir().unwrap();
+        let legacy_path = dir.path().join("legacy.gtkrypt");
+        write_legacy_v2_container(&legacy_path, b"legacy data", b"legacy_pass");
+        let legacy_path = legacy_path.to_str().unwrap();
+        assert!(check_passphrase(legacy_path, b"legacy_pass").is_ok());
+        assert!(matches!(
+            check_passphrase(legacy_path, b"wrong_password"),
+            Err(DecryptError::WrongPassphrase(_))
+        ));
+    }
+
+    #[test]
+    fn test_decrypt_restores_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let plaintext = b"perm test";
+        let passphrase = "perm_pass";
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let encrypted_path = encrypt_fixture(
+            output_dir.path(),
+            plaintext,
+            passphrase.as_bytes(),
+            Fixture {
+                mode: Some(0o640),
+                ..Default::default()
+            },
+        );
 
         let decrypted_path = output_dir.path().join("out.txt");
         let dec_opts = DecryptOptions {
-            input_path: encrypted_path.to_str().unwrap().to_string(),
+            input_path: encrypted_path.clone(),
             output_path: decrypted_path.to_str().unwrap().to_string(),
             passphrase: passphrase.as_bytes().to_vec(),
+            ..Default::default()
         };
 
         decrypt(&dec_opts).unwrap();
@@ -466,5 +2123,151 @@ mod tests {
         let restored =
             std::fs::metadata(&decrypted_path).unwrap().permissions().mode() & 0o7777;
         assert_eq!(restored, 0o640);
+
+        let decrypt_with = |name: &str, permissions: PermissionPolicy| {
+            let path = output_dir.path().join(name);
+            decrypt(&DecryptOptions {
+                input_path: encrypted_path.clone(),
+                output_path: path.to_str().unwrap().to_string(),
+                passphrase: passphrase.as_bytes().to_vec(),
+                restore: RestoreOptions {
+                    permissions,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .unwrap();
+            std::fs::metadata(path).unwrap().permissions().mode() & 0o7777
+        };
+        assert_eq!(decrypt_with("fixed.txt", PermissionPolicy::Mode(0o604)), 0o604);
+        assert_eq!(
+            decrypt_with("umask.txt", PermissionPolicy::Umask),
+            0o666 & !metadata::umask()
+        );
+    }
+
+    #[test]
+    fn test_resolve_output_path_in_directory_mode() {
+        let opts = |input: &str| DecryptOptions {
+            input_path: input.to_string(),
+            output_path: "/srv/out".to_string(),
+            output_dir: true,
+            ..Default::default()
+        };
+        let named = Metadata {
+            filename: Some("../../.ssh/authorized_keys".to_string()),
+            ..Default::default()
+        };
+        let unnamed = Metadata::default();
+
+        let path = opts("x.gtkrypt").resolve_output_path(&named).unwrap();
+        assert_eq!(path, Path::new("/srv/out/authorized_keys"));
+        let path = opts("/tmp/notes.txt.gtkrypt").resolve_output_path(&unnamed).unwrap();
+        assert_eq!(path, Path::new("/srv/out/notes.txt"));
+        assert!(matches!(
+            opts("/tmp/notes.bin").resolve_output_path(&unnamed),
+            Err(DecryptError::Internal(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_in_place_roundtrip_keeps_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path_str = encrypt_fixture(
+            dir.path(),
+            b"dear diary",
+            b"in_place_pass",
+            Fixture {
+                filename: Some("journal.txt"),
+                in_place: true,
+                mode: Some(0o640),
+                ..Default::default()
+            },
+        );
+        let path = Path::new(&path_str);
+        let mode = || fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+
+        assert_eq!(&fs::read(path).unwrap()[..8], header::MAGIC);
+        assert_eq!(mode(), 0o640);
+
+        // The container's mode wins over the stored one
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600)).unwrap();
+        decrypt(&DecryptOptions {
+            input_path: path_str.clone(),
+            output_path: path_str.clone(),
+            passphrase: b"in_place_pass".to_vec(),
+            in_place: true,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"dear diary");
+        assert_eq!(mode(), 0o600);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_permission_policy_modes() {
+        let mode = |permissions, dir| {
+            RestoreOptions {
+                permissions,
+                ..Default::default()
+            }
+            .mode(Some(0o640), dir)
+        };
+        assert_eq!(mode(PermissionPolicy::Stored, false), Some(0o640));
+        assert_eq!(mode(PermissionPolicy::Mode(0o600), false), Some(0o600));
+        assert_eq!(mode(PermissionPolicy::Mode(0o640), true), Some(0o750));
+        let umask = RestoreOptions {
+            permissions: PermissionPolicy::Umask,
+            ..Default::default()
+        };
+        assert_eq!(umask.attributes(Some(0x02)), None);
+        assert_eq!(RestoreOptions::default().attributes(Some(0x02)), Some(0x02));
+        assert_eq!(parse_mode("0755"), Ok(0o755));
+        assert!(parse_mode("888").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
+
+    #[test]
+    fn test_decrypt_restores_times() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let dir = tempfile::tempdir().unwrap();
+        let mtime = UNIX_EPOCH + Duration::new(1_600_000_000, 250_000_000);
+        let atime = UNIX_EPOCH + Duration::from_secs(1_650_000_000);
+        let encrypted_path = encrypt_fixture(
+            dir.path(),
+            b"dear diary",
+            b"time_pass",
+            Fixture {
+                filename: Some("diary.txt"),
+                times: Some(fs::FileTimes::new().set_modified(mtime).set_accessed(atime)),
+                ..Default::default()
+            },
+        );
+
+        let decrypt_with = |name: &str, times: bool| {
+            let output_path = dir.path().join(name);
+            decrypt(&DecryptOptions {
+                input_path: encrypted_path.clone(),
+                output_path: output_path.to_str().unwrap().to_string(),
+                passphrase: b"time_pass".to_vec(),
+                restore: RestoreOptions {
+                    times,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .unwrap();
+            fs::metadata(output_path).unwrap()
+        };
+
+        let restored = decrypt_with("restored.txt", true);
+        assert_eq!(restored.modified().unwrap(), mtime);
+        assert_eq!(restored.accessed().unwrap(), atime);
+        assert_ne!(decrypt_with("fresh.txt", false).modified().unwrap(), mtime);
     }
 }
@@ -0,0 +1,497 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::progress;
+
+/// Alignment of buffers, file offsets and lengths for direct I/O, which
+/// covers the logical block size of common devices.
+pub const ALIGN: usize = 4096;
+
+/// Bytes moved per direct read or write.
+pub const BLOCK_LEN: usize = 1 << 20;
+
+/// Default size of the buffers between the files and the chunk stream, so
+/// that each read or write moves many chunks at once.
+pub const DEFAULT_IO_BUFFER_SIZE: usize = 1 << 20;
+
+/// Buffer size for an `io_buffer_size` setting, where 0 means the default.
+pub fn io_buffer_size(size: usize) -> usize {
+    match size {
+        0 => DEFAULT_IO_BUFFER_SIZE,
+        size => size,
+    }
+}
+
+/// Bytes of a streamed file whose cached pages are dropped together.
+const DROP_WINDOW: u64 = 8 << 20;
+
+/// Turn direct I/O, which bypasses the page cache, on or off for `file`.
+#[cfg(target_os = "linux")]
+pub fn set_direct(file: &File, on: bool) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    // SAFETY: F_GETFL only reads the status flags of a descriptor held open
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if on {
+        flags | libc::O_DIRECT
+    } else {
+        flags & !libc::O_DIRECT
+    };
+    // SAFETY: F_SETFL only changes the status flags of the same descriptor
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_direct(_file: &File, on: bool) -> io::Result<()> {
+    if on {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "direct I/O is only available on Linux",
+        ));
+    }
+    Ok(())
+}
+
+/// Warn that direct I/O could not be turned on for `what`, which then goes
+/// through the page cache as usual.
+pub fn warn_unsupported(what: &str, e: &io::Error) {
+    progress::emit_warning(
+        "direct_io_unsupported",
+        &format!("Direct I/O is not available for the {}, using the page cache: {}", what, e),
+    );
+}
+
+/// Drops the cached pages of a file read or written front to back, a
+/// window at a time behind the current offset, so a long stream does not
+/// push everything else out of the page cache.
+#[derive(Default)]
+pub struct CacheDropper {
+    /// End of the pages dropped so far.
+    dropped: u64,
+    /// End of the written pages sent to disk so far.
+    flushed: u64,
+}
+
+impl CacheDropper {
+    /// Note that `file` has been read up to `offset`.
+    pub fn read_to(&mut self, file: &File, offset: u64) {
+        self.rewind(offset);
+        let end = offset - offset % DROP_WINDOW;
+        if end > self.dropped {
+            advise_dontneed(file, self.dropped, end - self.dropped);
+            self.dropped = end;
+        }
+    }
+
+    /// Note that `file` has been written up to `offset`. Dirty pages only
+    /// leave the cache once on disk, so each window is sent to disk when
+    /// complete and dropped once the next one is complete too.
+    pub fn written_to(&mut self, file: &File, offset: u64) {
+        self.rewind(offset);
+        let end = offset - offset % DROP_WINDOW;
+        while self.flushed < end {
+            start_writeback(file, self.flushed, DROP_WINDOW);
+            self.flushed += DROP_WINDOW;
+            if self.flushed - self.dropped > DROP_WINDOW {
+                wait_writeback(file, self.dropped, DROP_WINDOW);
+                advise_dontneed(file, self.dropped, DROP_WINDOW);
+                self.dropped += DROP_WINDOW;
+            }
+        }
+    }
+
+    /// Start over from the window holding `offset` after a jump back.
+    fn rewind(&mut self, offset: u64) {
+        if offset < self.dropped || offset < self.flushed {
+            self.dropped = offset - offset % DROP_WINDOW;
+            self.flushed = self.dropped;
+        }
+    }
+}
+
+// The cache hints below are advice: a failure changes nothing but how much
+// the page cache holds, so it is ignored.
+
+#[cfg(target_os = "linux")]
+fn advise_dontneed(file: &File, offset: u64, len: u64) {
+    use std::os::fd::AsRawFd;
+    // SAFETY: posix_fadvise only reads its integer arguments
+    unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_DONTNEED,
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn start_writeback(file: &File, offset: u64, len: u64) {
+    sync_range(file, offset, len, libc::SYNC_FILE_RANGE_WRITE);
+}
+
+#[cfg(target_os = "linux")]
+fn wait_writeback(file: &File, offset: u64, len: u64) {
+    let flags = libc::SYNC_FILE_RANGE_WAIT_BEFORE
+        | libc::SYNC_FILE_RANGE_WRITE
+        | libc::SYNC_FILE_RANGE_WAIT_AFTER;
+    sync_range(file, offset, len, flags);
+}
+
+#[cfg(target_os = "linux")]
+fn sync_range(file: &File, offset: u64, len: u64, flags: libc::c_uint) {
+    use std::os::fd::AsRawFd;
+    // SAFETY: sync_file_range only reads its integer arguments
+    unsafe {
+        libc::sync_file_range(
+            file.as_raw_fd(),
+            offset as libc::off64_t,
+            len as libc::off64_t,
+            flags,
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_dontneed(_file: &File, _offset: u64, _len: u64) {}
+
+#[cfg(not(target_os = "linux"))]
+fn start_writeback(_file: &File, _offset: u64, _len: u64) {}
+
+#[cfg(not(target_os = "linux"))]
+fn wait_writeback(_file: &File, _offset: u64, _len: u64) {}
+
+/// A heap buffer whose start is aligned to `ALIGN`.
+struct AlignedBuf {
+    raw: Vec<u8>,
+    start: usize,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        let raw = vec![0u8; len + ALIGN];
+        let start = (ALIGN - raw.as_ptr() as usize % ALIGN) % ALIGN;
+        AlignedBuf { raw, start }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.raw[self.start..self.start + self.raw.len() - ALIGN]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        let end = self.start + self.raw.len() - ALIGN;
+        &mut self.raw[self.start..end]
+    }
+}
+
+/// Reads files in aligned blocks of `BLOCK_LEN`, as direct I/O needs, and
+/// serves any byte range from the block read last.
+pub struct BlockReader {
+    buf: AlignedBuf,
+    /// Which file the block belongs to, as told by the caller.
+    key: usize,
+    /// File offset of the block.
+    start: u64,
+    len: usize,
+}
+
+impl Default for BlockReader {
+    fn default() -> Self {
+        BlockReader {
+            buf: AlignedBuf::new(BLOCK_LEN),
+            key: 0,
+            start: 0,
+            len: 0,
+        }
+    }
+}
+
+impl BlockReader {
+    /// Read up to `out.len()` bytes of `file`, known as `key`, at `offset`.
+    /// Returns 0 at the end of the file.
+    pub fn read_at(
+        &mut self,
+        file: &File,
+        key: usize,
+        offset: u64,
+        out: &mut [u8],
+    ) -> io::Result<usize> {
+        let cached = self.key == key && offset >= self.start && offset < self.end();
+        if !cached {
+            self.len = 0;
+            self.key = key;
+            self.start = offset - offset % ALIGN as u64;
+            self.len = fill_at(file, self.start, self.buf.as_mut_slice())?;
+            if offset >= self.end() {
+                return Ok(0);
+            }
+        }
+        let from = (offset - self.start) as usize;
+        let n = std::cmp::min(out.len(), self.len - from);
+        out[..n].copy_from_slice(&self.buf.as_slice()[from..from + n]);
+        Ok(n)
+    }
+
+    fn end(&self) -> u64 {
+        self.start + self.len as u64
+    }
+}
+
+/// Fill `buf` from `file` at `offset`, short only at the end of the file.
+/// A short read elsewhere leaves the next offset unaligned, which direct
+/// I/O refuses, so the file then falls back to the page cache.
+fn fill_at(file: &File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    let mut direct = true;
+    while filled < buf.len() {
+        match read_at(file, &mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) if direct && e.kind() == io::ErrorKind::InvalidInput => {
+                set_direct(file, false)?;
+                direct = false;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Read at `offset` without moving the file position, in a single call
+/// where the platform allows.
+#[cfg(unix)]
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(not(unix))]
+pub fn read_at(mut file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read(buf)
+}
+
+/// A file read from the start through a `BlockReader`.
+pub struct DirectReader {
+    file: File,
+    blocks: BlockReader,
+    offset: u64,
+}
+
+impl DirectReader {
+    pub fn new(file: File) -> Self {
+        DirectReader {
+            file,
+            blocks: BlockReader::default(),
+            offset: 0,
+        }
+    }
+}
+
+impl Read for DirectReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = self.blocks.read_at(&self.file, 0, self.offset, out)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// A file read from the start through a `BufReader`, dropping the pages
+/// behind it from the page cache.
+pub struct UncachedReader {
+    reader: io::BufReader<File>,
+    offset: u64,
+    cache: CacheDropper,
+}
+
+impl UncachedReader {
+    /// Read `file` through a buffer of `capacity` bytes.
+    pub fn new(file: File, capacity: usize) -> Self {
+        UncachedReader {
+            reader: io::BufReader::with_capacity(capacity, file),
+            offset: 0,
+            cache: CacheDropper::default(),
+        }
+    }
+}
+
+impl Read for UncachedReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(out)?;
+        self.offset += n as u64;
+        self.cache.read_to(self.reader.get_ref(), self.offset);
+        Ok(n)
+    }
+}
+
+/// Collects writes and passes them on in whole aligned blocks, as direct
+/// I/O needs. Whatever is left over once the output is complete goes out
+/// through the page cache.
+pub struct AlignedWriter {
+    buf: AlignedBuf,
+    len: usize,
+    /// Direct I/O is still on for the file.
+    direct: bool,
+}
+
+impl AlignedWriter {
+    pub fn new(direct: bool) -> Self {
+        Self::with_capacity(direct, BLOCK_LEN)
+    }
+
+    /// Collect blocks of `capacity` bytes, rounded up to whole aligned
+    /// units.
+    pub fn with_capacity(direct: bool, capacity: usize) -> Self {
+        AlignedWriter {
+            buf: AlignedBuf::new(capacity.max(1).next_multiple_of(ALIGN)),
+            len: 0,
+            direct,
+        }
+    }
+
+    /// Add `data` to the output, writing each block to `file` once full.
+    pub fn write(&mut self, mut file: &File, mut data: &[u8]) -> io::Result<()> {
+        let block_len = self.buf.as_slice().len();
+        while !data.is_empty() {
+            let n = std::cmp::min(data.len(), block_len - self.len);
+            self.buf.as_mut_slice()[self.len..self.len + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+            if self.len == block_len {
+                file.write_all(self.buf.as_slice())?;
+                self.len = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write out what is held to `file`. A partial block needs direct I/O
+    /// off, and it stays off for `file` from then on.
+    pub fn drain(&mut self, mut file: &File) -> io::Result<()> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        if self.direct {
+            set_direct(file, false)?;
+            self.direct = false;
+        }
+        file.write_all(&self.buf.as_slice()[..self.len])?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+/// A buffered writer over a file that writes in whole aligned blocks when
+/// `direct` is set, so the file can bypass the page cache.
+pub struct BlockWriter<'a> {
+    file: &'a File,
+    blocks: AlignedWriter,
+    /// Offset the next write goes to.
+    offset: u64,
+    cache: Option<CacheDropper>,
+}
+
+impl<'a> BlockWriter<'a> {
+    /// Write `file` in blocks of `capacity` bytes, as `AlignedWriter`.
+    pub fn new(file: &'a File, direct: bool, capacity: usize) -> Self {
+        BlockWriter {
+            file,
+            blocks: AlignedWriter::with_capacity(direct, capacity),
+            offset: 0,
+            cache: None,
+        }
+    }
+
+    /// Drop the written pages from the page cache as the file grows.
+    pub fn set_drop_cache(&mut self) {
+        self.cache = Some(CacheDropper::default());
+    }
+}
+
+impl Write for BlockWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.blocks.write(self.file, data)?;
+        self.offset += data.len() as u64;
+        if let Some(ref mut cache) = self.cache {
+            cache.written_to(self.file, self.offset);
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.blocks.drain(self.file)
+    }
+}
+
+impl Seek for BlockWriter<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.blocks.drain(self.file)?;
+        let mut file = self.file;
+        self.offset = file.seek(pos)?;
+        Ok(self.offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_direct_write_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("direct.bin");
+        let data: Vec<u8> = (0..BLOCK_LEN as u32 * 2 + 1234).map(|i| (i % 241) as u8).collect();
+
+        let file = File::create(&path).unwrap();
+        let direct = set_direct(&file, true).is_ok();
+        let mut writer = BlockWriter::new(&file, direct, BLOCK_LEN);
+        for piece in data.chunks(70_000) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        let file = File::open(&path).unwrap();
+        let _ = set_direct(&file, true);
+        let mut read_back = Vec::new();
+        DirectReader::new(file).read_to_end(&mut read_back).unwrap();
+        assert_eq!(read_back, data);
+
+        // Any range, in any order, across block boundaries
+        let file = File::open(&path).unwrap();
+        let _ = set_direct(&file, true);
+        let mut blocks = BlockReader::default();
+        let mut buf = [0u8; 100];
+        for offset in [BLOCK_LEN + 4090, 17, 2 * BLOCK_LEN + 1200] {
+            let n = blocks.read_at(&file, 0, offset as u64, &mut buf).unwrap();
+            assert_eq!(buf[..n], data[offset..offset + n]);
+        }
+        assert_eq!(blocks.read_at(&file, 0, data.len() as u64, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_cache_dropper_trails_the_offset() {
+        let file = tempfile::tempfile().unwrap();
+        let mut cache = CacheDropper::default();
+        cache.written_to(&file, 3 * DROP_WINDOW + 5);
+        assert_eq!(cache.flushed, 3 * DROP_WINDOW);
+        // The window last sent to disk is still on its way
+        assert_eq!(cache.dropped, 2 * DROP_WINDOW);
+
+        let mut cache = CacheDropper::default();
+        cache.read_to(&file, 2 * DROP_WINDOW + 5);
+        assert_eq!(cache.dropped, 2 * DROP_WINDOW);
+        // Jumping back starts over from there
+        cache.read_to(&file, DROP_WINDOW + 5);
+        assert_eq!(cache.dropped, DROP_WINDOW);
+    }
+}
@@ -0,0 +1,290 @@
+use std::fs;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::audit;
+use crate::decrypt::{self, DecryptError};
+use crate::footer::{self, ChunkEntry};
+use crate::header::{self, EXT_ENCRYPTED_METADATA, MAX_COMMENT_LEN};
+use crate::kdf::{KdfLimits, KeyCache};
+use crate::metadata;
+use crate::persist;
+use crate::progress;
+use crate::temp;
+
+/// Options for editing the metadata in a container header.
+#[derive(Default)]
+pub struct EditOptions {
+    pub input_path: String,
+    pub passphrase: Vec<u8>,
+    /// New stored filename; an empty string removes it.
+    pub filename: Option<String>,
+    /// New comment; an empty string removes it.
+    pub comment: Option<String>,
+}
+
+/// Rewrite the metadata of a v3 container without touching the encrypted
+/// payload.
+///
+/// Chunks only bind the fixed header prefix into their AAD, so a new
+/// filename or comment just needs a re-signed header, a resealed metadata
+/// block when metadata is encrypted, and a re-signed chunk index. The
+/// payload is copied into a new file that atomically replaces the
+/// original.
+pub fn edit_header(opts: &EditOptions) -> Result<(), DecryptError> {
+    let result = edit_header_file(opts);
+    audit::record("edit-header", &opts.input_path, &result);
+    result
+}
+
+fn edit_header_file(opts: &EditOptions) -> Result<(), DecryptError> {
+    if let Some(ref name) = opts.filename {
+        let clean = name.is_empty() || metadata::sanitize_filename(name).as_deref() == Some(name);
+        if !clean || name.len() > u16::MAX as usize {
+            return Err(DecryptError::Internal(format!("Invalid filename '{}'", name)));
+        }
+    }
+    if let Some(ref comment) = opts.comment {
+        if comment.len() > MAX_COMMENT_LEN {
+            return Err(DecryptError::Internal(format!(
+                "Comment too long: {} bytes exceeds maximum of {} bytes",
+                comment.len(),
+                MAX_COMMENT_LEN
+            )));
+        }
+    }
+
+    let (mut reader, header_obj, header_size, header_bytes) =
+        decrypt::open_container(&opts.input_path)?;
+    if header_obj.version < 3 {
+        return Err(DecryptError::Internal(format!(
+            "Editing the header requires a version 3 container, found version {}",
+            header_obj.version
+        )));
+    }
+
+    if reader.get_ref().is_split() {
+        return Err(DecryptError::Internal(
+            "Editing the header of a split container is not supported".to_string(),
+        ));
+    }
+    // Parity covers the header bytes; rewriting them would invalidate it
+    if header_obj.parity().map_err(decrypt::map_header_error)?.is_some() {
+        return Err(DecryptError::Internal(
+            "Editing the header of a container with parity data is not supported".to_string(),
+        ));
+    }
+
+    decrypt::check_container_size(reader.get_ref().size(), &header_obj, header_size)?;
+
+    progress::emit_progress("kdf", 0, 0);
+    let key = decrypt::derive_header_key(
+        &opts.passphrase,
+        &header_obj,
+        &KeyCache::default(),
+        &KdfLimits::default(),
+    )?;
+    progress::emit_progress("kdf", 1, 1);
+
+    let key_confirmed = decrypt::verify_key(&header_obj, &header_bytes, &key)?;
+    let mut file_metadata = metadata::resolve(&header_obj, &header_bytes, &key)
+        .map_err(|e| decrypt::map_metadata_error(e, key_confirmed))?;
+
+    let footer_len = header_obj.footer_len().map_err(decrypt::map_header_error)?;
+    let footer_offset = decrypt::footer_offset(&header_obj, header_size);
+    let entries = match footer_len {
+        Some(_) => Some(decrypt::read_chunk_index(
+            &mut reader,
+            footer_offset,
+            &header_obj,
+            &header_bytes,
+            &key,
+        )?),
+        None => None,
+    };
+
+    if let Some(ref name) = opts.filename {
+        file_metadata.filename = Some(name.clone()).filter(|n| !n.is_empty());
+    }
+    if let Some(ref comment) = opts.comment {
+        file_metadata.comment = Some(comment.clone()).filter(|c| !c.is_empty());
+    }
+
+    // Rebuild the header with the new metadata, keeping every other record
+    let encrypted = header_obj.extension(EXT_ENCRYPTED_METADATA).is_some();
+    let mut new_header = header_obj.clone();
+    metadata::strip(&mut new_header);
+    metadata::store(&mut new_header, &file_metadata, encrypted, &key)
+        .map_err(DecryptError::Internal)?;
+    let mut new_header_bytes = header::encode_header(&new_header);
+    header::sign_header(&mut new_header_bytes, &key);
+
+    // Chunks move by the change in header size
+    let new_footer = entries.map(|entries| {
+        let shifted: Vec<ChunkEntry> = entries
+            .iter()
+            .map(|entry| ChunkEntry {
+                offset: entry.offset - header_size as u64 + new_header_bytes.len() as u64,
+                len: entry.len,
+            })
+            .collect();
+        footer::encode(&shifted, &new_header_bytes, &key)
+    });
+
+    // Always through a new file: the footer is bound to the header, so a
+    // crash between overwriting one and the other would lose the container
+    reader
+        .seek(SeekFrom::Start(header_size as u64))
+        .map_err(|e| DecryptError::Internal(format!("Failed to seek input: {}", e)))?;
+    let payload_len = footer_offset - header_size as u64;
+    rewrite_copy(
+        &opts.input_path,
+        &mut reader.take(payload_len),
+        &new_header_bytes,
+        new_footer.as_deref(),
+    )
+}
+
+/// Write a new container from a header, the unchanged payload and a footer,
+/// then atomically replace the original.
+fn rewrite_copy<R: Read>(
+    path: &str,
+    payload: &mut R,
+    header_bytes: &[u8],
+    footer_bytes: Option<&[u8]>,
+) -> Result<(), DecryptError> {
+    let output_dir = Path::new(path).parent().unwrap_or(Path::new("."));
+    let temp_file = temp::temp_file_in(output_dir).map_err(|e| {
+        if e.kind() == io::ErrorKind::PermissionDenied {
+            DecryptError::Permission(format!("Cannot write to output directory: {}", e))
+        } else {
+            DecryptError::Internal(format!("Failed to create temp file: {}", e))
+        }
+    })?;
+
+    let write_error =
+        |e: io::Error| DecryptError::Internal(format!("Failed to write container: {}", e));
+    let mut writer = BufWriter::new(temp_file.as_file());
+    writer.write_all(header_bytes).map_err(write_error)?;
+    io::copy(payload, &mut writer).map_err(write_error)?;
+    if let Some(footer_bytes) = footer_bytes {
+        writer.write_all(footer_bytes).map_err(write_error)?;
+    }
+    writer.flush().map_err(write_error)?;
+    drop(writer);
+
+    // Keep the original file's permissions
+    let permissions = fs::metadata(path)
+        .map_err(|e| DecryptError::Internal(format!("Failed to stat input file: {}", e)))?
+        .permissions();
+    fs::set_permissions(temp_file.path(), permissions)
+        .map_err(|e| DecryptError::Internal(format!("Failed to set permissions: {}", e)))?;
+
+    persist::persist(temp_file, Path::new(path), true).map_err(|e| {
+        if e.kind() == io::ErrorKind::PermissionDenied {
+            DecryptError::Permission(format!("Cannot write to output path: {}", e))
+        } else {
+            DecryptError::Internal(format!("Failed to rename temp file to output: {}", e))
+        }
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt::tests::{encrypt_fixture, Fixture};
+    use crate::decrypt::DecryptOptions;
+    use crate::inspect::{self, InspectOptions};
+
+    const PASSPHRASE: &[u8] = b"edit_pass";
+
+    fn encrypt_report(dir: &Path, plaintext: &[u8], encrypt_metadata: bool) -> String {
+        let fixture = Fixture {
+            filename: Some("report.txt"),
+            comment: Some("draft"),
+            encrypt_metadata,
+            ..Default::default()
+        };
+        encrypt_fixture(dir, plaintext, PASSPHRASE, fixture)
+    }
+
+    fn decrypt_fixture(path: &str, dir: &Path) -> Vec<u8> {
+        let output_path = dir.join("decrypted.bin");
+        decrypt::decrypt(&DecryptOptions {
+            input_path: path.to_string(),
+            output_path: output_path.to_str().unwrap().to_string(),
+            passphrase: PASSPHRASE.to_vec(),
+            ..Default::default()
+        })
+        .unwrap();
+        fs::read(output_path).unwrap()
+    }
+
+    fn inspect_fixture(path: &str) -> inspect::InspectReport {
+        inspect::inspect(&InspectOptions {
+            input_path: path.to_string(),
+            passphrase: Some(PASSPHRASE.to_vec()),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_edit_same_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let plaintext = vec![0x5Au8; 100_000];
+        let path = encrypt_report(dir.path(), &plaintext, false);
+        let size_before = fs::metadata(&path).unwrap().len();
+
+        edit_header(&EditOptions {
+            input_path: path.clone(),
+            passphrase: PASSPHRASE.to_vec(),
+            filename: Some("REPORT.txt".to_string()),
+            comment: Some("final".to_string()),
+        })
+        .unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().len(), size_before);
+        let report = inspect_fixture(&path);
+        assert_eq!(report.filename.as_deref(), Some("REPORT.txt"));
+        assert_eq!(report.comment.as_deref(), Some("final"));
+        assert_eq!(decrypt_fixture(&path, dir.path()), plaintext);
+    }
+
+    #[test]
+    fn test_edit_resizes_encrypted_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let plaintext = vec![0xA5u8; 70_000];
+        let path = encrypt_report(dir.path(), &plaintext, true);
+
+        edit_header(&EditOptions {
+            input_path: path.clone(),
+            passphrase: PASSPHRASE.to_vec(),
+            filename: Some("quarterly-report-2024.txt".to_string()),
+            comment: Some(String::new()),
+        })
+        .unwrap();
+
+        let report = inspect_fixture(&path);
+        assert!(report.metadata_encrypted);
+        assert_eq!(report.filename.as_deref(), Some("quarterly-report-2024.txt"));
+        assert_eq!(report.comment, None);
+        assert_eq!(decrypt_fixture(&path, dir.path()), plaintext);
+    }
+
+    #[test]
+    fn test_edit_wrong_passphrase_leaves_file_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = encrypt_report(dir.path(), b"unchanged", false);
+        let before = fs::read(&path).unwrap();
+
+        let result = edit_header(&EditOptions {
+            input_path: path.clone(),
+            passphrase: b"wrong".to_vec(),
+            comment: Some("nope".to_string()),
+            ..Default::default()
+        });
+        assert!(matches!(result, Err(DecryptError::WrongPassphrase(_))));
+        assert_eq!(fs::read(&path).unwrap(), before);
+    }
+}
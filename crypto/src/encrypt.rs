@@ -1,18 +1,41 @@
+use std::collections::BTreeMap;
 use std::fs;
 use std::io::{BufReader, BufWriter, Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use aes_gcm::aead::AeadInPlace;
-use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
 use rand::RngCore;
 
+use crate::aead::{Backend, PayloadCipher};
+use crate::archive::{ArchiveReader, PathFilter, SymlinkPolicy, ARCHIVE_VERSION};
+use crate::audit;
+use crate::control;
+use crate::cpu;
+use crate::decrypt::{self, DecryptError};
+use crate::direct::{self, DirectReader, UncachedReader};
+use crate::fd;
+use crate::footer::{self, ChunkEntry};
 use crate::header::{
-    self, ContainerHeader, KDF_ID_ARGON2ID, NONCE_LEN, SALT_LEN, TAG_LEN, VERSION, CHUNK_SIZE,
+    self, ContainerHeader, Extension, EXT_ARCHIVE, EXT_CHUNK_INDEX, EXT_KEY_CHECK, EXT_PARITY,
+    EXT_PLAINTEXT_HASH, KDF_ID_ARGON2ID, MAX_COMMENT_LEN, NONCE_LEN, PLAINTEXT_HASH_LEN,
+    SALT_LEN, TAG_LEN, VERSION, CHUNK_SIZE, MAGIC,
 };
-use crate::kdf::{self, KdfParams};
+use crate::kdf::{self, KdfParams, KeyCache, SUBKEY_PAYLOAD};
+use crate::lock;
+use crate::log;
+use crate::metadata::{self, Metadata, Timestamp};
+use crate::parity::{self, ParityWriter};
+use crate::pipeline;
 use crate::progress;
+use crate::shred;
+use crate::space;
+use crate::volume::VolumeWriter;
+
+/// Tool identifier recorded in new containers.
+pub const TOOL_VERSION: &str = concat!("gtkrypt-crypto ", env!("CARGO_PKG_VERSION"));
 
 /// Options for encryption.
+#[derive(Default)]
 pub struct EncryptOptions {
     pub input_path: String,
     pub output_path: String,
@@ -21,37 +44,199 @@ pub struct EncryptOptions {
     pub memory_cost_kib: u32,
     pub parallelism: u32,
     pub store_filename: bool,
+    /// Keep filename, mode and size in an encrypted metadata block instead
+    /// of the plaintext header fields.
+    pub encrypt_metadata: bool,
+    /// Optional free-text comment stored in the header.
+    pub comment: Option<String>,
+    /// User key/value metadata stored in the header.
+    pub user_metadata: BTreeMap<String, String>,
+    /// Pad the plaintext to a Padmé bucket so the container size does not
+    /// reveal the exact input size. Implies `encrypt_metadata`.
+    pub pad: bool,
+    /// Split the container into volumes of at most this many bytes.
+    pub split_size: Option<u64>,
+    /// Append Reed-Solomon parity worth this percentage of the container;
+    /// 0 disables it.
+    pub parity: u8,
+    /// Re-read the finished container and authenticate every chunk before
+    /// reporting success.
+    pub verify: bool,
+    /// Glob patterns selecting what to archive from a directory input.
+    pub include: Vec<String>,
+    /// Glob patterns for entries to leave out of a directory archive.
+    pub exclude: Vec<String>,
+    /// How symbolic links inside a directory input are archived.
+    pub symlinks: SymlinkPolicy,
+    /// `output_path` is the input itself: the container replaces it and
+    /// keeps its mode and owner.
+    pub in_place: bool,
+    /// Remove the input file once the container is safely on disk.
+    pub delete_input: bool,
+    /// With `delete_input`, overwrite the file this many times before
+    /// removing it; 0 just unlinks it.
+    pub shred_passes: u32,
+    /// Key cache shared by the files of a batch. Their containers get the
+    /// same salt and key, which shows they were encrypted together.
+    pub keys: KeyCache,
+    /// Read a file input and write the container with direct I/O,
+    /// bypassing the page cache.
+    pub direct_io: bool,
+    /// Drop the pages of a file input and of the container from the page
+    /// cache once they are processed.
+    pub drop_cache: bool,
+    /// Size of the read and write buffers in bytes; 0 uses the default.
+    pub io_buffer_size: usize,
+    /// Where the chunks are encrypted.
+    pub backend: Backend,
+    /// Stage the container here rather than beside the output.
+    pub temp_dir: Option<PathBuf>,
+    /// Encrypt an input that is a gtkrypt container already, which is
+    /// refused otherwise as most likely a mistake.
+    pub allow_double: bool,
 }
 
-/// Perform streaming chunked encryption of the input file and write the
-/// gtkrypt container to the output path.
+/// How the payload of a new container is laid out.
+#[derive(Default, Clone, Copy)]
+pub struct ContainerLayout {
+    pub encrypt_metadata: bool,
+    pub pad: bool,
+    /// Parity percentage, or 0 for none.
+    pub parity: u8,
+    /// The payload is a directory archive.
+    pub archive: bool,
+}
+
+/// How the chunks of a new container are processed, which does not show
+/// in the container itself.
+#[derive(Default, Clone, Copy)]
+pub struct StreamSettings {
+    /// Size of the write buffer in bytes; 0 uses the default.
+    pub io_buffer_size: usize,
+    pub backend: Backend,
+    /// The input is a pipe or device, whose size only shows at its end, so
+    /// progress is reported without a total.
+    pub unsized_input: bool,
+}
+
+/// Padmé padded length for a payload of `len` bytes.
 ///
-/// The file is split into 64 KiB chunks, each independently encrypted with
-/// AES-256-GCM using a derived per-chunk nonce. This keeps peak memory usage
-/// bounded regardless of input file size.
-pub fn encrypt(opts: &EncryptOptions) -> Result<(), EncryptError> {
-    // 1. Generate random salt and nonce
-    let mut salt = [0u8; SALT_LEN];
-    let mut nonce_bytes = [0u8; NONCE_LEN];
-    let mut rng = rand::thread_rng();
-    rng.fill_bytes(&mut salt);
-    rng.fill_bytes(&mut nonce_bytes);
+/// Rounds up by clearing the low bits of the length so that at most
+/// O(log log len) bits of the size leak, with at most ~12% overhead.
+fn padme_length(len: u64) -> u64 {
+    if len < 2 {
+        return len;
+    }
+    let e = 63 - len.leading_zeros() as u64;
+    let s = 64 - e.leading_zeros() as u64;
+    let last_bits = e - s;
+    let mask = (1u64 << last_bits) - 1;
+    (len + mask) & !mask
+}
 
-    // 2. Derive key via Argon2id
-    let kdf_params = KdfParams {
-        time_cost: opts.time_cost,
-        memory_cost_kib: opts.memory_cost_kib,
-        parallelism: opts.parallelism,
-    };
+/// A key derived for a new container under a fresh random salt.
+pub struct ContainerKey {
+    pub key: [u8; 32],
+    pub salt: [u8; SALT_LEN],
+    pub kdf_params: KdfParams,
+}
+
+/// Derive the key for a new container, generating a random salt.
+pub fn derive_container_key(
+    passphrase: &[u8],
+    kdf_params: KdfParams,
+) -> Result<ContainerKey, EncryptError> {
+    derive_batch_key(passphrase, kdf_params, &KeyCache::default())
+}
+
+/// Derive the key for a new container under the salt of `keys`, so that
+/// the containers of a batch share one Argon2id run. The memory cost is
+/// lowered, with a warning, where it would not fit in memory.
+pub fn derive_batch_key(
+    passphrase: &[u8],
+    kdf_params: KdfParams,
+    keys: &KeyCache,
+) -> Result<ContainerKey, EncryptError> {
+    let salt = keys.salt();
+    let kdf_params = kdf::fit_to_available_memory(kdf_params);
 
     progress::emit_progress("kdf", 0, 0);
 
-    let key = kdf::derive_key(&opts.passphrase, &salt, &kdf_params)
+    let key = keys
+        .derive(passphrase, &salt, &kdf_params)
         .map_err(|e| EncryptError::Internal(format!("KDF failed: {}", e)))?;
 
     progress::emit_progress("kdf", 1, 1);
 
-    // 3. Get input file size without reading the whole file
+    Ok(ContainerKey {
+        key,
+        salt,
+        kdf_params,
+    })
+}
+
+/// Create the temporary output for a new container at `output_path`, split
+/// into volumes when `split_size` is set and staged in `temp_dir` if given.
+pub fn create_output(
+    output_path: &str,
+    split_size: Option<u64>,
+    temp_dir: Option<&Path>,
+) -> Result<VolumeWriter, EncryptError> {
+    VolumeWriter::create(output_path, split_size, temp_dir).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            EncryptError::Permission(format!("Cannot write to output directory: {}", e))
+        } else {
+            EncryptError::Internal(format!("Failed to create temp file: {}", e))
+        }
+    })
+}
+
+/// What `write_container` wrote.
+#[derive(Debug, Clone, Copy)]
+pub struct ContainerStats {
+    /// Payload chunks, padding included.
+    pub chunks: u64,
+    /// Size of the container, across all of its volumes.
+    pub container_len: u64,
+}
+
+/// Perform streaming chunked encryption of the input file and write the
+/// gtkrypt container to the output path. A directory input is encrypted as
+/// an archive of the files below it.
+pub fn encrypt(opts: &EncryptOptions) -> Result<(), EncryptError> {
+    let started = Instant::now();
+    let result = encrypt_file(opts);
+    log::record("encrypt", &opts.input_path, started.elapsed(), &result);
+    audit::record("encrypt", &opts.input_path, &result);
+    result
+}
+
+fn encrypt_file(opts: &EncryptOptions) -> Result<(), EncryptError> {
+    if let Some(ref comment) = opts.comment {
+        if comment.len() > MAX_COMMENT_LEN {
+            return Err(EncryptError::Internal(format!(
+                "Comment too long: {} bytes exceeds maximum of {} bytes",
+                comment.len(),
+                MAX_COMMENT_LEN
+            )));
+        }
+    }
+    metadata::validate_user_metadata(&opts.user_metadata).map_err(EncryptError::Internal)?;
+    if opts.parity > 100 {
+        return Err(EncryptError::Internal(format!(
+            "Parity must be between 0 and 100 percent, got {}",
+            opts.parity
+        )));
+    }
+    // Repair works on a single file, so parity spanning volumes would be useless
+    if opts.parity > 0 && opts.split_size.is_some() {
+        return Err(EncryptError::Internal(
+            "Parity data cannot be combined with split volumes".to_string(),
+        ));
+    }
+
+    // 3. Get input file size without reading the whole file. Done before
+    // the KDF, so that the input can be checked for a container
     let input_metadata = fs::metadata(&opts.input_path).map_err(|e| {
         if e.kind() == std::io::ErrorKind::PermissionDenied {
             EncryptError::Permission(format!("Cannot read input file: {}", e))
@@ -59,18 +244,28 @@ pub fn encrypt(opts: &EncryptOptions) -> Result<(), EncryptError> {
             EncryptError::Internal(format!("Failed to stat input file: {}", e))
         }
     })?;
-    let input_size = input_metadata.len();
-
-    // Guard against nonce reuse: chunk_index is u32, so we can have at most
-    // u32::MAX chunks. Reject files that would exceed this limit.
-    let max_input_size: u64 = (u32::MAX as u64) * (CHUNK_SIZE as u64);
-    if input_size > max_input_size {
-        return Err(EncryptError::Internal(format!(
-            "File too large: {} bytes exceeds maximum of {} bytes",
-            input_size, max_input_size
-        )));
+    // After the stat, so that the peek does not show in the stored atime
+    if !opts.allow_double
+        && input_metadata.is_file()
+        && is_container(Path::new(&opts.input_path))
+    {
+        return Err(EncryptError::AlreadyEncrypted(opts.input_path.clone()));
     }
 
+    let mut timer = progress::PhaseTimer::start("encrypt");
+    // Held until the container has its name, and taken before the KDF so
+    // a clash is reported at once
+    let _lock = lock::lock_output(Path::new(&opts.output_path)).map_err(lock_error)?;
+
+    // 1-2. Generate a random salt and derive the key via Argon2id
+    let kdf_params = KdfParams {
+        time_cost: opts.time_cost,
+        memory_cost_kib: opts.memory_cost_kib,
+        parallelism: opts.parallelism,
+    };
+    let container_key =
+        timer.time("kdf", || derive_batch_key(&opts.passphrase, kdf_params, &opts.keys))?;
+
     #[cfg(unix)]
     let mode = {
         use std::os::unix::fs::PermissionsExt;
@@ -82,138 +277,457 @@ pub fn encrypt(opts: &EncryptOptions) -> Result<(), EncryptError> {
 
     // 4. Determine optional original filename
     let filename = if opts.store_filename {
-        Path::new(&opts.input_path)
-            .file_name()
-            .and_then(|n| n.to_str())
-            .map(|s| s.to_string())
+        fd::file_name(Path::new(&opts.input_path))
     } else {
         None
     };
 
-    // 5. Build header
-    //    ciphertext_length = original file size (each chunk's ciphertext
-    //    is the same length as its plaintext; tags are additional).
-    let container_header = ContainerHeader {
-        version: VERSION,
-        kdf_id: KDF_ID_ARGON2ID,
-        kdf_params: kdf_params.clone(),
-        salt,
-        nonce: nonce_bytes,
+    // 5. Open the input: a file is read as is, a directory as an archive
+    let has_filters = !opts.include.is_empty() || !opts.exclude.is_empty();
+    if has_filters && !input_metadata.is_dir() {
+        return Err(EncryptError::Internal(
+            "Include and exclude patterns only apply to a directory input".to_string(),
+        ));
+    }
+    let (mut reader, input_size): (Box<dyn Read + Send>, u64) = if input_metadata.is_dir() {
+        let filter =
+            PathFilter::new(&opts.include, &opts.exclude).map_err(EncryptError::Internal)?;
+        let root = Path::new(&opts.input_path);
+        let archive = ArchiveReader::new(root, &filter, opts.symlinks).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::PermissionDenied {
+                EncryptError::Permission(format!("Cannot read input directory: {}", e))
+            } else {
+                EncryptError::Internal(format!("Failed to read input directory: {}", e))
+            }
+        })?;
+        let size = archive.size();
+        (Box::new(archive), size)
+    } else {
+        let input_file = fs::File::open(&opts.input_path).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::PermissionDenied {
+                EncryptError::Permission(format!("Cannot read input file: {}", e))
+            } else {
+                EncryptError::Internal(format!("Failed to open input file: {}", e))
+            }
+        })?;
+        let direct = opts.direct_io
+            && direct::set_direct(&input_file, true)
+                .map_err(|e| direct::warn_unsupported("input", &e))
+                .is_ok();
+        let capacity = direct::io_buffer_size(opts.io_buffer_size);
+        let reader: Box<dyn Read + Send> = if direct {
+            Box::new(DirectReader::new(input_file))
+        } else if opts.drop_cache {
+            Box::new(UncachedReader::new(input_file, capacity))
+        } else {
+            Box::new(BufReader::with_capacity(capacity, input_file))
+        };
+        (reader, input_metadata.len())
+    };
+
+    let file_metadata = Metadata {
         filename,
         mode,
         original_file_size: input_size,
-        ciphertext_length: input_size,
+        comment: opts.comment.clone(),
+        user_metadata: opts.user_metadata.clone(),
+        created_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs()),
+        tool_version: Some(TOOL_VERSION.to_string()),
+        // Taken before the input was opened, so reading it does not show
+        mtime: input_metadata.modified().ok().map(Timestamp::from_system_time),
+        atime: input_metadata.accessed().ok().map(Timestamp::from_system_time),
+        btime: input_metadata.created().ok().map(Timestamp::from_system_time),
+        owner: metadata::file_owner(&input_metadata),
+        attributes: metadata::file_attributes(&input_metadata),
     };
 
-    let header_bytes = header::encode_header(&container_header);
-    let aad = header::extract_aad(&header_bytes).to_vec();
-
-    // 6. Initialize cipher
-    let cipher = Aes256Gcm::new_from_slice(&key)
-        .map_err(|e| EncryptError::Internal(format!("Failed to initialize cipher: {}", e)))?;
+    if opts.in_place && (input_metadata.is_dir() || opts.split_size.is_some()) {
+        return Err(EncryptError::Internal(
+            "In-place encryption needs a single file and an unsplit container".to_string(),
+        ));
+    }
+    if opts.delete_input && !input_metadata.is_file() {
+        return Err(EncryptError::Internal(
+            "Only a file input can be deleted after encryption".to_string(),
+        ));
+    }
 
-    // 7. Open input file with BufReader
-    let input_file = fs::File::open(&opts.input_path).map_err(|e| {
-        if e.kind() == std::io::ErrorKind::PermissionDenied {
-            EncryptError::Permission(format!("Cannot read input file: {}", e))
-        } else {
-            EncryptError::Internal(format!("Failed to open input file: {}", e))
+    let mut output =
+        create_output(&opts.output_path, opts.split_size, opts.temp_dir.as_deref())?;
+    if opts.delete_input {
+        output.set_durable();
+    }
+    // Applied before the rename, so the input is never replaced by a file
+    // with the wrong mode or owner
+    if opts.in_place {
+        output.set_replaces(input_metadata.clone());
+    }
+    if opts.direct_io {
+        if let Err(e) = output.set_direct_io() {
+            direct::warn_unsupported("output", &e);
         }
+    }
+    if opts.drop_cache {
+        output.set_drop_cache();
+    }
+    cpu::warn_if_slow(input_size);
+    let stats = timer.time("encrypt", || {
+        write_container(
+            &mut reader,
+            output,
+            "encrypt",
+            &container_key,
+            &file_metadata,
+            ContainerLayout {
+                encrypt_metadata: opts.encrypt_metadata,
+                pad: opts.pad,
+                parity: opts.parity,
+                archive: input_metadata.is_dir(),
+            },
+            StreamSettings {
+                io_buffer_size: opts.io_buffer_size,
+                backend: opts.backend,
+                unsized_input: !input_metadata.is_file() && !input_metadata.is_dir(),
+            },
+        )
     })?;
-    let mut reader = BufReader::new(input_file);
-
-    // 8. Open temp output file with BufWriter
-    let output_dir = Path::new(&opts.output_path)
-        .parent()
-        .unwrap_or(Path::new("."));
 
-    let temp_file = tempfile::NamedTempFile::new_in(output_dir).map_err(|e| {
-        if e.kind() == std::io::ErrorKind::PermissionDenied {
-            EncryptError::Permission(format!("Cannot write to output directory: {}", e))
-        } else {
-            EncryptError::Internal(format!("Failed to create temp file: {}", e))
-        }
-    })?;
+    // The key is already at hand, so this costs one read of the output
+    if opts.verify {
+        let key = &container_key.key;
+        let verified = timer.time("verify", || decrypt::verify_container(&opts.output_path, key));
+        verified.map_err(|e| match e {
+            DecryptError::Permission(msg) => EncryptError::Permission(msg),
+            e => EncryptError::Internal(format!("Written container failed verification: {}", e)),
+        })?;
+    }
 
-    // Set restrictive permissions (0600) before writing content
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        let perms = std::fs::Permissions::from_mode(0o600);
-        fs::set_permissions(temp_file.path(), perms).map_err(|e| {
-            EncryptError::Internal(format!("Failed to set temp file permissions: {}", e))
+    // Only now that the container is on disk under its name
+    if opts.delete_input {
+        let input_path = Path::new(&opts.input_path);
+        let removed = match opts.shred_passes {
+            0 => fs::remove_file(input_path),
+            passes => shred::shred(input_path, passes),
+        };
+        removed.map_err(|e| {
+            if e.kind() == std::io::ErrorKind::PermissionDenied {
+                EncryptError::Permission(format!("Cannot delete input file: {}", e))
+            } else {
+                EncryptError::Internal(format!("Failed to delete input file: {}", e))
+            }
         })?;
+        progress::emit_input_deleted(input_path, opts.shred_passes > 0);
     }
 
-    let mut writer = BufWriter::new(temp_file.as_file());
+    timer.emit(Path::new(&opts.output_path), input_size, stats.container_len, stats.chunks);
+    Ok(())
+}
 
-    // 9. Write header
-    writer.write_all(&header_bytes).map_err(|e| {
-        EncryptError::Internal(format!("Failed to write header: {}", e))
-    })?;
+/// Encrypt `file_metadata.original_file_size` bytes of plaintext from
+/// `reader` into a new container at `output_path`.
+///
+/// The plaintext is split into 64 KiB chunks, each independently encrypted
+/// with AES-256-GCM using a STREAM nonce (64-bit counter plus last-block
+/// flag). This keeps peak memory usage bounded regardless of input size. An
+/// authenticated index of the chunk offsets is appended after the last
+/// chunk, followed by the parity region if `layout.parity` is set.
+///
+/// The header carries a keyed hash of the plaintext, which is only known
+/// once the last chunk is written; it is written with a placeholder first
+/// and rewritten at the end, at the same size. The output only takes its
+/// final name once it is complete. Progress is reported under `phase`.
+///
+/// The chunks are encrypted on the backend of `stream`, and writes are
+/// gathered in its buffer size, each chunk going out together with its tag.
+pub fn write_container<R: Read + Send>(
+    reader: &mut R,
+    mut output: VolumeWriter,
+    phase: &str,
+    container_key: &ContainerKey,
+    file_metadata: &Metadata,
+    layout: ContainerLayout,
+    stream: StreamSettings,
+) -> Result<ContainerStats, EncryptError> {
+    let key = container_key.key;
+    let input_size = file_metadata.original_file_size;
 
-    // 10. Stream chunks: read CHUNK_SIZE, encrypt, write ciphertext + tag
-    progress::emit_progress("encrypt", 0, input_size);
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce_bytes);
 
-    let mut chunk_buf = vec![0u8; CHUNK_SIZE];
-    let mut chunk_index: u32 = 0;
-    let mut bytes_processed: u64 = 0;
+    // With --pad the payload is followed by zero bytes up to the bucket size;
+    // the true length is only recorded in the encrypted metadata.
+    let payload_size = if layout.pad {
+        padme_length(input_size)
+    } else {
+        input_size
+    };
 
-    loop {
-        let bytes_read = read_exact_or_eof(&mut reader, &mut chunk_buf)?;
-        if bytes_read == 0 {
-            break;
-        }
+    // v3 uses a 64-bit chunk counter, so no input size can exhaust it
+    let num_chunks = payload_size.div_ceil(CHUNK_SIZE as u64);
 
-        let chunk_data = &mut chunk_buf[..bytes_read];
+    // 6. Build header
+    //    ciphertext_length = payload size (each chunk's ciphertext is the
+    //    same length as its plaintext; tags are additional).
+    let mut container_header = ContainerHeader {
+        version: VERSION,
+        kdf_id: KDF_ID_ARGON2ID,
+        kdf_params: container_key.kdf_params.clone(),
+        salt: container_key.salt,
+        nonce: nonce_bytes,
+        filename: None,
+        mode: None,
+        original_file_size: 0,
+        ciphertext_length: payload_size,
+        extensions: vec![
+            Extension {
+                tag: EXT_KEY_CHECK,
+                value: header::key_check_value(&key).to_vec(),
+            },
+            Extension {
+                tag: EXT_CHUNK_INDEX,
+                value: footer::footer_len(num_chunks).to_be_bytes().to_vec(),
+            },
+            Extension {
+                tag: EXT_PLAINTEXT_HASH,
+                value: vec![0u8; PLAINTEXT_HASH_LEN],
+            },
+        ],
+    };
 
-        // Derive per-chunk nonce and AAD
-        let chunk_nonce_bytes = header::derive_chunk_nonce(&nonce_bytes, chunk_index);
-        let chunk_nonce = Nonce::from_slice(&chunk_nonce_bytes);
-        let chunk_aad = header::build_chunk_aad(&aad, chunk_index);
+    if layout.archive {
+        container_header.extensions.push(Extension {
+            tag: EXT_ARCHIVE,
+            value: vec![ARCHIVE_VERSION],
+        });
+    }
 
-        // Encrypt in place, get detached tag
-        let tag = cipher
-            .encrypt_in_place_detached(chunk_nonce, &chunk_aad, chunk_data)
-            .map_err(|e| EncryptError::Internal(format!("Encryption failed at chunk {}: {}", chunk_index, e)))?;
+    // With encrypted metadata, the plaintext fields are blanked and the real
+    // values move into a block sealed under the metadata subkey.
+    metadata::store(
+        &mut container_header,
+        file_metadata,
+        layout.encrypt_metadata || layout.pad,
+        &key,
+    )
+    .map_err(EncryptError::Internal)?;
+
+    // The parity region protects everything before it, header included, so
+    // its length depends on the encoded header size. The record itself has
+    // a fixed size, so a placeholder gives the final header length.
+    let mut parity_len = 0;
+    if layout.parity > 0 {
+        container_header.extensions.push(Extension {
+            tag: EXT_PARITY,
+            value: vec![0u8; 9],
+        });
+        let protected_len = header::encode_header(&container_header).len() as u64
+            + payload_size
+            + num_chunks * TAG_LEN as u64
+            + footer::footer_len(num_chunks);
+        parity_len = parity::region_len(protected_len, layout.parity);
+        let mut value = parity_len.to_be_bytes().to_vec();
+        value.push(layout.parity);
+        container_header.extensions.last_mut().unwrap().value = value;
+    }
 
-        // Write ciphertext chunk
-        writer.write_all(chunk_data).map_err(|e| {
-            EncryptError::Internal(format!("Failed to write ciphertext: {}", e))
-        })?;
+    let mut header_bytes = header::encode_header(&container_header);
+    header::sign_header(&mut header_bytes, &key);
+    let aad = header::extract_aad(&header_bytes);
 
-        // Write tag (16 bytes)
-        assert_eq!(tag.len(), TAG_LEN);
-        writer.write_all(&tag).map_err(|e| {
-            EncryptError::Internal(format!("Failed to write auth tag: {}", e))
-        })?;
+    // 7. Initialize cipher (v3 encrypts the payload under a dedicated subkey)
+    let payload_key = if VERSION >= 3 {
+        kdf::derive_subkey(&key, SUBKEY_PAYLOAD)
+    } else {
+        key
+    };
+    let cipher = PayloadCipher::new(&payload_key, stream.backend);
+
+    // The whole container size is known up front: make sure it fits and
+    // reserve it, so a full disk shows before any chunk is encrypted
+    let container_len = header_bytes.len() as u64
+        + payload_size
+        + num_chunks * TAG_LEN as u64
+        + footer::footer_len(num_chunks)
+        + parity_len;
+    output
+        .reserve(container_len)
+        .map_err(|e| output_error("Failed to reserve output space", e))?;
+    log::debug(|| {
+        format!(
+            "{}: {} bytes of input, {} of payload in {} chunks, {} bytes of container, \
+             I/O buffer of {} bytes",
+            phase,
+            input_size,
+            payload_size,
+            num_chunks,
+            container_len,
+            direct::io_buffer_size(stream.io_buffer_size)
+        )
+    });
+
+    // 8. Buffer writes to the temp output file(s), computing parity on the
+    //    way through
+    let output = BufWriter::with_capacity(direct::io_buffer_size(stream.io_buffer_size), output);
+    let mut writer = ParityWriter::new(output, layout.parity)
+        .map_err(|e| EncryptError::Internal(format!("Failed to create parity file: {}", e)))?;
 
-        bytes_processed += bytes_read as u64;
-        chunk_index += 1;
+    // 9. Write header
+    writer
+        .write_all(&header_bytes)
+        .map_err(|e| output_error("Failed to write header", e))?;
+
+    // 10. Stream chunks: read CHUNK_SIZE, encrypt, write ciphertext + tag,
+    //     with reading and writing on threads of their own
+    let report_progress = |bytes_processed| {
+        if stream.unsized_input {
+            progress::emit_progress_without_total(phase, bytes_processed);
+        } else {
+            progress::emit_progress(phase, bytes_processed, payload_size);
+        }
+    };
+    report_progress(0);
 
-        progress::emit_progress("encrypt", bytes_processed, input_size);
-    }
+    let mut plaintext_hasher = header::plaintext_hasher(&key);
+    let mut bytes_read_total: u64 = 0;
+    let mut chunk_index: u64 = 0;
+    let mut bytes_processed: u64 = 0;
+    let mut chunk_offset = header_bytes.len() as u64;
+    let mut index_entries = Vec::new();
+
+    pipeline::run(
+        || Vec::with_capacity(CHUNK_SIZE + TAG_LEN),
+        |chunk: &mut Vec<u8>| {
+            chunk.resize(CHUNK_SIZE, 0);
+            let mut bytes_read = read_exact_or_eof(reader, chunk)?;
+            plaintext_hasher.update(&chunk[..bytes_read]);
+
+            // Past the end of the input, fill the chunk with padding
+            if bytes_read < CHUNK_SIZE && bytes_read_total + (bytes_read as u64) < payload_size {
+                let pad_len = std::cmp::min(
+                    (CHUNK_SIZE - bytes_read) as u64,
+                    payload_size - bytes_read_total - bytes_read as u64,
+                ) as usize;
+                chunk[bytes_read..bytes_read + pad_len].fill(0);
+                bytes_read += pad_len;
+            }
+            chunk.truncate(bytes_read);
+            bytes_read_total += bytes_read as u64;
+            Ok(bytes_read > 0)
+        },
+        |chunk| {
+            if control::is_cancelled() {
+                return Err(EncryptError::Cancelled);
+            }
+            // Derive per-chunk nonce and AAD
+            let last = chunk_index + 1 == num_chunks;
+            let chunk_nonce = header::chunk_nonce(VERSION, &nonce_bytes, chunk_index, last);
+            let chunk_aad = header::chunk_aad(VERSION, &aad, chunk_index, last);
+
+            // Encrypt in place and append the detached tag (16 bytes) into
+            // the room kept for it, so the chunk and its tag are written
+            // with a single call
+            let tag = cipher.seal(&chunk_nonce, &chunk_aad, chunk).map_err(|e| {
+                EncryptError::Internal(format!("Encryption failed at chunk {}: {}", chunk_index, e))
+            })?;
+            let entry = ChunkEntry {
+                offset: chunk_offset,
+                len: chunk.len() as u32,
+            };
+            chunk.extend_from_slice(&tag);
+
+            chunk_offset += entry.stored_len();
+            index_entries.push(entry);
+
+            bytes_processed += entry.len as u64;
+            chunk_index += 1;
+
+            report_progress(bytes_processed);
+            Ok(())
+        },
+        |chunk| {
+            writer
+                .write_all(chunk)
+                .map_err(|e| output_error("Failed to write ciphertext", e))
+        },
+    )?;
+
+    // Fill in the plaintext hash; the chunk AAD only covers the fixed
+    // prefix, so the chunks already written stay valid
+    let hash_ext = container_header
+        .extensions
+        .iter_mut()
+        .find(|ext| ext.tag == EXT_PLAINTEXT_HASH)
+        .unwrap();
+    hash_ext.value = plaintext_hasher.finalize().as_bytes().to_vec();
+    let placeholder_len = header_bytes.len();
+    header_bytes = header::encode_header(&container_header);
+    assert_eq!(header_bytes.len(), placeholder_len);
+    header::sign_header(&mut header_bytes, &key);
+    writer.patch_start(&header_bytes);
+
+    // Append the authenticated chunk index
+    let index_footer = footer::encode(&index_entries, &header_bytes, &key);
+    writer
+        .write_all(&index_footer)
+        .map_err(|e| output_error("Failed to write chunk index", e))?;
+
+    let mut output = writer
+        .finish()
+        .map_err(|e| output_error("Failed to write parity data", e))?
+        .into_inner()
+        .map_err(|e| output_error("Failed to flush output", e.into_error()))?;
+    output
+        .overwrite_start(&header_bytes)
+        .map_err(|e| output_error("Failed to write header", e))?;
 
-    writer.flush().map_err(|e| {
-        EncryptError::Internal(format!("Failed to flush output: {}", e))
+    // 11. Atomic rename
+    output.finish().map_err(|e| {
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            EncryptError::Permission(format!("Cannot write to output path: {}", e))
+        } else {
+            output_error("Failed to rename temp file to output", e)
+        }
     })?;
-    // Drop the BufWriter so only the NamedTempFile owns the file handle
-    drop(writer);
 
-    // 11. Atomic rename
-    temp_file
-        .persist(&opts.output_path)
-        .map_err(|e| {
-            if e.error.kind() == std::io::ErrorKind::PermissionDenied {
-                EncryptError::Permission(format!("Cannot write to output path: {}", e.error))
-            } else {
-                EncryptError::Internal(format!("Failed to rename temp file to output: {}", e.error))
-            }
-        })?;
+    // By now the size is known either way
+    let total = if stream.unsized_input { bytes_processed } else { payload_size };
+    progress::emit_progress(phase, total, total);
 
-    progress::emit_progress("encrypt", input_size, input_size);
+    Ok(ContainerStats {
+        chunks: num_chunks,
+        container_len,
+    })
+}
 
-    Ok(())
+/// Whether the file at `path` starts with the container magic. Only asked
+/// of regular files, as peeking at a pipe would consume its data.
+fn is_container(path: &Path) -> bool {
+    let mut magic = [0u8; MAGIC.len()];
+    fs::File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok()
+        && magic == *MAGIC
+}
+
+/// Error for an output lock that could not be taken.
+fn lock_error(e: std::io::Error) -> EncryptError {
+    match e.kind() {
+        std::io::ErrorKind::WouldBlock => EncryptError::Busy(e.to_string()),
+        std::io::ErrorKind::PermissionDenied => {
+            EncryptError::Permission(format!("Cannot lock output: {}", e))
+        }
+        _ => EncryptError::Internal(format!("Failed to lock output: {}", e)),
+    }
+}
+
+/// Error for a failed write to the output, telling a full disk apart.
+fn output_error(context: &str, e: std::io::Error) -> EncryptError {
+    if space::is_disk_full(&e) {
+        EncryptError::DiskFull(format!("{}: {}", context, e))
+    } else {
+        EncryptError::Internal(format!("{}: {}", context, e))
+    }
 }
 
 /// Read up to `buf.len()` bytes from the reader, filling the buffer as
@@ -242,6 +756,14 @@ fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize, E
 pub enum EncryptError {
     Permission(String),
     Internal(String),
+    /// The output does not fit on its filesystem.
+    DiskFull(String),
+    /// Another process is writing the same output.
+    Busy(String),
+    /// The operation was cancelled between two chunks.
+    Cancelled,
+    /// The input at this path is a gtkrypt container already.
+    AlreadyEncrypted(String),
 }
 
 impl std::fmt::Display for EncryptError {
@@ -249,6 +771,10 @@ impl std::fmt::Display for EncryptError {
         match self {
             EncryptError::Permission(msg) => write!(f, "Permission error: {}", msg),
             EncryptError::Internal(msg) => write!(f, "Internal error: {}", msg),
+            EncryptError::DiskFull(msg) => write!(f, "Disk full: {}", msg),
+            EncryptError::Busy(msg) => write!(f, "Resource busy: {}", msg),
+            EncryptError::Cancelled => write!(f, "Cancelled"),
+            EncryptError::AlreadyEncrypted(path) => write!(f, "Already encrypted: {}", path),
         }
     }
 }
@@ -279,6 +805,7 @@ mod tests {
             memory_cost_kib: 1024,
             parallelism: 1,
             store_filename: false,
+            ..Default::default()
         };
 
         encrypt(&opts).unwrap();
@@ -293,14 +820,6 @@ mod tests {
         assert_eq!(&output_data[0..8], b"GTKRYPT\0");
     }
 
-    #[test]
-    fn test_max_input_size_constant() {
-        // Verify the max input size is u32::MAX * CHUNK_SIZE
-        let max: u64 = (u32::MAX as u64) * (CHUNK_SIZE as u64);
-        // At 64 KiB chunks, this is ~256 TiB
-        assert_eq!(max, 4294967295u64 * 65536);
-    }
-
     #[test]
     fn test_encrypt_with_stored_filename() {
         let mut input_file = NamedTempFile::new().unwrap();
@@ -318,9 +837,117 @@ mod tests {
             memory_cost_kib: 1024,
             parallelism: 1,
             store_filename: true,
+            ..Default::default()
         };
 
         encrypt(&opts).unwrap();
         assert!(output_path.exists());
     }
+
+    #[test]
+    fn test_encrypt_metadata_blanks_plaintext_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("private-name.txt");
+        fs::write(&input_path, b"metadata test").unwrap();
+        let output_path = dir.path().join("test.gtkrypt");
+
+        let opts = EncryptOptions {
+            input_path: input_path.to_str().unwrap().to_string(),
+            output_path: output_path.to_str().unwrap().to_string(),
+            passphrase: b"password123".to_vec(),
+            time_cost: 1,
+            memory_cost_kib: 1024,
+            parallelism: 1,
+            store_filename: true,
+            encrypt_metadata: true,
+            ..Default::default()
+        };
+
+        encrypt(&opts).unwrap();
+
+        let data = fs::read(&output_path).unwrap();
+        let (parsed, _) = header::decode_header(&data).unwrap();
+        assert_eq!(parsed.filename, None);
+        assert_eq!(parsed.mode, Some(0));
+        assert_eq!(parsed.original_file_size, 0);
+        assert!(parsed.extension(header::EXT_ENCRYPTED_METADATA).is_some());
+        let needle = b"private-name";
+        assert!(!data.windows(needle.len()).any(|w| w == needle));
+    }
+
+    #[test]
+    fn test_padme_length() {
+        assert_eq!(padme_length(0), 0);
+        assert_eq!(padme_length(1), 1);
+        assert_eq!(padme_length(9), 10);
+        assert_eq!(padme_length(1000), 1024);
+        assert_eq!(padme_length(65536), 65536);
+        assert_eq!(padme_length(65537), 67584);
+        for len in [3u64, 100, 12345, 1 << 30, (1 << 30) + 1] {
+            let padded = padme_length(len);
+            assert!(padded >= len);
+            assert!(padded - len <= len / 8, "overhead too large for {}", len);
+        }
+    }
+
+    #[test]
+    fn test_pad_hides_size_and_encrypts_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("padded.bin");
+        fs::write(&input_path, vec![0xAB; 65537]).unwrap();
+        let output_path = dir.path().join("padded.gtkrypt");
+
+        let opts = EncryptOptions {
+            input_path: input_path.to_str().unwrap().to_string(),
+            output_path: output_path.to_str().unwrap().to_string(),
+            passphrase: b"password123".to_vec(),
+            time_cost: 1,
+            memory_cost_kib: 1024,
+            parallelism: 1,
+            pad: true,
+            ..Default::default()
+        };
+
+        encrypt(&opts).unwrap();
+
+        let data = fs::read(&output_path).unwrap();
+        let (parsed, header_size) = header::decode_header(&data).unwrap();
+        assert_eq!(parsed.ciphertext_length, 67584);
+        assert_eq!(parsed.original_file_size, 0);
+        assert!(parsed.extension(header::EXT_ENCRYPTED_METADATA).is_some());
+        assert_eq!(
+            data.len() as u64,
+            header_size as u64 + 67584 + 2 * TAG_LEN as u64 + footer::footer_len(2)
+        );
+    }
+
+    #[test]
+    fn test_container_input_needs_allow_double() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("a.txt");
+        fs::write(&input, b"once").unwrap();
+        let once = dir.path().join("a.txt.gtkrypt");
+        let twice = dir.path().join("a.txt.gtkrypt.gtkrypt");
+        let opts = |input: &Path, output: &Path, allow_double| EncryptOptions {
+            input_path: input.to_str().unwrap().to_string(),
+            output_path: output.to_str().unwrap().to_string(),
+            passphrase: b"pass".to_vec(),
+            time_cost: 1,
+            memory_cost_kib: 1024,
+            parallelism: 1,
+            allow_double,
+            ..Default::default()
+        };
+        encrypt(&opts(&input, &once, false)).unwrap();
+
+        let err = encrypt(&opts(&once, &twice, false)).unwrap_err();
+        assert!(matches!(&err, EncryptError::AlreadyEncrypted(path) if path.ends_with(".gtkrypt")));
+        assert!(!twice.exists());
+
+        encrypt(&opts(&once, &twice, true)).unwrap();
+        assert!(is_container(&twice));
+        // Too short to hold the magic, so not a container
+        fs::write(&input, b"GTK").unwrap();
+        assert!(!is_container(&input));
+    }
 }
@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use rand::RngCore;
+use serde::Serialize;
+
+use crate::aead::{Backend, PayloadCipher};
+use crate::bench;
+use crate::decrypt::{self, DecryptError};
+use crate::header::{CHUNK_SIZE, NONCE_LEN};
+use crate::kdf;
+
+/// Time spent measuring the cipher: enough for a steady rate, short enough
+/// to ask before every decryption.
+const BENCH_DURATION: Duration = Duration::from_millis(100);
+
+/// What decrypting a container would cost here, printed as one JSON object
+/// on stdout by `estimate`.
+#[derive(Debug, Serialize)]
+pub struct Estimate {
+    pub kdf_memory_kib: u32,
+    pub kdf_time_cost: u32,
+    pub kdf_parallelism: u32,
+    /// Expected run time of the key derivation.
+    pub kdf_secs: f64,
+    /// Payload bytes to decrypt, padding included.
+    pub payload_bytes: u64,
+    pub chunks: u64,
+    /// Threads the chunks would be decrypted on.
+    pub threads: usize,
+    /// Bytes a single thread decrypts per second.
+    pub decrypt_bytes_per_sec: u64,
+    /// Expected time for the payload, taking the cipher alone into
+    /// account: a disk slower than it adds to this.
+    pub decrypt_secs: f64,
+    pub total_secs: f64,
+}
+
+/// Estimate the cost of decrypting the container at `input_path` with
+/// `threads` (0: one per core) on `backend`, from its header, the
+/// calibrated Argon2id rate and a short measurement of the cipher.
+pub fn estimate(
+    input_path: &str,
+    threads: usize,
+    backend: Backend,
+) -> Result<Estimate, DecryptError> {
+    let (_reader, header_obj, _header_size, _header_bytes) = decrypt::open_container(input_path)?;
+    let mut key = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+    let cipher = PayloadCipher::new(&key, backend);
+    let bytes_per_sec = bench::decrypt_bytes_per_sec(&cipher, &[0u8; NONCE_LEN], BENCH_DURATION)
+        .map_err(DecryptError::Internal)?;
+
+    let chunks = header_obj.ciphertext_length.div_ceil(CHUNK_SIZE as u64);
+    Ok(build(
+        &header_obj.kdf_params,
+        kdf::estimate_duration(&header_obj.kdf_params).as_secs_f64(),
+        header_obj.ciphertext_length,
+        chunks,
+        decrypt::decrypt_threads(threads),
+        bytes_per_sec,
+    ))
+}
+
+/// The estimate for a payload of `payload_bytes` in `chunks`, spread over
+/// `threads` that each decrypt `bytes_per_sec`.
+fn build(
+    params: &kdf::KdfParams,
+    kdf_secs: f64,
+    payload_bytes: u64,
+    chunks: u64,
+    threads: usize,
+    bytes_per_sec: u64,
+) -> Estimate {
+    // Fewer chunks than threads leave the rest idle
+    let busy = (threads as u64).clamp(1, chunks.max(1));
+    let decrypt_secs = payload_bytes as f64 / (bytes_per_sec.max(1) * busy) as f64;
+    Estimate {
+        kdf_memory_kib: params.memory_cost_kib,
+        kdf_time_cost: params.time_cost,
+        kdf_parallelism: params.parallelism,
+        kdf_secs,
+        payload_bytes,
+        chunks,
+        threads,
+        decrypt_bytes_per_sec: bytes_per_sec,
+        decrypt_secs,
+        total_secs: kdf_secs + decrypt_secs,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decrypt_time_scales_with_busy_threads() {
+        let params = kdf::KdfParams {
+            time_cost: 3,
+            memory_cost_kib: 65536,
+            parallelism: 4,
+        };
+        let mib = 1024 * 1024;
+        let estimate = build(&params, 2.0, 400 * mib, 6400, 4, 100 * mib);
+        assert_eq!(estimate.decrypt_secs, 1.0);
+        assert_eq!(estimate.total_secs, 3.0);
+
+        // A single chunk is decrypted on one thread
+        let estimate = build(&params, 2.0, 50 * mib, 1, 4, 100 * mib);
+        assert_eq!(estimate.decrypt_secs, 0.5);
+
+        let estimate = build(&params, 2.0, 0, 0, 4, 100 * mib);
+        assert_eq!(estimate.total_secs, 2.0);
+    }
+}
@@ -0,0 +1,179 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tempfile::TempDir;
+
+use crate::temp;
+
+/// Check that the inherited descriptor `fd` is open, for writing if `write`
+/// is set and for reading otherwise. Descriptor 0 is refused, since stdin
+/// carries the passphrase and control messages.
+#[cfg(unix)]
+pub fn check(fd: i32, write: bool) -> io::Result<()> {
+    if fd == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "stdin carries the passphrase and control messages",
+        ));
+    }
+    // SAFETY: F_GETFL only reads the flags of the descriptor, if it is open
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (refused, message) = if write {
+        (libc::O_RDONLY, "the descriptor is not open for writing")
+    } else {
+        (libc::O_WRONLY, "the descriptor is not open for reading")
+    };
+    if flags & libc::O_ACCMODE == refused {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn check(_fd: i32, _write: bool) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "file descriptors can only be passed on Unix",
+    ))
+}
+
+/// Where the descriptors of this process can be opened again by number.
+const FD_DIR: &str = if cfg!(target_os = "linux") { "/proc/self/fd" } else { "/dev/fd" };
+
+/// A path that opens the file behind descriptor `fd` afresh, as a sandbox
+/// can even where the path of the file itself is out of its reach.
+pub fn path(fd: i32) -> String {
+    format!("{}/{}", FD_DIR, fd)
+}
+
+/// The name of the file at `path`, or of the file behind the descriptor it
+/// opens. A descriptor on a pipe or socket has none.
+pub fn file_name(path: &Path) -> Option<String> {
+    let behind;
+    let path = if path.parent() == Some(Path::new(FD_DIR)) {
+        behind = std::fs::read_link(path).ok().filter(|target| target.is_absolute())?;
+        &behind
+    } else {
+        path
+    };
+    path.file_name().and_then(|n| n.to_str()).map(|s| s.to_string())
+}
+
+/// An inherited descriptor to write an output to. The output is made in a
+/// staging directory, as it would be beside its path, and copied into the
+/// descriptor once complete, so that a failed run leaves it untouched.
+pub struct OutputFd {
+    fd: i32,
+    staging: TempDir,
+    _lock: File,
+}
+
+impl OutputFd {
+    /// Stage the output for descriptor `fd` in `temp_dir`, or the system
+    /// temp directory.
+    pub fn stage(fd: i32, temp_dir: Option<&Path>) -> io::Result<Self> {
+        check(fd, true)?;
+        let dir = temp_dir.map_or_else(std::env::temp_dir, Path::to_path_buf);
+        let (staging, lock) = temp::temp_dir_in(&dir)?;
+        Ok(OutputFd {
+            fd,
+            staging,
+            _lock: lock,
+        })
+    }
+
+    /// Path to write the output to.
+    pub fn path(&self) -> PathBuf {
+        self.staging.path().join("output")
+    }
+
+    /// Copy the finished output into the descriptor, replacing what a file
+    /// there held, and flush it to disk.
+    #[cfg(unix)]
+    pub fn deliver(self) -> io::Result<()> {
+        use std::io::Seek;
+        use std::os::fd::FromRawFd;
+
+        // SAFETY: the descriptor is open, and was handed down for this alone
+        let mut target = unsafe { File::from_raw_fd(self.fd) };
+        let is_file = target.metadata()?.is_file();
+        if is_file {
+            target.set_len(0)?;
+            target.rewind()?;
+        }
+        io::copy(&mut File::open(self.path())?, &mut target)?;
+        if is_file {
+            target.sync_all()?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn deliver(self) -> io::Result<()> {
+        check(self.fd, true)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::fd::{AsRawFd, IntoRawFd};
+
+    #[test]
+    fn test_check_access_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("data");
+        std::fs::write(&file_path, b"data").unwrap();
+        let read_only = File::open(&file_path).unwrap();
+        let write_only = File::options().write(true).open(&file_path).unwrap();
+
+        assert!(check(read_only.as_raw_fd(), false).is_ok());
+        assert!(check(read_only.as_raw_fd(), true).is_err());
+        assert!(check(write_only.as_raw_fd(), true).is_ok());
+        assert!(check(write_only.as_raw_fd(), false).is_err());
+        assert!(check(0, false).is_err());
+        assert!(check(i32::MAX, false).is_err());
+    }
+
+    #[test]
+    fn test_descriptor_paths_reopen_and_name_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("report.pdf");
+        std::fs::write(&file_path, b"reopened").unwrap();
+        let file = File::open(&file_path).unwrap();
+
+        let path = path(file.as_raw_fd());
+        assert_eq!(std::fs::read(&path).unwrap(), b"reopened");
+        assert_eq!(file_name(Path::new(&path)).as_deref(), Some("report.pdf"));
+        assert_eq!(file_name(&file_path).as_deref(), Some("report.pdf"));
+    }
+
+    #[test]
+    fn test_output_is_delivered_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let target_path = dir.path().join("target");
+        std::fs::write(&target_path, b"a longer old content").unwrap();
+        let target = File::options().write(true).open(&target_path).unwrap();
+
+        let staged = OutputFd::stage(target.into_raw_fd(), Some(dir.path())).unwrap();
+        File::create(staged.path()).unwrap().write_all(b"new").unwrap();
+        let staging = staged.staging.path().to_path_buf();
+        staged.deliver().unwrap();
+        assert_eq!(std::fs::read(&target_path).unwrap(), b"new");
+        assert!(!staging.exists());
+
+        // A pipe gets the bytes as they are
+        let (mut reader, writer) = std::io::pipe().unwrap();
+        let staged = OutputFd::stage(writer.into_raw_fd(), Some(dir.path())).unwrap();
+        File::create(staged.path()).unwrap().write_all(b"piped").unwrap();
+        staged.deliver().unwrap();
+        let mut piped = Vec::new();
+        reader.read_to_end(&mut piped).unwrap();
+        assert_eq!(piped, b"piped");
+    }
+}
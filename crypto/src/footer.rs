@@ -0,0 +1,228 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::header::{CHUNK_SIZE, HEADER_MAC_LEN, TAG_LEN};
+use crate::kdf::{self, SUBKEY_CHUNK_INDEX};
+
+/// Size of one index entry: file offset (uint64 BE) + ciphertext length
+/// (uint32 BE, excluding the tag).
+pub const INDEX_ENTRY_LEN: usize = 12;
+
+/// Location of one encrypted chunk within the container file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkEntry {
+    /// Byte offset of the chunk ciphertext from the start of the file.
+    pub offset: u64,
+    /// Ciphertext length, which equals the plaintext length of the chunk.
+    pub len: u32,
+}
+
+impl ChunkEntry {
+    /// Bytes occupied on disk by the chunk including its tag.
+    pub fn stored_len(&self) -> u64 {
+        self.len as u64 + TAG_LEN as u64
+    }
+}
+
+/// Size of the index footer for a container with `num_chunks` chunks:
+/// the entries followed by a 32-byte MAC.
+pub fn footer_len(num_chunks: u64) -> u64 {
+    num_chunks * INDEX_ENTRY_LEN as u64 + HEADER_MAC_LEN as u64
+}
+
+/// Chunk layout implied by the fixed 64 KiB chunk size, used for
+/// containers written without an index footer.
+pub fn fixed_layout(header_size: usize, ciphertext_length: u64) -> Vec<ChunkEntry> {
+    let mut entries = Vec::new();
+    let mut offset = header_size as u64;
+    let mut remaining = ciphertext_length;
+    while remaining > 0 {
+        let len = std::cmp::min(remaining, CHUNK_SIZE as u64);
+        let entry = ChunkEntry {
+            offset,
+            len: len as u32,
+        };
+        offset += entry.stored_len();
+        remaining -= len;
+        entries.push(entry);
+    }
+    entries
+}
+
+/// MAC over the full header and the encoded entries, so an index cannot be
+/// moved between containers or edited independently of the header.
+fn index_mac(master_key: &[u8; 32], header_bytes: &[u8], entries: &[u8]) -> Hmac<Sha256> {
+    let mac_key = kdf::derive_subkey(master_key, SUBKEY_CHUNK_INDEX);
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&mac_key)
+        .expect("HMAC-SHA256 accepts keys of any length");
+    mac.update(header_bytes);
+    mac.update(entries);
+    mac
+}
+
+/// Encode the index footer: entries followed by their MAC.
+pub fn encode(entries: &[ChunkEntry], header_bytes: &[u8], master_key: &[u8; 32]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(footer_len(entries.len() as u64) as usize);
+    for entry in entries {
+        buf.extend_from_slice(&entry.offset.to_be_bytes());
+        buf.extend_from_slice(&entry.len.to_be_bytes());
+    }
+    let tag = index_mac(master_key, header_bytes, &buf).finalize().into_bytes();
+    buf.extend_from_slice(&tag);
+    buf
+}
+
+/// Verify and decode an index footer.
+///
+/// Besides the MAC, checks that the chunks are laid out back to back after
+/// the header and add up to `ciphertext_length`, so callers can seek using
+/// the entries without further bounds checks.
+pub fn decode(
+    data: &[u8],
+    header_bytes: &[u8],
+    ciphertext_length: u64,
+    master_key: &[u8; 32],
+) -> Result<Vec<ChunkEntry>, FooterError> {
+    if data.len() < HEADER_MAC_LEN
+        || !(data.len() - HEADER_MAC_LEN).is_multiple_of(INDEX_ENTRY_LEN)
+    {
+        return Err(FooterError::Malformed("Invalid chunk index length".to_string()));
+    }
+    let (body, tag) = data.split_at(data.len() - HEADER_MAC_LEN);
+    if index_mac(master_key, header_bytes, body)
+        .verify_slice(tag)
+        .is_err()
+    {
+        return Err(FooterError::Authentication);
+    }
+
+    let mut entries = Vec::with_capacity(body.len() / INDEX_ENTRY_LEN);
+    let mut expected_offset = header_bytes.len() as u64;
+    let mut total: u64 = 0;
+    for raw in body.chunks_exact(INDEX_ENTRY_LEN) {
+        let entry = ChunkEntry {
+            offset: u64::from_be_bytes(raw[..8].try_into().unwrap()),
+            len: u32::from_be_bytes(raw[8..].try_into().unwrap()),
+        };
+        if entry.offset != expected_offset {
+            return Err(FooterError::Malformed(format!(
+                "Chunk {} starts at offset {}, expected {}",
+                entries.len(),
+                entry.offset,
+                expected_offset
+            )));
+        }
+        if entry.len == 0 || entry.len as usize > CHUNK_SIZE {
+            return Err(FooterError::Malformed(format!(
+                "Chunk {} has invalid length {}",
+                entries.len(),
+                entry.len
+            )));
+        }
+        expected_offset += entry.stored_len();
+        total += entry.len as u64;
+        entries.push(entry);
+    }
+
+    if total != ciphertext_length {
+        return Err(FooterError::Malformed(format!(
+            "Chunk index covers {} bytes, header declares {}",
+            total, ciphertext_length
+        )));
+    }
+    Ok(entries)
+}
+
+/// Errors from decoding the chunk index footer.
+#[derive(Debug)]
+pub enum FooterError {
+    /// The index MAC did not verify.
+    Authentication,
+    /// The index verified but describes an impossible layout.
+    Malformed(String),
+}
+
+impl std::fmt::Display for FooterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FooterError::Authentication => write!(f, "Chunk index authentication failed"),
+            FooterError::Malformed(msg) => write!(f, "Malformed chunk index: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for FooterError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [9u8; 32];
+
+    #[test]
+    fn test_fixed_layout() {
+        let entries = fixed_layout(100, CHUNK_SIZE as u64 + 10);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], ChunkEntry { offset: 100, len: CHUNK_SIZE as u32 });
+        assert_eq!(
+            entries[1],
+            ChunkEntry {
+                offset: 100 + (CHUNK_SIZE + TAG_LEN) as u64,
+                len: 10,
+            }
+        );
+        assert!(fixed_layout(100, 0).is_empty());
+    }
+
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        let header_bytes = vec![0xAAu8; 120];
+        let entries = fixed_layout(header_bytes.len(), 3 * CHUNK_SIZE as u64 + 7);
+        let footer = encode(&entries, &header_bytes, &KEY);
+        assert_eq!(footer.len() as u64, footer_len(entries.len() as u64));
+
+        let decoded = decode(&footer, &header_bytes, 3 * CHUNK_SIZE as u64 + 7, &KEY).unwrap();
+        assert_eq!(decoded, entries);
+    }
+
+    #[test]
+    fn test_decode_rejects_tampering() {
+        let header_bytes = vec![0xAAu8; 120];
+        let entries = fixed_layout(header_bytes.len(), 1000);
+        let mut footer = encode(&entries, &header_bytes, &KEY);
+
+        // Wrong key or a different header fail authentication
+        assert!(matches!(
+            decode(&footer, &header_bytes, 1000, &[1u8; 32]),
+            Err(FooterError::Authentication)
+        ));
+        assert!(matches!(
+            decode(&footer, &[0xBBu8; 120], 1000, &KEY),
+            Err(FooterError::Authentication)
+        ));
+
+        footer[11] ^= 0x01;
+        assert!(matches!(
+            decode(&footer, &header_bytes, 1000, &KEY),
+            Err(FooterError::Authentication)
+        ));
+    }
+
+    #[test]
+    fn test_decode_rejects_inconsistent_layout() {
+        let header_bytes = vec![0xAAu8; 120];
+        let entries = fixed_layout(header_bytes.len(), 1000);
+        let footer = encode(&entries, &header_bytes, &KEY);
+        assert!(matches!(
+            decode(&footer, &header_bytes, 999, &KEY),
+            Err(FooterError::Malformed(_))
+        ));
+
+        let gap = vec![ChunkEntry { offset: 200, len: 1000 }];
+        let footer = encode(&gap, &header_bytes, &KEY);
+        assert!(matches!(
+            decode(&footer, &header_bytes, 1000, &KEY),
+            Err(FooterError::Malformed(_))
+        ));
+    }
+}
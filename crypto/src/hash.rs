@@ -0,0 +1,88 @@
+use crate::aead::Backend;
+
+/// A SHA-256 implementation, for the bulk hashing of parity shards.
+pub trait Sha256Hash {
+    fn digest(data: &[u8]) -> [u8; 32];
+}
+
+/// SHA-256 from RustCrypto.
+pub struct RustCryptoSha256;
+
+impl Sha256Hash for RustCryptoSha256 {
+    fn digest(data: &[u8]) -> [u8; 32] {
+        use sha2::Digest;
+        sha2::Sha256::digest(data).into()
+    }
+}
+
+/// SHA-256 from ring.
+#[cfg(feature = "ring")]
+pub struct RingSha256;
+
+#[cfg(feature = "ring")]
+impl Sha256Hash for RingSha256 {
+    fn digest(data: &[u8]) -> [u8; 32] {
+        ring::digest::digest(&ring::digest::SHA256, data)
+            .as_ref()
+            .try_into()
+            .unwrap()
+    }
+}
+
+/// SHA-256 from the system OpenSSL.
+#[cfg(feature = "openssl")]
+pub struct OpensslSha256;
+
+#[cfg(feature = "openssl")]
+impl Sha256Hash for OpensslSha256 {
+    fn digest(data: &[u8]) -> [u8; 32] {
+        openssl::sha::sha256(data)
+    }
+}
+
+/// The implementation picked at build time: OpenSSL, then ring, then
+/// RustCrypto.
+#[cfg(feature = "openssl")]
+type Sha256 = OpensslSha256;
+#[cfg(all(feature = "ring", not(feature = "openssl")))]
+type Sha256 = RingSha256;
+#[cfg(not(any(feature = "ring", feature = "openssl")))]
+type Sha256 = RustCryptoSha256;
+
+/// SHA-256 of `data`.
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data)
+}
+
+/// A one-shot SHA-256 function.
+pub type DigestFn = fn(&[u8]) -> [u8; 32];
+
+/// SHA-256 on `backend`, where this build includes it there.
+pub fn sha256_on(backend: Backend) -> Option<DigestFn> {
+    match backend {
+        Backend::Software => Some(RustCryptoSha256::digest),
+        #[cfg(feature = "ring")]
+        Backend::Ring => Some(RingSha256::digest),
+        #[cfg(feature = "openssl")]
+        Backend::Openssl => Some(OpensslSha256::digest),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aead::BACKENDS;
+
+    #[test]
+    fn test_sha256_backends_agree() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 241) as u8).collect();
+        let expected = RustCryptoSha256::digest(&data);
+        assert_eq!(sha256(&data), expected);
+        for &backend in BACKENDS {
+            if let Some(digest) = sha256_on(backend) {
+                assert_eq!(digest(&data), expected, "{}", backend.name());
+            }
+        }
+    }
+}
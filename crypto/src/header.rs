@@ -1,12 +1,15 @@
 use std::io::Read;
 
-use crate::kdf::KdfParams;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::kdf::{self, KdfParams, SUBKEY_HEADER_MAC, SUBKEY_KEY_CHECK, SUBKEY_PLAINTEXT_HASH};
 
 /// Magic bytes identifying a gtkrypt container file.
 pub const MAGIC: &[u8; 8] = b"GTKRYPT\0";
 
 /// Current container format version.
-pub const VERSION: u8 = 2;
+pub const VERSION: u8 = 3;
 
 /// KDF identifier for Argon2id.
 pub const KDF_ID_ARGON2ID: u8 = 1;
@@ -23,6 +26,131 @@ pub const TAG_LEN: usize = 16;
 /// Chunk size for streaming encryption/decryption (64 KiB).
 pub const CHUNK_SIZE: usize = 65536;
 
+/// Length of the v3 header MAC (HMAC-SHA256) that terminates the header.
+pub const HEADER_MAC_LEN: usize = 32;
+
+/// Extension tags with this bit set are critical: a reader that does not
+/// recognise the tag must reject the container instead of skipping it.
+pub const EXT_CRITICAL: u16 = 0x8000;
+
+/// Upper bound on the size of the v3 extension area (1 MiB).
+pub const MAX_EXTENSIONS_LEN: usize = 1024 * 1024;
+
+/// Upper bound on a stored filename in bytes, well above any filesystem's
+/// name limit.
+pub const MAX_FILENAME_LEN: usize = 1024;
+
+/// Extension tag: encrypted metadata block (nonce + AES-GCM ciphertext + tag)
+/// holding the filename, mode and size instead of the plaintext fields.
+pub const EXT_ENCRYPTED_METADATA: u16 = 0x0001;
+
+/// Extension tag: original filename (used inside the encrypted metadata block).
+pub const EXT_FILENAME: u16 = 0x0002;
+
+/// Extension tag: unix mode, uint32 BE (used inside the encrypted metadata block).
+pub const EXT_MODE: u16 = 0x0003;
+
+/// Extension tag: original file size, uint64 BE (used inside the encrypted
+/// metadata block).
+pub const EXT_FILE_SIZE: u16 = 0x0004;
+
+/// Extension tag: free-text UTF-8 comment.
+pub const EXT_COMMENT: u16 = 0x0005;
+
+/// Maximum length of a stored comment in bytes.
+pub const MAX_COMMENT_LEN: usize = 4096;
+
+/// Extension tag: one user metadata pair, encoded as key_len (uint16 BE),
+/// key, value. The tag repeats once per pair.
+pub const EXT_USER_METADATA: u16 = 0x0006;
+
+/// Maximum number of user metadata pairs per container.
+pub const MAX_USER_METADATA_ENTRIES: usize = 64;
+
+/// Maximum length of a user metadata key in bytes.
+pub const MAX_USER_METADATA_KEY_LEN: usize = 128;
+
+/// Maximum length of a user metadata value in bytes.
+pub const MAX_USER_METADATA_VALUE_LEN: usize = 4096;
+
+/// Extension tag: container creation time, uint64 BE seconds since the
+/// Unix epoch.
+pub const EXT_CREATED_AT: u16 = 0x0007;
+
+/// Extension tag: name and version of the tool that wrote the container.
+pub const EXT_TOOL_VERSION: u16 = 0x0008;
+
+/// Extension tag (critical): the container ends with an authenticated
+/// chunk index footer; the value is the footer length as uint64 BE.
+pub const EXT_CHUNK_INDEX: u16 = EXT_CRITICAL | 0x0009;
+
+/// Extension tag: key-check value used to recognise a wrong passphrase
+/// before any other header authentication.
+pub const EXT_KEY_CHECK: u16 = 0x000A;
+
+/// Length of the key-check value (a truncated HMAC-SHA256).
+pub const KEY_CHECK_LEN: usize = 16;
+
+/// Extension tag (critical): Reed-Solomon parity data follows the chunk
+/// index; the value is the parity region length (uint64 BE) followed by
+/// the parity percentage (uint8).
+pub const EXT_PARITY: u16 = EXT_CRITICAL | 0x000B;
+
+/// Extension tag: keyed BLAKE3 hash of the plaintext, checked once a full
+/// decryption has reassembled the output.
+pub const EXT_PLAINTEXT_HASH: u16 = 0x000C;
+
+/// Length of the plaintext hash.
+pub const PLAINTEXT_HASH_LEN: usize = 32;
+
+/// Extension tags: modification, access and birth time of the input, each
+/// as int64 BE seconds since the Unix epoch followed by uint32 BE
+/// nanoseconds.
+pub const EXT_MTIME: u16 = 0x000E;
+pub const EXT_ATIME: u16 = 0x000F;
+pub const EXT_BTIME: u16 = 0x0010;
+
+/// Extension tag: owner of the input, uint32 BE uid then uint32 BE gid.
+pub const EXT_OWNER: u16 = 0x0011;
+
+/// Extension tag: Windows file attributes of the input (uint32 BE), limited
+/// to the readonly, hidden, system and archive bits.
+pub const EXT_ATTRIBUTES: u16 = 0x0012;
+
+/// Extension tag (critical): the payload is a directory archive rather
+/// than a single file; the value is the archive format version (uint8).
+pub const EXT_ARCHIVE: u16 = EXT_CRITICAL | 0x000D;
+
+/// Extension tags understood by this build. Unknown non-critical tags are
+/// carried through untouched; unknown critical tags are rejected.
+const KNOWN_EXTENSIONS: &[u16] = &[
+    EXT_ENCRYPTED_METADATA,
+    EXT_FILENAME,
+    EXT_MODE,
+    EXT_FILE_SIZE,
+    EXT_COMMENT,
+    EXT_USER_METADATA,
+    EXT_CREATED_AT,
+    EXT_TOOL_VERSION,
+    EXT_CHUNK_INDEX,
+    EXT_KEY_CHECK,
+    EXT_PARITY,
+    EXT_PLAINTEXT_HASH,
+    EXT_ARCHIVE,
+    EXT_MTIME,
+    EXT_ATIME,
+    EXT_BTIME,
+    EXT_OWNER,
+    EXT_ATTRIBUTES,
+];
+
+/// A single TLV record from the v3 extension area.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+    pub tag: u16,
+    pub value: Vec<u8>,
+}
+
 /// Parsed container header.
 #[derive(Debug, Clone)]
 pub struct ContainerHeader {
@@ -35,6 +163,63 @@ pub struct ContainerHeader {
     pub mode: Option<u32>,
     pub original_file_size: u64,
     pub ciphertext_length: u64,
+    /// v3 extension records (always empty for v1/v2).
+    pub extensions: Vec<Extension>,
+}
+
+impl ContainerHeader {
+    /// Return the value of the first extension record with the given tag.
+    pub fn extension(&self, tag: u16) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|e| e.tag == tag)
+            .map(|e| e.value.as_slice())
+    }
+
+    /// Length of the chunk index footer, or `None` if the container has
+    /// no index.
+    pub fn footer_len(&self) -> Result<Option<u64>, HeaderError> {
+        match self.extension(EXT_CHUNK_INDEX) {
+            Some(value) => {
+                let bytes: [u8; 8] = value
+                    .try_into()
+                    .map_err(|_| HeaderError::InvalidExtensionArea)?;
+                Ok(Some(u64::from_be_bytes(bytes)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Length and percentage of the parity region, or `None` if the
+    /// container carries no parity data.
+    pub fn parity(&self) -> Result<Option<(u64, u8)>, HeaderError> {
+        match self.extension(EXT_PARITY) {
+            Some(value) => {
+                if value.len() != 9 {
+                    return Err(HeaderError::InvalidExtensionArea);
+                }
+                let len = u64::from_be_bytes(value[..8].try_into().unwrap());
+                Ok(Some((len, value[8])))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Stored plaintext hash, or `None` if the container has none.
+    pub fn plaintext_hash(&self) -> Result<Option<[u8; PLAINTEXT_HASH_LEN]>, HeaderError> {
+        self.extension(EXT_PLAINTEXT_HASH)
+            .map(|value| value.try_into().map_err(|_| HeaderError::InvalidExtensionArea))
+            .transpose()
+    }
+}
+
+/// Size of the fixed (non-extension) part of a header with no filename.
+fn fixed_header_len(version: u8) -> usize {
+    if version == 1 {
+        67
+    } else {
+        71
+    }
 }
 
 /// Encode a container header into bytes.
@@ -57,11 +242,16 @@ pub fn encode_header(header: &ContainerHeader) -> Vec<u8> {
     //   = 67 + N
     // v2 adds mode (uint32 BE) after filename:
     //   = 71 + N
-    let total_size = if header.version == 1 {
-        67 + filename_bytes.len()
+    // v3 appends the extension area: ext_len (uint32 BE) + TLV records,
+    // followed by the header MAC (32 bytes, see `sign_header`)
+    //   = 107 + N + ext_len
+    let (extension_bytes, mac_len) = if header.version >= 3 {
+        (encode_extensions(&header.extensions), HEADER_MAC_LEN)
     } else {
-        71 + filename_bytes.len()
+        (Vec::new(), 0)
     };
+    let total_size =
+        fixed_header_len(header.version) + filename_bytes.len() + extension_bytes.len() + mac_len;
     let mut buf = Vec::with_capacity(total_size);
 
     // Magic (8 bytes)
@@ -114,20 +304,162 @@ pub fn encode_header(header: &ContainerHeader) -> Vec<u8> {
     // Ciphertext length (uint64 BE)
     buf.extend_from_slice(&header.ciphertext_length.to_be_bytes());
 
+    // Extension area (v3+ only)
+    buf.extend_from_slice(&extension_bytes);
+
+    // Header MAC placeholder (v3+ only), filled in by `sign_header`
+    buf.resize(buf.len() + mac_len, 0);
+
+    buf
+}
+
+/// Compute the v3 header MAC: HMAC-SHA256 under the header MAC subkey over
+/// every header byte preceding the MAC field.
+fn header_mac(master_key: &[u8; 32], covered: &[u8]) -> Hmac<Sha256> {
+    let mac_key = kdf::derive_subkey(master_key, SUBKEY_HEADER_MAC);
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&mac_key)
+        .expect("HMAC-SHA256 accepts keys of any length");
+    mac.update(covered);
+    mac
+}
+
+/// Key-check MAC: HMAC-SHA256 of a fixed string under the key-check subkey.
+fn key_check_mac(master_key: &[u8; 32]) -> Hmac<Sha256> {
+    let check_key = kdf::derive_subkey(master_key, SUBKEY_KEY_CHECK);
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&check_key)
+        .expect("HMAC-SHA256 accepts keys of any length");
+    mac.update(b"gtkrypt key check");
+    mac
+}
+
+/// Compute the key-check value stored in `EXT_KEY_CHECK`.
+pub fn key_check_value(master_key: &[u8; 32]) -> [u8; KEY_CHECK_LEN] {
+    let tag = key_check_mac(master_key).finalize().into_bytes();
+    let mut value = [0u8; KEY_CHECK_LEN];
+    value.copy_from_slice(&tag[..KEY_CHECK_LEN]);
+    value
+}
+
+/// Compare a stored key-check value against the key in constant time.
+pub fn verify_key_check(value: &[u8], master_key: &[u8; 32]) -> bool {
+    value.len() == KEY_CHECK_LEN && key_check_mac(master_key).verify_truncated_left(value).is_ok()
+}
+
+/// Start the keyed BLAKE3 hash stored in `EXT_PLAINTEXT_HASH`. Keying it
+/// keeps the header from confirming guesses about the file contents.
+pub fn plaintext_hasher(master_key: &[u8; 32]) -> blake3::Hasher {
+    blake3::Hasher::new_keyed(&kdf::derive_subkey(master_key, SUBKEY_PLAINTEXT_HASH))
+}
+
+/// Fill in the trailing MAC of an encoded v3 header. This authenticates
+/// every header field (filename, mode, sizes, extensions), not just the
+/// AAD prefix that is bound into each chunk. No-op for v1/v2 headers.
+pub fn sign_header(header_bytes: &mut [u8], master_key: &[u8; 32]) {
+    if header_bytes[8] < 3 {
+        return;
+    }
+    let mac_start = header_bytes.len() - HEADER_MAC_LEN;
+    let tag = header_mac(master_key, &header_bytes[..mac_start]).finalize().into_bytes();
+    header_bytes[mac_start..].copy_from_slice(&tag);
+}
+
+/// Verify the trailing MAC of an encoded v3 header in constant time.
+/// v1/v2 headers carry no MAC and always verify.
+pub fn verify_header(header_bytes: &[u8], master_key: &[u8; 32]) -> bool {
+    if header_bytes[8] < 3 {
+        return true;
+    }
+    let mac_start = header_bytes.len() - HEADER_MAC_LEN;
+    header_mac(master_key, &header_bytes[..mac_start])
+        .verify_slice(&header_bytes[mac_start..])
+        .is_ok()
+}
+
+/// Encode extension records as a length-prefixed TLV area:
+/// ext_len (uint32 BE), then per record tag (uint16 BE), len (uint32 BE), value.
+pub fn encode_extensions(extensions: &[Extension]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for ext in extensions {
+        body.extend_from_slice(&ext.tag.to_be_bytes());
+        body.extend_from_slice(&(ext.value.len() as u32).to_be_bytes());
+        body.extend_from_slice(&ext.value);
+    }
+
+    let mut buf = Vec::with_capacity(4 + body.len());
+    buf.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&body);
     buf
 }
 
+/// Decode the TLV records of an extension area (without its length prefix).
+///
+/// Unknown tags are kept so they survive a header rewrite, unless they are
+/// marked critical, in which case the container cannot be read safely.
+pub fn decode_extensions(data: &[u8]) -> Result<Vec<Extension>, HeaderError> {
+    let mut extensions = Vec::new();
+    let mut offset = 0;
+
+    while offset < data.len() {
+        if data.len() - offset < 6 {
+            return Err(HeaderError::InvalidExtensionArea);
+        }
+        let tag = u16::from_be_bytes([data[offset], data[offset + 1]]);
+        let len = u32::from_be_bytes([
+            data[offset + 2],
+            data[offset + 3],
+            data[offset + 4],
+            data[offset + 5],
+        ]) as usize;
+        offset += 6;
+
+        if len > data.len() - offset {
+            return Err(HeaderError::InvalidExtensionArea);
+        }
+
+        if tag & EXT_CRITICAL != 0 && !KNOWN_EXTENSIONS.contains(&tag) {
+            return Err(HeaderError::UnsupportedExtension(tag));
+        }
+        // A repeated single-valued tag would be read one way here and
+        // another elsewhere. User metadata takes one record per entry, and
+        // unknown tags are left to whoever defines them.
+        if tag != EXT_USER_METADATA
+            && KNOWN_EXTENSIONS.contains(&tag)
+            && extensions.iter().any(|e: &Extension| e.tag == tag)
+        {
+            return Err(HeaderError::InvalidExtensionArea);
+        }
+
+        extensions.push(Extension {
+            tag,
+            value: data[offset..offset + len].to_vec(),
+        });
+        offset += len;
+    }
+
+    Ok(extensions)
+}
+
 /// The AAD (Additional Authenticated Data) is the header bytes from offset 0
 /// through the end of the nonce field.
 /// Layout: magic(8) + version(1) + kdf_id(1) + time_cost(4) + memory_cost(4)
 ///         + parallelism(1) + salt_len(1) + salt(16) + nonce_len(1) + nonce(12) = 49
 pub const AAD_LENGTH: usize = MAGIC.len() + 1 + 1 + 4 + 4 + 1 + 1 + SALT_LEN + 1 + NONCE_LEN;
 
-/// Extract the AAD portion from encoded header bytes.
-pub fn extract_aad(header_bytes: &[u8]) -> &[u8] {
+/// Return the fixed AAD prefix (magic through nonce, offset 0..49) of
+/// encoded header bytes.
+pub fn extract_aad_prefix(header_bytes: &[u8]) -> &[u8] {
     &header_bytes[..AAD_LENGTH]
 }
 
+/// Extract the AAD from encoded header bytes.
+///
+/// This is the fixed prefix (offset 0..49) for every version. In v3 the
+/// remaining header fields are authenticated by the header MAC instead,
+/// so they can be rewritten without re-encrypting the payload.
+pub fn extract_aad(header_bytes: &[u8]) -> Vec<u8> {
+    extract_aad_prefix(header_bytes).to_vec()
+}
+
 /// Decode a container header from raw bytes read from a file.
 ///
 /// Returns the parsed header and the total number of bytes consumed.
@@ -144,7 +476,7 @@ pub fn decode_header(data: &[u8]) -> Result<(ContainerHeader, usize), HeaderErro
 
     // Version
     let version = data[8];
-    if version != 1 && version != 2 {
+    if !(1..=VERSION).contains(&version) {
         return Err(HeaderError::UnsupportedVersion(version));
     }
 
@@ -163,6 +495,13 @@ pub fn decode_header(data: &[u8]) -> Result<(ContainerHeader, usize), HeaderErro
     // Argon2 parallelism (uint8 at offset 18)
     let parallelism = data[18] as u32;
 
+    let kdf_params = KdfParams {
+        time_cost,
+        memory_cost_kib,
+        parallelism,
+    };
+    validate_kdf_params(&kdf_params)?;
+
     // Salt length (offset 19, must be 16)
     let salt_len = data[19] as usize;
     if salt_len != SALT_LEN {
@@ -185,6 +524,12 @@ pub fn decode_header(data: &[u8]) -> Result<(ContainerHeader, usize), HeaderErro
 
     // Filename length (uint16 BE at offset 49)
     let filename_len = u16::from_be_bytes([data[49], data[50]]) as usize;
+    if filename_len > MAX_FILENAME_LEN {
+        return Err(HeaderError::FilenameTooLong(filename_len));
+    }
+    if data.len() < fixed_header_len(version) + filename_len {
+        return Err(HeaderError::TooShort);
+    }
 
     // Filename (offset 51..51+filename_len)
     let filename = if filename_len > 0 {
@@ -268,25 +613,71 @@ pub fn decode_header(data: &[u8]) -> Result<(ContainerHeader, usize), HeaderErro
         (Some(mode), original_file_size, ciphertext_length, offset + 20)
     };
 
+    // v3: extension area (uint32 BE length + TLV records)
+    let (extensions, total_consumed) = if version >= 3 {
+        let offset = total_consumed;
+        if data.len() < offset + 4 {
+            return Err(HeaderError::TooShort);
+        }
+        let ext_len = u32::from_be_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ]) as usize;
+        if ext_len > MAX_EXTENSIONS_LEN {
+            return Err(HeaderError::InvalidExtensionArea);
+        }
+        let ext_start = offset + 4;
+        if data.len() < ext_start + ext_len {
+            return Err(HeaderError::TooShort);
+        }
+        let mac_end = ext_start + ext_len + HEADER_MAC_LEN;
+        if data.len() < mac_end {
+            return Err(HeaderError::TooShort);
+        }
+        (
+            decode_extensions(&data[ext_start..ext_start + ext_len])?,
+            mac_end,
+        )
+    } else {
+        (Vec::new(), total_consumed)
+    };
+
     let header = ContainerHeader {
         version,
         kdf_id,
-        kdf_params: KdfParams {
-            time_cost,
-            memory_cost_kib,
-            parallelism,
-        },
+        kdf_params,
         salt,
         nonce,
         filename,
         mode,
         original_file_size,
         ciphertext_length,
+        extensions,
     };
 
     Ok((header, total_consumed))
 }
 
+/// Reject Argon2id parameters no valid container carries: Argon2 needs at
+/// least one pass and one lane, and 8 KiB of memory per lane.
+fn validate_kdf_params(params: &KdfParams) -> Result<(), HeaderError> {
+    if params.time_cost == 0 {
+        return Err(HeaderError::InvalidKdfParams("time cost is 0".to_string()));
+    }
+    if params.parallelism == 0 {
+        return Err(HeaderError::InvalidKdfParams("parallelism is 0".to_string()));
+    }
+    if params.memory_cost_kib < 8 * params.parallelism {
+        return Err(HeaderError::InvalidKdfParams(format!(
+            "memory cost {} KiB is below 8 KiB per lane",
+            params.memory_cost_kib
+        )));
+    }
+    Ok(())
+}
+
 /// Errors that can occur when parsing a container header.
 #[derive(Debug)]
 pub enum HeaderError {
@@ -297,6 +688,10 @@ pub enum HeaderError {
     InvalidSaltLength(usize),
     InvalidNonceLength(usize),
     InvalidFilename,
+    FilenameTooLong(usize),
+    InvalidKdfParams(String),
+    InvalidExtensionArea,
+    UnsupportedExtension(u16),
 }
 
 impl std::fmt::Display for HeaderError {
@@ -315,6 +710,16 @@ impl std::fmt::Display for HeaderError {
                 write!(f, "Invalid nonce length: {} (expected {})", len, NONCE_LEN)
             }
             HeaderError::InvalidFilename => write!(f, "Filename is not valid UTF-8"),
+            HeaderError::FilenameTooLong(len) => write!(
+                f,
+                "Filename length {} exceeds maximum of {} bytes",
+                len, MAX_FILENAME_LEN
+            ),
+            HeaderError::InvalidKdfParams(msg) => write!(f, "Invalid Argon2id parameters: {}", msg),
+            HeaderError::InvalidExtensionArea => write!(f, "Malformed header extension area"),
+            HeaderError::UnsupportedExtension(tag) => {
+                write!(f, "Unsupported critical header extension: 0x{:04x}", tag)
+            }
         }
     }
 }
@@ -340,6 +745,52 @@ pub fn build_chunk_aad(header_aad: &[u8], chunk_index: u32) -> Vec<u8> {
     aad
 }
 
+/// Derive a v3 STREAM nonce: the 64-bit chunk counter (big-endian) is
+/// XOR-ed into bytes 3..11 of the base nonce and the last-block flag into
+/// byte 11, so every (counter, last) pair yields a distinct nonce.
+pub fn derive_stream_nonce(
+    base_nonce: &[u8; NONCE_LEN],
+    counter: u64,
+    last: bool,
+) -> [u8; NONCE_LEN] {
+    let mut nonce = *base_nonce;
+    for (byte, counter_byte) in nonce[3..11].iter_mut().zip(counter.to_be_bytes()) {
+        *byte ^= counter_byte;
+    }
+    nonce[11] ^= last as u8;
+    nonce
+}
+
+/// Nonce for a chunk of a container with the given format version.
+/// v1/v2 use the u32 XOR counter; v3 uses the STREAM construction.
+pub fn chunk_nonce(
+    version: u8,
+    base_nonce: &[u8; NONCE_LEN],
+    chunk_index: u64,
+    last: bool,
+) -> [u8; NONCE_LEN] {
+    if version >= 3 {
+        derive_stream_nonce(base_nonce, chunk_index, last)
+    } else {
+        derive_chunk_nonce(base_nonce, chunk_index as u32)
+    }
+}
+
+/// AAD for a chunk of a container with the given format version: the
+/// header AAD followed by the chunk index as u32 (v1/v2), or by the u64
+/// index and a final-chunk flag byte (v3). Binding the flag means a
+/// container cut at a chunk boundary fails authentication.
+pub fn chunk_aad(version: u8, header_aad: &[u8], chunk_index: u64, last: bool) -> Vec<u8> {
+    if version >= 3 {
+        let mut aad = header_aad.to_vec();
+        aad.extend_from_slice(&chunk_index.to_be_bytes());
+        aad.push(last as u8);
+        aad
+    } else {
+        build_chunk_aad(header_aad, chunk_index as u32)
+    }
+}
+
 /// Read and decode a container header from a reader without loading the
 /// entire file into memory. Returns the parsed header, the total header
 /// byte count consumed, and the raw header bytes (needed for AAD extraction).
@@ -356,25 +807,51 @@ pub fn read_header_from_reader<R: Read>(
     let version = header_buf[8];
     let filename_len = u16::from_be_bytes([header_buf[49], header_buf[50]]) as usize;
 
-    let total_size = if version == 2 {
-        71 + filename_len
-    } else {
-        67 + filename_len
-    };
+    if !(1..=VERSION).contains(&version) {
+        return Err(HeaderError::UnsupportedVersion(version));
+    }
+    if filename_len > MAX_FILENAME_LEN {
+        return Err(HeaderError::FilenameTooLong(filename_len));
+    }
 
-    if total_size > header_buf.len() {
-        let extra_needed = total_size - header_buf.len();
-        let mut extra = vec![0u8; extra_needed];
-        reader
-            .read_exact(&mut extra)
-            .map_err(|_| HeaderError::TooShort)?;
-        header_buf.extend_from_slice(&extra);
+    let total_size = fixed_header_len(version) + filename_len;
+    read_more(reader, &mut header_buf, total_size)?;
+
+    // v3: read the extension length prefix, then the extension area and MAC
+    if version >= 3 {
+        read_more(reader, &mut header_buf, total_size + 4)?;
+        let ext_len = u32::from_be_bytes([
+            header_buf[total_size],
+            header_buf[total_size + 1],
+            header_buf[total_size + 2],
+            header_buf[total_size + 3],
+        ]) as usize;
+        if ext_len > MAX_EXTENSIONS_LEN {
+            return Err(HeaderError::InvalidExtensionArea);
+        }
+        read_more(reader, &mut header_buf, total_size + 4 + ext_len + HEADER_MAC_LEN)?;
     }
 
     let (header, consumed) = decode_header(&header_buf)?;
     Ok((header, consumed, header_buf))
 }
 
+/// Grow `buf` to `target_len` bytes by reading from the reader.
+fn read_more<R: Read>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    target_len: usize,
+) -> Result<(), HeaderError> {
+    if target_len > buf.len() {
+        let start = buf.len();
+        buf.resize(target_len, 0);
+        reader
+            .read_exact(&mut buf[start..])
+            .map_err(|_| HeaderError::TooShort)?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -394,6 +871,7 @@ mod tests {
             mode: Some(0o600),
             original_file_size: 12345,
             ciphertext_length: 12361, // 12345 + 16 tag
+            extensions: Vec::new(),
         }
     }
 
@@ -449,6 +927,7 @@ mod tests {
             mode: Some(0o640),
             original_file_size: 12345,
             ciphertext_length: 12345,
+            extensions: Vec::new(),
         };
 
         let encoded = encode_header(&header);
@@ -506,7 +985,8 @@ mod tests {
 
     #[test]
     fn test_aad_length() {
-        let header = make_test_header(None);
+        let mut header = make_test_header(None);
+        header.version = 2;
         let encoded = encode_header(&header);
         let aad = extract_aad(&encoded);
         assert_eq!(aad.len(), AAD_LENGTH);
@@ -585,6 +1065,53 @@ mod tests {
         assert_eq!(&aad[AAD_LENGTH..], &5u32.to_be_bytes());
     }
 
+    #[test]
+    fn test_key_check_value() {
+        let key = [8u8; 32];
+        let value = key_check_value(&key);
+        assert!(verify_key_check(&value, &key));
+        assert!(!verify_key_check(&value, &[9u8; 32]));
+        assert!(!verify_key_check(&value[..8], &key));
+    }
+
+    #[test]
+    fn test_derive_stream_nonce() {
+        let base_nonce = [0u8; NONCE_LEN];
+        let n = derive_stream_nonce(&base_nonce, 0x0102_0304_0506_0708, false);
+        assert_eq!(&n[..3], &[0, 0, 0]);
+        assert_eq!(&n[3..11], &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(n[11], 0);
+
+        // The last-block flag changes the nonce for the same counter
+        let last = derive_stream_nonce(&base_nonce, 0x0102_0304_0506_0708, true);
+        assert_eq!(last[11], 1);
+        assert_ne!(n, last);
+
+        // Counters beyond u32::MAX stay distinct
+        let base_nonce = [0x5A; NONCE_LEN];
+        assert_ne!(
+            derive_stream_nonce(&base_nonce, 1, false),
+            derive_stream_nonce(&base_nonce, 1 << 32 | 1, false)
+        );
+    }
+
+    #[test]
+    fn test_chunk_nonce_and_aad_by_version() {
+        let base_nonce = [0x42; NONCE_LEN];
+        assert_eq!(chunk_nonce(2, &base_nonce, 7, true), derive_chunk_nonce(&base_nonce, 7));
+        assert_eq!(
+            chunk_nonce(3, &base_nonce, 7, true),
+            derive_stream_nonce(&base_nonce, 7, true)
+        );
+
+        let header_aad = vec![0xAA; AAD_LENGTH];
+        assert_eq!(chunk_aad(2, &header_aad, 5, true).len(), AAD_LENGTH + 4);
+        let aad = chunk_aad(3, &header_aad, 5, false);
+        assert_eq!(&aad[AAD_LENGTH..AAD_LENGTH + 8], &5u64.to_be_bytes());
+        assert_eq!(aad[AAD_LENGTH + 8], 0);
+        assert_eq!(chunk_aad(3, &header_aad, 5, true)[AAD_LENGTH + 8], 1);
+    }
+
     #[test]
     fn test_read_header_from_reader_no_filename() {
         let header = make_test_header(None);
@@ -613,4 +1140,225 @@ mod tests {
         assert_eq!(consumed, encoded.len());
         assert_eq!(decoded.filename, Some("secret.txt".to_string()));
     }
+
+    #[test]
+    fn test_roundtrip_v3_extensions() {
+        let mut header = make_test_header(Some("notes.txt"));
+        header.extensions = vec![
+            Extension { tag: 0x0042, value: b"hello".to_vec() },
+            Extension { tag: 0x0043, value: Vec::new() },
+        ];
+        let encoded = encode_header(&header);
+
+        let (decoded, consumed) = decode_header(&encoded).unwrap();
+        assert_eq!(consumed, encoded.len());
+        assert_eq!(decoded.version, 3);
+        assert_eq!(decoded.filename, Some("notes.txt".to_string()));
+        assert_eq!(decoded.extensions, header.extensions);
+        assert_eq!(decoded.extension(0x0042), Some(&b"hello"[..]));
+        assert_eq!(decoded.extension(0x0099), None);
+    }
+
+    #[test]
+    fn test_v3_keeps_v2_field_offsets() {
+        let mut v2 = make_test_header(Some("a.txt"));
+        v2.version = 2;
+        let mut v3 = v2.clone();
+        v3.version = 3;
+
+        let enc2 = encode_header(&v2);
+        let enc3 = encode_header(&v3);
+        // v3 is the v2 layout plus an extension area (here: empty, 4 bytes)
+        // and the header MAC
+        assert_eq!(enc3.len(), enc2.len() + 4 + HEADER_MAC_LEN);
+        assert_eq!(&enc3[9..enc2.len() + 4], &[&enc2[9..], &[0u8; 4][..]].concat()[..]);
+    }
+
+    #[test]
+    fn test_reject_unknown_critical_extension() {
+        let mut header = make_test_header(None);
+        header.extensions = vec![Extension { tag: EXT_CRITICAL | 0x7777, value: vec![1] }];
+        let encoded = encode_header(&header);
+
+        let result = decode_header(&encoded);
+        assert!(matches!(result, Err(HeaderError::UnsupportedExtension(0xf777))));
+    }
+
+    #[test]
+    fn test_reject_truncated_extension_record() {
+        // Record claims 10 bytes of value but only 2 are present
+        let mut area = Vec::new();
+        area.extend_from_slice(&1u16.to_be_bytes());
+        area.extend_from_slice(&10u32.to_be_bytes());
+        area.extend_from_slice(&[0, 0]);
+        assert!(matches!(
+            decode_extensions(&area),
+            Err(HeaderError::InvalidExtensionArea)
+        ));
+    }
+
+    #[test]
+    fn test_reject_oversized_extension_area() {
+        let header = make_test_header(None);
+        let mut encoded = encode_header(&header);
+        let len_offset = encoded.len() - HEADER_MAC_LEN - 4;
+        encoded[len_offset..len_offset + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        assert!(matches!(
+            decode_header(&encoded),
+            Err(HeaderError::InvalidExtensionArea)
+        ));
+        let mut reader = std::io::Cursor::new(encoded);
+        assert!(matches!(
+            read_header_from_reader(&mut reader),
+            Err(HeaderError::InvalidExtensionArea)
+        ));
+    }
+
+    #[test]
+    fn test_reject_duplicate_extension() {
+        let mut header = make_test_header(None);
+        header.extensions = vec![
+            Extension { tag: EXT_COMMENT, value: b"one".to_vec() },
+            Extension { tag: EXT_COMMENT, value: b"two".to_vec() },
+        ];
+        assert!(matches!(
+            decode_header(&encode_header(&header)),
+            Err(HeaderError::InvalidExtensionArea)
+        ));
+    }
+
+    #[test]
+    fn test_reject_oversized_filename_length() {
+        let header = make_test_header(Some("a.txt"));
+        let mut encoded = encode_header(&header);
+        encoded[49..51].copy_from_slice(&u16::MAX.to_be_bytes());
+
+        assert!(matches!(
+            decode_header(&encoded),
+            Err(HeaderError::FilenameTooLong(65535))
+        ));
+        let mut reader = std::io::Cursor::new(encoded);
+        assert!(matches!(
+            read_header_from_reader(&mut reader),
+            Err(HeaderError::FilenameTooLong(65535))
+        ));
+
+        // Within the cap but longer than the data
+        let mut encoded = encode_header(&header);
+        encoded.truncate(70);
+        encoded[49..51].copy_from_slice(&(MAX_FILENAME_LEN as u16).to_be_bytes());
+        assert!(matches!(decode_header(&encoded), Err(HeaderError::TooShort)));
+    }
+
+    #[test]
+    fn test_reject_invalid_kdf_params() {
+        for (time_cost, memory_cost_kib, parallelism) in [(0, 1024, 1), (1, 1024, 0), (1, 31, 4)] {
+            let mut header = make_test_header(None);
+            header.kdf_params = KdfParams {
+                time_cost,
+                memory_cost_kib,
+                parallelism,
+            };
+            assert!(matches!(
+                decode_header(&encode_header(&header)),
+                Err(HeaderError::InvalidKdfParams(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_decode_header_survives_hostile_bytes() {
+        use rand::{Rng, SeedableRng};
+
+        let mut header = make_test_header(Some("fuzz.bin"));
+        header.extensions = vec![
+            Extension { tag: EXT_COMMENT, value: b"comment".to_vec() },
+            Extension { tag: EXT_CHUNK_INDEX, value: 64u64.to_be_bytes().to_vec() },
+        ];
+        let encoded = encode_header(&header);
+
+        // Every truncation fails cleanly
+        for len in 0..encoded.len() {
+            assert!(decode_header(&encoded[..len]).is_err(), "length {}", len);
+            let mut reader = std::io::Cursor::new(&encoded[..len]);
+            assert!(read_header_from_reader(&mut reader).is_err(), "length {}", len);
+        }
+
+        // Random corruptions may decode or not, but never panic, and never
+        // claim more bytes than were given
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1363);
+        for _ in 0..20_000 {
+            let mut data = encoded.clone();
+            for _ in 0..rng.gen_range(1..8) {
+                let at = rng.gen_range(0..data.len());
+                data[at] = rng.gen();
+            }
+            if let Ok((_, consumed)) = decode_header(&data) {
+                assert!(consumed <= data.len());
+            }
+            let _ = read_header_from_reader(&mut std::io::Cursor::new(&data));
+        }
+    }
+
+    #[test]
+    fn test_v3_aad_is_fixed_prefix() {
+        let mut header = make_test_header(None);
+        header.extensions = vec![Extension { tag: 7, value: b"abc".to_vec() }];
+        let encoded = encode_header(&header);
+        let aad = extract_aad(&encoded);
+        assert_eq!(&aad[..], &encoded[..AAD_LENGTH]);
+    }
+
+    #[test]
+    fn test_header_mac_sign_and_verify() {
+        let key = [5u8; 32];
+        let mut encoded = encode_header(&make_test_header(Some("a.txt")));
+        assert!(!verify_header(&encoded, &key));
+
+        sign_header(&mut encoded, &key);
+        assert!(verify_header(&encoded, &key));
+        assert!(!verify_header(&encoded, &[6u8; 32]));
+    }
+
+    #[test]
+    fn test_header_mac_detects_field_tampering() {
+        let key = [5u8; 32];
+        let mut encoded = encode_header(&make_test_header(Some("a.txt")));
+        sign_header(&mut encoded, &key);
+
+        // filename, mode, original size and ciphertext length all lie
+        // outside the AAD prefix but must still be covered
+        for offset in [51, 57, 62, 70, 75] {
+            let mut tampered = encoded.clone();
+            tampered[offset] ^= 0x01;
+            assert!(!verify_header(&tampered, &key), "offset {} not covered", offset);
+        }
+    }
+
+    #[test]
+    fn test_v2_headers_carry_no_mac() {
+        let mut header = make_test_header(None);
+        header.version = 2;
+        let mut encoded = encode_header(&header);
+        let before = encoded.clone();
+        sign_header(&mut encoded, &[5u8; 32]);
+        assert_eq!(encoded, before);
+        assert!(verify_header(&encoded, &[5u8; 32]));
+    }
+
+    #[test]
+    fn test_read_header_from_reader_with_extensions() {
+        let mut header = make_test_header(Some("secret.txt"));
+        header.extensions = vec![Extension { tag: 1, value: vec![9; 300] }];
+        let encoded = encode_header(&header);
+        let mut data = encoded.clone();
+        data.extend_from_slice(&[0u8; 100]);
+
+        let mut reader = std::io::Cursor::new(data);
+        let (decoded, consumed, raw) = read_header_from_reader(&mut reader).unwrap();
+        assert_eq!(consumed, encoded.len());
+        assert_eq!(raw, encoded);
+        assert_eq!(decoded.extensions, header.extensions);
+    }
 }
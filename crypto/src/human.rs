@@ -0,0 +1,386 @@
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use serde_json::{Map, Value};
+
+/// Width of the progress bar, between its brackets.
+const BAR_WIDTH: usize = 30;
+
+/// Least time between two drawings of a bar.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The bar on screen.
+static BAR: Mutex<Option<Bar>> = Mutex::new(None);
+
+struct Bar {
+    phase: String,
+    drawn: Instant,
+    /// Drawn full, on a line of its own now.
+    finished: bool,
+}
+
+/// Back to the start of the line and clear it, taking the progress bar
+/// away before anything else is written.
+const CLEAR_LINE: &str = "\r\x1b[K";
+
+const RED: &str = "31";
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const BOLD: &str = "1";
+
+/// Whether to color what goes to a stream, as the user asked through
+/// `NO_COLOR` and as the stream allows.
+fn color(stream: &impl IsTerminal) -> bool {
+    stream.is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+}
+
+fn paint(text: &str, code: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// `event` as text for a person at a terminal: a progress bar redrawn in
+/// place, or a line of its own for anything else. Nothing, for a redraw
+/// too soon after the last or a heartbeat under a bar.
+pub fn render(event: &Value) -> Vec<u8> {
+    if !due(event, Instant::now()) {
+        return Vec::new();
+    }
+    render_with(event, color(&io::stdout())).into_bytes()
+}
+
+/// Whether `event` is worth drawing at `now`, given the bar on screen.
+fn due(event: &Value, now: Instant) -> bool {
+    let mut bar = BAR.lock().unwrap_or_else(PoisonError::into_inner);
+    let phase = event["phase"].as_str().unwrap_or_default();
+    if event.get("heartbeat").is_some() {
+        return bar.as_ref().is_none_or(|bar| bar.phase != phase);
+    }
+    let Some(fields) = event.as_object().filter(|fields| fields.contains_key("bytes_processed"))
+    else {
+        // A line of its own, which takes the bar away
+        *bar = None;
+        return true;
+    };
+    let finished = finished(fields);
+    let redraw = match bar.as_ref() {
+        Some(bar) if bar.phase == phase => {
+            !bar.finished && (finished || now.duration_since(bar.drawn) >= REDRAW_INTERVAL)
+        }
+        _ => true,
+    };
+    if redraw {
+        *bar = Some(Bar {
+            phase: phase.to_string(),
+            drawn: now,
+            finished,
+        });
+    }
+    redraw
+}
+
+/// Write the error `event` to stderr as a line of text, taking the
+/// progress bar on stdout away first.
+pub fn print_error(event: &Value) {
+    let mut stdout = io::stdout().lock();
+    let _ = stdout.write_all(CLEAR_LINE.as_bytes()).and_then(|()| stdout.flush());
+    let color = color(&io::stderr());
+    let message = event["message"].as_str().unwrap_or("unknown error");
+    eprintln!("{} {}", paint("error:", &format!("{};{}", BOLD, RED), color), message);
+}
+
+fn render_with(event: &Value, color: bool) -> String {
+    let Some(fields) = event.as_object() else {
+        return format!("{}{}\n", CLEAR_LINE, event);
+    };
+    let text = |key: &str| fields.get(key).and_then(Value::as_str).unwrap_or_default();
+    let number = |key: &str| fields.get(key).and_then(Value::as_u64).unwrap_or_default();
+    let file = fields.get("file").and_then(Value::as_str);
+
+    if fields.contains_key("bytes_processed") {
+        return progress_bar(fields, file);
+    }
+    if fields.contains_key("heartbeat") {
+        static SPIN: AtomicUsize = AtomicUsize::new(0);
+        let spinner = ['|', '/', '-', '\\'][SPIN.fetch_add(1, Ordering::Relaxed) % 4];
+        return format!("{}{}{} {}", CLEAR_LINE, about(file), phase_label(text("phase")), spinner);
+    }
+
+    let line = if fields.contains_key("summary") {
+        let (done, size) = match text("summary") {
+            "encrypt" => ("Encrypted", number("input_bytes")),
+            _ => ("Decrypted", number("output_bytes")),
+        };
+        let elapsed = fields.get("elapsed_secs").and_then(Value::as_f64).unwrap_or_default();
+        format!(
+            "{} {} to {} in {} ({}/s)",
+            paint(done, GREEN, color),
+            format_bytes(size),
+            text("output_path"),
+            format_secs(elapsed),
+            format_bytes(number("bytes_per_sec")),
+        )
+    } else if fields.contains_key("warning") {
+        format!("{} {}", paint("warning:", &format!("{};{}", BOLD, YELLOW), color), text("message"))
+    } else if fields.contains_key("result") {
+        // A batch input, whose name is the line itself
+        let line = match text("result") {
+            "ok" => format!("{} {}", paint("ok", GREEN, color), text("file")),
+            _ => format!("{} {}: {}", paint("failed", RED, color), text("file"), text("message")),
+        };
+        return format!("{}{}\n", CLEAR_LINE, line);
+    } else if let Some(failed) = fields.get("failed").and_then(Value::as_array) {
+        let failed: Vec<&str> = failed.iter().filter_map(Value::as_str).collect();
+        match failed.len() {
+            0 => format!("{} succeeded", number("succeeded")),
+            _ => format!(
+                "{} succeeded, {}: {}",
+                number("succeeded"),
+                paint(&format!("{} failed", failed.len()), RED, color),
+                failed.join(", ")
+            ),
+        }
+    } else if fields.contains_key("passphrase_strength") {
+        let strength = number("passphrase_strength");
+        let shade = match strength {
+            0 | 1 => RED,
+            2 => YELLOW,
+            _ => GREEN,
+        };
+        format!(
+            "Passphrase strength {}, guessed in {}",
+            paint(&format!("{}/4", strength), shade, color),
+            text("crack_time")
+        )
+    } else if fields.contains_key("key_cache") {
+        match text("key_cache") {
+            "hit" => "Passphrase taken from the session keyring".to_string(),
+            _ => format!(
+                "Passphrase kept in the session keyring for {}",
+                format_secs(number("expires_in_secs") as f64)
+            ),
+        }
+    } else if fields.contains_key("input_deleted") {
+        let verb = if fields.contains_key("shredded") { "Shredded" } else { "Deleted" };
+        format!("{} {}", verb, text("input_deleted"))
+    } else if fields.contains_key("temp_removed") {
+        format!("Removed stale temporary {}", text("temp_removed"))
+    } else if fields.contains_key("input_count") {
+        format!("{} inputs", number("input_count"))
+    } else if fields.contains_key("state") {
+        match text("state") {
+            "paused" => "Paused".to_string(),
+            _ => "Running".to_string(),
+        }
+    } else if fields.len() == 1 + usize::from(file.is_some()) && fields.contains_key("output_path")
+    {
+        format!("Writing {}", text("output_path"))
+    } else {
+        plain_fields(fields)
+    };
+    format!("{}{}{}\n", CLEAR_LINE, about(file), line)
+}
+
+/// "file: " before a line about one input of several.
+fn about(file: Option<&str>) -> String {
+    file.map(|file| format!("{}: ", file)).unwrap_or_default()
+}
+
+fn phase_label(phase: &str) -> &str {
+    match phase {
+        "kdf" => "Deriving key",
+        "encrypt" => "Encrypting",
+        "decrypt" => "Decrypting",
+        "verify" => "Verifying",
+        "sync" => "Syncing",
+        "shred" => "Shredding",
+        "extract" => "Extracting",
+        "repair" => "Repairing",
+        other => other,
+    }
+}
+
+/// The bar for a progress event, left open to be drawn over, but for the
+/// last of its phase, which stays.
+fn progress_bar(fields: &Map<String, Value>, file: Option<&str>) -> String {
+    let label = phase_label(fields["phase"].as_str().unwrap_or_default());
+    let done = fields["bytes_processed"].as_u64().unwrap_or_default();
+    let total = fields.get("total_bytes").and_then(Value::as_u64);
+    let rate = fields.get("bytes_per_second").and_then(Value::as_u64);
+    let eta = fields.get("eta_seconds").and_then(Value::as_f64);
+    // The key derivation counts estimated bytes of memory, not data
+    let kdf = fields["phase"] == "kdf";
+
+    let mut bar = format!("{}{}{:<13}", CLEAR_LINE, about(file), label);
+    let finished = finished(fields);
+    match total {
+        Some(total) => {
+            // A total of 0 marks the start of a phase
+            let share = if total == 0 { 0.0 } else { (done as f64 / total as f64).min(1.0) };
+            let filled = (share * BAR_WIDTH as f64).round() as usize;
+            let inside = match filled {
+                BAR_WIDTH => "=".repeat(BAR_WIDTH),
+                _ => format!("{}>{}", "=".repeat(filled), " ".repeat(BAR_WIDTH - filled - 1)),
+            };
+            bar.push_str(&format!(" [{}] {:>3}%", inside, (share * 100.0).floor() as u32));
+            if !kdf {
+                bar.push_str(&format!(" {}/{}", format_bytes(done), format_bytes(total)));
+            }
+        }
+        None => bar.push_str(&format!(" {}", format_bytes(done))),
+    }
+    if let Some(rate) = rate {
+        bar.push_str(&format!(" {}/s", format_bytes(rate)));
+    }
+    match eta {
+        Some(eta) if !finished => bar.push_str(&format!(" ETA {}", format_secs(eta))),
+        _ => {}
+    }
+    if finished {
+        bar.push('\n');
+    }
+    bar
+}
+
+/// Whether a progress event is the last of its phase.
+fn finished(fields: &Map<String, Value>) -> bool {
+    let done = fields["bytes_processed"].as_u64().unwrap_or_default();
+    match fields.get("total_bytes").and_then(Value::as_u64) {
+        Some(total) => total > 0 && done >= total,
+        None => false,
+    }
+}
+
+/// The fields of an event with no text of its own, as `key: value` pairs.
+fn plain_fields(fields: &Map<String, Value>) -> String {
+    let pairs: Vec<String> = fields
+        .iter()
+        .map(|(key, value)| match value {
+            Value::String(text) => format!("{}: {}", key, text),
+            other => format!("{}: {}", key, other),
+        })
+        .collect();
+    pairs.join(", ")
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn format_secs(secs: f64) -> String {
+    if secs < 10.0 {
+        return format!("{:.1}s", secs);
+    }
+    let secs = secs.round() as u64;
+    match secs {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m {:02}s", s / 60, s % 60),
+        s => format!("{}h {:02}m", s / 3600, s % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn shown(event: Value) -> String {
+        render_with(&event, false).replace(CLEAR_LINE, "")
+    }
+
+    #[test]
+    fn test_progress_bar() {
+        let bar = shown(json!({
+            "progress": 0.5, "bytes_processed": 1_048_576, "total_bytes": 2_097_152,
+            "phase": "encrypt", "bytes_per_second": 524_288, "eta_seconds": 2.0,
+        }));
+        assert_eq!(
+            bar,
+            format!(
+                "Encrypting    [{}>{}]  50% 1.0 MiB/2.0 MiB 512.0 KiB/s ETA 2.0s",
+                "=".repeat(15),
+                " ".repeat(14)
+            )
+        );
+
+        // The last of a phase stays on its line
+        let bar = shown(json!({
+            "progress": 1.0, "bytes_processed": 10, "total_bytes": 10, "phase": "kdf",
+        }));
+        assert_eq!(bar, format!("Deriving key  [{}] 100%\n", "=".repeat(BAR_WIDTH)));
+
+        let bar = shown(json!({
+            "progress": null, "bytes_processed": 2048, "total_bytes": null, "phase": "decrypt",
+            "file": "a.gtkrypt",
+        }));
+        assert_eq!(bar, "a.gtkrypt: Decrypting    2.0 KiB");
+    }
+
+    #[test]
+    fn test_redraws_are_spaced() {
+        let at = |done: u64| {
+            json!({"progress": null, "bytes_processed": done, "total_bytes": 100, "phase": "p"})
+        };
+        let start = Instant::now();
+        assert!(due(&at(0), start));
+        assert!(!due(&at(1), start + Duration::from_millis(10)));
+        assert!(!due(&json!({"phase": "p", "heartbeat": true}), start));
+        assert!(due(&at(2), start + REDRAW_INTERVAL));
+        // The last one always, and once
+        assert!(due(&at(100), start + REDRAW_INTERVAL));
+        assert!(!due(&at(100), start + REDRAW_INTERVAL * 2));
+        assert!(due(&json!({"warning": "w", "message": "m"}), start));
+        assert!(due(&json!({"phase": "p", "heartbeat": true}), start));
+    }
+
+    #[test]
+    fn test_event_lines() {
+        let summary = shown(json!({
+            "summary": "encrypt", "output_path": "a.gtkrypt", "input_bytes": 3_145_728,
+            "output_bytes": 3_145_900, "elapsed_secs": 1.5, "bytes_per_sec": 3_145_728,
+        }));
+        assert_eq!(summary, "Encrypted 3.0 MiB to a.gtkrypt in 1.5s (3.0 MiB/s)\n");
+        let warning = shown(json!({"warning": "slow", "message": "No AES", "file": "a"}));
+        assert_eq!(warning, "a: warning: No AES\n");
+        let failed = shown(json!({"file": "b", "result": "failed", "message": "Wrong"}));
+        assert_eq!(failed, "failed b: Wrong\n");
+        let totals = shown(json!({"succeeded": 2, "failed": ["b", "c"]}));
+        assert_eq!(totals, "2 succeeded, 2 failed: b, c\n");
+        assert_eq!(shown(json!({"output_path": "x"})), "Writing x\n");
+        assert_eq!(shown(json!({"output_path": "x", "file": "y"})), "y: Writing x\n");
+        let other = shown(json!({"header_backup": "h.bak", "bytes": 512}));
+        assert_eq!(other, "bytes: 512, header_backup: h.bak\n");
+    }
+
+    #[test]
+    fn test_colors() {
+        let warning = render_with(&json!({"warning": "w", "message": "m"}), true);
+        assert!(warning.contains("\x1b[1;33mwarning:\x1b[0m m"));
+    }
+
+    #[test]
+    fn test_formatting() {
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 << 40), "5.0 TiB");
+        assert_eq!(format_secs(0.5), "0.5s");
+        assert_eq!(format_secs(42.4), "42s");
+        assert_eq!(format_secs(185.0), "3m 05s");
+        assert_eq!(format_secs(7300.0), "2h 01m");
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::decrypt::{self, DecryptError};
+use crate::header::{EXT_ENCRYPTED_METADATA, KDF_ID_ARGON2ID};
+use crate::kdf::{self, KdfLimits, KeyCache};
+use crate::metadata::{self, Metadata, Timestamp};
+
+/// Options for inspecting a container.
+pub struct InspectOptions {
+    pub input_path: String,
+    /// Key material used to unlock encrypted metadata. When `None`, only
+    /// the plaintext header is reported.
+    pub passphrase: Option<Vec<u8>>,
+}
+
+/// KDF parameters as reported by `inspect`.
+#[derive(Debug, Serialize)]
+pub struct KdfReport {
+    pub algorithm: String,
+    pub time_cost: u32,
+    pub memory_cost_kib: u32,
+    pub parallelism: u32,
+    /// Hex, for deriving the key in another process.
+    pub salt: String,
+}
+
+/// Header summary printed by the `inspect` subcommand.
+#[derive(Debug, Serialize)]
+pub struct InspectReport {
+    pub version: u8,
+    pub kdf: KdfReport,
+    pub header_size: usize,
+    pub ciphertext_length: u64,
+    /// True when filename, mode and size live in an encrypted block.
+    pub metadata_encrypted: bool,
+    /// False when metadata is encrypted and no passphrase was supplied.
+    pub metadata_available: bool,
+    /// Stored filename after [`metadata::sanitize_filename`].
+    pub filename: Option<String>,
+    /// True when the stored filename had to be changed to be safe, which a
+    /// container made by this tool never needs.
+    pub filename_suspicious: bool,
+    pub mode: Option<u32>,
+    pub original_file_size: Option<u64>,
+    pub comment: Option<String>,
+    pub user_metadata: BTreeMap<String, String>,
+    /// Creation time in seconds since the Unix epoch.
+    pub created_at: Option<u64>,
+    pub tool_version: Option<String>,
+    /// Modification time of the original file.
+    pub mtime: Option<Timestamp>,
+}
+
+impl InspectReport {
+    /// Render the report as human-readable `Key: value` lines.
+    pub fn to_text(&self) -> String {
+        let mut lines = vec![
+            format!("Format version: {}", self.version),
+            format!(
+                "KDF: {} (time cost {}, memory {} KiB, parallelism {})",
+                self.kdf.algorithm,
+                self.kdf.time_cost,
+                self.kdf.memory_cost_kib,
+                self.kdf.parallelism
+            ),
+            format!("Header size: {} bytes", self.header_size),
+            format!("Ciphertext length: {} bytes", self.ciphertext_length),
+        ];
+
+        if self.metadata_encrypted && !self.metadata_available {
+            lines.push("Metadata: encrypted (supply the passphrase to reveal)".to_string());
+            return lines.join("\n");
+        }
+        if self.metadata_encrypted {
+            lines.push("Metadata: encrypted".to_string());
+        }
+        match (&self.filename, self.filename_suspicious) {
+            (Some(name), false) => lines.push(format!("Filename: {}", name)),
+            (Some(name), true) => {
+                lines.push(format!("Filename: {} (sanitized; stored name is suspicious)", name))
+            }
+            (None, true) => lines.push("Filename: (stored name is suspicious)".to_string()),
+            (None, false) => {}
+        }
+        if let Some(mode) = self.mode.filter(|m| *m != 0) {
+            lines.push(format!("Mode: {:04o}", mode));
+        }
+        if let Some(size) = self.original_file_size {
+            lines.push(format!("Original size: {} bytes", size));
+        }
+        if let Some(ref comment) = self.comment {
+            lines.push(format!("Comment: {}", comment));
+        }
+        if let Some(created_at) = self.created_at {
+            lines.push(format!("Created: {}", format_unix_time(created_at)));
+        }
+        if let Some(ref tool_version) = self.tool_version {
+            lines.push(format!("Created with: {}", tool_version));
+        }
+        if let Some(mtime) = self.mtime.filter(|t| t.secs >= 0) {
+            lines.push(format!("Modified: {}", format_unix_time(mtime.secs as u64)));
+        }
+        for (key, value) in &self.user_metadata {
+            lines.push(format!("Meta: {}={}", key, value));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Format seconds since the Unix epoch as an RFC 3339 UTC timestamp.
+pub fn format_unix_time(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // Civil-from-days conversion (proleptic Gregorian calendar)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        (rem % 3_600) / 60,
+        rem % 60
+    )
+}
+
+/// Read a container header and summarize it, decrypting the metadata block
+/// when a passphrase is supplied. The payload is never read.
+pub fn inspect(opts: &InspectOptions) -> Result<InspectReport, DecryptError> {
+    let (reader, header_obj, header_size, header_bytes) =
+        decrypt::open_container(&opts.input_path)?;
+
+    let metadata_encrypted = header_obj.extension(EXT_ENCRYPTED_METADATA).is_some();
+
+    let file_metadata = match (&opts.passphrase, metadata_encrypted) {
+        (Some(passphrase), _) => {
+            // A truncated container still shows its header, but is not
+            // worth a KDF run
+            decrypt::check_container_size(reader.get_ref().size(), &header_obj, header_size)?;
+            let key = decrypt::derive_header_key(
+                passphrase,
+                &header_obj,
+                &KeyCache::default(),
+                &KdfLimits::default(),
+            )?;
+            let key_confirmed = decrypt::verify_key(&header_obj, &header_bytes, &key)?;
+            Some(
+                metadata::resolve(&header_obj, &header_bytes, &key)
+                    .map_err(|e| decrypt::map_metadata_error(e, key_confirmed))?,
+            )
+        }
+        (None, false) => {
+            Some(
+                Metadata::from_header(&header_obj)
+                    .map_err(|e| decrypt::map_metadata_error(e, false))?,
+            )
+        }
+        (None, true) => None,
+    };
+
+    let algorithm = if header_obj.kdf_id == KDF_ID_ARGON2ID {
+        "argon2id"
+    } else {
+        "unknown"
+    };
+
+    let mut report = InspectReport {
+        version: header_obj.version,
+        kdf: KdfReport {
+            algorithm: algorithm.to_string(),
+            time_cost: header_obj.kdf_params.time_cost,
+            memory_cost_kib: header_obj.kdf_params.memory_cost_kib,
+            parallelism: header_obj.kdf_params.parallelism,
+            salt: kdf::to_hex(&header_obj.salt),
+        },
+        header_size,
+        ciphertext_length: header_obj.ciphertext_length,
+        metadata_encrypted,
+        metadata_available: file_metadata.is_some(),
+        filename: None,
+        filename_suspicious: false,
+        mode: None,
+        original_file_size: None,
+        comment: None,
+        user_metadata: BTreeMap::new(),
+        created_at: None,
+        tool_version: None,
+        mtime: None,
+    };
+
+    if let Some(md) = file_metadata {
+        if let Some(stored) = md.filename {
+            report.filename = metadata::sanitize_filename(&stored);
+            report.filename_suspicious = report.filename.as_deref() != Some(stored.as_str());
+        }
+        report.mode = md.mode;
+        report.original_file_size = Some(md.original_file_size);
+        report.comment = md.comment;
+        report.user_metadata = md.user_metadata;
+        report.created_at = md.created_at;
+        report.tool_version = md.tool_version;
+        report.mtime = md.mtime;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt::tests::{encrypt_fixture, Fixture};
+    use crate::encrypt;
+    use crate::header;
+
+    #[test]
+    fn test_inspect_plaintext_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = encrypt_fixture(
+            dir.path(),
+            b"inspect me",
+            b"inspect_pass",
+            Fixture {
+                filename: Some("receipts.txt"),
+                comment: Some("taxes 2023, includes receipts"),
+                meta: &[("backup.job", "nightly")],
+                ..Default::default()
+            },
+        );
+
+        let report = inspect(&InspectOptions {
+            input_path: path,
+            passphrase: None,
+        })
+        .unwrap();
+        assert_eq!(report.version, header::VERSION);
+        assert!(!report.metadata_encrypted);
+        assert_eq!(report.filename.as_deref(), Some("receipts.txt"));
+        assert!(!report.filename_suspicious);
+        assert_eq!(report.original_file_size, Some(10));
+        assert_eq!(report.comment.as_deref(), Some("taxes 2023, includes receipts"));
+        assert!(report.to_text().contains("Comment: taxes 2023, includes receipts"));
+        assert_eq!(report.user_metadata.get("backup.job").map(String::as_str), Some("nightly"));
+        assert!(report.to_text().contains("Meta: backup.job=nightly"));
+        assert!(report.created_at.is_some());
+        assert_eq!(report.tool_version.as_deref(), Some(encrypt::TOOL_VERSION));
+        assert!(report.mtime.is_some());
+        assert!(report.to_text().contains("Modified: "));
+    }
+
+    /// A backslash is an ordinary filename character on unix but a path
+    /// separator wherever the GUI may write the file.
+    #[cfg(unix)]
+    #[test]
+    fn test_inspect_flags_suspicious_filename() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = encrypt_fixture(
+            dir.path(),
+            b"payload",
+            b"inspect_pass",
+            Fixture {
+                filename: Some("..\\..\\startup.bat"),
+                ..Default::default()
+            },
+        );
+
+        let report = inspect(&InspectOptions {
+            input_path: path,
+            passphrase: None,
+        })
+        .unwrap();
+        assert_eq!(report.filename.as_deref(), Some("startup.bat"));
+        assert!(report.filename_suspicious);
+        assert!(report.to_text().contains("stored name is suspicious"));
+    }
+
+    #[test]
+    fn test_format_unix_time() {
+        assert_eq!(format_unix_time(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_unix_time(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_unix_time(1_700_000_000), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn test_inspect_encrypted_metadata_requires_passphrase() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = encrypt_fixture(
+            dir.path(),
+            b"inspect me",
+            b"inspect_pass",
+            Fixture {
+                filename: Some("receipts.txt"),
+                comment: Some("taxes 2023, includes receipts"),
+                meta: &[("backup.job", "nightly")],
+                encrypt_metadata: true,
+                ..Default::default()
+            },
+        );
+
+        let locked = inspect(&InspectOptions {
+            input_path: path.clone(),
+            passphrase: None,
+        })
+        .unwrap();
+        assert!(locked.metadata_encrypted);
+        assert!(!locked.metadata_available);
+        assert_eq!(locked.comment, None);
+
+        let unlocked = inspect(&InspectOptions {
+            input_path: path.clone(),
+            passphrase: Some(b"inspect_pass".to_vec()),
+        })
+        .unwrap();
+        assert_eq!(unlocked.comment.as_deref(), Some("taxes 2023, includes receipts"));
+        assert_eq!(unlocked.filename.as_deref(), Some("receipts.txt"));
+        assert_eq!(unlocked.user_metadata.len(), 1);
+
+        let wrong = inspect(&InspectOptions {
+            input_path: path,
+            passphrase: Some(b"nope".to_vec()),
+        });
+        assert!(matches!(wrong, Err(DecryptError::WrongPassphrase(_))));
+    }
+}
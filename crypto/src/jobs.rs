@@ -0,0 +1,306 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::decrypt::{self, DecryptOptions, PermissionPolicy, RestoreOptions};
+use crate::encrypt::EncryptOptions;
+use crate::kdf::{KdfParams, KeyCache};
+use crate::naming::{Conflict, OutputNaming};
+
+/// One entry of a jobs manifest, selected by its `operation` field.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "operation", rename_all = "lowercase")]
+pub enum Job {
+    Encrypt(EncryptJob),
+    Decrypt(DecryptJob),
+}
+
+/// An `encrypt` job. Fields left out take the defaults of the matching
+/// command line flags.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptJob {
+    pub input: String,
+    pub output: Option<String>,
+    pub output_template: Option<String>,
+    pub conflict: Conflict,
+    pub time_cost: u32,
+    pub memory_cost: u32,
+    pub parallelism: u32,
+    pub store_filename: bool,
+    pub encrypt_metadata: bool,
+    pub comment: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+    pub pad: bool,
+    pub parity: u8,
+    pub verify: bool,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub delete_input: bool,
+    pub allow_double: bool,
+}
+
+impl Default for EncryptJob {
+    fn default() -> Self {
+        let kdf = KdfParams::default();
+        EncryptJob {
+            input: String::new(),
+            output: None,
+            output_template: None,
+            conflict: Conflict::default(),
+            time_cost: kdf.time_cost,
+            memory_cost: kdf.memory_cost_kib,
+            parallelism: kdf.parallelism,
+            store_filename: false,
+            encrypt_metadata: false,
+            comment: None,
+            metadata: BTreeMap::new(),
+            pad: false,
+            parity: 0,
+            verify: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            delete_input: false,
+            allow_double: false,
+        }
+    }
+}
+
+/// A `decrypt` job. Fields left out take the defaults of the matching
+/// command line flags.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DecryptJob {
+    pub input: String,
+    pub output: Option<String>,
+    pub output_dir: Option<String>,
+    pub output_template: Option<String>,
+    pub conflict: Conflict,
+    pub restore_times: bool,
+    pub restore_owner: bool,
+    pub restore_permissions: bool,
+    /// Octal mode such as "640", in place of the stored one.
+    #[serde(deserialize_with = "deserialize_mode")]
+    pub mode: Option<u32>,
+}
+
+impl Default for DecryptJob {
+    fn default() -> Self {
+        DecryptJob {
+            input: String::new(),
+            output: None,
+            output_dir: None,
+            output_template: None,
+            conflict: Conflict::default(),
+            restore_times: true,
+            restore_owner: false,
+            restore_permissions: true,
+            mode: None,
+        }
+    }
+}
+
+fn deserialize_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|mode| decrypt::parse_mode(&mode))
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
+impl Job {
+    pub fn input(&self) -> &str {
+        match self {
+            Job::Encrypt(job) => &job.input,
+            Job::Decrypt(job) => &job.input,
+        }
+    }
+
+    /// Catch what the command line parser would have refused.
+    fn check(&self) -> Result<(), String> {
+        if self.input().is_empty() {
+            return Err("no input".to_string());
+        }
+        match self {
+            Job::Encrypt(job) => match (&job.output, &job.output_template) {
+                (Some(_), Some(_)) => Err("output and output_template exclude each other".into()),
+                (None, None) => Err("needs an output or an output_template".to_string()),
+                _ => Ok(()),
+            },
+            Job::Decrypt(job) => match (&job.output, &job.output_dir) {
+                (Some(_), Some(_)) => Err("output and output_dir exclude each other".to_string()),
+                (None, None) => Err("needs an output or an output_dir".to_string()),
+                (Some(_), None) if job.output_template.is_some() => {
+                    Err("output_template needs an output_dir".to_string())
+                }
+                _ if job.mode.is_some() && !job.restore_permissions => {
+                    Err("mode and restore_permissions: false exclude each other".to_string())
+                }
+                _ => Ok(()),
+            },
+        }
+    }
+}
+
+impl EncryptJob {
+    pub fn naming(&self) -> OutputNaming {
+        OutputNaming {
+            template: self.output_template.clone(),
+            conflict: self.conflict,
+        }
+    }
+
+    /// Options for this job, writing to the already settled `output_path`.
+    pub fn options(
+        &self,
+        output_path: String,
+        passphrase: Vec<u8>,
+        keys: KeyCache,
+    ) -> EncryptOptions {
+        EncryptOptions {
+            input_path: self.input.clone(),
+            output_path,
+            passphrase,
+            time_cost: self.time_cost,
+            memory_cost_kib: self.memory_cost,
+            parallelism: self.parallelism,
+            store_filename: self.store_filename,
+            encrypt_metadata: self.encrypt_metadata,
+            comment: self.comment.clone(),
+            user_metadata: self.metadata.clone(),
+            pad: self.pad,
+            parity: self.parity,
+            verify: self.verify,
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            delete_input: self.delete_input,
+            allow_double: self.allow_double,
+            keys,
+            ..Default::default()
+        }
+    }
+}
+
+impl DecryptJob {
+    pub fn options(&self, passphrase: Vec<u8>, keys: KeyCache) -> DecryptOptions {
+        let permissions = match self.mode {
+            Some(mode) => PermissionPolicy::Mode(mode),
+            None if !self.restore_permissions => PermissionPolicy::Umask,
+            None => PermissionPolicy::Stored,
+        };
+        DecryptOptions {
+            input_path: self.input.clone(),
+            output_dir: self.output_dir.is_some(),
+            output_path: self.output.clone().or(self.output_dir.clone()).unwrap_or_default(),
+            naming: OutputNaming {
+                template: self.output_template.clone(),
+                conflict: self.conflict,
+            },
+            passphrase,
+            restore: RestoreOptions {
+                times: self.restore_times,
+                owner: self.restore_owner,
+                permissions,
+            },
+            keys,
+            ..Default::default()
+        }
+    }
+}
+
+/// Read a manifest, a JSON array of jobs, from `path` or from the rest of
+/// stdin for "-". Every job is checked before any of them runs.
+pub fn read_manifest(path: &str) -> Result<Vec<Job>, String> {
+    let text = if path == "-" {
+        let mut text = String::new();
+        std::io::stdin()
+            .read_to_string(&mut text)
+            .map_err(|e| format!("Failed to read jobs from stdin: {}", e))?;
+        text
+    } else {
+        std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read jobs file '{}': {}", path, e))?
+    };
+    parse_manifest(&text)
+}
+
+/// Read a single job for `operation` from its other fields, as a `serve`
+/// request gives them.
+pub fn parse_job(operation: &str, mut fields: serde_json::Value) -> Result<Job, String> {
+    let Some(object) = fields.as_object_mut() else {
+        return Err("The params must be an object".to_string());
+    };
+    object.insert("operation".to_string(), operation.into());
+    let job: Job = serde_json::from_value(fields).map_err(|e| e.to_string())?;
+    job.check()?;
+    Ok(job)
+}
+
+fn parse_manifest(text: &str) -> Result<Vec<Job>, String> {
+    let jobs: Vec<Job> =
+        serde_json::from_str(text).map_err(|e| format!("Invalid jobs manifest: {}", e))?;
+    if jobs.is_empty() {
+        return Err("The jobs manifest lists no jobs".to_string());
+    }
+    for (index, job) in jobs.iter().enumerate() {
+        job.check().map_err(|e| format!("Job {} ({}): {}", index, job.input(), e))?;
+    }
+    Ok(jobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_manifest() {
+        let jobs = parse_manifest(
+            r#"[
+                {"operation": "encrypt", "input": "a.txt", "output_template": "{stem}.gtkrypt",
+                 "conflict": "rename", "store_filename": true, "metadata": {"owner": "ops"}},
+                {"operation": "decrypt", "input": "b.gtkrypt", "output_dir": "out",
+                 "mode": "640", "restore_times": false}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(jobs.len(), 2);
+        let Job::Encrypt(ref encrypt) = jobs[0] else { panic!("expected encrypt") };
+        assert_eq!(encrypt.conflict, Conflict::Rename);
+        assert_eq!(encrypt.time_cost, KdfParams::default().time_cost);
+        assert_eq!(encrypt.metadata["owner"], "ops");
+        let Job::Decrypt(ref decrypt) = jobs[1] else { panic!("expected decrypt") };
+        let opts = decrypt.options(Vec::new(), KeyCache::default());
+        assert!(opts.output_dir);
+        assert!(!opts.restore.times);
+        assert_eq!(opts.restore.permissions, PermissionPolicy::Mode(0o640));
+    }
+
+    #[test]
+    fn test_manifest_is_checked_before_running() {
+        let bad = [
+            r#"[]"#,
+            r#"[{"operation": "shred", "input": "a"}]"#,
+            r#"[{"operation": "encrypt", "input": "a"}]"#,
+            r#"[{"operation": "encrypt", "input": "a", "output": "b", "colour": "red"}]"#,
+            r#"[{"operation": "decrypt", "input": "a", "output": "b", "output_dir": "c"}]"#,
+            r#"[{"operation": "decrypt", "input": "a", "output": "b", "mode": "999"}]"#,
+            r#"[{"operation": "decrypt", "output": "b"}]"#,
+        ];
+        for manifest in bad {
+            assert!(parse_manifest(manifest).is_err(), "{}", manifest);
+        }
+    }
+
+    #[test]
+    fn test_parse_job() {
+        let fields = serde_json::json!({"input": "a.gtkrypt", "output": "a.txt"});
+        let Job::Decrypt(job) = parse_job("decrypt", fields).unwrap() else {
+            panic!("expected decrypt")
+        };
+        assert_eq!(job.output.as_deref(), Some("a.txt"));
+
+        assert!(parse_job("encrypt", serde_json::json!({"input": "a"})).is_err());
+        assert!(parse_job("shred", serde_json::json!({"input": "a"})).is_err());
+        assert!(parse_job("encrypt", serde_json::json!(["a"])).is_err());
+    }
+}
@@ -1,7 +1,39 @@
-use argon2::{Algorithm, Argon2, Params, Version};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use argon2::{Config, ThreadMode, Variant, Version};
+use hkdf::Hkdf;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::calibration;
+use crate::header::SALT_LEN;
+use crate::keyring;
+use crate::log;
+use crate::progress;
+
+/// HKDF label for the v3 payload (chunk) encryption key.
+pub const SUBKEY_PAYLOAD: &[u8] = b"gtkrypt v3 payload";
+
+/// HKDF label for the v3 encrypted metadata block key.
+pub const SUBKEY_METADATA: &[u8] = b"gtkrypt v3 metadata";
+
+/// HKDF label for the v3 header MAC key.
+pub const SUBKEY_HEADER_MAC: &[u8] = b"gtkrypt v3 header mac";
+
+/// HKDF label for the v3 key-check value key.
+pub const SUBKEY_KEY_CHECK: &[u8] = b"gtkrypt v3 key check";
+
+/// HKDF label for the v3 chunk index footer MAC key.
+pub const SUBKEY_CHUNK_INDEX: &[u8] = b"gtkrypt v3 chunk index";
+
+/// HKDF label for the v3 plaintext hash key.
+pub const SUBKEY_PLAINTEXT_HASH: &[u8] = b"gtkrypt v3 plaintext hash";
 
 /// Argon2id key derivation parameters.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct KdfParams {
     pub time_cost: u32,
     pub memory_cost_kib: u32,
@@ -21,26 +53,437 @@ impl Default for KdfParams {
 
 /// Derive a 32-byte key from a passphrase and salt using Argon2id.
 ///
-/// Returns a 32-byte key suitable for AES-256-GCM.
+/// Each lane is filled on its own thread, so wall time drops with the
+/// configured parallelism on machines with the cores for it.
+///
+/// Returns a 32-byte key suitable for AES-256-GCM. With `--cache-key`, a
+/// key derived by an earlier run is taken from the session keyring.
 pub fn derive_key(passphrase: &[u8], salt: &[u8], params: &KdfParams) -> Result<[u8; 32], String> {
-    let argon2_params = Params::new(
-        params.memory_cost_kib,
-        params.time_cost,
-        params.parallelism,
-        Some(32),
-    )
-    .map_err(|e| format!("Invalid Argon2 params: {}", e))?;
-
-    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params);
+    if let Some(key) = keyring::cached_key(passphrase, salt, params) {
+        log::debug(|| "Argon2id key taken from the session keyring".to_string());
+        return Ok(key);
+    }
+    let config = Config {
+        variant: Variant::Argon2id,
+        version: Version::Version13,
+        mem_cost: params.memory_cost_kib,
+        time_cost: params.time_cost,
+        lanes: params.parallelism,
+        thread_mode: ThreadMode::from_threads(params.parallelism),
+        hash_length: 32,
+        ..Config::default()
+    };
 
-    let mut key = [0u8; 32];
-    argon2
-        .hash_password_into(passphrase, salt, &mut key)
+    let _heartbeat = progress::heartbeat("kdf");
+    // Argon2 cannot report how far it has got, so the progress lines in
+    // between follow the clock against an estimate of the run time
+    let _ticks = progress::tick_progress(
+        "kdf",
+        u64::from(params.memory_cost_kib) * 1024 * u64::from(params.time_cost),
+        estimate_duration(params),
+    );
+    let started = Instant::now();
+    let hash = argon2::hash_raw(passphrase, salt, &config)
         .map_err(|e| format!("Argon2id key derivation failed: {}", e))?;
+    log::debug(|| {
+        format!(
+            "Argon2id with m={} KiB, t={}, p={} took {:.3}s",
+            params.memory_cost_kib,
+            params.time_cost,
+            params.parallelism,
+            started.elapsed().as_secs_f64()
+        )
+    });
 
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&hash);
+    keyring::cache_key(passphrase, salt, params, &key);
     Ok(key)
 }
 
+/// Memory used by the calibration run: large enough not to fit in cache,
+/// small enough to take only milliseconds.
+const CALIBRATION_KIB: u32 = 4096;
+
+/// How many KiB of Argon2id memory one thread fills per second on this
+/// machine, measured with a small run and kept in the state directory, so
+/// that later runs skip the measurement.
+fn kib_per_second() -> f64 {
+    static RATE: OnceLock<f64> = OnceLock::new();
+    *RATE.get_or_init(|| {
+        let rate = calibration::load().unwrap_or_else(measure_kib_per_second);
+        log::debug(|| format!("Argon2id fills {:.0} KiB a second per lane here", rate));
+        rate
+    })
+}
+
+fn measure_kib_per_second() -> f64 {
+    let config = Config {
+        variant: Variant::Argon2id,
+        version: Version::Version13,
+        mem_cost: CALIBRATION_KIB,
+        time_cost: 1,
+        lanes: 1,
+        hash_length: 32,
+        ..Config::default()
+    };
+    let started = Instant::now();
+    let _ = argon2::hash_raw(b"calibration", &[0u8; SALT_LEN], &config);
+    let rate = f64::from(CALIBRATION_KIB) / started.elapsed().as_secs_f64().max(1e-6);
+    calibration::save(rate);
+    rate
+}
+
+/// Expected run time of a derivation with `params` on this machine.
+pub fn estimate_duration(params: &KdfParams) -> Duration {
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get() as u32);
+    estimated_duration(params, kib_per_second(), cores)
+}
+
+/// The Argon2id time cost that makes a derivation with `memory_cost_kib`
+/// and `parallelism` take about `target` on this machine, going by the
+/// calibrated rate. Never below 1.
+pub fn time_cost_for(target: Duration, memory_cost_kib: u32, parallelism: u32) -> u32 {
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get() as u32);
+    let params = KdfParams {
+        time_cost: 1,
+        memory_cost_kib,
+        parallelism,
+    };
+    time_cost_within(target, &params, kib_per_second(), cores)
+}
+
+fn time_cost_within(target: Duration, params: &KdfParams, kib_per_second: f64, cores: u32) -> u32 {
+    let pass = estimated_duration(params, kib_per_second, cores).as_secs_f64();
+    let passes = target.as_secs_f64() / pass.max(1e-9);
+    passes.round().clamp(1.0, f64::from(u32::MAX)) as u32
+}
+
+/// Parse a `--target-time` such as `500ms`, `2s` or `2`, in seconds.
+pub fn parse_target_time(arg: &str) -> Result<Duration, String> {
+    let arg = arg.trim();
+    let (digits, scale) = match arg.strip_suffix("ms") {
+        Some(millis) => (millis, 1000.0),
+        None => (arg.strip_suffix('s').unwrap_or(arg), 1.0),
+    };
+    digits
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|value| value.is_finite() && *value > 0.0)
+        .and_then(|value| Duration::try_from_secs_f64(value / scale).ok())
+        .ok_or_else(|| format!("Invalid target time '{}': expected a time such as 500ms or 2s", arg))
+}
+
+/// Expected run time of a derivation with `params`, filling
+/// `kib_per_second` per thread with `cores` threads at hand.
+fn estimated_duration(params: &KdfParams, kib_per_second: f64, cores: u32) -> Duration {
+    let threads = params.parallelism.clamp(1, cores.max(1));
+    let kib = f64::from(params.memory_cost_kib) * f64::from(params.time_cost);
+    Duration::try_from_secs_f64(kib / (kib_per_second * f64::from(threads)))
+        .unwrap_or(Duration::MAX)
+}
+
+/// Upper bounds on the Argon2id parameters a container header may ask
+/// for, so that a crafted header cannot tie up the machine. `None` leaves
+/// a parameter uncapped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KdfLimits {
+    pub max_memory_kib: Option<u32>,
+    pub max_time_cost: Option<u32>,
+}
+
+impl KdfLimits {
+    /// Check `params` against the limits.
+    pub fn check(&self, params: &KdfParams) -> Result<(), String> {
+        if let Some(max) = self.max_memory_kib.filter(|&max| params.memory_cost_kib > max) {
+            return Err(format!(
+                "Argon2id memory cost {} KiB exceeds the limit of {} KiB",
+                params.memory_cost_kib, max
+            ));
+        }
+        if let Some(max) = self.max_time_cost.filter(|&max| params.time_cost > max) {
+            return Err(format!(
+                "Argon2id time cost {} exceeds the limit of {}",
+                params.time_cost, max
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Memory, in KiB, that can be allocated without pushing the machine into
+/// swap or the OOM killer: `MemAvailable`, lowered to the headroom under
+/// the cgroup limit when the process runs in a constrained container.
+/// `None` where this cannot be read.
+pub fn available_memory_kib() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let available = meminfo.lines().find_map(|line| {
+        line.strip_prefix("MemAvailable:")?
+            .trim()
+            .strip_suffix("kB")?
+            .trim()
+            .parse::<u64>()
+            .ok()
+    })?;
+
+    let read = |name: &str| std::fs::read_to_string(format!("/sys/fs/cgroup/{}", name)).ok();
+    // "max" when the cgroup has no limit, which fails to parse
+    let headroom = read("memory.max")
+        .and_then(|max| max.trim().parse::<u64>().ok())
+        .zip(read("memory.current").and_then(|cur| cur.trim().parse::<u64>().ok()))
+        .map(|(max, current)| max.saturating_sub(current) / 1024);
+    Some(headroom.map_or(available, |headroom| available.min(headroom)))
+}
+
+/// Check that Argon2id with `params` fits in the memory available now.
+/// Where the available memory is unknown, the check passes.
+pub fn check_memory(params: &KdfParams) -> Result<(), String> {
+    match available_memory_kib() {
+        Some(available) => check_memory_within(params, available),
+        None => Ok(()),
+    }
+}
+
+fn check_memory_within(params: &KdfParams, available_kib: u64) -> Result<(), String> {
+    if u64::from(params.memory_cost_kib) > available_kib {
+        return Err(format!(
+            "Argon2id needs {} KiB of memory but only {} KiB is available",
+            params.memory_cost_kib, available_kib
+        ));
+    }
+    Ok(())
+}
+
+/// `params` with the memory cost halved until it fits in the memory
+/// available now, for new containers. Emits a `kdf_memory_reduced` warning
+/// when it had to be lowered.
+pub fn fit_to_available_memory(params: KdfParams) -> KdfParams {
+    let Some(available) = available_memory_kib() else {
+        return params;
+    };
+    let fitted = fit_memory_within(params.clone(), available);
+    if fitted != params {
+        progress::emit_warning(
+            "kdf_memory_reduced",
+            &format!(
+                "Only {} KiB of memory is available; Argon2id memory lowered from {} KiB to \
+                 {} KiB, which makes the passphrase cheaper to attack",
+                available, params.memory_cost_kib, fitted.memory_cost_kib
+            ),
+        );
+    }
+    fitted
+}
+
+fn fit_memory_within(mut params: KdfParams, available_kib: u64) -> KdfParams {
+    // Argon2 needs at least 8 KiB per lane
+    let floor = 8 * params.parallelism.max(1);
+    while u64::from(params.memory_cost_kib) > available_kib && params.memory_cost_kib / 2 >= floor
+    {
+        params.memory_cost_kib /= 2;
+    }
+    params
+}
+
+/// Argon2id results kept for a batch of files under one passphrase, so the
+/// passphrase is stretched once per salt and parameter set rather than once
+/// per file. Clones share the same cache.
+#[derive(Clone, Default)]
+pub struct KeyCache {
+    inner: Arc<Mutex<KeyCacheInner>>,
+}
+
+/// One cached key; its own lock lets different salts derive in parallel.
+type KeySlot = Arc<Mutex<Option<[u8; 32]>>>;
+
+#[derive(Default)]
+struct KeyCacheInner {
+    salt: Option<[u8; SALT_LEN]>,
+    keys: HashMap<([u8; SALT_LEN], KdfParams), KeySlot>,
+    /// Only serves the keys it was given, never running Argon2id.
+    sealed: bool,
+}
+
+impl KeyCache {
+    /// A cache holding only `key`, derived elsewhere for `salt` and
+    /// `params`. New containers take `salt`, and any other salt or
+    /// parameter set fails rather than being derived.
+    pub fn with_key(salt: [u8; SALT_LEN], params: KdfParams, key: [u8; 32]) -> Self {
+        let inner = KeyCacheInner {
+            salt: Some(salt),
+            keys: HashMap::from([((salt, params), Arc::new(Mutex::new(Some(key))))]),
+            sealed: true,
+        };
+        KeyCache {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// Salt for new containers, drawn at random on first use. Every
+    /// container written through the same cache shares it, and with it the
+    /// key.
+    pub fn salt(&self) -> [u8; SALT_LEN] {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        *inner.salt.get_or_insert_with(|| {
+            let mut salt = [0u8; SALT_LEN];
+            rand::thread_rng().fill_bytes(&mut salt);
+            salt
+        })
+    }
+
+    /// [`derive_key`], unless this salt and parameter set were seen before.
+    pub fn derive(
+        &self,
+        passphrase: &[u8],
+        salt: &[u8; SALT_LEN],
+        params: &KdfParams,
+    ) -> Result<[u8; 32], String> {
+        let slot = {
+            let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+            if inner.sealed && !inner.keys.contains_key(&(*salt, params.clone())) {
+                return Err("no key was given for this salt and parameter set".to_string());
+            }
+            inner.keys.entry((*salt, params.clone())).or_default().clone()
+        };
+        // Held across the derivation so concurrent users of a salt wait
+        // for one run
+        let mut key = slot.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(key) = *key {
+            return Ok(key);
+        }
+        let derived = derive_key(passphrase, salt, params)?;
+        *key = Some(derived);
+        Ok(derived)
+    }
+}
+
+/// A [`KeyCache`] per passphrase, for a `serve` process that outlives many
+/// operations. A cache only ever serves the one passphrase, so each is
+/// found by the SHA-256 of the key material rather than the material itself.
+#[derive(Default)]
+pub struct KeyCaches {
+    caches: Mutex<HashMap<[u8; 32], KeyCache>>,
+}
+
+impl KeyCaches {
+    /// The cache for `passphrase`, empty the first time.
+    pub fn get(&self, passphrase: &[u8]) -> KeyCache {
+        use sha2::Digest;
+
+        let digest: [u8; 32] = Sha256::digest(passphrase).into();
+        let mut caches = self.caches.lock().unwrap_or_else(|e| e.into_inner());
+        caches.entry(digest).or_default().clone()
+    }
+}
+
+/// The params of a `derive_key` request besides the passphrase: the salt
+/// (hex) and parameters of an existing container, or no salt for a new one.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeriveRequest {
+    pub salt: Option<String>,
+    pub time_cost: u32,
+    pub memory_cost: u32,
+    pub parallelism: u32,
+}
+
+impl Default for DeriveRequest {
+    fn default() -> Self {
+        let params = KdfParams::default();
+        DeriveRequest {
+            salt: None,
+            time_cost: params.time_cost,
+            memory_cost: params.memory_cost_kib,
+            parallelism: params.parallelism,
+        }
+    }
+}
+
+/// A container key with what it was derived under, handed from the process
+/// that holds the passphrase to one that must not see it. Key and salt are
+/// hex.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DerivedKey {
+    pub key: String,
+    pub salt: String,
+    pub time_cost: u32,
+    pub memory_cost: u32,
+    pub parallelism: u32,
+}
+
+impl DerivedKey {
+    /// Derive the key for the salt of `request`, or for a new random salt
+    /// with the memory cost fitted to the memory available, as for a new
+    /// container.
+    pub fn derive(passphrase: &[u8], request: DeriveRequest) -> Result<Self, String> {
+        let params = KdfParams {
+            time_cost: request.time_cost,
+            memory_cost_kib: request.memory_cost,
+            parallelism: request.parallelism,
+        };
+        let salt = request.salt.map(|salt| from_hex(&salt).ok_or("salt is not 16 bytes of hex"));
+        let (salt, params) = match salt.transpose()? {
+            Some(salt) => {
+                check_memory(&params)?;
+                (salt, params)
+            }
+            None => (KeyCache::default().salt(), fit_to_available_memory(params)),
+        };
+        let key = derive_key(passphrase, &salt, &params)?;
+        Ok(DerivedKey {
+            key: to_hex(&key),
+            salt: to_hex(&salt),
+            time_cost: params.time_cost,
+            memory_cost: params.memory_cost_kib,
+            parallelism: params.parallelism,
+        })
+    }
+
+    pub fn params(&self) -> KdfParams {
+        KdfParams {
+            time_cost: self.time_cost,
+            memory_cost_kib: self.memory_cost,
+            parallelism: self.parallelism,
+        }
+    }
+
+    /// A cache serving only this key; see [`KeyCache::with_key`].
+    pub fn key_cache(&self) -> Result<KeyCache, String> {
+        let key = from_hex(&self.key).ok_or("key is not 32 bytes of hex")?;
+        let salt = from_hex(&self.salt).ok_or("salt is not 16 bytes of hex")?;
+        Ok(KeyCache::with_key(salt, self.params(), key))
+    }
+}
+
+/// Lowercase hex of `bytes`.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The `N` bytes spelled in hex by `text`, if it spells exactly that many.
+pub fn from_hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != 2 * N || !text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (byte, pair) in bytes.iter_mut().zip(text.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Derive a purpose-specific 32-byte subkey from the Argon2id output using
+/// HKDF-SHA256, so that independent uses of the key never share key material.
+pub fn derive_subkey(master_key: &[u8; 32], label: &[u8]) -> [u8; 32] {
+    let hk = Hkdf::<Sha256>::new(None, master_key);
+    let mut subkey = [0u8; 32];
+    hk.expand(label, &mut subkey)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    subkey
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -97,6 +540,49 @@ mod tests {
         assert_eq!(key1, key2);
     }
 
+    #[test]
+    fn test_derive_key_known_answers() {
+        // Outputs of the single-threaded implementation used before, which
+        // existing containers were written with
+        let salt = [5u8; 16];
+        let params = KdfParams {
+            time_cost: 2,
+            memory_cost_kib: 4096,
+            parallelism: 4,
+        };
+        assert_eq!(
+            derive_key(b"parallel lanes", &salt, &params).unwrap(),
+            [
+                0x67, 0xe8, 0x2b, 0xa8, 0x21, 0xa8, 0xd9, 0xf9, 0x34, 0x2c, 0x30, 0xdb, 0x55, 0x07,
+                0x31, 0xa2, 0xb4, 0xe4, 0x2a, 0xfd, 0x9e, 0x76, 0x66, 0x23, 0x69, 0xd9, 0x47, 0xe2,
+                0x00, 0x46, 0xa5, 0xca,
+            ]
+        );
+        let params = KdfParams {
+            time_cost: 1,
+            memory_cost_kib: 1024,
+            parallelism: 1,
+        };
+        assert_eq!(
+            derive_key(b"parallel lanes", &salt, &params).unwrap(),
+            [
+                0xb6, 0x61, 0x41, 0x91, 0x40, 0xbd, 0x45, 0x9f, 0x1a, 0xd1, 0x8e, 0xde, 0x78, 0xef,
+                0x24, 0xfc, 0x4f, 0xab, 0xfb, 0xcc, 0xaa, 0x71, 0x48, 0x46, 0x18, 0xfc, 0x70, 0x93,
+                0x02, 0x25, 0x50, 0x87,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_params_are_rejected() {
+        let params = KdfParams {
+            time_cost: 1,
+            memory_cost_kib: 1024,
+            parallelism: 0,
+        };
+        assert!(derive_key(b"pw", &[0u8; 16], &params).is_err());
+    }
+
     #[test]
     fn test_default_params() {
         let params = KdfParams::default();
@@ -104,4 +590,180 @@ mod tests {
         assert_eq!(params.memory_cost_kib, 65536);
         assert_eq!(params.parallelism, 4);
     }
+
+    #[test]
+    fn test_memory_checked_against_available() {
+        let params = KdfParams::default();
+        assert!(check_memory_within(&params, 65536).is_ok());
+        let err = check_memory_within(&params, 65535).unwrap_err();
+        assert!(err.contains("65536 KiB"), "{}", err);
+        assert!(available_memory_kib().is_none_or(|kib| kib > 0));
+    }
+
+    #[test]
+    fn test_estimated_duration_scales_with_cost_and_threads() {
+        let params = KdfParams {
+            time_cost: 3,
+            memory_cost_kib: 65536,
+            parallelism: 4,
+        };
+        let secs = |params: &KdfParams, cores| {
+            estimated_duration(params, 65536.0, cores).as_secs_f64()
+        };
+        assert_eq!(secs(&params, 8), 0.75);
+        // Lanes beyond the cores at hand share them
+        assert_eq!(secs(&params, 2), 1.5);
+        assert_eq!(secs(&params, 0), 3.0);
+        let params = KdfParams {
+            time_cost: 1,
+            ..params
+        };
+        assert_eq!(secs(&params, 8), 0.25);
+        assert!(kib_per_second() > 0.0);
+    }
+
+    #[test]
+    fn test_time_cost_for_target() {
+        let params = KdfParams {
+            time_cost: 1,
+            memory_cost_kib: 65536,
+            parallelism: 4,
+        };
+        // One pass takes 0.25s at this rate on enough cores
+        let time_cost = |target| time_cost_within(target, &params, 65536.0, 8);
+        assert_eq!(time_cost(Duration::from_secs(1)), 4);
+        assert_eq!(time_cost(Duration::from_millis(900)), 4);
+        assert_eq!(time_cost(Duration::from_millis(10)), 1);
+        assert_eq!(time_cost_within(Duration::from_secs(1), &params, 65536.0, 2), 2);
+
+        assert_eq!(parse_target_time("2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_target_time("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_target_time("1.5"), Ok(Duration::from_millis(1500)));
+        assert!(parse_target_time("0s").is_err());
+        assert!(parse_target_time("fast").is_err());
+    }
+
+    #[test]
+    fn test_kdf_limits() {
+        let params = KdfParams::default();
+        assert!(KdfLimits::default().check(&params).is_ok());
+        let limits = KdfLimits {
+            max_memory_kib: Some(65536),
+            max_time_cost: Some(3),
+        };
+        assert!(limits.check(&params).is_ok());
+        let err = KdfLimits {
+            max_memory_kib: Some(65535),
+            ..limits
+        }
+        .check(&params)
+        .unwrap_err();
+        assert!(err.contains("memory"), "{}", err);
+        let err = KdfLimits {
+            max_time_cost: Some(2),
+            ..limits
+        }
+        .check(&params)
+        .unwrap_err();
+        assert!(err.contains("time cost"), "{}", err);
+    }
+
+    #[test]
+    fn test_memory_fitted_by_halving() {
+        let params = KdfParams {
+            time_cost: 3,
+            memory_cost_kib: 1 << 20,
+            parallelism: 4,
+        };
+        assert_eq!(fit_memory_within(params.clone(), 1 << 20), params);
+        let fitted = fit_memory_within(params.clone(), 600 << 10);
+        assert_eq!(fitted.memory_cost_kib, 512 << 10);
+        assert_eq!(fitted.time_cost, 3);
+        assert_eq!(fitted.parallelism, 4);
+        // Never below the Argon2 minimum, even when that does not fit
+        assert_eq!(fit_memory_within(params, 1).memory_cost_kib, 32);
+    }
+
+    #[test]
+    fn test_key_cache_derives_once_per_salt() {
+        let params = KdfParams {
+            time_cost: 1,
+            memory_cost_kib: 1024,
+            parallelism: 1,
+        };
+        let cache = KeyCache::default();
+        let salt = cache.salt();
+        assert_eq!(cache.clone().salt(), salt);
+
+        let key = cache.derive(b"batch", &salt, &params).unwrap();
+        assert_eq!(key, derive_key(b"batch", &salt, &params).unwrap());
+        // A hit never runs Argon2id, which the passphrase shows: the cache
+        // serves a single one
+        assert_eq!(cache.clone().derive(b"other", &salt, &params).unwrap(), key);
+        let other_salt = [9u8; SALT_LEN];
+        let other = cache.derive(b"batch", &other_salt, &params).unwrap();
+        assert_eq!(other, derive_key(b"batch", &other_salt, &params).unwrap());
+        assert_ne!(KeyCache::default().salt(), salt);
+    }
+
+    #[test]
+    fn test_key_caches_are_per_passphrase() {
+        let caches = KeyCaches::default();
+        let salt = caches.get(b"first").salt();
+        assert_eq!(caches.get(b"first").salt(), salt);
+        assert_ne!(caches.get(b"second").salt(), salt);
+    }
+
+    #[test]
+    fn test_derived_key_serves_without_the_passphrase() {
+        let params = KdfParams {
+            time_cost: 1,
+            memory_cost_kib: 1024,
+            parallelism: 1,
+        };
+        let salt = [7u8; SALT_LEN];
+        let request = DeriveRequest {
+            salt: Some("07".repeat(SALT_LEN)),
+            time_cost: 1,
+            memory_cost: 1024,
+            parallelism: 1,
+        };
+        let derived = DerivedKey::derive(b"elsewhere", request).unwrap();
+        assert_eq!(derived.salt, "07".repeat(SALT_LEN));
+
+        let cache = derived.key_cache().unwrap();
+        assert_eq!(cache.salt(), salt);
+        let key = derive_key(b"elsewhere", &salt, &params).unwrap();
+        assert_eq!(cache.derive(b"", &salt, &params).unwrap(), key);
+        // Anything else is refused rather than derived from no passphrase
+        assert!(cache.derive(b"", &[8u8; SALT_LEN], &params).is_err());
+        let other = KdfParams { time_cost: 2, ..params };
+        assert!(cache.derive(b"", &salt, &other).is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0x00, 0xab, 0x7f]), "00ab7f");
+        assert_eq!(from_hex::<3>("00AB7f"), Some([0x00, 0xab, 0x7f]));
+        assert_eq!(from_hex::<3>("00ab7"), None);
+        assert_eq!(from_hex::<2>("00ab7f"), None);
+        assert_eq!(from_hex::<2>("zzzz"), None);
+        assert_eq!(from_hex::<2>("+1+1"), None);
+        assert_eq!(from_hex::<1>("é"), None);
+    }
+
+    #[test]
+    fn test_subkeys_are_distinct() {
+        let master = [7u8; 32];
+        let payload = derive_subkey(&master, SUBKEY_PAYLOAD);
+        let metadata = derive_subkey(&master, SUBKEY_METADATA);
+        let header_mac = derive_subkey(&master, SUBKEY_HEADER_MAC);
+        assert_ne!(payload, metadata);
+        assert_ne!(payload, header_mac);
+        assert_ne!(metadata, header_mac);
+        assert_ne!(header_mac, derive_subkey(&master, SUBKEY_CHUNK_INDEX));
+        assert_ne!(payload, derive_subkey(&master, SUBKEY_PLAINTEXT_HASH));
+        assert_ne!(payload, master);
+        assert_eq!(payload, derive_subkey(&master, SUBKEY_PAYLOAD));
+    }
 }
@@ -0,0 +1,209 @@
+use std::borrow::Cow;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use rand::RngCore;
+
+/// Start of a keyfile whose secret is wrapped with Windows DPAPI, for the
+/// user account and machine that created it. The DPAPI blob follows.
+pub const DPAPI_MAGIC: &[u8] = b"GTKRYPT-DPAPI-KEYFILE\n";
+
+/// Random bytes in a new keyfile.
+pub const SECRET_LEN: usize = 64;
+
+/// The secret in the keyfile `contents`: the contents as they are, or
+/// unwrapped with DPAPI for a keyfile that starts with [`DPAPI_MAGIC`].
+pub fn secret(contents: &[u8]) -> Result<Cow<'_, [u8]>, String> {
+    match contents.strip_prefix(DPAPI_MAGIC) {
+        Some(blob) => dpapi::unprotect(blob).map(Cow::Owned).map_err(|e| {
+            format!(
+                "Cannot unwrap the DPAPI keyfile, which only works for the Windows \
+                 account that created it: {}",
+                e
+            )
+        }),
+        None => Ok(Cow::Borrowed(contents)),
+    }
+}
+
+/// Create a keyfile of [`SECRET_LEN`] random bytes at `path`, which must
+/// not exist, readable only by its owner. With `dpapi`, the secret is
+/// wrapped for the current Windows user, so that a copy of the keyfile is
+/// useless anywhere else.
+pub fn create(path: &Path, dpapi: bool) -> io::Result<()> {
+    let mut secret = [0u8; SECRET_LEN];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let contents = if dpapi {
+        [DPAPI_MAGIC, &dpapi::protect(&secret)?[..]].concat()
+    } else {
+        secret.to_vec()
+    };
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    if let Err(e) = file.write_all(&contents).and_then(|_| file.sync_all()) {
+        drop(file);
+        let _ = fs::remove_file(path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+mod dpapi {
+    use std::io;
+
+    use windows_sys::Win32::Foundation::{LocalFree, HLOCAL};
+    use windows_sys::Win32::Security::Cryptography::{
+        CryptProtectData, CryptUnprotectData, CRYPTPROTECT_UI_FORBIDDEN, CRYPT_INTEGER_BLOB,
+    };
+
+    /// Mixed into every wrap, so that only gtkrypt unwraps its keyfiles
+    /// without further ado.
+    const ENTROPY: &[u8] = b"gtkrypt keyfile";
+
+    fn blob(data: &[u8]) -> CRYPT_INTEGER_BLOB {
+        CRYPT_INTEGER_BLOB {
+            cbData: data.len() as u32,
+            pbData: data.as_ptr() as *mut u8,
+        }
+    }
+
+    /// Copy out a blob that DPAPI allocated, and free it.
+    ///
+    /// # Safety
+    ///
+    /// `out` must have been filled in by a successful DPAPI call.
+    unsafe fn take(out: CRYPT_INTEGER_BLOB) -> Vec<u8> {
+        let data = std::slice::from_raw_parts(out.pbData, out.cbData as usize).to_vec();
+        LocalFree(out.pbData as HLOCAL);
+        data
+    }
+
+    pub fn protect(data: &[u8]) -> io::Result<Vec<u8>> {
+        let (input, entropy) = (blob(data), blob(ENTROPY));
+        let mut out = CRYPT_INTEGER_BLOB {
+            cbData: 0,
+            pbData: std::ptr::null_mut(),
+        };
+        // SAFETY: the input blobs point into live slices DPAPI only reads,
+        // and `out` is filled in on success
+        let wrapped = unsafe {
+            CryptProtectData(
+                &input,
+                std::ptr::null(),
+                &entropy,
+                std::ptr::null(),
+                std::ptr::null(),
+                CRYPTPROTECT_UI_FORBIDDEN,
+                &mut out,
+            )
+        };
+        if wrapped == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the call succeeded
+        Ok(unsafe { take(out) })
+    }
+
+    pub fn unprotect(blob_data: &[u8]) -> io::Result<Vec<u8>> {
+        let (input, entropy) = (blob(blob_data), blob(ENTROPY));
+        let mut out = CRYPT_INTEGER_BLOB {
+            cbData: 0,
+            pbData: std::ptr::null_mut(),
+        };
+        // SAFETY: as for `protect`
+        let unwrapped = unsafe {
+            CryptUnprotectData(
+                &input,
+                std::ptr::null_mut(),
+                &entropy,
+                std::ptr::null(),
+                std::ptr::null(),
+                CRYPTPROTECT_UI_FORBIDDEN,
+                &mut out,
+            )
+        };
+        if unwrapped == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the call succeeded
+        Ok(unsafe { take(out) })
+    }
+}
+
+#[cfg(not(windows))]
+mod dpapi {
+    use std::io;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, "DPAPI is only available on Windows")
+    }
+
+    pub fn protect(_data: &[u8]) -> io::Result<Vec<u8>> {
+        Err(unsupported())
+    }
+
+    pub fn unprotect(_blob: &[u8]) -> io::Result<Vec<u8>> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_keyfile_is_its_own_secret() {
+        assert_eq!(&*secret(b"any bytes at all").unwrap(), b"any bytes at all");
+    }
+
+    #[test]
+    fn test_create_keyfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        create(&path, false).unwrap();
+        let contents = fs::read(&path).unwrap();
+        assert_eq!(contents.len(), SECRET_LEN);
+        assert!(!contents.starts_with(DPAPI_MAGIC));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        // Never over an existing file
+        let err = create(&path, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&path).unwrap(), contents);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_dpapi_keyfile_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        create(&path, true).unwrap();
+        let contents = fs::read(&path).unwrap();
+        assert!(contents.starts_with(DPAPI_MAGIC));
+        assert_eq!(secret(&contents).unwrap().len(), SECRET_LEN);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_dpapi_is_windows_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        assert_eq!(create(&path, true).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        assert!(!path.exists());
+        let wrapped = [DPAPI_MAGIC, &b"blob"[..]].concat();
+        assert!(secret(&wrapped).unwrap_err().contains("Windows account"));
+    }
+}
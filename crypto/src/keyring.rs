@@ -0,0 +1,322 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Once, OnceLock};
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use crate::kdf::KdfParams;
+use crate::progress;
+
+/// How long passphrases and keys stay in the session keyring, set with
+/// `--cache-key`. Unset, the keyring is left alone.
+static LIFETIME: OnceLock<Duration> = OnceLock::new();
+
+/// Set once the passphrase of this run came from the keyring.
+static PASSPHRASE_FROM_CACHE: AtomicBool = AtomicBool::new(false);
+
+/// Set once the first passphrase of the run was served or stored; any
+/// further one, as the new passphrase of `re-encrypt`, is not cached.
+static PASSPHRASE_DONE: AtomicBool = AtomicBool::new(false);
+
+/// Name of the cached passphrase in the keyring.
+const PASSPHRASE: &str = "gtkrypt:passphrase";
+
+/// Keep passphrases and derived keys in the session keyring for `lifetime`
+/// from when they are first stored, and take them from there.
+pub fn set_lifetime(lifetime: Duration) {
+    let _ = LIFETIME.set(lifetime);
+}
+
+/// A cache hit or a newly stored passphrase, emitted as a JSON line on
+/// stdout, so that a frontend knows whether to ask for the passphrase.
+#[derive(Debug, serde::Serialize)]
+pub struct KeyCacheEvent {
+    /// "hit" when the passphrase came from the keyring, "stored" when it
+    /// was put there.
+    pub key_cache: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_in_secs: Option<u64>,
+}
+
+/// The passphrase cached by an earlier run, if `--cache-key` is set and
+/// it has not expired. Stdin is then left for the control messages.
+pub fn cached_passphrase() -> Option<Vec<u8>> {
+    LIFETIME.get()?;
+    if PASSPHRASE_DONE.load(Ordering::Relaxed) {
+        return None;
+    }
+    let passphrase = find(PASSPHRASE)?;
+    PASSPHRASE_DONE.store(true, Ordering::Relaxed);
+    PASSPHRASE_FROM_CACHE.store(true, Ordering::Relaxed);
+    progress::emit_event(&KeyCacheEvent {
+        key_cache: "hit",
+        expires_in_secs: None,
+    });
+    Some(passphrase)
+}
+
+/// Cache `passphrase` for later runs, if `--cache-key` is set.
+pub fn cache_passphrase(passphrase: &[u8]) {
+    let Some(&lifetime) = LIFETIME.get() else {
+        return;
+    };
+    if PASSPHRASE_DONE.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    if store(PASSPHRASE, passphrase, lifetime) {
+        progress::emit_event(&KeyCacheEvent {
+            key_cache: "stored",
+            expires_in_secs: Some(lifetime.as_secs()),
+        });
+    }
+}
+
+/// Drop the cached passphrase if it is the one this run used, as it was
+/// found to be wrong; the next run asks for it again.
+pub fn forget_passphrase() {
+    if PASSPHRASE_FROM_CACHE.swap(false, Ordering::Relaxed) {
+        forget(PASSPHRASE);
+    }
+}
+
+/// The key that `derive_key` gave for `passphrase`, `salt` and `params` in
+/// an earlier run, if `--cache-key` is set and it has not expired.
+pub fn cached_key(passphrase: &[u8], salt: &[u8], params: &KdfParams) -> Option<[u8; 32]> {
+    LIFETIME.get()?;
+    find(&key_name(passphrase, salt, params))?.try_into().ok()
+}
+
+/// Cache `key`, derived from `passphrase`, `salt` and `params`, for later
+/// runs, if `--cache-key` is set.
+pub fn cache_key(passphrase: &[u8], salt: &[u8], params: &KdfParams, key: &[u8; 32]) {
+    if let Some(&lifetime) = LIFETIME.get() {
+        store(&key_name(passphrase, salt, params), key, lifetime);
+    }
+}
+
+/// Keys are named by a hash of what they were derived from, so that only
+/// the same passphrase, salt and parameters find them.
+fn key_name(passphrase: &[u8], salt: &[u8], params: &KdfParams) -> String {
+    let mut hash = Sha256::new();
+    for part in [passphrase, salt] {
+        hash.update((part.len() as u64).to_le_bytes());
+        hash.update(part);
+    }
+    hash.update(params.memory_cost_kib.to_le_bytes());
+    hash.update(params.time_cost.to_le_bytes());
+    hash.update(params.parallelism.to_le_bytes());
+    format!("gtkrypt:key:{}", crate::kdf::to_hex(&hash.finalize()))
+}
+
+fn find(name: &str) -> Option<Vec<u8>> {
+    sys::find(name).unwrap_or_else(|e| {
+        warn_unavailable(&e);
+        None
+    })
+}
+
+/// Store `payload` as `name`, reporting whether it was.
+fn store(name: &str, payload: &[u8], lifetime: Duration) -> bool {
+    sys::store(name, payload, lifetime).map_err(|e| warn_unavailable(&e)).is_ok()
+}
+
+fn forget(name: &str) {
+    if let Err(e) = sys::forget(name) {
+        warn_unavailable(&e);
+    }
+}
+
+/// Warn, once per run, that the keyring could not be used; the run goes
+/// on without it.
+fn warn_unavailable(e: &io::Error) {
+    static WARNED: Once = Once::new();
+    WARNED.call_once(|| {
+        progress::emit_warning(
+            "key_cache_unavailable",
+            &format!("The session keyring cannot be used, so nothing is cached: {}", e),
+        );
+    });
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::ffi::CString;
+    use std::io;
+    use std::time::Duration;
+
+    const KEY_SPEC_SESSION_KEYRING: libc::c_long = -3;
+    const KEYCTL_SETPERM: libc::c_long = 5;
+    const KEYCTL_SEARCH: libc::c_long = 10;
+    const KEYCTL_READ: libc::c_long = 11;
+    const KEYCTL_SET_TIMEOUT: libc::c_long = 15;
+    const KEYCTL_INVALIDATE: libc::c_long = 21;
+    /// Every right for processes that possess the key, through the session
+    /// keyring, and none for others, not even to see its name.
+    const KEY_POS_ALL: libc::c_long = 0x3f00_0000;
+
+    fn c_string(s: &str) -> io::Result<CString> {
+        CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn check(result: libc::c_long) -> io::Result<libc::c_long> {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result)
+        }
+    }
+
+    /// The serial of the key `name` in the session keyring, if there is one.
+    fn search(name: &str) -> io::Result<Option<libc::c_long>> {
+        let name = c_string(name)?;
+        // SAFETY: the strings are NUL-terminated and outlive the call
+        let serial = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                KEYCTL_SEARCH,
+                KEY_SPEC_SESSION_KEYRING,
+                c"user".as_ptr(),
+                name.as_ptr(),
+                0,
+            )
+        };
+        match check(serial) {
+            Ok(serial) => Ok(Some(serial)),
+            // Missing, expired or revoked
+            Err(e) if matches!(
+                e.raw_os_error(),
+                Some(libc::ENOKEY | libc::EKEYEXPIRED | libc::EKEYREVOKED)
+            ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn find(name: &str) -> io::Result<Option<Vec<u8>>> {
+        let Some(serial) = search(name)? else {
+            return Ok(None);
+        };
+        let mut payload = Vec::new();
+        loop {
+            // SAFETY: the buffer has room for `payload.len()` bytes
+            let len = unsafe {
+                libc::syscall(
+                    libc::SYS_keyctl,
+                    KEYCTL_READ,
+                    serial,
+                    payload.as_mut_ptr(),
+                    payload.len(),
+                )
+            };
+            let len = match check(len) {
+                Ok(len) => len as usize,
+                Err(e) if e.raw_os_error() == Some(libc::EKEYEXPIRED) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            // Read again if the payload did not fit
+            if len <= payload.len() {
+                payload.truncate(len);
+                return Ok(Some(payload));
+            }
+            payload.resize(len, 0);
+        }
+    }
+
+    pub fn store(name: &str, payload: &[u8], lifetime: Duration) -> io::Result<()> {
+        let name = c_string(name)?;
+        // SAFETY: the strings are NUL-terminated and the payload is
+        // `payload.len()` bytes, all outliving the call
+        let serial = check(unsafe {
+            libc::syscall(
+                libc::SYS_add_key,
+                c"user".as_ptr(),
+                name.as_ptr(),
+                payload.as_ptr(),
+                payload.len(),
+                KEY_SPEC_SESSION_KEYRING,
+            )
+        })?;
+        // SAFETY: plain integer arguments
+        let set_up = check(unsafe {
+            libc::syscall(libc::SYS_keyctl, KEYCTL_SETPERM, serial, KEY_POS_ALL)
+        })
+        .and_then(|_| {
+            // SAFETY: as above; a timeout of 0 would keep the key for good
+            check(unsafe {
+                let secs = lifetime.as_secs().max(1) as libc::c_long;
+                libc::syscall(libc::SYS_keyctl, KEYCTL_SET_TIMEOUT, serial, secs)
+            })
+        });
+        if let Err(e) = set_up {
+            // Not left behind without its limits
+            // SAFETY: as above
+            unsafe { libc::syscall(libc::SYS_keyctl, KEYCTL_INVALIDATE, serial) };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn forget(name: &str) -> io::Result<()> {
+        if let Some(serial) = search(name)? {
+            // SAFETY: plain integer arguments
+            check(unsafe { libc::syscall(libc::SYS_keyctl, KEYCTL_INVALIDATE, serial) })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io;
+    use std::time::Duration;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, "the kernel keyring is Linux only")
+    }
+
+    pub fn find(_name: &str) -> io::Result<Option<Vec<u8>>> {
+        Err(unsupported())
+    }
+
+    pub fn store(_name: &str, _payload: &[u8], _lifetime: Duration) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn forget(_name: &str) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyring_round_trip() {
+        let name = format!("gtkrypt:test:{}", std::process::id());
+        // Containers often filter out the keyring system calls
+        if sys::store(&name, b"cached bytes", Duration::from_secs(60)).is_err() {
+            return;
+        }
+        assert_eq!(sys::find(&name).unwrap().as_deref(), Some(&b"cached bytes"[..]));
+        sys::forget(&name).unwrap();
+        assert_eq!(sys::find(&name).unwrap(), None);
+    }
+
+    #[test]
+    fn test_key_names_cover_every_input() {
+        let params = KdfParams::default();
+        let name = key_name(b"passphrase", b"salt", &params);
+        assert!(name.starts_with("gtkrypt:key:"));
+        assert_ne!(name, key_name(b"passphrasesalt", b"", &params));
+        assert_ne!(name, key_name(b"passphrase", b"pepper", &params));
+        let params = KdfParams {
+            time_cost: params.time_cost + 1,
+            ..params
+        };
+        assert_ne!(name, key_name(b"passphrase", b"salt", &params));
+    }
+}
@@ -0,0 +1,122 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cancel;
+
+/// An exclusive advisory lock on an output path, taken through a hidden
+/// lock file next to it so that it works before the output exists. Two
+/// runs writing the same output would otherwise race each other's renames.
+/// The lock is released, and the lock file removed, on drop.
+pub struct OutputLock {
+    path: PathBuf,
+    _file: File,
+}
+
+/// The lock file guarding `output`: `.<name>.gtkrypt-lock` beside it.
+pub fn lock_path(output: &Path) -> PathBuf {
+    let name = output.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    output.with_file_name(format!(".{}.gtkrypt-lock", name))
+}
+
+/// Take an exclusive lock on `file` without waiting. `false` when another
+/// open file holds it.
+#[cfg(unix)]
+pub fn try_lock(file: &File) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: flock on a descriptor we own
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            return Ok(false);
+        }
+        return Err(err);
+    }
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+pub fn try_lock(_file: &File) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Lock `output` without waiting. Fails with `WouldBlock` while another
+/// process holds the lock.
+pub fn lock_output(output: &Path) -> io::Result<OutputLock> {
+    cancel::track(|| take_lock(output), |lock| &lock.path)
+}
+
+#[cfg(unix)]
+fn take_lock(output: &Path) -> io::Result<OutputLock> {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = lock_path(output);
+    loop {
+        let file = File::options().create(true).truncate(false).write(true).open(&path)?;
+        if !try_lock(&file)? {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} is in use by another gtkrypt process", output.display()),
+            ));
+        }
+        // The previous holder may have removed the file between our open
+        // and flock, leaving us a lock nobody else can see; start over
+        let locked = file.metadata()?;
+        match std::fs::metadata(&path) {
+            Ok(current) if current.dev() == locked.dev() && current.ino() == locked.ino() => {
+                return Ok(OutputLock { path, _file: file });
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn take_lock(output: &Path) -> io::Result<OutputLock> {
+    let path = lock_path(output);
+    let file = File::options().create(true).truncate(false).write(true).open(&path)?;
+    Ok(OutputLock { path, _file: file })
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        // Removed while still held, so a waiting process notices the swap
+        let _ = std::fs::remove_file(&self.path);
+        cancel::untrack(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_lock_is_refused_until_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("report.pdf.gtkrypt");
+
+        let lock = lock_output(&output).unwrap();
+        assert!(lock_path(&output).exists());
+        #[cfg(unix)]
+        {
+            let err = lock_output(&output).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        }
+
+        drop(lock);
+        assert!(!lock_path(&output).exists());
+        let again = lock_output(&output).unwrap();
+        drop(again);
+    }
+
+    #[test]
+    fn test_lock_path_is_hidden_sibling() {
+        assert_eq!(
+            lock_path(Path::new("/out/a.txt")),
+            PathBuf::from("/out/.a.txt.gtkrypt-lock")
+        );
+    }
+}
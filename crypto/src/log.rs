@@ -0,0 +1,316 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::progress;
+
+/// Socket journald takes entries on in its native protocol.
+#[cfg(unix)]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Socket the syslog daemon listens on.
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// Name the entries in journald and syslog are filed under.
+const IDENTIFIER: &str = "gtkrypt";
+
+/// How much is logged: 0 nothing, 1 `debug` (`-v`), 2 `trace` as well
+/// (`-vv`).
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+
+/// Where log lines go instead of stderr.
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+/// When logging started, which the lines are timed from.
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+/// Where the log goes, set with `--log-target`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogTarget {
+    /// Lines on stderr, or in the `--log-file`.
+    #[default]
+    Stderr,
+    /// Entries with structured fields in the systemd journal.
+    Journald,
+    /// Lines for the syslog daemon, with the fields as `key=value`.
+    Syslog,
+}
+
+/// Parse a `--log-target` value.
+pub fn parse_log_target(arg: &str) -> Result<LogTarget, String> {
+    match arg {
+        "stderr" => Ok(LogTarget::Stderr),
+        "journald" => Ok(LogTarget::Journald),
+        "syslog" => Ok(LogTarget::Syslog),
+        _ => Err(format!("expected stderr, journald or syslog, got '{}'", arg)),
+    }
+}
+
+enum Sink {
+    File(Mutex<File>),
+    #[cfg(unix)]
+    Journald(UnixDatagram),
+    #[cfg(unix)]
+    Syslog(UnixDatagram),
+}
+
+/// Log at `verbosity` from now on, to `log_file` if given, else to
+/// `target`. A log file alone asks for `debug` lines. Log lines are for
+/// people looking into a run and are no part of the event protocol.
+///
+/// With journald or syslog, each encryption and decryption is also
+/// recorded there, whatever the verbosity; see [`record`].
+pub fn init(verbosity: u8, log_file: Option<&Path>, target: LogTarget) -> io::Result<()> {
+    let (sink, verbosity) = match (log_file, target) {
+        (Some(path), _) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            (Some(Sink::File(Mutex::new(file))), verbosity.max(1))
+        }
+        (None, LogTarget::Stderr) => (None, verbosity),
+        #[cfg(unix)]
+        (None, LogTarget::Journald) => {
+            (Some(Sink::Journald(connect(JOURNALD_SOCKET)?)), verbosity)
+        }
+        #[cfg(unix)]
+        (None, LogTarget::Syslog) => (Some(Sink::Syslog(connect(SYSLOG_SOCKET)?)), verbosity),
+        #[cfg(not(unix))]
+        (None, _) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "journald and syslog are only available on Unix",
+            ))
+        }
+    };
+    if let Some(sink) = sink {
+        let _ = SINK.set(sink);
+    }
+    STARTED.get_or_init(Instant::now);
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn connect(path: &str) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+/// Whether `trace` lines are logged, for callers that would measure
+/// something only to log it.
+pub fn tracing() -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= 2
+}
+
+/// Log the line `message` makes, with `-v` and up.
+pub fn debug(message: impl FnOnce() -> String) {
+    if VERBOSITY.load(Ordering::Relaxed) >= 1 {
+        write_line("debug", &message());
+    }
+}
+
+/// Log the line `message` makes, with `-vv`.
+pub fn trace(message: impl FnOnce() -> String) {
+    if tracing() {
+        write_line("trace", &message());
+    }
+}
+
+/// `level` and `message` as a log line: seconds since the start, the
+/// level, and the input of a batch it is about.
+fn format_line(secs: f64, level: &str, file: Option<&str>, message: &str) -> String {
+    match file {
+        Some(file) => format!("[{:10.6} {}] {}: {}\n", secs, level, file, message),
+        None => format!("[{:10.6} {}] {}\n", secs, level, message),
+    }
+}
+
+fn write_line(level: &str, message: &str) {
+    let file = progress::current_file();
+    let line = || {
+        let secs = STARTED.get_or_init(Instant::now).elapsed().as_secs_f64();
+        format_line(secs, level, file.as_deref(), message)
+    };
+    // One write a line, so that the lines of different threads stay whole
+    match SINK.get() {
+        Some(Sink::File(log_file)) => {
+            let mut log_file = log_file.lock().unwrap_or_else(PoisonError::into_inner);
+            let _ = log_file.write_all(line().as_bytes());
+        }
+        #[cfg(unix)]
+        Some(Sink::Journald(_) | Sink::Syslog(_)) => {
+            let mut fields = vec![("MESSAGE", message.to_string())];
+            fields.extend(file.map(|file| ("INPUT", file)));
+            send(DEBUG, &fields);
+        }
+        None => {
+            let _ = io::stderr().lock().write_all(line().as_bytes());
+        }
+    }
+}
+
+/// Syslog severities the entries are sent at.
+const ERR: u8 = 3;
+const INFO: u8 = 6;
+const DEBUG: u8 = 7;
+
+/// Record the outcome of `operation` on `input`, which took `duration`,
+/// in journald or syslog, so that scheduled runs on a headless machine
+/// leave a trace. Nothing is recorded on stderr or in a log file, where
+/// the events and errors of the run already are.
+pub fn record<E: std::fmt::Display>(
+    operation: &str,
+    input: &str,
+    duration: Duration,
+    result: &Result<(), E>,
+) {
+    #[cfg(unix)]
+    if matches!(SINK.get(), Some(Sink::Journald(_) | Sink::Syslog(_))) {
+        let (priority, fields) = record_fields(operation, input, duration, result);
+        send(priority, &fields);
+    }
+    #[cfg(not(unix))]
+    let _ = (operation, input, duration, result);
+}
+
+/// The priority and fields of a [`record`] entry.
+fn record_fields<E: std::fmt::Display>(
+    operation: &str,
+    input: &str,
+    duration: Duration,
+    result: &Result<(), E>,
+) -> (u8, Vec<(&'static str, String)>) {
+    let secs = format!("{:.3}", duration.as_secs_f64());
+    let (priority, outcome, message) = match result {
+        Ok(()) => (INFO, "ok", format!("{} {}: ok in {}s", operation, input, secs)),
+        Err(e) => {
+            let message = format!("{} {}: failed after {}s: {}", operation, input, secs, e);
+            (ERR, "failed", message)
+        }
+    };
+    let mut fields = vec![
+        ("MESSAGE", message),
+        ("OPERATION", operation.to_string()),
+        ("INPUT", input.to_string()),
+        ("DURATION", secs),
+        ("RESULT", outcome.to_string()),
+    ];
+    if let Err(e) = result {
+        fields.push(("ERROR", e.to_string()));
+    }
+    (priority, fields)
+}
+
+/// Send an entry of `fields` at `priority` to journald or syslog. An
+/// entry that cannot be sent is dropped: the run goes on without it.
+#[cfg(unix)]
+fn send(priority: u8, fields: &[(&str, String)]) {
+    let _ = match SINK.get() {
+        Some(Sink::Journald(socket)) => socket.send(&journal_entry(priority, fields)),
+        Some(Sink::Syslog(socket)) => socket.send(&syslog_line(priority, fields)),
+        _ => return,
+    };
+}
+
+/// `fields` in the native journald protocol: `KEY=value` lines, or for a
+/// value with a newline, the key, its length as 64-bit little-endian and
+/// the value.
+fn journal_entry(priority: u8, fields: &[(&str, String)]) -> Vec<u8> {
+    let mut entry = Vec::new();
+    let identifier = [("PRIORITY", priority.to_string()), ("SYSLOG_IDENTIFIER", IDENTIFIER.into())];
+    for (key, value) in identifier.iter().chain(fields) {
+        entry.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+    entry
+}
+
+/// `fields` as a syslog line of the user facility: the message, then the
+/// other fields as `key=value`.
+fn syslog_line(priority: u8, fields: &[(&str, String)]) -> Vec<u8> {
+    let mut line = format!("<{}>{}[{}]:", 8 + priority, IDENTIFIER, std::process::id());
+    for (key, value) in fields {
+        match *key {
+            "MESSAGE" => line.push_str(&format!(" {}", value)),
+            _ => line.push_str(&format!(" {}={:?}", key.to_lowercase(), value)),
+        }
+    }
+    line.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_line() {
+        assert_eq!(
+            format_line(1.5, "debug", None, "Argon2id took 0.2s"),
+            "[  1.500000 debug] Argon2id took 0.2s\n"
+        );
+        assert_eq!(
+            format_line(0.0, "trace", Some("a.txt"), "chunk 3"),
+            "[  0.000000 trace] a.txt: chunk 3\n"
+        );
+    }
+
+    #[test]
+    fn test_record_fields() {
+        let ok: Result<(), String> = Ok(());
+        let duration = Duration::from_millis(1500);
+        let (priority, fields) = record_fields("encrypt", "a.txt", duration, &ok);
+        assert_eq!(priority, INFO);
+        assert_eq!(
+            fields,
+            [
+                ("MESSAGE", "encrypt a.txt: ok in 1.500s".to_string()),
+                ("OPERATION", "encrypt".to_string()),
+                ("INPUT", "a.txt".to_string()),
+                ("DURATION", "1.500".to_string()),
+                ("RESULT", "ok".to_string()),
+            ]
+        );
+
+        let failed: Result<(), String> = Err("Wrong passphrase".to_string());
+        let (priority, fields) = record_fields("decrypt", "a.gtkrypt", Duration::ZERO, &failed);
+        assert_eq!(priority, ERR);
+        assert_eq!(fields[0].1, "decrypt a.gtkrypt: failed after 0.000s: Wrong passphrase");
+        assert_eq!(fields[4], ("RESULT", "failed".to_string()));
+        assert_eq!(fields[5], ("ERROR", "Wrong passphrase".to_string()));
+    }
+
+    #[test]
+    fn test_journal_entry() {
+        let fields = [("MESSAGE", "done".to_string()), ("ERROR", "a\nb".to_string())];
+        let mut expected = b"PRIORITY=6\nSYSLOG_IDENTIFIER=gtkrypt\nMESSAGE=done\nERROR\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+        assert_eq!(journal_entry(INFO, &fields), expected);
+    }
+
+    #[test]
+    fn test_syslog_line() {
+        let fields = [("MESSAGE", "done".to_string()), ("INPUT", "my file".to_string())];
+        let line = String::from_utf8(syslog_line(ERR, &fields)).unwrap();
+        assert_eq!(line, format!("<11>gtkrypt[{}]: done input=\"my file\"", std::process::id()));
+    }
+
+    #[test]
+    fn test_nothing_is_formatted_below_the_verbosity() {
+        debug(|| unreachable!("not logged without -v"));
+        trace(|| unreachable!("not logged without -vv"));
+    }
+}
@@ -1,40 +1,681 @@
+mod aead;
+mod afalg;
+mod archive;
+mod audit;
+mod backup;
+mod batch;
+mod bench;
+mod calibration;
+mod cancel;
+mod capabilities;
+mod control;
+mod cpu;
 mod decrypt;
+mod direct;
+mod edit;
 mod encrypt;
+mod estimate;
+mod fd;
+mod footer;
+mod hash;
 mod header;
+mod human;
+mod inspect;
+mod jobs;
 mod kdf;
+mod keyfile;
+mod keyring;
+mod lock;
+mod log;
+mod metadata;
+mod naming;
+mod parity;
+mod passphrase;
+mod persist;
+mod pipeline;
 mod progress;
+mod reencrypt;
+mod serve;
+mod shred;
+mod space;
+mod stats;
+mod strength;
+mod temp;
+mod upgrade;
+mod volume;
+mod watch;
 
-use std::io::BufRead;
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
 
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 use sha2::{Sha256, Digest};
 
 use decrypt::DecryptError;
+use naming::NamingError;
 use encrypt::EncryptError;
+use progress::ErrorCode;
 
 /// gtkrypt-crypto: AES-256-GCM encryption/decryption backend for gtkrypt.
 ///
 /// Reads passphrase from stdin (one line), performs the requested operation,
-/// and reports progress as JSON lines on stdout and errors as JSON on stderr.
+/// and reports progress as JSON lines on stdout, or on `--progress-fd`, and
+/// errors as JSON on stderr.
+/// While encrypting or decrypting, further lines on stdin may pause and
+/// resume the work between chunks: {"cmd":"pause"} and {"cmd":"resume"}.
 #[derive(Parser)]
-#[command(name = "gtkrypt-crypto")]
+#[command(name = "gtkrypt-crypto", version)]
 #[command(about = "AES-256-GCM encryption/decryption backend for gtkrypt")]
 struct Cli {
+    /// Print the version, the container versions and the protocol version
+    /// as JSON and exit
+    #[arg(long, exclusive = true)]
+    version_json: bool,
+
+    /// Give up after this many seconds, removing any partial output, with
+    /// a `timeout` error (exit code 12)
+    #[arg(
+        long,
+        global = true,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    timeout: Option<u64>,
+
+    /// Leave at least this much between two progress lines of a phase: a
+    /// time such as 250ms or 2s, or a share of the total such as 5%, which
+    /// is one line a second where there is no total. The first and last
+    /// line of a phase are always emitted
+    #[arg(long, global = true, value_parser = progress::parse_progress_interval)]
+    progress_interval: Option<progress::ProgressInterval>,
+
+    /// Write the progress and other event lines to this inherited file
+    /// descriptor instead of stdout, for when stdout carries data
+    #[arg(long, global = true, value_name = "FD")]
+    progress_fd: Option<i32>,
+
+    /// Encoding of the progress and other events: json lines, or msgpack
+    /// maps each preceded by a 4-byte big-endian length. Errors on stderr
+    /// stay JSON. Without it, a terminal on stdout gets text and a
+    /// progress bar instead, and anything else json
+    #[arg(long, global = true, value_parser = progress::parse_event_format)]
+    event_format: Option<progress::EventFormat>,
+
+    /// Speak the JSON protocol even to a terminal, and print the reports
+    /// of `list` and `inspect` as JSON instead of text
+    #[arg(long, global = true, conflicts_with = "event_format")]
+    json: bool,
+
+    /// How passphrases are sent on stdin: a line each, or a 4-byte
+    /// big-endian length followed by the bytes as they are, for
+    /// passphrases holding line breaks
+    #[arg(
+        long,
+        global = true,
+        default_value = "line",
+        value_parser = passphrase::parse_passphrase_format
+    )]
+    passphrase_format: passphrase::PassphraseFormat,
+
+    /// Encoding the passphrase bytes are meant to be in: utf-8 or latin-1
+    /// warn of a passphrase that does not look like it. Its bytes are
+    /// used as given whatever the encoding
+    #[arg(
+        long,
+        global = true,
+        default_value = "bytes",
+        value_parser = passphrase::parse_passphrase_encoding
+    )]
+    passphrase_encoding: passphrase::PassphraseEncoding,
+
+    /// Refuse to encrypt with a passphrase whose strength, from 0 to 4, is
+    /// below this. The strength is reported whatever the minimum
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(0..=4)
+    )]
+    min_strength: u8,
+
+    /// Take the passphrase from the systemd credential NAME, in
+    /// $CREDENTIALS_DIRECTORY, or else from the environment variable
+    /// GTKRYPT_CREDENTIAL_NAME, with NAME in upper case and anything but
+    /// letters and digits turned into _, rather than from stdin. With
+    /// --new-passphrase, only the current passphrase comes from it
+    #[arg(long, global = true, value_name = "NAME")]
+    passphrase_from_credential: Option<String>,
+
+    /// Keep the passphrase, and the keys derived from it, in the session
+    /// keyring for SECS seconds, and take them from there in later runs
+    /// rather than reading stdin or running Argon2id again (Linux only)
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    cache_key: Option<u64>,
+
+    /// Log what the run does, for looking into slow or failing runs: -v
+    /// for the key derivation, I/O sizes and stage times, -vv for the time
+    /// of every block as well. Log lines go to stderr, apart from events
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Append the log lines to this file instead of stderr; without -v,
+    /// it logs as -v does
+    #[arg(long, global = true, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Send the log to the systemd journal or to syslog instead of
+    /// stderr, and record there each file encrypted or decrypted, with its
+    /// operation, input, duration and result: stderr, journald or syslog
+    #[arg(
+        long,
+        global = true,
+        default_value = "stderr",
+        value_parser = log::parse_log_target,
+        conflicts_with = "log_file"
+    )]
+    log_target: log::LogTarget,
+
+    /// Record each encryption, decryption, re-encryption, upgrade and
+    /// header edit in this append-only audit log: when, by whom, on which
+    /// path and with what result, each entry naming the SHA-256 of the one
+    /// before it. Check it with `verify-audit-log`
+    #[arg(long, global = true, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+
+    /// Only missing with `--version-json`
     #[command(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Encrypt a file
+    /// Encrypt a file, or a directory as an archive
     Encrypt {
-        /// Path to the input (plaintext) file
+        /// Path to the input (plaintext) file or directory. Several inputs
+        /// are encrypted in turn with one key derivation; each gets a
+        /// `result` line and its other lines carry a `file` field. A glob
+        /// such as 'photos/**/*.raw' stands for the files it matches
+        #[arg(long, required_unless_present_any = ["input_fd", "files"], num_args = 1..)]
+        input: Vec<String>,
+
+        /// The inputs, as with --input. Without an output option, each is
+        /// encrypted to FILE.gtkrypt next to it
+        #[arg(value_name = "FILE", conflicts_with_all = ["input", "input_fd"])]
+        files: Vec<String>,
+
+        /// Read the input from this inherited file descriptor, such as one
+        /// a sandboxed frontend got through the document portal, instead
+        /// of --input
+        #[arg(
+            long,
+            value_name = "FD",
+            conflicts_with_all = ["input", "in_place", "delete_input", "shred_input"]
+        )]
+        input_fd: Option<i32>,
+
+        /// Path to the output (encrypted) file
+        #[arg(
+            short,
+            long,
+            required_unless_present_any = ["output_template", "in_place", "output_fd", "files"]
+        )]
+        output: Option<String>,
+
+        /// Write the container to this inherited file descriptor instead
+        /// of --output, once it is complete. It is staged in --temp-dir or
+        /// the system temp directory
+        #[arg(
+            long,
+            value_name = "FD",
+            conflicts_with_all = ["output", "output_template", "in_place", "split_size"]
+        )]
+        output_fd: Option<i32>,
+
+        /// Name the output next to the input from a template instead of
+        /// --output: {name}, {stem} and {ext} stand for the input file name,
+        /// the name without its extension and the extension
+        #[arg(long, conflicts_with = "output")]
+        output_template: Option<String>,
+
+        /// If the output exists, pick "name (1)", "name (2)", ... instead
+        #[arg(long, default_value_t = false)]
+        no_clobber: bool,
+
+        /// Replace an existing output. Without this or --no-clobber an
+        /// existing output is an `output_exists` error (exit code 4)
+        #[arg(long, default_value_t = false, conflicts_with = "no_clobber")]
+        force: bool,
+
+        /// Replace the input file with the container, keeping its mode and
+        /// owner
+        #[arg(
+            long,
+            default_value_t = false,
+            conflicts_with_all = ["output", "output_template", "no_clobber", "split_size"]
+        )]
+        in_place: bool,
+
+        /// Delete the input file once the container is written and flushed
+        /// to disk (reported as an `input_deleted` line)
+        #[arg(long, default_value_t = false, conflicts_with = "in_place")]
+        delete_input: bool,
+
+        /// Like --delete-input, but first overwrite the file with random
+        /// data (best effort: not reliable on SSDs or copy-on-write
+        /// filesystems)
+        #[arg(
+            long,
+            default_value_t = false,
+            conflicts_with_all = ["in_place", "delete_input"]
+        )]
+        shred_input: bool,
+
+        /// Overwrite passes for --shred-input
+        #[arg(
+            long,
+            default_value_t = 3,
+            requires = "shred_input",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        shred_passes: u32,
+
+        /// Argon2id time cost parameter
+        #[arg(long, default_value_t = 3)]
+        time_cost: u32,
+
+        /// Pick the Argon2id time cost so that deriving the key takes about
+        /// this long here, such as 500ms or 2s, with the given memory cost
+        /// and parallelism. The Argon2id rate of the machine is measured
+        /// once and kept in the state directory for later runs
+        #[arg(
+            long,
+            value_name = "TIME",
+            conflicts_with = "time_cost",
+            value_parser = kdf::parse_target_time
+        )]
+        target_time: Option<std::time::Duration>,
+
+        /// Argon2id memory cost in KiB
+        #[arg(long, default_value_t = 65536)]
+        memory_cost: u32,
+
+        /// Argon2id parallelism parameter
+        #[arg(long, default_value_t = 4)]
+        parallelism: u32,
+
+        /// Store the original filename in the container header
+        #[arg(long, default_value_t = false)]
+        store_filename: bool,
+
+        /// Encrypt the filename, mode and size instead of storing them in
+        /// the plaintext header
+        #[arg(long, default_value_t = false)]
+        encrypt_metadata: bool,
+
+        /// Free-text comment stored in the container header (encrypted
+        /// along with the other metadata when --encrypt-metadata is set)
+        #[arg(long)]
+        comment: Option<String>,
+
+        /// User metadata attribute as key=value (repeatable)
+        #[arg(long = "meta", value_parser = parse_key_value)]
+        meta: Vec<(String, String)>,
+
+        /// Pad the plaintext so the container size hides the exact file
+        /// size (implies --encrypt-metadata)
+        #[arg(long, default_value_t = false)]
+        pad: bool,
+
+        /// Split the output into volumes of at most this size (e.g. 2G),
+        /// named OUTPUT.001, OUTPUT.002, ...
+        #[arg(long, value_parser = volume::parse_size)]
+        split_size: Option<u64>,
+
+        /// Append Reed-Solomon parity data worth this percentage of the
+        /// file (e.g. 5), so damaged blocks can be fixed with `repair`
+        #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+        parity: u8,
+
+        /// Re-read the written file and authenticate every chunk before
+        /// reporting success
+        #[arg(long, default_value_t = false)]
+        verify_after_encrypt: bool,
+
+        /// When encrypting a directory, only archive entries matching this
+        /// glob (repeatable)
+        #[arg(long)]
+        include: Vec<String>,
+
+        /// When encrypting a directory, skip entries matching this glob,
+        /// e.g. node_modules or '*.log' (repeatable)
+        #[arg(long)]
+        exclude: Vec<String>,
+
+        /// Read exclude globs from a file, one per line (blank lines and
+        /// lines starting with # are ignored)
+        #[arg(long)]
+        exclude_from: Option<String>,
+
+        /// Symbolic links in a directory: store the link itself, follow it
+        /// and archive its target, or skip it with a warning
+        #[arg(long, default_value = "store", value_parser = archive::parse_symlink_policy)]
+        symlinks: archive::SymlinkPolicy,
+
+        /// With several inputs, process up to this many at once
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        jobs: u32,
+
+        /// Read a file input and write the container bypassing the page
+        /// cache (Linux), so a huge transfer does not push everything else
+        /// out of memory. Falls back with a warning where unsupported
+        #[arg(long, default_value_t = false)]
+        direct_io: bool,
+
+        /// Drop the pages of the input and output from the page cache as
+        /// they are processed (Linux), so a long stream leaves the cache to
+        /// other programs
+        #[arg(long, default_value_t = false)]
+        drop_cache: bool,
+
+        /// Size in bytes of the read and write buffers; larger buffers
+        /// mean fewer system calls (0: the default)
+        #[arg(long, default_value_t = direct::DEFAULT_IO_BUFFER_SIZE)]
+        io_buffer_size: usize,
+
+        /// Where the chunks are encrypted: software, kernel for the Linux
+        /// kernel crypto API (AF_ALG), which can reach accelerators on
+        /// embedded devices, or ring/openssl in builds with those features.
+        /// Falls back with a warning where unavailable
+        #[arg(long, default_value = "software", value_parser = aead::parse_backend)]
+        crypto_backend: aead::Backend,
+
+        /// Write the container in this directory, e.g. on a fast local
+        /// disk when the output is on a network mount, and move it into
+        /// place once complete
+        #[arg(long)]
+        temp_dir: Option<PathBuf>,
+
+        /// First remove temporary files that crashed or killed runs left
+        /// in the output directory and --temp-dir, as `cleanup` does
+        #[arg(long, default_value_t = false)]
+        clean_temp: bool,
+
+        /// Encrypt an input that is a gtkrypt container already, which
+        /// is refused otherwise
+        #[arg(long, default_value_t = false)]
+        allow_double: bool,
+
+        /// Optional keyfile path for two-factor encryption
+        #[arg(long)]
+        keyfile: Option<String>,
+    },
+
+    /// Decrypt a file
+    Decrypt {
+        /// Path to the input (encrypted) file. Several inputs are decrypted
+        /// in turn, deriving the key once per salt; each gets a `result`
+        /// line and its other lines carry a `file` field. A glob such as
+        /// 'backup/**/*.gtkrypt' stands for the files it matches
+        #[arg(long, required_unless_present_any = ["input_fd", "files"], num_args = 1..)]
+        input: Vec<String>,
+
+        /// The inputs, as with --input. Without an output option, each
+        /// FILE.gtkrypt is decrypted to FILE next to it
+        #[arg(value_name = "FILE", conflicts_with_all = ["input", "input_fd"])]
+        files: Vec<String>,
+
+        /// Read the container from this inherited file descriptor instead
+        /// of --input
+        #[arg(
+            long,
+            value_name = "FD",
+            conflicts_with_all = ["input", "in_place", "recursive"]
+        )]
+        input_fd: Option<i32>,
+
+        /// Path to the output (decrypted) file
+        #[arg(
+            short,
+            long,
+            required_unless_present_any = ["output_dir", "in_place", "output_fd", "files"]
+        )]
+        output: Option<String>,
+
+        /// Write the plaintext to this inherited file descriptor instead
+        /// of --output, once it is complete. It is staged in --temp-dir or
+        /// the system temp directory
+        #[arg(
+            long,
+            value_name = "FD",
+            conflicts_with_all = ["output", "output_dir", "in_place"]
+        )]
+        output_fd: Option<i32>,
+
+        /// Directory to decrypt into, naming the output after the stored
+        /// filename (the chosen path is reported as an `output_path` line)
+        #[arg(long, conflicts_with = "output")]
+        output_dir: Option<String>,
+
+        /// Decrypt every .gtkrypt file below the --input directory into
+        /// --output-dir, keeping the relative directory structure
+        #[arg(long, requires = "output_dir", conflicts_with_all = ["in_place", "offset", "length"])]
+        recursive: bool,
+
+        /// Template for the output name inside --output-dir, applied to the
+        /// stored filename: {name}, {stem} and {ext}
+        #[arg(long, requires = "output_dir")]
+        output_template: Option<String>,
+
+        /// If the output exists, pick "name (1)", "name (2)", ... instead
+        #[arg(long, default_value_t = false)]
+        no_clobber: bool,
+
+        /// Replace an existing output. Without this or --no-clobber an
+        /// existing output is an `output_exists` error (exit code 4)
+        #[arg(long, default_value_t = false, conflicts_with = "no_clobber")]
+        force: bool,
+
+        /// Replace the input file with the plaintext, keeping its mode and
+        /// owner
+        #[arg(
+            long,
+            default_value_t = false,
+            conflicts_with_all = ["output", "output_dir", "no_clobber", "offset", "length"]
+        )]
+        in_place: bool,
+
+        /// Plaintext byte offset to start from; only the chunks covering
+        /// the requested range are decrypted
+        #[arg(long, default_value_t = 0)]
+        offset: u64,
+
+        /// Number of plaintext bytes to write (default: to the end)
+        #[arg(long)]
+        length: Option<u64>,
+
+        /// On corruption, keep checking the remaining chunks and report
+        /// every bad one instead of stopping at the first
+        #[arg(long, default_value_t = false)]
+        scan_all: bool,
+
+        /// Salvage a damaged file: keep every chunk that authenticates and
+        /// leave zero-filled holes for the bad ones (still exits with the
+        /// corrupt_file error listing them)
+        #[arg(long, default_value_t = false)]
+        keep_partial: bool,
+
+        /// Leave the output with the current time instead of the stored
+        /// modification and access times
+        #[arg(long, default_value_t = false)]
+        no_restore_times: bool,
+
+        /// Give the output its stored owner and group (needs root or
+        /// CAP_CHOWN; otherwise a warning is emitted)
+        #[arg(long, default_value_t = false)]
+        restore_owner: bool,
+
+        /// Leave permissions to the umask instead of the stored mode
+        #[arg(long, default_value_t = false, conflicts_with = "mode")]
+        no_restore_permissions: bool,
+
+        /// Give the output this octal mode instead of the stored one
+        #[arg(long, value_parser = decrypt::parse_mode)]
+        mode: Option<u32>,
+
+        /// With several inputs, process up to this many at once
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        jobs: u32,
+
+        /// Threads that authenticate and decrypt the chunks of a file (0:
+        /// one per available core)
+        #[arg(long, default_value_t = 0)]
+        threads: usize,
+
+        /// Read the container and write the plaintext bypassing the page
+        /// cache (Linux). Falls back with a warning where unsupported
+        #[arg(long, default_value_t = false)]
+        direct_io: bool,
+
+        /// Drop the pages of the input and output from the page cache as
+        /// they are processed (Linux)
+        #[arg(long, default_value_t = false)]
+        drop_cache: bool,
+
+        /// Size in bytes of the read and write buffers (0: the default)
+        #[arg(long, default_value_t = direct::DEFAULT_IO_BUFFER_SIZE)]
+        io_buffer_size: usize,
+
+        /// Where the chunks are decrypted: software, kernel for the Linux
+        /// kernel crypto API (AF_ALG), or ring/openssl in builds with those
+        /// features
+        #[arg(long, default_value = "software", value_parser = aead::parse_backend)]
+        crypto_backend: aead::Backend,
+
+        /// Refuse containers whose header asks for more Argon2id memory
+        /// than this, in KiB
+        #[arg(long)]
+        max_kdf_memory: Option<u32>,
+
+        /// Refuse containers whose header asks for a higher Argon2id time
+        /// cost than this
+        #[arg(long)]
+        max_kdf_time: Option<u32>,
+
+        /// Write a file output in this directory and move it into place
+        /// once complete. Archives are still extracted beside the output
+        #[arg(long)]
+        temp_dir: Option<PathBuf>,
+
+        /// First remove temporary files that crashed or killed runs left
+        /// in the output directory and --temp-dir, as `cleanup` does
+        #[arg(long, default_value_t = false)]
+        clean_temp: bool,
+
+        /// Optional keyfile path for two-factor decryption
+        #[arg(long)]
+        keyfile: Option<String>,
+    },
+
+    /// Watch a directory and encrypt each file that appears in it, once it
+    /// has stopped changing
+    Watch {
+        /// Directory to watch (its subdirectories are not)
+        #[arg(long)]
+        dir: String,
+
+        /// Directory for the containers, named after the files with a
+        /// .gtkrypt extension
+        #[arg(long)]
+        output_dir: String,
+
+        /// Milliseconds a file's size and modification time must hold
+        /// still before it is encrypted
+        #[arg(long, default_value_t = 2000)]
+        settle_ms: u64,
+
+        /// If a container exists, pick "name (1)", "name (2)", ... instead
+        #[arg(long, default_value_t = false)]
+        no_clobber: bool,
+
+        /// Replace an existing container
+        #[arg(long, default_value_t = false, conflicts_with = "no_clobber")]
+        force: bool,
+
+        /// Delete each file once its container is written and flushed
+        #[arg(long, default_value_t = false)]
+        delete_input: bool,
+
+        /// Argon2id time cost parameter
+        #[arg(long, default_value_t = 3)]
+        time_cost: u32,
+
+        /// Argon2id memory cost in KiB
+        #[arg(long, default_value_t = 65536)]
+        memory_cost: u32,
+
+        /// Argon2id parallelism parameter
+        #[arg(long, default_value_t = 4)]
+        parallelism: u32,
+
+        /// Store the original filename in the container header
+        #[arg(long, default_value_t = false)]
+        store_filename: bool,
+
+        /// Encrypt the filename, mode and size instead of storing them in
+        /// the plaintext header
+        #[arg(long, default_value_t = false)]
+        encrypt_metadata: bool,
+
+        /// Optional keyfile path for two-factor encryption
+        #[arg(long)]
+        keyfile: Option<String>,
+    },
+
+    /// Run the encrypt and decrypt jobs listed in a JSON manifest, in order
+    /// and under one passphrase, with a `result` line per job
+    Batch {
+        /// The manifest: an array of objects with an "operation" of
+        /// "encrypt" or "decrypt", an "input" and that operation's options.
+        /// "-" reads it from stdin after the passphrase line
+        #[arg(long)]
+        jobs_file: String,
+
+        /// Run up to this many jobs at once
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        jobs: u32,
+
+        /// Optional keyfile path for two-factor encryption
+        #[arg(long)]
+        keyfile: Option<String>,
+    },
+
+    /// Verify the passphrase for an encrypted file without decrypting it
+    /// (exit code 0 if correct, 1 if wrong)
+    CheckPassphrase {
+        /// Path to the input (encrypted) file
         #[arg(long)]
         input: String,
 
-        /// Path to the output (encrypted) file
+        /// Optional keyfile path for two-factor decryption
         #[arg(long)]
-        output: String,
+        keyfile: Option<String>,
+    },
+
+    /// Re-encrypt a version 1 or 2 file in the current format
+    Upgrade {
+        /// Path to the input (encrypted) file
+        #[arg(long)]
+        input: String,
+
+        /// Path for the upgraded file (default: replace the input once the
+        /// upgrade has succeeded)
+        #[arg(long)]
+        output: Option<String>,
 
         /// Argon2id time cost parameter
         #[arg(long, default_value_t = 3)]
@@ -44,185 +685,1811 @@ enum Commands {
         #[arg(long, default_value_t = 65536)]
         memory_cost: u32,
 
-        /// Argon2id parallelism parameter
-        #[arg(long, default_value_t = 4)]
-        parallelism: u32,
+        /// Argon2id parallelism parameter
+        #[arg(long, default_value_t = 4)]
+        parallelism: u32,
+
+        /// Encrypt the filename, mode and size instead of storing them in
+        /// the plaintext header
+        #[arg(long, default_value_t = false)]
+        encrypt_metadata: bool,
+
+        /// Optional keyfile path for two-factor decryption
+        #[arg(long)]
+        keyfile: Option<String>,
+    },
+
+    /// Re-encrypt a file with a fresh salt and nonce, optionally changing
+    /// the KDF parameters or the passphrase
+    ReEncrypt {
+        /// Path to the input (encrypted) file
+        #[arg(long)]
+        input: String,
+
+        /// Path for the re-encrypted file (default: replace the input once
+        /// re-encryption has succeeded)
+        #[arg(long)]
+        output: Option<String>,
+
+        /// New Argon2id time cost (default: keep the current value)
+        #[arg(long)]
+        time_cost: Option<u32>,
+
+        /// New Argon2id memory cost in KiB (default: keep the current value)
+        #[arg(long)]
+        memory_cost: Option<u32>,
+
+        /// New Argon2id parallelism (default: keep the current value)
+        #[arg(long)]
+        parallelism: Option<u32>,
+
+        /// Read a new passphrase from the second line of stdin
+        #[arg(long, default_value_t = false)]
+        new_passphrase: bool,
+
+        /// Optional keyfile path for the current passphrase
+        #[arg(long)]
+        keyfile: Option<String>,
+
+        /// Keyfile for the new passphrase (default: the current keyfile)
+        #[arg(long)]
+        new_keyfile: Option<String>,
+    },
+
+    /// Change the stored filename or comment of an encrypted file without
+    /// re-encrypting it
+    EditHeader {
+        /// Path to the encrypted file, rewritten in place
+        #[arg(long)]
+        input: String,
+
+        /// New stored filename (empty to remove it)
+        #[arg(long)]
+        set_filename: Option<String>,
+
+        /// New comment (empty to remove it)
+        #[arg(long)]
+        set_comment: Option<String>,
+
+        /// Optional keyfile path for two-factor decryption
+        #[arg(long)]
+        keyfile: Option<String>,
+    },
+
+    /// Find and fix damaged blocks of an encrypted file using its parity
+    /// data (no passphrase needed)
+    Repair {
+        /// Path to the encrypted file, repaired in place
+        #[arg(long)]
+        input: String,
+    },
+
+    /// List the entries of an encrypted directory archive
+    List {
+        /// Path to the input (encrypted) archive
+        #[arg(long)]
+        input: String,
+
+        /// Optional keyfile path for two-factor decryption
+        #[arg(long)]
+        keyfile: Option<String>,
+    },
+
+    /// Extract entries of an encrypted directory archive
+    Extract {
+        /// Path to the input (encrypted) archive
+        #[arg(long)]
+        input: String,
+
+        /// Directory to extract into (created if missing)
+        #[arg(long)]
+        output_dir: String,
+
+        /// Entry to extract, with everything below it (repeatable; default:
+        /// all entries)
+        #[arg(long)]
+        entry: Vec<String>,
+
+        /// Leave extracted entries with the current time instead of the
+        /// stored modification times
+        #[arg(long, default_value_t = false)]
+        no_restore_times: bool,
+
+        /// Give extracted entries their stored owner and group (needs root
+        /// or CAP_CHOWN; otherwise a warning is emitted)
+        #[arg(long, default_value_t = false)]
+        restore_owner: bool,
+
+        /// Leave permissions to the umask instead of the stored mode
+        #[arg(long, default_value_t = false, conflicts_with = "mode")]
+        no_restore_permissions: bool,
+
+        /// Give extracted files this octal mode instead of the stored one
+        #[arg(long, value_parser = decrypt::parse_mode)]
+        mode: Option<u32>,
+
+        /// Optional keyfile path for two-factor decryption
+        #[arg(long)]
+        keyfile: Option<String>,
+    },
+
+    /// Back up or restore the header of an encrypted file
+    Header {
+        #[command(subcommand)]
+        action: HeaderAction,
+    },
+
+    /// Measure cipher throughput on this machine and print the results as
+    /// JSON (no passphrase needed)
+    Bench {
+        /// Cipher to measure, or all of them
+        #[arg(long, default_value = "all", value_parser = bench::parse_cipher_choice)]
+        cipher: bench::CipherChoice,
+
+        /// Time spent on each direction of each cipher, in milliseconds
+        #[arg(long, default_value_t = 1000)]
+        duration_ms: u64,
+    },
+
+    /// Estimate how long decrypting a container would take here, from its
+    /// header, the Argon2id rate of this machine and a short measurement
+    /// of the cipher, and print it as JSON, without the passphrase
+    Estimate {
+        /// Path to the input (encrypted) file
+        #[arg(long)]
+        input: String,
+
+        /// Threads the chunks would be decrypted on, as with decrypt (0:
+        /// one per available core)
+        #[arg(long, default_value_t = 0)]
+        threads: usize,
+
+        /// Backend the chunks would be decrypted with, as with decrypt
+        #[arg(long, default_value = "software", value_parser = aead::parse_backend)]
+        crypto_backend: aead::Backend,
+    },
+
+    /// Show where the bytes of containers go: header, chunks and their
+    /// tags, chunk index, parity and padding, and the overhead over the
+    /// original size, read from the headers alone
+    Stats {
+        /// Encrypted files to report on
+        #[arg(required = true, value_name = "FILE")]
+        files: Vec<String>,
+
+        /// Read the passphrase from stdin to reveal the original size, and
+        /// so the padding, of containers with encrypted metadata
+        #[arg(long, default_value_t = false)]
+        unlock: bool,
+
+        /// Optional keyfile path (only used with --unlock)
+        #[arg(long)]
+        keyfile: Option<String>,
+    },
+
+    /// Print what this backend and machine support as JSON: container
+    /// versions, ciphers, KDFs, subcommands, the protocol version and
+    /// hardware AES acceleration
+    Capabilities,
+
+    /// Print a shell completion script generated from this command line,
+    /// for packagers to install or to source from a shell's startup file
+    Completions {
+        /// Shell to complete for
+        shell: clap_complete::Shell,
+    },
+
+    /// Print the man page, generated from this command line, as roff
+    Manpage {
+        /// Write a page for the command and one for each subcommand
+        /// (`gtkrypt-crypto-encrypt.1`, ...) into this directory instead
+        #[arg(long, value_name = "DIR")]
+        output_dir: Option<PathBuf>,
+    },
+
+    /// Remove the temporary files and directories that crashed or killed
+    /// runs left behind, reporting each as a `temp_removed` line. Only
+    /// gtkrypt's own temporaries that no running process holds are touched
+    Cleanup {
+        /// Directory to clean; not searched recursively (repeatable)
+        #[arg(long, required = true, num_args = 1..)]
+        dir: Vec<PathBuf>,
+
+        /// Leave temporaries modified less than this many seconds ago
+        #[arg(long, default_value_t = 60)]
+        min_age_secs: u64,
+    },
+
+    /// Create a keyfile of random bytes, for `--keyfile`, readable only by
+    /// its owner
+    Keyfile {
+        /// Path to create the keyfile at; it must not exist
+        #[arg(long)]
+        output: PathBuf,
+
+        /// Wrap the keyfile with DPAPI, so that it only works for the
+        /// current user on this machine (Windows only)
+        #[arg(long, default_value_t = false)]
+        dpapi: bool,
+    },
+
+    /// Stay running and serve JSON-RPC 2.0 requests, one per line:
+    /// `encrypt`, `decrypt`, `inspect` and `derive_key`, with their events
+    /// sent as `event` notifications carrying the request id, and `cancel`
+    /// to stop one
+    Serve {
+        /// Read requests from stdin and answer on stdout
+        #[arg(long, required_unless_present = "socket", conflicts_with = "socket")]
+        stdio: bool,
+
+        /// Listen on a Unix socket created at this path and serve each
+        /// client of the same user as on stdio, sharing cached keys
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+    },
+
+    /// The privileged helper started through pkexec for files the user
+    /// cannot reach: serves `encrypt`, `decrypt` and `inspect` on stdio
+    /// like `serve`, but requests carry a `derived_key` from the
+    /// unprivileged `derive_key` and a passphrase is refused
+    PrivilegedHelper,
+
+    /// Check that no entry of an `--audit-log` was changed, removed or
+    /// reordered, and print the number of entries and the SHA-256 of the
+    /// last one as JSON. Compared with a head kept elsewhere, that shows
+    /// nothing was cut off the end either
+    VerifyAuditLog {
+        /// Path to the audit log
+        #[arg(long)]
+        input: PathBuf,
+    },
+
+    /// Show the header of an encrypted file without decrypting it
+    Inspect {
+        /// Path to the input (encrypted) file
+        #[arg(long)]
+        input: String,
+
+        /// Read the passphrase from stdin to reveal encrypted metadata
+        #[arg(long, default_value_t = false)]
+        unlock: bool,
+
+        /// Optional keyfile path (only used with --unlock)
+        #[arg(long)]
+        keyfile: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum HeaderAction {
+    /// Save the header of an encrypted file to a backup file
+    Backup {
+        /// Path to the input (encrypted) file
+        #[arg(long)]
+        input: String,
+
+        /// Path to the header backup file to write
+        #[arg(long)]
+        output: String,
+    },
+
+    /// Overwrite the header of an encrypted file with a saved backup
+    Restore {
+        /// Path to the header backup file
+        #[arg(long)]
+        backup: String,
+
+        /// Path to the encrypted file to repair
+        #[arg(long)]
+        target: String,
+    },
+}
+
+/// Parse a `key=value` argument. The value may itself contain `=`.
+fn parse_key_value(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) => Ok((key.to_string(), value.to_string())),
+        None => Err(format!("expected key=value, got '{}'", arg)),
+    }
+}
+
+/// Read a passphrase from stdin as raw bytes, framed as set with
+/// `--passphrase-format`. The first comes from the credential named with
+/// `--passphrase-from-credential` instead, if there is one, or from the
+/// keyring with `--cache-key`; when stdin is a terminal, it is prompted
+/// for as `label`, twice if `confirm` is set.
+fn read_passphrase(label: &str, confirm: bool) -> Result<Vec<u8>, String> {
+    if let Some(passphrase) = passphrase::take_credential() {
+        return passphrase;
+    }
+    if let Some(passphrase) = keyring::cached_passphrase() {
+        return Ok(passphrase);
+    }
+    let passphrase = if std::io::stdin().is_terminal() {
+        passphrase::prompt(label, confirm)?
+    } else {
+        passphrase::read(&mut std::io::stdin().lock())?
+    };
+    keyring::cache_passphrase(&passphrase);
+    Ok(passphrase)
+}
+
+/// Read a keyfile (up to 64 KiB) and return the SHA-256 hash of its secret,
+/// unwrapped first if it is protected with DPAPI.
+fn read_keyfile(path: &str) -> Result<[u8; 32], String> {
+    use std::io::Read;
+
+    const MAX_KEYFILE_SIZE: usize = 64 * 1024; // 64 KiB
+
+    let mut file = std::fs::File::open(path)
+        .map_err(|e| format!("Failed to open keyfile '{}': {}", path, e))?;
+
+    let mut buf = vec![0u8; MAX_KEYFILE_SIZE];
+    let mut total = 0;
+    loop {
+        match file.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => {
+                total += n;
+                if total >= MAX_KEYFILE_SIZE {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(format!("Failed to read keyfile '{}': {}", path, e)),
+        }
+    }
+
+    let secret = keyfile::secret(&buf[..total])
+        .map_err(|e| format!("Failed to read keyfile '{}': {}", path, e))?;
+    let mut hasher = Sha256::new();
+    hasher.update(&secret);
+    Ok(hasher.finalize().into())
+}
+
+/// Permission policy from the `--no-restore-permissions` and `--mode`
+/// flags, which clap keeps from being combined.
+fn permission_policy(no_restore: bool, mode: Option<u32>) -> decrypt::PermissionPolicy {
+    match mode {
+        Some(mode) => decrypt::PermissionPolicy::Mode(mode),
+        None if no_restore => decrypt::PermissionPolicy::Umask,
+        None => decrypt::PermissionPolicy::Stored,
+    }
+}
+
+/// Conflict policy from the `--force` and `--no-clobber` flags, which clap
+/// keeps from being combined.
+fn conflict_policy(force: bool, no_clobber: bool) -> naming::Conflict {
+    match (force, no_clobber) {
+        (true, _) => naming::Conflict::Replace,
+        (_, true) => naming::Conflict::Rename,
+        _ => naming::Conflict::Fail,
+    }
+}
+
+/// Output path for `encrypt`: `--output` as given, or the template applied
+/// to the input name in the input's directory. A path the caller did not
+/// spell out is reported.
+fn encrypt_output_path(
+    input: &str,
+    output: Option<String>,
+    naming: &naming::OutputNaming,
+) -> Result<String, NamingError> {
+    let (path, renamed) = match output {
+        Some(output) => naming.settle(Path::new(input), PathBuf::from(output), false)?,
+        None => {
+            let input = Path::new(input);
+            let name = input.file_name().and_then(|name| name.to_str()).ok_or_else(|| {
+                NamingError::Invalid(format!("Cannot name the output after '{}'", input.display()))
+            })?;
+            let dir = input.parent().unwrap_or(Path::new(""));
+            naming.resolve(input, dir, name)?
+        }
+    };
+    if renamed {
+        progress::emit_output_path(&path);
+    }
+    path.into_os_string()
+        .into_string()
+        .map_err(|path| NamingError::Invalid(format!("Output path is not valid UTF-8: {:?}", path)))
+}
+
+/// Output of decrypting `input` when none is given: the input without its
+/// `.gtkrypt` extension, next to it.
+fn decrypt_output_path(input: &str) -> Result<String, decrypt::DecryptError> {
+    match input.strip_suffix(".gtkrypt") {
+        Some(output) if !output.is_empty() && !output.ends_with(['/', '\\']) => {
+            Ok(output.to_string())
+        }
+        _ => Err(decrypt::DecryptError::Internal(format!(
+            "Cannot name the output of '{}', which does not end in .gtkrypt; give -o or \
+             --output-dir",
+            input
+        ))),
+    }
+}
+
+/// Expand the inputs that are glob patterns, reporting the number of
+/// inputs before any is processed when a pattern was used.
+fn expand_inputs(input: Vec<String>) -> Vec<String> {
+    match batch::expand_globs(input) {
+        Ok((inputs, expanded)) => {
+            if expanded {
+                progress::emit_input_count(inputs.len());
+            }
+            inputs
+        }
+        Err(msg) => progress::emit_error_and_exit(ErrorCode::InternalError, &msg, 10),
+    }
+}
+
+/// Directory under `output_dir` for a container found by `--recursive`:
+/// the container's own directory relative to `root`, created if needed.
+fn recursive_output_dir(
+    root: &Path,
+    input: &str,
+    output_dir: Option<&str>,
+) -> Result<String, DecryptError> {
+    let relative = Path::new(input)
+        .parent()
+        .and_then(|parent| parent.strip_prefix(root).ok())
+        .unwrap_or(Path::new(""));
+    let dir = Path::new(output_dir.unwrap_or_default()).join(relative);
+    std::fs::create_dir_all(&dir).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            DecryptError::Permission(format!("Cannot create output directory: {}", e))
+        } else {
+            DecryptError::Internal(format!("Failed to create output directory: {}", e))
+        }
+    })?;
+    // Both halves came from UTF-8 strings
+    Ok(dir.to_string_lossy().into_owned())
+}
+
+/// Remove stale temporaries from `dir` and `temp_dir` before an operation
+/// writes there. Failing to is only worth a warning.
+fn clean_stale_temps(dir: Option<&Path>, temp_dir: Option<&Path>) {
+    let min_age = std::time::Duration::from_secs(60);
+    for dir in dir.into_iter().chain(temp_dir) {
+        match temp::sweep(dir, min_age) {
+            Ok(removed) => removed.iter().for_each(|path| progress::emit_temp_removed(path)),
+            Err(e) => progress::emit_warning(
+                "temp_cleanup_failed",
+                &format!("Cannot clean stale temporary files in {}: {}", dir.display(), e),
+            ),
+        }
+    }
+}
+
+/// Read glob patterns from a file, one per line, skipping blank lines and
+/// `#` comments.
+fn read_pattern_file(path: &str) -> Result<Vec<String>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read pattern file '{}': {}", path, e))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Combine passphrase with optional keyfile hash into key material.
+/// If keyfile is provided: passphrase_bytes || SHA-256(keyfile_bytes)
+/// If no keyfile: passphrase_bytes
+fn build_key_material(passphrase: &[u8], keyfile_path: &Option<String>) -> Result<Vec<u8>, String> {
+    let mut material = passphrase.to_vec();
+
+    if let Some(path) = keyfile_path {
+        let keyfile_hash = read_keyfile(path)?;
+        material.extend_from_slice(&keyfile_hash);
+    }
+
+    Ok(material)
+}
+
+/// The path to read the inherited descriptor `fd` through, exiting with an
+/// internal error if it cannot be read.
+fn input_fd_path(fd: i32) -> String {
+    if let Err(e) = fd::check(fd, false) {
+        let msg = format!("Cannot read from file descriptor {}: {}", fd, e);
+        progress::emit_error_and_exit(ErrorCode::InternalError, &msg, 10);
+    }
+    fd::path(fd)
+}
+
+/// Stage an output for the inherited descriptor `fd`, exiting with an
+/// internal error if it cannot be written.
+fn stage_output_fd(fd: i32, temp_dir: Option<&Path>) -> fd::OutputFd {
+    match fd::OutputFd::stage(fd, temp_dir) {
+        Ok(staged) => staged,
+        Err(e) => {
+            let msg = format!("Cannot write to file descriptor {}: {}", fd, e);
+            progress::emit_error_and_exit(ErrorCode::InternalError, &msg, 10)
+        }
+    }
+}
+
+fn output_fd_path(staged: &fd::OutputFd) -> String {
+    staged.path().to_string_lossy().into_owned()
+}
+
+/// Copy a finished output into its descriptor, or exit with the error.
+fn deliver_output_fd(staged: fd::OutputFd) {
+    if let Err(e) = staged.deliver() {
+        let msg = format!("Failed to write the output to its file descriptor: {}", e);
+        if space::is_disk_full(&e) {
+            progress::emit_error_and_exit(ErrorCode::DiskFull, &msg, progress::EXIT_DISK_FULL);
+        }
+        progress::emit_error_and_exit(ErrorCode::InternalError, &msg, 10);
+    }
+}
+
+/// Read the passphrase from stdin and combine it with an optional keyfile,
+/// exiting with an internal error if either cannot be read.
+fn read_key_material(keyfile: &Option<String>) -> Vec<u8> {
+    key_material_from(read_passphrase("Passphrase", false), keyfile)
+}
+
+/// Like `read_key_material`, for a passphrase to encrypt with, which a
+/// prompt asks for twice and which must be strong enough.
+fn read_new_key_material(keyfile: &Option<String>) -> Vec<u8> {
+    let passphrase = read_passphrase("Passphrase", true);
+    if let Ok(passphrase) = &passphrase {
+        check_strength(passphrase);
+    }
+    key_material_from(passphrase, keyfile)
+}
+
+/// Report how strong a passphrase to encrypt with is, and exit if it is
+/// below the `--min-strength`.
+fn check_strength(passphrase: &[u8]) {
+    let strength = strength::estimate(passphrase);
+    progress::emit_event(&strength);
+    if let Err(event) = strength::check(&strength) {
+        progress::emit_error_event_and_exit(event, progress::EXIT_WEAK_PASSPHRASE);
+    }
+}
+
+fn key_material_from(passphrase: Result<Vec<u8>, String>, keyfile: &Option<String>) -> Vec<u8> {
+    let passphrase = match passphrase {
+        Ok(p) => p,
+        Err(msg) => {
+            progress::emit_error_and_exit(ErrorCode::InternalError, &msg, 10);
+        }
+    };
+
+    match build_key_material(&passphrase, keyfile) {
+        Ok(m) => m,
+        Err(msg) => {
+            progress::emit_error_and_exit(ErrorCode::InternalError, &msg, 10);
+        }
+    }
+}
+
+/// Report a decryption-side error on stderr and exit with its code.
+fn exit_with_decrypt_error(err: DecryptError) -> ! {
+    let (event, code) = decrypt_error_event(err);
+    progress::emit_error_event_and_exit(event, code)
+}
+
+/// The error event and exit code for a decryption-side error.
+fn decrypt_error_event(err: DecryptError) -> (progress::ErrorEvent, i32) {
+    use progress::{ErrorDetails, ErrorEvent};
+    match err {
+        DecryptError::WrongPassphrase(msg) => {
+            // A cached passphrase that is wrong would be wrong every time
+            keyring::forget_passphrase();
+            (ErrorEvent::new(ErrorCode::WrongPassphrase, &msg), 1)
+        }
+        DecryptError::CorruptFile(msg) => (ErrorEvent::new(ErrorCode::CorruptFile, &msg), 2),
+        DecryptError::CorruptChunks(msg, bad_chunks) => {
+            let count = bad_chunks.len();
+            let event = ErrorEvent {
+                details: Box::new(ErrorDetails {
+                    chunk_index: bad_chunks.first().map(|chunk| chunk.chunk_index),
+                    ..Default::default()
+                }),
+                bad_chunks,
+                ..ErrorEvent::with_id(
+                    ErrorCode::CorruptFile,
+                    &msg,
+                    "chunks_corrupt",
+                    [("count", count.into())],
+                )
+            };
+            (event, 2)
+        }
+        DecryptError::SizeMismatch(expected, actual) => {
+            let message =
+                format!("File size mismatch: expected {} bytes, got {}", expected, actual);
+            let event = ErrorEvent {
+                details: Box::new(ErrorDetails {
+                    expected_size: Some(expected),
+                    actual_size: Some(actual),
+                    ..Default::default()
+                }),
+                ..ErrorEvent::with_id(
+                    ErrorCode::CorruptFile,
+                    &message,
+                    "file_size_mismatch",
+                    [("expected", expected.into()), ("actual", actual.into())],
+                )
+            };
+            (event, 2)
+        }
+        DecryptError::Permission(msg) => (ErrorEvent::new(ErrorCode::PermissionError, &msg), 3),
+        DecryptError::Internal(msg) => (ErrorEvent::new(ErrorCode::InternalError, &msg), 10),
+        DecryptError::DiskFull(msg) => {
+            (ErrorEvent::new(ErrorCode::DiskFull, &msg), progress::EXIT_DISK_FULL)
+        }
+        DecryptError::InsufficientMemory(msg) => (
+            ErrorEvent::new(ErrorCode::InsufficientMemory, &msg),
+            progress::EXIT_INSUFFICIENT_MEMORY,
+        ),
+        DecryptError::KdfParamsExcessive(msg) => (
+            ErrorEvent::new(ErrorCode::KdfParamsExcessive, &msg),
+            progress::EXIT_KDF_PARAMS_EXCESSIVE,
+        ),
+        DecryptError::Busy(msg) => {
+            (ErrorEvent::new(ErrorCode::ResourceBusy, &msg), progress::EXIT_RESOURCE_BUSY)
+        }
+        DecryptError::OutputExists(path) => {
+            let message = format!("Output already exists: {}", path);
+            let event = ErrorEvent {
+                details: Box::new(ErrorDetails {
+                    path: Some(path.clone()),
+                    ..Default::default()
+                }),
+                output_path: Some(path.clone()),
+                ..ErrorEvent::with_id(
+                    ErrorCode::OutputExists,
+                    &message,
+                    "output_exists",
+                    [("path", path.into())],
+                )
+            };
+            (event, progress::EXIT_OUTPUT_EXISTS)
+        }
+        DecryptError::SameFile(path) => {
+            let message =
+                format!("Output {} is the input file itself; use --in-place to replace it", path);
+            let event = ErrorEvent {
+                details: Box::new(ErrorDetails {
+                    path: Some(path.clone()),
+                    ..Default::default()
+                }),
+                output_path: Some(path.clone()),
+                ..ErrorEvent::with_id(
+                    ErrorCode::SameFile,
+                    &message,
+                    "output_is_input",
+                    [("path", path.into())],
+                )
+            };
+            (event, progress::EXIT_SAME_FILE)
+        }
+        DecryptError::Cancelled => (
+            ErrorEvent::new(ErrorCode::Cancelled, "Cancelled; nothing was written"),
+            progress::EXIT_CANCELLED,
+        ),
+    }
+}
+
+/// The error event and exit code for an encryption error.
+fn encrypt_error_event(err: EncryptError) -> (progress::ErrorEvent, i32) {
+    use progress::{ErrorDetails, ErrorEvent};
+    match err {
+        EncryptError::Permission(msg) => (ErrorEvent::new(ErrorCode::PermissionError, &msg), 3),
+        EncryptError::Internal(msg) => (ErrorEvent::new(ErrorCode::InternalError, &msg), 10),
+        EncryptError::DiskFull(msg) => {
+            (ErrorEvent::new(ErrorCode::DiskFull, &msg), progress::EXIT_DISK_FULL)
+        }
+        EncryptError::Busy(msg) => {
+            (ErrorEvent::new(ErrorCode::ResourceBusy, &msg), progress::EXIT_RESOURCE_BUSY)
+        }
+        EncryptError::Cancelled => (
+            ErrorEvent::new(ErrorCode::Cancelled, "Cancelled; nothing was written"),
+            progress::EXIT_CANCELLED,
+        ),
+        EncryptError::AlreadyEncrypted(path) => {
+            let message = format!(
+                "{} is a gtkrypt container already; use --allow-double to encrypt it again",
+                path
+            );
+            let event = ErrorEvent {
+                details: Box::new(ErrorDetails {
+                    path: Some(path.clone()),
+                    ..Default::default()
+                }),
+                ..ErrorEvent::with_id(
+                    ErrorCode::AlreadyEncrypted,
+                    &message,
+                    "already_encrypted",
+                    [("path", path.into())],
+                )
+            };
+            (event, progress::EXIT_ALREADY_ENCRYPTED)
+        }
+    }
+}
+
+/// Take the string `field` out of the params of a `serve` request.
+fn take_param(
+    params: &mut serde_json::Value,
+    field: &str,
+) -> Result<Option<String>, serve::RpcError> {
+    match params.as_object_mut().and_then(|params| params.remove(field)) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(value)) => Ok(Some(value)),
+        Some(_) => Err(serve::RpcError::invalid_params(&format!("{} must be a string", field))),
+    }
+}
+
+/// Run one `serve` request. `encrypt` and `decrypt` take the fields of a
+/// batch job, `inspect` an `input`; each also takes a `passphrase`, needed
+/// to unlock, and a `keyfile`. Keys are derived once per passphrase and
+/// container for as long as the server runs.
+///
+/// `derive_key` only stretches a passphrase, for a salt or a new one, so
+/// that `encrypt` and `decrypt` can be given the resulting `derived_key`
+/// in its place. The `privileged-helper` takes nothing else, so that the
+/// passphrase never reaches it.
+fn serve_call(
+    method: &str,
+    mut params: serde_json::Value,
+    keys: &kdf::KeyCaches,
+    privileged: bool,
+) -> Result<serde_json::Value, serve::RpcError> {
+    let methods: &[&str] = if privileged {
+        &["encrypt", "decrypt", "inspect"]
+    } else {
+        &["encrypt", "decrypt", "inspect", "derive_key"]
+    };
+    if !methods.contains(&method) {
+        return Err(serve::RpcError::method_not_found(method));
+    }
+    let passphrase = take_param(&mut params, "passphrase")?;
+    let keyfile = take_param(&mut params, "keyfile")?;
+    if privileged && (passphrase.is_some() || keyfile.is_some()) {
+        let message = "The privileged helper takes a derived_key, never a passphrase";
+        return Err(serve::RpcError::invalid_params(message));
+    }
+    let strength = passphrase
+        .as_deref()
+        .filter(|_| method == "encrypt")
+        .map(|passphrase| strength::estimate(passphrase.as_bytes()));
+    let key_material = passphrase
+        .map(|passphrase| build_key_material(passphrase.as_bytes(), &keyfile))
+        .transpose()
+        .map_err(|msg| progress::ErrorEvent::new(ErrorCode::InternalError, &msg))
+        .map_err(|event| serve::RpcError::from((event, 10)))?;
+
+    if method == "inspect" {
+        let input = take_param(&mut params, "input")?;
+        let input = input.ok_or_else(|| serve::RpcError::invalid_params("no input"))?;
+        let opts = inspect::InspectOptions {
+            input_path: input,
+            passphrase: key_material,
+        };
+        let report = inspect::inspect(&opts).map_err(decrypt_error_event)?;
+        return serde_json::to_value(report).map_err(|e| {
+            let message = format!("Failed to serialize report: {}", e);
+            let event = progress::ErrorEvent::new(ErrorCode::InternalError, &message);
+            serve::RpcError::from((event, 10))
+        });
+    }
+
+    if method == "derive_key" {
+        let key_material =
+            key_material.ok_or_else(|| serve::RpcError::invalid_params("no passphrase"))?;
+        let request = serde_json::from_value(params)
+            .map_err(|e| serve::RpcError::invalid_params(&e.to_string()))?;
+        let derived = kdf::DerivedKey::derive(&key_material, request).map_err(|msg| {
+            let event = progress::ErrorEvent::new(ErrorCode::InternalError, &msg);
+            serve::RpcError::from((event, 10))
+        })?;
+        return Ok(serde_json::json!(derived));
+    }
+
+    let derived_key = params
+        .as_object_mut()
+        .and_then(|params| params.remove("derived_key"))
+        .map(serde_json::from_value::<kdf::DerivedKey>)
+        .transpose()
+        .map_err(|e| serve::RpcError::invalid_params(&format!("derived_key: {}", e)))?;
+    let (key_material, keys) = match (key_material, &derived_key) {
+        (Some(key_material), None) => {
+            let keys = keys.get(&key_material);
+            (key_material, keys)
+        }
+        // Never stretched, the sealed cache holding the only key served
+        (None, Some(derived_key)) => {
+            let keys = derived_key.key_cache().map_err(|e| {
+                serve::RpcError::invalid_params(&format!("derived_key: {}", e))
+            })?;
+            (Vec::new(), keys)
+        }
+        (Some(_), Some(_)) => {
+            let message = "passphrase and derived_key exclude each other";
+            return Err(serve::RpcError::invalid_params(message));
+        }
+        (None, None) => return Err(serve::RpcError::invalid_params("no passphrase")),
+    };
+    match jobs::parse_job(method, params).map_err(|e| serve::RpcError::invalid_params(&e))? {
+        jobs::Job::Encrypt(mut job) => {
+            if let Some(strength) = strength {
+                progress::emit_event(&strength);
+                strength::check(&strength)
+                    .map_err(|event| (event, progress::EXIT_WEAK_PASSPHRASE))?;
+            }
+            if let Some(derived_key) = &derived_key {
+                job.time_cost = derived_key.time_cost;
+                job.memory_cost = derived_key.memory_cost;
+                job.parallelism = derived_key.parallelism;
+            }
+            let output = encrypt_output_path(&job.input, job.output.clone(), &job.naming())
+                .map_err(|e| decrypt_error_event(decrypt::naming_error(e)))?;
+            let opts = job.options(output, key_material, keys);
+            encrypt::encrypt(&opts).map_err(encrypt_error_event)?;
+        }
+        jobs::Job::Decrypt(job) => {
+            let opts = job.options(key_material, keys);
+            decrypt::decrypt(&opts).map_err(decrypt_error_event)?;
+        }
+    }
+    Ok(serde_json::json!({}))
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if cli.version_json {
+        if cli.command.is_some() {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "--version-json cannot be used with a subcommand",
+                )
+                .exit();
+        }
+        match serde_json::to_string(&capabilities::version_info()) {
+            Ok(out) => println!("{}", out),
+            Err(e) => progress::emit_error_and_exit(
+                ErrorCode::InternalError,
+                &format!("Failed to serialize version: {}", e),
+                10,
+            ),
+        }
+        std::process::exit(0);
+    }
+    let Some(command) = cli.command else {
+        Cli::command()
+            .error(clap::error::ErrorKind::MissingSubcommand, "a subcommand is required")
+            .exit();
+    };
+    // It changes the environment, so before any thread is spawned
+    if let Some(name) = cli.passphrase_from_credential {
+        passphrase::set_credential(name);
+    }
+    cancel::install();
+    if let Some(seconds) = cli.timeout {
+        cancel::set_timeout(std::time::Duration::from_secs(seconds));
+    }
+    if let Some(interval) = cli.progress_interval {
+        progress::set_progress_interval(interval);
+    }
+    // A person at a terminal gets text; a frontend, or a `serve` client,
+    // the protocol
+    let human = !cli.json
+        && cli.progress_fd.is_none()
+        && std::io::stdout().is_terminal()
+        && !matches!(command, Commands::Serve { .. } | Commands::PrivilegedHelper);
+    progress::set_event_format(cli.event_format.unwrap_or(if human {
+        progress::EventFormat::Human
+    } else {
+        progress::EventFormat::Json
+    }));
+    passphrase::set_format(cli.passphrase_format);
+    passphrase::set_encoding(cli.passphrase_encoding);
+    strength::set_minimum(cli.min_strength);
+    calibration::enable();
+    if let Some(secs) = cli.cache_key {
+        keyring::set_lifetime(std::time::Duration::from_secs(secs));
+    }
+    if let Err(e) = log::init(cli.verbose, cli.log_file.as_deref(), cli.log_target) {
+        let msg = match (cli.log_file.as_deref(), cli.log_target) {
+            (Some(path), _) => format!("Cannot open log file {}: {}", path.display(), e),
+            (None, log::LogTarget::Syslog) => format!("Cannot log to syslog: {}", e),
+            (None, _) => format!("Cannot log to journald: {}", e),
+        };
+        progress::emit_error_and_exit(ErrorCode::InternalError, &msg, 10);
+    }
+    if let Some(path) = cli.audit_log.as_deref() {
+        if let Err(e) = audit::init(path) {
+            let msg = format!("Cannot open audit log {}: {}", path.display(), e);
+            progress::emit_error_and_exit(ErrorCode::InternalError, &msg, 10);
+        }
+    }
+    log::debug(|| {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        format!("gtkrypt-crypto {} {}", env!("CARGO_PKG_VERSION"), args.join(" "))
+    });
+    if let Some(fd) = cli.progress_fd {
+        if let Err(e) = progress::set_progress_fd(fd) {
+            let msg = format!("Cannot write progress to file descriptor {}: {}", fd, e);
+            progress::emit_error_and_exit(ErrorCode::InternalError, &msg, 10);
+        }
+    }
+
+    match command {
+        Commands::Encrypt {
+            input,
+            files,
+            input_fd,
+            output,
+            output_fd,
+            mut output_template,
+            no_clobber,
+            force,
+            in_place,
+            delete_input,
+            shred_input,
+            shred_passes,
+            mut time_cost,
+            target_time,
+            memory_cost,
+            parallelism,
+            store_filename,
+            encrypt_metadata,
+            comment,
+            meta,
+            pad,
+            split_size,
+            parity,
+            verify_after_encrypt,
+            include,
+            mut exclude,
+            exclude_from,
+            symlinks,
+            jobs,
+            direct_io,
+            drop_cache,
+            io_buffer_size,
+            crypto_backend,
+            temp_dir,
+            clean_temp,
+            allow_double,
+            keyfile,
+        } => {
+            if let Some(target) = target_time {
+                time_cost = kdf::time_cost_for(target, memory_cost, parallelism);
+                log::debug(|| format!("Argon2id time cost {} to take {:?}", time_cost, target));
+            }
+            let mut user_metadata = BTreeMap::new();
+            for (key, value) in meta {
+                if user_metadata.insert(key.clone(), value).is_some() {
+                    progress::emit_error_and_exit(
+                        ErrorCode::InternalError,
+                        &format!("Duplicate metadata key '{}'", key),
+                        10,
+                    );
+                }
+            }
 
-        /// Store the original filename in the container header
-        #[arg(long, default_value_t = false)]
-        store_filename: bool,
+            if let Some(path) = exclude_from {
+                match read_pattern_file(&path) {
+                    Ok(patterns) => exclude.extend(patterns),
+                    Err(msg) => progress::emit_error_and_exit(ErrorCode::InternalError, &msg, 10),
+                }
+            }
 
-        /// Optional keyfile path for two-factor encryption
-        #[arg(long)]
-        keyfile: Option<String>,
-    },
+            // Positional inputs with no output option go next to themselves
+            if !files.is_empty() && output.is_none() && output_fd.is_none() && !in_place {
+                output_template.get_or_insert_with(|| "{name}.gtkrypt".to_string());
+            }
+            let input = match input_fd {
+                Some(fd) => vec![input_fd_path(fd)],
+                None if files.is_empty() => expand_inputs(input),
+                None => expand_inputs(files),
+            };
+            let output_fd = output_fd.map(|fd| stage_output_fd(fd, temp_dir.as_deref()));
+            let output = output_fd.as_ref().map(output_fd_path).or(output);
+            if input.len() > 1 && output.is_some() {
+                progress::emit_error_and_exit(
+                    ErrorCode::InternalError,
+                    "--output names a single file; use --output-template or --in-place to \
+                     encrypt several inputs",
+                    10,
+                );
+            }
+            let naming = naming::OutputNaming {
+                template: output_template,
+                conflict: conflict_policy(force, no_clobber),
+            };
+            // Read once the first output is settled, so a refused output
+            // needs no passphrase
+            let key_material = std::sync::OnceLock::new();
+            let keys = kdf::KeyCache::default();
 
-    /// Decrypt a file
-    Decrypt {
-        /// Path to the input (encrypted) file
-        #[arg(long)]
-        input: String,
+            let result = batch::run(&input, jobs as usize, |input| {
+                let output = if in_place {
+                    input.to_string()
+                } else {
+                    encrypt_output_path(input, output.clone(), &naming)
+                        .map_err(|e| decrypt_error_event(decrypt::naming_error(e)))?
+                };
+                if clean_temp {
+                    clean_stale_temps(Path::new(&output).parent(), temp_dir.as_deref());
+                }
 
-        /// Path to the output (decrypted) file
-        #[arg(long)]
-        output: String,
+                let opts = encrypt::EncryptOptions {
+                    input_path: input.to_string(),
+                    output_path: output,
+                    passphrase: key_material
+                        .get_or_init(|| {
+                            let key_material = read_new_key_material(&keyfile);
+                            control::listen();
+                            key_material
+                        })
+                        .clone(),
+                    time_cost,
+                    memory_cost_kib: memory_cost,
+                    parallelism,
+                    store_filename,
+                    encrypt_metadata,
+                    comment: comment.clone(),
+                    user_metadata: user_metadata.clone(),
+                    pad,
+                    split_size,
+                    parity,
+                    verify: verify_after_encrypt,
+                    include: include.clone(),
+                    exclude: exclude.clone(),
+                    symlinks,
+                    in_place,
+                    delete_input: delete_input || shred_input,
+                    shred_passes: if shred_input { shred_passes } else { 0 },
+                    keys: keys.clone(),
+                    direct_io,
+                    drop_cache,
+                    io_buffer_size,
+                    backend: crypto_backend,
+                    temp_dir: temp_dir.clone(),
+                    allow_double,
+                };
+                encrypt::encrypt(&opts).map_err(encrypt_error_event)
+            });
 
-        /// Optional keyfile path for two-factor decryption
-        #[arg(long)]
-        keyfile: Option<String>,
-    },
-}
+            match result {
+                Ok(()) => {
+                    if let Some(staged) = output_fd {
+                        deliver_output_fd(staged);
+                    }
+                    std::process::exit(0);
+                }
+                Err((event, code)) => {
+                    // Exiting skips destructors, which would remove it
+                    drop(output_fd);
+                    progress::emit_error_event_and_exit(event, code)
+                }
+            }
+        }
 
-/// Read a single line passphrase from stdin.
-fn read_passphrase() -> Result<String, String> {
-    let stdin = std::io::stdin();
-    let mut line = String::new();
-    let mut handle = stdin.lock();
+        Commands::Decrypt {
+            input,
+            files,
+            input_fd,
+            output,
+            output_fd,
+            output_dir,
+            recursive,
+            output_template,
+            no_clobber,
+            force,
+            in_place,
+            offset,
+            length,
+            scan_all,
+            keep_partial,
+            no_restore_times,
+            restore_owner,
+            no_restore_permissions,
+            mode,
+            jobs,
+            threads,
+            direct_io,
+            drop_cache,
+            io_buffer_size,
+            crypto_backend,
+            max_kdf_memory,
+            max_kdf_time,
+            temp_dir,
+            clean_temp,
+            keyfile,
+        } => {
+            let input = if files.is_empty() { input } else { files };
+            let input = match input_fd {
+                Some(fd) => vec![input_fd_path(fd)],
+                None if recursive => input,
+                None => expand_inputs(input),
+            };
+            let output_fd = output_fd.map(|fd| stage_output_fd(fd, temp_dir.as_deref()));
+            let output = output_fd.as_ref().map(output_fd_path).or(output);
+            if input.len() > 1 && output.is_some() {
+                progress::emit_error_and_exit(
+                    ErrorCode::InternalError,
+                    "--output names a single file; use --output-dir or --in-place to decrypt \
+                     several inputs",
+                    10,
+                );
+            }
+            // Recursively, the inputs are the containers below the one
+            // directory given
+            let root = recursive.then(|| PathBuf::from(&input[0]));
+            let input = match root {
+                Some(_) if input.len() > 1 => progress::emit_error_and_exit(
+                    ErrorCode::InternalError,
+                    "--recursive takes a single input directory",
+                    10,
+                ),
+                Some(ref root) => match batch::find_containers(root) {
+                    Ok(found) => found,
+                    Err(e) => progress::emit_error_and_exit(
+                        ErrorCode::InternalError,
+                        &format!("Failed to read input directory: {}", e),
+                        10,
+                    ),
+                },
+                None => input,
+            };
+            let naming = naming::OutputNaming {
+                template: output_template,
+                conflict: conflict_policy(force, no_clobber),
+            };
+            let key_material = read_key_material(&keyfile);
+            control::listen();
+            let keys = kdf::KeyCache::default();
 
-    handle
-        .read_line(&mut line)
-        .map_err(|e| format!("Failed to read passphrase from stdin: {}", e))?;
+            let decrypt_one = |input: &str| {
+                let output_path = match root {
+                    Some(ref root) => recursive_output_dir(root, input, output_dir.as_deref())
+                        .map_err(decrypt_error_event)?,
+                    None => match output.clone().or_else(|| output_dir.clone()) {
+                        Some(output) => output,
+                        None if in_place => String::new(),
+                        // Positional inputs with no output option
+                        None => decrypt_output_path(input).map_err(decrypt_error_event)?,
+                    },
+                };
+                if clean_temp {
+                    let dir = if output_dir.is_some() || root.is_some() {
+                        Some(Path::new(&output_path))
+                    } else if in_place {
+                        Path::new(input).parent()
+                    } else {
+                        Path::new(&output_path).parent()
+                    };
+                    clean_stale_temps(dir, temp_dir.as_deref());
+                }
+                let opts = decrypt::DecryptOptions {
+                    input_path: input.to_string(),
+                    output_dir: output_dir.is_some(),
+                    output_path,
+                    in_place,
+                    naming: naming.clone(),
+                    passphrase: key_material.clone(),
+                    offset,
+                    length,
+                    scan_all,
+                    keep_partial,
+                    restore: decrypt::RestoreOptions {
+                        times: !no_restore_times,
+                        owner: restore_owner,
+                        permissions: permission_policy(no_restore_permissions, mode),
+                    },
+                    keys: keys.clone(),
+                    threads,
+                    direct_io,
+                    drop_cache,
+                    io_buffer_size,
+                    backend: crypto_backend,
+                    kdf_limits: kdf::KdfLimits {
+                        max_memory_kib: max_kdf_memory,
+                        max_time_cost: max_kdf_time,
+                    },
+                    temp_dir: temp_dir.clone(),
+                };
+                decrypt::decrypt(&opts).map_err(decrypt_error_event)
+            };
+            let result = if root.is_some() {
+                batch::run_all(&input, jobs as usize, |_, input| decrypt_one(input))
+            } else {
+                batch::run(&input, jobs as usize, decrypt_one)
+            };
 
-    // Remove trailing newline
-    if line.ends_with('\n') {
-        line.pop();
-        if line.ends_with('\r') {
-            line.pop();
+            match result {
+                Ok(()) => {
+                    if let Some(staged) = output_fd {
+                        deliver_output_fd(staged);
+                    }
+                    std::process::exit(0);
+                }
+                Err((event, code)) => {
+                    // Exiting skips destructors, which would remove it
+                    drop(output_fd);
+                    progress::emit_error_event_and_exit(event, code)
+                }
+            }
         }
-    }
 
-    if line.is_empty() {
-        return Err("Passphrase is empty".to_string());
-    }
+        Commands::Watch {
+            dir,
+            output_dir,
+            settle_ms,
+            no_clobber,
+            force,
+            delete_input,
+            time_cost,
+            memory_cost,
+            parallelism,
+            store_filename,
+            encrypt_metadata,
+            keyfile,
+        } => {
+            let key_material = read_new_key_material(&keyfile);
+            let naming = naming::OutputNaming {
+                template: None,
+                conflict: conflict_policy(force, no_clobber),
+            };
+            // Every file shares the one key derivation
+            let keys = kdf::KeyCache::default();
 
-    Ok(line)
-}
+            let result = watch::watch(
+                Path::new(&dir),
+                std::time::Duration::from_millis(settle_ms),
+                |input| {
+                    let input_path = Path::new(input);
+                    let name = input_path.file_name().unwrap_or_default().to_string_lossy();
+                    let (output, _) = naming
+                        .resolve(input_path, Path::new(&output_dir), &format!("{}.gtkrypt", name))
+                        .map_err(|e| decrypt_error_event(decrypt::naming_error(e)))?;
+                    progress::emit_output_path(&output);
 
-/// Read a keyfile (up to 64 KiB) and return its SHA-256 hash.
-fn read_keyfile(path: &str) -> Result<[u8; 32], String> {
-    use std::io::Read;
+                    let opts = encrypt::EncryptOptions {
+                        input_path: input.to_string(),
+                        output_path: output.to_string_lossy().into_owned(),
+                        passphrase: key_material.clone(),
+                        time_cost,
+                        memory_cost_kib: memory_cost,
+                        parallelism,
+                        store_filename,
+                        encrypt_metadata,
+                        delete_input,
+                        keys: keys.clone(),
+                        ..Default::default()
+                    };
+                    encrypt::encrypt(&opts).map_err(encrypt_error_event)
+                },
+            );
 
-    const MAX_KEYFILE_SIZE: usize = 64 * 1024; // 64 KiB
+            if let Err(e) = result {
+                progress::emit_error_and_exit(
+                    ErrorCode::InternalError,
+                    &format!("Cannot watch '{}': {}", dir, e),
+                    10,
+                );
+            }
+        }
 
-    let mut file = std::fs::File::open(path)
-        .map_err(|e| format!("Failed to open keyfile '{}': {}", path, e))?;
+        Commands::Batch {
+            jobs_file,
+            jobs: parallel,
+            keyfile,
+        } => {
+            // The manifest, which may come after the passphrase, can hold
+            // encrypt jobs
+            let passphrase = read_passphrase("Passphrase", true);
+            let jobs = match jobs::read_manifest(&jobs_file) {
+                Ok(jobs) => jobs,
+                Err(msg) => progress::emit_error_and_exit(ErrorCode::InternalError, &msg, 10),
+            };
+            if let Ok(passphrase) = &passphrase {
+                if jobs.iter().any(|job| matches!(job, jobs::Job::Encrypt(_))) {
+                    check_strength(passphrase);
+                }
+            }
+            let key_material = key_material_from(passphrase, &keyfile);
+            // A manifest from stdin takes the rest of it
+            if jobs_file != "-" {
+                control::listen();
+            }
+            let inputs: Vec<&str> = jobs.iter().map(jobs::Job::input).collect();
+            let keys = kdf::KeyCache::default();
 
-    let mut buf = vec![0u8; MAX_KEYFILE_SIZE];
-    let mut total = 0;
-    loop {
-        match file.read(&mut buf[total..]) {
-            Ok(0) => break,
-            Ok(n) => {
-                total += n;
-                if total >= MAX_KEYFILE_SIZE {
-                    break;
+            let result = batch::run_all(&inputs, parallel as usize, |index, _| {
+                match &jobs[index] {
+                    jobs::Job::Encrypt(job) => {
+                        let output =
+                            encrypt_output_path(&job.input, job.output.clone(), &job.naming())
+                                .map_err(|e| decrypt_error_event(decrypt::naming_error(e)))?;
+                        let opts = job.options(output, key_material.clone(), keys.clone());
+                        encrypt::encrypt(&opts).map_err(encrypt_error_event)
+                    }
+                    jobs::Job::Decrypt(job) => {
+                        let opts = job.options(key_material.clone(), keys.clone());
+                        decrypt::decrypt(&opts).map_err(decrypt_error_event)
+                    }
                 }
+            });
+
+            match result {
+                Ok(()) => {
+                    std::process::exit(0);
+                }
+                Err((event, code)) => progress::emit_error_event_and_exit(event, code),
             }
-            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
-            Err(e) => return Err(format!("Failed to read keyfile '{}': {}", path, e)),
         }
-    }
-
-    let mut hasher = Sha256::new();
-    hasher.update(&buf[..total]);
-    Ok(hasher.finalize().into())
-}
 
-/// Combine passphrase with optional keyfile hash into key material.
-/// If keyfile is provided: passphrase_bytes || SHA-256(keyfile_bytes)
-/// If no keyfile: passphrase_bytes
-fn build_key_material(passphrase: &str, keyfile_path: &Option<String>) -> Result<Vec<u8>, String> {
-    let mut material = passphrase.as_bytes().to_vec();
+        Commands::CheckPassphrase { input, keyfile } => {
+            let key_material = read_key_material(&keyfile);
 
-    if let Some(path) = keyfile_path {
-        let keyfile_hash = read_keyfile(path)?;
-        material.extend_from_slice(&keyfile_hash);
-    }
+            match decrypt::check_passphrase(&input, &key_material) {
+                Ok(()) => {
+                    std::process::exit(0);
+                }
+                Err(e) => exit_with_decrypt_error(e),
+            }
+        }
 
-    Ok(material)
-}
+        Commands::Upgrade {
+            input,
+            output,
+            time_cost,
+            memory_cost,
+            parallelism,
+            encrypt_metadata,
+            keyfile,
+        } => {
+            let key_material = read_key_material(&keyfile);
 
-fn main() {
-    let cli = Cli::parse();
+            let opts = upgrade::UpgradeOptions {
+                input_path: input,
+                output_path: output,
+                passphrase: key_material,
+                time_cost,
+                memory_cost_kib: memory_cost,
+                parallelism,
+                encrypt_metadata,
+            };
 
-    // Read passphrase from stdin
-    let passphrase = match read_passphrase() {
-        Ok(p) => p,
-        Err(msg) => {
-            progress::emit_error_and_exit("internal_error", &msg, 10);
+            match upgrade::upgrade(&opts) {
+                Ok(()) => {
+                    std::process::exit(0);
+                }
+                Err(e) => exit_with_decrypt_error(e),
+            }
         }
-    };
 
-    match cli.command {
-        Commands::Encrypt {
+        Commands::ReEncrypt {
             input,
             output,
             time_cost,
             memory_cost,
             parallelism,
-            store_filename,
+            new_passphrase,
             keyfile,
+            new_keyfile,
         } => {
+            let passphrase = match read_passphrase("Current passphrase", false) {
+                Ok(p) => p,
+                Err(msg) => progress::emit_error_and_exit(ErrorCode::InternalError, &msg, 10),
+            };
+            let new_passphrase = if new_passphrase {
+                match read_passphrase("New passphrase", true) {
+                    Ok(p) => {
+                        check_strength(&p);
+                        Some(p)
+                    }
+                    Err(msg) => progress::emit_error_and_exit(ErrorCode::InternalError, &msg, 10),
+                }
+            } else {
+                None
+            };
+
+            // The new key material differs only if the passphrase or the
+            // keyfile changes
+            let new_material = if new_passphrase.is_some() || new_keyfile.is_some() {
+                let new_keyfile = new_keyfile.or_else(|| keyfile.clone());
+                let new_passphrase = new_passphrase.as_deref().unwrap_or(&passphrase);
+                match build_key_material(new_passphrase, &new_keyfile) {
+                    Ok(m) => Some(m),
+                    Err(msg) => progress::emit_error_and_exit(ErrorCode::InternalError, &msg, 10),
+                }
+            } else {
+                None
+            };
             let key_material = match build_key_material(&passphrase, &keyfile) {
                 Ok(m) => m,
-                Err(msg) => {
-                    progress::emit_error_and_exit("internal_error", &msg, 10);
-                }
+                Err(msg) => progress::emit_error_and_exit(ErrorCode::InternalError, &msg, 10),
             };
 
-            let opts = encrypt::EncryptOptions {
+            let opts = reencrypt::ReencryptOptions {
                 input_path: input,
                 output_path: output,
                 passphrase: key_material,
+                new_passphrase: new_material,
                 time_cost,
                 memory_cost_kib: memory_cost,
                 parallelism,
-                store_filename,
             };
 
-            match encrypt::encrypt(&opts) {
+            match reencrypt::reencrypt(&opts) {
                 Ok(()) => {
                     std::process::exit(0);
                 }
-                Err(EncryptError::Permission(msg)) => {
-                    progress::emit_error_and_exit("permission_error", &msg, 3);
-                }
-                Err(EncryptError::Internal(msg)) => {
-                    progress::emit_error_and_exit("internal_error", &msg, 10);
+                Err(e) => exit_with_decrypt_error(e),
+            }
+        }
+
+        Commands::EditHeader {
+            input,
+            set_filename,
+            set_comment,
+            keyfile,
+        } => {
+            if set_filename.is_none() && set_comment.is_none() {
+                progress::emit_error_and_exit(
+                    ErrorCode::InternalError,
+                    "Nothing to edit: pass --set-filename or --set-comment",
+                    10,
+                );
+            }
+            let key_material = read_key_material(&keyfile);
+
+            let opts = edit::EditOptions {
+                input_path: input,
+                passphrase: key_material,
+                filename: set_filename,
+                comment: set_comment,
+            };
+
+            match edit::edit_header(&opts) {
+                Ok(()) => {
+                    std::process::exit(0);
                 }
+                Err(e) => exit_with_decrypt_error(e),
             }
         }
 
-        Commands::Decrypt { input, output, keyfile } => {
-            let key_material = match build_key_material(&passphrase, &keyfile) {
-                Ok(m) => m,
-                Err(msg) => {
-                    progress::emit_error_and_exit("internal_error", &msg, 10);
+        Commands::Repair { input } => match parity::repair(&input) {
+            Ok(report) => {
+                match serde_json::to_string(&report) {
+                    Ok(out) => println!("{}", out),
+                    Err(e) => progress::emit_error_and_exit(
+                        ErrorCode::InternalError,
+                        &format!("Failed to serialize report: {}", e),
+                        10,
+                    ),
+                }
+                std::process::exit(0);
+            }
+            Err(e) => exit_with_decrypt_error(e),
+        },
+
+        Commands::VerifyAuditLog { input } => match audit::verify(&input) {
+            Ok(report) => {
+                match serde_json::to_string(&report) {
+                    Ok(out) => println!("{}", out),
+                    Err(e) => progress::emit_error_and_exit(
+                        ErrorCode::InternalError,
+                        &format!("Failed to serialize report: {}", e),
+                        10,
+                    ),
+                }
+                std::process::exit(0);
+            }
+            Err(e) => exit_with_decrypt_error(e),
+        },
+
+        Commands::List {
+            input,
+            keyfile,
+        } => {
+            let key_material = read_key_material(&keyfile);
+
+            match archive::list(&input, &key_material) {
+                Ok(entries) => {
+                    if cli.json {
+                        match serde_json::to_string(&entries) {
+                            Ok(out) => println!("{}", out),
+                            Err(e) => progress::emit_error_and_exit(
+                                ErrorCode::InternalError,
+                                &format!("Failed to serialize listing: {}", e),
+                                10,
+                            ),
+                        }
+                    } else {
+                        println!("{}", archive::listing_text(&entries));
+                    }
+                    std::process::exit(0);
                 }
+                Err(e) => exit_with_decrypt_error(e),
+            }
+        }
+
+        Commands::Extract {
+            input,
+            output_dir,
+            entry,
+            no_restore_times,
+            restore_owner,
+            no_restore_permissions,
+            mode,
+            keyfile,
+        } => {
+            let key_material = read_key_material(&keyfile);
+            let restore = decrypt::RestoreOptions {
+                times: !no_restore_times,
+                owner: restore_owner,
+                permissions: permission_policy(no_restore_permissions, mode),
             };
 
-            let opts = decrypt::DecryptOptions {
-                input_path: input,
-                output_path: output,
-                passphrase: key_material,
+            match archive::extract_entries(&input, &key_material, &output_dir, &entry, restore) {
+                Ok(()) => {
+                    std::process::exit(0);
+                }
+                Err(e) => exit_with_decrypt_error(e),
+            }
+        }
+
+        Commands::Header { action } => {
+            let result = match action {
+                HeaderAction::Backup { input, output } => {
+                    backup::backup_header(&input, &output).map(|_| ())
+                }
+                HeaderAction::Restore { backup, target } => {
+                    backup::restore_header(&backup, &target)
+                }
             };
 
-            match decrypt::decrypt(&opts) {
+            match result {
                 Ok(()) => {
                     std::process::exit(0);
                 }
-                Err(DecryptError::WrongPassphrase(msg)) => {
-                    progress::emit_error_and_exit("wrong_passphrase", &msg, 1);
+                Err(e) => exit_with_decrypt_error(e),
+            }
+        }
+
+        Commands::Bench {
+            cipher,
+            duration_ms,
+        } => match bench::run(cipher, std::time::Duration::from_millis(duration_ms)) {
+            Ok(report) => {
+                match serde_json::to_string(&report) {
+                    Ok(out) => println!("{}", out),
+                    Err(e) => progress::emit_error_and_exit(
+                        ErrorCode::InternalError,
+                        &format!("Failed to serialize report: {}", e),
+                        10,
+                    ),
+                }
+                std::process::exit(0);
+            }
+            Err(msg) => progress::emit_error_and_exit(ErrorCode::InternalError, &msg, 10),
+        },
+
+        Commands::Estimate {
+            input,
+            threads,
+            crypto_backend,
+        } => match estimate::estimate(&input, threads, crypto_backend) {
+            Ok(estimate) => {
+                match serde_json::to_string(&estimate) {
+                    Ok(out) => println!("{}", out),
+                    Err(e) => progress::emit_error_and_exit(
+                        ErrorCode::InternalError,
+                        &format!("Failed to serialize estimate: {}", e),
+                        10,
+                    ),
                 }
-                Err(DecryptError::CorruptFile(msg)) => {
-                    progress::emit_error_and_exit("corrupt_file", &msg, 2);
+                std::process::exit(0);
+            }
+            Err(e) => exit_with_decrypt_error(e),
+        },
+
+        Commands::Stats {
+            files,
+            unlock,
+            keyfile,
+        } => {
+            let passphrase = unlock.then(|| read_key_material(&keyfile));
+            match stats::stats(&files, passphrase.as_deref()) {
+                Ok(report) => {
+                    if cli.json {
+                        match serde_json::to_string(&report) {
+                            Ok(out) => println!("{}", out),
+                            Err(e) => progress::emit_error_and_exit(
+                                ErrorCode::InternalError,
+                                &format!("Failed to serialize report: {}", e),
+                                10,
+                            ),
+                        }
+                    } else {
+                        println!("{}", report.to_text());
+                    }
+                    std::process::exit(0);
                 }
-                Err(DecryptError::Permission(msg)) => {
-                    progress::emit_error_and_exit("permission_error", &msg, 3);
+                Err(e) => exit_with_decrypt_error(e),
+            }
+        }
+
+        Commands::Cleanup { dir, min_age_secs } => {
+            let min_age = std::time::Duration::from_secs(min_age_secs);
+            for dir in dir {
+                match temp::sweep(&dir, min_age) {
+                    Ok(removed) => {
+                        removed.iter().for_each(|path| progress::emit_temp_removed(path))
+                    }
+                    Err(e) => {
+                        let (code, exit_code) =
+                            if e.kind() == std::io::ErrorKind::PermissionDenied {
+                                (ErrorCode::PermissionError, 3)
+                            } else {
+                                (ErrorCode::InternalError, 10)
+                            };
+                        let message = format!("Cannot clean {}: {}", dir.display(), e);
+                        let path = dir.to_string_lossy().into_owned();
+                        let event = progress::ErrorEvent {
+                            details: Box::new(progress::ErrorDetails::io(&dir, &e)),
+                            ..progress::ErrorEvent::with_id(
+                                code,
+                                &message,
+                                "cleanup_failed",
+                                [("path", path.into())],
+                            )
+                        };
+                        progress::emit_error_event_and_exit(event, exit_code)
+                    }
                 }
-                Err(DecryptError::Internal(msg)) => {
-                    progress::emit_error_and_exit("internal_error", &msg, 10);
+            }
+            std::process::exit(0);
+        }
+
+        Commands::Keyfile { output, dpapi } => match keyfile::create(&output, dpapi) {
+            Ok(()) => {
+                progress::emit_output_path(&output);
+                std::process::exit(0);
+            }
+            Err(e) => {
+                let message = format!("Cannot create keyfile {}: {}", output.display(), e);
+                let (code, exit_code) = match e.kind() {
+                    std::io::ErrorKind::AlreadyExists => (ErrorCode::OutputExists, 4),
+                    std::io::ErrorKind::PermissionDenied => (ErrorCode::PermissionError, 3),
+                    _ => (ErrorCode::InternalError, 10),
+                };
+                let event = progress::ErrorEvent {
+                    details: Box::new(progress::ErrorDetails::io(&output, &e)),
+                    output_path: Some(output.to_string_lossy().into_owned()),
+                    ..progress::ErrorEvent::new(code, &message)
+                };
+                progress::emit_error_event_and_exit(event, exit_code)
+            }
+        },
+
+        Commands::Capabilities => {
+            let subcommands = Cli::command()
+                .get_subcommands()
+                .map(|command| command.get_name().to_string())
+                .collect();
+            match serde_json::to_string(&capabilities::capabilities(subcommands)) {
+                Ok(out) => println!("{}", out),
+                Err(e) => progress::emit_error_and_exit(
+                    ErrorCode::InternalError,
+                    &format!("Failed to serialize capabilities: {}", e),
+                    10,
+                ),
+            }
+            std::process::exit(0);
+        }
+
+        Commands::Completions { shell } => {
+            let mut command = Cli::command();
+            let name = command.get_name().to_string();
+            // Generated whole first: the generator panics on write errors
+            let mut script = Vec::new();
+            clap_complete::generate(shell, &mut command, name, &mut script);
+            if let Err(e) = std::io::Write::write_all(&mut std::io::stdout(), &script) {
+                progress::emit_error_and_exit(
+                    ErrorCode::InternalError,
+                    &format!("Failed to write the completion script: {}", e),
+                    10,
+                );
+            }
+            std::process::exit(0);
+        }
+
+        Commands::Manpage { output_dir } => {
+            let written = match &output_dir {
+                Some(dir) => std::fs::create_dir_all(dir)
+                    .and_then(|_| clap_mangen::generate_to(Cli::command(), dir)),
+                None => clap_mangen::Man::new(Cli::command()).render(&mut std::io::stdout()),
+            };
+            if let Err(e) = written {
+                progress::emit_error_and_exit(
+                    ErrorCode::InternalError,
+                    &format!("Failed to write the man page: {}", e),
+                    10,
+                );
+            }
+            std::process::exit(0);
+        }
+
+        Commands::PrivilegedHelper => {
+            let keys = kdf::KeyCaches::default();
+            serve::serve_stdio(|method: &str, params| serve_call(method, params, &keys, true));
+            std::process::exit(0);
+        }
+
+        Commands::Serve { stdio: _, socket } => {
+            // Stretching passphrases as root is what the helper exists to avoid
+            if serve::running_as_root() {
+                progress::emit_error_and_exit(
+                    ErrorCode::PermissionError,
+                    "serve does not run as root; use privileged-helper",
+                    3,
+                );
+            }
+            let keys = kdf::KeyCaches::default();
+            let call = |method: &str, params| serve_call(method, params, &keys, false);
+            let Some(socket) = socket else {
+                serve::serve_stdio(call);
+                std::process::exit(0);
+            };
+            if let Err(e) = serve::serve_socket(&socket, call) {
+                let (code, exit_code) = if e.kind() == std::io::ErrorKind::PermissionDenied {
+                    (ErrorCode::PermissionError, 3)
+                } else {
+                    (ErrorCode::InternalError, 10)
+                };
+                let message = format!("Cannot listen on {}: {}", socket.display(), e);
+                progress::emit_error_and_exit(code, &message, exit_code);
+            }
+            std::process::exit(0);
+        }
+
+        Commands::Inspect {
+            input,
+            unlock,
+            keyfile,
+        } => {
+            let passphrase = if unlock {
+                Some(read_key_material(&keyfile))
+            } else {
+                None
+            };
+
+            let opts = inspect::InspectOptions {
+                input_path: input,
+                passphrase,
+            };
+
+            match inspect::inspect(&opts) {
+                Ok(report) => {
+                    if cli.json {
+                        match serde_json::to_string(&report) {
+                            Ok(out) => println!("{}", out),
+                            Err(e) => progress::emit_error_and_exit(
+                                ErrorCode::InternalError,
+                                &format!("Failed to serialize report: {}", e),
+                                10,
+                            ),
+                        }
+                    } else {
+                        println!("{}", report.to_text());
+                    }
+                    std::process::exit(0);
                 }
+                Err(e) => exit_with_decrypt_error(e),
             }
         }
     }
@@ -246,6 +2513,16 @@ mod tests {
         assert_eq!(line, "my_passphrase");
     }
 
+    #[test]
+    fn test_parse_key_value() {
+        assert_eq!(
+            parse_key_value("owner=alice=admin").unwrap(),
+            ("owner".to_string(), "alice=admin".to_string())
+        );
+        assert_eq!(parse_key_value("empty=").unwrap(), ("empty".to_string(), String::new()));
+        assert!(parse_key_value("novalue").is_err());
+    }
+
     #[test]
     fn test_passphrase_crlf_trimming() {
         let mut line = "my_passphrase\r\n".to_string();
@@ -0,0 +1,808 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use rand::RngCore;
+use serde::Serialize;
+
+use crate::header::{
+    self, ContainerHeader, Extension, EXT_ATIME, EXT_ATTRIBUTES, EXT_BTIME, EXT_COMMENT,
+    EXT_CREATED_AT, EXT_ENCRYPTED_METADATA, EXT_FILENAME, EXT_FILE_SIZE, EXT_MODE, EXT_MTIME,
+    EXT_OWNER, EXT_TOOL_VERSION, EXT_USER_METADATA, MAX_FILENAME_LEN,
+    MAX_USER_METADATA_ENTRIES, MAX_USER_METADATA_KEY_LEN, MAX_USER_METADATA_VALUE_LEN, NONCE_LEN,
+    TAG_LEN,
+};
+use crate::kdf::{self, SUBKEY_METADATA};
+
+/// A file timestamp with nanosecond precision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Timestamp {
+    /// Seconds since the Unix epoch; negative before it.
+    pub secs: i64,
+    pub nanos: u32,
+}
+
+impl Timestamp {
+    /// Encoded length: int64 BE seconds, then uint32 BE nanoseconds.
+    pub const LEN: usize = 12;
+
+    pub fn from_system_time(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(after) => Timestamp {
+                secs: after.as_secs() as i64,
+                nanos: after.subsec_nanos(),
+            },
+            Err(e) => {
+                let before = e.duration();
+                let mut secs = -(before.as_secs() as i64);
+                let mut nanos = before.subsec_nanos();
+                if nanos > 0 {
+                    secs -= 1;
+                    nanos = 1_000_000_000 - nanos;
+                }
+                Timestamp { secs, nanos }
+            }
+        }
+    }
+
+    pub fn to_system_time(self) -> SystemTime {
+        let nanos = Duration::from_nanos(u64::from(self.nanos));
+        if self.secs >= 0 {
+            UNIX_EPOCH + Duration::from_secs(self.secs as u64) + nanos
+        } else {
+            UNIX_EPOCH - Duration::from_secs(self.secs.unsigned_abs()) + nanos
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::LEN] {
+        let mut bytes = [0u8; Self::LEN];
+        bytes[..8].copy_from_slice(&self.secs.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.nanos.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::LEN {
+            return None;
+        }
+        let secs = i64::from_be_bytes(bytes[..8].try_into().unwrap());
+        let nanos = u32::from_be_bytes(bytes[8..].try_into().unwrap());
+        (nanos < 1_000_000_000).then_some(Timestamp { secs, nanos })
+    }
+}
+
+/// Set the modification and access times of an open file, leaving any
+/// that are `None` unchanged.
+pub fn set_file_times(
+    file: &fs::File,
+    mtime: Option<Timestamp>,
+    atime: Option<Timestamp>,
+) -> io::Result<()> {
+    let mut times = fs::FileTimes::new();
+    if let Some(mtime) = mtime {
+        times = times.set_modified(mtime.to_system_time());
+    }
+    if let Some(atime) = atime {
+        times = times.set_accessed(atime.to_system_time());
+    }
+    file.set_times(times)
+}
+
+/// The process umask. Reading it means setting it, so it is put straight
+/// back.
+#[cfg(unix)]
+pub fn umask() -> u32 {
+    // SAFETY: umask cannot fail and only swaps the process file mode mask
+    unsafe {
+        let mask = libc::umask(0o022);
+        libc::umask(mask);
+        mask as u32
+    }
+}
+
+#[cfg(not(unix))]
+pub fn umask() -> u32 {
+    0o022
+}
+
+/// Owner of the input as (uid, gid), where the platform has one.
+#[cfg(unix)]
+pub fn file_owner(metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.uid(), metadata.gid()))
+}
+
+#[cfg(not(unix))]
+pub fn file_owner(_metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    None
+}
+
+/// Give `path` the stored owner, without following a final symlink.
+/// Returns `Ok(false)` when the process lacks the privilege to do so.
+#[cfg(unix)]
+pub fn restore_owner(path: &Path, owner: (u32, u32)) -> io::Result<bool> {
+    match std::os::unix::fs::lchown(path, Some(owner.0), Some(owner.1)) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn restore_owner(_path: &Path, _owner: (u32, u32)) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Windows attribute bits worth carrying: readonly, hidden, system and
+/// archive.
+pub const ATTRIBUTE_MASK: u32 = 0x0000_0027;
+
+/// Windows attributes of the input, where the platform has them.
+#[cfg(windows)]
+pub fn file_attributes(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::windows::fs::MetadataExt;
+    Some(metadata.file_attributes() & ATTRIBUTE_MASK)
+}
+
+#[cfg(not(windows))]
+pub fn file_attributes(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Apply stored Windows attributes to `path`. Other platforms have no
+/// equivalent and leave the file as it is.
+#[cfg(windows)]
+pub fn set_file_attributes(path: &Path, attributes: u32) -> io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetFileAttributesW(name: *const u16) -> u32;
+        fn SetFileAttributesW(name: *const u16, attributes: u32) -> i32;
+    }
+
+    let name: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    // SAFETY: `name` is a NUL-terminated wide string that outlives both calls
+    unsafe {
+        let current = GetFileAttributesW(name.as_ptr());
+        if current == u32::MAX {
+            return Err(io::Error::last_os_error());
+        }
+        let wanted = (current & !ATTRIBUTE_MASK) | (attributes & ATTRIBUTE_MASK);
+        if SetFileAttributesW(name.as_ptr(), wanted) == 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+pub fn set_file_attributes(_path: &Path, _attributes: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Give `path` the mode and owner of the `original` file it replaces.
+/// Returns `Ok(false)` when the owner could not be kept for lack of
+/// privilege; the mode is applied either way.
+pub fn carry_over(original: &fs::Metadata, path: &Path) -> io::Result<bool> {
+    // Before the mode, since changing owner clears setuid and setgid bits
+    let kept = match file_owner(original) {
+        Some(owner) => restore_owner(path, owner)?,
+        None => true,
+    };
+    fs::set_permissions(path, original.permissions())?;
+    Ok(kept)
+}
+
+/// Reduce a stored filename to something safe to create in a chosen
+/// directory: only the part after the last `/` or `\`, without control
+/// characters or surrounding whitespace. `None` when nothing usable is left.
+///
+/// The stored name comes from whoever made the container, so it is passed
+/// through here before being shown or used.
+pub fn sanitize_filename(name: &str) -> Option<String> {
+    let last = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = last.chars().filter(|c| !c.is_control()).collect();
+    let cleaned = cleaned.trim();
+    match cleaned {
+        "" | "." | ".." => None,
+        _ => Some(cleaned.to_string()),
+    }
+}
+
+/// File metadata carried by a container, taken either from the plaintext
+/// header fields or from the encrypted metadata block.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub filename: Option<String>,
+    pub mode: Option<u32>,
+    pub original_file_size: u64,
+    pub comment: Option<String>,
+    /// Arbitrary key/value attributes supplied with `--meta key=value`.
+    pub user_metadata: BTreeMap<String, String>,
+    /// Creation time in seconds since the Unix epoch.
+    pub created_at: Option<u64>,
+    /// Tool that wrote the container, e.g. `gtkrypt-crypto 0.1.0`.
+    pub tool_version: Option<String>,
+    /// Modification, access and birth time of the input.
+    pub mtime: Option<Timestamp>,
+    pub atime: Option<Timestamp>,
+    pub btime: Option<Timestamp>,
+    /// Owner of the input as (uid, gid).
+    pub owner: Option<(u32, u32)>,
+    /// Windows attributes of the input (see [`ATTRIBUTE_MASK`]).
+    pub attributes: Option<u32>,
+}
+
+impl Metadata {
+    /// Metadata stored in the plaintext header: the fixed fields plus any
+    /// plaintext extension records.
+    pub fn from_header(header: &ContainerHeader) -> Result<Self, MetadataError> {
+        let mut metadata = Metadata::from_extensions(&header.extensions)?;
+        metadata.filename = header.filename.clone();
+        metadata.mode = header.mode;
+        metadata.original_file_size = header.original_file_size;
+        Ok(metadata)
+    }
+
+    /// Encode the fields that have no fixed header slot as TLV records.
+    pub fn extra_extensions(&self) -> Vec<Extension> {
+        let mut records = Vec::new();
+        if let Some(ref comment) = self.comment {
+            records.push(Extension {
+                tag: EXT_COMMENT,
+                value: comment.as_bytes().to_vec(),
+            });
+        }
+        if let Some(created_at) = self.created_at {
+            records.push(Extension {
+                tag: EXT_CREATED_AT,
+                value: created_at.to_be_bytes().to_vec(),
+            });
+        }
+        if let Some(ref tool_version) = self.tool_version {
+            records.push(Extension {
+                tag: EXT_TOOL_VERSION,
+                value: tool_version.as_bytes().to_vec(),
+            });
+        }
+        let times = [
+            (EXT_MTIME, self.mtime),
+            (EXT_ATIME, self.atime),
+            (EXT_BTIME, self.btime),
+        ];
+        for (tag, time) in times {
+            if let Some(time) = time {
+                records.push(Extension {
+                    tag,
+                    value: time.to_bytes().to_vec(),
+                });
+            }
+        }
+        if let Some((uid, gid)) = self.owner {
+            let mut value = uid.to_be_bytes().to_vec();
+            value.extend_from_slice(&gid.to_be_bytes());
+            records.push(Extension {
+                tag: EXT_OWNER,
+                value,
+            });
+        }
+        if let Some(attributes) = self.attributes {
+            records.push(Extension {
+                tag: EXT_ATTRIBUTES,
+                value: attributes.to_be_bytes().to_vec(),
+            });
+        }
+        for (key, value) in &self.user_metadata {
+            let mut encoded = Vec::with_capacity(2 + key.len() + value.len());
+            encoded.extend_from_slice(&(key.len() as u16).to_be_bytes());
+            encoded.extend_from_slice(key.as_bytes());
+            encoded.extend_from_slice(value.as_bytes());
+            records.push(Extension {
+                tag: EXT_USER_METADATA,
+                value: encoded,
+            });
+        }
+        records
+    }
+
+    /// Encode all metadata as TLV records for the encrypted metadata block.
+    pub fn to_extensions(&self) -> Vec<Extension> {
+        let mut records = Vec::new();
+        if let Some(ref name) = self.filename {
+            records.push(Extension {
+                tag: EXT_FILENAME,
+                value: name.as_bytes().to_vec(),
+            });
+        }
+        if let Some(mode) = self.mode {
+            records.push(Extension {
+                tag: EXT_MODE,
+                value: mode.to_be_bytes().to_vec(),
+            });
+        }
+        records.push(Extension {
+            tag: EXT_FILE_SIZE,
+            value: self.original_file_size.to_be_bytes().to_vec(),
+        });
+        records.extend(self.extra_extensions());
+        records
+    }
+
+    /// Decode metadata from the TLV records of a decrypted metadata block.
+    pub fn from_extensions(records: &[Extension]) -> Result<Self, MetadataError> {
+        let mut metadata = Metadata::default();
+        for record in records {
+            match record.tag {
+                EXT_FILENAME => {
+                    if record.value.len() > MAX_FILENAME_LEN {
+                        return Err(MetadataError::Malformed(format!(
+                            "Stored filename length {} exceeds maximum of {} bytes",
+                            record.value.len(),
+                            MAX_FILENAME_LEN
+                        )));
+                    }
+                    let name = String::from_utf8(record.value.clone()).map_err(|_| {
+                        MetadataError::Malformed("Stored filename is not valid UTF-8".to_string())
+                    })?;
+                    metadata.filename = Some(name);
+                }
+                EXT_MODE => {
+                    let bytes: [u8; 4] = record.value.as_slice().try_into().map_err(|_| {
+                        MetadataError::Malformed("Invalid mode field length".to_string())
+                    })?;
+                    metadata.mode = Some(u32::from_be_bytes(bytes));
+                }
+                EXT_FILE_SIZE => {
+                    let bytes: [u8; 8] = record.value.as_slice().try_into().map_err(|_| {
+                        MetadataError::Malformed("Invalid file size field length".to_string())
+                    })?;
+                    metadata.original_file_size = u64::from_be_bytes(bytes);
+                }
+                EXT_COMMENT => {
+                    let comment = String::from_utf8(record.value.clone()).map_err(|_| {
+                        MetadataError::Malformed("Comment is not valid UTF-8".to_string())
+                    })?;
+                    metadata.comment = Some(comment);
+                }
+                EXT_CREATED_AT => {
+                    let bytes: [u8; 8] = record.value.as_slice().try_into().map_err(|_| {
+                        MetadataError::Malformed("Invalid creation time field length".to_string())
+                    })?;
+                    metadata.created_at = Some(u64::from_be_bytes(bytes));
+                }
+                EXT_TOOL_VERSION => {
+                    let tool_version = String::from_utf8(record.value.clone()).map_err(|_| {
+                        MetadataError::Malformed("Tool version is not valid UTF-8".to_string())
+                    })?;
+                    metadata.tool_version = Some(tool_version);
+                }
+                EXT_MTIME | EXT_ATIME | EXT_BTIME => {
+                    let time = Timestamp::from_bytes(&record.value).ok_or_else(|| {
+                        MetadataError::Malformed("Invalid timestamp field".to_string())
+                    })?;
+                    match record.tag {
+                        EXT_MTIME => metadata.mtime = Some(time),
+                        EXT_ATIME => metadata.atime = Some(time),
+                        _ => metadata.btime = Some(time),
+                    }
+                }
+                EXT_OWNER => {
+                    let bytes: [u8; 8] = record.value.as_slice().try_into().map_err(|_| {
+                        MetadataError::Malformed("Invalid owner field length".to_string())
+                    })?;
+                    metadata.owner = Some((
+                        u32::from_be_bytes(bytes[..4].try_into().unwrap()),
+                        u32::from_be_bytes(bytes[4..].try_into().unwrap()),
+                    ));
+                }
+                EXT_ATTRIBUTES => {
+                    let bytes: [u8; 4] = record.value.as_slice().try_into().map_err(|_| {
+                        MetadataError::Malformed("Invalid attributes field length".to_string())
+                    })?;
+                    metadata.attributes = Some(u32::from_be_bytes(bytes) & ATTRIBUTE_MASK);
+                }
+                EXT_USER_METADATA => {
+                    let (key, value) = decode_user_metadata(&record.value)?;
+                    metadata.user_metadata.insert(key, value);
+                }
+                _ => {}
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+/// Decode a single `EXT_USER_METADATA` record into its key and value.
+fn decode_user_metadata(data: &[u8]) -> Result<(String, String), MetadataError> {
+    let malformed = || MetadataError::Malformed("Invalid user metadata record".to_string());
+    if data.len() < 2 {
+        return Err(malformed());
+    }
+    let key_len = u16::from_be_bytes([data[0], data[1]]) as usize;
+    if data.len() < 2 + key_len {
+        return Err(malformed());
+    }
+    let key = String::from_utf8(data[2..2 + key_len].to_vec()).map_err(|_| malformed())?;
+    let value = String::from_utf8(data[2 + key_len..].to_vec()).map_err(|_| malformed())?;
+    Ok((key, value))
+}
+
+/// Check user metadata against the size limits and key syntax. Keys may
+/// only contain ASCII letters, digits and `.`, `_`, `-`, `:` so that they
+/// stay usable as machine-readable identifiers.
+pub fn validate_user_metadata(entries: &BTreeMap<String, String>) -> Result<(), String> {
+    if entries.len() > MAX_USER_METADATA_ENTRIES {
+        return Err(format!(
+            "Too many metadata entries: {} exceeds maximum of {}",
+            entries.len(),
+            MAX_USER_METADATA_ENTRIES
+        ));
+    }
+    for (key, value) in entries {
+        if key.is_empty() || key.len() > MAX_USER_METADATA_KEY_LEN {
+            return Err(format!(
+                "Metadata key must be 1 to {} bytes long: '{}'",
+                MAX_USER_METADATA_KEY_LEN, key
+            ));
+        }
+        if !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | ':'))
+        {
+            return Err(format!("Invalid character in metadata key '{}'", key));
+        }
+        if value.len() > MAX_USER_METADATA_VALUE_LEN {
+            return Err(format!(
+                "Metadata value for '{}' too long: {} bytes exceeds maximum of {} bytes",
+                key,
+                value.len(),
+                MAX_USER_METADATA_VALUE_LEN
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Extension tags that carry file metadata, replaced as a unit by `store`.
+const METADATA_TAGS: &[u16] = &[
+    EXT_ENCRYPTED_METADATA,
+    EXT_FILENAME,
+    EXT_MODE,
+    EXT_FILE_SIZE,
+    EXT_COMMENT,
+    EXT_USER_METADATA,
+    EXT_CREATED_AT,
+    EXT_TOOL_VERSION,
+    EXT_MTIME,
+    EXT_ATIME,
+    EXT_BTIME,
+    EXT_OWNER,
+    EXT_ATTRIBUTES,
+];
+
+/// Remove all metadata from a header so it can be stored again, keeping
+/// unrelated extension records.
+pub fn strip(header: &mut ContainerHeader) {
+    header.filename = None;
+    header.mode = None;
+    header.original_file_size = 0;
+    header
+        .extensions
+        .retain(|ext| !METADATA_TAGS.contains(&ext.tag));
+}
+
+/// Store metadata in a header that is being built: either in the plaintext
+/// fields and extensions, or sealed into an encrypted metadata block with
+/// the plaintext fields blanked.
+pub fn store(
+    header: &mut ContainerHeader,
+    metadata: &Metadata,
+    encrypt: bool,
+    master_key: &[u8; 32],
+) -> Result<(), String> {
+    if !encrypt {
+        header.filename = metadata.filename.clone();
+        header.mode = metadata.mode;
+        header.original_file_size = metadata.original_file_size;
+        header.extensions.extend(metadata.extra_extensions());
+        return Ok(());
+    }
+
+    header.filename = None;
+    header.mode = None;
+    header.original_file_size = 0;
+    let prefix = header::encode_header(header);
+    let block = seal(metadata, master_key, header::extract_aad_prefix(&prefix))?;
+    header.extensions.push(Extension {
+        tag: EXT_ENCRYPTED_METADATA,
+        value: block,
+    });
+    Ok(())
+}
+
+/// Encrypt metadata into a block suitable for the `EXT_ENCRYPTED_METADATA`
+/// extension: nonce (12) || ciphertext || tag (16).
+///
+/// The block is bound to its container by using the header prefix (magic
+/// through nonce) as AAD.
+pub fn seal(metadata: &Metadata, master_key: &[u8; 32], aad: &[u8]) -> Result<Vec<u8>, String> {
+    let key = kdf::derive_subkey(master_key, SUBKEY_METADATA);
+    let cipher = Aes256Gcm::new_from_slice(&key)
+        .map_err(|e| format!("Failed to initialize metadata cipher: {}", e))?;
+
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let plaintext = header::encode_extensions(&metadata.to_extensions());
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &plaintext,
+                aad,
+            },
+        )
+        .map_err(|e| format!("Metadata encryption failed: {}", e))?;
+
+    let mut block = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    block.extend_from_slice(&nonce);
+    block.extend_from_slice(&ciphertext);
+    Ok(block)
+}
+
+/// Decrypt and decode an encrypted metadata block.
+pub fn open(block: &[u8], master_key: &[u8; 32], aad: &[u8]) -> Result<Metadata, MetadataError> {
+    if block.len() < NONCE_LEN + TAG_LEN {
+        return Err(MetadataError::Malformed(
+            "Encrypted metadata block is too short".to_string(),
+        ));
+    }
+
+    let key = kdf::derive_subkey(master_key, SUBKEY_METADATA);
+    let cipher = Aes256Gcm::new_from_slice(&key)
+        .map_err(|e| MetadataError::Malformed(format!("Failed to initialize cipher: {}", e)))?;
+
+    let (nonce, ciphertext) = block.split_at(NONCE_LEN);
+    let plaintext = cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| MetadataError::Authentication)?;
+
+    // The plaintext is an extension area: uint32 BE length + TLV records
+    if plaintext.len() < 4
+        || u32::from_be_bytes([plaintext[0], plaintext[1], plaintext[2], plaintext[3]]) as usize
+            != plaintext.len() - 4
+    {
+        return Err(MetadataError::Malformed(
+            "Encrypted metadata has an invalid length".to_string(),
+        ));
+    }
+    let records = header::decode_extensions(&plaintext[4..])
+        .map_err(|e| MetadataError::Malformed(e.to_string()))?;
+
+    Metadata::from_extensions(&records)
+}
+
+/// Resolve the effective metadata of a container: decrypt the metadata
+/// block if the header carries one, otherwise use the plaintext fields.
+pub fn resolve(
+    header: &ContainerHeader,
+    header_bytes: &[u8],
+    master_key: &[u8; 32],
+) -> Result<Metadata, MetadataError> {
+    match header.extension(EXT_ENCRYPTED_METADATA) {
+        Some(block) => open(block, master_key, header::extract_aad_prefix(header_bytes)),
+        None => Metadata::from_header(header),
+    }
+}
+
+/// Errors that can occur when reading container metadata.
+#[derive(Debug)]
+pub enum MetadataError {
+    /// The metadata block failed authentication (wrong key or tampering).
+    Authentication,
+    Malformed(String),
+}
+
+impl std::fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetadataError::Authentication => {
+                write!(f, "Encrypted metadata failed authentication")
+            }
+            MetadataError::Malformed(msg) => write!(f, "Malformed metadata: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::AAD_LENGTH;
+
+    fn sample() -> Metadata {
+        Metadata {
+            filename: Some("tax-return.pdf".to_string()),
+            mode: Some(0o600),
+            original_file_size: 4242,
+            comment: Some("taxes 2023, includes receipts".to_string()),
+            user_metadata: BTreeMap::from([
+                ("backup.job".to_string(), "nightly".to_string()),
+                ("owner".to_string(), "alice=admin".to_string()),
+            ]),
+            created_at: Some(1_700_000_000),
+            tool_version: Some("gtkrypt-crypto 0.1.0".to_string()),
+            mtime: Some(Timestamp {
+                secs: 1_690_000_000,
+                nanos: 123_456_789,
+            }),
+            atime: None,
+            btime: Some(Timestamp {
+                secs: -86_400,
+                nanos: 0,
+            }),
+            owner: Some((1000, 100)),
+            attributes: Some(0x22),
+        }
+    }
+
+    #[test]
+    fn test_seal_open_roundtrip() {
+        let key = [3u8; 32];
+        let aad = [9u8; AAD_LENGTH];
+        let block = seal(&sample(), &key, &aad).unwrap();
+
+        let opened = open(&block, &key, &aad).unwrap();
+        assert_eq!(opened, sample());
+    }
+
+    #[test]
+    fn test_sanitize_filename() {
+        let clean = |name| sanitize_filename(name);
+        assert_eq!(clean("report.pdf").as_deref(), Some("report.pdf"));
+        assert_eq!(clean(".bashrc").as_deref(), Some(".bashrc"));
+        assert_eq!(clean("../../etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(clean("C:\\Windows\\win.ini").as_deref(), Some("win.ini"));
+        assert_eq!(clean("evil\u{1b}[2Jname\n.txt").as_deref(), Some("evil[2Jname.txt"));
+        assert_eq!(clean("dir/.."), None);
+        assert_eq!(clean(" \t"), None);
+    }
+
+    #[test]
+    fn test_sealed_block_hides_filename() {
+        let block = seal(&sample(), &[3u8; 32], &[0u8; AAD_LENGTH]).unwrap();
+        let needle = b"tax-return";
+        assert!(!block.windows(needle.len()).any(|w| w == needle));
+    }
+
+    #[test]
+    fn test_open_with_wrong_key_fails() {
+        let aad = [0u8; AAD_LENGTH];
+        let block = seal(&sample(), &[3u8; 32], &aad).unwrap();
+        assert!(matches!(
+            open(&block, &[4u8; 32], &aad),
+            Err(MetadataError::Authentication)
+        ));
+    }
+
+    #[test]
+    fn test_open_with_different_aad_fails() {
+        let key = [3u8; 32];
+        let block = seal(&sample(), &key, &[0u8; AAD_LENGTH]).unwrap();
+        assert!(matches!(
+            open(&block, &key, &[1u8; AAD_LENGTH]),
+            Err(MetadataError::Authentication)
+        ));
+    }
+
+    #[test]
+    fn test_open_rejects_short_block() {
+        assert!(matches!(
+            open(&[0u8; 10], &[3u8; 32], &[0u8; AAD_LENGTH]),
+            Err(MetadataError::Malformed(_))
+        ));
+    }
+
+    fn blank_header() -> ContainerHeader {
+        ContainerHeader {
+            version: header::VERSION,
+            kdf_id: header::KDF_ID_ARGON2ID,
+            kdf_params: crate::kdf::KdfParams::default(),
+            salt: [1u8; header::SALT_LEN],
+            nonce: [2u8; NONCE_LEN],
+            filename: None,
+            mode: None,
+            original_file_size: 0,
+            ciphertext_length: 0,
+            extensions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_store_plaintext_keeps_comment_in_extensions() {
+        let mut header = blank_header();
+        store(&mut header, &sample(), false, &[3u8; 32]).unwrap();
+        assert_eq!(header.filename.as_deref(), Some("tax-return.pdf"));
+        assert_eq!(
+            header.extension(EXT_COMMENT),
+            Some(&b"taxes 2023, includes receipts"[..])
+        );
+        assert_eq!(Metadata::from_header(&header).unwrap(), sample());
+    }
+
+    #[test]
+    fn test_store_encrypted_resolves_with_key() {
+        let key = [3u8; 32];
+        let mut header = blank_header();
+        store(&mut header, &sample(), true, &key).unwrap();
+        assert_eq!(header.filename, None);
+        assert_eq!(header.extension(EXT_COMMENT), None);
+
+        let bytes = header::encode_header(&header);
+        assert_eq!(resolve(&header, &bytes, &key).unwrap(), sample());
+    }
+
+    #[test]
+    fn test_user_metadata_roundtrip_through_extensions() {
+        let records = sample().extra_extensions();
+        assert_eq!(
+            records.iter().filter(|r| r.tag == EXT_USER_METADATA).count(),
+            2
+        );
+        let decoded = Metadata::from_extensions(&records).unwrap();
+        assert_eq!(decoded.user_metadata, sample().user_metadata);
+    }
+
+    #[test]
+    fn test_timestamp_conversions() {
+        for (secs, nanos) in [(0, 0), (1_690_000_000, 5), (-1, 999_999_999), (-86_400, 0)] {
+            let time = Timestamp { secs, nanos };
+            assert_eq!(Timestamp::from_system_time(time.to_system_time()), time);
+            assert_eq!(Timestamp::from_bytes(&time.to_bytes()), Some(time));
+        }
+        let mut bytes = Timestamp { secs: 0, nanos: 0 }.to_bytes();
+        bytes[8..].copy_from_slice(&1_000_000_000u32.to_be_bytes());
+        assert_eq!(Timestamp::from_bytes(&bytes), None);
+    }
+
+    #[test]
+    fn test_validate_user_metadata() {
+        assert!(validate_user_metadata(&sample().user_metadata).is_ok());
+
+        let bad_key = BTreeMap::from([("has space".to_string(), "v".to_string())]);
+        assert!(validate_user_metadata(&bad_key).is_err());
+
+        let empty_key = BTreeMap::from([(String::new(), "v".to_string())]);
+        assert!(validate_user_metadata(&empty_key).is_err());
+
+        let long_value = BTreeMap::from([(
+            "k".to_string(),
+            "x".repeat(MAX_USER_METADATA_VALUE_LEN + 1),
+        )]);
+        assert!(validate_user_metadata(&long_value).is_err());
+    }
+
+    #[test]
+    fn test_reject_truncated_user_metadata_record() {
+        let record = Extension {
+            tag: EXT_USER_METADATA,
+            value: vec![0, 10, b'a'],
+        };
+        assert!(matches!(
+            Metadata::from_extensions(&[record]),
+            Err(MetadataError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,247 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::metadata;
+
+/// What to do when the output path is already taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Conflict {
+    /// Refuse with [`NamingError::Exists`], so the caller can ask the user.
+    #[default]
+    Fail,
+    /// Replace the existing file (`--force`).
+    Replace,
+    /// Pick `name (1)`, `name (2)`, ... instead (`--no-clobber`).
+    Rename,
+}
+
+/// How an output is named when the caller does not spell out the path.
+#[derive(Debug, Clone, Default)]
+pub struct OutputNaming {
+    /// File name template such as `{stem}.gtkrypt`; see [`render_template`].
+    pub template: Option<String>,
+    /// What happens when the output path is taken.
+    pub conflict: Conflict,
+}
+
+/// Why no output path could be settled on.
+#[derive(Debug)]
+pub enum NamingError {
+    /// No usable name, such as from a malformed template.
+    Invalid(String),
+    /// The output exists and [`Conflict::Fail`] is in effect.
+    Exists(PathBuf),
+    /// The output is the input itself, under the same or another name.
+    SameFile(PathBuf),
+}
+
+impl std::fmt::Display for NamingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NamingError::Invalid(msg) => write!(f, "{}", msg),
+            NamingError::Exists(path) => write!(f, "Output already exists: {}", path.display()),
+            NamingError::SameFile(path) => {
+                write!(f, "Output {} is the input file itself", path.display())
+            }
+        }
+    }
+}
+
+impl OutputNaming {
+    /// Path for an output of `input` in `dir` that would be called `name`
+    /// without a template, and whether it differs from what the caller could
+    /// predict.
+    pub fn resolve(
+        &self,
+        input: &Path,
+        dir: &Path,
+        name: &str,
+    ) -> Result<(PathBuf, bool), NamingError> {
+        let (path, renamed) = match self.template {
+            Some(ref template) => {
+                let name = render_template(template, name).map_err(NamingError::Invalid)?;
+                (dir.join(name), true)
+            }
+            None => (dir.join(name), false),
+        };
+        self.settle(input, path, renamed)
+    }
+
+    /// Apply the conflict policy to `path`: keep it, refuse it, or move on
+    /// to the first free numbered variant. Replacing the input itself is
+    /// always refused, since the rename over it would destroy the source.
+    pub fn settle(
+        &self,
+        input: &Path,
+        path: PathBuf,
+        renamed: bool,
+    ) -> Result<(PathBuf, bool), NamingError> {
+        if !taken(&path) {
+            return Ok((path, renamed));
+        }
+        if self.conflict != Conflict::Rename && same_file(input, &path) {
+            return Err(NamingError::SameFile(path));
+        }
+        match self.conflict {
+            Conflict::Fail => return Err(NamingError::Exists(path)),
+            Conflict::Replace => return Ok((path, renamed)),
+            Conflict::Rename => {}
+        }
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        let (stem, ext) = split_name(name);
+        let free = (1u64..)
+            .map(|n| match ext {
+                "" => path.with_file_name(format!("{} ({})", stem, n)),
+                _ => path.with_file_name(format!("{} ({}).{}", stem, n, ext)),
+            })
+            .find(|candidate| !taken(candidate))
+            .unwrap_or(path);
+        Ok((free, true))
+    }
+}
+
+fn taken(path: &Path) -> bool {
+    path.symlink_metadata().is_ok()
+}
+
+/// True when both paths lead to the same file, through symlinks or hard
+/// links included.
+#[cfg(unix)]
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Split a file name into stem and extension at the last dot. A leading
+/// dot starts a hidden name rather than an extension.
+fn split_name(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, ""),
+    }
+}
+
+/// Expand a file name template for an output derived from `name`:
+/// `{name}` is the whole name, `{stem}` the name without its last
+/// extension and `{ext}` that extension without the dot.
+pub fn render_template(template: &str, name: &str) -> Result<String, String> {
+    let (stem, ext) = split_name(name);
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        rendered.push_str(&rest[..open]);
+        let close = rest[open..]
+            .find('}')
+            .ok_or_else(|| format!("Unclosed '{{' in output template '{}'", template))?;
+        rendered.push_str(match &rest[open + 1..open + close] {
+            "name" => name,
+            "stem" => stem,
+            "ext" => ext,
+            other => {
+                return Err(format!(
+                    "Unknown placeholder '{{{}}}' in output template (expected name, stem \
+                     or ext)",
+                    other
+                ))
+            }
+        });
+        rest = &rest[open + close + 1..];
+    }
+    rendered.push_str(rest);
+
+    if metadata::sanitize_filename(&rendered).as_deref() != Some(rendered.as_str()) {
+        return Err(format!(
+            "Output template '{}' gives '{}', which is not a plain file name",
+            template, rendered
+        ));
+    }
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        assert_eq!(render_template("{stem}.gtkrypt", "notes.txt").unwrap(), "notes.gtkrypt");
+        assert_eq!(render_template("{name}.gtkrypt", "notes.txt").unwrap(), "notes.txt.gtkrypt");
+        assert_eq!(render_template("{stem}-copy.{ext}", "a.tar.gz").unwrap(), "a.tar-copy.gz");
+        assert_eq!(render_template("{stem}.bak", ".bashrc").unwrap(), ".bashrc.bak");
+        assert!(render_template("{size}.gtkrypt", "notes.txt").is_err());
+        assert!(render_template("{stem", "notes.txt").is_err());
+        assert!(render_template("../{name}", "notes.txt").is_err());
+    }
+
+    #[test]
+    fn test_conflict_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("report.gtkrypt");
+        let naming = OutputNaming {
+            template: None,
+            conflict: Conflict::Rename,
+        };
+        let (path, renamed) = naming.resolve(&input, dir.path(), "report.pdf").unwrap();
+        assert_eq!((path, renamed), (dir.path().join("report.pdf"), false));
+
+        fs::write(dir.path().join("report.pdf"), b"").unwrap();
+        fs::write(dir.path().join("report (1).pdf"), b"").unwrap();
+        let (path, renamed) = naming.resolve(&input, dir.path(), "report.pdf").unwrap();
+        assert_eq!((path, renamed), (dir.path().join("report (2).pdf"), true));
+
+        let force = OutputNaming {
+            template: None,
+            conflict: Conflict::Replace,
+        };
+        let (path, _) = force.resolve(&input, dir.path(), "report.pdf").unwrap();
+        assert_eq!(path, dir.path().join("report.pdf"));
+
+        let refused = OutputNaming::default().resolve(&input, dir.path(), "report.pdf");
+        assert!(matches!(refused, Err(NamingError::Exists(path)) if path.ends_with("report.pdf")));
+        assert!(OutputNaming::default().resolve(&input, dir.path(), "new.pdf").is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_refuses_to_replace_the_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("ledger.txt");
+        fs::write(&input, b"").unwrap();
+        let alias = dir.path().join("alias.txt");
+        std::os::unix::fs::symlink(&input, &alias).unwrap();
+        let linked = dir.path().join("linked.txt");
+        fs::hard_link(&input, &linked).unwrap();
+
+        let force = OutputNaming {
+            template: None,
+            conflict: Conflict::Replace,
+        };
+        for output in [&input, &alias, &linked] {
+            let settled = force.settle(&input, output.clone(), false);
+            assert!(matches!(settled, Err(NamingError::SameFile(_))), "{:?}", output);
+        }
+        let refused = OutputNaming::default().settle(&input, input.clone(), false);
+        assert!(matches!(refused, Err(NamingError::SameFile(_))));
+
+        let rename = OutputNaming {
+            template: None,
+            conflict: Conflict::Rename,
+        };
+        let (path, _) = rename.settle(&input, input.clone(), false).unwrap();
+        assert_eq!(path, dir.path().join("ledger (1).txt"));
+    }
+}
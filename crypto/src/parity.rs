@@ -0,0 +1,475 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::Serialize;
+
+use crate::decrypt::DecryptError;
+use crate::hash;
+use crate::progress;
+
+/// Size of one Reed-Solomon shard. The protected bytes (header, chunks and
+/// chunk index) are cut into shards of this size, the last one zero-padded.
+pub const SHARD_SIZE: usize = 64 * 1024;
+
+/// Maximum number of data shards encoded together as one stripe.
+const STRIPE_DATA_SHARDS: usize = 100;
+
+/// Each shard, data or parity, has a SHA-256 hash so damage can be located
+/// without the passphrase.
+const SHARD_HASH_LEN: usize = 32;
+
+type ShardHash = [u8; SHARD_HASH_LEN];
+
+const TRAILER_MAGIC: &[u8; 8] = b"GTKRPAR\0";
+
+/// Trailer at the very end of the file: magic, protected length (uint64
+/// BE), shard size (uint32 BE), data shards per stripe (uint16 BE), parity
+/// percentage, a reserved byte, and the first 8 bytes of a SHA-256 over
+/// the preceding fields.
+const TRAILER_LEN: usize = 32;
+
+/// Number of parity shards protecting a stripe of `data_shards` shards.
+fn parity_shards(data_shards: usize, percent: u8) -> usize {
+    std::cmp::max(1, (data_shards * percent as usize).div_ceil(100))
+}
+
+/// Total data and parity shard counts for `data_len` protected bytes.
+fn shard_counts(data_len: u64, percent: u8) -> (u64, u64) {
+    let data_shards = data_len.div_ceil(SHARD_SIZE as u64);
+    let full_stripes = data_shards / STRIPE_DATA_SHARDS as u64;
+    let rest = (data_shards % STRIPE_DATA_SHARDS as u64) as usize;
+    let mut parity = full_stripes * parity_shards(STRIPE_DATA_SHARDS, percent) as u64;
+    if rest > 0 {
+        parity += parity_shards(rest, percent) as u64;
+    }
+    (data_shards, parity)
+}
+
+/// Size of the parity region appended after `data_len` protected bytes:
+/// parity shards, the shard hash table and the trailer.
+pub fn region_len(data_len: u64, percent: u8) -> u64 {
+    let (data_shards, parity) = shard_counts(data_len, percent);
+    parity * SHARD_SIZE as u64 + (data_shards + parity) * SHARD_HASH_LEN as u64 + TRAILER_LEN as u64
+}
+
+fn shard_hash(shard: &[u8]) -> ShardHash {
+    hash::sha256(shard)
+}
+
+fn encode_trailer(data_len: u64, percent: u8) -> [u8; TRAILER_LEN] {
+    let mut trailer = [0u8; TRAILER_LEN];
+    trailer[..8].copy_from_slice(TRAILER_MAGIC);
+    trailer[8..16].copy_from_slice(&data_len.to_be_bytes());
+    trailer[16..20].copy_from_slice(&(SHARD_SIZE as u32).to_be_bytes());
+    trailer[20..22].copy_from_slice(&(STRIPE_DATA_SHARDS as u16).to_be_bytes());
+    trailer[22] = percent;
+    let checksum = hash::sha256(&trailer[..24]);
+    trailer[24..].copy_from_slice(&checksum[..8]);
+    trailer
+}
+
+/// Parse a trailer, returning the protected length and parity percentage.
+fn decode_trailer(trailer: &[u8; TRAILER_LEN]) -> Result<(u64, u8), DecryptError> {
+    if &trailer[..8] != TRAILER_MAGIC || hash::sha256(&trailer[..24])[..8] != trailer[24..] {
+        return Err(DecryptError::CorruptFile(
+            "No parity data found, or its trailer is damaged".to_string(),
+        ));
+    }
+    let shard_size = u32::from_be_bytes(trailer[16..20].try_into().unwrap());
+    let stripe = u16::from_be_bytes(trailer[20..22].try_into().unwrap());
+    let percent = trailer[22];
+    if shard_size as usize != SHARD_SIZE
+        || stripe as usize != STRIPE_DATA_SHARDS
+        || !(1..=100).contains(&percent)
+    {
+        return Err(DecryptError::CorruptFile("Unsupported parity layout".to_string()));
+    }
+    Ok((u64::from_be_bytes(trailer[8..16].try_into().unwrap()), percent))
+}
+
+/// Writer that passes the container through and computes Reed-Solomon
+/// parity over it, appending the parity region on `finish`.
+///
+/// The first stripe is held in memory until the end so the header at its
+/// start can still be patched; after that only the current stripe is, and
+/// parity shards are spooled to an anonymous temporary file. With a
+/// percentage of zero it is a plain pass-through.
+pub struct ParityWriter<W: Write> {
+    inner: W,
+    percent: u8,
+    codec: Option<ReedSolomon>,
+    first_stripe: Option<Vec<u8>>,
+    stripe: Vec<u8>,
+    spill: Option<fs::File>,
+    data_hashes: Vec<ShardHash>,
+    parity_hashes: Vec<ShardHash>,
+    data_len: u64,
+}
+
+impl<W: Write> ParityWriter<W> {
+    pub fn new(inner: W, percent: u8) -> io::Result<Self> {
+        let spill = if percent > 0 {
+            Some(tempfile::tempfile()?)
+        } else {
+            None
+        };
+        Ok(ParityWriter {
+            inner,
+            percent,
+            codec: None,
+            first_stripe: None,
+            stripe: Vec::new(),
+            spill,
+            data_hashes: Vec::new(),
+            parity_hashes: Vec::new(),
+            data_len: 0,
+        })
+    }
+
+    /// Replace the first `bytes.len()` bytes written so far in the parity
+    /// input. Only bytes of the first stripe can be patched.
+    pub fn patch_start(&mut self, bytes: &[u8]) {
+        if self.percent == 0 {
+            return;
+        }
+        let stripe = self.first_stripe.as_mut().unwrap_or(&mut self.stripe);
+        stripe[..bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Encode a stripe, returning its data shard hashes and parity shards.
+    fn encode_stripe(
+        &mut self,
+        mut stripe: Vec<u8>,
+    ) -> io::Result<(Vec<ShardHash>, Vec<Vec<u8>>)> {
+        let data_shards = stripe.len().div_ceil(SHARD_SIZE);
+        let parity = parity_shards(data_shards, self.percent);
+        stripe.resize(data_shards * SHARD_SIZE, 0);
+
+        // Full stripes all share one codec
+        let codec = match self.codec.take() {
+            Some(codec) if codec.data_shard_count() == data_shards => codec,
+            _ => ReedSolomon::new(data_shards, parity).map_err(io::Error::other)?,
+        };
+        let mut shards: Vec<Vec<u8>> = stripe
+            .chunks(SHARD_SIZE)
+            .map(|shard| shard.to_vec())
+            .chain(std::iter::repeat_n(vec![0u8; SHARD_SIZE], parity))
+            .collect();
+        codec.encode(&mut shards).map_err(io::Error::other)?;
+        self.codec = Some(codec);
+
+        let parity_shards = shards.split_off(data_shards);
+        Ok((shards.iter().map(|shard| shard_hash(shard)).collect(), parity_shards))
+    }
+
+    /// Encode the buffered stripe and spool its parity shards.
+    fn flush_stripe(&mut self) -> io::Result<()> {
+        let stripe = std::mem::take(&mut self.stripe);
+        let (data_hashes, parity) = self.encode_stripe(stripe)?;
+        self.data_hashes.extend(data_hashes);
+        let spill = self.spill.as_mut().unwrap();
+        for shard in parity {
+            self.parity_hashes.push(shard_hash(&shard));
+            spill.write_all(&shard)?;
+        }
+        Ok(())
+    }
+
+    /// Append the parity region and return the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.percent == 0 {
+            return Ok(self.inner);
+        }
+        if !self.stripe.is_empty() {
+            self.flush_stripe()?;
+        }
+
+        // The held first stripe goes ahead of everything spooled after it
+        let (mut data_hashes, mut parity_hashes) = (Vec::new(), Vec::new());
+        if let Some(first_stripe) = self.first_stripe.take() {
+            let (hashes, parity) = self.encode_stripe(first_stripe)?;
+            data_hashes = hashes;
+            for shard in parity {
+                parity_hashes.push(shard_hash(&shard));
+                self.inner.write_all(&shard)?;
+            }
+        }
+        data_hashes.append(&mut self.data_hashes);
+        parity_hashes.append(&mut self.parity_hashes);
+
+        let mut spill = self.spill.take().unwrap();
+        spill.seek(SeekFrom::Start(0))?;
+        io::copy(&mut spill, &mut self.inner)?;
+        for hash in data_hashes.iter().chain(&parity_hashes) {
+            self.inner.write_all(hash)?;
+        }
+        self.inner
+            .write_all(&encode_trailer(self.data_len, self.percent))?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ParityWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if self.percent > 0 {
+            let mut rest = &buf[..n];
+            while !rest.is_empty() {
+                let room = STRIPE_DATA_SHARDS * SHARD_SIZE - self.stripe.len();
+                let take = std::cmp::min(room, rest.len());
+                self.stripe.extend_from_slice(&rest[..take]);
+                rest = &rest[take..];
+                if self.stripe.len() == STRIPE_DATA_SHARDS * SHARD_SIZE {
+                    if self.first_stripe.is_none() && self.data_hashes.is_empty() {
+                        self.first_stripe = Some(std::mem::take(&mut self.stripe));
+                    } else {
+                        self.flush_stripe()?;
+                    }
+                }
+            }
+        }
+        self.data_len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Outcome of a `repair` run, counted in shards.
+#[derive(Debug, Serialize)]
+pub struct RepairReport {
+    pub blocks_checked: u64,
+    pub blocks_damaged: u64,
+    pub blocks_repaired: u64,
+}
+
+/// Check a container against its parity data and rewrite damaged shards in
+/// place.
+///
+/// Damaged shards are found by their hashes, so no passphrase is needed.
+/// Each stripe can lose as many shards as it has parity shards; stripes
+/// with more damage are left as they are and reported as an error once the
+/// rest of the file has been repaired.
+pub fn repair(path: &str) -> Result<RepairReport, DecryptError> {
+    let io_error = |e: io::Error| {
+        if e.kind() == io::ErrorKind::PermissionDenied {
+            DecryptError::Permission(format!("Cannot access container: {}", e))
+        } else {
+            DecryptError::Internal(format!("Failed to repair container: {}", e))
+        }
+    };
+
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(io_error)?;
+    let file_len = file.metadata().map_err(io_error)?.len();
+    if file_len < TRAILER_LEN as u64 {
+        return Err(DecryptError::CorruptFile("File is too short".to_string()));
+    }
+
+    let mut trailer = [0u8; TRAILER_LEN];
+    file.seek(SeekFrom::Start(file_len - TRAILER_LEN as u64))
+        .and_then(|_| file.read_exact(&mut trailer))
+        .map_err(io_error)?;
+    let (data_len, percent) = decode_trailer(&trailer)?;
+    if data_len.checked_add(region_len(data_len, percent)) != Some(file_len) {
+        return Err(DecryptError::CorruptFile(
+            "Parity data does not match the file size".to_string(),
+        ));
+    }
+
+    let (data_shards, parity_total) = shard_counts(data_len, percent);
+    let parity_offset = data_len;
+    let mut hashes = vec![0u8; ((data_shards + parity_total) as usize) * SHARD_HASH_LEN];
+    file.seek(SeekFrom::Start(parity_offset + parity_total * SHARD_SIZE as u64))
+        .and_then(|_| file.read_exact(&mut hashes))
+        .map_err(io_error)?;
+    let hash_of = |index: u64| {
+        let start = index as usize * SHARD_HASH_LEN;
+        &hashes[start..start + SHARD_HASH_LEN]
+    };
+
+    let mut report = RepairReport {
+        blocks_checked: 0,
+        blocks_damaged: 0,
+        blocks_repaired: 0,
+    };
+    let mut unrepairable = 0u64;
+    let mut first_data = 0u64;
+    let mut first_parity = 0u64;
+    progress::emit_progress("repair", 0, data_len);
+
+    while first_data < data_shards {
+        let stripe_data = std::cmp::min(STRIPE_DATA_SHARDS as u64, data_shards - first_data);
+        let stripe_parity = parity_shards(stripe_data as usize, percent) as u64;
+
+        // Shard locations: (file offset, stored length, hash table index)
+        let locations: Vec<(u64, usize, u64)> = (0..stripe_data)
+            .map(|i| {
+                let offset = (first_data + i) * SHARD_SIZE as u64;
+                let len = std::cmp::min(SHARD_SIZE as u64, data_len - offset) as usize;
+                (offset, len, first_data + i)
+            })
+            .chain((0..stripe_parity).map(|i| {
+                let offset = parity_offset + (first_parity + i) * SHARD_SIZE as u64;
+                (offset, SHARD_SIZE, data_shards + first_parity + i)
+            }))
+            .collect();
+
+        let mut shards: Vec<Option<Vec<u8>>> = Vec::with_capacity(locations.len());
+        for &(offset, len, hash_index) in &locations {
+            let mut shard = vec![0u8; SHARD_SIZE];
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.read_exact(&mut shard[..len]))
+                .map_err(io_error)?;
+            if shard_hash(&shard) == hash_of(hash_index) {
+                shards.push(Some(shard));
+            } else {
+                shards.push(None);
+            }
+        }
+
+        let damaged: Vec<usize> = (0..shards.len()).filter(|&i| shards[i].is_none()).collect();
+        report.blocks_checked += shards.len() as u64;
+        report.blocks_damaged += damaged.len() as u64;
+
+        if !damaged.is_empty() && damaged.len() as u64 <= stripe_parity {
+            let codec = ReedSolomon::new(stripe_data as usize, stripe_parity as usize)
+                .map_err(|e| DecryptError::Internal(format!("Parity setup failed: {:?}", e)))?;
+            codec
+                .reconstruct(&mut shards)
+                .map_err(|e| DecryptError::Internal(format!("Reconstruction failed: {:?}", e)))?;
+
+            for &i in &damaged {
+                let (offset, len, hash_index) = locations[i];
+                let shard = shards[i].as_ref().unwrap();
+                if shard_hash(shard) != hash_of(hash_index) {
+                    // The hash itself was damaged, or reconstruction used a
+                    // shard whose hash collided; leave the data alone
+                    unrepairable += 1;
+                    continue;
+                }
+                file.seek(SeekFrom::Start(offset))
+                    .and_then(|_| file.write_all(&shard[..len]))
+                    .map_err(io_error)?;
+                report.blocks_repaired += 1;
+            }
+        } else {
+            unrepairable += damaged.len() as u64;
+        }
+
+        first_data += stripe_data;
+        first_parity += stripe_parity;
+        progress::emit_progress(
+            "repair",
+            std::cmp::min(first_data * SHARD_SIZE as u64, data_len),
+            data_len,
+        );
+    }
+
+    file.sync_all().map_err(io_error)?;
+
+    if unrepairable > 0 {
+        return Err(DecryptError::CorruptFile(format!(
+            "{} of {} damaged blocks could not be repaired",
+            unrepairable, report.blocks_damaged
+        )));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_protected(path: &std::path::Path, data: &[u8], percent: u8) {
+        let mut writer = ParityWriter::new(Vec::new(), percent).unwrap();
+        writer.write_all(data).unwrap();
+        let out = writer.finish().unwrap();
+        assert_eq!(out.len() as u64, data.len() as u64 + region_len(data.len() as u64, percent));
+        fs::write(path, out).unwrap();
+    }
+
+    #[test]
+    fn test_parity_shards() {
+        assert_eq!(parity_shards(100, 5), 5);
+        assert_eq!(parity_shards(3, 5), 1);
+        assert_eq!(parity_shards(100, 100), 100);
+        assert_eq!(shard_counts(1, 5), (1, 1));
+        assert_eq!(shard_counts(101 * SHARD_SIZE as u64, 5), (101, 6));
+    }
+
+    #[test]
+    fn test_repair_damaged_shards() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("protected.bin");
+        let data: Vec<u8> = (0..(3 * SHARD_SIZE + 123) as u32).map(|i| (i % 253) as u8).collect();
+        write_protected(&path, &data, 50);
+        let path = path.to_str().unwrap();
+
+        let report = repair(path).unwrap();
+        assert_eq!(report.blocks_damaged, 0);
+
+        // Two parity shards cover four data shards: damage two of them,
+        // including the short last one
+        let mut damaged = fs::read(path).unwrap();
+        damaged[10] ^= 0xFF;
+        damaged[3 * SHARD_SIZE + 100] ^= 0xFF;
+        fs::write(path, &damaged).unwrap();
+
+        let report = repair(path).unwrap();
+        assert_eq!(report.blocks_damaged, 2);
+        assert_eq!(report.blocks_repaired, 2);
+        assert_eq!(&fs::read(path).unwrap()[..data.len()], &data[..]);
+    }
+
+    #[test]
+    fn test_patch_start_across_stripes() {
+        // The first stripe is held back, so a patched header is protected
+        // and its parity lands ahead of the later stripes'
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("protected.bin");
+        let mut data: Vec<u8> = (0..(STRIPE_DATA_SHARDS * SHARD_SIZE + 70_000) as u32)
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        let mut writer = ParityWriter::new(Vec::new(), 1).unwrap();
+        writer.write_all(&data).unwrap();
+        data[..64].fill(0xEE);
+        writer.patch_start(&data[..64]);
+        let mut out = writer.finish().unwrap();
+        out[..64].copy_from_slice(&data[..64]);
+        fs::write(&path, &out).unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut damaged = fs::read(path).unwrap();
+        damaged[3] ^= 0xFF;
+        damaged[STRIPE_DATA_SHARDS * SHARD_SIZE + 5] ^= 0xFF;
+        fs::write(path, &damaged).unwrap();
+
+        let report = repair(path).unwrap();
+        assert_eq!(report.blocks_repaired, 2);
+        assert_eq!(&fs::read(path).unwrap()[..data.len()], &data[..]);
+    }
+
+    #[test]
+    fn test_repair_reports_too_much_damage() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("protected.bin");
+        let data = vec![0x42u8; 4 * SHARD_SIZE];
+        write_protected(&path, &data, 5);
+        let path = path.to_str().unwrap();
+
+        let mut damaged = fs::read(path).unwrap();
+        damaged[0] ^= 0x01;
+        damaged[SHARD_SIZE] ^= 0x01;
+        fs::write(path, &damaged).unwrap();
+
+        assert!(matches!(repair(path), Err(DecryptError::CorruptFile(_))));
+    }
+}
@@ -0,0 +1,482 @@
+use std::ffi::OsString;
+use std::io::BufRead;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+use crate::progress;
+
+/// How passphrases are framed on stdin.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PassphraseFormat {
+    /// One line ended by LF or CRLF, which the passphrase cannot contain.
+    #[default]
+    Line,
+    /// The length in bytes as a 4-byte big-endian integer, then the
+    /// passphrase as it is.
+    LengthPrefixed,
+}
+
+/// The encoding the passphrase bytes are meant to be in. The bytes are
+/// used as given either way; a passphrase that does not look like its
+/// encoding gets a warning, since it would not match one typed elsewhere.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PassphraseEncoding {
+    /// No expectation.
+    #[default]
+    Bytes,
+    Utf8,
+    Latin1,
+}
+
+/// Parse a `--passphrase-format` value.
+pub fn parse_passphrase_format(arg: &str) -> Result<PassphraseFormat, String> {
+    match arg {
+        "line" => Ok(PassphraseFormat::Line),
+        "length-prefixed" => Ok(PassphraseFormat::LengthPrefixed),
+        _ => Err(format!("expected line or length-prefixed, got '{}'", arg)),
+    }
+}
+
+/// Parse a `--passphrase-encoding` value.
+pub fn parse_passphrase_encoding(arg: &str) -> Result<PassphraseEncoding, String> {
+    match arg {
+        "bytes" => Ok(PassphraseEncoding::Bytes),
+        "utf-8" => Ok(PassphraseEncoding::Utf8),
+        "latin-1" => Ok(PassphraseEncoding::Latin1),
+        _ => Err(format!("expected bytes, utf-8 or latin-1, got '{}'", arg)),
+    }
+}
+
+/// The framing of the passphrases, set with `--passphrase-format`.
+static FORMAT: OnceLock<PassphraseFormat> = OnceLock::new();
+
+/// The encoding declared with `--passphrase-encoding`.
+static ENCODING: OnceLock<PassphraseEncoding> = OnceLock::new();
+
+/// Read passphrases in `format` from now on.
+pub fn set_format(format: PassphraseFormat) {
+    let _ = FORMAT.set(format);
+}
+
+/// Check passphrases against `encoding` from now on.
+pub fn set_encoding(encoding: PassphraseEncoding) {
+    let _ = ENCODING.set(encoding);
+}
+
+/// The credential named with `--passphrase-from-credential`, until the
+/// passphrase has been taken from it.
+static CREDENTIAL: Mutex<Option<Credential>> = Mutex::new(None);
+
+struct Credential {
+    name: String,
+    /// The value of `credential_variable`, taken out of the environment.
+    variable: Option<OsString>,
+}
+
+/// Take the passphrase from the credential `name` rather than stdin.
+///
+/// The environment variable standing in for it, named by
+/// `credential_variable`, is read and removed here, so that child
+/// processes do not inherit it. Changing the environment is only sound
+/// while no other thread runs, so this is called before any is spawned.
+pub fn set_credential(name: String) {
+    let variable_name = credential_variable(&name);
+    let variable = std::env::var_os(&variable_name);
+    if variable.is_some() {
+        std::env::remove_var(&variable_name);
+    }
+    let credential = Credential { name, variable };
+    *CREDENTIAL.lock().unwrap_or_else(PoisonError::into_inner) = Some(credential);
+}
+
+/// The passphrase from the credential set with `set_credential`, the first
+/// time this is called; any further passphrase comes from stdin.
+///
+/// The credential is the file of that name in `$CREDENTIALS_DIRECTORY`,
+/// where systemd puts those of a service, or else the environment variable
+/// that `set_credential` took.
+pub fn take_credential() -> Option<Result<Vec<u8>, String>> {
+    let Credential { name, variable } =
+        CREDENTIAL.lock().unwrap_or_else(PoisonError::into_inner).take()?;
+    let directory = std::env::var_os("CREDENTIALS_DIRECTORY");
+    let passphrase = read_credential(&name, directory.as_deref().map(Path::new), variable);
+    if let Ok(passphrase) = &passphrase {
+        warn_on_encoding(passphrase);
+    }
+    Some(passphrase)
+}
+
+/// The environment variable standing in for the credential `name`:
+/// `GTKRYPT_CREDENTIAL_` and the name in upper case, with anything but
+/// letters and digits turned into `_`. Never a variable such as `HOME`
+/// that is set for other reasons.
+fn credential_variable(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("GTKRYPT_CREDENTIAL_{}", name)
+}
+
+fn read_credential(
+    name: &str,
+    directory: Option<&Path>,
+    variable: Option<OsString>,
+) -> Result<Vec<u8>, String> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        return Err(format!("Invalid credential name '{}'", name));
+    }
+    let from_file = directory.and_then(|directory| {
+        match std::fs::read(directory.join(name)) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            read => Some(read),
+        }
+    });
+    let mut passphrase = match (from_file, variable) {
+        (Some(read), _) => {
+            read.map_err(|e| format!("Failed to read credential {}: {}", name, e))?
+        }
+        (None, Some(variable)) => variable.into_encoded_bytes(),
+        (None, None) => {
+            return Err(format!(
+                "No credential {} in $CREDENTIALS_DIRECTORY or ${}",
+                name,
+                credential_variable(name)
+            ))
+        }
+    };
+    // A credential loaded from a text file ends with a newline
+    if passphrase.ends_with(b"\n") {
+        passphrase.pop();
+        if passphrase.ends_with(b"\r") {
+            passphrase.pop();
+        }
+    }
+    if passphrase.is_empty() {
+        return Err(format!("Credential {} is empty", name));
+    }
+    Ok(passphrase)
+}
+
+/// Longest passphrase taken with a length prefix, so that a garbled prefix
+/// cannot make the backend wait for gigabytes.
+pub const MAX_LEN: usize = 64 * 1024;
+
+/// Read one passphrase from `input` as raw bytes, framed as set with
+/// `set_format`, warning if they do not fit the encoding declared.
+pub fn read(input: &mut impl BufRead) -> Result<Vec<u8>, String> {
+    let passphrase = read_as(FORMAT.get().copied().unwrap_or_default(), input)?;
+    warn_on_encoding(&passphrase);
+    Ok(passphrase)
+}
+
+fn warn_on_encoding(passphrase: &[u8]) {
+    let encoding = ENCODING.get().copied().unwrap_or_default();
+    if let Some(message) = encoding_mismatch(encoding, passphrase) {
+        progress::emit_warning("passphrase_encoding", &message);
+    }
+}
+
+/// Why `passphrase` does not look like `encoding`, if it does not.
+fn encoding_mismatch(encoding: PassphraseEncoding, passphrase: &[u8]) -> Option<String> {
+    match (encoding, std::str::from_utf8(passphrase)) {
+        (PassphraseEncoding::Utf8, Err(e)) => Some(format!(
+            "Passphrase is not valid UTF-8 from byte {}; its bytes are used as given",
+            e.valid_up_to()
+        )),
+        // Latin-1 text is seldom also valid UTF-8 beyond ASCII
+        (PassphraseEncoding::Latin1, Ok(text)) if !text.is_ascii() => Some(
+            "Passphrase looks like UTF-8 rather than Latin-1; its bytes are used as given"
+                .to_string(),
+        ),
+        _ => None,
+    }
+}
+
+fn read_as(format: PassphraseFormat, input: &mut impl BufRead) -> Result<Vec<u8>, String> {
+    let passphrase = match format {
+        PassphraseFormat::Line => read_line(input)?,
+        PassphraseFormat::LengthPrefixed => read_length_prefixed(input)?,
+    };
+    if passphrase.is_empty() {
+        return Err("Passphrase is empty".to_string());
+    }
+    Ok(passphrase)
+}
+
+fn read_line(input: &mut impl BufRead) -> Result<Vec<u8>, String> {
+    let mut line = Vec::new();
+    input
+        .read_until(b'\n', &mut line)
+        .map_err(|e| format!("Failed to read passphrase from stdin: {}", e))?;
+
+    // Remove trailing newline
+    if line.ends_with(b"\n") {
+        line.pop();
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+    }
+    Ok(line)
+}
+
+fn read_length_prefixed(input: &mut impl BufRead) -> Result<Vec<u8>, String> {
+    let mut len = [0u8; 4];
+    input
+        .read_exact(&mut len)
+        .map_err(|e| format!("Failed to read passphrase length from stdin: {}", e))?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_LEN {
+        return Err(format!(
+            "Passphrase of {} bytes exceeds the maximum of {} bytes",
+            len, MAX_LEN
+        ));
+    }
+    let mut passphrase = vec![0u8; len];
+    input
+        .read_exact(&mut passphrase)
+        .map_err(|e| format!("Failed to read passphrase from stdin: {}", e))?;
+    Ok(passphrase)
+}
+
+/// How many times a prompt asks before giving up.
+const PROMPT_TRIES: usize = 3;
+
+/// The settings of the terminal a prompt turned echo off on, to put back
+/// once the prompt ends, or by `restore_terminal` should the run be
+/// cancelled in the middle of it.
+#[cfg(unix)]
+static SAVED_TERMINAL: Mutex<Option<(i32, libc::termios)>> = Mutex::new(None);
+
+/// Ask for a passphrase on the controlling terminal, shown as `label`,
+/// with echo off. When `confirm` is set it is asked for twice, and again
+/// until both entries match.
+#[cfg(unix)]
+pub fn prompt(label: &str, confirm: bool) -> Result<Vec<u8>, String> {
+    use std::io::Write;
+
+    let tty = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .map_err(|e| format!("Failed to open the terminal: {}", e))?;
+    let repeat = format!("Repeat {}: ", label.to_lowercase());
+    for _ in 0..PROMPT_TRIES {
+        let passphrase = ask(&tty, &format!("{}: ", label))?;
+        let retry = if passphrase.is_empty() {
+            "Passphrase is empty"
+        } else if confirm && ask(&tty, &repeat)? != passphrase {
+            "Passphrases do not match"
+        } else {
+            warn_on_encoding(&passphrase);
+            return Ok(passphrase);
+        };
+        writeln!(&tty, "{}", retry).map_err(|e| format!("Failed to write to the terminal: {}", e))?;
+    }
+    Err(format!("No passphrase given in {} tries", PROMPT_TRIES))
+}
+
+/// Elsewhere the passphrase is read from stdin as from a pipe.
+#[cfg(not(unix))]
+pub fn prompt(_label: &str, _confirm: bool) -> Result<Vec<u8>, String> {
+    read(&mut std::io::stdin().lock())
+}
+
+/// Show `prompt` on `tty` and read one line with echo off.
+#[cfg(unix)]
+fn ask(tty: &std::fs::File, prompt: &str) -> Result<Vec<u8>, String> {
+    use std::io::Write;
+    use std::os::fd::AsRawFd;
+
+    let fd = tty.as_raw_fd();
+    // SAFETY: termios is plain old data, filled in by tcgetattr before use
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    // SAFETY: `fd` is open for as long as `tty` and `termios` is valid
+    if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(format!("Failed to read the terminal settings: {}", e));
+    }
+    let mut silent = termios;
+    // The newline still shows, so the next line starts afresh
+    silent.c_lflag &= !libc::ECHO;
+    silent.c_lflag |= libc::ECHONL;
+    *SAVED_TERMINAL.lock().unwrap_or_else(PoisonError::into_inner) = Some((fd, termios));
+    // SAFETY: as for tcgetattr
+    if unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &silent) } != 0 {
+        restore_terminal();
+        return Err(format!("Failed to turn off echo: {}", std::io::Error::last_os_error()));
+    }
+
+    let mut line = Vec::new();
+    let read = (|| {
+        let mut tty = tty;
+        tty.write_all(prompt.as_bytes())?;
+        std::io::BufReader::new(tty).read_until(b'\n', &mut line)
+    })();
+    restore_terminal();
+    read.map_err(|e| format!("Failed to read passphrase from the terminal: {}", e))?;
+    // Without a newline the terminal was closed, with ctrl-D say
+    if line.pop() != Some(b'\n') {
+        return Err("The terminal was closed before a passphrase was given".to_string());
+    }
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(line)
+}
+
+/// Put back the terminal settings a prompt changed, if one is under way.
+/// Called when the run is cancelled, since that exits at once.
+#[cfg(unix)]
+pub fn restore_terminal() {
+    let saved = SAVED_TERMINAL.lock().unwrap_or_else(PoisonError::into_inner).take();
+    if let Some((fd, termios)) = saved {
+        // SAFETY: `fd` stays open while its settings are saved
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) };
+    }
+}
+
+#[cfg(not(unix))]
+pub fn restore_terminal() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framed(passphrase: &[u8]) -> Vec<u8> {
+        let mut frame = (passphrase.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(passphrase);
+        frame
+    }
+
+    #[test]
+    fn test_line_passphrase() {
+        let mut input: &[u8] = b"first\r\ns\xe9cond\nthird";
+        assert_eq!(read_as(PassphraseFormat::Line, &mut input).unwrap(), b"first");
+        // Bytes that are not UTF-8 come through unchanged
+        assert_eq!(read_as(PassphraseFormat::Line, &mut input).unwrap(), b"s\xe9cond");
+        assert_eq!(read_as(PassphraseFormat::Line, &mut input).unwrap(), b"third");
+        assert!(read_as(PassphraseFormat::Line, &mut input).is_err());
+    }
+
+    #[test]
+    fn test_length_prefixed_passphrase_keeps_every_byte() {
+        let mut input = framed(b"two\nlines\r\n");
+        input.extend(framed(b" spaced "));
+        input.extend_from_slice(b"{\"cmd\":\"pause\"}\n");
+        let mut input = &input[..];
+
+        let format = PassphraseFormat::LengthPrefixed;
+        assert_eq!(read_as(format, &mut input).unwrap(), b"two\nlines\r\n");
+        assert_eq!(read_as(format, &mut input).unwrap(), b" spaced ");
+        // What follows is left for the control messages
+        assert_eq!(input, b"{\"cmd\":\"pause\"}\n");
+    }
+
+    #[test]
+    fn test_length_prefixed_passphrase_errors() {
+        let format = PassphraseFormat::LengthPrefixed;
+        assert_eq!(read_as(format, &mut &framed(b"")[..]).unwrap_err(), "Passphrase is empty");
+        assert_eq!(read_as(format, &mut &framed(b"\xff\xfe")[..]).unwrap(), b"\xff\xfe");
+        // Cut short, in the prefix or after it
+        assert!(read_as(format, &mut &[0u8, 0][..]).is_err());
+        assert!(read_as(format, &mut &framed(b"truncated")[..6]).is_err());
+        let too_long = ((MAX_LEN + 1) as u32).to_be_bytes();
+        assert!(read_as(format, &mut &too_long[..]).unwrap_err().contains("maximum"));
+    }
+
+    #[test]
+    fn test_parse_passphrase_format() {
+        assert_eq!(parse_passphrase_format("line").unwrap(), PassphraseFormat::Line);
+        assert_eq!(
+            parse_passphrase_format("length-prefixed").unwrap(),
+            PassphraseFormat::LengthPrefixed
+        );
+        assert!(parse_passphrase_format("netstring").is_err());
+    }
+
+    #[test]
+    fn test_encoding_mismatch() {
+        let utf8 = "grüße".as_bytes();
+        let latin1 = b"gr\xfc\xdfe";
+        assert!(encoding_mismatch(PassphraseEncoding::Utf8, utf8).is_none());
+        let message = encoding_mismatch(PassphraseEncoding::Utf8, latin1).unwrap();
+        assert!(message.contains("from byte 2"), "{}", message);
+        assert!(encoding_mismatch(PassphraseEncoding::Latin1, latin1).is_none());
+        assert!(encoding_mismatch(PassphraseEncoding::Latin1, utf8).is_some());
+        assert!(encoding_mismatch(PassphraseEncoding::Latin1, b"ascii").is_none());
+        assert!(encoding_mismatch(PassphraseEncoding::Bytes, latin1).is_none());
+        assert_eq!(parse_passphrase_encoding("utf-8").unwrap(), PassphraseEncoding::Utf8);
+        assert!(parse_passphrase_encoding("utf-16").is_err());
+    }
+
+    #[test]
+    fn test_read_credential() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("backup"), b"from a file\n").unwrap();
+        std::fs::write(dir.path().join("empty"), b"\n").unwrap();
+        let variable = || Some(OsString::from("from the environment"));
+
+        let read = read_credential("backup", Some(dir.path()), variable());
+        assert_eq!(read.unwrap(), b"from a file");
+        let read = read_credential("missing", Some(dir.path()), variable());
+        assert_eq!(read.unwrap(), b"from the environment");
+        assert_eq!(read_credential("backup", None, variable()).unwrap(), b"from the environment");
+        assert!(read_credential("missing", Some(dir.path()), None).is_err());
+        assert!(read_credential("empty", Some(dir.path()), variable()).is_err());
+        assert!(read_credential("../backup", Some(dir.path()), variable()).is_err());
+    }
+
+    #[test]
+    fn test_credential_variable() {
+        assert_eq!(credential_variable("HOME"), "GTKRYPT_CREDENTIAL_HOME");
+        assert_eq!(
+            credential_variable("backup-passphrase"),
+            "GTKRYPT_CREDENTIAL_BACKUP_PASSPHRASE"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_ask_reads_without_echo() {
+        use std::io::{Read, Write};
+        use std::os::fd::{AsRawFd, FromRawFd};
+
+        let (mut master, mut slave) = (0, 0);
+        // SAFETY: both descriptors are written on success and owned below
+        let opened = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(opened, 0);
+        // SAFETY: openpty handed over both descriptors
+        let (mut master, slave) =
+            unsafe { (std::fs::File::from_raw_fd(master), std::fs::File::from_raw_fd(slave)) };
+
+        let asking = std::thread::spawn(move || (ask(&slave, "Passphrase: "), slave));
+        let mut shown = Vec::new();
+        while !shown.ends_with(b"Passphrase: ") {
+            let mut byte = [0u8];
+            master.read_exact(&mut byte).unwrap();
+            shown.push(byte[0]);
+        }
+        master.write_all(b"hunter2\n").unwrap();
+        let (asked, slave) = asking.join().unwrap();
+        assert_eq!(asked.unwrap(), b"hunter2");
+
+        // Only the newline was echoed, and echo is back on
+        let mut echoed = [0u8; 16];
+        let n = master.read(&mut echoed).unwrap();
+        assert_eq!(&echoed[..n], b"\r\n");
+        // SAFETY: as in `ask`
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::tcgetattr(slave.as_raw_fd(), &mut termios) }, 0);
+        assert_ne!(termios.c_lflag & libc::ECHO, 0);
+        assert!(SAVED_TERMINAL.lock().unwrap().is_none());
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use tempfile::NamedTempFile;
+
+use crate::cancel;
+use crate::metadata;
+use crate::progress;
+use crate::temp;
+
+/// Give a finished temporary file its final name. Unless `replace` is set,
+/// an existing file at `target` is left alone and the rename fails with
+/// `AlreadyExists`.
+///
+/// A rename cannot cross filesystems, which a temp file outside the target
+/// directory, or a bind mount between the two, can make it do. Then the
+/// data is copied to a new temp file beside `target` and renamed from
+/// there, so the output still appears in one step.
+pub fn persist(temp_file: NamedTempFile, target: &Path, replace: bool) -> io::Result<()> {
+    let temp_path = temp_file.path().to_path_buf();
+    let renamed = if replace {
+        temp_file.persist(target)
+    } else {
+        temp_file.persist_noclobber(target)
+    };
+    match renamed {
+        Ok(_) => {
+            cancel::untrack(&temp_path);
+            Ok(())
+        }
+        Err(e) if is_cross_device(&e.error) => copy_across(&e.file, target, replace),
+        Err(e) => Err(e.error),
+    }
+}
+
+fn is_cross_device(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(libc::EXDEV) {
+        return true;
+    }
+    e.kind() == io::ErrorKind::CrossesDevices
+}
+
+/// Copy `temp_file` into a new temp file in the directory of `target`,
+/// keeping its mode, owner and times, flush it to disk and rename it into
+/// place. The original is removed when the caller drops it.
+fn copy_across(temp_file: &NamedTempFile, target: &Path, replace: bool) -> io::Result<()> {
+    let dir = target.parent().unwrap_or(Path::new("."));
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let mut copy = temp::temp_file_in(dir)?;
+    // Copying and flushing a large output can take a while
+    let _heartbeat = progress::heartbeat("sync");
+
+    let original = temp_file.as_file().metadata()?;
+    io::copy(&mut temp_file.reopen()?, copy.as_file_mut())?;
+    // The owner only differs where it was already restored, so this can
+    // only fail where that failed too
+    metadata::carry_over(&original, copy.path())?;
+    let times = fs::FileTimes::new()
+        .set_modified(original.modified()?)
+        .set_accessed(original.accessed()?);
+    copy.as_file().set_times(times)?;
+    copy.as_file().sync_all()?;
+
+    let copy_path = copy.path().to_path_buf();
+    let renamed = if replace {
+        copy.persist(target)
+    } else {
+        copy.persist_noclobber(target)
+    };
+    renamed.map_err(|e| e.error)?;
+    cancel::untrack(&copy_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_persist_honours_replace() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out.txt");
+        fs::write(&target, b"old").unwrap();
+
+        let mut temp_file = NamedTempFile::new_in(dir.path()).unwrap();
+        io::Write::write_all(&mut temp_file, b"new").unwrap();
+        let err = persist(temp_file, &target, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&target).unwrap(), b"old");
+
+        let mut temp_file = NamedTempFile::new_in(dir.path()).unwrap();
+        io::Write::write_all(&mut temp_file, b"new").unwrap();
+        persist(temp_file, &target, true).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"new");
+    }
+
+    #[test]
+    fn test_copy_across_keeps_data_mode_and_times() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let out_dir = tempfile::tempdir().unwrap();
+        let target = out_dir.path().join("out.bin");
+
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut temp_file = NamedTempFile::new_in(temp_dir.path()).unwrap();
+        io::Write::write_all(&mut temp_file, &data).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(temp_file.path(), fs::Permissions::from_mode(0o640)).unwrap();
+        }
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+        temp_file
+            .as_file()
+            .set_times(fs::FileTimes::new().set_modified(mtime))
+            .unwrap();
+
+        copy_across(&temp_file, &target, false).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), data);
+        assert_eq!(fs::metadata(&target).unwrap().modified().unwrap(), mtime);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&target).unwrap().permissions().mode() & 0o777, 0o640);
+        }
+        // Only the output is left beside the target
+        assert_eq!(fs::read_dir(out_dir.path()).unwrap().count(), 1);
+
+        let err = copy_across(&temp_file, &target, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_dir(out_dir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cross_device_error_is_recognised() {
+        assert!(is_cross_device(&io::Error::from_raw_os_error(libc::EXDEV)));
+        assert!(!is_cross_device(&io::Error::from_raw_os_error(libc::EACCES)));
+    }
+}
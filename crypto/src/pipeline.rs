@@ -0,0 +1,180 @@
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::control;
+use crate::log;
+use crate::progress;
+
+/// Blocks in circulation: one in each stage and one waiting between two.
+const BLOCKS: usize = 4;
+
+/// Run a stream of blocks through `read`, `process` and `write`, each stage
+/// on a thread of its own, so that the next block is read and the previous
+/// one written while the current one is processed.
+///
+/// `new_block` makes the few blocks that circulate between the stages.
+/// `read` fills a block and returns `false` once there is nothing left,
+/// `process` runs on the calling thread, and `write` gets the blocks in
+/// order. A failing stage stops the others; the error returned is the one
+/// for the earliest block in the stream.
+pub fn run<B, E, N, R, P, W>(
+    new_block: N,
+    mut read: R,
+    mut process: P,
+    mut write: W,
+) -> Result<(), E>
+where
+    B: Send,
+    E: Send,
+    N: Fn() -> B,
+    R: FnMut(&mut B) -> Result<bool, E> + Send,
+    P: FnMut(&mut B) -> Result<(), E>,
+    W: FnMut(&mut B) -> Result<(), E> + Send,
+{
+    let (free_tx, free_rx) = mpsc::sync_channel(BLOCKS);
+    for _ in 0..BLOCKS {
+        // Cannot fail: the channel has room for every block
+        let _ = free_tx.send(new_block());
+    }
+    let (read_tx, read_rx) = mpsc::sync_channel::<B>(1);
+    let (processed_tx, processed_rx) = mpsc::sync_channel::<B>(1);
+    // The stage threads tag their events like the caller's
+    let tags = progress::event_tags();
+    let reader_tags = tags.clone();
+
+    thread::scope(|scope| {
+        // Each thread owns its channel ends, so a stage that stops closes
+        // them and the others stop waiting
+        let reader = scope.spawn(move || {
+            progress::set_event_tags(reader_tags);
+            let mut busy = Duration::ZERO;
+            // Ends when the writer has stopped handing blocks back
+            for index in 0.. {
+                let Ok(mut block) = free_rx.recv() else {
+                    break;
+                };
+                let started = Instant::now();
+                let more = read(&mut block)?;
+                // The read that finds the end has no block to show for it
+                busy += if more { timed("read", index, started) } else { started.elapsed() };
+                if !more || read_tx.send(block).is_err() {
+                    break;
+                }
+            }
+            Ok(busy)
+        });
+        let writer = scope.spawn(move || {
+            progress::set_event_tags(tags);
+            let mut busy = Duration::ZERO;
+            for (index, mut block) in processed_rx.into_iter().enumerate() {
+                let started = Instant::now();
+                write(&mut block)?;
+                busy += timed("wrote", index as u64, started);
+                let _ = free_tx.send(block);
+            }
+            Ok(busy)
+        });
+
+        let mut processed = Ok(());
+        let mut blocks = 0;
+        let mut busy = Duration::ZERO;
+        for mut block in read_rx {
+            control::wait_if_paused();
+            let started = Instant::now();
+            if let Err(e) = process(&mut block) {
+                processed = Err(e);
+                break;
+            }
+            busy += timed("processed", blocks, started);
+            blocks += 1;
+            if processed_tx.send(block).is_err() {
+                break;
+            }
+        }
+        drop(processed_tx);
+
+        let written = writer.join().unwrap_or_else(|e| std::panic::resume_unwind(e));
+        let read = reader.join().unwrap_or_else(|e| std::panic::resume_unwind(e));
+        if let (Ok(writing), Ok(reading)) = (&written, &read) {
+            log::debug(|| {
+                format!(
+                    "{} blocks: reading took {:.3}s, processing {:.3}s, writing {:.3}s",
+                    blocks,
+                    reading.as_secs_f64(),
+                    busy.as_secs_f64(),
+                    writing.as_secs_f64()
+                )
+            });
+        }
+        // A block is written only after it was processed, and processed
+        // only after it was read, so a write error comes first
+        written.and(processed).and(read).map(drop)
+    })
+}
+
+/// The time since `started`, logged as what `stage` took for block `index`.
+fn timed(stage: &str, index: u64, started: Instant) -> Duration {
+    let took = started.elapsed();
+    log::trace(|| format!("{} block {} in {:.3} ms", stage, index, took.as_secs_f64() * 1e3));
+    took
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_keeps_block_order() {
+        let mut next = 0u32;
+        let mut written = Vec::new();
+        run(
+            || 0u32,
+            |block| {
+                *block = next;
+                next += 1;
+                Ok::<_, String>(next <= 100)
+            },
+            |block| {
+                *block *= 2;
+                Ok(())
+            },
+            |block| {
+                written.push(*block);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(written, (0..100).map(|n| n * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_run_stops_at_the_first_error() {
+        let mut next = 0u32;
+        let result = run(
+            || 0u32,
+            |block| {
+                *block = next;
+                next += 1;
+                Ok(true)
+            },
+            |block| match *block {
+                7 => Err(format!("process {}", block)),
+                _ => Ok(()),
+            },
+            |block| match *block {
+                3 => Err(format!("write {}", block)),
+                _ => Ok(()),
+            },
+        );
+        assert_eq!(result, Err("write 3".to_string()));
+
+        let result = run(
+            || 0u32,
+            |_| Err::<bool, _>("read".to_string()),
+            |_| Ok(()),
+            |_| Ok(()),
+        );
+        assert_eq!(result, Err("read".to_string()));
+    }
+}
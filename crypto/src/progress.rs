@@ -1,46 +1,934 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, OnceLock, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
 use serde::Serialize;
 
+use crate::fd;
+use crate::human;
+
+thread_local! {
+    /// Input the events of this thread belong to, while a batch runs.
+    static CURRENT_FILE: RefCell<Option<String>> = const { RefCell::new(None) };
+
+    /// Id of the `serve` request the events of this thread belong to.
+    static REQUEST_ID: RefCell<Option<serde_json::Value>> = const { RefCell::new(None) };
+
+    /// Connection of a `serve --socket` client the events of this thread
+    /// go to, in place of stdout.
+    static CONNECTION: RefCell<Option<Connection>> = const { RefCell::new(None) };
+
+    /// Phase, time and progress of the last progress line this thread
+    /// emitted, against which `--progress-interval` is measured.
+    static LAST_PROGRESS: RefCell<Option<LastProgress>> = const { RefCell::new(None) };
+
+    /// Speed of the phase this thread reports progress for.
+    static RATE: RefCell<Option<Rate>> = const { RefCell::new(None) };
+}
+
+/// The least change between two progress lines of a phase, set with
+/// `--progress-interval`.
+static PROGRESS_INTERVAL: OnceLock<ProgressInterval> = OnceLock::new();
+
+/// Version of the protocol spoken on stdout and stderr: the events, the
+/// errors and their codes. Raised when a change could break a frontend
+/// that reads them; new fields and events leave it alone.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// A client connection events can be sent to.
+pub type Connection = Arc<Mutex<dyn Write + Send>>;
+
+/// Where the event lines go instead of stdout, set with `--progress-fd`.
+static EVENT_SINK: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Send the event lines to the inherited file descriptor `fd` from now on,
+/// leaving stdout to data. Errors still go to stderr.
+#[cfg(unix)]
+pub fn set_progress_fd(fd: i32) -> io::Result<()> {
+    use std::os::fd::FromRawFd;
+
+    fd::check(fd, true)?;
+    // SAFETY: the descriptor is open, and was handed down for this alone
+    let file = unsafe { File::from_raw_fd(fd) };
+    let _ = EVENT_SINK.set(Mutex::new(file));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_progress_fd(fd: i32) -> io::Result<()> {
+    fd::check(fd, true)
+}
+
+/// How the events on stdout, or on `--progress-fd`, are encoded. Errors
+/// on stderr are JSON but for `Human`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EventFormat {
+    /// One JSON object per line.
+    #[default]
+    Json,
+    /// A MessagePack map per event with the same fields as the JSON
+    /// object, preceded by its length as a 4-byte big-endian integer.
+    Msgpack,
+    /// Text for a person at a terminal, with a progress bar, and errors
+    /// as text too. Not a protocol: chosen when stdout is a terminal,
+    /// unless `--json` or `--event-format` is given.
+    Human,
+}
+
+/// Parse an `--event-format` value.
+pub fn parse_event_format(arg: &str) -> Result<EventFormat, String> {
+    match arg {
+        "json" => Ok(EventFormat::Json),
+        "msgpack" => Ok(EventFormat::Msgpack),
+        _ => Err(format!("expected json or msgpack, got '{}'", arg)),
+    }
+}
+
+/// The encoding of the events, set with `--event-format`.
+static EVENT_FORMAT: OnceLock<EventFormat> = OnceLock::new();
+
+/// Encode the events in `format` from now on.
+pub fn set_event_format(format: EventFormat) {
+    let _ = EVENT_FORMAT.set(format);
+}
+
+/// `event` framed as it goes out in `format`: a JSON line, or a length
+/// prefix and a MessagePack map.
+fn encode_event<T: Serialize>(format: EventFormat, event: &T) -> Option<Vec<u8>> {
+    match format {
+        EventFormat::Json => {
+            let mut line = serde_json::to_vec(event).ok()?;
+            line.push(b'\n');
+            Some(line)
+        }
+        EventFormat::Msgpack => {
+            let body = rmp_serde::to_vec_named(event).ok()?;
+            let mut frame = u32::try_from(body.len()).ok()?.to_be_bytes().to_vec();
+            frame.extend_from_slice(&body);
+            Some(frame)
+        }
+        EventFormat::Human => Some(human::render(&serde_json::to_value(event).ok()?)),
+    }
+}
+
+/// Write `event` to stdout, or to the descriptor given with
+/// `--progress-fd`, in a single write so that events from different
+/// threads stay whole. While this thread serves a request, the event goes
+/// out as an `event` notification for it, on the connection of its client
+/// if there is one.
+pub fn emit_event<T: Serialize>(event: &T) {
+    let format = EVENT_FORMAT.get().copied().unwrap_or_default();
+    let request_id = REQUEST_ID.with(|current| current.borrow().clone());
+    let frame = match request_id {
+        Some(ref request_id) => encode_event(
+            format,
+            &Notification {
+                jsonrpc: "2.0",
+                method: "event",
+                params: RequestEvent { request_id, event },
+            },
+        ),
+        None => encode_event(format, event),
+    };
+    let Some(frame) = frame else {
+        return;
+    };
+    if let Some(connection) = CONNECTION.with(|current| current.borrow().clone()) {
+        let mut connection = connection.lock().unwrap_or_else(PoisonError::into_inner);
+        let _ = connection.write_all(&frame).and_then(|()| connection.flush());
+        return;
+    }
+    match EVENT_SINK.get() {
+        Some(sink) => {
+            let mut sink = sink.lock().unwrap_or_else(PoisonError::into_inner);
+            let _ = sink.write_all(&frame);
+        }
+        None => {
+            let mut stdout = io::stdout().lock();
+            let _ = stdout.write_all(&frame).and_then(|()| stdout.flush());
+        }
+    }
+}
+
+/// Tag the progress, warning and output events emitted by this thread with
+/// `file` until it is cleared with `None`.
+pub fn set_current_file(file: Option<&str>) {
+    CURRENT_FILE.with(|current| *current.borrow_mut() = file.map(str::to_string));
+}
+
+/// The input set with `set_current_file`, if any.
+pub fn current_file() -> Option<String> {
+    CURRENT_FILE.with(|current| current.borrow().clone())
+}
+
+/// Send the events emitted by this thread as notifications of the `serve`
+/// request `id` until it is cleared with `None`.
+pub fn set_request_id(id: Option<serde_json::Value>) {
+    REQUEST_ID.with(|current| *current.borrow_mut() = id);
+}
+
+/// Send the events emitted by this thread to `connection` instead of
+/// stdout until it is cleared with `None`.
+pub fn set_connection(connection: Option<Connection>) {
+    CONNECTION.with(|current| *current.borrow_mut() = connection);
+}
+
+/// What the events of a thread are tagged with, and where they go, to be
+/// carried over to the threads it starts for the same work.
+#[derive(Clone, Default)]
+pub struct EventTags {
+    file: Option<String>,
+    request_id: Option<serde_json::Value>,
+    connection: Option<Connection>,
+}
+
+/// The tags of the events emitted by this thread.
+pub fn event_tags() -> EventTags {
+    EventTags {
+        file: current_file(),
+        request_id: REQUEST_ID.with(|current| current.borrow().clone()),
+        connection: CONNECTION.with(|current| current.borrow().clone()),
+    }
+}
+
+/// Tag the events emitted by this thread with `tags`.
+pub fn set_event_tags(tags: EventTags) {
+    set_current_file(tags.file.as_deref());
+    set_request_id(tags.request_id);
+    set_connection(tags.connection);
+}
+
+/// An event of a `serve` request, sent as a JSON-RPC notification.
+#[derive(Debug, Serialize)]
+struct Notification<'a, T> {
+    jsonrpc: &'static str,
+    method: &'static str,
+    params: RequestEvent<'a, T>,
+}
+
+/// The fields of an event, with the id of the request it belongs to.
+#[derive(Debug, Serialize)]
+struct RequestEvent<'a, T> {
+    request_id: &'a serde_json::Value,
+    #[serde(flatten)]
+    event: &'a T,
+}
+
 /// A progress event emitted as a JSON line on stdout.
+///
+/// Where the size of the input is not known up front, as with a pipe,
+/// `total_bytes` and `progress` are null and only `bytes_processed` counts
+/// up, until the last line of the phase gives the total.
 #[derive(Debug, Serialize)]
 pub struct ProgressEvent {
-    pub progress: f64,
+    pub progress: Option<f64>,
     pub bytes_processed: u64,
-    pub total_bytes: u64,
+    pub total_bytes: Option<u64>,
     pub phase: String,
+    /// Recent speed of the phase, smoothed over a few seconds; absent
+    /// until there is a second line of it to measure against.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_per_second: Option<u64>,
+    /// Time left in the phase at `bytes_per_second`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eta_seconds: Option<f64>,
+    /// The input this event is about, in batch mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+/// The kind of an error event. The names are part of the protocol and
+/// stay stable; the messages may change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    WrongPassphrase,
+    CorruptFile,
+    PermissionError,
+    OutputExists,
+    SameFile,
+    DiskFull,
+    InsufficientMemory,
+    KdfParamsExcessive,
+    ResourceBusy,
+    InternalError,
+    Cancelled,
+    Timeout,
+    WeakPassphrase,
+    AlreadyEncrypted,
+}
+
+impl ErrorCode {
+    /// The name of the code on the wire.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::WrongPassphrase => "wrong_passphrase",
+            ErrorCode::CorruptFile => "corrupt_file",
+            ErrorCode::PermissionError => "permission_error",
+            ErrorCode::OutputExists => "output_exists",
+            ErrorCode::SameFile => "same_file",
+            ErrorCode::DiskFull => "disk_full",
+            ErrorCode::InsufficientMemory => "insufficient_memory",
+            ErrorCode::KdfParamsExcessive => "kdf_params_excessive",
+            ErrorCode::ResourceBusy => "resource_busy",
+            ErrorCode::InternalError => "internal_error",
+            ErrorCode::Cancelled => "cancelled",
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::WeakPassphrase => "weak_passphrase",
+            ErrorCode::AlreadyEncrypted => "already_encrypted",
+        }
+    }
+}
+
+/// Facts about an error for a frontend to act on, each present where it
+/// applies and is known.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ErrorDetails {
+    /// The file or directory the error is about.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// The operating system error number behind it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errno: Option<i32>,
+    /// The first payload chunk that failed authentication.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk_index: Option<u64>,
+    /// The size the container should have and the size it has.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual_size: Option<u64>,
+}
+
+impl ErrorDetails {
+    /// The path and error number of an I/O error on `path`.
+    pub fn io(path: &Path, e: &std::io::Error) -> Self {
+        ErrorDetails {
+            path: Some(path.to_string_lossy().into_owned()),
+            errno: e.raw_os_error(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == ErrorDetails::default()
+    }
+}
+
+/// An entry of the message catalogue, and the values it is filled in
+/// with. Messages without an entry of their own have the name of their
+/// error code.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MessageText {
+    pub message_id: &'static str,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub message_params: BTreeMap<&'static str, serde_json::Value>,
 }
 
 /// An error event emitted as JSON on stderr.
 #[derive(Debug, Serialize)]
 pub struct ErrorEvent {
-    pub error: String,
+    /// The name of `code`, as frontends read it before there was one.
+    pub error: &'static str,
+    pub code: ErrorCode,
+    /// For people; not meant to be parsed.
     pub message: String,
+    /// What `message` says, for a frontend to show in its own language.
+    /// Boxed like `details`.
+    #[serde(flatten)]
+    pub text: Box<MessageText>,
+    /// Boxed, as it is rarely filled in and errors are passed by value.
+    #[serde(skip_serializing_if = "ErrorDetails::is_empty")]
+    pub details: Box<ErrorDetails>,
+    /// Chunks that failed authentication, for `corrupt_file` errors that
+    /// can be pinned to part of the payload.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub bad_chunks: Vec<BadChunk>,
+    /// The offending output, for `output_exists` and `same_file` errors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_path: Option<String>,
 }
 
-/// Emit a progress JSON line to stdout.
-pub fn emit_progress(phase: &str, bytes_processed: u64, total_bytes: u64) {
-    let progress = if total_bytes > 0 {
-        bytes_processed as f64 / total_bytes as f64
+impl ErrorEvent {
+    pub fn new(code: ErrorCode, message: &str) -> Self {
+        ErrorEvent {
+            error: code.as_str(),
+            code,
+            message: message.to_string(),
+            text: Box::new(MessageText {
+                message_id: code.as_str(),
+                message_params: BTreeMap::new(),
+            }),
+            details: Box::default(),
+            bad_chunks: Vec::new(),
+            output_path: None,
+        }
+    }
+
+    /// An event whose message is the catalogue entry `message_id` filled in
+    /// with `params`; `message` is its English text.
+    pub fn with_id<const N: usize>(
+        code: ErrorCode,
+        message: &str,
+        message_id: &'static str,
+        params: [(&'static str, serde_json::Value); N],
+    ) -> Self {
+        ErrorEvent {
+            text: Box::new(MessageText {
+                message_id,
+                message_params: BTreeMap::from(params),
+            }),
+            ..ErrorEvent::new(code, message)
+        }
+    }
+}
+
+/// A warning emitted as a JSON line on stdout; the operation carries on.
+#[derive(Debug, Serialize)]
+pub struct WarningEvent {
+    pub warning: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+/// The output path chosen by the backend, emitted as a JSON line on stdout
+/// when the caller only named a directory.
+#[derive(Debug, Serialize)]
+pub struct OutputEvent {
+    pub output_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+/// The outcome for one input of a batch, emitted as a JSON line on stdout.
+/// A failure carries the fields of the error event the input would have
+/// ended with on its own.
+#[derive(Debug, Serialize)]
+pub struct FileResultEvent {
+    pub file: String,
+    /// "ok" or "failed".
+    pub result: &'static str,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorEvent>,
+}
+
+/// Confirmation, as a JSON line on stdout, that the input was removed after
+/// a successful operation.
+#[derive(Debug, Serialize)]
+pub struct InputDeletedEvent {
+    pub input_deleted: String,
+    /// The content was overwritten before the file was removed.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub shredded: bool,
+}
+
+/// Location of a chunk that failed authentication, both in the container
+/// and in the plaintext.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BadChunk {
+    pub chunk_index: u64,
+    /// Byte range of the chunk ciphertext and tag in the container.
+    pub file_offset: u64,
+    pub file_length: u64,
+    /// Byte range of the plaintext (including any padding) it would hold.
+    pub plaintext_offset: u64,
+    pub plaintext_length: u64,
+}
+
+/// How far apart progress lines of a phase are at least: in time, or as
+/// a share of the total.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressInterval {
+    Time(Duration),
+    /// Between 0 and 1.
+    Fraction(f64),
+}
+
+/// Parse a progress interval such as `250ms`, `2s` or `5%`.
+pub fn parse_progress_interval(arg: &str) -> Result<ProgressInterval, String> {
+    let arg = arg.trim();
+    let number = |digits: &str| {
+        digits
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|value| value.is_finite() && *value >= 0.0)
+            .ok_or_else(|| format!("Invalid progress interval '{}'", arg))
+    };
+    if let Some(percent) = arg.strip_suffix('%') {
+        let percent = number(percent)?;
+        if percent > 100.0 {
+            return Err(format!("Progress interval '{}' is over 100%", arg));
+        }
+        Ok(ProgressInterval::Fraction(percent / 100.0))
+    } else if let Some(millis) = arg.strip_suffix("ms") {
+        Ok(ProgressInterval::Time(Duration::from_secs_f64(number(millis)? / 1000.0)))
+    } else if let Some(secs) = arg.strip_suffix('s') {
+        Ok(ProgressInterval::Time(Duration::from_secs_f64(number(secs)?)))
     } else {
-        1.0
+        Err(format!(
+            "Invalid progress interval '{}': expected a time such as 250ms or 2s, or a \
+             percentage such as 5%",
+            arg
+        ))
+    }
+}
+
+/// Emit progress lines no closer together than `interval` from now on.
+pub fn set_progress_interval(interval: ProgressInterval) {
+    let _ = PROGRESS_INTERVAL.set(interval);
+}
+
+/// The last progress line a thread emitted.
+struct LastProgress {
+    phase: String,
+    at: Instant,
+    progress: Option<f64>,
+}
+
+/// Whether `event` is due under `interval`, given the `last` line emitted.
+/// The first and the last line of a phase always are. Without a total to
+/// take a share of, a share falls back to one line per heartbeat interval.
+fn progress_due(
+    interval: ProgressInterval,
+    last: Option<&LastProgress>,
+    event: &ProgressEvent,
+    now: Instant,
+) -> bool {
+    let finished = event.total_bytes.is_some_and(|total| event.bytes_processed >= total);
+    if event.bytes_processed == 0 || finished {
+        return true;
+    }
+    let Some(last) = last.filter(|last| last.phase == event.phase) else {
+        return true;
     };
+    let since = now.duration_since(last.at);
+    match (interval, event.progress.zip(last.progress)) {
+        (ProgressInterval::Time(interval), _) => since >= interval,
+        (ProgressInterval::Fraction(share), Some((progress, last))) => progress - last >= share,
+        (ProgressInterval::Fraction(_), None) => since >= HEARTBEAT_INTERVAL,
+    }
+}
+
+/// Time over which the reported speed follows a change in actual speed,
+/// roughly, so that it neither jumps around nor lags far behind.
+const RATE_SMOOTHING: Duration = Duration::from_secs(3);
+
+/// The smoothed speed of a phase, as of its latest progress report.
+struct Rate {
+    phase: String,
+    at: Instant,
+    bytes_processed: u64,
+    bytes_per_second: Option<f64>,
+}
+
+impl Rate {
+    /// The speed once `bytes_processed` of `phase` are done at `now`. A new
+    /// phase, or one that started over, is measured afresh.
+    fn update(last: Option<Rate>, phase: &str, bytes_processed: u64, now: Instant) -> Rate {
+        let bytes_per_second = match last {
+            Some(last) if last.phase == phase && bytes_processed >= last.bytes_processed => {
+                let elapsed = now.duration_since(last.at).as_secs_f64();
+                if elapsed > 0.0 {
+                    let sample = (bytes_processed - last.bytes_processed) as f64 / elapsed;
+                    // Exponential smoothing, weighted by the time the sample spans
+                    let weight = 1.0 - (-elapsed / RATE_SMOOTHING.as_secs_f64()).exp();
+                    Some(match last.bytes_per_second {
+                        Some(rate) => rate + weight * (sample - rate),
+                        None => sample,
+                    })
+                } else {
+                    last.bytes_per_second
+                }
+            }
+            _ => None,
+        };
+        Rate {
+            phase: phase.to_string(),
+            at: now,
+            bytes_processed,
+            bytes_per_second,
+        }
+    }
+}
+
+/// Emit a progress JSON line to stdout, unless one for the same phase was
+/// emitted too recently.
+pub fn emit_progress(phase: &str, bytes_processed: u64, total_bytes: u64) {
+    report_progress(phase, bytes_processed, Some(total_bytes));
+}
+
+/// [`emit_progress`] for input of a size that is not known until it ends.
+pub fn emit_progress_without_total(phase: &str, bytes_processed: u64) {
+    report_progress(phase, bytes_processed, None);
+}
+
+fn report_progress(phase: &str, bytes_processed: u64, total_bytes: Option<u64>) {
+    let progress = total_bytes.map(|total| {
+        if total > 0 {
+            bytes_processed as f64 / total as f64
+        } else {
+            1.0
+        }
+    });
+    // Measured on every report, including those held back below. The key
+    // derivation's progress is only an estimate, so it has no speed.
+    let bytes_per_second = RATE.with(|rate| {
+        let mut rate = rate.borrow_mut();
+        let updated = Rate::update(rate.take(), phase, bytes_processed, Instant::now());
+        rate.insert(updated).bytes_per_second.filter(|_| phase != "kdf")
+    });
+    let eta_seconds = bytes_per_second
+        .filter(|&rate| rate > 0.0)
+        .zip(total_bytes)
+        .map(|(rate, total)| total.saturating_sub(bytes_processed) as f64 / rate);
     let event = ProgressEvent {
         progress,
         bytes_processed,
         total_bytes,
         phase: phase.to_string(),
+        bytes_per_second: bytes_per_second.map(|rate| rate as u64),
+        eta_seconds,
+        file: current_file(),
     };
-    if let Ok(json) = serde_json::to_string(&event) {
-        println!("{}", json);
+    if let Some(&interval) = PROGRESS_INTERVAL.get() {
+        let now = Instant::now();
+        let due = LAST_PROGRESS.with(|last| {
+            let mut last = last.borrow_mut();
+            let due = progress_due(interval, last.as_ref(), &event, now);
+            if due {
+                *last = Some(LastProgress {
+                    phase: event.phase.clone(),
+                    at: now,
+                    progress,
+                });
+            }
+            due
+        });
+        if !due {
+            return;
+        }
     }
+    emit_event(&event);
 }
 
-/// Emit an error JSON object to stderr and exit with the given code.
-pub fn emit_error_and_exit(error_code: &str, message: &str, exit_code: i32) -> ! {
-    let event = ErrorEvent {
-        error: error_code.to_string(),
+/// Time between heartbeat lines.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sign of life during a phase with no progress to report, such as the key
+/// derivation or flushing to disk, emitted as a JSON line on stdout.
+#[derive(Debug, Serialize)]
+pub struct HeartbeatEvent {
+    pub phase: &'static str,
+    pub heartbeat: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+/// Emits heartbeat lines from a thread of its own until dropped.
+pub struct Heartbeat {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// Emit a heartbeat for `phase` every `HEARTBEAT_INTERVAL` until the
+/// returned guard is dropped.
+pub fn heartbeat(phase: &'static str) -> Heartbeat {
+    beat_every(HEARTBEAT_INTERVAL, move || {
+        let event = HeartbeatEvent {
+            phase,
+            heartbeat: true,
+            file: current_file(),
+        };
+        emit_event(&event);
+    })
+}
+
+/// Time between estimated progress lines.
+pub const TICK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Report progress for `phase` every `TICK_INTERVAL` until the returned
+/// guard is dropped, for work that cannot say how far it has got but is
+/// expected to take `estimate`. The reports follow the clock out of
+/// `total` and slow down past the estimate, so they never reach the end;
+/// the caller reports that once the work is done.
+pub fn tick_progress(phase: &'static str, total: u64, estimate: Duration) -> Heartbeat {
+    let started = Instant::now();
+    beat_every(TICK_INTERVAL, move || {
+        let done = estimated_done(started.elapsed(), estimate, total);
+        emit_progress(phase, done, total);
+    })
+}
+
+/// How much of `total` is done after `elapsed`, if it all takes `estimate`:
+/// linear up to 90%, then closing in on the end without reaching it, at a
+/// pace that keeps moving however far off the estimate was.
+fn estimated_done(elapsed: Duration, estimate: Duration, total: u64) -> u64 {
+    let share = elapsed.as_secs_f64() / estimate.as_secs_f64().max(f64::EPSILON);
+    let share = if share <= 0.9 {
+        share
+    } else {
+        0.9 + 0.1 * (1.0 - (-(share - 0.9) / 0.1).exp())
+    };
+    ((share * total as f64) as u64).min(total.saturating_sub(1))
+}
+
+/// Run `beat` on a thread of its own every `interval`, with the event tags
+/// of the calling thread.
+fn beat_every<F>(interval: Duration, beat: F) -> Heartbeat
+where
+    F: Fn() + Send + 'static,
+{
+    let tags = event_tags();
+    let (stop, stopped) = mpsc::channel::<()>();
+    let thread = thread::spawn(move || {
+        set_event_tags(tags);
+        // Ends as soon as the sender is dropped
+        while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            beat();
+        }
+    });
+    Heartbeat {
+        stop: Some(stop),
+        thread: Some(thread),
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Emit a warning JSON line to stdout.
+pub fn emit_warning(warning: &str, message: &str) {
+    let event = WarningEvent {
+        warning: warning.to_string(),
         message: message.to_string(),
+        file: current_file(),
     };
-    if let Ok(json) = serde_json::to_string(&event) {
+    emit_event(&event);
+}
+
+/// Emit the chosen output path as a JSON line to stdout.
+pub fn emit_output_path(path: &Path) {
+    let event = OutputEvent {
+        output_path: path.to_string_lossy().into_owned(),
+        file: current_file(),
+    };
+    emit_event(&event);
+}
+
+/// Emit the deleted input path as a JSON line to stdout.
+pub fn emit_input_deleted(path: &Path, shredded: bool) {
+    let event = InputDeletedEvent {
+        input_deleted: path.to_string_lossy().into_owned(),
+        shredded,
+    };
+    emit_event(&event);
+}
+
+/// A stale temporary file or directory removed by `cleanup` or
+/// `--clean-temp`, emitted as a JSON line on stdout.
+#[derive(Debug, Serialize)]
+pub struct TempRemovedEvent {
+    pub temp_removed: String,
+}
+
+/// Emit the path of a removed temporary as a JSON line to stdout.
+pub fn emit_temp_removed(path: &Path) {
+    let event = TempRemovedEvent {
+        temp_removed: path.to_string_lossy().into_owned(),
+    };
+    emit_event(&event);
+}
+
+/// Confirmation of a pause or resume control message, emitted as a JSON
+/// line on stdout: `paused` once no further chunk will be started, or
+/// `running`.
+#[derive(Debug, Serialize)]
+pub struct StateEvent {
+    pub state: &'static str,
+}
+
+/// Emit the run state as a JSON line to stdout.
+pub fn emit_state(state: &'static str) {
+    emit_event(&StateEvent { state });
+}
+
+/// The number of inputs a glob expanded to, emitted as a JSON line on
+/// stdout before the first is processed.
+#[derive(Debug, Serialize)]
+pub struct InputCountEvent {
+    pub input_count: usize,
+}
+
+/// Emit the number of inputs as a JSON line to stdout.
+pub fn emit_input_count(input_count: usize) {
+    emit_event(&InputCountEvent { input_count });
+}
+
+/// Totals for a finished batch, emitted as a JSON line on stdout after the
+/// last result line.
+#[derive(Debug, Serialize)]
+pub struct BatchSummaryEvent {
+    pub succeeded: usize,
+    /// Inputs that failed, in input order.
+    pub failed: Vec<String>,
+}
+
+/// Emit the totals for a finished batch as a JSON line to stdout.
+pub fn emit_batch_summary(succeeded: usize, failed: Vec<String>) {
+    let event = BatchSummaryEvent { succeeded, failed };
+    emit_event(&event);
+}
+
+/// Figures for a finished encryption or decryption, emitted as a JSON line
+/// on stdout once the output is in place.
+#[derive(Debug, Serialize)]
+pub struct SummaryEvent {
+    /// "encrypt" or "decrypt".
+    pub summary: &'static str,
+    pub output_path: String,
+    /// Size of the input: the plaintext, or the container across all of
+    /// its volumes.
+    pub input_bytes: u64,
+    /// Size of the output: the container, the plaintext, or the file
+    /// contents of an extracted directory.
+    pub output_bytes: u64,
+    /// Payload chunks encrypted or decrypted, padding included.
+    pub chunks: u64,
+    /// Seconds spent in each phase, under the names of its progress events.
+    pub phase_secs: BTreeMap<&'static str, f64>,
+    pub elapsed_secs: f64,
+    /// Plaintext bytes per second outside of the key derivation.
+    pub bytes_per_sec: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+/// Times the phases of one operation for its summary line.
+pub struct PhaseTimer {
+    operation: &'static str,
+    started: Instant,
+    phase_secs: BTreeMap<&'static str, f64>,
+}
+
+impl PhaseTimer {
+    /// Start timing `operation`, "encrypt" or "decrypt".
+    pub fn start(operation: &'static str) -> Self {
+        PhaseTimer {
+            operation,
+            started: Instant::now(),
+            phase_secs: BTreeMap::new(),
+        }
+    }
+
+    /// Run `f`, counting the time it takes towards `phase`.
+    pub fn time<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = f();
+        *self.phase_secs.entry(phase).or_default() += started.elapsed().as_secs_f64();
+        result
+    }
+
+    fn summary(
+        self,
+        output_path: &Path,
+        input_bytes: u64,
+        output_bytes: u64,
+        chunks: u64,
+    ) -> SummaryEvent {
+        let plaintext = if self.operation == "encrypt" { input_bytes } else { output_bytes };
+        let kdf_secs = self.phase_secs.get("kdf").copied().unwrap_or_default();
+        let elapsed_secs = self.started.elapsed().as_secs_f64();
+        let data_secs = elapsed_secs - kdf_secs;
+        SummaryEvent {
+            summary: self.operation,
+            output_path: output_path.to_string_lossy().into_owned(),
+            input_bytes,
+            output_bytes,
+            chunks,
+            phase_secs: self.phase_secs,
+            elapsed_secs,
+            bytes_per_sec: if data_secs > 0.0 { (plaintext as f64 / data_secs) as u64 } else { 0 },
+            file: current_file(),
+        }
+    }
+
+    /// Emit the summary of the finished operation as a JSON line to stdout.
+    pub fn emit(self, output_path: &Path, input_bytes: u64, output_bytes: u64, chunks: u64) {
+        let event = self.summary(output_path, input_bytes, output_bytes, chunks);
+        emit_event(&event);
+    }
+}
+
+/// Emit the outcome for one input of a batch as a JSON line to stdout.
+pub fn emit_file_result(file: &str, error: Option<ErrorEvent>) {
+    let event = FileResultEvent {
+        file: file.to_string(),
+        result: if error.is_none() { "ok" } else { "failed" },
+        error,
+    };
+    emit_event(&event);
+}
+
+/// Exit code for an output that exists while neither `--force` nor
+/// `--no-clobber` was given.
+pub const EXIT_OUTPUT_EXISTS: i32 = 4;
+
+/// Exit code for an output path that leads to the input file.
+pub const EXIT_SAME_FILE: i32 = 5;
+
+/// Exit code for an output that does not fit on its filesystem.
+pub const EXIT_DISK_FULL: i32 = 6;
+
+/// Exit code for Argon2id parameters that need more memory than is
+/// available.
+pub const EXIT_INSUFFICIENT_MEMORY: i32 = 7;
+
+/// Exit code for Argon2id parameters in a header beyond the caller's
+/// `--max-kdf-memory` or `--max-kdf-time`.
+pub const EXIT_KDF_PARAMS_EXCESSIVE: i32 = 8;
+
+/// Exit code for an output that another process is writing.
+pub const EXIT_RESOURCE_BUSY: i32 = 9;
+
+/// Exit code for a run stopped by SIGINT or SIGTERM.
+pub const EXIT_CANCELLED: i32 = 11;
+
+/// Exit code for a run stopped by `--timeout`.
+pub const EXIT_TIMEOUT: i32 = 12;
+
+/// Exit code for a passphrase to encrypt with that scores below
+/// `--min-strength`.
+pub const EXIT_WEAK_PASSPHRASE: i32 = 13;
+
+/// Exit code for an input to encrypt that is a gtkrypt container already,
+/// without `--allow-double`.
+pub const EXIT_ALREADY_ENCRYPTED: i32 = 14;
+
+/// Emit an error JSON object to stderr and exit with the given code.
+pub fn emit_error_and_exit(error_code: ErrorCode, message: &str, exit_code: i32) -> ! {
+    emit_error_event_and_exit(ErrorEvent::new(error_code, message), exit_code)
+}
+
+/// Emit a prepared error event to stderr and exit with the given code.
+pub fn emit_error_event_and_exit(event: ErrorEvent, exit_code: i32) -> ! {
+    if EVENT_FORMAT.get() == Some(&EventFormat::Human) {
+        if let Ok(value) = serde_json::to_value(&event) {
+            human::print_error(&value);
+        }
+    } else if let Ok(json) = serde_json::to_string(&event) {
         eprintln!("{}", json);
     }
     std::process::exit(exit_code);
@@ -50,30 +938,391 @@ pub fn emit_error_and_exit(error_code: &str, message: &str, exit_code: i32) -> !
 mod tests {
     use super::*;
 
+    #[cfg(unix)]
+    #[test]
+    fn test_progress_fd_must_be_open_for_writing() {
+        use std::os::fd::AsRawFd;
+
+        assert_eq!(set_progress_fd(0).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events");
+        std::fs::write(&path, b"").unwrap();
+        let read_only = File::open(&path).unwrap();
+        let err = set_progress_fd(read_only.as_raw_fd()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // Far above any descriptor the tests open
+        let closed = i32::MAX;
+        assert_eq!(set_progress_fd(closed).unwrap_err().raw_os_error(), Some(libc::EBADF));
+        assert!(EVENT_SINK.get().is_none());
+    }
+
+    #[test]
+    fn test_events_encoded_as_msgpack_frames() {
+        let event = ProgressEvent {
+            progress: Some(0.5),
+            bytes_processed: 1024,
+            total_bytes: Some(2048),
+            phase: "encrypt".to_string(),
+            bytes_per_second: None,
+            eta_seconds: None,
+            file: None,
+        };
+        let json = encode_event(EventFormat::Json, &event).unwrap();
+        assert_eq!(json.last(), Some(&b'\n'));
+        let frame = encode_event(EventFormat::Msgpack, &event).unwrap();
+        let len = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+        assert_eq!(frame.len(), 4 + len);
+        // The same fields as the JSON line, in less space
+        let decoded: serde_json::Value = rmp_serde::from_slice(&frame[4..]).unwrap();
+        assert_eq!(decoded, serde_json::from_slice::<serde_json::Value>(&json).unwrap());
+        assert!(frame.len() < json.len());
+
+        // Flattened fields come out as part of the map
+        let result = FileResultEvent {
+            file: "a.txt".to_string(),
+            result: "failed",
+            error: Some(ErrorEvent::new(ErrorCode::CorruptFile, "Truncated")),
+        };
+        let frame = encode_event(EventFormat::Msgpack, &result).unwrap();
+        let decoded: serde_json::Value = rmp_serde::from_slice(&frame[4..]).unwrap();
+        assert_eq!(decoded["message_id"], "corrupt_file");
+
+        assert_eq!(parse_event_format("msgpack"), Ok(EventFormat::Msgpack));
+        assert!(parse_event_format("cbor").is_err());
+    }
+
+    #[test]
+    fn test_request_events_are_notifications() {
+        let event = StateEvent { state: "paused" };
+        let request_id = serde_json::json!(7);
+        let notification = Notification {
+            jsonrpc: "2.0",
+            method: "event",
+            params: RequestEvent {
+                request_id: &request_id,
+                event: &event,
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&notification).unwrap(),
+            r#"{"jsonrpc":"2.0","method":"event","params":{"request_id":7,"state":"paused"}}"#
+        );
+
+        // Carried over to the threads started for the same work
+        set_request_id(Some(request_id.clone()));
+        let tags = event_tags();
+        set_request_id(None);
+        let carried = thread::spawn(move || {
+            set_event_tags(tags);
+            event_tags().request_id
+        });
+        assert_eq!(carried.join().unwrap(), Some(request_id));
+        assert_eq!(event_tags().request_id, None);
+    }
+
+    #[test]
+    fn test_events_go_to_the_connection() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        set_connection(Some(received.clone()));
+        set_request_id(Some(serde_json::json!("a")));
+        let tags = event_tags();
+        set_connection(None);
+        set_request_id(None);
+        thread::spawn(move || {
+            set_event_tags(tags);
+            emit_event(&StateEvent { state: "running" });
+        })
+        .join()
+        .unwrap();
+
+        let received = received.lock().unwrap();
+        let line: serde_json::Value = serde_json::from_slice(&received).unwrap();
+        assert_eq!(line["params"]["request_id"], "a");
+        assert_eq!(line["params"]["state"], "running");
+    }
+
+    #[test]
+    fn test_heartbeat_beats_until_dropped() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let beats = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&beats);
+        let heartbeat = beat_every(Duration::from_millis(10), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        thread::sleep(Duration::from_millis(100));
+        drop(heartbeat);
+        let after_drop = beats.load(Ordering::SeqCst);
+        assert!(after_drop >= 2, "{}", after_drop);
+
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(beats.load(Ordering::SeqCst), after_drop);
+
+        let event = HeartbeatEvent {
+            phase: "kdf",
+            heartbeat: true,
+            file: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"phase":"kdf","heartbeat":true}"#
+        );
+    }
+
+    #[test]
+    fn test_estimated_progress_never_reaches_the_end() {
+        let estimate = Duration::from_secs(4);
+        let at = |secs: f64| estimated_done(Duration::from_secs_f64(secs), estimate, 1000);
+        assert_eq!(at(0.0), 0);
+        assert_eq!(at(1.0), 250);
+        assert_eq!(at(2.0), 500);
+        // Still moving past the estimate, but short of the end
+        assert!(at(4.0) > 900 && at(4.0) < at(5.0), "{} {}", at(4.0), at(5.0));
+        assert!(at(5.0) < 1000);
+        assert_eq!(at(1000.0), 999);
+        assert_eq!(estimated_done(Duration::from_secs(1), Duration::ZERO, 1000), 999);
+        assert_eq!(estimated_done(Duration::from_secs(1), estimate, 0), 0);
+    }
+
+    #[test]
+    fn test_summary_times_phases() {
+        let mut timer = PhaseTimer::start("decrypt");
+        timer.time("kdf", || thread::sleep(Duration::from_millis(20)));
+        let written = timer.time("decrypt", || {
+            thread::sleep(Duration::from_millis(20));
+            1_000_000
+        });
+        let event = timer.summary(Path::new("/out/a.txt"), 1_000_100, written, 16);
+
+        assert_eq!(event.summary, "decrypt");
+        assert_eq!(event.output_path, "/out/a.txt");
+        assert_eq!(event.phase_secs.keys().copied().collect::<Vec<_>>(), ["decrypt", "kdf"]);
+        assert!(event.phase_secs["kdf"] >= 0.02);
+        assert!(event.elapsed_secs >= event.phase_secs.values().sum::<f64>());
+        // The key derivation does not count against the throughput
+        assert!(event.bytes_per_sec > 0 && event.bytes_per_sec <= 50_000_000);
+
+        let json = serde_json::to_string(&event).unwrap();
+        assert!(json.starts_with(r#"{"summary":"decrypt","output_path":"/out/a.txt","#));
+        assert!(json.contains(r#""input_bytes":1000100,"output_bytes":1000000,"chunks":16,"#));
+        assert!(json.contains(r#""phase_secs":{"decrypt":"#));
+        assert!(!json.contains("\"file\""));
+    }
+
+    #[test]
+    fn test_parse_progress_interval() {
+        let time = |millis| Ok(ProgressInterval::Time(Duration::from_millis(millis)));
+        assert_eq!(parse_progress_interval("250ms"), time(250));
+        assert_eq!(parse_progress_interval("2s"), time(2000));
+        assert_eq!(parse_progress_interval("0.5s"), time(500));
+        assert_eq!(parse_progress_interval("5%"), Ok(ProgressInterval::Fraction(0.05)));
+        assert!(parse_progress_interval("150%").is_err());
+        assert!(parse_progress_interval("-1s").is_err());
+        assert!(parse_progress_interval("250").is_err());
+        assert!(parse_progress_interval("fast").is_err());
+    }
+
+    #[test]
+    fn test_progress_throttled_between_bounds() {
+        let event = |phase: &str, bytes_processed: u64| ProgressEvent {
+            progress: Some(bytes_processed as f64 / 100.0),
+            bytes_processed,
+            total_bytes: Some(100),
+            phase: phase.to_string(),
+            bytes_per_second: None,
+            eta_seconds: None,
+            file: None,
+        };
+        let start = Instant::now();
+        let last = LastProgress {
+            phase: "encrypt".to_string(),
+            at: start,
+            progress: Some(0.1),
+        };
+
+        let every_10 = ProgressInterval::Fraction(0.1);
+        assert!(!progress_due(every_10, Some(&last), &event("encrypt", 15), start));
+        assert!(progress_due(every_10, Some(&last), &event("encrypt", 20), start));
+        // The ends of a phase, and a new phase, are never held back
+        assert!(progress_due(every_10, Some(&last), &event("encrypt", 100), start));
+        assert!(progress_due(every_10, Some(&last), &event("encrypt", 0), start));
+        assert!(progress_due(every_10, Some(&last), &event("verify", 11), start));
+        assert!(progress_due(every_10, None, &event("encrypt", 11), start));
+
+        let second = ProgressInterval::Time(Duration::from_secs(1));
+        let soon = start + Duration::from_millis(500);
+        let later = start + Duration::from_secs(1);
+        assert!(!progress_due(second, Some(&last), &event("encrypt", 90), soon));
+        assert!(progress_due(second, Some(&last), &event("encrypt", 11), later));
+
+        // Without a total, a share is taken as once a heartbeat interval
+        let unsized_event = ProgressEvent {
+            progress: None,
+            total_bytes: None,
+            ..event("encrypt", 1 << 30)
+        };
+        assert!(!progress_due(every_10, Some(&last), &unsized_event, soon));
+        assert!(progress_due(every_10, Some(&last), &unsized_event, later));
+        assert!(!progress_due(second, Some(&last), &unsized_event, soon));
+    }
+
+    #[test]
+    fn test_rate_is_smoothed_per_phase() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        let rate = Rate::update(None, "encrypt", 0, start);
+        assert_eq!(rate.bytes_per_second, None);
+        // 1 MB in the first second
+        let rate = Rate::update(Some(rate), "encrypt", 1_000_000, at(1000));
+        assert_eq!(rate.bytes_per_second, Some(1_000_000.0));
+        // A burst at four times the speed only moves it part of the way
+        let rate = Rate::update(Some(rate), "encrypt", 1_400_000, at(1100));
+        let smoothed = rate.bytes_per_second.unwrap();
+        assert!(smoothed > 1_000_000.0 && smoothed < 1_300_000.0, "{}", smoothed);
+
+        // A new phase starts from scratch
+        let rate = Rate::update(Some(rate), "verify", 10, at(1200));
+        assert_eq!(rate.bytes_per_second, None);
+    }
+
     #[test]
     fn test_progress_event_serialization() {
         let event = ProgressEvent {
-            progress: 0.5,
+            progress: Some(0.5),
             bytes_processed: 1024,
-            total_bytes: 2048,
+            total_bytes: Some(2048),
             phase: "encrypt".to_string(),
+            bytes_per_second: None,
+            eta_seconds: None,
+            file: None,
         };
         let json = serde_json::to_string(&event).unwrap();
         assert!(json.contains("\"progress\":0.5"));
         assert!(json.contains("\"bytes_processed\":1024"));
         assert!(json.contains("\"total_bytes\":2048"));
         assert!(json.contains("\"phase\":\"encrypt\""));
+
+        let unsized_event = ProgressEvent {
+            progress: None,
+            total_bytes: None,
+            ..event
+        };
+        assert_eq!(
+            serde_json::to_string(&unsized_event).unwrap(),
+            r#"{"progress":null,"bytes_processed":1024,"total_bytes":null,"phase":"encrypt"}"#
+        );
     }
 
     #[test]
     fn test_error_event_serialization() {
         let event = ErrorEvent {
-            error: "wrong_passphrase".to_string(),
+            error: "wrong_passphrase",
+            code: ErrorCode::WrongPassphrase,
             message: "Authentication failed".to_string(),
+            text: Box::new(MessageText {
+                message_id: "wrong_passphrase",
+                message_params: BTreeMap::new(),
+            }),
+            details: Box::default(),
+            bad_chunks: Vec::new(),
+            output_path: None,
         };
         let json = serde_json::to_string(&event).unwrap();
         assert!(json.contains("\"error\":\"wrong_passphrase\""));
         assert!(json.contains("\"message\":\"Authentication failed\""));
+        assert!(json.contains("\"code\":\"wrong_passphrase\""));
+        assert!(!json.contains("details"));
+        assert!(!json.contains("bad_chunks"));
+        assert!(!json.contains("output_path"));
+    }
+
+    #[test]
+    fn test_error_code_names_match_the_wire() {
+        use ErrorCode::*;
+        let codes = [
+            WrongPassphrase,
+            CorruptFile,
+            PermissionError,
+            OutputExists,
+            SameFile,
+            DiskFull,
+            InsufficientMemory,
+            KdfParamsExcessive,
+            ResourceBusy,
+            InternalError,
+            Cancelled,
+            Timeout,
+            WeakPassphrase,
+            AlreadyEncrypted,
+        ];
+        for code in codes {
+            assert_eq!(serde_json::to_value(code).unwrap(), code.as_str());
+        }
+    }
+
+    #[test]
+    fn test_error_event_message_ids() {
+        let plain = ErrorEvent::new(ErrorCode::DiskFull, "Disk full");
+        let json = serde_json::to_string(&plain).unwrap();
+        assert!(json.ends_with(r#""message":"Disk full","message_id":"disk_full"}"#));
+        assert!(!json.contains("message_params"));
+
+        let event = ErrorEvent::with_id(
+            ErrorCode::Timeout,
+            "Gave up after 5 seconds; nothing was written",
+            "timed_out",
+            [("seconds", 5.into())],
+        );
+        let json = serde_json::to_string(&event).unwrap();
+        assert!(json.contains(r#""message_id":"timed_out","message_params":{"seconds":5}"#));
+    }
+
+    #[test]
+    fn test_error_event_with_details() {
+        let e = std::io::Error::from_raw_os_error(13);
+        let event = ErrorEvent {
+            details: Box::new(ErrorDetails::io(Path::new("/out/a.txt"), &e)),
+            ..ErrorEvent::new(ErrorCode::PermissionError, "Cannot write")
+        };
+        let json = serde_json::to_string(&event).unwrap();
+        assert!(json.starts_with(r#"{"error":"permission_error","code":"permission_error","#));
+        assert!(json.contains(r#""details":{"path":"/out/a.txt","errno":13}"#));
+
+        let sizes = ErrorDetails {
+            expected_size: Some(300),
+            actual_size: Some(70),
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_string(&sizes).unwrap(),
+            r#"{"expected_size":300,"actual_size":70}"#
+        );
+    }
+
+    #[test]
+    fn test_error_event_with_bad_chunks() {
+        let event = ErrorEvent {
+            error: "corrupt_file",
+            code: ErrorCode::CorruptFile,
+            message: "Authentication failed for chunk 3".to_string(),
+            text: Box::new(MessageText {
+                message_id: "corrupt_file",
+                message_params: BTreeMap::new(),
+            }),
+            details: Box::default(),
+            bad_chunks: vec![BadChunk {
+                chunk_index: 3,
+                file_offset: 196_800,
+                file_length: 65_552,
+                plaintext_offset: 196_608,
+                plaintext_length: 65_536,
+            }],
+            output_path: None,
+        };
+        let json = serde_json::to_string(&event).unwrap();
+        assert!(json.contains("\"bad_chunks\":[{\"chunk_index\":3,\"file_offset\":196800"));
     }
 
     #[test]
@@ -82,11 +1331,34 @@ mod tests {
         let total: u64 = 0;
         let progress_val = if total > 0 { 0.0 } else { 1.0 };
         let event = ProgressEvent {
-            progress: progress_val,
+            progress: Some(progress_val),
             bytes_processed: 0,
-            total_bytes: 0,
+            total_bytes: Some(0),
             phase: "encrypt".to_string(),
+            bytes_per_second: None,
+            eta_seconds: None,
+            file: None,
+        };
+        assert!((event.progress.unwrap() - 1.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_file_result_event_serialization() {
+        let ok = FileResultEvent {
+            file: "a.txt".to_string(),
+            result: "ok",
+            error: None,
+        };
+        assert_eq!(serde_json::to_string(&ok).unwrap(), r#"{"file":"a.txt","result":"ok"}"#);
+
+        let failed = FileResultEvent {
+            file: "b.gtkrypt".to_string(),
+            result: "failed",
+            error: Some(ErrorEvent::new(ErrorCode::WrongPassphrase, "Authentication failed")),
         };
-        assert!((event.progress - 1.0).abs() < f64::EPSILON);
+        let json = serde_json::to_string(&failed).unwrap();
+        assert!(json.starts_with(r#"{"file":"b.gtkrypt","result":"failed","error":"wrong_"#));
+        assert!(json.contains(r#""message":"Authentication failed""#));
+        assert!(!json.contains("bad_chunks"));
     }
 }
@@ -0,0 +1,181 @@
+use crate::audit;
+use crate::decrypt::{self, DecryptError};
+use crate::encrypt::{self, ContainerLayout, EncryptError, TOOL_VERSION};
+use crate::header::{EXT_ARCHIVE, EXT_ENCRYPTED_METADATA};
+use crate::kdf::KdfParams;
+
+/// Options for re-encrypting a container.
+#[derive(Default)]
+pub struct ReencryptOptions {
+    pub input_path: String,
+    /// Where to write the new container; `None` replaces the input.
+    pub output_path: Option<String>,
+    pub passphrase: Vec<u8>,
+    /// Key material for the new container; `None` keeps the current one.
+    pub new_passphrase: Option<Vec<u8>>,
+    /// Argon2id parameters for the new container; each one left unset is
+    /// taken from the existing header.
+    pub time_cost: Option<u32>,
+    pub memory_cost_kib: Option<u32>,
+    pub parallelism: Option<u32>,
+}
+
+/// Re-encrypt a container under a fresh salt and nonce, optionally with new
+/// KDF parameters or a new passphrase.
+///
+/// The payload streams from the old container into the new one without
+/// touching the disk as plaintext. Metadata, padding, metadata encryption,
+/// parity and the archive marker are carried over. The output replaces the
+/// input only after every old chunk has authenticated.
+pub fn reencrypt(opts: &ReencryptOptions) -> Result<(), DecryptError> {
+    let result = reencrypt_file(opts);
+    audit::record("re-encrypt", &opts.input_path, &result);
+    result
+}
+
+fn reencrypt_file(opts: &ReencryptOptions) -> Result<(), DecryptError> {
+    let (header_obj, mut file_metadata, mut payload) =
+        decrypt::open_payload(&opts.input_path, &opts.passphrase)?;
+    file_metadata.tool_version = Some(TOOL_VERSION.to_string());
+
+    let kdf_params = KdfParams {
+        time_cost: opts.time_cost.unwrap_or(header_obj.kdf_params.time_cost),
+        memory_cost_kib: opts
+            .memory_cost_kib
+            .unwrap_or(header_obj.kdf_params.memory_cost_kib),
+        parallelism: opts.parallelism.unwrap_or(header_obj.kdf_params.parallelism),
+    };
+    let passphrase = opts.new_passphrase.as_ref().unwrap_or(&opts.passphrase);
+    let container_key =
+        encrypt::derive_container_key(passphrase, kdf_params).map_err(map_encrypt_error)?;
+
+    let layout = ContainerLayout {
+        encrypt_metadata: header_obj.extension(EXT_ENCRYPTED_METADATA).is_some(),
+        pad: header_obj.ciphertext_length > file_metadata.original_file_size,
+        parity: header_obj
+            .parity()
+            .map_err(decrypt::map_header_error)?
+            .map_or(0, |(_, percent)| percent),
+        archive: header_obj.extension(EXT_ARCHIVE).is_some(),
+    };
+
+    // Writing a single file over one volume of a set would orphan the rest
+    if payload.is_split() && opts.output_path.is_none() {
+        return Err(DecryptError::Internal(
+            "An output path is required for a split container".to_string(),
+        ));
+    }
+    let output_path = opts.output_path.as_deref().unwrap_or(&opts.input_path);
+    let output = encrypt::create_output(output_path, None, None).map_err(map_encrypt_error)?;
+    encrypt::write_container(
+        &mut payload,
+        output,
+        "reencrypt",
+        &container_key,
+        &file_metadata,
+        layout,
+        Default::default(),
+    )
+    .map_err(|e| payload.take_error().unwrap_or_else(|| map_encrypt_error(e)))?;
+    Ok(())
+}
+
+/// Report an error from writing the new container as a decryption error.
+pub fn map_encrypt_error(e: EncryptError) -> DecryptError {
+    match e {
+        EncryptError::Permission(msg) => DecryptError::Permission(msg),
+        EncryptError::Internal(msg) => DecryptError::Internal(msg),
+        EncryptError::DiskFull(msg) => DecryptError::DiskFull(msg),
+        EncryptError::Busy(msg) => DecryptError::Busy(msg),
+        EncryptError::AlreadyEncrypted(path) => {
+            DecryptError::Internal(format!("{} is a gtkrypt container already", path))
+        }
+        EncryptError::Cancelled => DecryptError::Cancelled,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt::tests::{encrypt_fixture, Fixture};
+    use crate::decrypt::DecryptOptions;
+    use crate::header::{self, CHUNK_SIZE};
+    use std::fs;
+    use std::path::Path;
+
+    fn encrypt_ledger(dir: &Path, plaintext: &[u8], pad: bool) -> String {
+        let fixture = Fixture {
+            filename: Some("ledger.csv"),
+            comment: Some("ledger"),
+            pad,
+            ..Default::default()
+        };
+        encrypt_fixture(dir, plaintext, b"old_pass", fixture)
+    }
+
+    fn decrypt_to_vec(path: &str, passphrase: &[u8], dir: &Path) -> Result<Vec<u8>, DecryptError> {
+        let output_path = dir.join("decrypted.bin");
+        decrypt::decrypt(&DecryptOptions {
+            input_path: path.to_string(),
+            output_path: output_path.to_str().unwrap().to_string(),
+            passphrase: passphrase.to_vec(),
+            ..Default::default()
+        })?;
+        Ok(fs::read(output_path).unwrap())
+    }
+
+    #[test]
+    fn test_reencrypt_new_passphrase_and_kdf() {
+        let plaintext: Vec<u8> = (0..CHUNK_SIZE as u32 * 2 + 77).map(|i| (i % 251) as u8).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = encrypt_ledger(dir.path(), &plaintext, false);
+        let (old_header, _) = header::decode_header(&fs::read(&path).unwrap()).unwrap();
+
+        reencrypt(&ReencryptOptions {
+            input_path: path.clone(),
+            passphrase: b"old_pass".to_vec(),
+            new_passphrase: Some(b"new_pass".to_vec()),
+            time_cost: Some(2),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let (new_header, _) = header::decode_header(&fs::read(&path).unwrap()).unwrap();
+        assert_ne!(new_header.salt, old_header.salt);
+        assert_ne!(new_header.nonce, old_header.nonce);
+        assert_eq!(new_header.kdf_params.time_cost, 2);
+        assert_eq!(new_header.kdf_params.memory_cost_kib, 1024);
+        assert_eq!(new_header.filename.as_deref(), Some("ledger.csv"));
+
+        assert!(matches!(
+            decrypt_to_vec(&path, b"old_pass", dir.path()),
+            Err(DecryptError::WrongPassphrase(_))
+        ));
+        assert_eq!(decrypt_to_vec(&path, b"new_pass", dir.path()).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn test_reencrypt_keeps_padding() {
+        let plaintext = vec![0x33u8; CHUNK_SIZE + 1];
+        let dir = tempfile::tempdir().unwrap();
+        let path = encrypt_ledger(dir.path(), &plaintext, true);
+        let size_before = fs::metadata(&path).unwrap().len();
+        let output_path = dir.path().join("rotated.gtkrypt");
+
+        reencrypt(&ReencryptOptions {
+            input_path: path.clone(),
+            output_path: Some(output_path.to_str().unwrap().to_string()),
+            passphrase: b"old_pass".to_vec(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let output = output_path.to_str().unwrap();
+        let data = fs::read(output).unwrap();
+        let (parsed, _) = header::decode_header(&data).unwrap();
+        assert!(parsed.extension(EXT_ENCRYPTED_METADATA).is_some());
+        assert!(parsed.ciphertext_length > plaintext.len() as u64);
+        assert_eq!(data.len() as u64, size_before);
+        assert_eq!(decrypt_to_vec(output, b"old_pass", dir.path()).unwrap(), plaintext);
+    }
+}
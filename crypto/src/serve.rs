@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::cancel;
+use crate::control;
+use crate::progress::{self, Connection, ErrorEvent};
+
+/// JSON-RPC error codes for requests that never reach an operation. An
+/// operation that fails uses its exit code instead.
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+
+/// A JSON-RPC 2.0 request, one per line. Without an `id` it is a
+/// notification, which gets no response.
+#[derive(Debug, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// The response to a request: its `result`, or an `error`.
+#[derive(Debug, Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+/// Why a request failed. For a failed operation, `code` is the exit code
+/// the command would have had and `data` its error event.
+#[derive(Debug, Serialize)]
+pub struct RpcError {
+    pub code: i32,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Box<ErrorEvent>>,
+}
+
+impl RpcError {
+    fn new(code: i32, message: &str) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+
+    pub fn invalid_params(message: &str) -> Self {
+        RpcError::new(INVALID_PARAMS, message)
+    }
+
+    pub fn method_not_found(method: &str) -> Self {
+        RpcError::new(METHOD_NOT_FOUND, &format!("Unknown method '{}'", method))
+    }
+}
+
+impl From<(ErrorEvent, i32)> for RpcError {
+    fn from((event, code): (ErrorEvent, i32)) -> Self {
+        RpcError {
+            code,
+            message: event.message.clone(),
+            data: Some(Box::new(event)),
+        }
+    }
+}
+
+/// The `cancel` parameters: the id of the request to stop.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CancelParams {
+    id: Value,
+}
+
+/// Requests still running, by id, with the flag that cancels each.
+#[derive(Default)]
+struct Running(Mutex<HashMap<String, Arc<AtomicBool>>>);
+
+impl Running {
+    /// Register `id`, unless a request of that id is running already.
+    fn start(&self, id: &Value) -> Option<Arc<AtomicBool>> {
+        let mut running = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if running.contains_key(&id.to_string()) {
+            return None;
+        }
+        let flag = Arc::new(AtomicBool::new(false));
+        running.insert(id.to_string(), Arc::clone(&flag));
+        Some(flag)
+    }
+
+    fn finish(&self, id: &Value) {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).remove(&id.to_string());
+    }
+
+    /// Cancel request `id`; `false` if it is not running.
+    fn cancel(&self, id: &Value) -> bool {
+        let running = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let flag = running.get(&id.to_string());
+        if let Some(flag) = flag {
+            flag.store(true, Ordering::Relaxed);
+        }
+        flag.is_some()
+    }
+}
+
+fn respond(id: Option<Value>, outcome: Result<Value, RpcError>) {
+    // A notification gets no response, whatever happened
+    let Some(id) = id else {
+        return;
+    };
+    let (result, error) = match outcome {
+        Ok(result) => (Some(result), None),
+        Err(error) => (None, Some(error)),
+    };
+    progress::emit_event(&Response {
+        jsonrpc: "2.0",
+        id,
+        result,
+        error,
+    });
+}
+
+/// Serve JSON-RPC requests read line by line from stdin until it closes,
+/// then wait for those still running.
+pub fn serve_stdio<F>(call: F)
+where
+    F: Fn(&str, Value) -> Result<Value, RpcError> + Sync,
+{
+    serve_lines(std::io::stdin().lock(), None, &call);
+}
+
+/// Whether this process runs with the effective uid of root.
+#[cfg(unix)]
+pub fn running_as_root() -> bool {
+    // SAFETY: geteuid cannot fail and has no preconditions
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+pub fn running_as_root() -> bool {
+    false
+}
+
+/// Serve the clients connecting to a socket created at `path`, each as on
+/// stdin and stdout, and all with the same `call`. Only processes of the
+/// same user are served.
+#[cfg(unix)]
+pub fn serve_socket<F>(path: &Path, call: F) -> io::Result<()>
+where
+    F: Fn(&str, Value) -> Result<Value, RpcError> + Sync,
+{
+    // Removed if the server is stopped by a signal
+    let (listener, _) = cancel::track(|| bind(path), |bound| bound.1.as_path())?;
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let call = &call;
+            scope.spawn(move || serve_connection(stream, call));
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn serve_socket<F>(_path: &Path, _call: F) -> io::Result<()>
+where
+    F: Fn(&str, Value) -> Result<Value, RpcError> + Sync,
+{
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "local sockets are only served on Unix",
+    ))
+}
+
+/// Listen at `path`, accessible to its owner only. A socket left there by
+/// a server that is gone is replaced; one that still answers is not.
+#[cfg(unix)]
+fn bind(path: &Path) -> io::Result<(UnixListener, PathBuf)> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let listener = match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            let is_socket = fs::symlink_metadata(path)?.file_type().is_socket();
+            let abandoned = UnixStream::connect(path)
+                .is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused);
+            if !is_socket || !abandoned {
+                return Err(e);
+            }
+            fs::remove_file(path)?;
+            UnixListener::bind(path)?
+        }
+        bound => bound?,
+    };
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok((listener, path.to_path_buf()))
+}
+
+/// Serve one client, unless it runs as another user.
+#[cfg(unix)]
+fn serve_connection<F>(stream: UnixStream, call: &F)
+where
+    F: Fn(&str, Value) -> Result<Value, RpcError> + Sync,
+{
+    let Ok(writer) = stream.try_clone() else {
+        return;
+    };
+    let connection: Connection = Arc::new(Mutex::new(writer));
+    // SAFETY: geteuid cannot fail and has no preconditions
+    let uid = unsafe { libc::geteuid() };
+    match peer_uid(&stream) {
+        Ok(peer) if peer == uid => {
+            serve_lines(BufReader::new(stream), Some(connection), call);
+        }
+        peer => {
+            let message = match peer {
+                Ok(peer) => format!("Clients of uid {} are not served, only of {}", peer, uid),
+                Err(e) => format!("Cannot tell who the client is: {}", e),
+            };
+            progress::set_connection(Some(connection));
+            respond(Some(Value::Null), Err(RpcError::new(INVALID_REQUEST, &message)));
+            progress::set_connection(None);
+        }
+    }
+}
+
+/// The user the process at the other end of `stream` runs as.
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: ucred is plain old data, filled in by getsockopt
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: `cred` is a buffer of `len` bytes, valid for the call
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    use std::os::fd::AsRawFd;
+
+    let (mut uid, mut gid) = (0, 0);
+    // SAFETY: `uid` and `gid` are valid for the call
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+/// Serve the requests read line by line from `input` until it closes, then
+/// wait for those still running. Events and responses go to `connection`,
+/// or stdout without one.
+///
+/// Each request runs `call` with its method and params on a thread of its
+/// own, so several can run at once. Their events go out as `event`
+/// notifications tagged with the request id, and `cancel` stops a running
+/// one at its next chunk.
+fn serve_lines<F>(input: impl BufRead, connection: Option<Connection>, call: &F)
+where
+    F: Fn(&str, Value) -> Result<Value, RpcError> + Sync,
+{
+    let running = Running::default();
+    progress::set_connection(connection.clone());
+    thread::scope(|scope| {
+        for line in input.lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let request: Request = match serde_json::from_str(&line) {
+                Ok(request) => request,
+                Err(e) => {
+                    let id = Some(Value::Null);
+                    let code = if e.is_data() { INVALID_REQUEST } else { PARSE_ERROR };
+                    respond(id, Err(RpcError::new(code, &e.to_string())));
+                    continue;
+                }
+            };
+            if request.jsonrpc != "2.0" {
+                let error = RpcError::new(INVALID_REQUEST, "Only JSON-RPC 2.0 is spoken");
+                respond(request.id, Err(error));
+                continue;
+            }
+
+            if request.method == "cancel" {
+                let outcome = serde_json::from_value(request.params)
+                    .map(|params: CancelParams| {
+                        serde_json::json!({ "cancelled": running.cancel(&params.id) })
+                    })
+                    .map_err(|e| RpcError::invalid_params(&e.to_string()));
+                respond(request.id, outcome);
+                continue;
+            }
+
+            // A notification cannot be cancelled, having no id to name it by
+            let flag = match &request.id {
+                Some(id) => match running.start(id) {
+                    Some(flag) => Some(flag),
+                    None => {
+                        let message = "A request of this id is running";
+                        let error = RpcError::new(INVALID_REQUEST, message);
+                        respond(request.id, Err(error));
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let (running, connection) = (&running, connection.clone());
+            scope.spawn(move || {
+                progress::set_connection(connection);
+                control::set_cancel_flag(flag);
+                progress::set_request_id(request.id.clone());
+                let outcome = call(&request.method, request.params);
+                progress::set_request_id(None);
+                if let Some(id) = &request.id {
+                    running.finish(id);
+                }
+                respond(request.id, outcome);
+            });
+        }
+    });
+    progress::set_connection(None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_shapes() {
+        let ok = Response {
+            jsonrpc: "2.0",
+            id: serde_json::json!(1),
+            result: Some(serde_json::json!({})),
+            error: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&ok).unwrap(),
+            r#"{"jsonrpc":"2.0","id":1,"result":{}}"#
+        );
+
+        let event = ErrorEvent::new(progress::ErrorCode::Cancelled, "Cancelled");
+        let failed = Response {
+            jsonrpc: "2.0",
+            id: serde_json::json!("a"),
+            result: None,
+            error: Some(RpcError::from((event, progress::EXIT_CANCELLED))),
+        };
+        let json = serde_json::to_value(&failed).unwrap();
+        assert_eq!(json["error"]["code"], progress::EXIT_CANCELLED);
+        assert_eq!(json["error"]["message"], "Cancelled");
+        assert_eq!(json["error"]["data"]["code"], "cancelled");
+        assert!(json.get("result").is_none());
+    }
+
+    #[test]
+    fn test_running_requests_are_cancelled_by_id() {
+        let running = Running::default();
+        let flag = running.start(&serde_json::json!(1)).unwrap();
+        // Ids are told apart by type, as JSON-RPC has them
+        assert!(running.start(&serde_json::json!(1)).is_none());
+        assert!(running.start(&serde_json::json!("1")).is_some());
+
+        assert!(!running.cancel(&serde_json::json!(2)));
+        assert!(running.cancel(&serde_json::json!(1)));
+        assert!(flag.load(Ordering::Relaxed));
+
+        running.finish(&serde_json::json!(1));
+        assert!(!running.cancel(&serde_json::json!(1)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_replaces_only_abandoned_sockets() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gtkrypt.sock");
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let (listener, _) = bind(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(bind(&path).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+        drop(listener);
+
+        let file = dir.path().join("not-a-socket");
+        fs::write(&file, b"data").unwrap();
+        assert!(bind(&file).is_err());
+        assert_eq!(fs::read(&file).unwrap(), b"data");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_peer_is_this_user() {
+        let (ours, _theirs) = UnixStream::pair().unwrap();
+        // SAFETY: geteuid cannot fail and has no preconditions
+        assert_eq!(peer_uid(&ours).unwrap(), unsafe { libc::geteuid() });
+    }
+}
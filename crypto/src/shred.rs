@@ -0,0 +1,68 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use rand::RngCore;
+
+use crate::header::CHUNK_SIZE;
+use crate::progress;
+
+/// Overwrite a file with random data `passes` times, flushing each pass to
+/// disk, then truncate and remove it.
+///
+/// This is best effort: copy-on-write and journaling filesystems, SSD wear
+/// levelling and snapshots can all keep the old blocks around, which the
+/// caller is warned about.
+pub fn shred(path: &Path, passes: u32) -> io::Result<()> {
+    progress::emit_warning(
+        "shred_best_effort",
+        "Overwriting cannot guarantee removal on SSDs, copy-on-write or journaling \
+         filesystems, or where snapshots and backups exist",
+    );
+
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let len = file.metadata()?.len();
+    let total = len * u64::from(passes);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut done = 0u64;
+    progress::emit_progress("shred", 0, total);
+
+    for _ in 0..passes {
+        file.seek(SeekFrom::Start(0))?;
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = std::cmp::min(remaining, buf.len() as u64) as usize;
+            rand::thread_rng().fill_bytes(&mut buf[..n]);
+            file.write_all(&buf[..n])?;
+            remaining -= n as u64;
+            done += n as u64;
+            progress::emit_progress("shred", done, total);
+        }
+        file.sync_all()?;
+    }
+
+    file.set_len(0)?;
+    file.sync_all()?;
+    drop(file);
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shred_removes_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret.txt");
+        fs::write(&path, vec![0x41u8; CHUNK_SIZE + 100]).unwrap();
+        // A second name for the inode shows what became of the data
+        let witness = dir.path().join("witness");
+        fs::hard_link(&path, &witness).unwrap();
+
+        shred(&path, 2).unwrap();
+        assert!(!path.exists());
+        assert_eq!(fs::metadata(&witness).unwrap().len(), 0);
+        assert!(shred(&path, 1).is_err());
+    }
+}
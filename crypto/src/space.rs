@@ -0,0 +1,106 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// Bytes an unprivileged user can still write to the filesystem holding
+/// `dir`.
+#[cfg(unix)]
+pub fn available(dir: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: statvfs is plain old data, for which all zeros is valid
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is NUL-terminated and `stats` is a valid out pointer
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((stats.f_bavail as u64).saturating_mul(stats.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+pub fn available(_dir: &Path) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "free space is not known on this platform",
+    ))
+}
+
+/// Check that the filesystem holding `dir` has room for `needed` more
+/// bytes, failing with a `StorageFull` error otherwise. Where the free
+/// space cannot be read, the check passes and the writes themselves will
+/// tell.
+pub fn check(dir: &Path, needed: u64) -> io::Result<()> {
+    match available(dir) {
+        Ok(available) if available < needed => Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "Not enough space in {}: {} bytes needed, {} available",
+                display_dir(dir),
+                needed,
+                available
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Reserve `len` bytes on disk for `file` without changing its size, so
+/// that running out of space shows before streaming rather than part way
+/// through. Filesystems that cannot reserve space are left alone; only a
+/// full disk or quota is an error.
+#[cfg(target_os = "linux")]
+pub fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if len == 0 {
+        return Ok(());
+    }
+    // SAFETY: fallocate only reads its integer arguments
+    let result = unsafe {
+        libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len as libc::off_t)
+    };
+    if result < 0 {
+        let err = io::Error::last_os_error();
+        if is_disk_full(&err) {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn preallocate(_file: &File, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// Whether an I/O error means the disk or the user's quota is full.
+pub fn is_disk_full(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded)
+}
+
+fn display_dir(dir: &Path) -> String {
+    if dir.as_os_str().is_empty() {
+        ".".to_string()
+    } else {
+        dir.display().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_free_space() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(check(dir.path(), 1).is_ok());
+        let err = check(dir.path(), u64::MAX).unwrap_err();
+        assert!(is_disk_full(&err));
+        assert!(err.to_string().starts_with("Not enough space in "), "{}", err);
+        assert!(!is_disk_full(&io::ErrorKind::PermissionDenied.into()));
+    }
+}
@@ -0,0 +1,239 @@
+use serde::Serialize;
+
+use crate::decrypt::{self, DecryptError};
+use crate::header::{CHUNK_SIZE, TAG_LEN};
+use crate::inspect::{self, InspectOptions};
+
+/// Where the bytes of one container go, as reported by `stats`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ContainerStats {
+    pub file: String,
+    pub version: u8,
+    pub header_size: usize,
+    pub chunks: u64,
+    pub chunk_size: usize,
+    /// Bytes each chunk adds: its authentication tag.
+    pub chunk_overhead: usize,
+    /// The tags of all chunks.
+    pub tags_size: u64,
+    /// The chunk index after the last chunk.
+    pub footer_size: u64,
+    pub parity_size: u64,
+    /// Plaintext in the payload, padding included.
+    pub ciphertext_length: u64,
+    /// `None` when the metadata, and with it the original size, is
+    /// encrypted and no passphrase was given.
+    pub original_file_size: Option<u64>,
+    pub padding_size: Option<u64>,
+    /// Size on disk, across all volumes.
+    pub container_size: u64,
+    /// Bytes beyond the original, or beyond the padded payload when the
+    /// original size is unknown.
+    pub overhead: u64,
+    /// `overhead` as a percentage of the size it is counted from.
+    pub overhead_percent: Option<f64>,
+}
+
+/// Totals over the containers of a `stats` run.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StatsTotal {
+    pub containers: usize,
+    /// The original sizes, or the padded payloads where unknown.
+    pub original_size: u64,
+    pub container_size: u64,
+    pub overhead: u64,
+    pub overhead_percent: Option<f64>,
+}
+
+/// Printed by `stats`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StatsReport {
+    pub containers: Vec<ContainerStats>,
+    pub total: StatsTotal,
+}
+
+impl StatsReport {
+    /// Render the report as `Key: value` lines, a block per container and
+    /// a total line for several.
+    pub fn to_text(&self) -> String {
+        let mut blocks: Vec<String> =
+            self.containers.iter().map(ContainerStats::to_text).collect();
+        if self.containers.len() > 1 {
+            blocks.push(format!(
+                "Total: {} containers, {} bytes stored in {} bytes; overhead {}",
+                self.total.containers,
+                self.total.original_size,
+                self.total.container_size,
+                overhead_text(self.total.overhead, self.total.overhead_percent)
+            ));
+        }
+        blocks.join("\n\n")
+    }
+}
+
+impl ContainerStats {
+    fn to_text(&self) -> String {
+        let unknown = "unknown (metadata encrypted; see --unlock)".to_string();
+        [
+            format!("File: {}", self.file),
+            format!("Format version: {}", self.version),
+            format!(
+                "Chunks: {} of up to {} bytes, {} bytes of tag each",
+                self.chunks, self.chunk_size, self.chunk_overhead
+            ),
+            format!("Header: {} bytes", self.header_size),
+            format!("Tags: {} bytes", self.tags_size),
+            format!("Chunk index: {} bytes", self.footer_size),
+            format!("Parity: {} bytes", self.parity_size),
+            format!("Padding: {}", self.padding_size.map_or(unknown.clone(), bytes_text)),
+            format!("Original size: {}", self.original_file_size.map_or(unknown, bytes_text)),
+            format!("Container size: {} bytes", self.container_size),
+            format!("Overhead: {}", overhead_text(self.overhead, self.overhead_percent)),
+        ]
+        .join("\n")
+    }
+}
+
+fn bytes_text(bytes: u64) -> String {
+    format!("{} bytes", bytes)
+}
+
+fn overhead_text(overhead: u64, percent: Option<f64>) -> String {
+    match percent {
+        Some(percent) => format!("{} bytes ({:.2}%)", overhead, percent),
+        None => bytes_text(overhead),
+    }
+}
+
+/// `part` as a percentage of `whole`, if there is a whole.
+fn percent(part: u64, whole: u64) -> Option<f64> {
+    (whole > 0).then(|| part as f64 * 100.0 / whole as f64)
+}
+
+/// Account for the bytes of each container in `inputs` from its header.
+/// With `passphrase`, encrypted metadata is opened for the original size
+/// and with it the padding. The payload is never read.
+pub fn stats(
+    inputs: &[String],
+    passphrase: Option<&[u8]>,
+) -> Result<StatsReport, DecryptError> {
+    let containers = inputs
+        .iter()
+        .map(|input| container_stats(input, passphrase))
+        .collect::<Result<Vec<_>, _>>()?;
+    let original_size = containers
+        .iter()
+        .map(|stats| stats.original_file_size.unwrap_or(stats.ciphertext_length))
+        .sum();
+    let container_size = containers.iter().map(|stats| stats.container_size).sum();
+    let overhead = containers.iter().map(|stats| stats.overhead).sum();
+    Ok(StatsReport {
+        total: StatsTotal {
+            containers: containers.len(),
+            original_size,
+            container_size,
+            overhead,
+            overhead_percent: percent(overhead, original_size),
+        },
+        containers,
+    })
+}
+
+fn container_stats(
+    input: &str,
+    passphrase: Option<&[u8]>,
+) -> Result<ContainerStats, DecryptError> {
+    let (reader, header_obj, header_size, _header_bytes) = decrypt::open_container(input)?;
+    let container_size = reader.get_ref().size();
+    let chunks = header_obj.ciphertext_length.div_ceil(CHUNK_SIZE as u64);
+    let footer_size = header_obj.footer_len().map_err(decrypt::map_header_error)?.unwrap_or(0);
+    let parity = header_obj.parity().map_err(decrypt::map_header_error)?;
+    let original_file_size = inspect::inspect(&InspectOptions {
+        input_path: input.to_string(),
+        passphrase: passphrase.map(<[u8]>::to_vec),
+    })?
+    .original_file_size;
+    let base = original_file_size.unwrap_or(header_obj.ciphertext_length);
+    let overhead = container_size.saturating_sub(base);
+    Ok(ContainerStats {
+        file: input.to_string(),
+        version: header_obj.version,
+        header_size,
+        chunks,
+        chunk_size: CHUNK_SIZE,
+        chunk_overhead: TAG_LEN,
+        tags_size: chunks * TAG_LEN as u64,
+        footer_size,
+        parity_size: parity.map_or(0, |(len, _)| len),
+        ciphertext_length: header_obj.ciphertext_length,
+        original_file_size,
+        padding_size: original_file_size
+            .map(|size| header_obj.ciphertext_length.saturating_sub(size)),
+        container_size,
+        overhead,
+        overhead_percent: percent(overhead, base),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt::tests::{encrypt_fixture, Fixture};
+
+    #[test]
+    fn test_container_bytes_are_accounted_for() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = encrypt_fixture(dir.path(), &[5u8; 200_000], b"pass", Fixture::default());
+        let padded = encrypt_fixture(
+            dir.path(),
+            &[5u8; 1000],
+            b"pass",
+            Fixture {
+                filename: Some("padded.bin"),
+                pad: true,
+                ..Default::default()
+            },
+        );
+        let report = stats(&[plain.clone(), padded.clone()], None).unwrap();
+
+        let plain_stats = &report.containers[0];
+        assert_eq!(plain_stats.file, plain);
+        assert_eq!(plain_stats.chunks, 4);
+        assert_eq!(plain_stats.tags_size, 4 * TAG_LEN as u64);
+        assert_eq!(plain_stats.original_file_size, Some(200_000));
+        assert_eq!(plain_stats.padding_size, Some(0));
+        assert_eq!(
+            plain_stats.container_size,
+            plain_stats.header_size as u64
+                + plain_stats.ciphertext_length
+                + plain_stats.tags_size
+                + plain_stats.footer_size
+                + plain_stats.parity_size
+        );
+        assert_eq!(plain_stats.overhead, plain_stats.container_size - 200_000);
+
+        // Padding hides the original size in encrypted metadata
+        let padded_stats = &report.containers[1];
+        assert_eq!(padded_stats.ciphertext_length, 1024);
+        assert_eq!(padded_stats.original_file_size, None);
+        assert_eq!(padded_stats.padding_size, None);
+        assert_eq!(padded_stats.overhead, padded_stats.container_size - 1024);
+
+        assert_eq!(report.total.containers, 2);
+        assert_eq!(report.total.original_size, 201_024);
+        assert_eq!(report.total.overhead, plain_stats.overhead + padded_stats.overhead);
+        assert!(report.to_text().contains("\n\nTotal: 2 containers, 201024 bytes stored in "));
+
+        let unlocked = stats(&[padded], Some(b"pass")).unwrap();
+        let padded_stats = &unlocked.containers[0];
+        assert_eq!(padded_stats.original_file_size, Some(1000));
+        assert_eq!(padded_stats.padding_size, Some(24));
+        assert_eq!(padded_stats.overhead, padded_stats.container_size - 1000);
+    }
+
+    #[test]
+    fn test_percent() {
+        assert_eq!(percent(5, 200), Some(2.5));
+        assert_eq!(percent(371, 0), None);
+    }
+}
@@ -0,0 +1,492 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::progress::{ErrorCode, ErrorEvent};
+
+/// How hard a passphrase is to guess, emitted as a JSON line on stdout
+/// before encrypting, for a strength meter. Scored as zxcvbn scores: by
+/// the guesses needed by an attacker who tries common passwords, words,
+/// sequences, keyboard runs, repeats and years before anything else.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Strength {
+    /// 0 (guessed at once) to 4 (out of reach).
+    pub passphrase_strength: u8,
+    pub guesses_log10: f64,
+    /// Time to guess it at `GUESSES_PER_SEC`.
+    pub crack_time_secs: f64,
+    /// The same in words, as "3 hours" or "centuries".
+    pub crack_time: String,
+}
+
+/// Guesses a second of an offline attack on a slow hash, as zxcvbn assumes.
+/// Argon2id with the default parameters is slower still.
+pub const GUESSES_PER_SEC: f64 = 1e4;
+
+/// Characters weighed; more could only add strength.
+const MAX_CHARS: usize = 100;
+
+/// Common passwords and words, most common first.
+const COMMON: &[&str] = &[
+    "123456", "password", "123456789", "12345678", "12345", "qwerty", "1234567", "111111",
+    "1234567890", "123123", "abc123", "1234", "password1", "iloveyou", "1q2w3e4r", "000000",
+    "qwerty123", "zaq12wsx", "dragon", "sunshine", "princess", "letmein", "654321", "monkey",
+    "1qaz2wsx", "123321", "qwertyuiop", "superman", "asdfghjkl", "trustno1", "football",
+    "baseball", "welcome", "shadow", "master", "hello", "freedom", "whatever", "qazwsx",
+    "michael", "jennifer", "jordan", "hunter", "ashley", "mustang", "access", "login", "admin",
+    "secret", "starwars", "charlie", "batman", "flower", "cheese", "computer", "pepper",
+    "summer", "winter", "spring", "autumn", "orange", "banana", "apple", "soccer", "hockey",
+    "killer", "ranger", "buster", "tigger", "thomas", "robert", "daniel", "george", "maggie",
+    "ginger", "cookie", "love", "lovely", "angel", "family", "friend", "forever", "matrix",
+    "nothing", "changeme", "default", "secure", "private", "encrypt", "gtkrypt", "test",
+    "guest", "root", "user", "pass", "word", "correct", "horse", "battery", "staple", "god",
+    "money", "dog", "cat", "blue", "red", "green", "black", "white", "house", "home", "music",
+    "happy", "life", "time", "world", "secret", "open", "sesame", "mother", "father", "baby",
+];
+
+/// Letters, and the characters standing in for them in l33t speak.
+const L33T: &[(char, char)] = &[
+    ('4', 'a'),
+    ('@', 'a'),
+    ('8', 'b'),
+    ('(', 'c'),
+    ('3', 'e'),
+    ('6', 'g'),
+    ('1', 'i'),
+    ('!', 'i'),
+    ('|', 'l'),
+    ('0', 'o'),
+    ('$', 's'),
+    ('5', 's'),
+    ('7', 't'),
+    ('+', 't'),
+    ('2', 'z'),
+];
+
+/// Rows of a QWERTY keyboard, unshifted and shifted.
+const KEYBOARD_ROWS: &[&str] = &[
+    "`1234567890-=",
+    "qwertyuiop[]\\",
+    "asdfghjkl;'",
+    "zxcvbnm,./",
+    "~!@#$%^&*()_+",
+    "QWERTYUIOP{}|",
+    "ASDFGHJKL:\"",
+    "ZXCVBNM<>?",
+];
+
+/// The least a run of more than one character counts for, so that several
+/// short matches are not scored below what they cost to try.
+const MIN_SUBMATCH_GUESSES: f64 = 50.0;
+
+/// Each further match in a passphrase multiplies the patterns to try by
+/// this, so that many short matches are not scored as a few.
+const MIN_GUESSES_BEFORE_GROWING: f64 = 1e4;
+
+/// Least score a passphrase to encrypt with must have, set with
+/// `--min-strength`.
+static MINIMUM: OnceLock<u8> = OnceLock::new();
+
+/// Refuse passphrases to encrypt with that score below `minimum`.
+pub fn set_minimum(minimum: u8) {
+    let _ = MINIMUM.set(minimum);
+}
+
+/// The error for a passphrase that scores below the `--min-strength`.
+pub fn check(strength: &Strength) -> Result<(), ErrorEvent> {
+    let minimum = MINIMUM.get().copied().unwrap_or(0);
+    if strength.passphrase_strength >= minimum {
+        return Ok(());
+    }
+    Err(ErrorEvent::with_id(
+        ErrorCode::WeakPassphrase,
+        &format!(
+            "Passphrase is too weak: strength {} of 4, below the minimum of {}; it could be \
+             guessed in {}",
+            strength.passphrase_strength, minimum, strength.crack_time
+        ),
+        "passphrase_too_weak",
+        [
+            ("strength", strength.passphrase_strength.into()),
+            ("minimum", minimum.into()),
+        ],
+    ))
+}
+
+/// Estimate how hard `passphrase` is to guess. Bytes that are not UTF-8
+/// are weighed as replacement characters.
+pub fn estimate(passphrase: &[u8]) -> Strength {
+    let chars: Vec<char> = String::from_utf8_lossy(passphrase).chars().take(MAX_CHARS).collect();
+    let guesses = Estimator::default().fewest_guesses(&chars);
+    let crack_time_secs = guesses / GUESSES_PER_SEC;
+    Strength {
+        passphrase_strength: score(guesses),
+        guesses_log10: guesses.log10(),
+        crack_time_secs,
+        crack_time: display_time(crack_time_secs),
+    }
+}
+
+fn score(guesses: f64) -> u8 {
+    match guesses {
+        g if g < 1e3 + 5.0 => 0,
+        g if g < 1e6 + 5.0 => 1,
+        g if g < 1e8 + 5.0 => 2,
+        g if g < 1e10 + 5.0 => 3,
+        _ => 4,
+    }
+}
+
+fn display_time(secs: f64) -> String {
+    const UNITS: &[(&str, f64)] = &[
+        ("second", 1.0),
+        ("minute", 60.0),
+        ("hour", 3600.0),
+        ("day", 86_400.0),
+        ("month", 2_629_746.0),
+        ("year", 31_556_952.0),
+    ];
+    if secs < 1.0 {
+        return "less than a second".to_string();
+    }
+    if secs >= 100.0 * 31_556_952.0 {
+        return "centuries".to_string();
+    }
+    let (unit, size) = UNITS.iter().rev().find(|(_, size)| secs >= *size).unwrap_or(&UNITS[0]);
+    let count = (secs / size).round() as u64;
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+/// A stretch `start..end` of the passphrase that an attacker would find in
+/// `guesses` tries.
+#[derive(Debug, Clone, Copy)]
+struct Match {
+    start: usize,
+    end: usize,
+    guesses: f64,
+}
+
+#[derive(Default)]
+struct Estimator {
+    /// Fewest guesses of the repeated blocks seen so far.
+    blocks: HashMap<Vec<char>, f64>,
+}
+
+impl Estimator {
+    /// The fewest guesses over every way of splitting `chars` into matches
+    /// and characters tried one by one, counted as zxcvbn counts them.
+    fn fewest_guesses(&mut self, chars: &[char]) -> f64 {
+        let n = chars.len();
+        if n == 0 {
+            return 1.0;
+        }
+        let mut ending: Vec<Vec<Match>> = vec![Vec::new(); n + 1];
+        for found in self.matches(chars) {
+            ending[found.end].push(found);
+        }
+        // best[k][l]: fewest guesses of the first k characters as l matches
+        let mut best = vec![vec![f64::INFINITY; n + 1]; n + 1];
+        best[0][0] = 1.0;
+        for end in 1..=n {
+            let brute_force = (0..end).map(|start| Match {
+                start,
+                end,
+                guesses: brute_force_guesses(end - start),
+            });
+            for found in ending[end].iter().copied().chain(brute_force) {
+                for count in 1..=found.start + 1 {
+                    let before = best[found.start][count - 1];
+                    let guesses = before * found.guesses;
+                    if guesses < best[end][count] {
+                        best[end][count] = guesses;
+                    }
+                }
+            }
+        }
+        (1..=n)
+            .filter(|&count| best[n][count].is_finite())
+            .map(|count| {
+                factorial(count) * best[n][count]
+                    + MIN_GUESSES_BEFORE_GROWING.powi(count as i32 - 1)
+            })
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    fn matches(&mut self, chars: &[char]) -> Vec<Match> {
+        let mut found = dictionary_matches(chars);
+        found.extend(sequence_matches(chars));
+        found.extend(keyboard_matches(chars));
+        found.extend(year_matches(chars));
+        found.extend(self.repeat_matches(chars));
+        for found in &mut found {
+            let floor = if found.end - found.start == 1 { 10.0 } else { MIN_SUBMATCH_GUESSES };
+            found.guesses = found.guesses.max(floor);
+        }
+        found
+    }
+
+    /// A block said over and over, as "aaaa" or "abcabc", is guessed as
+    /// the block and the number of times.
+    fn repeat_matches(&mut self, chars: &[char]) -> Vec<Match> {
+        let mut found = Vec::new();
+        let n = chars.len();
+        for start in 0..n {
+            for len in 1..=(n - start) / 2 {
+                let block = &chars[start..start + len];
+                let times = chars[start..].chunks(len).take_while(|chunk| *chunk == block).count();
+                if times < 2 || (len == 1 && times < 3) {
+                    continue;
+                }
+                let block_guesses = match self.blocks.get(block) {
+                    Some(&guesses) => guesses,
+                    None => {
+                        let guesses = self.fewest_guesses(block);
+                        self.blocks.insert(block.to_vec(), guesses);
+                        guesses
+                    }
+                };
+                found.push(Match {
+                    start,
+                    end: start + len * times,
+                    guesses: block_guesses * times as f64,
+                });
+                break;
+            }
+        }
+        found
+    }
+}
+
+fn brute_force_guesses(len: usize) -> f64 {
+    let guesses = 10f64.powi(len as i32);
+    guesses.max(if len == 1 { 11.0 } else { MIN_SUBMATCH_GUESSES + 1.0 })
+}
+
+fn factorial(n: usize) -> f64 {
+    (2..=n).map(|i| i as f64).product()
+}
+
+fn binomial(n: usize, k: usize) -> f64 {
+    (0..k).map(|i| (n - i) as f64 / (i + 1) as f64).product()
+}
+
+/// Common words, spelt forwards or backwards, in any case and with letters
+/// swapped for look-alike characters.
+fn dictionary_matches(chars: &[char]) -> Vec<Match> {
+    static RANKS: OnceLock<HashMap<&str, usize>> = OnceLock::new();
+    let ranks = RANKS.get_or_init(|| {
+        let mut ranks = HashMap::new();
+        for (index, word) in COMMON.iter().enumerate() {
+            ranks.entry(*word).or_insert(index + 1);
+        }
+        ranks
+    });
+
+    let mut found = Vec::new();
+    for start in 0..chars.len() {
+        for end in start + 1..=chars.len() {
+            let word = &chars[start..end];
+            let typed: String = word.iter().map(char::to_ascii_lowercase).collect();
+            let unl33ted: String = typed.chars().map(unl33t).collect();
+            let readings = [(&typed, 1.0), (&unl33ted, l33t_variations(word))];
+            let guesses = readings
+                .iter()
+                .flat_map(|(reading, l33t)| {
+                    let reversed: String = reading.chars().rev().collect();
+                    let forward = ranks.get(reading.as_str()).map(|&rank| rank as f64 * l33t);
+                    let backward = ranks
+                        .get(reversed.as_str())
+                        .filter(|_| end - start > 1)
+                        .map(|&rank| rank as f64 * l33t * 2.0);
+                    forward.into_iter().chain(backward)
+                })
+                .reduce(f64::min);
+            let Some(guesses) = guesses else {
+                continue;
+            };
+            let guesses = guesses * case_variations(word);
+            found.push(Match { start, end, guesses });
+        }
+    }
+    found
+}
+
+fn unl33t(c: char) -> char {
+    L33T.iter().find(|(l33t, _)| *l33t == c).map_or(c, |(_, letter)| *letter)
+}
+
+/// The ways the letters of `word` could have been capitalised, with all
+/// lower case, all upper case and a capital first letter tried first.
+fn case_variations(word: &[char]) -> f64 {
+    let upper = word.iter().filter(|c| c.is_uppercase()).count();
+    let lower = word.iter().filter(|c| c.is_lowercase()).count();
+    if upper == 0 {
+        return 1.0;
+    }
+    let first_only = upper == 1 && word[0].is_uppercase();
+    if lower == 0 || first_only {
+        return 2.0;
+    }
+    (1..=upper.min(lower)).map(|i| binomial(upper + lower, i)).sum()
+}
+
+/// The ways the l33t characters in `word` could have been placed.
+fn l33t_variations(word: &[char]) -> f64 {
+    let mut variations = 1.0;
+    for &(l33t, letter) in L33T {
+        let swapped = word.iter().filter(|&&c| c == l33t).count();
+        if swapped == 0 {
+            continue;
+        }
+        let kept = word.iter().filter(|c| c.to_ascii_lowercase() == letter).count();
+        variations *= if kept == 0 {
+            2.0
+        } else {
+            (1..=swapped.min(kept)).map(|i| binomial(swapped + kept, i)).sum()
+        };
+    }
+    variations
+}
+
+/// Runs of three or more characters a fixed step apart, as "abc", "9753"
+/// or "zyx".
+fn sequence_matches(chars: &[char]) -> Vec<Match> {
+    let mut found = Vec::new();
+    let mut start = 0;
+    while start + 2 < chars.len() {
+        let step = chars[start + 1] as i64 - chars[start] as i64;
+        let mut end = start + 2;
+        while end < chars.len() && chars[end] as i64 - chars[end - 1] as i64 == step {
+            end += 1;
+        }
+        if end - start >= 3 && step != 0 && step.abs() <= 5 {
+            let first = chars[start];
+            let base = if matches!(first, 'a' | 'A' | 'z' | 'Z' | '0' | '1' | '9') {
+                4.0
+            } else if first.is_ascii_digit() {
+                10.0
+            } else {
+                26.0
+            };
+            let direction = if step < 0 { 2.0 } else { 1.0 };
+            found.push(Match {
+                start,
+                end,
+                guesses: base * (end - start) as f64 * direction,
+            });
+            start = end - 1;
+        } else {
+            start += 1;
+        }
+    }
+    found
+}
+
+/// Runs of three or more neighbouring keys along a keyboard row, either way.
+fn keyboard_matches(chars: &[char]) -> Vec<Match> {
+    let neighbours = |a: char, b: char| {
+        KEYBOARD_ROWS.iter().any(|row| {
+            let row: Vec<char> = row.chars().collect();
+            row.windows(2).any(|pair| pair == [a, b] || pair == [b, a])
+        })
+    };
+    let mut found = Vec::new();
+    let mut start = 0;
+    while start < chars.len() {
+        let mut end = start + 1;
+        while end < chars.len() && neighbours(chars[end - 1], chars[end]) {
+            end += 1;
+        }
+        if end - start >= 3 {
+            // Any of the keys to start from, either way along its row
+            let keys: usize = KEYBOARD_ROWS.iter().map(|row| row.chars().count()).sum();
+            found.push(Match {
+                start,
+                end,
+                guesses: (keys * 2 * (end - start)) as f64,
+            });
+        }
+        start = end;
+    }
+    found
+}
+
+/// Years from 1900 to 2099, guessed outward from the present one.
+fn year_matches(chars: &[char]) -> Vec<Match> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let this_year = 1970 + (now.as_secs() / 31_556_952) as i64;
+    chars
+        .windows(4)
+        .enumerate()
+        .filter_map(|(start, digits)| {
+            let year: String = digits.iter().collect();
+            let year: i64 = year.parse().ok().filter(|year| (1900..2100).contains(year))?;
+            Some(Match {
+                start,
+                end: start + 4,
+                guesses: (year - this_year).abs().max(20) as f64,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scored(passphrase: &str) -> u8 {
+        estimate(passphrase.as_bytes()).passphrase_strength
+    }
+
+    #[test]
+    fn test_common_patterns_score_low() {
+        let weak = ["password", "password1", "P@ssw0rd", "drowssap", "123456789", "aaaaaaaa"];
+        for weak in weak {
+            assert_eq!(scored(weak), 0, "{}", weak);
+        }
+        for weak in ["abcdefgh", "asdfghjkl;", "monkey1990", "abcabcabc", "Summer2024"] {
+            assert!(scored(weak) <= 1, "{}", weak);
+        }
+    }
+
+    #[test]
+    fn test_unpatterned_passphrases_score_high() {
+        assert!(scored("Tr0ub4dor&3") >= 2);
+        assert!(scored("correct horse battery staple") >= 3);
+        assert_eq!(scored("vX9#qL2!mZ7@pR4w"), 4);
+        assert_eq!(scored("glacier pocket umbrella ninety"), 4);
+    }
+
+    #[test]
+    fn test_estimate_fields() {
+        // The second most common password, at the floor for a match
+        let strength = estimate(b"password");
+        assert_eq!(strength.guesses_log10, (MIN_SUBMATCH_GUESSES + 1.0).log10());
+        assert_eq!(strength.crack_time, "less than a second");
+        let json = serde_json::to_value(&strength).unwrap();
+        assert_eq!(json["passphrase_strength"], 0);
+        assert!(json["crack_time_secs"].is_f64());
+    }
+
+    #[test]
+    fn test_display_time() {
+        assert_eq!(display_time(0.5), "less than a second");
+        assert_eq!(display_time(1.0), "1 second");
+        assert_eq!(display_time(150.0), "3 minutes");
+        assert_eq!(display_time(86_400.0 * 2.0), "2 days");
+        assert_eq!(display_time(31_556_952.0 * 99.0), "99 years");
+        assert_eq!(display_time(1e300), "centuries");
+    }
+
+    #[test]
+    fn test_minimum_strength() {
+        let weak = estimate(b"letmein");
+        set_minimum(3);
+        let refused = check(&weak).unwrap_err();
+        assert_eq!(refused.code, ErrorCode::WeakPassphrase);
+        assert!(refused.message.contains("below the minimum of 3"), "{}", refused.message);
+        assert!(check(&estimate(b"vX9#qL2!mZ7@pR4w")).is_ok());
+    }
+}
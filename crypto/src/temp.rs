@@ -0,0 +1,143 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use tempfile::{NamedTempFile, TempDir};
+
+use crate::cancel;
+use crate::lock;
+
+/// Name prefix of the temporary files and directories gtkrypt stages its
+/// outputs in, followed by `RANDOM_LEN` random letters and digits.
+pub const TEMP_PREFIX: &str = ".gtkrypt-tmp-";
+
+const RANDOM_LEN: usize = 6;
+
+fn temp_builder() -> tempfile::Builder<'static, 'static> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(TEMP_PREFIX).rand_bytes(RANDOM_LEN);
+    builder
+}
+
+/// Create a temporary file in `dir` under the gtkrypt naming convention,
+/// removed should the run be cancelled.
+///
+/// The file stays locked for as long as it is open, which is what tells a
+/// live one from one left behind by a crash: the lock goes with the
+/// process.
+pub fn temp_file_in(dir: &Path) -> io::Result<NamedTempFile> {
+    let builder = temp_builder();
+    let temp_file = cancel::track(|| builder.tempfile_in(dir), NamedTempFile::path)?;
+    lock::try_lock(temp_file.as_file())?;
+    Ok(temp_file)
+}
+
+/// Create a temporary directory in `dir`, like `temp_file_in`. The
+/// directory is locked through the returned handle, which must be kept
+/// until the directory is gone or renamed.
+pub fn temp_dir_in(dir: &Path) -> io::Result<(TempDir, File)> {
+    let builder = temp_builder();
+    let temp_dir = cancel::track(|| builder.tempdir_in(dir), TempDir::path)?;
+    let handle = File::open(temp_dir.path())?;
+    lock::try_lock(&handle)?;
+    Ok((temp_dir, handle))
+}
+
+/// Whether `name` is one `temp_file_in` or `temp_dir_in` could have made.
+fn is_temp_name(name: &str) -> bool {
+    name.strip_prefix(TEMP_PREFIX).is_some_and(|rest| {
+        rest.len() == RANDOM_LEN && rest.chars().all(|c| c.is_ascii_alphanumeric())
+    })
+}
+
+/// Remove the temporary files and directories in `dir` that gtkrypt left
+/// behind, and return their paths.
+///
+/// Only entries under the gtkrypt naming convention that have not been
+/// modified for `min_age` and that no process holds locked are removed.
+/// The age covers the moment between creating an entry and locking it, and
+/// is all there is to go on where locks are not available.
+pub fn sweep(dir: &Path, min_age: Duration) -> io::Result<Vec<PathBuf>> {
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let now = SystemTime::now();
+    let mut removed = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_name().to_str().is_some_and(is_temp_name) {
+            continue;
+        }
+        let path = entry.path();
+        let metadata = fs::symlink_metadata(&path)?;
+        if metadata.file_type().is_symlink() {
+            continue;
+        }
+        let modified = metadata.modified()?;
+        if now.duration_since(modified).unwrap_or_default() < min_age {
+            continue;
+        }
+        // Held until the entry is gone, so no run can take it back meanwhile
+        let handle = File::open(&path)?;
+        if !lock::try_lock(&handle)? {
+            continue;
+        }
+        if metadata.is_dir() {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+        removed.push(path);
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_temp_names() {
+        assert!(is_temp_name(".gtkrypt-tmp-a1B2c3"));
+        assert!(!is_temp_name(".gtkrypt-tmp-a1B2c"));
+        assert!(!is_temp_name(".gtkrypt-tmp-a1B2c3.gtkrypt-lock"));
+        assert!(!is_temp_name(".tmpa1B2c3"));
+        assert!(!is_temp_name("report.pdf"));
+    }
+
+    #[test]
+    fn test_sweep_removes_only_abandoned_temps() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // Left behind by a crash: nothing holds them any more
+        let abandoned = temp_file_in(dir.path()).unwrap().into_temp_path().keep().unwrap();
+        let (abandoned_dir, handle) = temp_dir_in(dir.path()).unwrap();
+        fs::write(abandoned_dir.path().join("entry.txt"), b"partial").unwrap();
+        let abandoned_dir = abandoned_dir.keep();
+        drop(handle);
+
+        let live = temp_file_in(dir.path()).unwrap();
+        fs::write(dir.path().join(".tmpAbC123"), b"someone else's").unwrap();
+        fs::write(dir.path().join("output.txt"), b"kept").unwrap();
+
+        let mut removed = sweep(dir.path(), Duration::ZERO).unwrap();
+        removed.sort();
+        let mut expected = vec![abandoned, abandoned_dir];
+        #[cfg(not(unix))]
+        expected.push(live.path().to_path_buf());
+        expected.sort();
+        assert_eq!(removed, expected);
+        #[cfg(unix)]
+        assert!(live.path().exists());
+        assert!(dir.path().join(".tmpAbC123").exists());
+        assert!(dir.path().join("output.txt").exists());
+    }
+
+    #[test]
+    fn test_sweep_spares_recent_temps() {
+        let dir = tempfile::tempdir().unwrap();
+        let abandoned = temp_file_in(dir.path()).unwrap().into_temp_path().keep().unwrap();
+
+        assert!(sweep(dir.path(), Duration::from_secs(3600)).unwrap().is_empty());
+        assert!(abandoned.exists());
+    }
+}
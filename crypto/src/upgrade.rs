@@ -0,0 +1,168 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::audit;
+use crate::decrypt::{self, DecryptError};
+use crate::encrypt::{self, ContainerLayout, TOOL_VERSION};
+use crate::header::VERSION;
+use crate::kdf::KdfParams;
+use crate::reencrypt::map_encrypt_error;
+
+/// Options for migrating a container to the current format.
+#[derive(Default)]
+pub struct UpgradeOptions {
+    pub input_path: String,
+    /// Where to write the new container; `None` replaces the input.
+    pub output_path: Option<String>,
+    pub passphrase: Vec<u8>,
+    pub time_cost: u32,
+    pub memory_cost_kib: u32,
+    pub parallelism: u32,
+    pub encrypt_metadata: bool,
+}
+
+/// Re-encrypt a v1/v2 container as a current-version container.
+///
+/// The old payload is decrypted one chunk at a time and fed straight into
+/// the encryptor, so plaintext never touches the disk and memory use stays
+/// bounded. The new container gets a fresh salt and nonce and the given
+/// KDF parameters. It is written to a temporary file and only replaces the
+/// original once every old chunk has authenticated.
+pub fn upgrade(opts: &UpgradeOptions) -> Result<(), DecryptError> {
+    let result = upgrade_file(opts);
+    audit::record("upgrade", &opts.input_path, &result);
+    result
+}
+
+fn upgrade_file(opts: &UpgradeOptions) -> Result<(), DecryptError> {
+    let (_reader, header_obj, _header_size, _header_bytes) =
+        decrypt::open_container(&opts.input_path)?;
+    if header_obj.version >= VERSION {
+        return Err(DecryptError::Internal(format!(
+            "Container is already version {}",
+            header_obj.version
+        )));
+    }
+
+    let (_header_obj, mut file_metadata, mut payload) =
+        decrypt::open_payload(&opts.input_path, &opts.passphrase)?;
+    file_metadata.created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_secs());
+    file_metadata.tool_version = Some(TOOL_VERSION.to_string());
+
+    let kdf_params = KdfParams {
+        time_cost: opts.time_cost,
+        memory_cost_kib: opts.memory_cost_kib,
+        parallelism: opts.parallelism,
+    };
+    let container_key =
+        encrypt::derive_container_key(&opts.passphrase, kdf_params).map_err(map_encrypt_error)?;
+
+    // Writing a single file over one volume of a set would orphan the rest
+    if payload.is_split() && opts.output_path.is_none() {
+        return Err(DecryptError::Internal(
+            "An output path is required for a split container".to_string(),
+        ));
+    }
+    let output_path = opts.output_path.as_deref().unwrap_or(&opts.input_path);
+    let output = encrypt::create_output(output_path, None, None).map_err(map_encrypt_error)?;
+    encrypt::write_container(
+        &mut payload,
+        output,
+        "upgrade",
+        &container_key,
+        &file_metadata,
+        ContainerLayout {
+            encrypt_metadata: opts.encrypt_metadata,
+            ..Default::default()
+        },
+        Default::default(),
+    )
+    .map_err(|e| payload.take_error().unwrap_or_else(|| map_encrypt_error(e)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypt::tests::write_legacy_v2_container;
+    use crate::decrypt::DecryptOptions;
+    use crate::header::{self, CHUNK_SIZE, TAG_LEN};
+    use std::fs;
+
+    fn upgrade_options(input: &str, output: Option<&str>) -> UpgradeOptions {
+        UpgradeOptions {
+            input_path: input.to_string(),
+            output_path: output.map(str::to_string),
+            passphrase: b"legacy_pass".to_vec(),
+            time_cost: 1,
+            memory_cost_kib: 1024,
+            parallelism: 1,
+            encrypt_metadata: false,
+        }
+    }
+
+    fn decrypt_to_vec(path: &str, dir: &std::path::Path) -> Vec<u8> {
+        let output_path = dir.join("decrypted.bin");
+        decrypt::decrypt(&DecryptOptions {
+            input_path: path.to_string(),
+            output_path: output_path.to_str().unwrap().to_string(),
+            passphrase: b"legacy_pass".to_vec(),
+            ..Default::default()
+        })
+        .unwrap();
+        fs::read(output_path).unwrap()
+    }
+
+    #[test]
+    fn test_upgrade_v2_in_place() {
+        let plaintext: Vec<u8> = (0..2 * CHUNK_SIZE as u32 + 9).map(|i| (i % 13) as u8).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("legacy.gtkrypt");
+        write_legacy_v2_container(&path, &plaintext, b"legacy_pass");
+        let path = path.to_str().unwrap();
+
+        upgrade(&upgrade_options(path, None)).unwrap();
+
+        let data = fs::read(path).unwrap();
+        let (parsed, _) = header::decode_header(&data).unwrap();
+        assert_eq!(parsed.version, VERSION);
+        assert_ne!(parsed.salt, [3u8; header::SALT_LEN]);
+        assert_eq!(parsed.mode, Some(0o600));
+        assert_eq!(decrypt_to_vec(path, dir.path()), plaintext);
+
+        // Already current: nothing to do
+        assert!(matches!(
+            upgrade(&upgrade_options(path, None)),
+            Err(DecryptError::Internal(_))
+        ));
+    }
+
+    #[test]
+    fn test_upgrade_keeps_original_on_failure() {
+        let plaintext = vec![0x11u8; CHUNK_SIZE + 500];
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("legacy.gtkrypt");
+        write_legacy_v2_container(&path, &plaintext, b"legacy_pass");
+
+        // Corrupt the second chunk: the first one decrypts and is already
+        // re-encrypted when the failure is found
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last - TAG_LEN] ^= 0x01;
+        fs::write(&path, &data).unwrap();
+
+        let output = dir.path().join("upgraded.gtkrypt");
+        let result = upgrade(&upgrade_options(
+            path.to_str().unwrap(),
+            Some(output.to_str().unwrap()),
+        ));
+        assert!(matches!(result, Err(DecryptError::WrongPassphrase(_))));
+        assert!(!output.exists());
+
+        let result = upgrade(&upgrade_options(path.to_str().unwrap(), None));
+        assert!(result.is_err());
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+}
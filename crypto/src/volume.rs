@@ -0,0 +1,507 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use tempfile::NamedTempFile;
+
+use crate::decrypt;
+use crate::direct::{self, AlignedWriter, BlockReader, CacheDropper};
+use crate::metadata;
+use crate::persist;
+use crate::progress;
+use crate::space;
+use crate::temp;
+
+/// Path of volume `index` (zero-based) of a split container: `base.001`,
+/// `base.002`, ...
+pub fn volume_path(base: &str, index: usize) -> String {
+    format!("{}.{:03}", base, index + 1)
+}
+
+/// Parse a size such as `4096`, `700M` or `2G`. Suffixes K, M, G and T are
+/// binary multiples and case-insensitive.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1u64 << 10),
+        Some('M') => (&s[..s.len() - 1], 1u64 << 20),
+        Some('G') => (&s[..s.len() - 1], 1u64 << 30),
+        Some('T') => (&s[..s.len() - 1], 1u64 << 40),
+        _ => (s, 1),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("Invalid size '{}'", s))?;
+    let size = value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Size '{}' is too large", s))?;
+    if size == 0 {
+        return Err("Size must be greater than zero".to_string());
+    }
+    Ok(size)
+}
+
+/// Output of a new container: a single file, or a set of volumes of at
+/// most `split_size` bytes each.
+///
+/// Everything is written to temporary files in the output directory, or a
+/// chosen staging directory, which only take their final names in `finish`.
+pub struct VolumeWriter {
+    path: String,
+    /// Where the temporary files are created.
+    dir: PathBuf,
+    split_size: Option<u64>,
+    volumes: Vec<NamedTempFile>,
+    /// Bytes written to the current volume.
+    written: u64,
+    /// Flush the volumes to disk before they take their names, and the
+    /// directory entries after.
+    durable: bool,
+    /// Set for direct I/O: writes to the current volume go out in aligned
+    /// blocks.
+    blocks: Option<AlignedWriter>,
+    /// Set to drop the written pages of the current volume from the cache.
+    cache: Option<CacheDropper>,
+    /// Bytes reserved with `reserve` not yet taken by earlier volumes.
+    reserved: u64,
+    /// The file the output replaces, whose mode and owner it takes before
+    /// it is moved into place.
+    replaces: Option<fs::Metadata>,
+}
+
+impl VolumeWriter {
+    /// Start the output at `path`, staging it in `temp_dir` when given and
+    /// beside `path` otherwise.
+    pub fn create(
+        path: &str,
+        split_size: Option<u64>,
+        temp_dir: Option<&Path>,
+    ) -> io::Result<Self> {
+        let dir = temp_dir
+            .unwrap_or_else(|| Path::new(path).parent().unwrap_or(Path::new(".")))
+            .to_path_buf();
+        let mut writer = VolumeWriter {
+            path: path.to_string(),
+            dir,
+            split_size,
+            volumes: Vec::new(),
+            written: 0,
+            durable: false,
+            blocks: None,
+            cache: None,
+            reserved: 0,
+            replaces: None,
+        };
+        writer.new_volume()?;
+        Ok(writer)
+    }
+
+    /// Write the volumes with direct I/O, bypassing the page cache.
+    pub fn set_direct_io(&mut self) -> io::Result<()> {
+        direct::set_direct(self.volumes.last().unwrap().as_file(), true)?;
+        self.blocks = Some(AlignedWriter::new(true));
+        Ok(())
+    }
+
+    /// Check that the output directory has room for `len` bytes, then
+    /// reserve them on disk, volume by volume as the volumes are created.
+    /// Fails with a disk full error when there is not enough space.
+    pub fn reserve(&mut self, len: u64) -> io::Result<()> {
+        space::check(&self.dir, len)?;
+        self.reserved = len;
+        self.reserve_volume()
+    }
+
+    fn reserve_volume(&mut self) -> io::Result<()> {
+        let len = self.split_size.map_or(self.reserved, |size| size.min(self.reserved));
+        space::preallocate(self.volumes.last().unwrap().as_file(), len)
+    }
+
+    /// Drop the written pages from the page cache as the volumes grow.
+    pub fn set_drop_cache(&mut self) {
+        self.cache = Some(CacheDropper::default());
+    }
+
+    /// Write out the part block held for direct I/O, if any.
+    fn drain(&mut self) -> io::Result<()> {
+        match (&mut self.blocks, self.volumes.last()) {
+            (Some(blocks), Some(volume)) => blocks.drain(volume.as_file()),
+            _ => Ok(()),
+        }
+    }
+
+    fn new_volume(&mut self) -> io::Result<()> {
+        self.drain()?;
+        let temp_file = temp::temp_file_in(&self.dir)?;
+        if self.blocks.is_some() {
+            let direct = direct::set_direct(temp_file.as_file(), true).is_ok();
+            self.blocks = Some(AlignedWriter::new(direct));
+        }
+        if self.cache.is_some() {
+            self.cache = Some(CacheDropper::default());
+        }
+
+        // Set restrictive permissions (0600) before writing content
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(temp_file.path(), fs::Permissions::from_mode(0o600))?;
+        }
+
+        self.volumes.push(temp_file);
+        self.reserved = self.reserved.saturating_sub(self.written);
+        self.written = 0;
+        if self.reserved > 0 {
+            self.reserve_volume()?;
+        }
+        Ok(())
+    }
+
+    /// Overwrite the first `bytes.len()` bytes already written, which may
+    /// span several volumes. Used to fill in header fields that are only
+    /// known once the payload has been written.
+    pub fn overwrite_start(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.drain()?;
+        let mut rest = bytes;
+        for temp_file in &mut self.volumes {
+            let file = temp_file.as_file_mut();
+            let len = std::cmp::min(file.metadata()?.len(), rest.len() as u64) as usize;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&rest[..len])?;
+            file.seek(SeekFrom::End(0))?;
+            rest = &rest[len..];
+            if rest.is_empty() {
+                return Ok(());
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "overwrite past the end of the output",
+        ))
+    }
+
+    /// Make `finish` wait until the output is on disk, for callers about
+    /// to act on its existence.
+    pub fn set_durable(&mut self) {
+        self.durable = true;
+    }
+
+    /// Give the output the mode and owner of `original`, the file it is
+    /// about to replace, before it takes its name in `finish`.
+    pub fn set_replaces(&mut self, original: fs::Metadata) {
+        self.replaces = Some(original);
+    }
+
+    /// Move the finished output into place. A split container also loses
+    /// any higher-numbered volumes left over from an earlier, longer set.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.drain()?;
+        if let Some(ref original) = self.replaces {
+            for temp_file in &self.volumes {
+                if !metadata::carry_over(original, temp_file.path())? {
+                    decrypt::warn_owner_not_restored();
+                }
+            }
+        }
+        let _heartbeat = self.durable.then(|| progress::heartbeat("sync"));
+        if self.durable {
+            for temp_file in &self.volumes {
+                temp_file.as_file().sync_all()?;
+            }
+        }
+        let durable = self.durable;
+        let dir = Path::new(&self.path).parent().unwrap_or(Path::new(".")).to_path_buf();
+        self.persist()?;
+        if durable {
+            sync_dir(&dir)?;
+        }
+        Ok(())
+    }
+
+    fn persist(self) -> io::Result<()> {
+        if self.split_size.is_none() {
+            for temp_file in self.volumes {
+                persist::persist(temp_file, Path::new(&self.path), true)?;
+            }
+            return Ok(());
+        }
+
+        let count = self.volumes.len();
+        for (index, temp_file) in self.volumes.into_iter().enumerate() {
+            persist::persist(temp_file, Path::new(&volume_path(&self.path, index)), true)?;
+        }
+        let mut index = count;
+        while Path::new(&volume_path(&self.path, index)).exists() {
+            fs::remove_file(volume_path(&self.path, index))?;
+            index += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Flush the entries of a directory, such as a rename into it, to disk.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut len = buf.len();
+        if let Some(split_size) = self.split_size {
+            if self.written == split_size {
+                self.new_volume()?;
+            }
+            len = std::cmp::min(len as u64, split_size - self.written) as usize;
+        }
+        let volume = self.volumes.last_mut().unwrap();
+        let n = match self.blocks {
+            Some(ref mut blocks) => {
+                blocks.write(volume.as_file(), &buf[..len])?;
+                len
+            }
+            None => volume.write(&buf[..len])?,
+        };
+        self.written += n as u64;
+        if let Some(ref mut cache) = self.cache {
+            cache.written_to(volume.as_file(), self.written);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain()?;
+        self.volumes.last_mut().unwrap().flush()
+    }
+}
+
+/// A container read from a single file or from a volume set, presented as
+/// one seekable stream.
+pub struct VolumeReader {
+    files: Vec<fs::File>,
+    /// Offset of each volume within the combined stream.
+    starts: Vec<u64>,
+    len: u64,
+    pos: u64,
+    split: bool,
+    /// Set for direct I/O: reads go through aligned blocks.
+    blocks: Option<BlockReader>,
+    /// One per volume when the pages read are dropped from the cache.
+    cache: Vec<CacheDropper>,
+}
+
+impl VolumeReader {
+    /// Open `path` as a container.
+    ///
+    /// A path ending in `.001`, or a path that does not exist but has a
+    /// `.001` volume next to it, opens the whole volume set.
+    pub fn open(path: &str) -> io::Result<Self> {
+        let exists = Path::new(path).exists();
+        let base = match path.strip_suffix(".001") {
+            Some(base) if exists => Some(base),
+            _ if !exists && Path::new(&volume_path(path, 0)).exists() => Some(path),
+            _ => None,
+        };
+
+        let paths = match base {
+            Some(base) => {
+                let mut paths = Vec::new();
+                while Path::new(&volume_path(base, paths.len())).exists() {
+                    paths.push(volume_path(base, paths.len()));
+                }
+                paths
+            }
+            None => vec![path.to_string()],
+        };
+
+        let mut files = Vec::with_capacity(paths.len());
+        let mut starts = Vec::with_capacity(paths.len());
+        let mut len = 0u64;
+        for volume in &paths {
+            let file = fs::File::open(volume)?;
+            starts.push(len);
+            len += file.metadata()?.len();
+            files.push(file);
+        }
+
+        Ok(VolumeReader {
+            files,
+            starts,
+            len,
+            pos: 0,
+            split: base.is_some(),
+            blocks: None,
+            cache: Vec::new(),
+        })
+    }
+
+    /// Drop the pages read from the page cache as the reader moves on.
+    pub fn set_drop_cache(&mut self) {
+        self.cache = self.files.iter().map(|_| CacheDropper::default()).collect();
+    }
+
+    /// Read the volumes with direct I/O, bypassing the page cache.
+    pub fn set_direct_io(&mut self) -> io::Result<()> {
+        let result = self.files.iter().try_for_each(|file| direct::set_direct(file, true));
+        if result.is_err() {
+            // Plain reads at any offset need it off everywhere
+            for file in &self.files {
+                let _ = direct::set_direct(file, false);
+            }
+            return result;
+        }
+        self.blocks = Some(BlockReader::default());
+        Ok(())
+    }
+
+    /// Total size of the container in bytes.
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    /// True when the container was opened from a volume set.
+    pub fn is_split(&self) -> bool {
+        self.split
+    }
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self.starts.partition_point(|&start| start <= self.pos) - 1;
+        let end = self
+            .starts
+            .get(index + 1)
+            .copied()
+            .unwrap_or(self.len);
+        let len = std::cmp::min(buf.len() as u64, end - self.pos) as usize;
+
+        let file = &mut self.files[index];
+        let offset = self.pos - self.starts[index];
+        let n = match self.blocks {
+            Some(ref mut blocks) => blocks.read_at(file, index, offset, &mut buf[..len])?,
+            None => direct::read_at(file, &mut buf[..len], offset)?,
+        };
+        if let Some(cache) = self.cache.get_mut(index) {
+            cache.read_to(file, offset + n as u64);
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for VolumeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        match new_pos {
+            Some(new_pos) => {
+                self.pos = new_pos;
+                Ok(new_pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("700M"), Ok(700 << 20));
+        assert_eq!(parse_size("2g"), Ok(2 << 30));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("12X").is_err());
+        assert!(parse_size("99999999T").is_err());
+    }
+
+    #[test]
+    fn test_split_write_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("data.gtkrypt");
+        let base = base.to_str().unwrap();
+        let data: Vec<u8> = (0..2500u32).map(|i| (i % 256) as u8).collect();
+
+        // A leftover fourth volume from an earlier run must be removed
+        fs::write(volume_path(base, 3), b"stale").unwrap();
+
+        let mut writer = VolumeWriter::create(base, Some(1000), None).unwrap();
+        writer.write_all(&[0u8; 1200]).unwrap();
+        writer.overwrite_start(&data[..1200]).unwrap();
+        writer.write_all(&data[1200..]).unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(fs::metadata(volume_path(base, 0)).unwrap().len(), 1000);
+        assert_eq!(fs::metadata(volume_path(base, 2)).unwrap().len(), 500);
+        assert!(!Path::new(&volume_path(base, 3)).exists());
+        assert!(!Path::new(base).exists());
+
+        for path in [base.to_string(), volume_path(base, 0)] {
+            let mut reader = VolumeReader::open(&path).unwrap();
+            assert!(reader.is_split());
+            assert_eq!(reader.size(), 2500);
+            let mut read_back = Vec::new();
+            reader.read_to_end(&mut read_back).unwrap();
+            assert_eq!(read_back, data);
+
+            // Seeking across a volume boundary
+            reader.seek(SeekFrom::Start(995)).unwrap();
+            let mut buf = [0u8; 10];
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &data[995..1005]);
+        }
+    }
+
+    #[test]
+    fn test_single_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("single.gtkrypt");
+        let path = path.to_str().unwrap();
+
+        let mut writer = VolumeWriter::create(path, None, None).unwrap();
+        writer.write_all(b"one file").unwrap();
+        writer.finish().unwrap();
+
+        let mut reader = VolumeReader::open(path).unwrap();
+        assert!(!reader.is_split());
+        let mut read_back = Vec::new();
+        reader.read_to_end(&mut read_back).unwrap();
+        assert_eq!(read_back, b"one file");
+    }
+
+    #[test]
+    fn test_staged_in_temp_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let staging = tempfile::tempdir().unwrap();
+        let path = dir.path().join("staged.gtkrypt");
+        let path = path.to_str().unwrap();
+
+        let mut writer = VolumeWriter::create(path, None, Some(staging.path())).unwrap();
+        writer.write_all(b"staged elsewhere").unwrap();
+        assert_eq!(fs::read_dir(staging.path()).unwrap().count(), 1);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+        writer.set_durable();
+        writer.finish().unwrap();
+
+        assert_eq!(fs::read(path).unwrap(), b"staged elsewhere");
+        assert_eq!(fs::read_dir(staging.path()).unwrap().count(), 0);
+    }
+}
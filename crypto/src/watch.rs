@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Serialize;
+
+use crate::progress::{self, ErrorEvent};
+
+/// How often files waiting to settle are looked at again.
+const RECHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Emitted as a JSON line on stdout once the directory is being watched.
+#[derive(Debug, Serialize)]
+struct WatchingEvent {
+    watching: String,
+}
+
+/// Size and modification time, which must hold still before a file is
+/// taken.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct FileState {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileState {
+    /// The state of a regular file at `path`; `None` for anything else.
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::symlink_metadata(path).ok()?;
+        metadata.is_file().then(|| FileState {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+/// Watch `dir` and call `op` for each file that appears or changes in it,
+/// once its size and modification time have held still for `settle`.
+/// Hidden files and containers are left alone. The events of each file are
+/// tagged with its path and followed by a result line. Runs until the
+/// process is stopped or the directory can no longer be watched.
+pub fn watch<F>(dir: &Path, settle: Duration, mut op: F) -> io::Result<()>
+where
+    F: FnMut(&str) -> Result<(), (ErrorEvent, i32)>,
+{
+    let mut watcher = Watcher::new(dir)?;
+    progress::emit_event(&WatchingEvent {
+        watching: dir.display().to_string(),
+    });
+
+    // Files seen changing, with the state they have held since when
+    let mut pending: HashMap<PathBuf, (FileState, Instant)> = HashMap::new();
+    loop {
+        let timeout = (!pending.is_empty()).then_some(RECHECK_INTERVAL);
+        for path in watcher.wait(timeout)? {
+            if !wanted(&path) {
+                continue;
+            }
+            if let Some(state) = FileState::of(&path) {
+                pending.insert(path, (state, Instant::now()));
+            }
+        }
+
+        let mut settled = Vec::new();
+        pending.retain(|path, (state, since)| match FileState::of(path) {
+            None => false,
+            Some(now) if now != *state => {
+                *state = now;
+                *since = Instant::now();
+                true
+            }
+            Some(_) if since.elapsed() >= settle => {
+                settled.push(path.clone());
+                false
+            }
+            Some(_) => true,
+        });
+        settled.sort();
+
+        for path in settled {
+            let Some(input) = path.to_str() else {
+                progress::emit_warning(
+                    "path_skipped",
+                    &format!("Skipping non-UTF-8 path {}", path.display()),
+                );
+                continue;
+            };
+            progress::set_current_file(Some(input));
+            let result = op(input);
+            progress::set_current_file(None);
+            progress::emit_file_result(input, result.err().map(|(event, _)| event));
+        }
+    }
+}
+
+/// Whether a changed file should be encrypted: not hidden, which also
+/// skips the temporary files of editors and of this tool, and not a
+/// container already.
+fn wanted(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return true;
+    };
+    !name.starts_with('.') && !name.ends_with(".gtkrypt") && !name.contains(".gtkrypt.")
+}
+
+/// Reports the files of a directory that were created, written or moved
+/// in, through inotify.
+#[cfg(target_os = "linux")]
+struct Watcher {
+    dir: PathBuf,
+    fd: std::os::fd::OwnedFd,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    fn new(dir: &Path) -> io::Result<Self> {
+        use std::ffi::CString;
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // SAFETY: inotify_init1 has no preconditions
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a fresh descriptor that nothing else owns
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE | libc::IN_MODIFY;
+        // SAFETY: `path` is NUL-terminated and outlives the call
+        let watched = unsafe { libc::inotify_add_watch(fd.as_raw_fd(), path.as_ptr(), mask) };
+        if watched < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Watcher {
+            dir: dir.to_path_buf(),
+            fd,
+        })
+    }
+
+    /// Wait up to `timeout` (or for good) for changes, and return the paths
+    /// that changed.
+    fn wait(&mut self, timeout: Option<Duration>) -> io::Result<Vec<PathBuf>> {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut poll = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+        // SAFETY: one valid pollfd
+        let ready = unsafe { libc::poll(&mut poll, 1, timeout_ms) };
+        if ready < 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::Interrupted => Ok(Vec::new()),
+                _ => Err(err),
+            };
+        }
+        if ready == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut buf = [0u8; 16 * 1024];
+        // SAFETY: reads into a buffer of the given length
+        let n = unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let buf = &buf[..n as usize];
+        let mut changed = Vec::new();
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut offset = 0;
+        while offset + header <= buf.len() {
+            // SAFETY: a whole event header lies at `offset`; read_unaligned
+            // copes with the alignment of the byte buffer
+            let event: libc::inotify_event =
+                unsafe { std::ptr::read_unaligned(buf[offset..].as_ptr().cast()) };
+            let end = (offset + header + event.len as usize).min(buf.len());
+            let name = &buf[offset + header..end];
+            offset = end;
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                // Events were lost: look at everything
+                for entry in fs::read_dir(&self.dir)? {
+                    changed.push(entry?.path());
+                }
+                continue;
+            }
+            if event.mask & libc::IN_IGNORED != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "The watched directory went away",
+                ));
+            }
+            let name = name.split(|&b| b == 0).next().unwrap_or_default();
+            if !name.is_empty() {
+                changed.push(self.dir.join(std::ffi::OsStr::from_bytes(name)));
+            }
+        }
+        Ok(changed)
+    }
+}
+
+/// Reports the files of a directory whose size or modification time
+/// changed, by looking at it every second.
+#[cfg(not(target_os = "linux"))]
+struct Watcher {
+    dir: PathBuf,
+    seen: HashMap<PathBuf, FileState>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    fn new(dir: &Path) -> io::Result<Self> {
+        let mut watcher = Watcher {
+            dir: dir.to_path_buf(),
+            seen: HashMap::new(),
+        };
+        // What is already there is not new
+        watcher.scan()?;
+        Ok(watcher)
+    }
+
+    fn wait(&mut self, timeout: Option<Duration>) -> io::Result<Vec<PathBuf>> {
+        std::thread::sleep(timeout.unwrap_or(Duration::from_secs(1)));
+        self.scan()
+    }
+
+    fn scan(&mut self) -> io::Result<Vec<PathBuf>> {
+        let mut changed = Vec::new();
+        let mut seen = HashMap::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if let Some(state) = FileState::of(&path) {
+                if self.seen.get(&path) != Some(&state) {
+                    changed.push(path.clone());
+                }
+                seen.insert(path, state);
+            }
+        }
+        self.seen = seen;
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wanted_skips_hidden_files_and_containers() {
+        assert!(wanted(Path::new("/drop/report.pdf")));
+        assert!(!wanted(Path::new("/drop/.report.pdf.swp")));
+        assert!(!wanted(Path::new("/drop/.gtkrypt-tmpXYZ")));
+        assert!(!wanted(Path::new("/drop/report.pdf.gtkrypt")));
+        assert!(!wanted(Path::new("/drop/report.pdf.gtkrypt.001")));
+    }
+}
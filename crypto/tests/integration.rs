@@ -16,6 +16,14 @@ fn binary_path() -> std::path::PathBuf {
     path
 }
 
+/// A command running the binary, with the KDF calibration cache turned
+/// off so that the tests never write to the home directory.
+fn crypto_command() -> Command {
+    let mut command = Command::new(binary_path());
+    command.env("GTKRYPT_STATE_DIR", "");
+    command
+}
+
 /// Helper: fast KDF encrypt args (used by keyfile tests).
 fn fast_encrypt_args<'a>(
     input: &'a str,
@@ -55,7 +63,7 @@ fn decrypt_args<'a>(input: &'a str, output: &'a str, keyfile: Option<&'a str>) -
 /// Run the gtkrypt-crypto binary with the given args and passphrase on stdin.
 fn run_crypto(args: &[&str], passphrase: &str) -> std::process::Output {
     let bin = binary_path();
-    let mut child = Command::new(&bin)
+    let mut child = crypto_command()
         .args(args)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
@@ -72,6 +80,30 @@ fn run_crypto(args: &[&str], passphrase: &str) -> std::process::Output {
     child.wait_with_output().unwrap()
 }
 
+/// Run the gtkrypt-crypto binary with `input` as the whole of stdin.
+fn run_crypto_with_stdin(args: &[&str], input: &[u8]) -> std::process::Output {
+    let bin = binary_path();
+    let mut child = crypto_command()
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| panic!("Failed to spawn {:?}: {}", bin, e));
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// Run the gtkrypt-crypto binary for a command that does not read stdin.
+fn run_crypto_no_stdin(args: &[&str]) -> std::process::Output {
+    let bin = binary_path();
+    crypto_command()
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap_or_else(|e| panic!("Failed to run {:?}: {}", bin, e))
+}
+
 #[test]
 fn test_roundtrip_encrypt_decrypt_small_file() {
     let dir = tempfile::tempdir().unwrap();
@@ -140,6 +172,126 @@ fn test_roundtrip_encrypt_decrypt_small_file() {
     );
 }
 
+#[test]
+fn test_positional_files_and_default_outputs() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("notes.txt");
+    let encrypted_path = dir.path().join("notes.txt.gtkrypt");
+    fs::write(&input_path, b"positional").unwrap();
+    let input = input_path.to_str().unwrap();
+    let encrypted = encrypted_path.to_str().unwrap();
+    let fast = ["--time-cost", "1", "--memory-cost", "1024", "--parallelism", "1"];
+
+    let output = run_crypto(&[&["encrypt", input][..], &fast].concat(), "pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(encrypted_path.exists());
+
+    fs::remove_file(&input_path).unwrap();
+    let output = run_crypto(&["decrypt", encrypted], "pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&input_path).unwrap(), b"positional");
+
+    // -o names the output, as --output does
+    let elsewhere = dir.path().join("elsewhere.txt");
+    let output = run_crypto(&["decrypt", encrypted, "-o", elsewhere.to_str().unwrap()], "pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&elsewhere).unwrap(), b"positional");
+
+    // No name to take off, and no mixing with --input
+    let renamed = dir.path().join("notes.bin");
+    fs::copy(&encrypted_path, &renamed).unwrap();
+    let output = run_crypto(&["decrypt", renamed.to_str().unwrap()], "pass");
+    assert_eq!(output.status.code(), Some(10));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("does not end in .gtkrypt"));
+    let output = run_crypto(&["decrypt", encrypted, "--input", encrypted], "pass");
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_verbose_logging() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("logged.txt");
+    let encrypted_path = dir.path().join("logged.txt.gtkrypt");
+    let log_path = dir.path().join("run.log");
+    fs::write(&input_path, vec![7u8; 200_000]).unwrap();
+    let input = input_path.to_str().unwrap();
+    let encrypted = encrypted_path.to_str().unwrap();
+
+    let args = [&fast_encrypt_args(input, encrypted, None)[..], &["-vv"]].concat();
+    let output = run_crypto(&args, "pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let logged = String::from_utf8_lossy(&output.stderr);
+    assert!(logged.contains(" debug] Argon2id with m=1024 KiB, t=1, p=1 took "), "{}", logged);
+    assert!(logged.contains(" debug] encrypt: 200000 bytes of input"), "{}", logged);
+    assert!(logged.contains(" trace] processed block 3 in "), "{}", logged);
+    // The events stay as they were
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        serde_json::from_str::<serde_json::Value>(line).unwrap();
+    }
+
+    // A log file takes the lines, at -v without trace
+    let decrypted = dir.path().join("out");
+    let args = decrypt_args(encrypted, decrypted.to_str().unwrap(), None);
+    let args = [&args[..], &["--log-file", log_path.to_str().unwrap()]].concat();
+    let output = run_crypto(&args, "pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.stderr.is_empty());
+    let logged = fs::read_to_string(&log_path).unwrap();
+    assert!(logged.contains(" debug] decrypt: 4 chunks, 4 to decrypt"), "{}", logged);
+    assert!(!logged.contains(" trace]"), "{}", logged);
+
+    // Quiet without either
+    let again = dir.path().join("again");
+    let output = run_crypto(&decrypt_args(encrypted, again.to_str().unwrap(), None), "pass");
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn test_audit_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("audited.txt");
+    let encrypted_path = dir.path().join("audited.txt.gtkrypt");
+    let audit_path = dir.path().join("audit.log");
+    fs::write(&input_path, b"for the record").unwrap();
+    let input = input_path.to_str().unwrap();
+    let encrypted = encrypted_path.to_str().unwrap();
+    let audit_log = audit_path.to_str().unwrap();
+
+    let args = fast_encrypt_args(input, encrypted, None);
+    let args = [&args[..], &["--audit-log", audit_log]].concat();
+    let output = run_crypto(&args, "pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let out = dir.path().join("out");
+    let args = decrypt_args(encrypted, out.to_str().unwrap(), None);
+    let args = [&args[..], &["--audit-log", audit_log]].concat();
+    assert_eq!(run_crypto(&args, "wrong").status.code(), Some(1));
+
+    let log = fs::read_to_string(&audit_path).unwrap();
+    let entries: Vec<serde_json::Value> =
+        log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["operation"], "encrypt");
+    assert_eq!(entries[0]["input"], input);
+    assert_eq!(entries[0]["result"], "ok");
+    assert_eq!(entries[1]["operation"], "decrypt");
+    assert_eq!(entries[1]["result"], "failed");
+    assert!(entries[1]["error"].as_str().unwrap().starts_with("Wrong passphrase"));
+
+    let output = run_crypto_no_stdin(&["verify-audit-log", "--input", audit_log]);
+    assert_eq!(output.status.code(), Some(0));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["entries"], 2);
+
+    // Rewriting an earlier entry is noticed
+    let rewritten = log.replacen("\"result\":\"ok\"", "\"result\":\"failed\"", 1);
+    fs::write(&audit_path, rewritten).unwrap();
+    let output = run_crypto_no_stdin(&["verify-audit-log", "--input", audit_log]);
+    assert_eq!(output.status.code(), Some(2));
+    let error: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(error["code"], "corrupt_file");
+}
+
 #[test]
 fn test_roundtrip_binary_data() {
     let dir = tempfile::tempdir().unwrap();
@@ -285,6 +437,52 @@ fn test_corrupt_file_fails_with_exit_code_2() {
     );
 }
 
+#[test]
+fn test_corrupt_chunk_reported_in_error_json() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("backup.img");
+    let encrypted_path = dir.path().join("backup.img.gtkrypt");
+    let decrypted_path = dir.path().join("backup_decrypted.img");
+
+    fs::write(&input_path, vec![0x6Bu8; 200_000]).unwrap();
+    let enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&enc_args, "scan_pass");
+    assert_eq!(output.status.code(), Some(0));
+
+    // Flip a bit near the end of the last chunk
+    let mut data = fs::read(&encrypted_path).unwrap();
+    let index_len = 4 * 12 + 32;
+    let target = data.len() - index_len - 100;
+    data[target] ^= 0x01;
+    fs::write(&encrypted_path, &data).unwrap();
+
+    let mut dec_args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    );
+    dec_args.push("--scan-all");
+    let output = run_crypto(&dec_args, "scan_pass");
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("\"error\":\"corrupt_file\""), "{}", stderr);
+    assert!(stderr.contains("\"bad_chunks\":[{\"chunk_index\":3,"), "{}", stderr);
+    assert!(!decrypted_path.exists());
+
+    // Salvage mode keeps the three good chunks and still reports the bad one
+    dec_args.pop();
+    dec_args.push("--keep-partial");
+    let output = run_crypto(&dec_args, "scan_pass");
+    assert_eq!(output.status.code(), Some(2));
+    let salvaged = fs::read(&decrypted_path).unwrap();
+    assert_eq!(salvaged.len(), 200_000);
+    assert!(salvaged[..3 * 65536].iter().all(|&b| b == 0x6B));
+}
+
 #[test]
 fn test_roundtrip_empty_file() {
     let dir = tempfile::tempdir().unwrap();
@@ -377,6 +575,522 @@ fn test_encrypt_with_store_filename_flag() {
     assert_eq!(decrypted, b"Fake PDF content");
 }
 
+#[test]
+fn test_decrypt_output_dir_uses_stored_filename() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("invoice.pdf");
+    let encrypted_path = dir.path().join("a1b2c3.gtkrypt");
+    let out_dir = dir.path().join("restored");
+    fs::create_dir(&out_dir).unwrap();
+    fs::write(&input_path, b"Invoice content").unwrap();
+
+    let mut args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    args.push("--store-filename");
+    assert_eq!(run_crypto(&args, "dir_pass").status.code(), Some(0));
+
+    let output = run_crypto(
+        &[
+            "decrypt",
+            "--input",
+            encrypted_path.to_str().unwrap(),
+            "--output-dir",
+            out_dir.to_str().unwrap(),
+        ],
+        "dir_pass",
+    );
+    assert_eq!(output.status.code(), Some(0));
+
+    let expected = out_dir.join("invoice.pdf");
+    assert_eq!(fs::read(&expected).unwrap(), b"Invoice content");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let event = stdout
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .find(|event| event.get("output_path").is_some())
+        .expect("no output_path event");
+    assert_eq!(event["output_path"], expected.to_str().unwrap());
+}
+
+#[test]
+fn test_encrypt_output_template_with_no_clobber() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("notes.txt");
+    fs::write(&input_path, b"template me").unwrap();
+
+    let encrypt = || {
+        let output = run_crypto(
+            &[
+                "encrypt",
+                "--input",
+                input_path.to_str().unwrap(),
+                "--output-template",
+                "{stem}.gtkrypt",
+                "--no-clobber",
+                "--time-cost",
+                "1",
+                "--memory-cost",
+                "1024",
+                "--parallelism",
+                "1",
+            ],
+            "template_pass",
+        );
+        assert_eq!(output.status.code(), Some(0));
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        stdout
+            .lines()
+            .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+            .find_map(|event| event["output_path"].as_str().map(str::to_string))
+            .expect("no output_path event")
+    };
+
+    let first = encrypt();
+    assert_eq!(first, dir.path().join("notes.gtkrypt").to_str().unwrap());
+    let second = encrypt();
+    assert_eq!(second, dir.path().join("notes (1).gtkrypt").to_str().unwrap());
+    assert!(fs::metadata(&first).unwrap().len() > 0);
+    assert!(fs::metadata(&second).unwrap().len() > 0);
+}
+
+#[test]
+fn test_batch_encrypt_and_decrypt() {
+    let dir = tempfile::tempdir().unwrap();
+    let out_dir = dir.path().join("out");
+    fs::create_dir(&out_dir).unwrap();
+    let inputs: Vec<String> = ["alpha.txt", "beta.txt", "missing.txt"]
+        .iter()
+        .map(|name| dir.path().join(name).to_str().unwrap().to_string())
+        .collect();
+    fs::write(&inputs[0], b"first file").unwrap();
+    fs::write(&inputs[1], b"second file").unwrap();
+
+    let mut args = vec!["encrypt", "--input"];
+    args.extend(inputs.iter().map(String::as_str));
+    args.extend([
+        "--output-template",
+        "{stem}.gtkrypt",
+        "--time-cost",
+        "1",
+        "--memory-cost",
+        "1024",
+        "--parallelism",
+        "1",
+    ]);
+    let output = run_crypto(&args, "batch_pass");
+    assert_eq!(output.status.code(), Some(10));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("1 of 3 files failed"), "{}", stderr);
+
+    let events: Vec<serde_json::Value> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    let results: Vec<(&str, &str)> = events
+        .iter()
+        .filter_map(|e| Some((e["file"].as_str()?, e["result"].as_str()?)))
+        .collect();
+    assert_eq!(
+        results,
+        [(inputs[0].as_str(), "ok"), (inputs[1].as_str(), "ok"), (inputs[2].as_str(), "failed")]
+    );
+    assert!(events
+        .iter()
+        .filter(|e| e.get("phase").is_some())
+        .all(|e| e["file"].is_string()));
+
+    // One key derivation for the batch: both containers share the salt
+    let containers: Vec<_> = ["alpha.gtkrypt", "beta.gtkrypt"]
+        .iter()
+        .map(|name| fs::read(dir.path().join(name)).unwrap())
+        .collect();
+    assert_eq!(containers[0][20..36], containers[1][20..36]);
+
+    let alpha = dir.path().join("alpha.gtkrypt");
+    let beta = dir.path().join("beta.gtkrypt");
+    let output = run_crypto(
+        &[
+            "decrypt",
+            "--input",
+            alpha.to_str().unwrap(),
+            "--input",
+            beta.to_str().unwrap(),
+            "--output-dir",
+            out_dir.to_str().unwrap(),
+        ],
+        "batch_pass",
+    );
+    assert_eq!(output.status.code(), Some(0));
+    // Without stored filenames the outputs are named after the containers
+    assert_eq!(fs::read(out_dir.join("alpha")).unwrap(), b"first file");
+    assert_eq!(fs::read(out_dir.join("beta")).unwrap(), b"second file");
+}
+
+#[test]
+fn test_batch_runs_parallel_jobs() {
+    let dir = tempfile::tempdir().unwrap();
+    let out_dir = dir.path().join("out");
+    fs::create_dir(&out_dir).unwrap();
+    let names = ["p0", "p1", "p2", "p3", "p4"];
+    for (n, name) in names.iter().enumerate() {
+        fs::write(dir.path().join(name), vec![n as u8; 200_000]).unwrap();
+    }
+    let inputs: Vec<String> =
+        names.iter().map(|name| dir.path().join(name).to_str().unwrap().to_string()).collect();
+    let containers: Vec<String> = inputs.iter().map(|input| format!("{}.gtkrypt", input)).collect();
+
+    let mut args = vec!["encrypt", "--jobs", "3", "--output-template", "{name}.gtkrypt"];
+    args.extend(["--time-cost", "1", "--memory-cost", "1024", "--parallelism", "1"]);
+    args.push("--input");
+    args.extend(inputs.iter().map(String::as_str));
+    let output = run_crypto(&args, "parallel_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let mut args = vec!["decrypt", "--jobs", "2", "--output-dir", out_dir.to_str().unwrap()];
+    args.push("--input");
+    args.extend(containers.iter().map(String::as_str));
+    let output = run_crypto(&args, "parallel_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let events: Vec<serde_json::Value> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("interleaved output line"))
+        .collect();
+    let ok = events.iter().filter(|e| e["result"] == "ok").count();
+    assert_eq!(ok, names.len());
+    assert!(events.iter().filter(|e| e.get("phase").is_some()).all(|e| e["file"].is_string()));
+    for (n, name) in names.iter().enumerate() {
+        assert_eq!(fs::read(out_dir.join(name)).unwrap(), vec![n as u8; 200_000]);
+    }
+}
+
+#[test]
+fn test_recursive_decrypt_keeps_tree() {
+    let dir = tempfile::tempdir().unwrap();
+    let tree = dir.path().join("vault");
+    let out_dir = dir.path().join("out");
+    fs::create_dir_all(tree.join("a/b")).unwrap();
+    fs::create_dir_all(tree.join("c")).unwrap();
+
+    for (plain, contents) in [("a/x.txt", "ex"), ("a/b/y.txt", "why"), ("z.txt", "zed")] {
+        let plain_path = tree.join(plain);
+        fs::write(&plain_path, contents).unwrap();
+        let encrypted = plain_path.with_extension("gtkrypt");
+        let mut args =
+            fast_encrypt_args(plain_path.to_str().unwrap(), encrypted.to_str().unwrap(), None);
+        args.push("--store-filename");
+        assert_eq!(run_crypto(&args, "tree_pass").status.code(), Some(0));
+        fs::remove_file(&plain_path).unwrap();
+    }
+    fs::write(tree.join("c/bad.gtkrypt"), b"not a container").unwrap();
+
+    let output = run_crypto(
+        &[
+            "decrypt",
+            "--recursive",
+            "--input",
+            tree.to_str().unwrap(),
+            "--output-dir",
+            out_dir.to_str().unwrap(),
+        ],
+        "tree_pass",
+    );
+    assert_eq!(output.status.code(), Some(2), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(out_dir.join("a/x.txt")).unwrap(), "ex");
+    assert_eq!(fs::read_to_string(out_dir.join("a/b/y.txt")).unwrap(), "why");
+    assert_eq!(fs::read_to_string(out_dir.join("z.txt")).unwrap(), "zed");
+
+    let summary = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .find(|event| event.get("succeeded").is_some())
+        .expect("no summary line");
+    assert_eq!(summary["succeeded"], 3);
+    assert_eq!(summary["failed"], serde_json::json!([tree.join("c/bad.gtkrypt")]));
+}
+
+#[test]
+fn test_encrypt_expands_glob_input() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("photos/2024")).unwrap();
+    for name in ["photos/a.raw", "photos/2024/b.raw", "photos/notes.txt"] {
+        fs::write(dir.path().join(name), name).unwrap();
+    }
+    let pattern = format!("{}/photos/**/*.raw", dir.path().to_str().unwrap());
+
+    let args = [
+        "encrypt",
+        "--input",
+        &pattern,
+        "--output-template",
+        "{name}.gtkrypt",
+        "--time-cost",
+        "1",
+        "--memory-cost",
+        "1024",
+        "--parallelism",
+        "1",
+    ];
+    let output = run_crypto(&args, "glob_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let first: serde_json::Value = serde_json::from_str(stdout.lines().next().unwrap()).unwrap();
+    assert_eq!(first["input_count"], 2);
+    assert!(dir.path().join("photos/a.raw.gtkrypt").exists());
+    assert!(dir.path().join("photos/2024/b.raw.gtkrypt").exists());
+    assert!(!dir.path().join("photos/notes.txt.gtkrypt").exists());
+
+    // Refused before the passphrase is read
+    let output = run_crypto_no_stdin(&[
+        "decrypt",
+        "--input",
+        "/nonexistent/*.gtkrypt",
+        "--output-dir",
+        ".",
+    ]);
+    assert_eq!(output.status.code(), Some(10));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No files match"));
+}
+
+#[test]
+fn test_watch_encrypts_new_files() {
+    use std::io::BufRead;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir().unwrap();
+    let drop_dir = dir.path().join("drop");
+    let out_dir = dir.path().join("encrypted");
+    fs::create_dir(&drop_dir).unwrap();
+    fs::create_dir(&out_dir).unwrap();
+
+    let mut child = crypto_command()
+        .args([
+            "watch",
+            "--dir",
+            drop_dir.to_str().unwrap(),
+            "--output-dir",
+            out_dir.to_str().unwrap(),
+            "--settle-ms",
+            "200",
+            "--time-cost",
+            "1",
+            "--memory-cost",
+            "1024",
+            "--parallelism",
+            "1",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    writeln!(child.stdin.as_mut().unwrap(), "watch_pass").unwrap();
+    let (tx, rx) = mpsc::channel();
+    let stdout = child.stdout.take().unwrap();
+    std::thread::spawn(move || {
+        for line in std::io::BufReader::new(stdout).lines().map_while(Result::ok) {
+            if let Ok(event) = serde_json::from_str::<serde_json::Value>(&line) {
+                let _ = tx.send(event);
+            }
+        }
+    });
+    let next = |key: &str| loop {
+        let event = rx.recv_timeout(Duration::from_secs(20)).expect("watch went quiet");
+        if event.get(key).is_some() {
+            return event;
+        }
+    };
+
+    next("watching");
+    fs::write(drop_dir.join(".hidden"), b"skip me").unwrap();
+    fs::write(drop_dir.join("scan.pdf"), b"dropped in").unwrap();
+    let result = next("result");
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert_eq!(result["result"], "ok");
+    assert_eq!(result["file"], drop_dir.join("scan.pdf").to_str().unwrap());
+    let container = out_dir.join("scan.pdf.gtkrypt");
+    let restored = dir.path().join("scan.pdf");
+    let dec_args = decrypt_args(container.to_str().unwrap(), restored.to_str().unwrap(), None);
+    assert_eq!(run_crypto(&dec_args, "watch_pass").status.code(), Some(0));
+    assert_eq!(fs::read(&restored).unwrap(), b"dropped in");
+    assert!(!out_dir.join(".hidden.gtkrypt").exists());
+}
+
+#[test]
+fn test_jobs_file_from_stdin() {
+    let dir = tempfile::tempdir().unwrap();
+    let plain = dir.path().join("report.txt");
+    let encrypted = dir.path().join("report.gtkrypt");
+    let restored = dir.path().join("report-restored.txt");
+    fs::write(&plain, b"quarterly numbers").unwrap();
+
+    let manifest = serde_json::json!([
+        {"operation": "encrypt", "input": plain, "output": encrypted, "time_cost": 1,
+         "memory_cost": 1024, "parallelism": 1},
+        {"operation": "decrypt", "input": encrypted, "output": restored},
+        {"operation": "decrypt", "input": dir.path().join("absent.gtkrypt"), "output": restored},
+    ]);
+    let output = run_crypto(
+        &["batch", "--jobs-file", "-"],
+        &format!("jobs_pass\n{}", manifest),
+    );
+    assert_eq!(output.status.code(), Some(10), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&restored).unwrap(), b"quarterly numbers");
+
+    let results: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter_map(|event| event["result"].as_str().map(str::to_string))
+        .collect();
+    assert_eq!(results, ["ok", "ok", "failed"]);
+
+    // A manifest with a bad job runs none of them
+    let untouched = dir.path().join("untouched.gtkrypt");
+    let manifest = serde_json::json!([
+        {"operation": "encrypt", "input": plain, "output": untouched},
+        {"operation": "decrypt", "input": encrypted},
+    ]);
+    let output = run_crypto(&["batch", "--jobs-file", "-"], &format!("jobs_pass\n{}", manifest));
+    assert_eq!(output.status.code(), Some(10));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Job 1"));
+    assert!(!untouched.exists());
+}
+
+#[test]
+fn test_delete_input_only_after_success() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("secret.txt");
+    let encrypted_path = dir.path().join("secret.gtkrypt");
+    fs::write(&input_path, b"burn after reading").unwrap();
+
+    // A failed encryption leaves the input alone
+    let missing_dir = dir.path().join("missing").join("secret.gtkrypt");
+    let mut args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        missing_dir.to_str().unwrap(),
+        None,
+    );
+    args.push("--delete-input");
+    assert_ne!(run_crypto(&args, "delete_pass").status.code(), Some(0));
+    assert!(input_path.exists());
+
+    let mut args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    args.push("--delete-input");
+    let output = run_crypto(&args, "delete_pass");
+    assert_eq!(output.status.code(), Some(0));
+    assert!(!input_path.exists());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let event = stdout
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .find(|event| event.get("input_deleted").is_some())
+        .expect("no input_deleted event");
+    assert_eq!(event["input_deleted"], input_path.to_str().unwrap());
+
+    let dec_args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        input_path.to_str().unwrap(),
+        None,
+    );
+    assert_eq!(run_crypto(&dec_args, "delete_pass").status.code(), Some(0));
+    assert_eq!(fs::read(&input_path).unwrap(), b"burn after reading");
+}
+
+#[test]
+fn test_shred_input_overwrites_then_deletes() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("diary.txt");
+    let encrypted_path = dir.path().join("diary.gtkrypt");
+    fs::write(&input_path, b"dear diary").unwrap();
+
+    let mut args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    args.extend(["--shred-input", "--shred-passes", "2"]);
+    let output = run_crypto(&args, "shred_pass");
+    assert_eq!(output.status.code(), Some(0));
+    assert!(!input_path.exists());
+    let events: Vec<serde_json::Value> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    assert!(events.iter().any(|e| e["warning"] == "shred_best_effort"));
+    assert!(events.iter().any(|e| e["input_deleted"].is_string() && e["shredded"] == true));
+
+    let dec_args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        input_path.to_str().unwrap(),
+        None,
+    );
+    assert_eq!(run_crypto(&dec_args, "shred_pass").status.code(), Some(0));
+    assert_eq!(fs::read(&input_path).unwrap(), b"dear diary");
+}
+
+#[test]
+fn test_existing_output_reported_unless_forced() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("plan.txt");
+    let encrypted_path = dir.path().join("plan.gtkrypt");
+    let decrypted_path = dir.path().join("plan-restored.txt");
+    fs::write(&input_path, b"new plan").unwrap();
+    fs::write(&decrypted_path, b"old plan").unwrap();
+
+    let enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    assert_eq!(run_crypto(&enc_args, "exists_pass").status.code(), Some(0));
+
+    let mut dec_args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&dec_args, "exists_pass");
+    assert_eq!(output.status.code(), Some(4));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let error: serde_json::Value = serde_json::from_str(stderr.trim()).unwrap();
+    assert_eq!(error["error"], "output_exists");
+    assert_eq!(error["code"], "output_exists");
+    assert_eq!(error["output_path"], decrypted_path.to_str().unwrap());
+    assert_eq!(error["details"]["path"], decrypted_path.to_str().unwrap());
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"old plan");
+
+    // Encrypting over an existing container is refused before the
+    // passphrase is even read
+    assert_eq!(run_crypto_no_stdin(&enc_args).status.code(), Some(4));
+
+    dec_args.push("--force");
+    assert_eq!(run_crypto(&dec_args, "exists_pass").status.code(), Some(0));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"new plan");
+
+    // Even --force does not let the output replace the input itself
+    let mut same_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        input_path.to_str().unwrap(),
+        None,
+    );
+    same_args.push("--force");
+    let output = run_crypto_no_stdin(&same_args);
+    assert_eq!(output.status.code(), Some(5));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("\"same_file\""));
+    assert_eq!(fs::read(&input_path).unwrap(), b"new plan");
+}
+
 #[test]
 fn test_progress_output_on_stdout() {
     let dir = tempfile::tempdir().unwrap();
@@ -823,3 +1537,2790 @@ fn test_keyfile_wrong_keyfile_fails() {
     );
     assert!(!decrypted_path.exists());
 }
+
+// ── Container format tests ──
+
+#[test]
+fn test_encrypt_writes_v3_container() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("v3.txt");
+    let encrypted_path = dir.path().join("v3.gtkrypt");
+    let decrypted_path = dir.path().join("v3_decrypted.txt");
+
+    fs::write(&input_path, b"Version three payload").unwrap();
+
+    let enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&enc_args, "v3_pass");
+    assert_eq!(output.status.code(), Some(0));
+
+    let data = fs::read(&encrypted_path).unwrap();
+    assert_eq!(data[8], 3, "New containers should be written as v3");
+
+    // Tampering with the (empty) extension area length must be rejected
+    let mut tampered = data.clone();
+    tampered[74] = 0x01;
+    let tampered_path = dir.path().join("v3_tampered.gtkrypt");
+    fs::write(&tampered_path, &tampered).unwrap();
+    let dec_args = decrypt_args(
+        tampered_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&dec_args, "v3_pass");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(!decrypted_path.exists());
+
+    let dec_args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&dec_args, "v3_pass");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"Version three payload");
+}
+
+#[test]
+fn test_encrypted_metadata_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("hidden-name.txt");
+    let encrypted_path = dir.path().join("hidden.gtkrypt");
+    let decrypted_path = dir.path().join("hidden_decrypted.txt");
+
+    fs::write(&input_path, b"Metadata is encrypted too").unwrap();
+
+    let mut enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    enc_args.push("--store-filename");
+    enc_args.push("--encrypt-metadata");
+    let output = run_crypto(&enc_args, "meta_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Encrypt failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let data = fs::read(&encrypted_path).unwrap();
+    let needle = b"hidden-name";
+    assert!(
+        !data.windows(needle.len()).any(|w| w == needle),
+        "Filename should not appear in plaintext"
+    );
+
+    // Wrong passphrase fails on the metadata block
+    let dec_args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&dec_args, "not_meta_pass");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!decrypted_path.exists());
+
+    let output = run_crypto(&dec_args, "meta_pass");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"Metadata is encrypted too");
+}
+
+#[test]
+fn test_padded_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("padded.bin");
+    let encrypted_path = dir.path().join("padded.gtkrypt");
+    let decrypted_path = dir.path().join("padded_decrypted.bin");
+
+    let plaintext: Vec<u8> = (0..70_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(&input_path, &plaintext).unwrap();
+
+    let mut enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    enc_args.push("--pad");
+    let output = run_crypto(&enc_args, "pad_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Encrypt failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // 70000 bytes pads to the next Padmé bucket of 71680 bytes
+    let data = fs::read(&encrypted_path).unwrap();
+    let ciphertext_len = u64::from_be_bytes(data[63..71].try_into().unwrap());
+    assert_eq!(ciphertext_len, 71_680);
+
+    let dec_args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&dec_args, "pad_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Decrypt failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(fs::read(&decrypted_path).unwrap(), plaintext);
+}
+
+#[test]
+fn test_decrypt_byte_range() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("large.bin");
+    let encrypted_path = dir.path().join("large.gtkrypt");
+    let slice_path = dir.path().join("slice.bin");
+
+    let plaintext: Vec<u8> = (0..300_000u32).map(|i| (i % 253) as u8).collect();
+    fs::write(&input_path, &plaintext).unwrap();
+
+    let enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&enc_args, "range_pass");
+    assert_eq!(output.status.code(), Some(0));
+
+    // Corrupt the first chunk: a range that skips it must still succeed
+    let mut data = fs::read(&encrypted_path).unwrap();
+    let footer_len = 5 * 12 + 32;
+    let header_size = data.len() - plaintext.len() - 5 * 16 - footer_len;
+    data[header_size + 100] ^= 0xFF;
+    fs::write(&encrypted_path, &data).unwrap();
+
+    let mut dec_args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        slice_path.to_str().unwrap(),
+        None,
+    );
+    dec_args.extend_from_slice(&["--offset", "150000", "--length", "4096"]);
+    let output = run_crypto(&dec_args, "range_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Range decrypt failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(fs::read(&slice_path).unwrap(), &plaintext[150_000..154_096]);
+}
+
+#[test]
+fn test_tampered_chunk_index_detected() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("indexed.txt");
+    let encrypted_path = dir.path().join("indexed.gtkrypt");
+    let decrypted_path = dir.path().join("indexed_decrypted.txt");
+
+    fs::write(&input_path, b"Chunk index footer").unwrap();
+
+    let enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&enc_args, "index_pass");
+    assert_eq!(output.status.code(), Some(0));
+
+    // The footer's MAC is the last 32 bytes of the file
+    let mut data = fs::read(&encrypted_path).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 0x01;
+    fs::write(&encrypted_path, &data).unwrap();
+
+    let dec_args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&dec_args, "index_pass");
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Chunk index"), "stderr: {}", stderr);
+    assert!(!decrypted_path.exists());
+}
+
+#[test]
+fn test_key_check_separates_wrong_passphrase_from_corruption() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("checked.txt");
+    let encrypted_path = dir.path().join("checked.gtkrypt");
+    let decrypted_path = dir.path().join("checked_decrypted.txt");
+
+    fs::write(&input_path, b"Key check value").unwrap();
+
+    let enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&enc_args, "check_pass");
+    assert_eq!(output.status.code(), Some(0));
+
+    let dec_args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&dec_args, "wrong_pass");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Incorrect passphrase"));
+
+    // Flip the last ciphertext byte (before the tag and the 44-byte index)
+    let mut data = fs::read(&encrypted_path).unwrap();
+    let offset = data.len() - 44 - 16 - 1;
+    data[offset] ^= 0xFF;
+    fs::write(&encrypted_path, &data).unwrap();
+
+    let output = run_crypto(&dec_args, "check_pass");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("corrupted"));
+    assert!(!decrypted_path.exists());
+}
+
+#[test]
+fn test_check_passphrase_subcommand() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("check.txt");
+    let encrypted_path = dir.path().join("check.gtkrypt");
+
+    fs::write(&input_path, b"Check the passphrase first").unwrap();
+
+    let enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&enc_args, "check_pass");
+    assert_eq!(output.status.code(), Some(0));
+
+    let check_args = ["check-passphrase", "--input", encrypted_path.to_str().unwrap()];
+    let output = run_crypto(&check_args, "check_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "check-passphrase failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let output = run_crypto(&check_args, "not_check_pass");
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let error: serde_json::Value = serde_json::from_str(stderr.trim()).unwrap();
+    assert_eq!(error["error"], "wrong_passphrase");
+}
+
+#[test]
+fn test_header_backup_and_restore() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("precious.txt");
+    let encrypted_path = dir.path().join("precious.gtkrypt");
+    let backup_path = dir.path().join("precious.header");
+    let decrypted_path = dir.path().join("precious_decrypted.txt");
+
+    fs::write(&input_path, b"Do not lose the salt").unwrap();
+
+    let enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&enc_args, "backup_pass");
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = run_crypto_no_stdin(&[
+        "header",
+        "backup",
+        "--input",
+        encrypted_path.to_str().unwrap(),
+        "--output",
+        backup_path.to_str().unwrap(),
+    ]);
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Header backup failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // Destroy the magic and salt
+    let mut data = fs::read(&encrypted_path).unwrap();
+    data[..36].fill(0xFF);
+    fs::write(&encrypted_path, &data).unwrap();
+
+    let dec_args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&dec_args, "backup_pass");
+    assert_eq!(output.status.code(), Some(2));
+
+    let output = run_crypto_no_stdin(&[
+        "header",
+        "restore",
+        "--backup",
+        backup_path.to_str().unwrap(),
+        "--target",
+        encrypted_path.to_str().unwrap(),
+    ]);
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Header restore failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let output = run_crypto(&dec_args, "backup_pass");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"Do not lose the salt");
+}
+
+#[test]
+fn test_reencrypt_with_new_passphrase() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("rotate.txt");
+    let encrypted_path = dir.path().join("rotate.gtkrypt");
+    let decrypted_path = dir.path().join("rotate_decrypted.txt");
+
+    let plaintext = vec![0x7Eu8; 150_000];
+    fs::write(&input_path, &plaintext).unwrap();
+
+    let enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&enc_args, "old_pass");
+    assert_eq!(output.status.code(), Some(0));
+
+    let reencrypt_args = [
+        "re-encrypt",
+        "--input",
+        encrypted_path.to_str().unwrap(),
+        "--new-passphrase",
+        "--time-cost",
+        "2",
+    ];
+    let output = run_crypto(&reencrypt_args, "wrong_pass\nnew_pass");
+    assert_eq!(output.status.code(), Some(1));
+
+    let output = run_crypto(&reencrypt_args, "old_pass\nnew_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "re-encrypt failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("\"phase\":\"reencrypt\""), "progress: {}", stdout);
+
+    let dec_args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&dec_args, "old_pass");
+    assert_eq!(output.status.code(), Some(1));
+    let output = run_crypto(&dec_args, "new_pass");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), plaintext);
+}
+
+#[test]
+fn test_split_volumes_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("archive.tar");
+    let encrypted_path = dir.path().join("archive.tar.gtkrypt");
+    let decrypted_path = dir.path().join("archive_decrypted.tar");
+
+    let plaintext: Vec<u8> = (0..320_000u32).map(|i| (i % 241) as u8).collect();
+    fs::write(&input_path, &plaintext).unwrap();
+
+    let mut enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    enc_args.extend_from_slice(&["--split-size", "100K"]);
+    let output = run_crypto(&enc_args, "split_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Split encrypt failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let base = encrypted_path.to_str().unwrap();
+    assert!(!encrypted_path.exists());
+    for volume in ["001", "002"] {
+        let size = fs::metadata(format!("{}.{}", base, volume)).unwrap().len();
+        assert_eq!(size, 100 * 1024);
+    }
+    assert!(fs::metadata(format!("{}.004", base)).unwrap().len() > 0);
+    assert!(!std::path::Path::new(&format!("{}.005", base)).exists());
+
+    // Either the base name or the first volume selects the whole set
+    let first_volume = format!("{}.001", base);
+    for input in [base, first_volume.as_str()] {
+        let dec_args = decrypt_args(input, decrypted_path.to_str().unwrap(), None);
+        let output = run_crypto(&dec_args, "split_pass");
+        assert_eq!(
+            output.status.code(),
+            Some(0),
+            "Split decrypt failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(fs::read(&decrypted_path).unwrap(), plaintext);
+        fs::remove_file(&decrypted_path).unwrap();
+    }
+
+    // A missing volume is reported as corruption
+    fs::remove_file(format!("{}.004", base)).unwrap();
+    let dec_args = decrypt_args(base, decrypted_path.to_str().unwrap(), None);
+    let output = run_crypto(&dec_args, "split_pass");
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_parity_repairs_damaged_container() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("photos.tar");
+    let encrypted_path = dir.path().join("photos.tar.gtkrypt");
+    let decrypted_path = dir.path().join("photos_decrypted.tar");
+
+    let plaintext: Vec<u8> = (0..200_000u32).map(|i| (i % 239) as u8).collect();
+    fs::write(&input_path, &plaintext).unwrap();
+
+    let mut enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    enc_args.extend_from_slice(&["--parity", "50"]);
+    let output = run_crypto(&enc_args, "parity_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Parity encrypt failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // Damage the header and a chunk in a different parity block
+    let mut data = fs::read(&encrypted_path).unwrap();
+    data[20] ^= 0xFF;
+    data[150_000] ^= 0xFF;
+    fs::write(&encrypted_path, &data).unwrap();
+
+    let dec_args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&dec_args, "parity_pass");
+    assert_ne!(output.status.code(), Some(0));
+
+    let output = run_crypto_no_stdin(&["repair", "--input", encrypted_path.to_str().unwrap()]);
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Repair failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("\"blocks_repaired\":2"), "Unexpected report: {}", stdout);
+
+    let output = run_crypto(&dec_args, "parity_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Decrypt after repair failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(fs::read(&decrypted_path).unwrap(), plaintext);
+}
+
+#[test]
+fn test_verify_after_encrypt_reports_verify_phase() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("thesis.pdf");
+    let encrypted_path = dir.path().join("thesis.pdf.gtkrypt");
+    let decrypted_path = dir.path().join("thesis_decrypted.pdf");
+
+    let plaintext: Vec<u8> = (0..150_000u32).map(|i| (i % 233) as u8).collect();
+    fs::write(&input_path, &plaintext).unwrap();
+
+    let mut enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    enc_args.extend_from_slice(&["--verify-after-encrypt", "--parity", "5", "--pad"]);
+    let output = run_crypto(&enc_args, "verify_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Verified encrypt failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let last_verify = stdout
+        .lines()
+        .rfind(|line| line.contains("\"phase\":\"verify\""))
+        .expect("no verify progress");
+    assert!(last_verify.contains("\"bytes_processed\":150000"), "{}", last_verify);
+
+    let dec_args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&dec_args, "verify_pass");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), plaintext);
+}
+
+#[test]
+fn test_tampered_mode_field_detected_by_header_mac() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("mac.txt");
+    let encrypted_path = dir.path().join("mac.gtkrypt");
+    let tampered_path = dir.path().join("mac_tampered.gtkrypt");
+    let decrypted_path = dir.path().join("mac_decrypted.txt");
+
+    fs::write(&input_path, b"Header MAC test").unwrap();
+
+    let enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&enc_args, "mac_pass");
+    assert_eq!(output.status.code(), Some(0));
+
+    // The mode field (offset 51..55 with no stored filename) lies outside
+    // the per-chunk AAD; flipping it must still be detected.
+    let mut data = fs::read(&encrypted_path).unwrap();
+    data[53] ^= 0x01;
+    fs::write(&tampered_path, &data).unwrap();
+
+    let dec_args = decrypt_args(
+        tampered_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&dec_args, "mac_pass");
+    assert_ne!(output.status.code(), Some(0));
+    assert!(!decrypted_path.exists());
+}
+
+#[test]
+fn test_comment_shown_by_inspect() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("taxes.pdf");
+    let encrypted_path = dir.path().join("taxes.pdf.gtkrypt");
+
+    fs::write(&input_path, b"Receipts").unwrap();
+
+    let mut enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    enc_args.extend_from_slice(&["--comment", "taxes 2023, includes receipts"]);
+    let output = run_crypto(&enc_args, "comment_pass");
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = run_crypto_no_stdin(&["inspect", "--input", encrypted_path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Comment: taxes 2023, includes receipts"),
+        "inspect output: {}",
+        stdout
+    );
+
+    let output = run_crypto_no_stdin(&[
+        "inspect",
+        "--json",
+        "--input",
+        encrypted_path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["comment"], "taxes 2023, includes receipts");
+    assert_eq!(report["version"], 3);
+}
+
+#[test]
+fn test_inspect_unlock_reveals_encrypted_comment() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("private.txt");
+    let encrypted_path = dir.path().join("private.gtkrypt");
+
+    fs::write(&input_path, b"Private").unwrap();
+
+    let mut enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    enc_args.extend_from_slice(&["--comment", "hidden note", "--encrypt-metadata"]);
+    let output = run_crypto(&enc_args, "unlock_pass");
+    assert_eq!(output.status.code(), Some(0));
+
+    let locked = run_crypto_no_stdin(&[
+        "inspect",
+        "--json",
+        "--input",
+        encrypted_path.to_str().unwrap(),
+    ]);
+    let report: serde_json::Value = serde_json::from_slice(&locked.stdout).unwrap();
+    assert_eq!(report["metadata_encrypted"], true);
+    assert!(report["comment"].is_null());
+
+    let unlocked = run_crypto(
+        &[
+            "inspect",
+            "--json",
+            "--unlock",
+            "--input",
+            encrypted_path.to_str().unwrap(),
+        ],
+        "unlock_pass",
+    );
+    assert_eq!(unlocked.status.code(), Some(0));
+    let report: serde_json::Value = serde_json::from_slice(&unlocked.stdout).unwrap();
+    assert_eq!(report["comment"], "hidden note");
+}
+
+#[test]
+fn test_edit_header_changes_comment_and_filename() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("notes.txt");
+    let encrypted_path = dir.path().join("notes.gtkrypt");
+    let decrypted_path = dir.path().join("notes_decrypted.txt");
+
+    let plaintext = vec![0x42u8; 200_000];
+    fs::write(&input_path, &plaintext).unwrap();
+
+    let mut enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    enc_args.extend_from_slice(&["--store-filename", "--comment", "first draft"]);
+    let output = run_crypto(&enc_args, "edit_pass");
+    assert_eq!(output.status.code(), Some(0));
+
+    let edit_args = [
+        "edit-header",
+        "--input",
+        encrypted_path.to_str().unwrap(),
+        "--set-filename",
+        "meeting-notes.txt",
+        "--set-comment",
+        "final version, reviewed",
+    ];
+    let output = run_crypto(&edit_args, "wrong_pass");
+    assert_eq!(output.status.code(), Some(1));
+
+    let output = run_crypto(&edit_args, "edit_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "edit-header failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let output = run_crypto_no_stdin(&[
+        "inspect",
+        "--json",
+        "--input",
+        encrypted_path.to_str().unwrap(),
+    ]);
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["filename"], "meeting-notes.txt");
+    assert_eq!(report["comment"], "final version, reviewed");
+
+    let dec_args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&dec_args, "edit_pass");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), plaintext);
+}
+
+#[test]
+fn test_user_metadata_in_inspect_json() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("tagged.txt");
+    let encrypted_path = dir.path().join("tagged.gtkrypt");
+
+    fs::write(&input_path, b"Tagged").unwrap();
+
+    let mut enc_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    enc_args.extend_from_slice(&["--meta", "backup.job=nightly", "--meta", "host=nas01"]);
+    let output = run_crypto(&enc_args, "meta_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Encrypt failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let output = run_crypto_no_stdin(&[
+        "inspect",
+        "--json",
+        "--input",
+        encrypted_path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["user_metadata"]["backup.job"], "nightly");
+    assert_eq!(report["user_metadata"]["host"], "nas01");
+    assert!(report["created_at"].as_u64().unwrap() > 0);
+    assert!(report["tool_version"]
+        .as_str()
+        .unwrap()
+        .starts_with("gtkrypt-crypto "));
+
+    // Invalid keys are rejected before anything is written
+    let rejected_path = dir.path().join("rejected.gtkrypt");
+    let mut bad_args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        rejected_path.to_str().unwrap(),
+        None,
+    );
+    bad_args.extend_from_slice(&["--meta", "bad key=x"]);
+    let output = run_crypto(&bad_args, "meta_pass");
+    assert_eq!(output.status.code(), Some(10));
+    assert!(!rejected_path.exists());
+}
+
+#[test]
+fn test_directory_archive_roundtrip_list_and_extract() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_dir = dir.path().join("project");
+    let encrypted_path = dir.path().join("project.gtkrypt");
+    let decrypted_dir = dir.path().join("project_restored");
+    let extract_dir = dir.path().join("extracted");
+
+    fs::create_dir_all(input_dir.join("src/empty")).unwrap();
+    fs::write(input_dir.join("notes.txt"), b"archive notes").unwrap();
+    let big: Vec<u8> = (0..100_000u32).map(|i| (i % 241) as u8).collect();
+    fs::write(input_dir.join("src/data.bin"), &big).unwrap();
+
+    let enc_args = fast_encrypt_args(
+        input_dir.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&enc_args, "archive_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Archive encrypt failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let dec_args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_dir.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&dec_args, "archive_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Archive decrypt failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(fs::read(decrypted_dir.join("notes.txt")).unwrap(), b"archive notes");
+    assert_eq!(fs::read(decrypted_dir.join("src/data.bin")).unwrap(), big);
+    assert!(decrypted_dir.join("src/empty").is_dir());
+
+    let list_args = ["list", "--input", encrypted_path.to_str().unwrap(), "--json"];
+    let output = run_crypto(&list_args, "archive_pass");
+    assert_eq!(output.status.code(), Some(0));
+    // The listing follows the KDF progress lines
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let entries: serde_json::Value = serde_json::from_str(stdout.lines().last().unwrap()).unwrap();
+    let paths: Vec<&str> = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["path"].as_str().unwrap())
+        .collect();
+    assert_eq!(paths, vec!["notes.txt", "src", "src/data.bin", "src/empty"]);
+    assert_eq!(entries[2]["kind"], "file");
+    assert_eq!(entries[2]["size"], 100_000);
+
+    let extract_args = [
+        "extract",
+        "--input",
+        encrypted_path.to_str().unwrap(),
+        "--output-dir",
+        extract_dir.to_str().unwrap(),
+        "--entry",
+        "src/data.bin",
+    ];
+    let output = run_crypto(&extract_args, "archive_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Extract failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(fs::read(extract_dir.join("src/data.bin")).unwrap(), big);
+    assert!(!extract_dir.join("notes.txt").exists());
+
+    let output = run_crypto(&extract_args, "wrong_pass");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_directory_archive_exclude_patterns() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_dir = dir.path().join("webapp");
+    let encrypted_path = dir.path().join("webapp.gtkrypt");
+    let patterns_path = dir.path().join("excludes.txt");
+
+    fs::create_dir_all(input_dir.join("node_modules/left-pad")).unwrap();
+    fs::create_dir_all(input_dir.join(".git")).unwrap();
+    fs::write(input_dir.join("node_modules/left-pad/index.js"), b"module").unwrap();
+    fs::write(input_dir.join(".git/HEAD"), b"ref").unwrap();
+    fs::write(input_dir.join("app.js"), b"app").unwrap();
+    fs::write(input_dir.join("server.log"), b"log").unwrap();
+    fs::write(&patterns_path, "# version control\n.git\n\n*.log\n").unwrap();
+
+    let mut enc_args = fast_encrypt_args(
+        input_dir.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    enc_args.extend_from_slice(&[
+        "--exclude",
+        "node_modules",
+        "--exclude-from",
+        patterns_path.to_str().unwrap(),
+    ]);
+    let output = run_crypto(&enc_args, "filter_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Filtered encrypt failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let list_args = ["list", "--input", encrypted_path.to_str().unwrap()];
+    let output = run_crypto(&list_args, "filter_pass");
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("app.js"));
+    assert!(!stdout.contains("node_modules"));
+    assert!(!stdout.contains("HEAD"));
+    assert!(!stdout.contains("server.log"));
+
+    // Patterns make no sense for a single file
+    let file_input = input_dir.join("app.js");
+    let file_output = dir.path().join("app.js.gtkrypt");
+    let mut enc_args = fast_encrypt_args(
+        file_input.to_str().unwrap(),
+        file_output.to_str().unwrap(),
+        None,
+    );
+    enc_args.extend_from_slice(&["--exclude", "*.log"]);
+    let output = run_crypto(&enc_args, "filter_pass");
+    assert_eq!(output.status.code(), Some(10));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_directory_archive_skips_symlinks_with_warning() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_dir = dir.path().join("home");
+    let encrypted_path = dir.path().join("home.gtkrypt");
+
+    fs::create_dir_all(&input_dir).unwrap();
+    fs::write(input_dir.join("todo.txt"), b"milk").unwrap();
+    std::os::unix::fs::symlink("/etc/hostname", input_dir.join("host")).unwrap();
+
+    let mut enc_args = fast_encrypt_args(
+        input_dir.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    enc_args.extend_from_slice(&["--symlinks", "skip"]);
+    let output = run_crypto(&enc_args, "link_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Encrypt failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let warning: serde_json::Value = stdout
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .find(|event| event.get("warning").is_some())
+        .expect("no warning event");
+    assert_eq!(warning["warning"], "symlink_skipped");
+    assert!(warning["message"].as_str().unwrap().contains("host"));
+
+    let list_args = ["list", "--input", encrypted_path.to_str().unwrap()];
+    let output = run_crypto(&list_args, "link_pass");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("todo.txt"));
+    assert!(!stdout.contains("host"));
+
+    let mut enc_args = fast_encrypt_args(
+        input_dir.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    enc_args.extend_from_slice(&["--symlinks", "sometimes"]);
+    let output = run_crypto(&enc_args, "link_pass");
+    assert_ne!(output.status.code(), Some(0));
+}
+
+#[test]
+fn test_direct_io_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("image.bin");
+    let encrypted_path = dir.path().join("image.bin.gtkrypt");
+    let decrypted_path = dir.path().join("image.out");
+    let content: Vec<u8> = (0..3_000_000u32).map(|i| (i % 253) as u8).collect();
+    fs::write(&input_path, &content).unwrap();
+
+    let input = input_path.to_str().unwrap();
+    let encrypted = encrypted_path.to_str().unwrap();
+    let mut args = fast_encrypt_args(input, encrypted, None);
+    args.push("--direct-io");
+    let output = run_crypto(&args, "direct_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Encrypt failed. stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let mut args = decrypt_args(encrypted, decrypted_path.to_str().unwrap(), None);
+    args.push("--direct-io");
+    let output = run_crypto(&args, "direct_pass");
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "Decrypt failed. stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(fs::read(&decrypted_path).unwrap(), content);
+}
+
+#[test]
+fn test_drop_cache_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("stream.bin");
+    let encrypted_path = dir.path().join("stream.bin.gtkrypt");
+    let decrypted_path = dir.path().join("stream.out");
+    let content: Vec<u8> = (0..20_000_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(&input_path, &content).unwrap();
+
+    let input = input_path.to_str().unwrap();
+    let encrypted = encrypted_path.to_str().unwrap();
+    let mut args = fast_encrypt_args(input, encrypted, None);
+    args.push("--drop-cache");
+    let output = run_crypto(&args, "cache_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let mut args = decrypt_args(encrypted, decrypted_path.to_str().unwrap(), None);
+    args.push("--drop-cache");
+    let output = run_crypto(&args, "cache_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), content);
+}
+
+#[test]
+fn test_io_buffer_size_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("buffers.bin");
+    let content: Vec<u8> = (0..1_000_000u32).map(|i| (i % 249) as u8).collect();
+    fs::write(&input_path, &content).unwrap();
+    let input = input_path.to_str().unwrap();
+
+    // Buffers smaller than a chunk, unaligned, and larger than the file
+    for size in ["1000", "65552", "4194304"] {
+        let encrypted_path = dir.path().join(format!("buffers.{}.gtkrypt", size));
+        let decrypted_path = dir.path().join(format!("buffers.{}.out", size));
+        let encrypted = encrypted_path.to_str().unwrap();
+        let mut args = fast_encrypt_args(input, encrypted, None);
+        args.extend(["--io-buffer-size", size]);
+        let output = run_crypto(&args, "buffer_pass");
+        assert_eq!(
+            output.status.code(),
+            Some(0),
+            "Encrypt failed. stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let mut args = decrypt_args(encrypted, decrypted_path.to_str().unwrap(), None);
+        args.extend(["--io-buffer-size", size]);
+        let output = run_crypto(&args, "buffer_pass");
+        assert_eq!(
+            output.status.code(),
+            Some(0),
+            "Decrypt failed. stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(fs::read(&decrypted_path).unwrap(), content);
+    }
+}
+
+#[test]
+fn test_bench_prints_cipher_results() {
+    let output = run_crypto_no_stdin(&["bench", "--cipher", "all", "--duration-ms", "20"]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let results = report["results"].as_array().unwrap();
+    assert_eq!(results[0]["cipher"], "aes-256-gcm");
+    assert!(results[0]["encrypt_bytes_per_sec"].as_u64().unwrap() > 0);
+
+    let output = run_crypto_no_stdin(&["bench", "--cipher", "rot13"]);
+    assert_ne!(output.status.code(), Some(0));
+}
+
+#[test]
+fn test_estimate_reports_decrypt_cost() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("estimated.bin");
+    let encrypted_path = dir.path().join("estimated.bin.gtkrypt");
+    fs::write(&input_path, vec![1u8; 300_000]).unwrap();
+    let input = input_path.to_str().unwrap();
+    let encrypted = encrypted_path.to_str().unwrap();
+    let output = run_crypto(&fast_encrypt_args(input, encrypted, None), "pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // No passphrase is needed
+    let output = run_crypto_no_stdin(&["estimate", "--input", encrypted, "--threads", "2"]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let estimate: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(estimate["kdf_memory_kib"], 1024);
+    assert_eq!(estimate["kdf_time_cost"], 1);
+    assert_eq!(estimate["payload_bytes"], 300_000);
+    assert_eq!(estimate["chunks"], 5);
+    assert_eq!(estimate["threads"], 2);
+    assert!(estimate["decrypt_bytes_per_sec"].as_u64().unwrap() > 0);
+    let kdf_secs = estimate["kdf_secs"].as_f64().unwrap();
+    let decrypt_secs = estimate["decrypt_secs"].as_f64().unwrap();
+    assert!(kdf_secs > 0.0 && decrypt_secs > 0.0);
+    // Parsed back from JSON, the sum may be off in the last bit
+    let total_secs = estimate["total_secs"].as_f64().unwrap();
+    assert!((total_secs - (kdf_secs + decrypt_secs)).abs() <= total_secs * 1e-12);
+
+    let output = run_crypto_no_stdin(&["estimate", "--input", input]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_target_time_calibration_is_kept_in_the_state_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let state_dir = dir.path().join("state");
+    let calibration_path = state_dir.join("kdf-calibration.json");
+    let input_path = dir.path().join("calibrated.bin");
+    let encrypted_path = dir.path().join("calibrated.bin.gtkrypt");
+    fs::write(&input_path, b"measured once").unwrap();
+    let input = input_path.to_str().unwrap();
+    let encrypted = encrypted_path.to_str().unwrap();
+    let encrypt = || {
+        let mut child = crypto_command()
+            .args(["encrypt", "--input", input, "--output", encrypted, "--force"])
+            .args(["--target-time", "100ms", "--memory-cost", "1024", "--parallelism", "1"])
+            .env("GTKRYPT_STATE_DIR", &state_dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        writeln!(child.stdin.take().unwrap(), "pass").unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+        let output = run_crypto_no_stdin(&["inspect", "--input", encrypted, "--json"]);
+        let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        report["kdf"]["time_cost"].as_u64().unwrap()
+    };
+
+    // The first run measures the machine and keeps the rate
+    assert!(encrypt() >= 1);
+    let mut calibrations: serde_json::Value =
+        serde_json::from_slice(&fs::read(&calibration_path).unwrap()).unwrap();
+    assert_eq!(calibrations.as_object().unwrap().len(), 1);
+
+    // Later runs go by the kept rate: at 51200 KiB a second, a 1 MiB pass
+    // takes 20ms, so 100ms is 5 passes
+    for calibration in calibrations.as_object_mut().unwrap().values_mut() {
+        calibration["kib_per_second"] = 51200.0.into();
+    }
+    fs::write(&calibration_path, calibrations.to_string()).unwrap();
+    assert_eq!(encrypt(), 5);
+}
+
+#[test]
+fn test_stats_accounts_for_container_sizes() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut containers = Vec::new();
+    for (name, len) in [("small.bin", 10), ("large.bin", 150_000)] {
+        let input = dir.path().join(name);
+        let encrypted = dir.path().join(format!("{}.gtkrypt", name));
+        fs::write(&input, vec![3u8; len]).unwrap();
+        let args = fast_encrypt_args(input.to_str().unwrap(), encrypted.to_str().unwrap(), None);
+        assert_eq!(run_crypto(&args, "pass").status.code(), Some(0));
+        containers.push(encrypted.to_str().unwrap().to_string());
+    }
+
+    let output = run_crypto_no_stdin(&["--json", "stats", &containers[0], &containers[1]]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let large = &report["containers"][1];
+    assert_eq!(large["version"], 3);
+    assert_eq!(large["chunks"], 3);
+    assert_eq!(large["chunk_overhead"], 16);
+    assert_eq!(large["original_file_size"], 150_000);
+    assert_eq!(large["padding_size"], 0);
+    let size = fs::metadata(&containers[1]).unwrap().len();
+    assert_eq!(large["container_size"], size);
+    assert_eq!(large["overhead"], size - 150_000);
+    assert_eq!(report["total"]["containers"], 2);
+    assert_eq!(report["total"]["original_size"], 150_010);
+
+    let output = run_crypto_no_stdin(&["stats", &containers[0]]);
+    let text = String::from_utf8_lossy(&output.stdout);
+    assert!(text.contains("Original size: 10 bytes\n"), "{}", text);
+    assert!(!text.contains("Total:"), "{}", text);
+}
+
+#[test]
+fn test_capabilities_reports_hardware() {
+    let output = run_crypto_no_stdin(&["capabilities"]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let capabilities: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(capabilities["hardware"]["aes_gcm_accelerated"].is_boolean());
+
+    let subcommands = capabilities["subcommands"].as_array().unwrap();
+    for name in ["encrypt", "decrypt", "inspect", "capabilities"] {
+        assert!(subcommands.iter().any(|command| command == name), "{}", name);
+    }
+    assert!(capabilities["protocol_version"].as_u64().unwrap() >= 1);
+    let read = capabilities["container_versions"]["read"].as_array().unwrap();
+    assert!(read.contains(&capabilities["container_versions"]["write"]));
+}
+
+#[test]
+fn test_completions_and_manpage() {
+    let shells = [("bash", "complete -F"), ("zsh", "#compdef"), ("fish", "complete -c")];
+    for (shell, marker) in shells {
+        let output = run_crypto_no_stdin(&["completions", shell]);
+        assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+        let script = String::from_utf8(output.stdout).unwrap();
+        assert!(script.contains(marker), "{}", shell);
+        assert!(script.contains("re-encrypt") && script.contains("passphrase-format"));
+    }
+    let output = run_crypto_no_stdin(&["completions", "cmd.exe"]);
+    assert_eq!(output.status.code(), Some(2));
+
+    let output = run_crypto_no_stdin(&["manpage"]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let page = String::from_utf8(output.stdout).unwrap();
+    assert!(page.contains(".TH gtkrypt-crypto 1"));
+    assert!(page.contains("re\\-encrypt"));
+
+    let dir = tempfile::tempdir().unwrap();
+    let pages = dir.path().join("man1");
+    let output = run_crypto_no_stdin(&["manpage", "--output-dir", pages.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let encrypt = fs::read_to_string(pages.join("gtkrypt-crypto-encrypt.1")).unwrap();
+    assert!(encrypt.contains("\\-\\-keyfile"));
+    assert!(pages.join("gtkrypt-crypto-header-backup.1").exists());
+    assert!(pages.join("gtkrypt-crypto.1").exists());
+}
+
+#[test]
+fn test_version_json() {
+    let output = run_crypto_no_stdin(&["--version-json"]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let version: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(version["container_versions"]["write"], 3);
+    assert!(version["protocol_version"].as_u64().unwrap() >= 1);
+
+    // The same version for people
+    let output = run_crypto_no_stdin(&["--version"]);
+    let text = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(text.trim(), format!("gtkrypt-crypto {}", env!("CARGO_PKG_VERSION")));
+
+    assert_eq!(run_crypto_no_stdin(&["--version-json", "capabilities"]).status.code(), Some(2));
+    assert_eq!(run_crypto_no_stdin(&[]).status.code(), Some(2));
+}
+
+#[test]
+fn test_kernel_backend_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("board.img");
+    let encrypted_path = dir.path().join("board.img.gtkrypt");
+    let decrypted_path = dir.path().join("board.out");
+    let content: Vec<u8> = (0..200_000u32).map(|i| (i % 247) as u8).collect();
+    fs::write(&input_path, &content).unwrap();
+
+    // Without AF_ALG the kernel backend falls back to software, so the
+    // roundtrip holds either way
+    let encrypted = encrypted_path.to_str().unwrap();
+    let mut args = fast_encrypt_args(input_path.to_str().unwrap(), encrypted, None);
+    args.extend(["--crypto-backend", "kernel"]);
+    let output = run_crypto(&args, "kernel_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let mut args = decrypt_args(encrypted, decrypted_path.to_str().unwrap(), None);
+    args.extend(["--crypto-backend", "kernel"]);
+    let output = run_crypto(&args, "kernel_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), content);
+}
+
+#[test]
+fn test_max_kdf_limits_refuse_excessive_header() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("capped.txt");
+    let encrypted_path = dir.path().join("capped.txt.gtkrypt");
+    let decrypted_path = dir.path().join("capped.out");
+    fs::write(&input_path, b"capped").unwrap();
+    let encrypted = encrypted_path.to_str().unwrap();
+    let decrypted = decrypted_path.to_str().unwrap();
+
+    let args = fast_encrypt_args(input_path.to_str().unwrap(), encrypted, None);
+    let output = run_crypto(&args, "cap_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // The header asks for 1024 KiB and a time cost of 1
+    for limit in [["--max-kdf-memory", "512"], ["--max-kdf-time", "0"]] {
+        let mut args = decrypt_args(encrypted, decrypted, None);
+        args.extend(limit);
+        let output = run_crypto(&args, "cap_pass");
+        assert_eq!(output.status.code(), Some(8));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("\"kdf_params_excessive\""), "{}", stderr);
+        assert!(!decrypted_path.exists());
+    }
+
+    let mut args = decrypt_args(encrypted, decrypted, None);
+    args.extend(["--max-kdf-memory", "1024", "--max-kdf-time", "1"]);
+    let output = run_crypto(&args, "cap_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"capped");
+}
+
+#[test]
+fn test_truncated_container_rejected_before_kdf() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("short.txt");
+    let encrypted_path = dir.path().join("short.gtkrypt");
+    fs::write(&input_path, vec![7u8; 100_000]).unwrap();
+    let encrypted = encrypted_path.to_str().unwrap();
+
+    let args = fast_encrypt_args(input_path.to_str().unwrap(), encrypted, None);
+    let output = run_crypto(&args, "short_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // A hostile header asking for ~4 TiB of Argon2 memory, on a file cut short
+    let mut data = fs::read(&encrypted_path).unwrap();
+    data[14..18].copy_from_slice(&0xffff_fff0u32.to_be_bytes());
+    data.truncate(data.len() - 1000);
+    fs::write(&encrypted_path, &data).unwrap();
+
+    for command in ["check-passphrase", "inspect"] {
+        let mut args = vec![command, "--input", encrypted];
+        if command == "inspect" {
+            args.push("--unlock");
+        }
+        let output = run_crypto(&args, "short_pass");
+        assert_eq!(output.status.code(), Some(2), "{}", String::from_utf8_lossy(&output.stderr));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("File size mismatch"), "{}", stderr);
+        let error: serde_json::Value = serde_json::from_str(stderr.trim()).unwrap();
+        assert_eq!(error["code"], "corrupt_file");
+        assert_eq!(error["details"]["expected_size"], data.len() as u64 + 1000);
+        assert_eq!(error["details"]["actual_size"], data.len() as u64);
+        assert_eq!(error["message_id"], "file_size_mismatch");
+        assert_eq!(error["message_params"]["actual"], data.len() as u64);
+        assert!(!String::from_utf8_lossy(&output.stdout).contains("\"kdf\""));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_output_locked_by_another_process_is_busy() {
+    use std::os::fd::AsRawFd;
+
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("locked.txt");
+    let encrypted_path = dir.path().join("locked.txt.gtkrypt");
+    fs::write(&input_path, b"one writer at a time").unwrap();
+    let encrypted = encrypted_path.to_str().unwrap();
+
+    // Stand in for a concurrent run holding the output
+    let lock_file = fs::File::create(dir.path().join(".locked.txt.gtkrypt.gtkrypt-lock")).unwrap();
+    assert_eq!(unsafe { libc::flock(lock_file.as_raw_fd(), libc::LOCK_EX) }, 0);
+
+    let args = fast_encrypt_args(input_path.to_str().unwrap(), encrypted, None);
+    let output = run_crypto(&args, "lock_pass");
+    assert_eq!(output.status.code(), Some(9));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let error: serde_json::Value = serde_json::from_str(stderr.trim()).unwrap();
+    assert_eq!(error["error"], "resource_busy");
+    assert!(!encrypted_path.exists());
+
+    drop(lock_file);
+    let output = run_crypto(&args, "lock_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    // The lock file goes with the lock
+    assert!(!dir.path().join(".locked.txt.gtkrypt.gtkrypt-lock").exists());
+}
+
+#[test]
+fn test_temp_dir_stages_output_elsewhere() {
+    let dir = tempfile::tempdir().unwrap();
+    // On another filesystem where there is one, so the move has to copy
+    let staging = if std::path::Path::new("/dev/shm").is_dir() {
+        tempfile::tempdir_in("/dev/shm").unwrap()
+    } else {
+        tempfile::tempdir().unwrap()
+    };
+    let input_path = dir.path().join("staged.txt");
+    let encrypted_path = dir.path().join("staged.txt.gtkrypt");
+    let decrypted_path = dir.path().join("staged.out");
+    fs::write(&input_path, b"written on the fast disk first").unwrap();
+    let staging_dir = staging.path().to_str().unwrap();
+
+    let mut args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    args.extend(["--temp-dir", staging_dir]);
+    let output = run_crypto(&args, "staging_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let mut args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    );
+    args.extend(["--temp-dir", staging_dir]);
+    let output = run_crypto(&args, "staging_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"written on the fast disk first");
+
+    // Nothing is left behind in either directory
+    assert_eq!(fs::read_dir(staging.path()).unwrap().count(), 0);
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+}
+
+#[test]
+fn test_cleanup_removes_abandoned_temps() {
+    use std::os::fd::AsRawFd;
+
+    let dir = tempfile::tempdir().unwrap();
+    let old = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+    let make_temp = |name: &str| {
+        let path = dir.path().join(name);
+        let file = fs::File::create(&path).unwrap();
+        file.set_times(fs::FileTimes::new().set_modified(old)).unwrap();
+        file
+    };
+    drop(make_temp(".gtkrypt-tmp-Ab12Cd"));
+    // Still being written by a live run
+    let live = make_temp(".gtkrypt-tmp-Ef34Gh");
+    assert_eq!(unsafe { libc::flock(live.as_raw_fd(), libc::LOCK_EX) }, 0);
+    fs::write(dir.path().join("notes.txt"), b"not ours").unwrap();
+
+    let output = run_crypto_no_stdin(&["cleanup", "--dir", dir.path().to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<serde_json::Value> =
+        stdout.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0]["temp_removed"].as_str().unwrap().ends_with(".gtkrypt-tmp-Ab12Cd"));
+    assert!(!dir.path().join(".gtkrypt-tmp-Ab12Cd").exists());
+    assert!(dir.path().join(".gtkrypt-tmp-Ef34Gh").exists());
+    assert!(dir.path().join("notes.txt").exists());
+
+    // Once the run is gone, the next encryption into the directory sweeps it
+    drop(live);
+    let input_path = dir.path().join("notes.txt");
+    let encrypted_path = dir.path().join("notes.txt.gtkrypt");
+    let mut args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    args.push("--clean-temp");
+    let output = run_crypto(&args, "cleanup_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("\"temp_removed\""));
+    assert!(!dir.path().join(".gtkrypt-tmp-Ef34Gh").exists());
+}
+
+#[test]
+fn test_sigterm_cancels_and_removes_temp_files() {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let fifo_path = dir.path().join("input.fifo");
+    let encrypted_path = dir.path().join("input.gtkrypt");
+    let fifo = CString::new(fifo_path.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+
+    let args = fast_encrypt_args(
+        fifo_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    let mut child = crypto_command()
+        .args(&args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    writeln!(child.stdin.as_mut().unwrap(), "cancel_pass").unwrap();
+
+    // The input stays open and unfinished, so the run stalls mid-stream
+    let mut input = fs::OpenOptions::new().write(true).open(&fifo_path).unwrap();
+    input.write_all(b"half of the input").unwrap();
+    let has_temp = || {
+        fs::read_dir(dir.path()).unwrap().any(|entry| {
+            entry.unwrap().file_name().to_string_lossy().starts_with(".gtkrypt-tmp-")
+        })
+    };
+    let start = std::time::Instant::now();
+    while !has_temp() {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    assert_eq!(unsafe { libc::kill(child.id() as i32, libc::SIGTERM) }, 0);
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(11));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let error: serde_json::Value = serde_json::from_str(stderr.trim()).unwrap();
+    assert_eq!(error["error"], "cancelled");
+
+    // Neither the temp output, the lock file nor a partial output remain
+    drop(input);
+    let left: Vec<_> = fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(left, vec![std::ffi::OsString::from("input.fifo")]);
+}
+
+#[test]
+fn test_pause_and_resume_over_stdin() {
+    use std::io::BufRead;
+
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("paused.bin");
+    let encrypted_path = dir.path().join("paused.bin.gtkrypt");
+    let decrypted_path = dir.path().join("paused.out");
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 249) as u8).collect();
+    fs::write(&input_path, &data).unwrap();
+
+    // A key derivation slow enough that the pause arrives before any chunk
+    let args = [
+        "encrypt",
+        "--input",
+        input_path.to_str().unwrap(),
+        "--output",
+        encrypted_path.to_str().unwrap(),
+        "--time-cost",
+        "2",
+        "--memory-cost",
+        "16384",
+        "--parallelism",
+        "1",
+    ];
+    let mut child = crypto_command()
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    writeln!(stdin, "pause_pass").unwrap();
+    writeln!(stdin, "{{\"cmd\":\"rewind\"}}").unwrap();
+    writeln!(stdin, "{{\"cmd\":\"pause\"}}").unwrap();
+
+    let mut stdout = std::io::BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut next_event = || -> serde_json::Value {
+        serde_json::from_str(&stdout.next().unwrap().unwrap()).unwrap()
+    };
+    let mut warned = false;
+    loop {
+        let event = next_event();
+        warned |= event["warning"] == "unknown_command";
+        if event["state"] == "paused" {
+            break;
+        }
+    }
+    assert!(warned);
+
+    // No chunk is encrypted while paused
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    assert!(child.try_wait().unwrap().is_none());
+    assert!(!encrypted_path.exists());
+
+    writeln!(stdin, "{{\"cmd\":\"resume\"}}").unwrap();
+    while next_event()["state"] != "running" {}
+    let status = child.wait().unwrap();
+    assert_eq!(status.code(), Some(0));
+    drop(stdin);
+
+    let args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&args, "pause_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), data);
+}
+
+#[test]
+fn test_timeout_stops_a_stalled_run() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("slow.txt");
+    let encrypted_path = dir.path().join("slow.txt.gtkrypt");
+    fs::write(&input_path, b"never gets there").unwrap();
+
+    let mut args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    args.extend(["--timeout", "1"]);
+    let mut child = crypto_command()
+        .args(&args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Held paused, with stdin left open, until the time runs out
+    let mut stdin = child.stdin.take().unwrap();
+    writeln!(stdin, "timeout_pass").unwrap();
+    writeln!(stdin, "{{\"cmd\":\"pause\"}}").unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(12));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let error: serde_json::Value = serde_json::from_str(stderr.trim()).unwrap();
+    assert_eq!(error["error"], "timeout");
+    assert_eq!(error["message_id"], "timed_out");
+    assert_eq!(error["message_params"]["seconds"], 1);
+    drop(stdin);
+
+    let mut left: Vec<_> = fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    left.sort();
+    assert_eq!(left, vec![std::ffi::OsString::from("slow.txt")]);
+}
+
+#[test]
+fn test_summary_line_ends_a_successful_run() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("data.bin");
+    let encrypted_path = dir.path().join("data.bin.gtkrypt");
+    let decrypted_path = dir.path().join("data.out");
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(&input_path, &data).unwrap();
+
+    let last_event = |output: &std::process::Output| -> serde_json::Value {
+        assert_eq!(output.status.code(), Some(0));
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        serde_json::from_str(stdout.lines().last().unwrap()).unwrap()
+    };
+
+    let input = input_path.to_str().unwrap();
+    let encrypted = encrypted_path.to_str().unwrap();
+    let output = run_crypto(&fast_encrypt_args(input, encrypted, None), "summary_pass");
+    let summary = last_event(&output);
+    assert_eq!(summary["summary"], "encrypt");
+    assert_eq!(summary["output_path"], encrypted);
+    assert_eq!(summary["input_bytes"], 200_000);
+    assert_eq!(summary["output_bytes"], fs::metadata(&encrypted_path).unwrap().len());
+    assert_eq!(summary["chunks"], 4);
+    assert!(summary["phase_secs"]["kdf"].is_f64());
+    assert!(summary["phase_secs"]["encrypt"].is_f64());
+    assert!(summary["elapsed_secs"].as_f64().unwrap() > 0.0);
+    assert!(summary["bytes_per_sec"].as_u64().unwrap() > 0);
+
+    let decrypted = decrypted_path.to_str().unwrap();
+    let output = run_crypto(&decrypt_args(encrypted, decrypted, None), "summary_pass");
+    let summary = last_event(&output);
+    assert_eq!(summary["summary"], "decrypt");
+    assert_eq!(summary["output_path"], decrypted);
+    assert_eq!(summary["input_bytes"], fs::metadata(&encrypted_path).unwrap().len());
+    assert_eq!(summary["output_bytes"], 200_000);
+    assert_eq!(summary["chunks"], 4);
+    assert!(summary["phase_secs"]["decrypt"].is_f64());
+    assert_eq!(fs::read(&decrypted_path).unwrap(), data);
+
+    // Nothing is summed up for a run that fails
+    let output = run_crypto(&decrypt_args(encrypted, decrypted, None), "wrong_pass");
+    assert_ne!(output.status.code(), Some(0));
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("\"summary\""));
+}
+
+#[test]
+fn test_progress_interval_thins_out_progress_lines() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("big.bin");
+    fs::write(&input_path, vec![3u8; 2 << 20]).unwrap();
+    let input = input_path.to_str().unwrap();
+
+    let encrypt_lines = |interval: Option<&str>, output: &str| {
+        let output_path = dir.path().join(output);
+        let mut args = fast_encrypt_args(input, output_path.to_str().unwrap(), None);
+        if let Some(interval) = interval {
+            args.extend(["--progress-interval", interval]);
+        }
+        let output = run_crypto(&args, "interval_pass");
+        assert_eq!(output.status.code(), Some(0));
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+            .filter(|event| event["phase"] == "encrypt")
+            .map(|event| event["progress"].as_f64().unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    // One line per 64 KiB chunk, plus the first and the last
+    assert!(encrypt_lines(None, "all.gtkrypt").len() > 32);
+    let thinned = encrypt_lines(Some("40%"), "thinned.gtkrypt");
+    assert_eq!(thinned.first(), Some(&0.0));
+    assert_eq!(thinned.last(), Some(&1.0));
+    assert!(thinned.len() <= 5, "{:?}", thinned);
+    assert!(thinned.windows(2).all(|pair| pair[1] - pair[0] >= 0.4 || pair[1] == 1.0));
+
+    let output = run_crypto(&["--progress-interval", "soon", "bench"], "");
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_progress_reports_speed_and_time_left() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("big.bin");
+    let encrypted_path = dir.path().join("big.bin.gtkrypt");
+    fs::write(&input_path, vec![5u8; 2 << 20]).unwrap();
+
+    let args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&args, "speed_pass");
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let events: Vec<serde_json::Value> = stdout
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .filter(|event: &serde_json::Value| event.get("phase").is_some())
+        .collect();
+
+    for event in events.iter().filter(|event| event["phase"] == "kdf") {
+        assert!(event.get("bytes_per_second").is_none(), "{}", event);
+    }
+    let encrypt: Vec<_> = events.iter().filter(|event| event["phase"] == "encrypt").collect();
+    // Nothing to measure against at the start of the phase
+    assert!(encrypt[0].get("bytes_per_second").is_none());
+    let last = encrypt.last().unwrap();
+    assert!(last["bytes_per_second"].as_u64().unwrap() > 0, "{}", last);
+    assert_eq!(last["eta_seconds"], 0.0);
+    let eta = |event: &&serde_json::Value| event["eta_seconds"].as_f64();
+    assert!(encrypt.iter().all(|event| eta(event).is_none_or(|eta| eta >= 0.0)));
+}
+
+#[test]
+fn test_kdf_reports_estimated_progress() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("slow.txt");
+    let encrypted_path = dir.path().join("slow.txt.gtkrypt");
+    fs::write(&input_path, b"derived slowly").unwrap();
+
+    // A key derivation that runs long enough for a few ticks
+    let args = [
+        "encrypt",
+        "--input",
+        input_path.to_str().unwrap(),
+        "--output",
+        encrypted_path.to_str().unwrap(),
+        "--time-cost",
+        "2",
+        "--memory-cost",
+        "32768",
+        "--parallelism",
+        "1",
+    ];
+    let output = run_crypto(&args, "tick_pass");
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let ticks: Vec<f64> = stdout
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|event| event["phase"] == "kdf" && event["total_bytes"] == 32768u64 * 1024 * 2)
+        .map(|event| event["progress"].as_f64().unwrap())
+        .collect();
+
+    assert!(!ticks.is_empty(), "{}", stdout);
+    assert!(ticks.iter().all(|&progress| progress > 0.0 && progress < 1.0), "{:?}", ticks);
+    assert!(ticks.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", ticks);
+}
+
+#[test]
+fn test_progress_from_a_pipe_has_no_total() {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let fifo_path = dir.path().join("piped.fifo");
+    let encrypted_path = dir.path().join("piped.gtkrypt");
+    let fifo = CString::new(fifo_path.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+
+    let writer_path = fifo_path.clone();
+    let writer = std::thread::spawn(move || {
+        let mut input = fs::OpenOptions::new().write(true).open(writer_path).unwrap();
+        input.write_all(&vec![3u8; 200_000]).unwrap();
+    });
+    let args = fast_encrypt_args(
+        fifo_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&args, "pipe_pass");
+    writer.join().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let events: Vec<serde_json::Value> = stdout
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .filter(|event: &serde_json::Value| event["phase"] == "encrypt")
+        .collect();
+
+    let (last, counting) = events.split_last().unwrap();
+    assert!(counting.len() > 1, "{}", stdout);
+    for event in counting {
+        assert!(event["total_bytes"].is_null(), "{}", event);
+        assert!(event["progress"].is_null(), "{}", event);
+        assert!(event.get("eta_seconds").is_none(), "{}", event);
+    }
+    // The total is known once the input ends
+    assert_eq!(last["bytes_processed"], 200_000);
+    assert_eq!(last["total_bytes"], 200_000);
+    assert_eq!(last["progress"], 1.0);
+}
+
+#[test]
+fn test_progress_fd_takes_the_event_lines() {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("routed.txt");
+    let encrypted_path = dir.path().join("routed.txt.gtkrypt");
+    let events_path = dir.path().join("events.jsonl");
+    fs::write(&input_path, b"progress goes elsewhere").unwrap();
+
+    let events = fs::File::create(&events_path).unwrap();
+    let events_fd = events.as_raw_fd();
+    let mut args = vec!["--progress-fd", "3"];
+    args.extend(fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    ));
+    let mut command = crypto_command();
+    command
+        .args(&args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // SAFETY: dup2 and fcntl are async-signal-safe. The file may already
+    // be descriptor 3, which dup2 then leaves marked close-on-exec
+    unsafe {
+        command.pre_exec(move || {
+            if libc::dup2(events_fd, 3) < 0 || libc::fcntl(3, libc::F_SETFD, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().unwrap();
+    writeln!(child.stdin.as_mut().unwrap(), "fd_pass").unwrap();
+    let output = child.wait_with_output().unwrap();
+    drop(events);
+
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.stdout.is_empty(), "{}", String::from_utf8_lossy(&output.stdout));
+    let lines = fs::read_to_string(&events_path).unwrap();
+    let events: Vec<serde_json::Value> =
+        lines.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert!(events.iter().any(|event| event["phase"] == "encrypt"), "{}", lines);
+    assert_eq!(events.last().unwrap()["summary"], "encrypt");
+
+    // A descriptor that was not handed down is refused before any work
+    let args = ["--progress-fd", "900", "inspect", "--input", encrypted_path.to_str().unwrap()];
+    let output = run_crypto_no_stdin(&args);
+    assert_eq!(output.status.code(), Some(10));
+    let error: serde_json::Value =
+        serde_json::from_str(String::from_utf8_lossy(&output.stderr).trim()).unwrap();
+    assert!(error["message"].as_str().unwrap().contains("file descriptor 900"), "{}", error);
+}
+
+#[test]
+fn test_msgpack_event_format() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("framed.txt");
+    let encrypted_path = dir.path().join("framed.txt.gtkrypt");
+    fs::write(&input_path, b"length-prefixed events").unwrap();
+
+    let mut args = vec!["--event-format", "msgpack"];
+    args.extend(fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    ));
+    let output = run_crypto(&args, "frame_pass");
+    assert_eq!(output.status.code(), Some(0));
+
+    let mut events = Vec::new();
+    let mut rest = &output.stdout[..];
+    while !rest.is_empty() {
+        let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        let event: serde_json::Value = rmp_serde::from_slice(&rest[4..4 + len]).unwrap();
+        events.push(event);
+        rest = &rest[4 + len..];
+    }
+    let last_progress = events.iter().rfind(|event| event["phase"] == "encrypt").unwrap();
+    assert_eq!(last_progress["progress"], 1.0);
+    assert_eq!(last_progress["total_bytes"], 22);
+    assert_eq!(events.last().unwrap()["summary"], "encrypt");
+
+    // Errors on stderr stay JSON
+    let decrypted_path = dir.path().join("out.txt");
+    let mut args = vec!["--event-format", "msgpack"];
+    args.extend(decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    ));
+    let output = run_crypto(&args, "wrong_pass");
+    assert_eq!(output.status.code(), Some(1));
+    let error: serde_json::Value =
+        serde_json::from_str(String::from_utf8_lossy(&output.stderr).trim()).unwrap();
+    assert_eq!(error["code"], "wrong_passphrase");
+}
+
+/// Whether the tests run as root, as which `serve` refuses to start.
+fn running_as_root() -> bool {
+    // SAFETY: geteuid cannot fail and has no preconditions
+    #[cfg(unix)]
+    return unsafe { libc::geteuid() } == 0;
+    #[cfg(not(unix))]
+    return false;
+}
+
+/// Uid and gid of `nobody`, which `serve` runs as when the tests run as
+/// root.
+#[cfg(unix)]
+const NOBODY: u32 = 65534;
+
+/// A command running the binary as the tests' user, or as `nobody` when
+/// that is root, for `serve`. As `nobody` it runs a copy in `dir`, made
+/// with `serve_dir`, since the build directory may be out of its reach.
+fn serve_command(dir: &std::path::Path) -> Command {
+    #[cfg(unix)]
+    if running_as_root() {
+        use std::os::unix::process::CommandExt;
+
+        let copy = dir.join("gtkrypt-crypto");
+        if !copy.exists() {
+            fs::copy(binary_path(), &copy).unwrap();
+        }
+        let mut command = Command::new(copy);
+        command.env("GTKRYPT_STATE_DIR", "").uid(NOBODY).gid(NOBODY);
+        return command;
+    }
+    #[cfg(not(unix))]
+    let _ = dir;
+    crypto_command()
+}
+
+/// A temp dir that the processes of `serve_command` can write to.
+fn serve_dir() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    #[cfg(unix)]
+    if running_as_root() {
+        std::os::unix::fs::chown(dir.path(), Some(NOBODY), Some(NOBODY)).unwrap();
+    }
+    dir
+}
+
+/// A `serve --stdio` process, answering one request at a time.
+struct Server {
+    child: std::process::Child,
+    stdin: std::process::ChildStdin,
+    lines: std::io::Lines<std::io::BufReader<std::process::ChildStdout>>,
+}
+
+impl Server {
+    fn start(dir: &std::path::Path, args: &[&str]) -> Self {
+        let mut command = serve_command(dir);
+        command.args(["serve", "--stdio"]).args(args);
+        Self::spawn(command)
+    }
+
+    fn spawn(mut command: Command) -> Self {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap_or_else(|e| panic!("Failed to spawn {:?}: {}", binary_path(), e));
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let lines = std::io::BufRead::lines(std::io::BufReader::new(stdout));
+        Server { child, stdin, lines }
+    }
+
+    /// Send a request and wait for its response, returned with the
+    /// messages that came before it.
+    fn request(
+        &mut self,
+        id: u32,
+        method: &str,
+        params: serde_json::Value,
+    ) -> (serde_json::Value, Vec<serde_json::Value>) {
+        let request = serde_json::json!({
+            "jsonrpc": "2.0", "id": id, "method": method, "params": params,
+        });
+        writeln!(self.stdin, "{}", request).unwrap();
+        let mut events = Vec::new();
+        loop {
+            let line = self.lines.next().unwrap().unwrap();
+            let message: serde_json::Value = serde_json::from_str(&line).unwrap();
+            if message["id"] == id {
+                return (message, events);
+            }
+            events.push(message);
+        }
+    }
+
+    fn stop(self) -> Option<i32> {
+        let Server { mut child, stdin, .. } = self;
+        drop(stdin);
+        child.wait().unwrap().code()
+    }
+}
+
+#[test]
+fn test_serve_stdio_requests() {
+    let dir = serve_dir();
+    let input_path = dir.path().join("served.txt");
+    let encrypted_path = dir.path().join("served.txt.gtkrypt");
+    fs::write(&input_path, b"served without a fork").unwrap();
+
+    let mut server = Server::start(dir.path(), &[]);
+    let (response, events) = server.request(1, "encrypt", serde_json::json!({
+        "input": input_path.to_str().unwrap(),
+        "output": encrypted_path.to_str().unwrap(),
+        "time_cost": 1, "memory_cost": 1024, "parallelism": 1,
+        "passphrase": "serve_pass",
+    }));
+    assert_eq!(response["result"], serde_json::json!({}));
+    assert!(encrypted_path.exists());
+    assert!(events.iter().all(|event| event["method"] == "event"));
+    assert!(events.iter().all(|event| event["params"]["request_id"] == 1));
+    assert_eq!(events.last().unwrap()["params"]["summary"], "encrypt");
+
+    let (response, _) = server.request(2, "inspect", serde_json::json!({
+        "input": encrypted_path.to_str().unwrap(),
+    }));
+    assert_eq!(response["result"]["original_file_size"], 21);
+
+    // A failed operation answers with its exit code and error event
+    let (response, _) = server.request(3, "decrypt", serde_json::json!({
+        "input": encrypted_path.to_str().unwrap(),
+        "output": dir.path().join("out.txt").to_str().unwrap(),
+        "passphrase": "wrong_pass",
+    }));
+    assert_eq!(response["error"]["code"], 1);
+    assert_eq!(response["error"]["data"]["code"], "wrong_passphrase");
+
+    let (response, _) = server.request(4, "cancel", serde_json::json!({"id": 1}));
+    assert_eq!(response["result"]["cancelled"], false);
+    let (response, _) = server.request(5, "shred", serde_json::json!({}));
+    assert_eq!(response["error"]["code"], -32601);
+
+    assert_eq!(server.stop(), Some(0));
+}
+
+#[test]
+fn test_privileged_helper_takes_derived_keys_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("system.conf");
+    let encrypted_path = dir.path().join("system.conf.gtkrypt");
+    let decrypted_path = dir.path().join("system.out");
+    fs::write(&input_path, b"owned by root").unwrap();
+
+    let user_dir = serve_dir();
+    let mut user = Server::start(user_dir.path(), &[]);
+    let mut helper = Server::spawn({
+        let mut command = crypto_command();
+        command.arg("privileged-helper");
+        command
+    });
+    let (derived, _) = user.request(1, "derive_key", serde_json::json!({
+        "passphrase": "helper_pass", "time_cost": 1, "memory_cost": 1024, "parallelism": 1,
+    }));
+    let derived_key = derived["result"].clone();
+    assert_eq!(derived_key["key"].as_str().unwrap().len(), 64);
+
+    let encrypt = serde_json::json!({
+        "input": input_path.to_str().unwrap(),
+        "output": encrypted_path.to_str().unwrap(),
+        "derived_key": derived_key,
+    });
+    let (response, _) = helper.request(1, "encrypt", encrypt);
+    assert_eq!(response["result"], serde_json::json!({}));
+
+    // The container opens with the passphrase the key came from
+    let output = run_crypto(
+        &decrypt_args(encrypted_path.to_str().unwrap(), decrypted_path.to_str().unwrap(), None),
+        "helper_pass",
+    );
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"owned by root");
+
+    // Decrypting goes by the salt and parameters the helper reports
+    let (report, _) = helper.request(2, "inspect", serde_json::json!({
+        "input": encrypted_path.to_str().unwrap(),
+    }));
+    let kdf = &report["result"]["kdf"];
+    assert_eq!(kdf["salt"], derived_key["salt"]);
+    let (derived, _) = user.request(2, "derive_key", serde_json::json!({
+        "passphrase": "helper_pass", "salt": kdf["salt"], "time_cost": kdf["time_cost"],
+        "memory_cost": kdf["memory_cost_kib"], "parallelism": kdf["parallelism"],
+    }));
+    assert_eq!(derived["result"], derived_key);
+    let decrypt = serde_json::json!({
+        "input": encrypted_path.to_str().unwrap(),
+        "output": decrypted_path.to_str().unwrap(),
+        "conflict": "replace",
+        "derived_key": derived["result"],
+    });
+    let (response, _) = helper.request(3, "decrypt", decrypt);
+    assert_eq!(response["result"], serde_json::json!({}));
+
+    // A passphrase never reaches the helper, nor does it stretch one
+    let decrypt = serde_json::json!({
+        "input": encrypted_path.to_str().unwrap(),
+        "output": decrypted_path.to_str().unwrap(),
+        "passphrase": "helper_pass",
+    });
+    let (response, _) = helper.request(4, "decrypt", decrypt);
+    assert_eq!(response["error"]["code"], -32602);
+    let (response, _) = helper.request(5, "derive_key", serde_json::json!({}));
+    assert_eq!(response["error"]["code"], -32601);
+
+    assert_eq!(user.stop(), Some(0));
+    assert_eq!(helper.stop(), Some(0));
+}
+
+#[test]
+fn test_serve_refuses_only_root() {
+    let output = crypto_command()
+        .args(["serve", "--stdio"])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    if running_as_root() {
+        assert_eq!(output.status.code(), Some(3));
+        let error: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+        assert_eq!(error["error"], "permission_error");
+    } else {
+        assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    }
+
+    // Any other user is served
+    let dir = serve_dir();
+    let output = serve_command(dir.path())
+        .args(["serve", "--stdio"])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+/// Connect to the socket of a server started with `serve_command`, as the
+/// user it runs as: a server only lets in clients of its own uid.
+#[cfg(unix)]
+fn connect_to_server(path: &std::path::Path) -> std::os::unix::net::UnixStream {
+    let connect = || std::os::unix::net::UnixStream::connect(path).unwrap();
+    if !running_as_root() {
+        return connect();
+    }
+    // Only a thread of its own changes its effective uid: the raw syscall,
+    // unlike seteuid, leaves the other threads of the test process be
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                #[cfg(target_os = "linux")]
+                // SAFETY: setresuid only changes the credentials of this thread
+                unsafe {
+                    let keep = -1 as libc::c_long;
+                    let euid = NOBODY as libc::c_long;
+                    assert_eq!(libc::syscall(libc::SYS_setresuid, keep, euid, keep), 0);
+                }
+                connect()
+            })
+            .join()
+            .unwrap()
+    })
+}
+
+#[cfg(unix)]
+#[test]
+fn test_serve_socket_clients() {
+    use std::io::BufRead;
+
+    let dir = serve_dir();
+    let socket_path = dir.path().join("gtkrypt.sock");
+    let input_path = dir.path().join("shared.txt");
+    fs::write(&input_path, b"one backend for all").unwrap();
+
+    let bin = binary_path();
+    let mut server = serve_command(dir.path())
+        .args(["serve", "--socket", socket_path.to_str().unwrap()])
+        .stdin(Stdio::null())
+        .spawn()
+        .unwrap_or_else(|e| panic!("Failed to spawn {:?}: {}", bin, e));
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while !socket_path.exists() && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+
+    // Two clients in turn, each on a connection of its own
+    for client in 0..2 {
+        let encrypted_path = dir.path().join(format!("shared.{}.gtkrypt", client));
+        let mut stream = connect_to_server(&socket_path);
+        let request = serde_json::json!({
+            "jsonrpc": "2.0", "id": "enc", "method": "encrypt",
+            "params": {
+                "input": input_path.to_str().unwrap(),
+                "output": encrypted_path.to_str().unwrap(),
+                "time_cost": 1, "memory_cost": 1024, "parallelism": 1,
+                "passphrase": "socket_pass",
+            },
+        });
+        writeln!(stream, "{}", request).unwrap();
+        let mut events = 0;
+        for line in std::io::BufReader::new(stream).lines() {
+            let message: serde_json::Value = serde_json::from_str(&line.unwrap()).unwrap();
+            if message["id"] == "enc" {
+                assert_eq!(message["result"], serde_json::json!({}));
+                break;
+            }
+            assert_eq!(message["params"]["request_id"], "enc");
+            events += 1;
+        }
+        assert!(events > 0);
+        assert!(encrypted_path.exists());
+    }
+
+    // Stopping the server removes its socket
+    assert_eq!(unsafe { libc::kill(server.id() as i32, libc::SIGTERM) }, 0);
+    assert_eq!(server.wait().unwrap().code(), Some(11));
+    assert!(!socket_path.exists());
+}
+
+/// Run the backend with `files` handed down as descriptors 3, 4, ...
+#[cfg(unix)]
+fn run_crypto_with_fds(
+    args: &[&str],
+    passphrase: &str,
+    files: &[&fs::File],
+) -> std::process::Output {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let fds: Vec<i32> = files.iter().map(|file| file.as_raw_fd()).collect();
+    let mut command = crypto_command();
+    command
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // SAFETY: fcntl and dup2 are async-signal-safe. Every file is first
+    // moved above the targets, so none is overwritten before it is placed
+    unsafe {
+        command.pre_exec(move || {
+            let mut moved = Vec::with_capacity(fds.len());
+            for &fd in &fds {
+                let high = libc::fcntl(fd, libc::F_DUPFD, 100);
+                if high < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                moved.push(high);
+            }
+            for (target, high) in (3..).zip(moved) {
+                if libc::dup2(high, target) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().unwrap();
+    writeln!(child.stdin.as_mut().unwrap(), "{}", passphrase).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[cfg(unix)]
+#[test]
+fn test_input_and_output_descriptors() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("portal.txt");
+    let encrypted_path = dir.path().join("portal.gtkrypt");
+    let decrypted_path = dir.path().join("portal.out");
+    fs::write(&input_path, b"opened through the portal").unwrap();
+    fs::write(&encrypted_path, b"an older and longer file, replaced whole").unwrap();
+
+    let input = fs::File::open(&input_path).unwrap();
+    let output = fs::OpenOptions::new().write(true).open(&encrypted_path).unwrap();
+    let args = [
+        "encrypt", "--input-fd", "3", "--output-fd", "4", "--store-filename",
+        "--time-cost", "1", "--memory-cost", "1024", "--parallelism", "1",
+    ];
+    let result = run_crypto_with_fds(&args, "fd_pass", &[&input, &output]);
+    assert_eq!(result.status.code(), Some(0), "{}", String::from_utf8_lossy(&result.stderr));
+
+    // The name comes from the file behind the descriptor
+    let args = ["inspect", "--input", encrypted_path.to_str().unwrap(), "--json"];
+    let report = run_crypto_no_stdin(&args);
+    let report: serde_json::Value = serde_json::from_slice(&report.stdout).unwrap();
+    assert_eq!(report["filename"], "portal.txt");
+
+    let input = fs::File::open(&encrypted_path).unwrap();
+    let output = fs::File::create(&decrypted_path).unwrap();
+    let args = ["decrypt", "--input-fd", "3", "--output-fd", "4"];
+    let result = run_crypto_with_fds(&args, "fd_pass", &[&input, &output]);
+    assert_eq!(result.status.code(), Some(0), "{}", String::from_utf8_lossy(&result.stderr));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"opened through the portal");
+
+    // A failed run leaves the descriptor's file alone
+    let input = fs::File::open(&encrypted_path).unwrap();
+    let output = fs::OpenOptions::new().write(true).open(&decrypted_path).unwrap();
+    let result = run_crypto_with_fds(&args, "wrong_pass", &[&input, &output]);
+    assert_eq!(result.status.code(), Some(1));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"opened through the portal");
+
+    // Descriptors that are not open the right way are refused up front
+    let result = run_crypto_with_fds(&args, "fd_pass", &[&input, &input]);
+    assert_eq!(result.status.code(), Some(10));
+}
+
+#[test]
+fn test_length_prefixed_passphrase() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("multiline.txt");
+    let encrypted_path = dir.path().join("multiline.txt.gtkrypt");
+    let decrypted_path = dir.path().join("multiline.out");
+    fs::write(&input_path, b"guarded by two lines").unwrap();
+
+    let passphrase = b"first line\r\nsecond line";
+    let mut framed = (passphrase.len() as u32).to_be_bytes().to_vec();
+    framed.extend_from_slice(passphrase);
+    let mut args = vec!["--passphrase-format", "length-prefixed"];
+    args.extend(fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    ));
+    let output = run_crypto_with_stdin(&args, &framed);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // A line only carries the part before the break
+    let decrypted = decrypted_path.to_str().unwrap();
+    let args = decrypt_args(encrypted_path.to_str().unwrap(), decrypted, None);
+    let output = run_crypto(&args, "first line\r\nsecond line");
+    assert_eq!(output.status.code(), Some(1));
+
+    let mut args = vec!["--passphrase-format", "length-prefixed"];
+    args.extend(decrypt_args(encrypted_path.to_str().unwrap(), decrypted, None));
+    let output = run_crypto_with_stdin(&args, &framed);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"guarded by two lines");
+
+    let output = run_crypto_with_stdin(&args, &framed[..6]);
+    assert_eq!(output.status.code(), Some(10));
+}
+
+#[test]
+fn test_passphrase_bytes_are_not_reencoded() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("latin1.txt");
+    let encrypted_path = dir.path().join("latin1.txt.gtkrypt");
+    let decrypted_path = dir.path().join("latin1.out");
+    fs::write(&input_path, b"typed on an old terminal").unwrap();
+
+    // "grüße" in Latin-1, which is not valid UTF-8
+    let mut args = vec!["--passphrase-encoding", "utf-8"];
+    args.extend(fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    ));
+    let output = run_crypto_with_stdin(&args, b"gr\xfc\xdfe\n");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let events: Vec<serde_json::Value> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    assert!(events.iter().any(|e| e["warning"] == "passphrase_encoding"));
+
+    // The same word in UTF-8 is a different passphrase
+    let decrypted = decrypted_path.to_str().unwrap();
+    let args = decrypt_args(encrypted_path.to_str().unwrap(), decrypted, None);
+    let output = run_crypto(&args, "grüße");
+    assert_eq!(output.status.code(), Some(1));
+
+    let output = run_crypto_with_stdin(&args, b"gr\xfc\xdfe\n");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"typed on an old terminal");
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("passphrase_encoding"));
+}
+
+/// Read from the terminal `master` until `text` has been shown on it.
+#[cfg(unix)]
+fn expect_on_terminal(master: &mut fs::File, text: &str) {
+    use std::io::Read;
+
+    let mut shown = Vec::new();
+    while !String::from_utf8_lossy(&shown).contains(text) {
+        let mut byte = [0u8];
+        master.read_exact(&mut byte).unwrap_or_else(|e| {
+            panic!("{:?} not shown, only {:?}: {}", text, String::from_utf8_lossy(&shown), e)
+        });
+        shown.push(byte[0]);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_passphrase_prompt_on_a_terminal() {
+    use std::os::fd::FromRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("typed.txt");
+    let encrypted_path = dir.path().join("typed.txt.gtkrypt");
+    let decrypted_path = dir.path().join("typed.out");
+    fs::write(&input_path, b"from an interactive shell").unwrap();
+
+    let (mut master, mut slave) = (0, 0);
+    let opened = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    assert_eq!(opened, 0);
+    let (mut master, slave) =
+        unsafe { (fs::File::from_raw_fd(master), fs::File::from_raw_fd(slave)) };
+
+    // The terminal becomes stdin and the controlling terminal of the run
+    let args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+    let mut command = crypto_command();
+    command
+        .args(&args)
+        .stdin(slave.try_clone().unwrap())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn().unwrap();
+    drop(slave);
+
+    expect_on_terminal(&mut master, "Passphrase: ");
+    master.write_all(b"typed_pass\n").unwrap();
+    expect_on_terminal(&mut master, "Repeat passphrase: ");
+    master.write_all(b"typo_pass\n").unwrap();
+    expect_on_terminal(&mut master, "Passphrases do not match");
+    expect_on_terminal(&mut master, "Passphrase: ");
+    master.write_all(b"typed_pass\n").unwrap();
+    expect_on_terminal(&mut master, "Repeat passphrase: ");
+    master.write_all(b"typed_pass\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // What was typed is the passphrase a pipe gives
+    let args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        None,
+    );
+    let output = run_crypto(&args, "typed_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"from an interactive shell");
+}
+
+/// Run the binary with stdout and stderr on a new terminal, returning the
+/// exit code and what was shown.
+#[cfg(unix)]
+fn run_crypto_on_terminal(args: &[&str], passphrase: &str) -> (Option<i32>, String) {
+    use std::io::Read;
+    use std::os::fd::FromRawFd;
+
+    let (mut master, mut slave) = (0, 0);
+    let opened = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    assert_eq!(opened, 0);
+    let (mut master, slave) =
+        unsafe { (fs::File::from_raw_fd(master), fs::File::from_raw_fd(slave)) };
+    let mut child = crypto_command()
+        .args(args)
+        .env_remove("NO_COLOR")
+        .stdin(Stdio::piped())
+        .stdout(slave.try_clone().unwrap())
+        .stderr(slave)
+        .spawn()
+        .unwrap();
+    writeln!(child.stdin.take().unwrap(), "{}", passphrase).unwrap();
+    // Ends with EIO once the run has closed the terminal
+    let mut shown = Vec::new();
+    let _ = master.read_to_end(&mut shown);
+    let status = child.wait().unwrap();
+    (status.code(), String::from_utf8_lossy(&shown).into_owned())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_text_and_progress_bar_on_a_terminal() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("shown.txt");
+    let encrypted_path = dir.path().join("shown.txt.gtkrypt");
+    fs::write(&input_path, b"for a person to watch").unwrap();
+    let args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    );
+
+    let (code, shown) = run_crypto_on_terminal(&args, "terminal_pass");
+    assert_eq!(code, Some(0), "{}", shown);
+    assert!(shown.contains("Deriving key  ["), "{}", shown);
+    assert!(shown.contains("] 100%"), "{}", shown);
+    assert!(shown.contains("\x1b[32mEncrypted\x1b[0m 21 B to "), "{}", shown);
+    assert!(!shown.contains('{'), "{}", shown);
+
+    // Errors in words, with the exit code they always had
+    let (code, shown) = run_crypto_on_terminal(&args, "terminal_pass");
+    assert_eq!(code, Some(4), "{}", shown);
+    assert!(shown.contains("\x1b[1;31merror:\x1b[0m Output already exists"), "{}", shown);
+
+    // --json keeps the protocol
+    let (code, shown) = run_crypto_on_terminal(&[&args[..], &["--json", "--force"]].concat(), "p");
+    assert_eq!(code, Some(0), "{}", shown);
+    for line in shown.lines() {
+        serde_json::from_str::<serde_json::Value>(line)
+            .unwrap_or_else(|e| panic!("{:?} is not JSON: {}", line, e));
+    }
+}
+
+#[test]
+fn test_passphrase_strength() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("plain.txt");
+    let encrypted_path = dir.path().join("plain.txt.gtkrypt");
+    fs::write(&input_path, b"worth guarding").unwrap();
+    let input = input_path.to_str().unwrap();
+    let encrypted = encrypted_path.to_str().unwrap();
+
+    let output = run_crypto(&fast_encrypt_args(input, encrypted, None), "password1");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let events: Vec<serde_json::Value> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    let strength = events.iter().find(|e| e.get("passphrase_strength").is_some()).unwrap();
+    assert_eq!(strength["passphrase_strength"], 0);
+    assert_eq!(strength["crack_time"], "less than a second");
+    fs::remove_file(&encrypted_path).unwrap();
+
+    let mut args = vec!["--min-strength", "3"];
+    args.extend(fast_encrypt_args(input, encrypted, None));
+    let output = run_crypto(&args, "password1");
+    assert_eq!(output.status.code(), Some(13));
+    let error: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(error["error"], "weak_passphrase");
+    assert_eq!(error["message_params"]["minimum"], 3);
+    assert!(!encrypted_path.exists());
+
+    let output = run_crypto(&args, "glacier pocket umbrella ninety");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // Decrypting takes whatever the file was encrypted with
+    let decrypted_path = dir.path().join("plain.out");
+    let mut args = vec!["--min-strength", "4"];
+    args.extend(decrypt_args(encrypted, decrypted_path.to_str().unwrap(), None));
+    let output = run_crypto(&args, "glacier pocket umbrella ninety");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("passphrase_strength"));
+}
+
+#[test]
+fn test_passphrase_from_credential() {
+    let dir = tempfile::tempdir().unwrap();
+    let credentials = dir.path().join("credentials");
+    fs::create_dir(&credentials).unwrap();
+    fs::write(credentials.join("backup-passphrase"), b"unattended_pass\n").unwrap();
+    let input_path = dir.path().join("nightly.txt");
+    let encrypted_path = dir.path().join("nightly.txt.gtkrypt");
+    let decrypted_path = dir.path().join("nightly.out");
+    fs::write(&input_path, b"backed up by a timer").unwrap();
+
+    let mut args = vec!["--passphrase-from-credential", "backup-passphrase"];
+    args.extend(fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    ));
+    let output = crypto_command()
+        .args(&args)
+        .env("CREDENTIALS_DIRECTORY", &credentials)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // The environment stands in for a missing credentials directory
+    let decrypted = decrypted_path.to_str().unwrap();
+    let mut args = vec!["--passphrase-from-credential", "backup-passphrase"];
+    args.extend(decrypt_args(encrypted_path.to_str().unwrap(), decrypted, None));
+    let output = crypto_command()
+        .args(&args)
+        .env_remove("CREDENTIALS_DIRECTORY")
+        .env("GTKRYPT_CREDENTIAL_BACKUP_PASSPHRASE", "unattended_pass")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"backed up by a timer");
+
+    // Only the namespaced variable, never one of the bare name
+    fs::remove_file(&decrypted_path).unwrap();
+    let output = crypto_command()
+        .args(&args)
+        .env_remove("CREDENTIALS_DIRECTORY")
+        .env_remove("GTKRYPT_CREDENTIAL_BACKUP_PASSPHRASE")
+        .env("backup-passphrase", "unattended_pass")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(10));
+    let error: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert!(error["message"].as_str().unwrap().contains("No credential"));
+    assert!(!decrypted_path.exists());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_cache_key_in_session_keyring() {
+    // A session keyring of this test process's own, so that the runs below
+    // neither see nor clobber what the user has cached
+    static JOINED: std::sync::Once = std::sync::Once::new();
+    JOINED.call_once(|| unsafe {
+        libc::syscall(libc::SYS_keyctl, 1, std::ptr::null::<libc::c_char>());
+    });
+    let events = |output: &std::process::Output| -> Vec<serde_json::Value> {
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect()
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("burst.txt");
+    let encrypted_path = dir.path().join("burst.txt.gtkrypt");
+    let decrypted_path = dir.path().join("burst.out");
+    fs::write(&input_path, b"one of many").unwrap();
+
+    let mut args = vec!["--cache-key", "60"];
+    args.extend(fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        None,
+    ));
+    let output = run_crypto(&args, "cached_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let events_seen = events(&output);
+    if events_seen.iter().any(|e| e["warning"] == "key_cache_unavailable") {
+        // Filtered out, as in many containers
+        return;
+    }
+    assert!(events_seen.iter().any(|e| e["key_cache"] == "stored" && e["expires_in_secs"] == 60));
+
+    // Neither the passphrase nor Argon2id is needed again
+    let decrypted = decrypted_path.to_str().unwrap();
+    let mut args = vec!["--cache-key", "60"];
+    args.extend(decrypt_args(encrypted_path.to_str().unwrap(), decrypted, None));
+    let output = run_crypto_no_stdin(&args);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"one of many");
+    let events_seen = events(&output);
+    assert!(events_seen.iter().any(|e| e["key_cache"] == "hit"));
+    let keys = fs::read_to_string("/proc/keys").unwrap_or_default();
+    assert!(keys.contains("gtkrypt:key:") || keys.is_empty(), "{}", keys);
+
+    // A file under another passphrase drops the cached one
+    let other_path = dir.path().join("other.gtkrypt");
+    let args = fast_encrypt_args(input_path.to_str().unwrap(), other_path.to_str().unwrap(), None);
+    assert_eq!(run_crypto(&args, "other_pass").status.code(), Some(0));
+    fs::remove_file(&decrypted_path).unwrap();
+    let mut args = vec!["--cache-key", "60"];
+    args.extend(decrypt_args(other_path.to_str().unwrap(), decrypted, None));
+    assert_eq!(run_crypto_no_stdin(&args).status.code(), Some(1));
+    let output = run_crypto(&args, "other_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!events(&output).iter().any(|e| e["key_cache"] == "hit"));
+}
+
+#[test]
+fn test_keyfile_command() {
+    let dir = tempfile::tempdir().unwrap();
+    let keyfile_path = dir.path().join("gtkrypt.key");
+    let keyfile = keyfile_path.to_str().unwrap();
+    let input_path = dir.path().join("two-factor.txt");
+    let encrypted_path = dir.path().join("two-factor.txt.gtkrypt");
+    let decrypted_path = dir.path().join("two-factor.out");
+    fs::write(&input_path, b"needs both").unwrap();
+
+    let output = run_crypto_no_stdin(&["keyfile", "--output", keyfile]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("output_path"));
+    assert_eq!(fs::read(&keyfile_path).unwrap().len(), 64);
+
+    let output = run_crypto_no_stdin(&["keyfile", "--output", keyfile]);
+    assert_eq!(output.status.code(), Some(4));
+
+    let args = fast_encrypt_args(
+        input_path.to_str().unwrap(),
+        encrypted_path.to_str().unwrap(),
+        Some(keyfile),
+    );
+    assert_eq!(run_crypto(&args, "keyed_pass").status.code(), Some(0));
+    let args = decrypt_args(
+        encrypted_path.to_str().unwrap(),
+        decrypted_path.to_str().unwrap(),
+        Some(keyfile),
+    );
+    let output = run_crypto(&args, "keyed_pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(&decrypted_path).unwrap(), b"needs both");
+
+    // A DPAPI keyfile only works on Windows, for the account that made it
+    let wrapped_path = dir.path().join("wrapped.key");
+    let wrapped = wrapped_path.to_str().unwrap();
+    let output = run_crypto_no_stdin(&["keyfile", "--output", wrapped, "--dpapi"]);
+    if cfg!(windows) {
+        assert_eq!(output.status.code(), Some(0));
+    } else {
+        assert_eq!(output.status.code(), Some(10));
+        assert!(!wrapped_path.exists());
+        fs::write(&wrapped_path, b"GTKRYPT-DPAPI-KEYFILE\nstolen blob").unwrap();
+        let args = decrypt_args(
+            encrypted_path.to_str().unwrap(),
+            decrypted_path.to_str().unwrap(),
+            Some(wrapped),
+        );
+        let output = run_crypto(&args, "keyed_pass");
+        assert_eq!(output.status.code(), Some(10));
+        assert!(String::from_utf8_lossy(&output.stderr).contains("DPAPI"));
+    }
+}
+
+#[test]
+fn test_encrypting_a_container_again_needs_allow_double() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("notes.txt");
+    fs::write(&input_path, b"already safe").unwrap();
+    let input = input_path.to_str().unwrap();
+    let once = dir.path().join("notes.txt.gtkrypt");
+    let twice = dir.path().join("notes.txt.gtkrypt.gtkrypt");
+    let once = once.to_str().unwrap();
+    let twice_str = twice.to_str().unwrap();
+
+    let output = run_crypto(&fast_encrypt_args(input, once, None), "pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let output = run_crypto(&fast_encrypt_args(once, twice_str, None), "pass");
+    assert_eq!(output.status.code(), Some(14));
+    let error: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(error["error"], "already_encrypted");
+    assert_eq!(error["message_id"], "already_encrypted");
+    assert_eq!(error["details"]["path"], once);
+    assert!(!twice.exists());
+
+    let mut args = fast_encrypt_args(once, twice_str, None);
+    args.push("--allow-double");
+    let output = run_crypto(&args, "pass");
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(twice.exists());
+}
@@ -52,6 +52,14 @@ export class PermissionError extends GtkryptError {
   }
 }
 
+/** The output file already exists and replacing it was not requested. */
+export class OutputExistsError extends GtkryptError {
+  constructor(message = "Output file already exists") {
+    super(message, _("A file with that name already exists."));
+    this.name = "OutputExistsError";
+  }
+}
+
 /** The user cancelled the operation. This is a silent error. */
 export class CancelledError extends GtkryptError {
   constructor(message = "Operation cancelled") {
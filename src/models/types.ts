@@ -66,7 +66,8 @@ export interface ContainerHeader {
 export interface ProgressEvent {
   fileIndex: number;
   bytesProcessed: number;
-  totalBytes: number;
+  /** Null while the size of the input is not known, as for a pipe. */
+  totalBytes: number | null;
   phase: "kdf" | "encrypt" | "decrypt";
 }
 
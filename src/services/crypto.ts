@@ -25,6 +25,7 @@ import {
   WrongPassphraseError,
   CorruptFileError,
   PermissionError,
+  OutputExistsError,
   CancelledError,
   InternalCryptoError,
   GtkryptError,
@@ -107,6 +108,8 @@ function mapExitCodeToError(
       return new CorruptFileError(detail);
     case 3:
       return new PermissionError(detail);
+    case 4:
+      return new OutputExistsError(detail);
     case 10:
       return new InternalCryptoError(detail);
     default:
@@ -128,6 +131,9 @@ function mapExitCodeToError(
  * {"progress":0.5,"bytes_processed":1024,"total_bytes":2048,"phase":"encrypt"}
  * ```
  *
+ * `total_bytes` and `progress` are null where the size of the input is not
+ * known until it ends.
+ *
  * Malformed lines are silently ignored (the Rust binary may emit
  * debug messages on stdout in development builds).
  *
@@ -199,13 +205,13 @@ function readProgressLines(
             const parsed = JSON.parse(line) as {
               progress?: number;
               bytes_processed?: number;
-              total_bytes?: number;
+              total_bytes?: number | null;
               phase?: string;
             };
 
             if (
               typeof parsed.bytes_processed === "number" &&
-              typeof parsed.total_bytes === "number" &&
+              (typeof parsed.total_bytes === "number" || parsed.total_bytes === null) &&
               typeof parsed.phase === "string"
             ) {
               emit({
@@ -272,6 +278,8 @@ export function encrypt(
         inputPath,
         "--output",
         outputPath,
+        // Output paths are already made unique by services/naming.ts
+        "--force",
         "--time-cost",
         String(params.timeCost),
         "--memory-cost",
@@ -419,6 +427,7 @@ export function decrypt(
         inputPath,
         "--output",
         outputPath,
+        "--force",
       ];
 
       if (keyfilePath) {
@@ -27,7 +27,7 @@ export const HEADER_MAGIC = new Uint8Array([
 ]);
 
 /** The newest container version this build can read. */
-export const CURRENT_VERSION = 2;
+export const CURRENT_VERSION = 3;
 
 /** KDF identifier for Argon2id. */
 const KDF_ARGON2ID = 1;
@@ -70,12 +70,12 @@ export function parseHeader(bytes: Uint8Array): ContainerHeader {
 
   // -- Version (offset 8, 1 byte) --
   const version = readUint8(bytes, 8);
-  if (version !== 1 && version !== 2) {
+  if (version < 1 || version > CURRENT_VERSION) {
     throw new UnsupportedVersionError(
-      `Container version ${version} is not supported (expected 1 or ${CURRENT_VERSION})`,
+      `Container version ${version} is not supported (expected 1 to ${CURRENT_VERSION})`,
     );
   }
-  if (version === 2 && bytes.byteLength < MIN_HEADER_SIZE_V2) {
+  if (version >= 2 && bytes.byteLength < MIN_HEADER_SIZE_V2) {
     throw new CorruptFileError("Header too short to be a valid gtkrypt v2 file");
   }
 
@@ -114,7 +114,7 @@ export function parseHeader(bytes: Uint8Array): ContainerHeader {
   const filenameLength = readUint16BE(bytes, 49);
 
   const requiredSize =
-    (version === 2 ? MIN_HEADER_SIZE_V2 : MIN_HEADER_SIZE_V1) + filenameLength;
+    (version >= 2 ? MIN_HEADER_SIZE_V2 : MIN_HEADER_SIZE_V1) + filenameLength;
   if (bytes.byteLength < requiredSize) {
     throw new CorruptFileError(
       "Header too short: filename extends past available bytes",
@@ -133,7 +133,9 @@ export function parseHeader(bytes: Uint8Array): ContainerHeader {
   let fileSize: bigint;
   let ciphertextLength: bigint;
 
-  if (version === 2) {
+  if (version >= 2) {
+    // v3 shares the v2 field layout; its extension area follows and is
+    // not needed by the frontend.
     // -- Mode (uint32BE at 51 + filenameLength) --
     mode = readUint32BE(bytes, offset);
 
@@ -1210,7 +1210,7 @@ class _GtkryptWindow extends Adw.ApplicationWindow {
             options as DecryptOptions,
             (event: ProgressEvent) => {
               const fraction =
-                event.totalBytes > 0
+                event.totalBytes !== null && event.totalBytes > 0
                   ? event.bytesProcessed / event.totalBytes
                   : 0;
               progressView.updateFileProgress(i, fraction, event.phase);
@@ -1228,7 +1228,7 @@ class _GtkryptWindow extends Adw.ApplicationWindow {
             options as EncryptOptions,
             (event: ProgressEvent) => {
               const fraction =
-                event.totalBytes > 0
+                event.totalBytes !== null && event.totalBytes > 0
                   ? event.bytesProcessed / event.totalBytes
                   : 0;
               progressView.updateFileProgress(i, fraction, event.phase);
@@ -127,22 +127,18 @@ test_corrupted_ciphertext() {
 
   do_encrypt "my-secret" "$PLAINTEXT" "$enc"
 
-  # Copy the encrypted file and flip a byte well inside the ciphertext area.
-  # Header with no filename is 71 bytes (v2). Ciphertext starts at byte 71.
-  # We target byte 80, safely within the first ciphertext chunk.
+  # Copy the encrypted file and flip the last ciphertext byte of the single
+  # chunk, which sits before its 16-byte tag and the 44-byte chunk index.
   cp "$enc" "$tampered"
   python3 -c "
-import sys
 data = bytearray(open('$tampered', 'rb').read())
-if len(data) > 80:
-    data[80] ^= 0xFF
-    open('$tampered', 'wb').write(data)
-else:
-    sys.exit(1)
+data[len(data) - 44 - 16 - 1] ^= 0xFF
+open('$tampered', 'wb').write(data)
 "
 
-  # GCM auth failure maps to exit code 1 (wrong passphrase / auth failure)
-  expect_exit 1 do_decrypt "my-secret" "$tampered" "$dec" || return 1
+  # The key-check value confirms the passphrase, so the GCM failure is
+  # reported as corruption (exit code 2)
+  expect_exit 2 do_decrypt "my-secret" "$tampered" "$dec" || return 1
 
   # No output file
   if [ -e "$dec" ]; then
@@ -181,16 +177,13 @@ test_corrupted_magic() {
 }
 
 # ---------------------------------------------------------------------------
-# Test 4: Tampered header AAD byte causes GCM authentication failure
+# Test 4: Tampered header AAD byte causes authentication failure
 #
 # The AAD covers header bytes 0..48 (magic, version, KDF params, salt, nonce).
 # We flip a byte in the nonce field (byte 40, within bytes 37-48). This does
 # NOT change the KDF params, so key derivation runs at the same fast speed,
-# but the stored nonce is now wrong:
-#   - The per-chunk nonce derived from the tampered base nonce differs from
-#     the one used during encryption.
-#   - The AAD passed to GCM also differs (it includes the tampered bytes).
-# Both effects cause GCM authentication to fail.
+# but the stored nonce is now wrong. The header MAC covers the nonce, so
+# the header fails authentication before any chunk is decrypted.
 # ---------------------------------------------------------------------------
 
 test_tampered_header_aad() {
@@ -211,9 +204,9 @@ data[40] ^= 0xFF
 open('$tampered', 'wb').write(data)
 "
 
-  # GCM decryption will fail because the nonce and AAD no longer match what
-  # was used during encryption. Exit code 1 (wrong passphrase / auth failure).
-  expect_exit 1 do_decrypt "aad-pass" "$tampered" "$dec" || return 1
+  # The passphrase still matches the key-check value, so the header MAC
+  # failure is reported as corruption (exit code 2).
+  expect_exit 2 do_decrypt "aad-pass" "$tampered" "$dec" || return 1
 
   # No output file
   if [ -e "$dec" ]; then
@@ -278,12 +271,13 @@ test_corrupted_auth_tag() {
 
   do_encrypt "tag-pass" "$PLAINTEXT" "$enc"
 
-  # The auth tag is the last 16 bytes of the first (and only) chunk.
-  # Flip a byte in the tag area (last byte of the file for single-chunk data).
+  # The auth tag is the last 16 bytes of the first (and only) chunk, which
+  # is followed by the 44-byte chunk index footer (one 12-byte entry plus a
+  # 32-byte MAC). Flip the last byte of the tag.
   cp "$enc" "$tampered"
   local file_size
   file_size="$(stat -c '%s' "$tampered" 2>/dev/null || stat -f '%z' "$tampered" 2>/dev/null)"
-  local tag_offset=$((file_size - 1))
+  local tag_offset=$((file_size - 44 - 1))
 
   python3 -c "
 data = bytearray(open('$tampered', 'rb').read())
@@ -291,7 +285,7 @@ data[$tag_offset] ^= 0xFF
 open('$tampered', 'wb').write(data)
 "
 
-  expect_exit 1 do_decrypt "tag-pass" "$tampered" "$dec" || return 1
+  expect_exit 2 do_decrypt "tag-pass" "$tampered" "$dec" || return 1
 
   if [ -e "$dec" ]; then
     echo "    Output file exists after corrupted auth tag"
@@ -463,7 +463,7 @@ const TEST_NONCE = new Uint8Array([
 // ---------------------------------------------------------------------------
 
 {
-  assertEqual(CURRENT_VERSION, 2, "CURRENT_VERSION is 2");
+  assertEqual(CURRENT_VERSION, 3, "CURRENT_VERSION is 3");
 }
 
 // ---------------------------------------------------------------------------